@@ -0,0 +1,57 @@
+//! Pipelined GET and SET against an embedded server, from reading the commands off the socket
+//! to writing back their replies
+
+use codecrafters_redis::{
+    resp::{client::Client, RedisValue},
+    server::Redis,
+};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+/// Commands sent before reading any reply, like `redis-benchmark -P`
+const PIPELINE: usize = 100;
+
+/// Send `commands` at once, then wait for all of their replies
+async fn pipeline(client: &mut Client, commands: &[RedisValue]) {
+    for command in commands {
+        client.send(command.clone()).await.unwrap();
+    }
+    for _ in commands {
+        client.receive().await.unwrap().unwrap();
+    }
+}
+
+fn dispatch(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let redis = rt.block_on(async {
+        Redis::builder()
+            .port(0)
+            .dir(std::env::temp_dir())
+            .start()
+            .await
+            .unwrap()
+    });
+    let mut client = rt.block_on(Client::connect(redis.addr())).unwrap();
+
+    let sets: Vec<_> = (0..PIPELINE)
+        .map(|i| RedisValue::command(["SET".to_string(), format!("key:{i}"), "value".into()]))
+        .collect();
+    let gets: Vec<_> = (0..PIPELINE)
+        .map(|i| RedisValue::command(["GET".to_string(), format!("key:{i}")]))
+        .collect();
+    let pings = vec![RedisValue::command(["PING"]); PIPELINE];
+
+    let mut group = c.benchmark_group("dispatch");
+    group.throughput(Throughput::Elements(PIPELINE as u64));
+    for (name, commands) in [("PING", &pings), ("SET", &sets), ("GET", &gets)] {
+        group.bench_function(format!("pipelined {name}"), |b| {
+            b.iter(|| rt.block_on(pipeline(&mut client, commands)))
+        });
+    }
+    group.finish();
+
+    drop(client);
+    rt.block_on(redis.stop()).unwrap();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,118 @@
+//! Concurrent access to the keyspace, the DashMap every connection reads and writes. Run with
+//! `--features sharded-keyspace` to measure the shard-per-core keyspace instead.
+
+use std::{sync::Arc, thread, time::Instant};
+
+use bytes::Bytes;
+use codecrafters_redis::server::Redis;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dashmap::DashMap;
+
+/// Keys spread over, so threads mostly touch different shards of the map
+const KEYS: usize = 10_000;
+
+/// Operations each thread runs per iteration
+const OPS: usize = 1_000;
+
+fn key(i: usize) -> Bytes {
+    format!("key:{}", i % KEYS).into()
+}
+
+/// Run `op` `OPS` times on each of `threads` threads, timing the slowest
+fn on_threads(threads: usize, iters: u64, op: impl Fn(usize) + Sync) -> std::time::Duration {
+    let start = Instant::now();
+    thread::scope(|s| {
+        for t in 0..threads {
+            let op = &op;
+            s.spawn(move || {
+                for i in 0..iters as usize * OPS {
+                    op(t * 7919 + i);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+/// The raw map, for the cost of contention alone
+fn dashmap(c: &mut Criterion) {
+    let map: Arc<DashMap<Bytes, Bytes>> = Arc::new(DashMap::new());
+    for i in 0..KEYS {
+        map.insert(key(i), Bytes::from_static(b"value"));
+    }
+
+    let mut group = c.benchmark_group("dashmap");
+    for threads in [1, 4, 8] {
+        group.throughput(Throughput::Elements((threads * OPS) as u64));
+        group.bench_function(format!("get, {threads} threads"), |b| {
+            b.iter_custom(|iters| {
+                on_threads(threads, iters, |i| {
+                    std::hint::black_box(map.get(&key(i)));
+                })
+            })
+        });
+        // one write for every nine reads, as a cache usually sees
+        group.bench_function(format!("90% get 10% insert, {threads} threads"), |b| {
+            b.iter_custom(|iters| {
+                on_threads(threads, iters, |i| match i % 10 {
+                    0 => {
+                        map.insert(key(i), Bytes::from_static(b"other"));
+                    }
+                    _ => {
+                        std::hint::black_box(map.get(&key(i)));
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The server's own keyspace, with its expiry checks and access bookkeeping
+fn keyspace(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let redis = rt.block_on(async {
+        Redis::builder()
+            .port(0)
+            .dir(std::env::temp_dir())
+            .start()
+            .await
+            .unwrap()
+    });
+    let keyspace = redis.keyspace().clone();
+    rt.block_on(async {
+        for i in 0..KEYS {
+            keyspace.set_string(key(i), "value", None).await.unwrap();
+        }
+    });
+
+    let mut group = c.benchmark_group("keyspace");
+    for threads in [1, 4, 8] {
+        group.throughput(Throughput::Elements((threads * OPS) as u64));
+        group.bench_function(format!("get, {threads} threads"), |b| {
+            b.iter_custom(|iters| {
+                on_threads(threads, iters, |i| {
+                    std::hint::black_box(keyspace.get_string(key(i)));
+                })
+            })
+        });
+        group.bench_function(format!("90% get 10% set, {threads} threads"), |b| {
+            b.iter_custom(|iters| {
+                on_threads(threads, iters, |i| match i % 10 {
+                    0 => rt
+                        .block_on(keyspace.set_string(key(i), "other", None))
+                        .unwrap(),
+                    _ => {
+                        std::hint::black_box(keyspace.get_string(key(i)));
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+
+    rt.block_on(redis.stop()).unwrap();
+}
+
+criterion_group!(benches, dashmap, keyspace);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+//! RESP decode and encode throughput, as seen by a connection reading a pipeline and writing
+//! its replies
+
+use bytes::{Bytes, BytesMut};
+use codecrafters_redis::resp::{codec::RespFrame, RedisValue};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Commands sent at once, like `redis-benchmark -P`
+const PIPELINE: usize = 100;
+
+fn encode(values: impl IntoIterator<Item = RedisValue>) -> BytesMut {
+    let mut codec = RespFrame::default();
+    let mut buf = BytesMut::new();
+    for value in values {
+        codec.encode(value, &mut buf).unwrap();
+    }
+    buf
+}
+
+fn sets(value_len: usize) -> impl Iterator<Item = RedisValue> {
+    let value = Bytes::from(vec![b'x'; value_len]);
+    (0..PIPELINE).map(move |i| {
+        RedisValue::command([Bytes::from("SET"), format!("key:{i}").into(), value.clone()])
+    })
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    for value_len in [16, 1024, 64 * 1024] {
+        let pipeline = encode(sets(value_len));
+        group.throughput(Throughput::Bytes(pipeline.len() as u64));
+        group.bench_function(format!("pipelined SET {value_len}B"), |b| {
+            b.iter_batched_ref(
+                || (RespFrame::default(), pipeline.clone()),
+                |(codec, buf)| {
+                    while let Some(value) = codec.decode(buf).unwrap() {
+                        std::hint::black_box(value);
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    // a large value arriving a segment at a time, which has to be resumed rather than reparsed
+    let pipeline = encode(sets(512 * 1024).take(1));
+    group.throughput(Throughput::Bytes(pipeline.len() as u64));
+    group.bench_function("SET 512KiB in 16KiB reads", |b| {
+        b.iter_batched_ref(
+            || (RespFrame::default(), BytesMut::new()),
+            |(codec, buf)| {
+                for segment in pipeline.chunks(16 * 1024) {
+                    buf.extend_from_slice(segment);
+                    if let Some(value) = codec.decode(buf).unwrap() {
+                        std::hint::black_box(value);
+                    }
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn encode_replies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    let ok = vec![RedisValue::SimpleString("OK".into()); PIPELINE];
+    group.throughput(Throughput::Elements(PIPELINE as u64));
+    group.bench_function("pipelined OK", |b| {
+        b.iter_batched(|| ok.clone(), encode, BatchSize::SmallInput)
+    });
+    for len in [10, 1000] {
+        // an LRANGE or MGET reply
+        let reply = RedisValue::Array(
+            (0..len)
+                .map(|i| RedisValue::BulkString(format!("element:{i}").into()))
+                .collect(),
+        );
+        group.throughput(Throughput::Elements(len));
+        group.bench_function(format!("array of {len}"), |b| {
+            b.iter_batched(|| [reply.clone()], encode, BatchSize::SmallInput)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode, encode_replies);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+//! Arbitrary arguments for the built-in commands, through the parser the dispatcher uses
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bytes::Bytes;
+use codecrafters_redis::{fuzz, resp::RedisValue};
+use libfuzzer_sys::fuzz_target;
+
+/// An argument as a client could send it, not necessarily a bulk string
+#[derive(Arbitrary, Debug)]
+enum Arg {
+    Bulk(Vec<u8>),
+    /// A number, the likeliest thing for an argument to be parsed as
+    Number(i64),
+    Integer(i64),
+    Simple(String),
+    Null,
+    Array(Vec<Vec<u8>>),
+}
+
+impl From<Arg> for RedisValue {
+    fn from(arg: Arg) -> Self {
+        match arg {
+            Arg::Bulk(b) => RedisValue::BulkString(b.into()),
+            Arg::Number(n) => RedisValue::BulkString(n.to_string().into()),
+            Arg::Integer(i) => RedisValue::Integer(i),
+            Arg::Simple(s) => RedisValue::SimpleString(s.into()),
+            Arg::Null => RedisValue::NullBulkString,
+            Arg::Array(v) => RedisValue::command(v),
+        }
+    }
+}
+
+#[derive(Arbitrary, Debug)]
+struct Command {
+    /// Which built-in command, rather than hoping to spell one
+    name: u8,
+    args: Vec<Arg>,
+}
+
+fuzz_target!(|command: Command| {
+    let names: Vec<_> = fuzz::command_names().collect();
+    let name = names[command.name as usize % names.len()];
+    let frame = std::iter::once(RedisValue::BulkString(Bytes::from_static(name.as_bytes())))
+        .chain(command.args.into_iter().map(RedisValue::from))
+        .collect();
+    fuzz::parse_command(RedisValue::Array(frame));
+});
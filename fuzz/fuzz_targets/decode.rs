@@ -0,0 +1,26 @@
+//! Arbitrary bytes through the codec a connection reads commands with, in arbitrary reads
+#![no_main]
+
+use bytes::BytesMut;
+use codecrafters_redis::resp::codec::RespFrame;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+fuzz_target!(|input: (u8, &[u8])| {
+    let (chunk, data) = input;
+    let mut codec = RespFrame::default();
+    let mut buf = BytesMut::new();
+    for piece in data.chunks(chunk.max(1) as usize) {
+        buf.extend_from_slice(piece);
+        loop {
+            let before = buf.len();
+            match codec.decode(&mut buf) {
+                // every frame takes up some of the buffer, so decoding always makes progress
+                Ok(Some(_)) => assert!(buf.len() < before),
+                Ok(None) => break,
+                // the connection is dropped after a protocol error
+                Err(_) => return,
+            }
+        }
+    }
+});
@@ -0,0 +1,19 @@
+//! Arbitrary bytes into the incremental parser. A frame parsed as it trickles in must take up
+//! no more than the input and be the same frame as when it arrives at once.
+#![no_main]
+
+use codecrafters_redis::fuzz::parse_frame;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (u8, &[u8])| {
+    let (chunk, data) = input;
+    let whole = parse_frame(data, data.len());
+    if let Some((pos, _)) = &whole {
+        assert!(*pos <= data.len());
+    }
+    let pieces = parse_frame(data, chunk as usize);
+    // a frame cut short by the limits or a syntax error is rejected either way
+    if let (Some(whole), Some(pieces)) = (&whole, &pieces) {
+        assert_eq!(whole, pieces);
+    }
+});
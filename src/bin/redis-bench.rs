@@ -0,0 +1,288 @@
+//! A minimal redis-benchmark: clients run a mix of commands against any RESP server, pipelined,
+//! and the throughput and latency percentiles of each test are reported.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use codecrafters_redis::resp::{client::Client, RedisValue};
+use futures::SinkExt;
+
+const USAGE: &str = "\
+Usage: redis-bench [-h <host>] [-p <port>] [-c <clients>] [-n <requests>] [-P <pipeline>]
+                   [-d <size>] [-r <keyspace>] [-t <tests>] [--mix]
+
+  -h <host>      Server hostname [127.0.0.1]
+  -p <port>      Server port [6379]
+  -c <clients>   Parallel connections [50]
+  -n <requests>  Requests per test [100000]
+  -P <pipeline>  Requests each client sends before waiting for their replies [1]
+  -d <size>      Bytes in each value SET or LPUSH [3]
+  -r <keyspace>  Spread keys over this many random names instead of using one [0]
+  -t <tests>     Comma separated commands to test: ping,set,get,incr,lpush [all]
+  --mix          Run the tests together, interleaved, as a single test
+  --help         Show this help
+";
+
+/// A command to benchmark
+#[derive(Debug, Clone, Copy)]
+enum Test {
+    Ping,
+    Set,
+    Get,
+    Incr,
+    Lpush,
+}
+
+impl Test {
+    const ALL: [Test; 5] = [Test::Ping, Test::Set, Test::Get, Test::Incr, Test::Lpush];
+
+    fn name(self) -> &'static str {
+        match self {
+            Test::Ping => "PING",
+            Test::Set => "SET",
+            Test::Get => "GET",
+            Test::Incr => "INCR",
+            Test::Lpush => "LPUSH",
+        }
+    }
+
+    fn command(self, key: &str, value: &str) -> RedisValue {
+        match self {
+            Test::Ping => RedisValue::command(["PING"]),
+            Test::Set => RedisValue::command(["SET", key, value].map(str::to_string)),
+            Test::Get => RedisValue::command(["GET", key].map(str::to_string)),
+            Test::Incr => RedisValue::command(["INCR".to_string(), format!("counter:{key}")]),
+            Test::Lpush => RedisValue::command([
+                "LPUSH".to_string(),
+                format!("list:{key}"),
+                value.to_string(),
+            ]),
+        }
+    }
+}
+
+struct Options {
+    host: String,
+    port: u16,
+    clients: usize,
+    requests: usize,
+    pipeline: usize,
+    size: usize,
+    keyspace: u64,
+    tests: Vec<Test>,
+    mix: bool,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>> {
+        let mut options = Options {
+            host: "127.0.0.1".to_string(),
+            port: 6379,
+            clients: 50,
+            requests: 100_000,
+            pipeline: 1,
+            size: 3,
+            keyspace: 0,
+            tests: Test::ALL.to_vec(),
+            mix: false,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{arg} needs a value"));
+            let count = |value: String| -> Result<usize> {
+                match value.parse() {
+                    Ok(0) => Err(anyhow::anyhow!("{arg} must be at least 1")),
+                    parsed => parsed.with_context(|| format!("Invalid {arg}")),
+                }
+            };
+            match arg.as_str() {
+                "-h" => options.host = value()?,
+                "-p" => options.port = value()?.parse().context("Invalid port")?,
+                "-c" => options.clients = count(value()?)?,
+                "-n" => options.requests = count(value()?)?,
+                "-P" => options.pipeline = count(value()?)?,
+                "-d" => options.size = value()?.parse().context("Invalid size")?,
+                "-r" => options.keyspace = value()?.parse().context("Invalid keyspace")?,
+                "-t" => {
+                    options.tests = value()?
+                        .split(',')
+                        .map(|name| {
+                            Test::ALL
+                                .into_iter()
+                                .find(|test| test.name().eq_ignore_ascii_case(name))
+                                .with_context(|| format!("Unknown test {name}"))
+                        })
+                        .collect::<Result<_>>()?;
+                }
+                "--mix" => options.mix = true,
+                "--help" => return Ok(None),
+                _ => return Err(anyhow::anyhow!("Unknown option {arg}, see --help")),
+            }
+        }
+        Ok(Some(options))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let Some(options) = Options::parse(std::env::args().skip(1))? else {
+        print!("{USAGE}");
+        return Ok(());
+    };
+    let options = Arc::new(options);
+    let runs: Vec<Vec<Test>> = match options.mix {
+        true => vec![options.tests.clone()],
+        false => options.tests.iter().map(|&test| vec![test]).collect(),
+    };
+    for tests in runs {
+        run(options.clone(), tests).await?;
+    }
+    Ok(())
+}
+
+/// Run `tests` with every client, then report how it went
+async fn run(options: Arc<Options>, tests: Vec<Test>) -> Result<()> {
+    let addr = format!("{}:{}", options.host, options.port);
+    let tests = Arc::new(tests);
+    let mut connections = Vec::with_capacity(options.clients);
+    for _ in 0..options.clients {
+        let client = Client::connect(&addr)
+            .await
+            .with_context(|| format!("Could not connect to {addr}"))?;
+        connections.push(client);
+    }
+    // timed from when every client is connected
+    let started = Instant::now();
+    let mut clients = Vec::new();
+    for (c, client) in connections.into_iter().enumerate() {
+        // the requests split as evenly as they go
+        let requests = options.requests / options.clients
+            + usize::from(c < options.requests % options.clients);
+        clients.push(tokio::spawn(bench_client(
+            client,
+            options.clone(),
+            tests.clone(),
+            requests,
+            c as u64,
+        )));
+    }
+    let (mut latencies, mut errors) = (Vec::with_capacity(options.requests), 0);
+    for client in clients {
+        let (client_latencies, client_errors) = client.await??;
+        latencies.extend(client_latencies);
+        errors += client_errors;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort_unstable();
+
+    let names: Vec<_> = tests.iter().map(|test| test.name()).collect();
+    println!("====== {} ======", names.join(","));
+    println!(
+        "  {} requests completed in {:.2} seconds",
+        latencies.len(),
+        elapsed.as_secs_f64()
+    );
+    println!("  {} parallel clients", options.clients);
+    println!("  {} bytes payload", options.size);
+    println!("  pipeline of {}", options.pipeline);
+    if errors > 0 {
+        println!("  {errors} error replies");
+    }
+    println!(
+        "  throughput summary: {:.2} requests per second",
+        latencies.len() as f64 / elapsed.as_secs_f64()
+    );
+    let average = latencies.iter().sum::<Duration>() / latencies.len().max(1) as u32;
+    println!("  latency summary (msec):");
+    println!("          avg       min       p50       p95       p99       max");
+    println!(
+        "    {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>9.3}",
+        msec(average),
+        msec(percentile(&latencies, 0.0)),
+        msec(percentile(&latencies, 50.0)),
+        msec(percentile(&latencies, 95.0)),
+        msec(percentile(&latencies, 99.0)),
+        msec(percentile(&latencies, 100.0)),
+    );
+    println!();
+    Ok(())
+}
+
+/// Send `requests` commands in pipelines, returning how long each took to be answered and how
+/// many were answered with an error. A command's latency is that of its whole pipeline.
+async fn bench_client(
+    mut client: Client,
+    options: Arc<Options>,
+    tests: Arc<Vec<Test>>,
+    requests: usize,
+    id: u64,
+) -> Result<(Vec<Duration>, usize)> {
+    let value = "x".repeat(options.size);
+    let mut keys = Keys::new(options.keyspace, id);
+    let (mut latencies, mut errors) = (Vec::with_capacity(requests), 0);
+    let mut sent = 0;
+    while sent < requests {
+        let batch = options.pipeline.min(requests - sent);
+        let started = Instant::now();
+        let framed = client.framed_mut();
+        for i in 0..batch {
+            let test = tests[(sent + i) % tests.len()];
+            framed.feed(test.command(&keys.next(), &value)).await?;
+        }
+        framed.flush().await?;
+        for _ in 0..batch {
+            let reply = client
+                .receive()
+                .await?
+                .context("Server closed the connection")?;
+            errors += usize::from(matches!(reply, RedisValue::SimpleError(_)));
+        }
+        latencies.extend(std::iter::repeat_n(started.elapsed(), batch));
+        sent += batch;
+    }
+    Ok((latencies, errors))
+}
+
+/// The keys commands are sent for: always the same one, or random ones out of a keyspace
+struct Keys {
+    keyspace: u64,
+    /// xorshift state, never 0
+    state: u64,
+}
+
+impl Keys {
+    fn new(keyspace: u64, id: u64) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        Self {
+            keyspace,
+            state: (seed ^ id.wrapping_mul(0x9e37_79b9_7f4a_7c15)) | 1,
+        }
+    }
+
+    fn next(&mut self) -> String {
+        if self.keyspace == 0 {
+            return "key".to_string();
+        }
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        format!("key:{:012}", self.state % self.keyspace)
+    }
+}
+
+/// The latency `p` percent of `sorted` are at or under
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn msec(latency: Duration) -> f64 {
+    latency.as_secs_f64() * 1000.0
+}
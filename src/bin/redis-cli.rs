@@ -0,0 +1,176 @@
+//! A minimal redis-cli: runs the command given on the command line, the commands piped to it a
+//! line each, or an interactive prompt. `--pipe` bulk loads RESP read from stdin.
+
+use std::io::{IsTerminal, Write};
+
+use anyhow::{Context, Result};
+use codecrafters_redis::resp::{
+    cli::{self, split_line},
+    client::Client,
+    codec::RespFrame,
+    RedisValue,
+};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_util::codec::FramedRead;
+
+const USAGE: &str = "\
+Usage: redis-cli [-h <host>] [-p <port>] [-a <password>] [--pipe] [cmd [arg]...]
+
+  -h <host>      Server hostname [127.0.0.1]
+  -p <port>      Server port [6379]
+  -a <password>  Password to AUTH with
+  --pipe         Send the RESP commands read from stdin, as fast as possible
+  --help         Show this help
+
+With a command, run it and print its reply. Otherwise run the commands read from stdin, a
+line each, or prompt for them at a terminal.
+";
+
+struct Options {
+    host: String,
+    port: u16,
+    password: Option<String>,
+    pipe: bool,
+    command: Vec<String>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Option<Self>> {
+        let mut options = Options {
+            host: "127.0.0.1".to_string(),
+            port: 6379,
+            password: None,
+            pipe: false,
+            command: Vec::new(),
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{arg} needs a value"));
+            match arg.as_str() {
+                "-h" => options.host = value()?,
+                "-p" => options.port = value()?.parse().context("Invalid port")?,
+                "-a" => options.password = Some(value()?),
+                "--pipe" => options.pipe = true,
+                "--help" => return Ok(None),
+                _ => {
+                    // the command starts at the first argument that is no option
+                    options.command.push(arg);
+                    options.command.extend(args);
+                    break;
+                }
+            }
+        }
+        Ok(Some(options))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let Some(options) = Options::parse(std::env::args().skip(1))? else {
+        print!("{USAGE}");
+        return Ok(());
+    };
+    let addr = format!("{}:{}", options.host, options.port);
+    let mut client = Client::connect(&addr)
+        .await
+        .with_context(|| format!("Could not connect to Redis at {addr}"))?;
+    if let Some(password) = &options.password
+        && let RedisValue::SimpleError(e) = client.request(["AUTH", password]).await?
+    {
+        eprintln!("AUTH failed: {}", String::from_utf8_lossy(&e));
+    }
+
+    if options.pipe {
+        return pipe(client).await;
+    }
+    // people get replies spelled out, scripts get them raw
+    let pretty = std::io::stdout().is_terminal();
+    if !options.command.is_empty() {
+        let reply = client.request(&options.command).await?;
+        return print_reply(&reply, pretty);
+    }
+    let interactive = std::io::stdin().is_terminal();
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        if interactive {
+            print!("{addr}> ");
+            std::io::stdout().flush()?;
+        }
+        let Some(line) = lines.next_line().await? else {
+            break;
+        };
+        let args = match split_line(&line) {
+            Ok(args) => args,
+            Err(_) => {
+                eprintln!("Invalid argument(s)");
+                continue;
+            }
+        };
+        match args.first().map(|name| name.to_lowercase()).as_deref() {
+            None => continue,
+            Some("quit" | "exit") => break,
+            Some(_) => {}
+        }
+        let reply = client.request(&args).await?;
+        print_reply(&reply, pretty || interactive)?;
+    }
+    Ok(())
+}
+
+fn print_reply(reply: &RedisValue, pretty: bool) -> Result<()> {
+    let mut out = std::io::stdout().lock();
+    match pretty {
+        true => writeln!(out, "{}", cli::pretty(reply))?,
+        false => {
+            let mut raw = Vec::new();
+            cli::raw(reply, &mut raw);
+            out.write_all(&raw)?;
+        }
+    }
+    Ok(())
+}
+
+/// Send every command read from stdin without waiting for replies, then count the replies up
+/// to that of an ECHO sent last
+async fn pipe(client: Client) -> Result<()> {
+    let (mut commands, mut replies) = client.into_framed().split();
+    let marker = format!(
+        "pipe-end-{}-{}",
+        std::process::id(),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos()
+    );
+    let send = async {
+        let mut input = FramedRead::new(tokio::io::stdin(), RespFrame::default());
+        while let Some(command) = input.next().await {
+            commands.feed(command?).await?;
+        }
+        commands
+            .send(RedisValue::command(["ECHO".to_string(), marker.clone()]))
+            .await?;
+        eprintln!("All data transferred. Waiting for the last reply...");
+        anyhow::Ok(())
+    };
+    let receive = async {
+        let (mut count, mut errors) = (0, 0);
+        while let Some(reply) = replies.next().await {
+            match reply? {
+                RedisValue::BulkString(echoed) if echoed == marker.as_bytes() => {
+                    return Ok((count, errors));
+                }
+                RedisValue::SimpleError(e) => {
+                    eprintln!("{}", String::from_utf8_lossy(&e));
+                    errors += 1;
+                }
+                _ => {}
+            }
+            count += 1;
+        }
+        Err(anyhow::anyhow!("Server closed the connection"))
+    };
+    let ((), (count, errors)) = tokio::try_join!(send, receive)?;
+    println!("Last reply received from server.");
+    println!("errors: {errors}, replies: {count}");
+    Ok(())
+}
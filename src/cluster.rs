@@ -0,0 +1,1130 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+
+use crate::{error::RedisError, replication, resp::RedisValue};
+
+pub(crate) mod bus;
+mod slot;
+
+pub(crate) use slot::key_slot;
+
+use bus::{Gossip, Heartbeat, Kind, Message};
+
+/// Number of hash slots the keyspace is split into
+pub(crate) const SLOT_COUNT: usize = 16384;
+
+/// Offset from the client port to the cluster bus port, as in Redis
+pub(crate) const BUS_PORT_OFFSET: u16 = 10000;
+
+/// Longest we go without pinging a node, however long the node timeout
+const PING_INTERVAL_MAX: Duration = Duration::from_secs(1);
+
+/// Shortest time a handshake is given to complete, however short the node timeout
+const HANDSHAKE_TIMEOUT_MIN: Duration = Duration::from_secs(1);
+
+/// Failure reports expire after this many node timeouts, as in Redis
+const FAIL_REPORT_VALIDITY_MULT: u32 = 2;
+
+/// A failed node serving slots is trusted again this many node timeouts after failing
+const FAIL_UNDO_TIME_MULT: u32 = 2;
+
+/// A node in the cluster, as we know it
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ClusterNode {
+    pub(crate) id: String,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) bus_port: u16,
+}
+
+impl ClusterNode {
+    /// Where the node's cluster bus listens
+    pub(crate) fn bus_addr(&self) -> BusAddr {
+        (self.host.clone(), self.bus_port)
+    }
+}
+
+/// The host and port of a node's cluster bus
+pub(crate) type BusAddr = (String, u16);
+
+/// Whether a node answers, as far as we can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Health {
+    Ok,
+    /// It stopped answering our pings (Redis' PFAIL)
+    Unreachable,
+    /// Enough masters agree it stopped answering (Redis' FAIL)
+    Failed,
+}
+
+/// A known node and what we know of its health
+#[derive(Debug)]
+struct Node {
+    info: ClusterNode,
+
+    /// Epoch of the node's claim to its slots; of two claims to a slot, the higher one wins
+    config_epoch: u64,
+
+    health: Health,
+
+    /// When the oldest ping the node has not answered was sent
+    ping_sent: Option<Instant>,
+
+    /// When we last pinged the node, answered or not
+    last_ping: Option<Instant>,
+
+    pong_received: Option<Instant>,
+
+    fail_time: Option<Instant>,
+
+    /// Masters that told us the node looks down, by index, with when they last said so
+    fail_reports: HashMap<usize, Instant>,
+}
+
+impl Node {
+    fn new(info: ClusterNode) -> Self {
+        Self {
+            info,
+            config_epoch: 0,
+            health: Health::Ok,
+            ping_sent: None,
+            last_ping: None,
+            pong_received: None,
+            fail_time: None,
+            fail_reports: HashMap::new(),
+        }
+    }
+}
+
+/// A node met but not heard from yet, so known only by address
+#[derive(Debug)]
+struct Handshake {
+    host: String,
+    bus_port: u16,
+    started: Instant,
+    last_meet: Option<Instant>,
+}
+
+impl Handshake {
+    fn bus_addr(&self) -> BusAddr {
+        (self.host.clone(), self.bus_port)
+    }
+}
+
+/// The known nodes and which of them serves each slot
+struct Topology {
+    nodes: Vec<Node>,
+
+    /// Nodes being met, not part of the cluster until they answer
+    handshakes: Vec<Handshake>,
+
+    /// Highest epoch seen in the cluster
+    current_epoch: u64,
+
+    /// Index into `nodes` of the owner of every slot, `None` while unassigned
+    slots: Vec<Option<usize>>,
+
+    /// Slots we own that are being moved to another node, by that node's index
+    migrating: HashMap<u16, usize>,
+
+    /// Slots being moved to us from the node at the given index
+    importing: HashMap<u16, usize>,
+}
+
+impl Topology {
+    /// Contiguous `(first, last)` slot ranges owned by the node at `index`
+    fn ranges(&self, index: usize) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if *owner != Some(index) {
+                continue;
+            }
+            let slot = slot as u16;
+            match ranges.last_mut() {
+                Some((_, last)) if *last + 1 == slot => *last = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+
+    fn assigned(&self) -> usize {
+        self.slots.iter().filter(|s| s.is_some()).count()
+    }
+
+    /// Number of nodes serving at least one slot, whose majority decides a node failed
+    fn size(&self) -> usize {
+        let mut serving = vec![false; self.nodes.len()];
+        for owner in self.slots.iter().flatten() {
+            serving[*owner] = true;
+        }
+        serving.into_iter().filter(|&s| s).count()
+    }
+
+    fn index(&self, id: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.info.id == id)
+    }
+
+    fn addr(&self, index: usize) -> String {
+        let node = &self.nodes[index].info;
+        format!("{}:{}", node.host, node.port)
+    }
+
+    /// The slots owned by the node at `index` as a bitmap, slot 0 the lowest bit of the first byte
+    fn bitmap(&self, index: usize) -> Bytes {
+        let mut bitmap = vec![0u8; SLOT_COUNT / 8];
+        for (slot, owner) in self.slots.iter().enumerate() {
+            if *owner == Some(index) {
+                bitmap[slot / 8] |= 1 << (slot % 8);
+            }
+        }
+        bitmap.into()
+    }
+
+    fn add_node(&mut self, info: ClusterNode) -> usize {
+        self.nodes.push(Node::new(info));
+        self.nodes.len() - 1
+    }
+
+    /// Start meeting the node at `host:bus_port`, unless we already are
+    fn handshake(&mut self, host: String, bus_port: u16, now: Instant) {
+        if !self
+            .handshakes
+            .iter()
+            .any(|h| h.host == host && h.bus_port == bus_port)
+        {
+            self.handshakes.push(Handshake {
+                host,
+                bus_port,
+                started: now,
+                last_meet: None,
+            });
+        }
+    }
+
+    fn mark_failed(&mut self, index: usize, now: Instant) {
+        let node = &mut self.nodes[index];
+        if node.health != Health::Failed {
+            tracing::warn!("Cluster node {} failed", node.info.id);
+            node.health = Health::Failed;
+            node.fail_time = Some(now);
+        }
+    }
+}
+
+/// Why a command cannot be served here (Redis' `CLUSTER_REDIR_*` cases)
+#[derive(Debug, PartialEq)]
+pub(crate) enum Redirect {
+    /// The keys span several slots, so no single node can serve them
+    CrossSlot,
+    /// The slot lives elsewhere for good
+    Moved { slot: u16, addr: String },
+    /// The slot is being migrated and this key has already left (or not yet arrived)
+    Ask { slot: u16, addr: String },
+    /// Nobody serves the slot
+    Down,
+}
+
+impl fmt::Display for Redirect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CrossSlot => write!(f, "CROSSSLOT Keys in request don't hash to the same slot"),
+            Self::Moved { slot, addr } => write!(f, "MOVED {slot} {addr}"),
+            Self::Ask { slot, addr } => write!(f, "ASK {slot} {addr}"),
+            Self::Down => write!(f, "CLUSTERDOWN Hash slot not served"),
+        }
+    }
+}
+
+/// A change to a slot made with CLUSTER SETSLOT, nodes named by ID
+#[derive(Debug, PartialEq)]
+pub(crate) enum SetSlot {
+    /// Start moving a slot we own to the node
+    Migrating(String),
+    /// Start taking a slot over from the node
+    Importing(String),
+    /// Stop migrating or importing the slot
+    Stable,
+    /// Hand the slot to the node, ending a migration
+    Node(String),
+}
+
+/// Cluster membership and slot ownership, present only when cluster mode is enabled
+pub(crate) struct ClusterState {
+    /// Index of this node in the topology
+    myself: usize,
+
+    /// How long a node may leave a ping unanswered before it looks down
+    node_timeout: Duration,
+
+    topology: RwLock<Topology>,
+
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+}
+
+impl ClusterState {
+    /// Start a cluster of one: a fresh node ID that owns every slot
+    pub(crate) fn new(host: String, port: u16, bus_port: u16, node_timeout: Duration) -> Self {
+        let myself = ClusterNode {
+            id: replication::generate_id(),
+            host,
+            port,
+            bus_port,
+        };
+        Self {
+            myself: 0,
+            node_timeout,
+            topology: RwLock::new(Topology {
+                nodes: vec![Node::new(myself)],
+                handshakes: Vec::new(),
+                current_epoch: 0,
+                slots: vec![Some(0); SLOT_COUNT],
+                migrating: HashMap::new(),
+                importing: HashMap::new(),
+            }),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn myid(&self) -> String {
+        self.topology.read().unwrap().nodes[self.myself]
+            .info
+            .id
+            .clone()
+    }
+
+    /// The CLUSTER INFO report
+    pub(crate) fn info(&self) -> String {
+        let topology = self.topology.read().unwrap();
+        let assigned = topology.assigned();
+        let (mut pfail, mut fail) = (0, 0);
+        for owner in topology.slots.iter().flatten() {
+            match topology.nodes[*owner].health {
+                Health::Ok => {}
+                Health::Unreachable => pfail += 1,
+                Health::Failed => fail += 1,
+            }
+        }
+
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "cluster_state:{}\r\n\
+             cluster_slots_assigned:{assigned}\r\n\
+             cluster_slots_ok:{}\r\n\
+             cluster_slots_pfail:{pfail}\r\n\
+             cluster_slots_fail:{fail}\r\n\
+             cluster_known_nodes:{}\r\n\
+             cluster_size:{}\r\n\
+             cluster_current_epoch:{}\r\n\
+             cluster_my_epoch:{}\r\n\
+             cluster_stats_messages_sent:{}\r\n\
+             cluster_stats_messages_received:{}\r\n\
+             total_cluster_links_buffer_limit_exceeded:0\r\n",
+            if assigned == SLOT_COUNT && fail == 0 {
+                "ok"
+            } else {
+                "fail"
+            },
+            assigned - pfail - fail,
+            topology.nodes.len(),
+            topology.size(),
+            topology.current_epoch,
+            topology.nodes[self.myself].config_epoch,
+            self.messages_sent.load(Ordering::Relaxed),
+            self.messages_received.load(Ordering::Relaxed),
+        );
+        out
+    }
+
+    /// The CLUSTER NODES report, a line per node:
+    /// `id host:port@bus-port flags master ping-sent pong-received config-epoch link slots...`
+    pub(crate) fn nodes(&self) -> String {
+        let topology = self.topology.read().unwrap();
+        let mut out = String::new();
+        for (index, node) in topology.nodes.iter().enumerate() {
+            let info = &node.info;
+            let flags = match (index == self.myself, node.health) {
+                (true, _) => "myself,master",
+                (false, Health::Ok) => "master",
+                (false, Health::Unreachable) => "master,fail?",
+                (false, Health::Failed) => "master,fail",
+            };
+            let link = match index == self.myself || node.ping_sent.is_none() {
+                true => "connected",
+                false => "disconnected",
+            };
+            let _ = write!(
+                out,
+                "{} {}:{}@{} {flags} - {} {} {} {link}",
+                info.id,
+                info.host,
+                info.port,
+                info.bus_port,
+                unix_ms(node.ping_sent),
+                unix_ms(node.pong_received),
+                node.config_epoch,
+            );
+            for (first, last) in topology.ranges(index) {
+                let _ = match first == last {
+                    true => write!(out, " {first}"),
+                    false => write!(out, " {first}-{last}"),
+                };
+            }
+            if index == self.myself {
+                let mut moving: Vec<_> = topology
+                    .migrating
+                    .iter()
+                    .map(|(slot, &to)| (*slot, "->-", to))
+                    .chain(
+                        topology
+                            .importing
+                            .iter()
+                            .map(|(slot, &from)| (*slot, "-<-", from)),
+                    )
+                    .collect();
+                moving.sort();
+                for (slot, arrow, other) in moving {
+                    let _ = write!(out, " [{slot}{arrow}{}]", topology.nodes[other].info.id);
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The CLUSTER SLOTS reply: `[first, last, [host, port, id]]` for every owned range
+    pub(crate) fn slots(&self) -> RedisValue {
+        let topology = self.topology.read().unwrap();
+        let mut reply = Vec::new();
+        for (index, node) in topology.nodes.iter().enumerate() {
+            let node = &node.info;
+            for (first, last) in topology.ranges(index) {
+                reply.push(RedisValue::Array(vec![
+                    RedisValue::Integer(first as i64),
+                    RedisValue::Integer(last as i64),
+                    RedisValue::Array(vec![
+                        RedisValue::BulkString(node.host.clone().into()),
+                        RedisValue::Integer(node.port as i64),
+                        RedisValue::BulkString(node.id.clone().into()),
+                    ]),
+                ]));
+            }
+        }
+        // clients expect ranges in slot order
+        reply.sort_by_key(|range| match range {
+            RedisValue::Array(fields) => match fields.first() {
+                Some(RedisValue::Integer(first)) => *first,
+                _ => 0,
+            },
+            _ => 0,
+        });
+        RedisValue::Array(reply)
+    }
+
+    /// The CLUSTER SHARDS reply, with one single-master shard per node
+    pub(crate) fn shards(&self) -> RedisValue {
+        let topology = self.topology.read().unwrap();
+        let bulk = |s: &str| RedisValue::BulkString(s.to_string().into());
+        let shards = topology
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(index, node)| {
+                let slots = topology
+                    .ranges(index)
+                    .into_iter()
+                    .flat_map(|(first, last)| {
+                        [
+                            RedisValue::Integer(first as i64),
+                            RedisValue::Integer(last as i64),
+                        ]
+                    })
+                    .collect();
+                let health = match node.health {
+                    Health::Failed => "failed",
+                    _ => "online",
+                };
+                let node = &node.info;
+                let node = RedisValue::Map(vec![
+                    (bulk("id"), bulk(&node.id)),
+                    (bulk("port"), RedisValue::Integer(node.port as i64)),
+                    (bulk("ip"), bulk(&node.host)),
+                    (bulk("endpoint"), bulk(&node.host)),
+                    (bulk("role"), bulk("master")),
+                    (bulk("replication-offset"), RedisValue::Integer(0)),
+                    (bulk("health"), bulk(health)),
+                ]);
+                RedisValue::Map(vec![
+                    (bulk("slots"), RedisValue::Array(slots)),
+                    (bulk("nodes"), RedisValue::Array(vec![node])),
+                ])
+            })
+            .collect();
+        RedisValue::Array(shards)
+    }
+
+    /// Decide whether a command on `keys` may run here, see [`Self::route`]
+    pub(crate) fn route_keys(
+        &self,
+        keys: &[&Bytes],
+        asking: bool,
+        missing_keys: bool,
+    ) -> Result<(), Redirect> {
+        let Some(first) = keys.first() else {
+            return Ok(());
+        };
+        let slot = key_slot(first);
+        if keys.iter().any(|key| key_slot(key) != slot) {
+            return Err(Redirect::CrossSlot);
+        }
+        self.route(slot, asking, missing_keys)
+    }
+
+    /// Decide whether a command on keys in `slot` may run here.
+    ///
+    /// `asking` is set when the client sent ASKING just before, and `missing_keys` when any of
+    /// the command's keys does not exist locally; together they decide where a key in a slot
+    /// being migrated lives.
+    pub(crate) fn route(
+        &self,
+        slot: u16,
+        asking: bool,
+        missing_keys: bool,
+    ) -> Result<(), Redirect> {
+        let topology = self.topology.read().unwrap();
+        match topology.slots[slot as usize] {
+            Some(owner) if owner == self.myself => match topology.migrating.get(&slot) {
+                Some(&target) if missing_keys => Err(Redirect::Ask {
+                    slot,
+                    addr: topology.addr(target),
+                }),
+                _ => Ok(()),
+            },
+            _ if asking && topology.importing.contains_key(&slot) => Ok(()),
+            Some(owner) => Err(Redirect::Moved {
+                slot,
+                addr: topology.addr(owner),
+            }),
+            None => Err(Redirect::Down),
+        }
+    }
+
+    /// Start meeting the node whose cluster bus listens at `host:bus_port`. It joins the cluster
+    /// once it answers, and the rest of the cluster learns of it by gossip.
+    pub(crate) fn meet(&self, host: String, bus_port: u16) {
+        self.topology
+            .write()
+            .unwrap()
+            .handshake(host, bus_port, Instant::now());
+    }
+
+    /// Apply CLUSTER SETSLOT to `slot`. `holds_keys` tells whether any key in the slot is still
+    /// stored here, which keeps us from giving the slot away.
+    pub(crate) fn set_slot(
+        &self,
+        slot: u16,
+        action: SetSlot,
+        holds_keys: impl FnOnce() -> bool,
+    ) -> Result<(), RedisError> {
+        let mut topology = self.topology.write().unwrap();
+        let known = |topology: &Topology, id: &str| {
+            topology
+                .index(id)
+                .ok_or_else(|| RedisError::other(format!("I don't know about node {id}")))
+        };
+        let owned = topology.slots[slot as usize] == Some(self.myself);
+        match action {
+            SetSlot::Migrating(_) if !owned => Err(RedisError::other(format!(
+                "I'm not the owner of hash slot {slot}"
+            ))),
+            SetSlot::Migrating(id) => {
+                let target = known(&topology, &id)?;
+                topology.migrating.insert(slot, target);
+                Ok(())
+            }
+            SetSlot::Importing(_) if owned => Err(RedisError::other(format!(
+                "I'm already the owner of hash slot {slot}"
+            ))),
+            SetSlot::Importing(id) => {
+                let source = known(&topology, &id)?;
+                topology.importing.insert(slot, source);
+                Ok(())
+            }
+            SetSlot::Stable => {
+                topology.migrating.remove(&slot);
+                topology.importing.remove(&slot);
+                Ok(())
+            }
+            SetSlot::Node(id) => {
+                let owner = topology
+                    .index(&id)
+                    .ok_or_else(|| RedisError::other(format!("Unknown node {id}")))?;
+                let holds_keys = holds_keys();
+                if owned && owner != self.myself && holds_keys {
+                    return Err(RedisError::other(format!(
+                        "Can't assign hashslot {slot} to a different node while I still hold \
+                         keys for this hash slot."
+                    )));
+                }
+                // the last key moved, so the migration is over
+                if !holds_keys {
+                    topology.migrating.remove(&slot);
+                }
+                topology.slots[slot as usize] = Some(owner);
+                // our claim must beat the old owner's, so like Redis we take a new config epoch
+                // without asking the cluster to vote on it
+                if owner == self.myself && topology.importing.remove(&slot).is_some() {
+                    topology.current_epoch += 1;
+                    topology.nodes[self.myself].config_epoch = topology.current_epoch;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// This node's ID, address and bus port
+    pub(crate) fn myself(&self) -> ClusterNode {
+        self.topology.read().unwrap().nodes[self.myself]
+            .info
+            .clone()
+    }
+
+    /// Our PING, PONG or MEET: our epochs, the slots we claim and what we know of the others
+    fn heartbeat(&self, topology: &Topology, kind: Kind) -> Message {
+        let myself = &topology.nodes[self.myself];
+        Message::Heartbeat(Heartbeat {
+            kind,
+            sender: myself.info.clone(),
+            current_epoch: topology.current_epoch,
+            config_epoch: myself.config_epoch,
+            slots: topology.bitmap(self.myself),
+            gossip: topology
+                .nodes
+                .iter()
+                .enumerate()
+                .filter(|&(index, _)| index != self.myself)
+                .map(|(_, node)| Gossip {
+                    node: node.info.clone(),
+                    failing: node.health != Health::Ok,
+                })
+                .collect(),
+        })
+    }
+
+    /// The periodic work of the cluster bus at `now`: pinging nodes and those being met, and
+    /// deciding which nodes are down. Returns the messages to send and where.
+    pub(crate) fn tick(&self, now: Instant) -> Vec<(BusAddr, Message)> {
+        let mut topology = self.topology.write().unwrap();
+        let timeout = self.node_timeout;
+        let interval = (timeout / 2).min(PING_INTERVAL_MAX);
+        let due = |last: Option<Instant>| last.is_none_or(|last| now - last >= interval);
+        let mut out = Vec::new();
+
+        let meet = self.heartbeat(&topology, Kind::Meet);
+        topology
+            .handshakes
+            .retain(|handshake| now - handshake.started < timeout.max(HANDSHAKE_TIMEOUT_MIN));
+        for handshake in &mut topology.handshakes {
+            if due(handshake.last_meet) {
+                handshake.last_meet = Some(now);
+                out.push((handshake.bus_addr(), meet.clone()));
+            }
+        }
+
+        let ping = self.heartbeat(&topology, Kind::Ping);
+        for (index, node) in topology.nodes.iter_mut().enumerate() {
+            if index == self.myself {
+                continue;
+            }
+            if due(node.last_ping) {
+                node.last_ping = Some(now);
+                node.ping_sent.get_or_insert(now);
+                out.push((node.info.bus_addr(), ping.clone()));
+            }
+            if node.health == Health::Ok && node.ping_sent.is_some_and(|sent| now - sent > timeout)
+            {
+                tracing::info!("Cluster node {} is not answering", node.info.id);
+                node.health = Health::Unreachable;
+            }
+            node.fail_reports
+                .retain(|_, &mut at| now - at <= timeout * FAIL_REPORT_VALIDITY_MULT);
+        }
+
+        // down for good once most of the nodes serving slots agree, counting ourselves
+        let quorum = topology.size() / 2 + 1;
+        for index in 0..topology.nodes.len() {
+            let node = &topology.nodes[index];
+            if node.health != Health::Unreachable || node.fail_reports.len() + 1 < quorum {
+                continue;
+            }
+            topology.mark_failed(index, now);
+            let fail = Message::Fail {
+                sender: topology.nodes[self.myself].info.id.clone(),
+                failed: topology.nodes[index].info.id.clone(),
+            };
+            for (other, node) in topology.nodes.iter().enumerate() {
+                if other != self.myself {
+                    out.push((node.info.bus_addr(), fail.clone()));
+                }
+            }
+        }
+
+        self.messages_sent
+            .fetch_add(out.len() as u64, Ordering::Relaxed);
+        out
+    }
+
+    /// Handle a message from the bus at `now`. `link` is the address we dialed when it came over
+    /// a connection we opened, so a PONG there answers our MEET. Returns the reply, if any.
+    pub(crate) fn receive(
+        &self,
+        message: Message,
+        link: Option<&BusAddr>,
+        now: Instant,
+    ) -> Option<Message> {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+        let mut topology = self.topology.write().unwrap();
+        let heartbeat = match message {
+            Message::Fail { failed, .. } => {
+                if let Some(index) = topology.index(&failed)
+                    && index != self.myself
+                {
+                    topology.mark_failed(index, now);
+                }
+                return None;
+            }
+            Message::Heartbeat(heartbeat) => heartbeat,
+        };
+
+        let myid = &topology.nodes[self.myself].info.id;
+        let sender = match (topology.index(&heartbeat.sender.id), heartbeat.kind, link) {
+            // we met ourselves under another address
+            _ if heartbeat.sender.id == *myid => {
+                if let Some(link) = link {
+                    topology.handshakes.retain(|h| h.bus_addr() != *link);
+                }
+                None
+            }
+            (None, Kind::Meet, _) => {
+                tracing::info!("Met by cluster node {}", heartbeat.sender.id);
+                Some(topology.add_node(heartbeat.sender.clone()))
+            }
+            (sender, Kind::Pong, Some(link)) => {
+                match topology
+                    .handshakes
+                    .iter()
+                    .position(|h| h.bus_addr() == *link)
+                {
+                    Some(handshake) => {
+                        // reached at the address we dialed, whatever it calls itself
+                        let handshake = topology.handshakes.remove(handshake);
+                        let info = ClusterNode {
+                            id: heartbeat.sender.id.clone(),
+                            host: handshake.host,
+                            port: heartbeat.sender.port,
+                            bus_port: handshake.bus_port,
+                        };
+                        tracing::info!("Met cluster node {} at {}", info.id, link.0);
+                        match sender {
+                            Some(index) => {
+                                topology.nodes[index].info = info;
+                                Some(index)
+                            }
+                            None => Some(topology.add_node(info)),
+                        }
+                    }
+                    None => sender,
+                }
+            }
+            (sender, ..) => sender,
+        };
+
+        let kind = heartbeat.kind;
+        if let Some(sender) = sender {
+            self.update(&mut topology, sender, heartbeat, now);
+        }
+        match kind {
+            Kind::Ping | Kind::Meet => {
+                self.messages_sent.fetch_add(1, Ordering::Relaxed);
+                Some(self.heartbeat(&topology, Kind::Pong))
+            }
+            Kind::Pong => None,
+        }
+    }
+
+    /// Learn from a heartbeat of the known node at `sender`
+    fn update(&self, topology: &mut Topology, sender: usize, heartbeat: Heartbeat, now: Instant) {
+        topology.current_epoch = topology.current_epoch.max(heartbeat.current_epoch);
+        let serving = topology.slots.contains(&Some(sender));
+        let node = &mut topology.nodes[sender];
+        node.config_epoch = node.config_epoch.max(heartbeat.config_epoch);
+        if heartbeat.kind == Kind::Pong {
+            node.pong_received = Some(now);
+            node.ping_sent = None;
+            // a failed node serving slots stays failed a while, so it can't flap
+            let undo = self.node_timeout * FAIL_UNDO_TIME_MULT;
+            let recovered = match node.health {
+                Health::Ok => false,
+                Health::Unreachable => true,
+                Health::Failed => !serving || node.fail_time.is_none_or(|at| now - at > undo),
+            };
+            if recovered {
+                tracing::info!("Cluster node {} is reachable again", node.info.id);
+                node.health = Health::Ok;
+                node.fail_time = None;
+            }
+        }
+
+        // a claim to a slot wins over an older one, unless we are taking the slot over
+        let epoch = node.config_epoch;
+        let mut lost = 0;
+        for slot in 0..SLOT_COUNT {
+            let claimed = heartbeat
+                .slots
+                .get(slot / 8)
+                .is_some_and(|byte| byte & 1 << (slot % 8) != 0);
+            let owner = topology.slots[slot];
+            if !claimed
+                || owner == Some(sender)
+                || topology.importing.contains_key(&(slot as u16))
+                || owner.is_some_and(|owner| topology.nodes[owner].config_epoch >= epoch)
+            {
+                continue;
+            }
+            if owner == Some(self.myself) {
+                topology.migrating.remove(&(slot as u16));
+                lost += 1;
+            }
+            topology.slots[slot] = Some(sender);
+        }
+        if lost > 0 {
+            tracing::warn!(
+                "Node {} took {lost} slots over from us with config epoch {epoch}",
+                heartbeat.sender.id
+            );
+        }
+
+        // of two masters claiming with the same epoch, the one with the smaller ID moves on
+        let myself = &topology.nodes[self.myself];
+        if epoch == myself.config_epoch && heartbeat.sender.id > myself.info.id {
+            topology.current_epoch += 1;
+            topology.nodes[self.myself].config_epoch = topology.current_epoch;
+            tracing::info!(
+                "Config epoch collision with node {}, now at config epoch {}",
+                heartbeat.sender.id,
+                topology.current_epoch
+            );
+        }
+
+        for Gossip { node, failing } in heartbeat.gossip {
+            if node.id == topology.nodes[self.myself].info.id {
+                continue;
+            }
+            match topology.index(&node.id) {
+                Some(index) => {
+                    let reports = &mut topology.nodes[index].fail_reports;
+                    match failing {
+                        true => reports.insert(sender, now),
+                        false => reports.remove(&sender),
+                    };
+                }
+                None if !failing => topology.handshake(node.host, node.bus_port, now),
+                None => {}
+            }
+        }
+    }
+}
+
+/// `at` in Unix milliseconds as CLUSTER NODES shows times, 0 for never
+fn unix_ms(at: Option<Instant>) -> u128 {
+    at.and_then(|at| {
+        (SystemTime::now() - at.elapsed())
+            .duration_since(UNIX_EPOCH)
+            .ok()
+    })
+    .map_or(0, |since| since.as_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn single_node_owns_every_slot() {
+        let cluster = ClusterState::new("127.0.0.1".into(), 7000, 17000, TIMEOUT);
+        assert_eq!(cluster.myid().len(), 40);
+        assert_eq!(cluster.myself().bus_port, 17000);
+        assert!(cluster.info().starts_with("cluster_state:ok\r\n"));
+        assert!(cluster.info().contains("cluster_slots_assigned:16384\r\n"));
+
+        let RedisValue::Array(ranges) = cluster.slots() else {
+            panic!("expected an array");
+        };
+        assert_eq!(
+            ranges,
+            vec![RedisValue::Array(vec![
+                RedisValue::Integer(0),
+                RedisValue::Integer(16383),
+                RedisValue::Array(vec![
+                    RedisValue::BulkString("127.0.0.1".into()),
+                    RedisValue::Integer(7000),
+                    RedisValue::BulkString(cluster.myid().into()),
+                ]),
+            ])]
+        );
+    }
+
+    #[test]
+    fn ranges_split_on_gaps() {
+        let topology = Topology {
+            nodes: Vec::new(),
+            handshakes: Vec::new(),
+            current_epoch: 0,
+            slots: (0..SLOT_COUNT)
+                .map(|slot| (slot % 100 != 50).then_some(0))
+                .collect(),
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        };
+        let ranges = topology.ranges(0);
+        assert_eq!(ranges.first(), Some(&(0, 49)));
+        assert_eq!(ranges.get(1), Some(&(51, 149)));
+        assert_eq!(topology.assigned(), SLOT_COUNT - 164);
+    }
+
+    #[test]
+    fn slots_migrate_between_nodes() {
+        let (source, target) = (
+            ClusterState::new("127.0.0.1".into(), 7000, 17000, TIMEOUT),
+            ClusterState::new("127.0.0.1".into(), 7001, 17001, TIMEOUT),
+        );
+        for (node, other) in [(&source, &target), (&target, &source)] {
+            node.topology.write().unwrap().add_node(other.myself());
+        }
+        let no_keys = || false;
+
+        // the target must first hear the slot is the source's
+        assert!(target
+            .set_slot(5, SetSlot::Importing(source.myid()), no_keys)
+            .is_err());
+        target
+            .set_slot(5, SetSlot::Node(source.myid()), no_keys)
+            .unwrap();
+        target
+            .set_slot(5, SetSlot::Importing(source.myid()), no_keys)
+            .unwrap();
+        assert_eq!(
+            source
+                .set_slot(5, SetSlot::Migrating("unknown".into()), no_keys)
+                .map_err(|e| e.to_string()),
+            Err("ERR I don't know about node unknown".to_string())
+        );
+        source
+            .set_slot(5, SetSlot::Migrating(target.myid()), no_keys)
+            .unwrap();
+        assert!(matches!(
+            source.route(5, false, true),
+            Err(Redirect::Ask { slot: 5, .. })
+        ));
+        assert_eq!(target.route(5, true, true), Ok(()));
+
+        // the slot can't be handed over until its last key has left
+        assert!(source
+            .set_slot(5, SetSlot::Node(target.myid()), || true)
+            .is_err());
+        for node in [&source, &target] {
+            node.set_slot(5, SetSlot::Node(target.myid()), no_keys)
+                .unwrap();
+        }
+        assert!(matches!(
+            source.route(5, false, false),
+            Err(Redirect::Moved { slot: 5, .. })
+        ));
+        assert_eq!(target.route(5, false, false), Ok(()));
+        assert!(target.info().contains("cluster_current_epoch:1\r\n"));
+        assert!(target.info().contains("cluster_my_epoch:1\r\n"));
+        assert!(source.topology.read().unwrap().migrating.is_empty());
+        assert!(target.topology.read().unwrap().importing.is_empty());
+    }
+
+    #[test]
+    fn redirects() {
+        let cluster = ClusterState::new("127.0.0.1".into(), 7000, 17000, TIMEOUT);
+        {
+            let mut topology = cluster.topology.write().unwrap();
+            topology.add_node(ClusterNode {
+                id: replication::generate_id(),
+                host: "127.0.0.1".into(),
+                port: 7001,
+                bus_port: 17001,
+            });
+            topology.slots[1] = Some(1);
+            topology.slots[2] = None;
+            topology.migrating.insert(3, 1);
+            topology.importing.insert(1, 1);
+        }
+        let other = "127.0.0.1:7001".to_string();
+
+        assert_eq!(cluster.route(0, false, true), Ok(()));
+        assert_eq!(
+            cluster.route(1, false, false),
+            Err(Redirect::Moved {
+                slot: 1,
+                addr: other.clone()
+            })
+        );
+        assert_eq!(cluster.route(1, true, false), Ok(()));
+        assert_eq!(cluster.route(2, false, false), Err(Redirect::Down));
+        assert_eq!(cluster.route(3, false, false), Ok(()));
+        assert_eq!(
+            cluster.route(3, false, true),
+            Err(Redirect::Ask {
+                slot: 3,
+                addr: other
+            })
+        );
+        assert_eq!(
+            cluster.route_keys(&[&"foo".into(), &"bar".into()], false, false),
+            Err(Redirect::CrossSlot)
+        );
+        assert_eq!(
+            cluster.route_keys(&[&"foo".into(), &"foo".into()], false, false),
+            Ok(())
+        );
+        assert_eq!(
+            Redirect::Moved {
+                slot: 1,
+                addr: "h:1".into()
+            }
+            .to_string(),
+            "MOVED 1 h:1"
+        );
+    }
+
+    /// Deliver what each of `nodes` sends at `now`, and the replies. Nodes left out are down.
+    fn exchange(nodes: &[&ClusterState], now: Instant) {
+        for node in nodes {
+            for (addr, message) in node.tick(now) {
+                let Some(peer) = nodes.iter().find(|peer| peer.myself().bus_port == addr.1) else {
+                    continue;
+                };
+                if let Some(reply) = peer.receive(message, None, now) {
+                    node.receive(reply, Some(&addr), now);
+                }
+            }
+        }
+    }
+
+    fn cluster(count: u16) -> Vec<ClusterState> {
+        (0..count)
+            .map(|i| ClusterState::new("127.0.0.1".into(), 7000 + i, 17000 + i, TIMEOUT))
+            .collect()
+    }
+
+    #[test]
+    fn nodes_meet_and_agree_on_slots() {
+        let nodes = cluster(3);
+        let nodes: Vec<_> = nodes.iter().collect();
+        // the third node only hears of the first through gossip
+        nodes[0].meet("127.0.0.1".into(), 17001);
+        nodes[1].meet("127.0.0.1".into(), 17002);
+        let mut now = Instant::now();
+        for _ in 0..10 {
+            exchange(&nodes, now);
+            now += TIMEOUT / 2;
+        }
+
+        // each started out owning every slot, and the claim with the highest epoch won them all
+        for node in &nodes {
+            assert!(node.info().contains("cluster_known_nodes:3\r\n"));
+            assert!(node.info().starts_with("cluster_state:ok\r\n"));
+            assert_eq!(node.nodes().lines().count(), 3);
+            assert_eq!(node.slots(), nodes[0].slots());
+        }
+        let RedisValue::Array(ranges) = nodes[0].slots() else {
+            panic!("expected an array");
+        };
+        assert_eq!(ranges.len(), 1);
+        let epochs: Vec<_> = nodes
+            .iter()
+            .map(|node| node.topology.read().unwrap().nodes[0].config_epoch)
+            .collect();
+        assert!(epochs
+            .iter()
+            .all(|&epoch| epochs.iter().filter(|&&e| e == epoch).count() == 1));
+    }
+
+    #[test]
+    fn failures_need_a_majority_and_heal() {
+        let nodes = cluster(3);
+        let nodes: Vec<_> = nodes.iter().collect();
+        // a third of the slots each, so two must agree the third is down
+        for (i, node) in nodes.iter().enumerate() {
+            node.topology.write().unwrap().slots = (0..SLOT_COUNT)
+                .map(|slot| (slot * 3 / SLOT_COUNT == i).then_some(0))
+                .collect();
+        }
+        nodes[0].meet("127.0.0.1".into(), 17001);
+        nodes[0].meet("127.0.0.1".into(), 17002);
+        let mut now = Instant::now();
+        for _ in 0..10 {
+            exchange(&nodes, now);
+            now += TIMEOUT / 2;
+        }
+        assert!(nodes[1].info().contains("cluster_size:3\r\n"));
+        assert!(nodes[1].info().starts_with("cluster_state:ok\r\n"));
+
+        // alone, a node finds the others unreachable but can't tell they failed
+        for _ in 0..4 {
+            nodes[0].tick(now);
+            now += TIMEOUT / 2;
+        }
+        let third = nodes[2].myid();
+        let line = |node: &ClusterState| {
+            let nodes = node.nodes();
+            nodes
+                .lines()
+                .find(|line| line.starts_with(&third))
+                .unwrap()
+                .to_string()
+        };
+        assert!(line(nodes[0]).contains(" master,fail? "));
+        assert!(nodes[0].info().contains("cluster_slots_pfail:10922\r\n"));
+
+        // once the second agrees, it has failed
+        for _ in 0..4 {
+            exchange(&nodes[..2], now);
+            now += TIMEOUT / 2;
+        }
+        for node in &nodes[..2] {
+            assert!(line(node).contains(" master,fail "));
+            assert!(node.info().starts_with("cluster_state:fail\r\n"));
+            assert!(node.info().contains("cluster_slots_fail:5461\r\n"));
+        }
+
+        // back, it serves its slots again once it has been failed a while
+        exchange(&nodes, now);
+        assert!(line(nodes[0]).contains(" master,fail "));
+        for _ in 0..5 {
+            now += TIMEOUT / 2;
+            exchange(&nodes, now);
+        }
+        for node in &nodes {
+            assert!(node.info().starts_with("cluster_state:ok\r\n"));
+        }
+    }
+}
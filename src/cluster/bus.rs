@@ -0,0 +1,353 @@
+//! The cluster bus: nodes ping each other on a port of their own, gossip about the nodes they
+//! know and spread failures. Messages are RESP arrays rather than Redis' binary format, so only
+//! nodes of this server can form a cluster together.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_util::{
+    codec::{FramedRead, FramedWrite},
+    sync::CancellationToken,
+};
+
+use crate::{
+    cluster::{BusAddr, ClusterNode, ClusterState},
+    resp::{client::Client, codec::RespFrame, RedisValue},
+    server::tasks,
+};
+
+/// How often the cluster cron runs, as in Redis
+const CRON_PERIOD: Duration = Duration::from_millis(100);
+
+/// Time a node has to accept a connection to its bus
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Ping,
+    Pong,
+    /// A PING that also asks the receiver to add us to the nodes it knows
+    Meet,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Ping => "PING",
+            Self::Pong => "PONG",
+            Self::Meet => "MEET",
+        }
+    }
+}
+
+/// A PING, PONG or MEET, carrying the sender's view of the cluster
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Heartbeat {
+    pub(crate) kind: Kind,
+    pub(crate) sender: ClusterNode,
+    pub(crate) current_epoch: u64,
+    pub(crate) config_epoch: u64,
+    /// Bitmap of the slots the sender owns, slot 0 the lowest bit of the first byte
+    pub(crate) slots: Bytes,
+    /// The other nodes the sender knows
+    pub(crate) gossip: Vec<Gossip>,
+}
+
+/// What a heartbeat tells of another node
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Gossip {
+    pub(crate) node: ClusterNode,
+    /// Whether the sender finds the node down
+    pub(crate) failing: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Message {
+    Heartbeat(Heartbeat),
+    /// The node `failed` is down, as enough of the cluster agrees
+    Fail {
+        sender: String,
+        failed: String,
+    },
+}
+
+impl Message {
+    /// The message as sent: `[kind, id, host, port, bus-port, current-epoch, config-epoch,
+    /// slots, [[id, host, port, bus-port, failing]...]]` for a heartbeat, or
+    /// `[FAIL, sender, failed]`
+    fn encode(&self) -> RedisValue {
+        let bulk = |s: &str| RedisValue::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+        let node = |node: &ClusterNode| {
+            vec![
+                bulk(&node.id),
+                bulk(&node.host),
+                RedisValue::Integer(node.port as i64),
+                RedisValue::Integer(node.bus_port as i64),
+            ]
+        };
+        match self {
+            Self::Heartbeat(heartbeat) => {
+                let mut fields = vec![bulk(heartbeat.kind.name())];
+                fields.extend(node(&heartbeat.sender));
+                fields.extend([
+                    RedisValue::Integer(heartbeat.current_epoch as i64),
+                    RedisValue::Integer(heartbeat.config_epoch as i64),
+                    RedisValue::BulkString(heartbeat.slots.clone()),
+                    RedisValue::Array(
+                        heartbeat
+                            .gossip
+                            .iter()
+                            .map(|gossip| {
+                                let mut fields = node(&gossip.node);
+                                fields.push(RedisValue::Integer(gossip.failing as i64));
+                                RedisValue::Array(fields)
+                            })
+                            .collect(),
+                    ),
+                ]);
+                RedisValue::Array(fields)
+            }
+            Self::Fail { sender, failed } => {
+                RedisValue::Array(vec![bulk("FAIL"), bulk(sender), bulk(failed)])
+            }
+        }
+    }
+
+    fn decode(value: RedisValue) -> Result<Self> {
+        let RedisValue::Array(fields) = value else {
+            bail!("Expected an array");
+        };
+        let mut fields = fields.into_iter();
+        let kind = match string(fields.next())?.as_str() {
+            "FAIL" => {
+                return Ok(Self::Fail {
+                    sender: string(fields.next())?,
+                    failed: string(fields.next())?,
+                });
+            }
+            "PING" => Kind::Ping,
+            "PONG" => Kind::Pong,
+            "MEET" => Kind::Meet,
+            other => bail!("Unknown message {other}"),
+        };
+        let sender = node(&mut fields)?;
+        let current_epoch = integer(fields.next())?;
+        let config_epoch = integer(fields.next())?;
+        let Some(RedisValue::BulkString(slots)) = fields.next() else {
+            bail!("Expected a slot bitmap");
+        };
+        let Some(RedisValue::Array(gossip)) = fields.next() else {
+            bail!("Expected gossip");
+        };
+        let gossip = gossip
+            .into_iter()
+            .map(|entry| {
+                let RedisValue::Array(fields) = entry else {
+                    bail!("Expected a gossip entry");
+                };
+                let mut fields = fields.into_iter();
+                Ok(Gossip {
+                    node: node(&mut fields)?,
+                    failing: integer::<u8>(fields.next())? != 0,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self::Heartbeat(Heartbeat {
+            kind,
+            sender,
+            current_epoch,
+            config_epoch,
+            slots,
+            gossip,
+        }))
+    }
+}
+
+fn string(field: Option<RedisValue>) -> Result<String> {
+    match field {
+        Some(RedisValue::BulkString(s)) => Ok(String::from_utf8(s.to_vec())?),
+        _ => bail!("Expected a string"),
+    }
+}
+
+fn integer<T: TryFrom<i64>>(field: Option<RedisValue>) -> Result<T> {
+    match field {
+        Some(RedisValue::Integer(i)) => T::try_from(i).map_err(|_| anyhow!("{i} out of range")),
+        _ => bail!("Expected an integer"),
+    }
+}
+
+fn node(fields: &mut impl Iterator<Item = RedisValue>) -> Result<ClusterNode> {
+    Ok(ClusterNode {
+        id: string(fields.next())?,
+        host: string(fields.next())?,
+        port: integer(fields.next())?,
+        bus_port: integer(fields.next())?,
+    })
+}
+
+/// Run the cluster bus on `listeners` until `shutdown`: answer the nodes that connect, and
+/// send what the cluster cron asks to over links we open to the others
+pub(crate) fn start(
+    listeners: Vec<TcpListener>,
+    cluster: Arc<ClusterState>,
+    shutdown: CancellationToken,
+) {
+    for listener in listeners {
+        let (cluster, shutdown) = (cluster.clone(), shutdown.clone());
+        tasks::spawn("cluster-bus", None, async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => {
+                            tasks::spawn(
+                                "cluster-peer",
+                                None,
+                                serve(stream, cluster.clone(), shutdown.clone()),
+                            );
+                        }
+                        Err(e) => tracing::warn!("Failed to accept a cluster bus connection: {e}"),
+                    },
+                    _ = shutdown.cancelled() => return,
+                }
+            }
+        });
+    }
+    tasks::spawn("cluster-cron", None, cron(cluster, shutdown));
+}
+
+/// Answer the messages of a node over the connection it opened
+async fn serve(stream: TcpStream, cluster: Arc<ClusterState>, shutdown: CancellationToken) {
+    let mut peer = Client::new(stream);
+    loop {
+        let received = tokio::select! {
+            received = peer.receive() => received,
+            _ = shutdown.cancelled() => return,
+        };
+        let message = match received.and_then(|value| value.map(Message::decode).transpose()) {
+            Ok(Some(message)) => message,
+            Ok(None) => return,
+            Err(e) => {
+                tracing::warn!("Dropping a cluster bus connection: {e:#}");
+                return;
+            }
+        };
+        if let Some(reply) = cluster.receive(message, None, Instant::now())
+            && peer.send(reply.encode()).await.is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Run [`ClusterState::tick`] every [`CRON_PERIOD`], sending what it asks to over a link per
+/// node, opened on demand
+async fn cron(cluster: Arc<ClusterState>, shutdown: CancellationToken) {
+    let mut links: HashMap<BusAddr, mpsc::UnboundedSender<Message>> = HashMap::new();
+    let mut interval = tokio::time::interval(CRON_PERIOD);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        // a broken link is replaced, and what was queued on it is sent again with the next ping
+        links.retain(|_, link| !link.is_closed());
+        for (addr, message) in cluster.tick(Instant::now()) {
+            let link = links
+                .entry(addr)
+                .or_insert_with_key(|addr| link(addr.clone(), cluster.clone(), shutdown.clone()));
+            let _ = link.send(message);
+        }
+    }
+}
+
+/// Open a link to the node at `addr` that sends the messages queued on it and hands the replies
+/// to the cluster state. It closes on the first error.
+fn link(
+    addr: BusAddr,
+    cluster: Arc<ClusterState>,
+    shutdown: CancellationToken,
+) -> mpsc::UnboundedSender<Message> {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+    tasks::spawn("cluster-link", None, async move {
+        let connected = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            TcpStream::connect((addr.0.as_str(), addr.1)),
+        )
+        .await;
+        let Ok(Ok(stream)) = connected else {
+            tracing::debug!("Can't reach the cluster bus at {}:{}", addr.0, addr.1);
+            return;
+        };
+        let (read, write) = stream.into_split();
+        let mut reader = FramedRead::new(read, RespFrame::default());
+        let mut writer = FramedWrite::new(write, RespFrame::default());
+        loop {
+            tokio::select! {
+                message = rx.recv() => match message {
+                    Some(message) if writer.send(message.encode()).await.is_ok() => {}
+                    _ => return,
+                },
+                received = reader.next() => match received.map(|value| value.and_then(Message::decode)) {
+                    Some(Ok(message)) => {
+                        cluster.receive(message, Some(&addr), Instant::now());
+                    }
+                    _ => return,
+                },
+                _ = shutdown.cancelled() => return,
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_roundtrip() {
+        let node = |id: &str, port| ClusterNode {
+            id: id.into(),
+            host: "127.0.0.1".into(),
+            port,
+            bus_port: port + 10000,
+        };
+        let heartbeat = Message::Heartbeat(Heartbeat {
+            kind: Kind::Meet,
+            sender: node("a", 7000),
+            current_epoch: 3,
+            config_epoch: 2,
+            slots: Bytes::from(vec![0b101; 2048]),
+            gossip: vec![
+                Gossip {
+                    node: node("b", 7001),
+                    failing: false,
+                },
+                Gossip {
+                    node: node("c", 7002),
+                    failing: true,
+                },
+            ],
+        });
+        let fail = Message::Fail {
+            sender: "a".into(),
+            failed: "c".into(),
+        };
+        for message in [heartbeat, fail] {
+            assert_eq!(Message::decode(message.encode()).unwrap(), message);
+        }
+        assert!(Message::decode(RedisValue::command(["PING", "a"])).is_err());
+        assert!(Message::decode(RedisValue::command(["HELLO"])).is_err());
+    }
+}
@@ -1,83 +1,314 @@
 use core::str;
-use std::time::Duration;
+use std::{ops::Deref, time::Duration};
 
 use anyhow::Result;
 use bytes::Bytes;
 
-use crate::resp::RedisValue;
+use crate::{
+    cluster::{self, SetSlot, SLOT_COUNT},
+    command::table::CommandSpec,
+    error::RedisError,
+    resp::RedisValue,
+    server::{hotkeys, tracking::TrackingOptions, types::ZAddFlags},
+};
+
+pub(crate) mod middleware;
+pub(crate) mod registry;
+pub(crate) mod table;
+
+/// Unknown command errors quote arguments only up to about this many bytes, as Redis does
+const UNKNOWN_COMMAND_ARGS_LEN: usize = 128;
+
+/// How long MIGRATE waits on its target when not told
+const MIGRATE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub(crate) enum ClusterCommand {
+    Info,
+    MyId,
+    Slots,
+    Shards,
+    KeySlot(Bytes),
+    Nodes,
+    /// Meet the node at `host` whose cluster bus listens on `bus_port`
+    Meet {
+        host: String,
+        bus_port: u16,
+    },
+    SetSlot {
+        slot: u16,
+        action: SetSlot,
+    },
+    CountKeysInSlot(u16),
+    GetKeysInSlot {
+        slot: u16,
+        count: usize,
+    },
+}
+
+pub(crate) enum ObjectCommand {
+    Encoding(Bytes),
+    Freq(Bytes),
+}
+
+pub(crate) enum MemoryCommand {
+    Usage(Bytes),
+}
+
+pub(crate) enum ClientCommand {
+    Id,
+    /// `CLIENT TRACKING ON` with its options, `None` for `OFF`
+    Tracking(Option<TrackingOptions>),
+    /// Whether to track the keys read by the next command, in OPTIN or OPTOUT mode
+    Caching(bool),
+    GetRedir,
+    /// Whether the client is spared when clients hold more than `maxmemory-clients`
+    NoEvict(bool),
+}
+
+pub(crate) enum HotKeysCommand {
+    /// Up to this many of the hottest keys
+    Get(usize),
+    Reset,
+}
+
+pub(crate) enum ConfigCommand {
+    /// Options matching any of the glob patterns
+    Get(Vec<Bytes>),
+}
+
+pub(crate) enum CommandCommand {
+    Count,
+    /// Details of the named commands, or of every command when none are named
+    Info(Vec<Bytes>),
+    List,
+    /// The keys of a command line, with how the command uses them if `flags` is set
+    GetKeys {
+        args: Vec<Bytes>,
+        flags: bool,
+    },
+}
 
 pub(crate) enum RedisCommand {
     Ping,
     Echo(Bytes),
     Get(Bytes),
+    MGet(Vec<Bytes>),
     Set {
         key: Bytes,
         value: Bytes,
         expiration: Option<Duration>,
     },
-    RPush {
+    MSet(Vec<(Bytes, Bytes)>),
+    /// RPUSH and LPUSH
+    Push {
         list_name: Bytes,
         elements: Vec<Bytes>,
+        front: bool,
+    },
+    /// LPOP and RPOP, `count` unset to pop a single element rather than an array of them
+    Pop {
+        key: Bytes,
+        count: Option<usize>,
+        front: bool,
+    },
+    /// BLPOP and BRPOP, `timeout` unset to block until an element arrives
+    BPop {
+        keys: Vec<Bytes>,
+        timeout: Option<Duration>,
+        front: bool,
+    },
+    LRange {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    SAdd {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    SRem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    /// `count` unset to pop a single member rather than an array of them
+    SPop {
+        key: Bytes,
+        count: Option<usize>,
+    },
+    SCard(Bytes),
+    HSet {
+        key: Bytes,
+        pairs: Vec<(Bytes, Bytes)>,
+    },
+    HGet {
+        key: Bytes,
+        field: Bytes,
+    },
+    /// ZADD, replying with the members added, or also those changed with `ch`
+    ZAdd {
+        key: Bytes,
+        pairs: Vec<(f64, Bytes)>,
+        flags: ZAddFlags,
+        ch: bool,
+    },
+    ZScore {
+        key: Bytes,
+        member: Bytes,
+    },
+    ZCard(Bytes),
+    /// `count` unset to pop a single member rather than an array of them
+    ZPopMin {
+        key: Bytes,
+        count: Option<usize>,
+    },
+    /// INCR, DECR, INCRBY and DECRBY, as the amount to add
+    IncrBy {
+        key: Bytes,
+        by: i64,
+    },
+    Del(Vec<Bytes>),
+    /// KEYS, with the glob pattern names are matched against
+    Keys(Bytes),
+    /// DEL that frees large values in the background
+    Unlink(Vec<Bytes>),
+    /// FLUSHALL and FLUSHDB, `None` leaving ASYNC or SYNC to the configuration
+    FlushAll {
+        lazy: Option<bool>,
+    },
+    /// Expire a key in `secs` seconds, deleting it straight away if that is not in the future
+    Expire {
+        key: Bytes,
+        secs: i64,
+    },
+    /// Expire a key at an absolute unix time in milliseconds
+    PExpireAt {
+        key: Bytes,
+        at_ms: u64,
+    },
+    /// TTL and PTTL, the time left in milliseconds with `ms`
+    Ttl {
+        key: Bytes,
+        ms: bool,
+    },
+    Dump(Bytes),
+    /// RESTORE and RESTORE-ASKING, `ttl_ms` 0 for no expiration
+    Restore {
+        key: Bytes,
+        ttl_ms: u64,
+        payload: Bytes,
+        replace: bool,
+        absttl: bool,
+    },
+    /// Move `keys` to the server at `host:port` by DUMP and RESTORE, or copy them with `copy`
+    Migrate {
+        host: String,
+        port: u16,
+        keys: Vec<Bytes>,
+        timeout: Duration,
+        copy: bool,
+        replace: bool,
+    },
+    Save,
+    BgSave,
+    BgRewriteAof,
+    ReplConf(Vec<Bytes>),
+    /// `PSYNC <replid> <offset>`, with `?` and -1 asking for a full resync
+    Psync {
+        replid: String,
+        offset: i64,
+    },
+    Wait {
+        replicas: usize,
+        timeout: Option<Duration>,
+    },
+    Info(Vec<String>),
+    Role,
+    Subscribe(Vec<Bytes>),
+    /// Unsubscribe from the channels named, or from every channel if none are
+    Unsubscribe(Vec<Bytes>),
+    Publish {
+        channel: Bytes,
+        message: Bytes,
+    },
+    /// `None` for `REPLICAOF NO ONE`
+    ReplicaOf(Option<(String, u16)>),
+    Failover {
+        target: Option<(String, u16)>,
+        force: bool,
+        abort: bool,
+        timeout: Option<Duration>,
+    },
+    Cluster(ClusterCommand),
+    Asking,
+    /// Let a cluster replica serve reads of its master's slots
+    ReadOnly,
+    ReadWrite,
+    Client(ClientCommand),
+    Object(ObjectCommand),
+    Memory(MemoryCommand),
+    HotKeys(HotKeysCommand),
+    /// Switch to the given protocol version, if any, and describe the connection
+    Hello(Option<u8>),
+    /// Authenticate the connection with `requirepass`, the only user being `default`
+    Auth {
+        username: Option<Bytes>,
+        password: Bytes,
+    },
+    Config(ConfigCommand),
+    Command(CommandCommand),
+    /// A command registered at startup, see [`registry`]
+    Custom {
+        handler: registry::Handler,
+        args: Vec<Bytes>,
     },
 }
 
 impl RedisCommand {
-    pub(crate) fn parse(msg: RedisValue) -> Result<Self> {
+    /// Parse a built-in command frame, as the [`registry`] does for the server
+    #[cfg(test)]
+    fn parse(msg: RedisValue) -> Result<Self> {
         // ensure that RedisValue is a BulkArray
         let RedisValue::Array(values) = msg else {
             tracing::error!("Invalid message, expected bulk array");
-            Err(anyhow::anyhow!("Invalid message"))?
+            return Err(RedisError::Protocol("expected a command array".into()).into());
         };
 
-        let cmd = values
-            .first()
-            .and_then(|v| match v {
-                RedisValue::BulkString(s) => {
-                    // attempt to interpret as String
-                    let s = str::from_utf8(&s[..]).ok()?;
-                    Some(s.to_uppercase())
-                }
-                _ => None,
-            })
-            .ok_or(anyhow::anyhow!("Invalid type in command array"))?;
+        let Some(spec) = table::lookup(Self::name(&values)?) else {
+            return Err(Self::unknown_command(&values));
+        };
+        Self::check_arity(spec, &values)?;
+        Self::parse_args(spec, &values)
+    }
 
-        match cmd.as_str() {
+    /// Parse a built-in command whose arity was checked against its `spec` in the [`table`],
+    /// leaving the frame to be propagated as it came
+    pub(crate) fn parse_args(spec: &CommandSpec, values: &[RedisValue]) -> Result<Self> {
+        let name = spec.name.as_ref();
+        match name {
             "PING" => Ok(Self::Ping),
             "ECHO" => {
-                let msg = Self::expect_bulk_string(&values, 1)?;
+                let msg = Self::expect_bulk_string(values, 1)?;
                 Ok(Self::Echo(msg))
             }
             "GET" => {
-                let key = Self::expect_bulk_string(&values, 1)?;
+                let key = Self::expect_bulk_string(values, 1)?;
                 Ok(Self::Get(key))
             }
             "SET" => {
                 // set requires key and value
-                let key = Self::expect_bulk_string(&values, 1)?;
-                let value = Self::expect_bulk_string(&values, 2)?;
+                let key = Self::expect_bulk_string(values, 1)?;
+                let value = Self::expect_bulk_string(values, 2)?;
 
                 let mut expiration = None;
-
-                let mut rest = values[3..].iter();
-                while let Some(v) = rest.next() {
-                    let arg: String = v.try_into()?;
-                    match arg.as_str() {
-                        "PX" => {
-                            let dur = rest.next().ok_or(anyhow::anyhow!(
-                                "Not enough args, expected duration specifier"
-                            ))?;
-                            expiration = Some(process_time(dur, Duration::from_millis)?);
-                        }
-                        "EX" => {
-                            let dur = rest.next().ok_or(anyhow::anyhow!(
-                                "Not enough args, expected duration specifier"
-                            ))?;
-                            expiration = Some(process_time(dur, Duration::from_secs)?);
-                        }
-                        _ => {
-                            return Err(anyhow::anyhow!("Unsupported or invalid argument: {arg}"));
-                        }
+                for (option, args) in Self::options(spec, values)? {
+                    // only one of EX and PX, once
+                    if expiration.is_some() {
+                        return Err(RedisError::Syntax.into());
                     }
+                    expiration = Some(match option {
+                        "PX" => expire_time(&args[0], Duration::from_millis)?,
+                        _ => expire_time(&args[0], Duration::from_secs)?,
+                    });
                 }
 
                 Ok(Self::Set {
@@ -86,38 +317,989 @@ impl RedisCommand {
                     expiration,
                 })
             }
-            "RPUSH" => {
-                let list_name = Self::expect_bulk_string(&values, 1)?;
-                // collect remaining values as Bytes values
-                let elements: Result<Vec<Bytes>, anyhow::Error> =
-                    values[2..].iter().map(|rv| rv.try_into()).collect();
-                Ok(Self::RPush {
+            "MGET" => Ok(Self::MGet(Self::keys(spec, values)?)),
+            "MSET" => {
+                if values.len().is_multiple_of(2) {
+                    return Err(Self::wrong_arity(values));
+                }
+                let mut pairs = Vec::with_capacity(values.len() / 2);
+                let mut args = Self::args(values, 1);
+                while let (Some(key), Some(value)) = (args.next(), args.next()) {
+                    pairs.push((key?, value?));
+                }
+                Ok(Self::MSet(pairs))
+            }
+            "RPUSH" | "LPUSH" => {
+                let mut args = Self::args(values, 1);
+                let list_name = args.next().ok_or(RedisError::Syntax)??;
+                Ok(Self::Push {
                     list_name,
-                    elements: elements?,
+                    elements: args.collect::<Result<_>>()?,
+                    front: name == "LPUSH",
+                })
+            }
+            "LPOP" | "RPOP" => {
+                let key = Self::expect_bulk_string(values, 1)?;
+                let count = match values.get(2..) {
+                    None | Some([]) => None,
+                    Some([count]) => {
+                        let count: Bytes = count.try_into()?;
+                        match parse_integer::<i64>(&count) {
+                            Ok(count) if count >= 0 => Some(count as usize),
+                            _ => {
+                                return Err(RedisError::other(
+                                    "value is out of range, must be positive",
+                                )
+                                .into())
+                            }
+                        }
+                    }
+                    Some(_) => return Err(Self::wrong_arity(values)),
+                };
+                Ok(Self::Pop {
+                    key,
+                    count,
+                    front: name == "LPOP",
+                })
+            }
+            "BLPOP" | "BRPOP" => {
+                let timeout = timeout_secs(&values[values.len() - 1])?;
+                Ok(Self::BPop {
+                    keys: Self::keys(spec, values)?,
+                    // a timeout of 0 blocks forever
+                    timeout: (!timeout.is_zero()).then_some(timeout),
+                    front: name == "BLPOP",
+                })
+            }
+            "LRANGE" => {
+                let key = Self::expect_bulk_string(values, 1)?;
+                let start = Self::expect_bulk_string(values, 2)?;
+                let stop = Self::expect_bulk_string(values, 3)?;
+                Ok(Self::LRange {
+                    key,
+                    start: parse_integer(&start)?,
+                    stop: parse_integer(&stop)?,
+                })
+            }
+            "SADD" | "SREM" => {
+                let mut args = Self::args(values, 1);
+                let key = args.next().ok_or(RedisError::Syntax)??;
+                let members = args.collect::<Result<_>>()?;
+                match name {
+                    "SADD" => Ok(Self::SAdd { key, members }),
+                    _ => Ok(Self::SRem { key, members }),
+                }
+            }
+            "SPOP" => Ok(Self::SPop {
+                key: Self::expect_bulk_string(values, 1)?,
+                count: Self::pop_count(values)?,
+            }),
+            "SCARD" => Ok(Self::SCard(Self::expect_bulk_string(values, 1)?)),
+            "HSET" => {
+                if !values.len().is_multiple_of(2) {
+                    return Err(Self::wrong_arity(values));
+                }
+                let mut pairs = Vec::with_capacity(values.len() / 2);
+                let mut args = Self::args(values, 1);
+                let key = args.next().ok_or(RedisError::Syntax)??;
+                while let (Some(field), Some(value)) = (args.next(), args.next()) {
+                    pairs.push((field?, value?));
+                }
+                Ok(Self::HSet { key, pairs })
+            }
+            "HGET" => Ok(Self::HGet {
+                key: Self::expect_bulk_string(values, 1)?,
+                field: Self::expect_bulk_string(values, 2)?,
+            }),
+            "ZADD" => Self::zadd(values),
+            "ZSCORE" => Ok(Self::ZScore {
+                key: Self::expect_bulk_string(values, 1)?,
+                member: Self::expect_bulk_string(values, 2)?,
+            }),
+            "ZCARD" => Ok(Self::ZCard(Self::expect_bulk_string(values, 1)?)),
+            "ZPOPMIN" => Ok(Self::ZPopMin {
+                key: Self::expect_bulk_string(values, 1)?,
+                count: Self::pop_count(values)?,
+            }),
+            "INCR" | "DECR" | "INCRBY" | "DECRBY" => {
+                let key = Self::expect_bulk_string(values, 1)?;
+                let by: i64 = match values.get(2) {
+                    Some(by) => parse_integer(&Bytes::try_from(by)?)?,
+                    None => 1,
+                };
+                let by = match name {
+                    "DECR" | "DECRBY" => by
+                        .checked_neg()
+                        .ok_or(RedisError::other("decrement would overflow"))?,
+                    _ => by,
+                };
+                Ok(Self::IncrBy { key, by })
+            }
+            "DEL" | "UNLINK" => {
+                let keys = Self::keys(spec, values)?;
+                if name == "UNLINK" {
+                    Ok(Self::Unlink(keys))
+                } else {
+                    Ok(Self::Del(keys))
+                }
+            }
+            "KEYS" => Ok(Self::Keys(Self::expect_bulk_string(values, 1)?)),
+            "FLUSHALL" | "FLUSHDB" => {
+                let lazy = match &Self::options(spec, values)?[..] {
+                    [] => None,
+                    [(mode, _)] => Some(*mode == "ASYNC"),
+                    _ => return Err(RedisError::Syntax.into()),
+                };
+                Ok(Self::FlushAll { lazy })
+            }
+            "EXPIRE" => {
+                let key = Self::expect_bulk_string(values, 1)?;
+                let secs = Self::expect_bulk_string(values, 2)?;
+                Ok(Self::Expire {
+                    key,
+                    secs: parse_integer(&secs)?,
+                })
+            }
+            "TTL" | "PTTL" => Ok(Self::Ttl {
+                key: Self::expect_bulk_string(values, 1)?,
+                ms: name == "PTTL",
+            }),
+            "PEXPIREAT" => {
+                let key = Self::expect_bulk_string(values, 1)?;
+                let at_ms = Self::expect_bulk_string(values, 2)?;
+                Ok(Self::PExpireAt {
+                    key,
+                    at_ms: parse_integer(&at_ms)?,
+                })
+            }
+            "DUMP" => Ok(Self::Dump(Self::expect_bulk_string(values, 1)?)),
+            "RESTORE" | "RESTORE-ASKING" => {
+                let key = Self::expect_bulk_string(values, 1)?;
+                let ttl_ms = match parse_integer::<i64>(&Self::expect_bulk_string(values, 2)?)? {
+                    ttl if ttl < 0 => {
+                        return Err(RedisError::other("Invalid TTL value, must be >= 0").into())
+                    }
+                    ttl => ttl as u64,
+                };
+                let payload = Self::expect_bulk_string(values, 3)?;
+                let (mut replace, mut absttl) = (false, false);
+                for (option, _) in Self::options(spec, values)? {
+                    match option {
+                        "REPLACE" => replace = true,
+                        _ => absttl = true,
+                    }
+                }
+                Ok(Self::Restore {
+                    key,
+                    ttl_ms,
+                    payload,
+                    replace,
+                    absttl,
+                })
+            }
+            "MIGRATE" => {
+                let host = Self::expect_bulk_string(values, 1)?;
+                let port = parse_integer(&Self::expect_bulk_string(values, 2)?)?;
+                let key = Self::expect_bulk_string(values, 3)?;
+                // there is only database 0 to move keys to
+                if parse_integer::<u64>(&Self::expect_bulk_string(values, 4)?)? != 0 {
+                    return Err(RedisError::other("DB index is out of range").into());
+                }
+                let timeout = match parse_integer::<i64>(&Self::expect_bulk_string(values, 5)?)? {
+                    // as in Redis, no timeout means the default one
+                    timeout if timeout <= 0 => MIGRATE_TIMEOUT,
+                    timeout => Duration::from_millis(timeout as u64),
+                };
+                let (mut copy, mut replace) = (false, false);
+                let mut keys = vec![key.clone()];
+                for (option, args) in Self::options(spec, values)? {
+                    match option {
+                        "COPY" => copy = true,
+                        "REPLACE" => replace = true,
+                        // the keys to move are given here when the key argument is empty
+                        _ if key.is_empty() => {
+                            keys = args.iter().map(Bytes::try_from).collect::<Result<_>>()?;
+                        }
+                        _ => {
+                            return Err(RedisError::other(
+                                "When using MIGRATE KEYS option, the key argument must be set to \
+                                 an empty string",
+                            )
+                            .into())
+                        }
+                    }
+                }
+                Ok(Self::Migrate {
+                    host: String::from_utf8_lossy(&host).into_owned(),
+                    port,
+                    keys,
+                    timeout,
+                    copy,
+                    replace,
+                })
+            }
+            "SAVE" => Ok(Self::Save),
+            "BGSAVE" => Ok(Self::BgSave),
+            "BGREWRITEAOF" => Ok(Self::BgRewriteAof),
+            "REPLCONF" => Ok(Self::ReplConf(
+                Self::args(values, 1).collect::<Result<_>>()?,
+            )),
+            "PSYNC" => {
+                let replid = Self::expect_bulk_string(values, 1)?;
+                let offset = Self::expect_bulk_string(values, 2)?;
+                Ok(Self::Psync {
+                    replid: str::from_utf8(&replid)?.to_string(),
+                    offset: parse_integer(&offset)?,
+                })
+            }
+            "INFO" => {
+                // section names are matched case-insensitively
+                let sections: Result<Vec<String>, anyhow::Error> = Self::args(values, 1)
+                    .map(|section| {
+                        Ok(String::from_utf8_lossy(&section?.to_ascii_uppercase()).into_owned())
+                    })
+                    .collect();
+                Ok(Self::Info(sections?))
+            }
+            "ROLE" => Ok(Self::Role),
+            "SUBSCRIBE" => Ok(Self::Subscribe(
+                Self::args(values, 1).collect::<Result<_>>()?,
+            )),
+            "UNSUBSCRIBE" => Ok(Self::Unsubscribe(
+                Self::args(values, 1).collect::<Result<_>>()?,
+            )),
+            "PUBLISH" => Ok(Self::Publish {
+                channel: Self::expect_bulk_string(values, 1)?,
+                message: Self::expect_bulk_string(values, 2)?,
+            }),
+            "REPLICAOF" | "SLAVEOF" => {
+                let host = Self::expect_bulk_string(values, 1)?;
+                let port = Self::expect_bulk_string(values, 2)?;
+                if host.eq_ignore_ascii_case(b"NO") && port.eq_ignore_ascii_case(b"ONE") {
+                    Ok(Self::ReplicaOf(None))
+                } else {
+                    let host = str::from_utf8(&host)?.to_string();
+                    let port = parse_integer(&port)?;
+                    Ok(Self::ReplicaOf(Some((host, port))))
+                }
+            }
+            "FAILOVER" => {
+                let mut target = None;
+                let mut force = false;
+                let mut abort = false;
+                let mut timeout = None;
+
+                for (option, args) in Self::options(spec, values)? {
+                    match option {
+                        "TO" => {
+                            let host: Bytes = (&args[0]).try_into()?;
+                            let port: Bytes = (&args[1]).try_into()?;
+                            target =
+                                Some((str::from_utf8(&host)?.to_string(), parse_integer(&port)?));
+                        }
+                        "FORCE" => force = true,
+                        "ABORT" => abort = true,
+                        _ => timeout = Some(timeout_ms(&args[0])?),
+                    }
+                }
+                Ok(Self::Failover {
+                    target,
+                    force,
+                    abort,
+                    timeout,
+                })
+            }
+            "CLUSTER" => {
+                let subcommand = Self::subcommand(values)?;
+                let subcommand = match &subcommand[..] {
+                    b"INFO" => ClusterCommand::Info,
+                    b"MYID" => ClusterCommand::MyId,
+                    b"SLOTS" => ClusterCommand::Slots,
+                    b"SHARDS" => ClusterCommand::Shards,
+                    b"KEYSLOT" => ClusterCommand::KeySlot(Self::expect_bulk_string(values, 2)?),
+                    b"NODES" => ClusterCommand::Nodes,
+                    b"MEET" => {
+                        if values.len() > 5 {
+                            return Err(Self::wrong_subcommand_arity(values));
+                        }
+                        let host = Self::expect_bulk_string(values, 2)?;
+                        let port = |index, which| -> Result<u16> {
+                            let port = Self::expect_bulk_string(values, index)?;
+                            Ok(parse_integer(&port).map_err(|_| {
+                                RedisError::other(format!(
+                                    "Invalid {which} port specified: {}",
+                                    String::from_utf8_lossy(&port)
+                                ))
+                            })?)
+                        };
+                        let base = port(3, "base")?;
+                        let bus_port = match values.len() {
+                            5 => port(4, "bus")?,
+                            _ => base.checked_add(cluster::BUS_PORT_OFFSET).ok_or_else(|| {
+                                RedisError::other(format!("Invalid base port specified: {base}"))
+                            })?,
+                        };
+                        ClusterCommand::Meet {
+                            host: String::from_utf8_lossy(&host).into_owned(),
+                            bus_port,
+                        }
+                    }
+                    b"SETSLOT" => {
+                        let slot = slot(&Self::expect_bulk_string(values, 2)?)?;
+                        let node = || -> Result<String> {
+                            let id = Self::expect_bulk_string(values, 4)?;
+                            Ok(String::from_utf8_lossy(&id).into_owned())
+                        };
+                        let action = keyword(&values[3])?;
+                        let action =
+                            match (&action[..], values.len()) {
+                                (b"MIGRATING", 5) => SetSlot::Migrating(node()?),
+                                (b"IMPORTING", 5) => SetSlot::Importing(node()?),
+                                (b"STABLE", 4) => SetSlot::Stable,
+                                (b"NODE", 5) => SetSlot::Node(node()?),
+                                _ => return Err(RedisError::other(
+                                    "Invalid CLUSTER SETSLOT action or number of arguments. Try \
+                                     CLUSTER HELP",
+                                )
+                                .into()),
+                            };
+                        ClusterCommand::SetSlot { slot, action }
+                    }
+                    b"COUNTKEYSINSLOT" => ClusterCommand::CountKeysInSlot(slot(
+                        &Self::expect_bulk_string(values, 2)?,
+                    )?),
+                    b"GETKEYSINSLOT" => {
+                        let slot = slot(&Self::expect_bulk_string(values, 2)?)?;
+                        let count = parse_integer::<i64>(&Self::expect_bulk_string(values, 3)?)?;
+                        let count = usize::try_from(count)
+                            .map_err(|_| RedisError::other("Invalid number of keys"))?;
+                        ClusterCommand::GetKeysInSlot { slot, count }
+                    }
+                    _ => return Err(Self::unknown_subcommand("CLUSTER", values)),
+                };
+                Ok(Self::Cluster(subcommand))
+            }
+            "ASKING" => Ok(Self::Asking),
+            "READONLY" => Ok(Self::ReadOnly),
+            "READWRITE" => Ok(Self::ReadWrite),
+            "CLIENT" => {
+                let subcommand = Self::subcommand(values)?;
+                let subcommand = match &subcommand[..] {
+                    b"ID" => ClientCommand::Id,
+                    b"TRACKING" => ClientCommand::Tracking(Self::tracking(spec, values)?),
+                    b"CACHING" => match &keyword(&values[2])?[..] {
+                        b"YES" => ClientCommand::Caching(true),
+                        b"NO" => ClientCommand::Caching(false),
+                        _ => return Err(RedisError::Syntax.into()),
+                    },
+                    b"GETREDIR" => ClientCommand::GetRedir,
+                    b"NO-EVICT" => match &keyword(&values[2])?[..] {
+                        b"ON" => ClientCommand::NoEvict(true),
+                        b"OFF" => ClientCommand::NoEvict(false),
+                        _ => return Err(RedisError::Syntax.into()),
+                    },
+                    _ => return Err(Self::unknown_subcommand("CLIENT", values)),
+                };
+                Ok(Self::Client(subcommand))
+            }
+            "HELLO" => {
+                let protover = match values.get(1) {
+                    Some(v) => {
+                        let protover: Bytes = v.try_into()?;
+                        Some(parse_integer(&protover).map_err(|_| {
+                            RedisError::other("Protocol version is not an integer or out of range")
+                        })?)
+                    }
+                    None => None,
+                };
+                // authentication and client names are not supported
+                if let Some(option) = values.get(2) {
+                    let option: Bytes = option.try_into()?;
+                    return Err(RedisError::Other(format!(
+                        "Syntax error in HELLO option '{}'",
+                        String::from_utf8_lossy(&option)
+                    ))
+                    .into());
+                }
+                Ok(Self::Hello(protover))
+            }
+            "AUTH" => {
+                let (username, password) = match values.len() {
+                    2 => (None, Self::expect_bulk_string(values, 1)?),
+                    3 => (
+                        Some(Self::expect_bulk_string(values, 1)?),
+                        Self::expect_bulk_string(values, 2)?,
+                    ),
+                    _ => return Err(RedisError::Syntax.into()),
+                };
+                Ok(Self::Auth { username, password })
+            }
+            "OBJECT" => {
+                let subcommand = Self::subcommand(values)?;
+                let subcommand = match &subcommand[..] {
+                    b"ENCODING" => ObjectCommand::Encoding(Self::expect_bulk_string(values, 2)?),
+                    b"FREQ" => ObjectCommand::Freq(Self::expect_bulk_string(values, 2)?),
+                    _ => return Err(Self::unknown_subcommand("OBJECT", values)),
+                };
+                Ok(Self::Object(subcommand))
+            }
+            "MEMORY" => {
+                let subcommand = Self::subcommand(values)?;
+                let subcommand = match &subcommand[..] {
+                    b"USAGE" => {
+                        let key = Self::expect_bulk_string(values, 2)?;
+                        // sizes are tracked exactly, so there is nothing to sample
+                        for (_, args) in Self::options(spec, values)? {
+                            let count: Bytes = (&args[0]).try_into()?;
+                            if parse_integer::<u64>(&count).is_err() {
+                                return Err(RedisError::Syntax.into());
+                            }
+                        }
+                        MemoryCommand::Usage(key)
+                    }
+                    _ => return Err(Self::unknown_subcommand("MEMORY", values)),
+                };
+                Ok(Self::Memory(subcommand))
+            }
+            "HOTKEYS" => {
+                let subcommand = Self::subcommand(values)?;
+                let subcommand = match &subcommand[..] {
+                    b"GET" => {
+                        let mut count = hotkeys::TOP_K;
+                        for (_, args) in Self::options(spec, values)? {
+                            count = match parse_integer::<i64>(&Bytes::try_from(&args[0])?)? {
+                                count @ 1.. => count as usize,
+                                _ => {
+                                    return Err(
+                                        RedisError::other("COUNT must be greater than 0").into()
+                                    )
+                                }
+                            };
+                        }
+                        HotKeysCommand::Get(count)
+                    }
+                    b"RESET" => HotKeysCommand::Reset,
+                    _ => return Err(Self::unknown_subcommand("HOTKEYS", values)),
+                };
+                Ok(Self::HotKeys(subcommand))
+            }
+            "CONFIG" => {
+                let subcommand = Self::subcommand(values)?;
+                let subcommand = match &subcommand[..] {
+                    b"GET" => ConfigCommand::Get(Self::args(values, 2).collect::<Result<_>>()?),
+                    _ => return Err(Self::unknown_subcommand("CONFIG", values)),
+                };
+                Ok(Self::Config(subcommand))
+            }
+            "COMMAND" => {
+                let subcommand = match values.get(1) {
+                    Some(subcommand) => keyword(subcommand)?,
+                    None => return Ok(Self::Command(CommandCommand::Info(Vec::new()))),
+                };
+                let subcommand = match &subcommand[..] {
+                    b"COUNT" => CommandCommand::Count,
+                    b"INFO" => CommandCommand::Info(Self::args(values, 2).collect::<Result<_>>()?),
+                    b"LIST" => CommandCommand::List,
+                    b"GETKEYS" | b"GETKEYSANDFLAGS" => CommandCommand::GetKeys {
+                        flags: subcommand.len() > b"GETKEYS".len(),
+                        args: Self::args(values, 2).collect::<Result<_>>()?,
+                    },
+                    _ => return Err(Self::unknown_subcommand("COMMAND", values)),
+                };
+                Ok(Self::Command(subcommand))
+            }
+            "WAIT" => {
+                let replicas = Self::expect_bulk_string(values, 1)?;
+                let timeout = timeout_ms(&values[2])?;
+                Ok(Self::Wait {
+                    replicas: parse_integer(&replicas)?,
+                    // a timeout of 0 blocks forever
+                    timeout: (!timeout.is_zero()).then_some(timeout),
                 })
             }
-            _ => Err(anyhow::anyhow!("Unsupported command: {cmd:?}")),
+            _ => Err(Self::unknown_command(values)),
         }
     }
 
+    /// The arguments of `ZADD key [NX|XX] [GT|LT] [CH] [INCR] score member [score member]...`
+    fn zadd(values: &[RedisValue]) -> Result<Self> {
+        let key = Self::expect_bulk_string(values, 1)?;
+        let (mut flags, mut ch) = (ZAddFlags::default(), false);
+        let mut rest = &values[2..];
+        while let Some((flag, after)) = rest.split_first() {
+            match &keyword(flag)?[..] {
+                b"NX" => flags.nx = true,
+                b"XX" => flags.xx = true,
+                b"GT" => flags.gt = true,
+                b"LT" => flags.lt = true,
+                b"CH" => ch = true,
+                b"INCR" => flags.incr = true,
+                _ => break,
+            }
+            rest = after;
+        }
+        if rest.is_empty() || !rest.len().is_multiple_of(2) {
+            return Err(RedisError::Syntax.into());
+        }
+        if flags.nx && flags.xx {
+            return Err(
+                RedisError::other("XX and NX options at the same time are not compatible").into(),
+            );
+        }
+        if [flags.nx, flags.gt, flags.lt]
+            .into_iter()
+            .filter(|&f| f)
+            .count()
+            > 1
+        {
+            return Err(RedisError::other(
+                "GT, LT, and/or NX options at the same time are not compatible",
+            )
+            .into());
+        }
+        if flags.incr && rest.len() > 2 {
+            return Err(
+                RedisError::other("INCR option supports a single increment-element pair").into(),
+            );
+        }
+        let pairs = rest
+            .chunks(2)
+            .map(|pair| Ok((score(&pair[0])?, Bytes::try_from(&pair[1])?)))
+            .collect::<Result<_>>()?;
+        Ok(Self::ZAdd {
+            key,
+            pairs,
+            flags,
+            ch,
+        })
+    }
+
+    /// The optional count of `SPOP key [count]` and `ZPOPMIN key [count]`
+    fn pop_count(values: &[RedisValue]) -> Result<Option<usize>> {
+        match values.get(2..) {
+            None | Some([]) => Ok(None),
+            Some([count]) => match parse_integer::<i64>(&Bytes::try_from(count)?) {
+                Ok(count) if count >= 0 => Ok(Some(count as usize)),
+                _ => Err(RedisError::other("value is out of range, must be positive").into()),
+            },
+            Some(_) => Err(RedisError::Syntax.into()),
+        }
+    }
+
+    /// The arguments of `CLIENT TRACKING ON|OFF [REDIRECT id] [PREFIX prefix]... [BCAST] [OPTIN]
+    /// [OPTOUT] [NOLOOP]`
+    fn tracking(spec: &CommandSpec, values: &[RedisValue]) -> Result<Option<TrackingOptions>> {
+        let on = match &keyword(&values[2])?[..] {
+            b"ON" => true,
+            b"OFF" => false,
+            _ => return Err(RedisError::Syntax.into()),
+        };
+        let mut options = TrackingOptions::default();
+        for (option, args) in Self::options(spec, values)? {
+            match option {
+                "REDIRECT" => {
+                    let id: Bytes = (&args[0]).try_into()?;
+                    options.redirect = Some(parse_integer(&id)?);
+                }
+                "PREFIX" => options.prefixes.push((&args[0]).try_into()?),
+                "BCAST" => options.bcast = true,
+                "OPTIN" => options.optin = true,
+                "OPTOUT" => options.optout = true,
+                _ => options.noloop = true,
+            }
+        }
+        Ok(on.then_some(options))
+    }
+
+    /// Check a command line against its spec: the arity of the command and of its subcommand,
+    /// which parsers can then take for granted
+    pub(crate) fn check_arity(spec: &CommandSpec, values: &[RedisValue]) -> Result<()> {
+        if !spec.accepts(values.len()) {
+            return Err(Self::wrong_arity(values));
+        }
+        match spec.subcommand(values) {
+            Some(subcommand) if !subcommand.accepts(values.len()) => {
+                Err(Self::wrong_subcommand_arity(values))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The options following the arguments a command (or its subcommand) requires, by their
+    /// name in the table with the arguments each took. Options the table does not list, and
+    /// options short of arguments, are syntax errors.
+    fn options<'a>(
+        spec: &CommandSpec,
+        values: &'a [RedisValue],
+    ) -> Result<Vec<(&'static str, &'a [RedisValue])>> {
+        let spec = spec.subcommand(values).unwrap_or(spec);
+        let mut rest = values
+            .get(spec.arity.unsigned_abs() as usize..)
+            .unwrap_or_default();
+        let mut options = Vec::new();
+        while let Some((option, args)) = rest.split_first() {
+            let option = keyword(option)?;
+            let option = spec
+                .options
+                .iter()
+                .find(|spec| spec.name.as_bytes() == &option[..])
+                .ok_or(RedisError::Syntax)?;
+            let (taken, left) = args
+                .split_at_checked(option.args.unwrap_or(args.len()))
+                .ok_or(RedisError::Syntax)?;
+            options.push((option.name, taken));
+            rest = left;
+        }
+        Ok(options)
+    }
+
+    /// The keys of a command taking several, where the table places them
+    fn keys(spec: &CommandSpec, values: &[RedisValue]) -> Result<Vec<Bytes>> {
+        spec.key_positions(values.len())
+            .map(|i| Bytes::try_from(&values[i]))
+            .collect()
+    }
+
+    /// The command name, the first argument
+    fn name(values: &[RedisValue]) -> Result<&Bytes> {
+        values
+            .first()
+            .and_then(RedisValue::as_bulk_string)
+            .ok_or_else(|| {
+                RedisError::Protocol("expected the command name as a bulk string".into()).into()
+            })
+    }
+
+    /// The argument at `index`, which a well formed command must have
     fn expect_bulk_string(values: &[RedisValue], index: usize) -> Result<Bytes> {
         values
             .get(index)
-            .and_then(|redis_val| match redis_val {
-                RedisValue::BulkString(b) => Some(b.slice(..)),
-                _ => None,
+            .ok_or_else(|| Self::wrong_arity(values))?
+            .try_into()
+    }
+
+    /// The arguments from `index` on, sharing the frame's buffers
+    fn args(values: &[RedisValue], index: usize) -> impl Iterator<Item = Result<Bytes>> + '_ {
+        values.iter().skip(index).map(Bytes::try_from)
+    }
+
+    /// The subcommand of a container command such as CLUSTER, upper-cased
+    fn subcommand(values: &[RedisValue]) -> Result<Keyword> {
+        keyword(values.get(1).ok_or_else(|| Self::wrong_arity(values))?)
+    }
+
+    /// Arguments of a command as quoted in errors, lossily decoded
+    fn display_args(values: &[RedisValue]) -> impl Iterator<Item = String> + '_ {
+        values.iter().map(|value| match value.as_bulk_string() {
+            Some(b) => String::from_utf8_lossy(b).into_owned(),
+            None => format!("{value:?}"),
+        })
+    }
+
+    fn wrong_arity(values: &[RedisValue]) -> anyhow::Error {
+        let name = Self::display_args(values).next().unwrap_or_default();
+        RedisError::WrongArity(name.to_lowercase()).into()
+    }
+
+    /// A wrong arity error naming the subcommand too, e.g. for 'client|no-evict'
+    fn wrong_subcommand_arity(values: &[RedisValue]) -> anyhow::Error {
+        let name = Self::display_args(values)
+            .take(2)
+            .collect::<Vec<_>>()
+            .join("|");
+        RedisError::WrongArity(name.to_lowercase()).into()
+    }
+
+    fn unknown_command(values: &[RedisValue]) -> anyhow::Error {
+        let mut args = Self::display_args(values);
+        let name = args.next().unwrap_or_default();
+        let mut len = 0;
+        let args = args
+            .take_while(|arg| {
+                len += arg.len();
+                len <= UNKNOWN_COMMAND_ARGS_LEN
             })
-            .ok_or(anyhow::anyhow!(
-                "Expected bulk string at index {index} of {values:?}"
-            ))
+            .collect();
+        RedisError::UnknownCommand { name, args }.into()
+    }
+
+    fn unknown_subcommand(command: &'static str, values: &[RedisValue]) -> anyhow::Error {
+        let subcommand = Self::display_args(values).nth(1).unwrap_or_default();
+        RedisError::UnknownSubcommand {
+            command,
+            subcommand,
+        }
+        .into()
+    }
+}
+
+/// Longest option or subcommand there is to match, longer arguments can't be one
+const KEYWORD_MAX: usize = 16;
+
+/// An option or subcommand, upper-cased on the stack so it matches case-insensitively without
+/// allocating. Arguments too long to be a keyword are left empty, which matches none.
+struct Keyword {
+    buf: [u8; KEYWORD_MAX],
+    len: usize,
+}
+
+impl Deref for Keyword {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+fn keyword(arg: &RedisValue) -> Result<Keyword> {
+    let arg = arg
+        .as_bulk_string()
+        .ok_or(RedisError::Protocol("expected a bulk string".into()))?;
+    let mut keyword = Keyword {
+        buf: [0; KEYWORD_MAX],
+        len: 0,
+    };
+    if arg.len() <= KEYWORD_MAX {
+        keyword.buf[..arg.len()].copy_from_slice(arg);
+        keyword.buf.make_ascii_uppercase();
+        keyword.len = arg.len();
     }
+    Ok(keyword)
 }
 
-fn process_time<F>(dur: &RedisValue, f: F) -> Result<Duration>
+/// An integer argument
+pub(crate) fn parse_integer<T: str::FromStr>(arg: &[u8]) -> Result<T> {
+    Ok(str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(RedisError::NotInteger)?)
+}
+
+/// A hash slot argument
+fn slot(arg: &[u8]) -> Result<u16> {
+    Ok(parse_integer::<u16>(arg)
+        .ok()
+        .filter(|&slot| (slot as usize) < SLOT_COUNT)
+        .ok_or(RedisError::other("Invalid or out of range slot"))?)
+}
+
+/// A relative expire time for SET, which must be positive
+fn expire_time<F>(dur: &RedisValue, f: F) -> Result<Duration>
 where
     F: Fn(u64) -> Duration,
 {
-    let dur_str: String = dur.try_into()?;
-    let dur: u64 = dur_str.parse()?;
-    Ok(f(dur))
+    let dur: Bytes = dur.try_into()?;
+    match parse_integer::<i64>(&dur)? {
+        dur if dur > 0 => Ok(f(dur as u64)),
+        _ => Err(RedisError::InvalidExpireTime("set").into()),
+    }
+}
+
+/// A sorted set score, which may be infinite but not NaN
+fn score(arg: &RedisValue) -> Result<f64> {
+    let arg: Bytes = arg.try_into()?;
+    Ok(str::from_utf8(&arg)
+        .ok()
+        .and_then(|s| s.parse::<f64>().ok())
+        .filter(|score| !score.is_nan())
+        .ok_or(RedisError::NotFloat)?)
+}
+
+/// A timeout in milliseconds, where 0 means none
+fn timeout_ms(t: &RedisValue) -> Result<Duration> {
+    let t: Bytes = t.try_into()?;
+    match parse_integer::<i64>(&t)? {
+        t if t < 0 => Err(RedisError::NegativeTimeout.into()),
+        t => Ok(Duration::from_millis(t as u64)),
+    }
+}
+
+/// A timeout given in seconds, fractions allowed
+fn timeout_secs(t: &RedisValue) -> Result<Duration> {
+    let t: Bytes = t.try_into()?;
+    let t = std::str::from_utf8(&t)
+        .ok()
+        .and_then(|t| t.parse::<f64>().ok())
+        .filter(|t| t.is_finite())
+        .ok_or(RedisError::other("timeout is not a float or out of range"))?;
+    match t {
+        t if t < 0.0 => Err(RedisError::NegativeTimeout.into()),
+        t => Ok(Duration::try_from_secs_f64(t)
+            .map_err(|_| RedisError::other("timeout is out of range"))?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_error(args: &[&str]) -> String {
+        let cmd = RedisValue::command(args.iter().map(|a| Bytes::from(a.to_string())));
+        match RedisCommand::parse(cmd) {
+            Ok(_) => panic!("{args:?} parsed"),
+            Err(e) => e.downcast::<RedisError>().unwrap().to_string(),
+        }
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let parse = |args: &[&str]| {
+            RedisCommand::parse(RedisValue::command(
+                args.iter().map(|a| Bytes::from(a.to_string())),
+            ))
+            .unwrap()
+        };
+        assert!(matches!(
+            parse(&["sEt", "k", "v", "px", "100"]),
+            RedisCommand::Set {
+                expiration: Some(d),
+                ..
+            } if d == Duration::from_millis(100)
+        ));
+        assert!(matches!(
+            parse(&["flushall", "async"]),
+            RedisCommand::FlushAll { lazy: Some(true) }
+        ));
+        assert!(matches!(
+            parse(&["cluster", "keyslot", "k"]),
+            RedisCommand::Cluster(ClusterCommand::KeySlot(_))
+        ));
+        assert!(matches!(
+            parse(&["memory", "usage", "k", "samples", "5"]),
+            RedisCommand::Memory(MemoryCommand::Usage(_))
+        ));
+        assert!(matches!(
+            parse(&["client", "tracking", "on", "bcast", "prefix", "a:", "noloop"]),
+            RedisCommand::Client(ClientCommand::Tracking(Some(TrackingOptions {
+                bcast: true,
+                noloop: true,
+                ref prefixes,
+                ..
+            }))) if prefixes == &[Bytes::from("a:")]
+        ));
+        assert!(matches!(
+            parse(&["failover", "to", "h", "1", "force", "timeout", "10"]),
+            RedisCommand::Failover { force: true, .. }
+        ));
+        assert!(matches!(
+            parse(&["migrate", "h", "1", "", "0", "5", "copy", "keys", "a", "b"]),
+            RedisCommand::Migrate { copy: true, ref keys, .. } if keys.len() == 2
+        ));
+        assert!(matches!(
+            parse(&["blpop", "a", "b", "0"]),
+            RedisCommand::BPop { ref keys, timeout: None, .. } if keys.len() == 2
+        ));
+    }
+
+    #[test]
+    fn binary_safe() {
+        let key = Bytes::from_static(b"k\xff\x00y");
+        let value = Bytes::from_static(b"\r\nSET\xc3\x28 px");
+        let RedisCommand::Set {
+            key: k,
+            value: v,
+            expiration: None,
+        } = RedisCommand::parse(RedisValue::command([
+            Bytes::from("SET"),
+            key.clone(),
+            value.clone(),
+        ]))
+        .unwrap()
+        else {
+            panic!("not a plain SET");
+        };
+        assert_eq!((k, v), (key, value));
+
+        // a lowercase value is not an option to be upper-cased
+        let RedisCommand::Echo(msg) =
+            RedisCommand::parse(RedisValue::command(["echo", "px"])).unwrap()
+        else {
+            panic!("not an ECHO");
+        };
+        assert_eq!(msg, Bytes::from("px"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            parse_error(&["GET"]),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert_eq!(
+            parse_error(&["MSet", "a"]),
+            "ERR wrong number of arguments for 'mset' command"
+        );
+        assert_eq!(
+            parse_error(&["nope", "a", "b"]),
+            "ERR unknown command 'nope', with args beginning with: 'a' 'b' "
+        );
+        assert_eq!(
+            parse_error(&["publish", "channel"]),
+            "ERR wrong number of arguments for 'publish' command"
+        );
+        assert_eq!(
+            parse_error(&["CLIENT", "No-Evict"]),
+            "ERR wrong number of arguments for 'client|no-evict' command"
+        );
+        assert_eq!(
+            parse_error(&["cluster", "meet", "h", "1", "2", "3"]),
+            "ERR wrong number of arguments for 'cluster|meet' command"
+        );
+        assert_eq!(parse_error(&["SET", "k", "v", "XX"]), "ERR syntax error");
+        assert_eq!(parse_error(&["FAILOVER", "TO", "h"]), "ERR syntax error");
+        assert_eq!(
+            parse_error(&["FLUSHALL", "ASYNC", "SYNC"]),
+            "ERR syntax error"
+        );
+        assert_eq!(
+            parse_error(&["CLIENT", "TRACKING", "on", "redirect"]),
+            "ERR syntax error"
+        );
+        assert_eq!(parse_error(&["set", "k", "v", "px"]), "ERR syntax error");
+        assert_eq!(
+            parse_error(&["SET", "k", "v", "px".repeat(KEYWORD_MAX).as_str()]),
+            "ERR syntax error"
+        );
+        assert_eq!(
+            parse_error(&["SET", "k", "v", "PX", "soon"]),
+            "ERR value is not an integer or out of range"
+        );
+        assert_eq!(
+            parse_error(&["SET", "k", "v", "EX", "0"]),
+            "ERR invalid expire time in 'set' command"
+        );
+        assert_eq!(parse_error(&["WAIT", "1", "-1"]), "ERR timeout is negative");
+        assert_eq!(
+            parse_error(&["BLPOP", "list", "-1"]),
+            "ERR timeout is negative"
+        );
+        assert_eq!(
+            parse_error(&["BLPOP", "list", "soon"]),
+            "ERR timeout is not a float or out of range"
+        );
+        assert_eq!(
+            parse_error(&["CLUSTER", "bogus"]),
+            "ERR unknown subcommand 'bogus'. Try CLUSTER HELP."
+        );
+        assert_eq!(
+            parse_error(&["CLUSTER", "SETSLOT", "16384", "STABLE"]),
+            "ERR Invalid or out of range slot"
+        );
+        assert_eq!(
+            parse_error(&["CLUSTER", "SETSLOT", "1", "STABLE", "id"]),
+            "ERR Invalid CLUSTER SETSLOT action or number of arguments. Try CLUSTER HELP"
+        );
+        assert_eq!(
+            parse_error(&["RESTORE", "k", "-1", "payload"]),
+            "ERR Invalid TTL value, must be >= 0"
+        );
+        assert_eq!(
+            parse_error(&["MIGRATE", "h", "1", "k", "0", "5", "KEYS", "a"]),
+            "ERR When using MIGRATE KEYS option, the key argument must be set to an empty string"
+        );
+        assert_eq!(
+            parse_error(&["MIGRATE", "h", "1", "k", "1", "5"]),
+            "ERR DB index is out of range"
+        );
+    }
 }
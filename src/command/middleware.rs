@@ -0,0 +1,102 @@
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+
+use crate::resp::RedisValue;
+
+/// The client a command was sent by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientContext {
+    /// As shown by CLIENT ID
+    pub id: u64,
+    pub addr: SocketAddr,
+}
+
+/// Hooks run around every command a client sends, for auditing, caching, refusing commands or
+/// collecting metrics. Commands are seen as their arguments, the command name first.
+///
+/// Hooks run on the connection's task, so they should be quick. Commands applied from a master
+/// are part of the replication stream and bypass middleware.
+pub trait Middleware: Send + Sync {
+    /// Called before a command runs. Returning a reply answers the command with it instead of
+    /// running it, e.g. a [`RedisValue::SimpleError`] to refuse it or a cached value.
+    fn before(&self, client: &ClientContext, args: &[Bytes]) -> Option<RedisValue> {
+        let _ = (client, args);
+        None
+    }
+
+    /// Called once a command ran, with its reply. Errors are [`RedisValue::SimpleError`]s.
+    fn after(&self, client: &ClientContext, args: &[Bytes], reply: &RedisValue) {
+        let _ = (client, args, reply);
+    }
+}
+
+/// A command's arguments, as middleware sees them
+pub(crate) fn args(command: &RedisValue) -> Vec<Bytes> {
+    match command {
+        RedisValue::Array(values) => values
+            .iter()
+            .filter_map(|value| value.as_bulk_string().cloned())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::server::Redis;
+
+    /// Refuses FLUSHALL and records every reply
+    #[derive(Clone, Default)]
+    struct Audit(Arc<Mutex<Vec<(Bytes, RedisValue)>>>);
+
+    impl Middleware for Audit {
+        fn before(&self, _: &ClientContext, args: &[Bytes]) -> Option<RedisValue> {
+            args[0]
+                .eq_ignore_ascii_case(b"FLUSHALL")
+                .then(|| RedisValue::SimpleError("ERR not here".into()))
+        }
+
+        fn after(&self, _: &ClientContext, args: &[Bytes], reply: &RedisValue) {
+            self.0
+                .lock()
+                .unwrap()
+                .push((args[0].clone(), reply.clone()));
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_around_commands() {
+        let audit = Audit::default();
+        let redis = Redis::builder()
+            .port(0)
+            .dir(std::env::temp_dir())
+            .dbfilename("middleware-test-missing.rdb")
+            .middleware(audit.clone())
+            .start()
+            .await
+            .unwrap();
+        let mut client = TcpStream::connect(redis.addr()).await.unwrap();
+        client
+            .write_all(b"*1\r\n$8\r\nFLUSHALL\r\n*1\r\n$4\r\nPING\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0; 22];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"-ERR not here\r\n+PONG\r\n");
+        redis.stop().await.unwrap();
+
+        assert_eq!(
+            *audit.0.lock().unwrap(),
+            vec![("PING".into(), RedisValue::SimpleString("PONG".into()))]
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use std::{borrow::Cow, fmt, future::Future, pin::Pin, sync::Arc};
+
+use anyhow::Result;
+use bytes::Bytes;
+
+use crate::{
+    command::{
+        middleware::{ClientContext, Middleware},
+        table::{self, CommandFlags, CommandSpec},
+        RedisCommand,
+    },
+    error::RedisError,
+    resp::RedisValue,
+};
+
+/// Runs a custom command, given its arguments with the command name first
+pub(crate) type Handler = Arc<
+    dyn Fn(Vec<Bytes>) -> Pin<Box<dyn Future<Output = Result<RedisValue>> + Send>> + Send + Sync,
+>;
+
+/// A command registered by the program embedding the server
+struct CustomCommand {
+    spec: CommandSpec,
+    handler: Handler,
+}
+
+/// Every command the server serves: the built-in table, then any registered at startup. Both
+/// COMMAND and the dispatcher look commands up here, and clients' commands go through the
+/// middleware registered with them.
+#[derive(Default)]
+pub(crate) struct CommandRegistry {
+    custom: Vec<CustomCommand>,
+    /// Run in order before each command, and again after it
+    middleware: Vec<Arc<dyn Middleware>>,
+}
+
+impl fmt::Debug for CommandRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandRegistry")
+            .field(
+                "custom",
+                &self
+                    .custom
+                    .iter()
+                    .map(|command| &command.spec.name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("middleware", &self.middleware.len())
+            .finish()
+    }
+}
+
+impl CommandRegistry {
+    /// Add a custom command. Its name must not be taken, built-in commands can't be replaced.
+    pub(crate) fn register(
+        &mut self,
+        name: &str,
+        arity: i32,
+        flags: CommandFlags,
+        handler: Handler,
+    ) -> Result<()> {
+        if arity == 0 {
+            anyhow::bail!("Command {name} needs an arity, at least its name");
+        }
+        if name.is_empty() || self.lookup(name.as_bytes()).is_some() {
+            anyhow::bail!("Command name {name:?} is already taken");
+        }
+        self.custom.push(CustomCommand {
+            spec: CommandSpec {
+                name: Cow::Owned(name.to_ascii_uppercase()),
+                arity,
+                flags,
+                first_key: 0,
+                last_key: 0,
+                step: 0,
+                options: &[],
+                subcommands: &[],
+            },
+            handler,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn add_middleware(&mut self, middleware: Arc<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    /// Whether any middleware wants to see commands
+    pub(crate) fn intercepted(&self) -> bool {
+        !self.middleware.is_empty()
+    }
+
+    /// Run the `before` hooks, stopping at the first that answers the command itself
+    pub(crate) fn before(&self, client: &ClientContext, args: &[Bytes]) -> Option<RedisValue> {
+        self.middleware
+            .iter()
+            .find_map(|middleware| middleware.before(client, args))
+    }
+
+    pub(crate) fn after(&self, client: &ClientContext, args: &[Bytes], reply: &RedisValue) {
+        for middleware in &self.middleware {
+            middleware.after(client, args, reply);
+        }
+    }
+
+    /// Find a command by name, case-insensitively, with its handler if it is a custom one
+    fn lookup(&self, name: &[u8]) -> Option<(&CommandSpec, Option<&Handler>)> {
+        if let Some(spec) = table::lookup(name) {
+            return Some((spec, None));
+        }
+        self.custom
+            .iter()
+            .find(|command| command.spec.name.as_bytes().eq_ignore_ascii_case(name))
+            .map(|command| (&command.spec, Some(&command.handler)))
+    }
+
+    /// Every command, built-in ones first
+    pub(crate) fn specs(&self) -> impl Iterator<Item = &CommandSpec> {
+        table::COMMANDS
+            .iter()
+            .chain(self.custom.iter().map(|command| &command.spec))
+    }
+
+    /// A command by name, case-insensitively
+    pub(crate) fn spec(&self, name: &[u8]) -> Option<&CommandSpec> {
+        self.lookup(name).map(|(spec, _)| spec)
+    }
+
+    /// Parse a command frame, checking the command exists and has the right arity. The frame is
+    /// left as it is, to be propagated once the command is served.
+    pub(crate) fn parse(&self, msg: &RedisValue) -> Result<(RedisCommand, &CommandSpec)> {
+        let RedisValue::Array(values) = msg else {
+            return Err(RedisError::Protocol("expected a command array".into()).into());
+        };
+        let name = RedisCommand::name(values)?;
+        let Some((spec, handler)) = self.lookup(name) else {
+            return Err(RedisCommand::unknown_command(values));
+        };
+        RedisCommand::check_arity(spec, values)?;
+        let cmd = match handler {
+            Some(handler) => RedisCommand::Custom {
+                handler: handler.clone(),
+                args: values.iter().map(Bytes::try_from).collect::<Result<_>>()?,
+            },
+            None => RedisCommand::parse_args(spec, values)?,
+        };
+        Ok((cmd, spec))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> Handler {
+        Arc::new(|args: Vec<Bytes>| {
+            Box::pin(async move { Ok(RedisValue::Integer(args.len() as i64)) })
+        })
+    }
+
+    fn parse(registry: &CommandRegistry, args: &[&'static str]) -> Result<RedisCommand> {
+        let frame = RedisValue::command(args.iter().copied());
+        registry.parse(&frame).map(|(cmd, _)| cmd)
+    }
+
+    #[tokio::test]
+    async fn dispatches_custom_commands() {
+        let mut registry = CommandRegistry::default();
+        registry
+            .register("hits", -2, CommandFlags::READONLY, handler())
+            .unwrap();
+        assert!(registry
+            .register("GET", 2, CommandFlags::READONLY, handler())
+            .is_err());
+        assert!(registry
+            .register("HITS", 2, CommandFlags::NONE, handler())
+            .is_err());
+
+        let Ok(RedisCommand::Custom { handler, args }) = parse(&registry, &["Hits", "a", "b"])
+        else {
+            panic!("HITS not dispatched to its handler");
+        };
+        assert_eq!(handler(args).await.unwrap(), RedisValue::Integer(3));
+
+        let error = |args| parse(&registry, args).err().unwrap().to_string();
+        assert_eq!(
+            error(&["hits"]),
+            "ERR wrong number of arguments for 'hits' command"
+        );
+        assert_eq!(
+            error(&["get", "a", "b"]),
+            "ERR wrong number of arguments for 'get' command"
+        );
+        assert!(error(&["nope"]).starts_with("ERR unknown command 'nope'"));
+        assert_eq!(registry.specs().count(), table::COMMANDS.len() + 1);
+        assert!(registry
+            .spec(b"set")
+            .unwrap()
+            .flags
+            .contains(CommandFlags::DENYOOM));
+    }
+}
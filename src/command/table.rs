@@ -0,0 +1,439 @@
+use std::{borrow::Cow, ops::BitOr};
+
+use bytes::Bytes;
+
+use crate::resp::RedisValue;
+
+/// Properties of a command that decide how it is served, as listed by COMMAND
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandFlags(u8);
+
+impl CommandFlags {
+    pub const NONE: Self = Self(0);
+    /// Modifies the dataset, so it is refused on replicas and propagated to them
+    pub const WRITE: Self = Self(1);
+    /// Only reads the dataset
+    pub const READONLY: Self = Self(1 << 1);
+    /// May grow the dataset, so it is refused once `maxmemory` is reached
+    pub const DENYOOM: Self = Self(1 << 2);
+    /// Administrative, such as SAVE or REPLICAOF
+    pub const ADMIN: Self = Self(1 << 3);
+    /// Runs in constant or logarithmic time
+    pub const FAST: Self = Self(1 << 4);
+    /// May wait for other clients' writes, so it applies and propagates its own effects
+    pub const BLOCKING: Self = Self(1 << 5);
+    /// Served while the dataset is still being loaded
+    pub const LOADING: Self = Self(1 << 6);
+
+    const NAMES: &[(Self, &str)] = &[
+        (Self::WRITE, "write"),
+        (Self::READONLY, "readonly"),
+        (Self::DENYOOM, "denyoom"),
+        (Self::ADMIN, "admin"),
+        (Self::FAST, "fast"),
+        (Self::BLOCKING, "blocking"),
+        (Self::LOADING, "loading"),
+    ];
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Names of the flags set, as COMMAND shows them
+    pub(crate) fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .iter()
+            .filter(move |(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+    }
+}
+
+impl BitOr for CommandFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// A command's arity, flags and where its keys sit among its arguments, in the style of Redis'
+/// legacy key specs
+pub(crate) struct CommandSpec {
+    pub(crate) name: Cow<'static, str>,
+
+    /// Number of arguments including the name, negative for at least that many
+    pub(crate) arity: i32,
+
+    pub(crate) flags: CommandFlags,
+
+    /// Index of the first key, 0 if the command takes no keys
+    pub(crate) first_key: usize,
+
+    /// Index of the last key, negative to count from the end (-1 is the last argument)
+    pub(crate) last_key: isize,
+
+    /// Distance between keys, e.g. 2 for MSET's key/value pairs
+    pub(crate) step: usize,
+
+    /// Options that may follow the arguments the arity requires, in any order
+    pub(crate) options: &'static [OptionSpec],
+
+    /// Subcommands of a container command such as CLIENT, their arity counting the command
+    pub(crate) subcommands: &'static [CommandSpec],
+}
+
+/// An option following a command's required arguments, such as SET's `PX milliseconds`
+#[derive(Debug)]
+pub(crate) struct OptionSpec {
+    pub(crate) name: &'static str,
+
+    /// Number of arguments it takes, `None` for all that follow it
+    pub(crate) args: Option<usize>,
+}
+
+const READ: CommandFlags = CommandFlags::READONLY;
+const WRITE: CommandFlags = CommandFlags::WRITE;
+const DENYOOM: CommandFlags = with(WRITE, CommandFlags::DENYOOM);
+const ADMIN: CommandFlags = CommandFlags::ADMIN;
+const FAST: CommandFlags = CommandFlags::FAST;
+const BLOCKING: CommandFlags = with(WRITE, CommandFlags::BLOCKING);
+const LOADING: CommandFlags = CommandFlags::LOADING;
+const NONE: CommandFlags = CommandFlags::NONE;
+
+/// `flags` combined, usable in constants
+const fn with(flags: CommandFlags, other: CommandFlags) -> CommandFlags {
+    CommandFlags(flags.0 | other.0)
+}
+
+const fn spec(
+    name: &'static str,
+    arity: i32,
+    flags: CommandFlags,
+    first_key: usize,
+    last_key: isize,
+    step: usize,
+) -> CommandSpec {
+    CommandSpec {
+        name: Cow::Borrowed(name),
+        arity,
+        flags,
+        first_key,
+        last_key,
+        step,
+        options: &[],
+        subcommands: &[],
+    }
+}
+
+/// An option taking `args` arguments
+const fn opt(name: &'static str, args: usize) -> OptionSpec {
+    OptionSpec {
+        name,
+        args: Some(args),
+    }
+}
+
+/// An option taking every argument after it
+const fn rest(name: &'static str) -> OptionSpec {
+    OptionSpec { name, args: None }
+}
+
+const CLUSTER: &[CommandSpec] = &[
+    spec("INFO", 2, NONE, 0, 0, 0),
+    spec("MYID", 2, NONE, 0, 0, 0),
+    spec("SLOTS", 2, NONE, 0, 0, 0),
+    spec("SHARDS", 2, NONE, 0, 0, 0),
+    spec("KEYSLOT", 3, NONE, 0, 0, 0),
+    spec("NODES", 2, NONE, 0, 0, 0),
+    spec("MEET", -4, ADMIN, 0, 0, 0),
+    spec("SETSLOT", -4, ADMIN, 0, 0, 0),
+    spec("COUNTKEYSINSLOT", 3, NONE, 0, 0, 0),
+    spec("GETKEYSINSLOT", 4, NONE, 0, 0, 0),
+];
+
+const CLIENT: &[CommandSpec] = &[
+    spec("ID", 2, NONE, 0, 0, 0),
+    spec("TRACKING", -3, NONE, 0, 0, 0).with_options(&[
+        opt("REDIRECT", 1),
+        opt("PREFIX", 1),
+        opt("BCAST", 0),
+        opt("OPTIN", 0),
+        opt("OPTOUT", 0),
+        opt("NOLOOP", 0),
+    ]),
+    spec("CACHING", 3, NONE, 0, 0, 0),
+    spec("GETREDIR", 2, NONE, 0, 0, 0),
+    spec("NO-EVICT", 3, ADMIN, 0, 0, 0),
+];
+
+const CONFIG: &[CommandSpec] = &[spec("GET", -3, ADMIN, 0, 0, 0)];
+
+const OBJECT: &[CommandSpec] = &[
+    spec("ENCODING", 3, READ, 2, 2, 1),
+    spec("FREQ", 3, READ, 2, 2, 1),
+];
+
+const MEMORY: &[CommandSpec] =
+    &[spec("USAGE", -3, READ, 2, 2, 1).with_options(&[opt("SAMPLES", 1)])];
+
+const HOTKEYS: &[CommandSpec] = &[
+    spec("GET", -2, ADMIN, 0, 0, 0).with_options(&[opt("COUNT", 1)]),
+    spec("RESET", 2, ADMIN, 0, 0, 0),
+];
+
+const COMMAND: &[CommandSpec] = &[
+    spec("COUNT", 2, NONE, 0, 0, 0),
+    spec("INFO", -2, NONE, 0, 0, 0),
+    spec("LIST", -2, NONE, 0, 0, 0),
+    spec("GETKEYS", -3, NONE, 0, 0, 0),
+    spec("GETKEYSANDFLAGS", -3, NONE, 0, 0, 0),
+];
+
+const FLUSH: &[OptionSpec] = &[opt("ASYNC", 0), opt("SYNC", 0)];
+
+const RESTORE: &[OptionSpec] = &[opt("REPLACE", 0), opt("ABSTTL", 0)];
+
+/// Every built-in command, with its arity, flags and key positions. A static, so each entry
+/// has the one address [`stats`](crate::server::stats) keeps its statistics by.
+pub(crate) static COMMANDS: &[CommandSpec] = &[
+    spec("PING", -1, with(FAST, LOADING), 0, 0, 0),
+    spec("ECHO", 2, with(FAST, LOADING), 0, 0, 0),
+    spec("GET", 2, with(READ, FAST), 1, 1, 1),
+    spec("MGET", -2, with(READ, FAST), 1, -1, 1),
+    spec("SET", -3, DENYOOM, 1, 1, 1).with_options(&[opt("PX", 1), opt("EX", 1)]),
+    spec("MSET", -3, DENYOOM, 1, -1, 2),
+    spec("INCR", 2, with(DENYOOM, FAST), 1, 1, 1),
+    spec("DECR", 2, with(DENYOOM, FAST), 1, 1, 1),
+    spec("INCRBY", 3, with(DENYOOM, FAST), 1, 1, 1),
+    spec("DECRBY", 3, with(DENYOOM, FAST), 1, 1, 1),
+    spec("RPUSH", -3, with(DENYOOM, FAST), 1, 1, 1),
+    spec("LPUSH", -3, with(DENYOOM, FAST), 1, 1, 1),
+    spec("LRANGE", 4, READ, 1, 1, 1),
+    spec("LPOP", -2, with(WRITE, FAST), 1, 1, 1),
+    spec("RPOP", -2, with(WRITE, FAST), 1, 1, 1),
+    spec("BLPOP", -3, BLOCKING, 1, -2, 1),
+    spec("BRPOP", -3, BLOCKING, 1, -2, 1),
+    spec("SADD", -3, with(DENYOOM, FAST), 1, 1, 1),
+    spec("SREM", -3, with(WRITE, FAST), 1, 1, 1),
+    spec("SPOP", -2, with(WRITE, FAST), 1, 1, 1),
+    spec("SCARD", 2, with(READ, FAST), 1, 1, 1),
+    spec("HSET", -4, with(DENYOOM, FAST), 1, 1, 1),
+    spec("HGET", 3, with(READ, FAST), 1, 1, 1),
+    spec("ZADD", -4, with(DENYOOM, FAST), 1, 1, 1),
+    spec("ZSCORE", 3, with(READ, FAST), 1, 1, 1),
+    spec("ZCARD", 2, with(READ, FAST), 1, 1, 1),
+    spec("ZPOPMIN", -2, with(WRITE, FAST), 1, 1, 1),
+    spec("DEL", -2, WRITE, 1, -1, 1),
+    spec("KEYS", 2, READ, 0, 0, 0),
+    spec("UNLINK", -2, with(WRITE, FAST), 1, -1, 1),
+    spec("FLUSHALL", -1, WRITE, 0, 0, 0).with_options(FLUSH),
+    spec("FLUSHDB", -1, WRITE, 0, 0, 0).with_options(FLUSH),
+    spec("EXPIRE", 3, with(WRITE, FAST), 1, 1, 1),
+    spec("PEXPIREAT", 3, with(WRITE, FAST), 1, 1, 1),
+    spec("TTL", 2, with(READ, FAST), 1, 1, 1),
+    spec("PTTL", 2, with(READ, FAST), 1, 1, 1),
+    spec("DUMP", 2, READ, 1, 1, 1),
+    spec("RESTORE", -4, DENYOOM, 1, 1, 1).with_options(RESTORE),
+    spec("RESTORE-ASKING", -4, DENYOOM, 1, 1, 1).with_options(RESTORE),
+    spec("MIGRATE", -6, WRITE, 3, 3, 1).with_options(&[
+        opt("COPY", 0),
+        opt("REPLACE", 0),
+        rest("KEYS"),
+    ]),
+    spec("SAVE", 1, ADMIN, 0, 0, 0),
+    spec("BGSAVE", -1, ADMIN, 0, 0, 0),
+    spec("BGREWRITEAOF", 1, ADMIN, 0, 0, 0),
+    spec("REPLCONF", -1, ADMIN, 0, 0, 0),
+    spec("PSYNC", -3, ADMIN, 0, 0, 0),
+    spec("WAIT", 3, NONE, 0, 0, 0),
+    spec("INFO", -1, LOADING, 0, 0, 0),
+    spec("ROLE", 1, with(FAST, LOADING), 0, 0, 0),
+    spec("SUBSCRIBE", -2, LOADING, 0, 0, 0),
+    spec("UNSUBSCRIBE", -1, LOADING, 0, 0, 0),
+    spec("PUBLISH", 3, with(FAST, LOADING), 0, 0, 0),
+    spec("REPLICAOF", 3, with(ADMIN, LOADING), 0, 0, 0),
+    spec("SLAVEOF", 3, with(ADMIN, LOADING), 0, 0, 0),
+    spec("FAILOVER", -1, ADMIN, 0, 0, 0).with_options(&[
+        opt("TO", 2),
+        opt("FORCE", 0),
+        opt("ABORT", 0),
+        opt("TIMEOUT", 1),
+    ]),
+    spec("CLUSTER", -2, NONE, 0, 0, 0).with_subcommands(CLUSTER),
+    spec("ASKING", 1, FAST, 0, 0, 0),
+    spec("READONLY", 1, with(FAST, LOADING), 0, 0, 0),
+    spec("READWRITE", 1, with(FAST, LOADING), 0, 0, 0),
+    spec("CLIENT", -2, LOADING, 0, 0, 0).with_subcommands(CLIENT),
+    spec("HELLO", -1, with(FAST, LOADING), 0, 0, 0),
+    spec("AUTH", -2, with(FAST, LOADING), 0, 0, 0),
+    spec("CONFIG", -2, with(ADMIN, LOADING), 0, 0, 0).with_subcommands(CONFIG),
+    spec("OBJECT", -2, READ, 2, 2, 1).with_subcommands(OBJECT),
+    spec("MEMORY", -2, READ, 2, 2, 1).with_subcommands(MEMORY),
+    spec("HOTKEYS", -2, ADMIN, 0, 0, 0).with_subcommands(HOTKEYS),
+    spec("COMMAND", -1, LOADING, 0, 0, 0).with_subcommands(COMMAND),
+];
+
+/// Find a command by name, case-insensitively
+pub(crate) fn lookup(name: &[u8]) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+}
+
+impl CommandSpec {
+    const fn with_options(mut self, options: &'static [OptionSpec]) -> Self {
+        self.options = options;
+        self
+    }
+
+    const fn with_subcommands(mut self, subcommands: &'static [CommandSpec]) -> Self {
+        self.subcommands = subcommands;
+        self
+    }
+
+    /// The subcommand a full command line (name included) calls, if it is one of ours
+    pub(crate) fn subcommand(&self, args: &[RedisValue]) -> Option<&CommandSpec> {
+        let name = args.get(1)?.as_bulk_string()?;
+        self.subcommands
+            .iter()
+            .find(|spec| spec.name.as_bytes().eq_ignore_ascii_case(name))
+    }
+
+    /// Whether a command line of `len` arguments (name included) has the right arity
+    pub(crate) fn accepts(&self, len: usize) -> bool {
+        match self.arity {
+            arity if arity < 0 => len >= arity.unsigned_abs() as usize,
+            arity => len == arity as usize,
+        }
+    }
+
+    /// The classic COMMAND INFO reply: name, arity, flags and key positions
+    pub(crate) fn info(&self) -> RedisValue {
+        RedisValue::Array(vec![
+            RedisValue::BulkString(self.name.to_lowercase().into()),
+            RedisValue::Integer(self.arity as i64),
+            RedisValue::Array(
+                self.flags
+                    .names()
+                    .map(|flag| RedisValue::SimpleString(flag.into()))
+                    .collect(),
+            ),
+            RedisValue::Integer(self.first_key as i64),
+            RedisValue::Integer(self.last_key as i64),
+            RedisValue::Integer(self.step as i64),
+        ])
+    }
+
+    /// How the command uses its keys, as COMMAND GETKEYSANDFLAGS reports it. There are no
+    /// per-key specs, so this follows from whether the command reads or writes.
+    pub(crate) fn key_flags(&self) -> &'static [&'static str] {
+        if self.flags.contains(CommandFlags::WRITE) {
+            &["RW", "update"]
+        } else if self.flags.contains(CommandFlags::READONLY) {
+            &["RO", "access"]
+        } else {
+            &[]
+        }
+    }
+
+    /// Where the keys sit in a command line of `len` arguments (name included)
+    pub(crate) fn key_positions(&self, len: usize) -> impl Iterator<Item = usize> + use<> {
+        if self.first_key == 0 {
+            return (0..0).step_by(1);
+        }
+        let last = match self.last_key {
+            last if last < 0 => len as isize + last,
+            last => last,
+        };
+        let end = ((last + 1).max(0) as usize).min(len);
+        (self.first_key..end).step_by(self.step)
+    }
+
+    /// The keys in a full command (name included), skipping any that are not bulk strings
+    pub(crate) fn keys<'a>(&self, args: &'a [RedisValue]) -> Vec<&'a Bytes> {
+        if self.name == "MIGRATE" {
+            return migrate_keys(args);
+        }
+        self.key_positions(args.len())
+            .filter_map(|i| args[i].as_bulk_string())
+            .collect()
+    }
+}
+
+/// The keys of MIGRATE, which follow KEYS rather than sit at a fixed position when its key
+/// argument is empty
+fn migrate_keys(args: &[RedisValue]) -> Vec<&Bytes> {
+    match args.get(3).and_then(RedisValue::as_bulk_string) {
+        Some(key) if key.is_empty() => args
+            .iter()
+            .skip(6)
+            .filter_map(RedisValue::as_bulk_string)
+            .skip_while(|arg| !arg.eq_ignore_ascii_case(b"KEYS"))
+            .skip(1)
+            .collect(),
+        key => key.into_iter().collect(),
+    }
+}
+
+/// The keys a raw command frame refers to, according to the command table
+pub(crate) fn command_keys(frame: &RedisValue) -> Vec<&Bytes> {
+    let RedisValue::Array(args) = frame else {
+        return Vec::new();
+    };
+    let Some(RedisValue::BulkString(name)) = args.first() else {
+        return Vec::new();
+    };
+    lookup(name).map_or_else(Vec::new, |spec| spec.keys(args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(args: &[&'static str]) -> Vec<Bytes> {
+        let frame = RedisValue::command(args.iter().copied());
+        command_keys(&frame).into_iter().cloned().collect()
+    }
+
+    #[test]
+    fn extracts_keys() {
+        assert_eq!(keys(&["get", "a"]), vec!["a"]);
+        assert_eq!(keys(&["SET", "a", "1", "PX", "100"]), vec!["a"]);
+        assert_eq!(keys(&["DEL", "a", "b", "c"]), vec!["a", "b", "c"]);
+        assert_eq!(keys(&["MSET", "a", "1", "b", "2"]), vec!["a", "b"]);
+        assert_eq!(
+            keys(&["MIGRATE", "h", "1", "a", "0", "5", "COPY"]),
+            vec!["a"]
+        );
+        assert_eq!(
+            keys(&["MIGRATE", "h", "1", "", "0", "5", "REPLACE", "KEYS", "a", "b"]),
+            vec!["a", "b"]
+        );
+        assert!(keys(&["PING"]).is_empty());
+        assert!(keys(&["GET"]).is_empty());
+        assert!(keys(&["UNKNOWN", "a"]).is_empty());
+    }
+
+    #[test]
+    fn subcommands_and_options_are_keywords() {
+        // matched against arguments upper-cased on the stack
+        let keyword = |name: &str| {
+            name.len() <= crate::command::KEYWORD_MAX && name == name.to_ascii_uppercase()
+        };
+        for spec in COMMANDS {
+            for subcommand in spec.subcommands {
+                assert!(keyword(&subcommand.name), "{}", subcommand.name);
+                // the arity counts the command's name
+                assert!(subcommand.arity.abs() >= 2, "{}", subcommand.name);
+                assert!(subcommand.options.iter().all(|option| keyword(option.name)));
+            }
+            assert!(spec.options.iter().all(|option| keyword(option.name)));
+        }
+        let client = lookup(b"client").unwrap();
+        let RedisValue::Array(args) = RedisValue::command(["CLIENT", "no-evict", "on"]) else {
+            unreachable!();
+        };
+        assert_eq!(client.subcommand(&args).unwrap().name, "NO-EVICT");
+        assert!(client.subcommand(&args[..1]).is_none());
+    }
+}
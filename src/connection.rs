@@ -1,131 +1,1844 @@
 use anyhow::Result;
-use dashmap::DashMap;
-use futures::{SinkExt, StreamExt};
+use bytes::Bytes;
+use futures::{FutureExt, StreamExt};
 use std::{
     net::SocketAddr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
-use tokio::{net::TcpStream, sync::mpsc::Sender};
-use tokio_util::codec::Framed;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadHalf},
+    sync::{
+        broadcast,
+        mpsc::{self, UnboundedSender},
+    },
+    task::JoinHandle,
+};
+use tokio_util::{
+    codec::{Decoder, Framed, FramedRead},
+    sync::CancellationToken,
+};
 
 use crate::{
-    command::RedisCommand,
-    resp::{codec::RespFrame, RedisValue},
-    server::types::{Database, ExpiryEvent, RedisKey, Value},
+    cluster::{self, ClusterState, Redirect},
+    command::{
+        middleware::{self, ClientContext},
+        parse_integer,
+        registry::CommandRegistry,
+        table::{self, CommandFlags},
+        ClientCommand, ClusterCommand, CommandCommand, ConfigCommand, HotKeysCommand,
+        MemoryCommand, ObjectCommand, RedisCommand,
+    },
+    error::{self, RedisError},
+    rdb::{self, RdbError},
+    replication::{
+        diskless,
+        failover::{self, FailoverTarget},
+        master, replica, FailoverState, ReplicationState, Role,
+    },
+    resp::{
+        codec::{Protocol, RespFrame},
+        RedisValue,
+    },
+    server::{
+        blocking::{self, Wakeup},
+        clients::{self, ClientMemory},
+        config::{glob_match, Config, OutputBufferLimit},
+        eviction, hotkeys, info,
+        keyspace::KeyType,
+        migrate::{self, Dumped},
+        persistence,
+        propagation::{Propagator, Write},
+        pubsub,
+        ratelimit::{RateLimiter, Verdict},
+        stats,
+        storage::Storage,
+        tasks, tracking,
+        types::{ExpiryEvent, Value},
+        watch::KeyEvent,
+    },
 };
 
+mod state;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub(crate) mod uring;
+pub(crate) mod writer;
+
+use state::{ReplyPolicy, State};
+use writer::Outgoing;
+
+/// Redis version we claim to be in HELLO, so clients enable the features we speak
+pub(crate) const REDIS_VERSION: &str = "7.2.0";
+
+/// Keys or elements a command can get through before it is run on the blocking pool instead, so
+/// it doesn't hold up the other clients served by the same worker thread
+const OFFLOAD_EFFORT: usize = 1024;
+
+/// Source of unique client IDs
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
 /// A type representing an active client connection
 pub(crate) struct RedisConnection {
+    /// Unique ID of this client
+    id: u64,
+
     /// Client address
     client_addr: SocketAddr,
 
-    /// Frame to read and write data to the client
-    frame: Framed<TcpStream, RespFrame>,
+    /// Commands read from the client
+    reader: Reader,
+
+    /// Output queued for the task writing to the client
+    outgoing: mpsc::Sender<Outgoing>,
+
+    /// The task writing to the client, see [`writer::run`]
+    writer: JoinHandle<()>,
+
+    /// Protocol negotiated with HELLO
+    protocol: Protocol,
+
+    /// Whether the client may run commands, only false until AUTH when `requirepass` is set
+    authenticated: bool,
 
     /// Reference to the global key / value store
-    db: Arc<Database>,
+    db: Arc<dyn Storage>,
+
+    /// Commands this server serves
+    commands: Arc<CommandRegistry>,
+
+    /// Server configuration
+    config: Arc<Config>,
+
+    /// Replication state shared with every other connection
+    replication: Arc<ReplicationState>,
     // big question here is would it be better to have this serialized through channels? i.e. have
     // a single channel I ask for a key for...? we'll see
     //
     /// Place to send newly set keys
-    expiration_tx: Sender<ExpiryEvent>,
+    expiration_tx: UnboundedSender<ExpiryEvent>,
+
+    /// Where writes go once applied
+    propagator: Arc<Propagator>,
+
+    /// What the connection is doing, which decides what it may run and whether it is answered
+    state: State,
+
+    /// Port announced with REPLCONF listening-port, if this client is a replica handshaking
+    listening_port: Option<u16>,
+
+    /// Address announced with REPLCONF ip-address, if this client is a replica handshaking
+    announced_ip: Option<String>,
+
+    /// Set by REPLCONF capa eof, when this client can take an RDB streamed without its length
+    capa_eof: bool,
+
+    /// What the write being served asked to be propagated in its place, see
+    /// [`propagate_effect`](Self::propagate_effect)
+    effects: Option<Vec<RedisValue>>,
+
+    /// Replication offset just after this client's most recent write, used by WAIT
+    last_write_offset: u64,
+
+    /// Cluster state, `None` unless running in cluster mode
+    cluster: Option<Arc<ClusterState>>,
+
+    /// Set by ASKING, lets the next command use a slot being imported
+    asking: bool,
+
+    /// Set by READONLY until READWRITE, lets reads of our master's slots be served here
+    readonly: bool,
+
+    /// Set by CLIENT TRACKING ON, see [`tracking`]
+    tracking: bool,
+
+    /// Set by CLIENT CACHING, whether to track the keys read by the next command
+    caching: Option<bool>,
+
+    /// Cancelled when the server shuts down
+    shutdown: CancellationToken,
+
+    /// Set when clients' commands are rate limited
+    rate_limiter: Option<RateLimiter>,
+
+    /// What the client's buffers hold, counted towards `maxmemory-clients`
+    memory: Arc<ClientMemory>,
+}
+
+/// A byte stream clients are served over: a plain TCP socket, one wrapped in TLS on the
+/// `tls-port`, or with the `io-uring` feature the stream bridged to a socket driven by
+/// [`uring::UringWorkers`].
+pub(crate) trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+pub(crate) type ClientStream = Box<dyn Transport>;
+
+/// The half of a client connection commands are read from
+pub(crate) type Reader = FramedRead<ReadHalf<ClientStream>, ClientCodec>;
+
+/// The codec commands are read with, keeping count of the client's query buffer
+pub(crate) struct ClientCodec {
+    codec: RespFrame,
+    memory: Arc<ClientMemory>,
+}
+
+impl Decoder for ClientCodec {
+    type Item = RedisValue;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>> {
+        let decoded = self.codec.decode(src);
+        self.memory.set_query(src.len());
+        decoded
+    }
+}
+
+/// Split a connection into the half read by the connection itself and a task writing its output
+fn split(
+    id: u64,
+    stream: ClientStream,
+    codec: RespFrame,
+    limit: OutputBufferLimit,
+    client_addr: SocketAddr,
+    memory: Arc<ClientMemory>,
+) -> (Reader, mpsc::Sender<Outgoing>, JoinHandle<()>) {
+    let (read, write) = tokio::io::split(stream);
+    let (tx, rx) = mpsc::channel(writer::OUTGOING_CAPACITY);
+    let writer = tasks::spawn(
+        "connection-writer",
+        Some(id),
+        writer::run(write, rx, limit, client_addr, memory.clone()),
+    );
+    (
+        FramedRead::new(read, ClientCodec { codec, memory }),
+        tx,
+        writer,
+    )
 }
 
 impl RedisConnection {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
-        stream: TcpStream,
+        stream: impl Transport + 'static,
         client_addr: SocketAddr,
-        db: Arc<Database>,
-        expiration_tx: Sender<ExpiryEvent>,
+        db: Arc<dyn Storage>,
+        commands: Arc<CommandRegistry>,
+        config: Arc<Config>,
+        replication: Arc<ReplicationState>,
+        expiration_tx: UnboundedSender<ExpiryEvent>,
+        propagator: Arc<Propagator>,
+        cluster: Option<Arc<ClusterState>>,
+        shutdown: CancellationToken,
     ) -> Self {
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let memory = ClientMemory::track(id, config.client_memory_limit() as usize);
+        let (reader, outgoing, writer) = split(
+            id,
+            Box::new(stream),
+            RespFrame::with_limits(config.proto_limits()).with_inline_commands(),
+            config.client_output_buffer_limit.normal,
+            client_addr,
+            memory.clone(),
+        );
+        clients::register(id, &outgoing);
+        let rate_limiter = RateLimiter::new(config.rate_limit, client_addr.ip());
         Self {
+            id,
             client_addr,
-            frame: Framed::new(stream, RespFrame),
+            reader,
+            outgoing,
+            writer,
+            protocol: Protocol::Resp2,
+            authenticated: config.requirepass.is_none(),
             db,
+            commands,
+            config,
+            replication,
             expiration_tx,
+            propagator,
+            state: State::Normal,
+            listening_port: None,
+            announced_ip: None,
+            capa_eof: false,
+            effects: None,
+            last_write_offset: 0,
+            cluster,
+            asking: false,
+            readonly: false,
+            tracking: false,
+            caching: None,
+            rate_limiter,
+            shutdown,
+            memory,
         }
     }
 
-    pub(crate) async fn client_loop(&mut self) {
-        while let Some(result) = self.frame.next().await {
+    /// Wrap an already established (and handshaken) connection to our master
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn master_link(
+        frame: Framed<ClientStream, RespFrame>,
+        master_addr: SocketAddr,
+        db: Arc<dyn Storage>,
+        commands: Arc<CommandRegistry>,
+        config: Arc<Config>,
+        replication: Arc<ReplicationState>,
+        expiration_tx: UnboundedSender<ExpiryEvent>,
+        propagator: Arc<Propagator>,
+    ) -> Self {
+        let parts = frame.into_parts();
+        // our master is never cut off for not reading our acks, nor for what it sends us
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        let memory = ClientMemory::untracked();
+        let (mut reader, outgoing, writer) = split(
+            id,
+            parts.io,
+            parts.codec,
+            OutputBufferLimit::default(),
+            master_addr,
+            memory.clone(),
+        );
+        // the start of the replication stream may have arrived with the end of the handshake
+        reader.read_buffer_mut().extend_from_slice(&parts.read_buf);
+        Self {
+            id,
+            client_addr: master_addr,
+            reader,
+            outgoing,
+            writer,
+            protocol: Protocol::Resp2,
+            authenticated: true,
+            db,
+            commands,
+            config,
+            replication,
+            expiration_tx,
+            propagator,
+            state: State::MasterLink,
+            listening_port: None,
+            announced_ip: None,
+            capa_eof: false,
+            effects: None,
+            last_write_offset: 0,
+            // cluster replicas follow their master, which already owns the slots
+            cluster: None,
+            asking: false,
+            readonly: false,
+            tracking: false,
+            caching: None,
+            // the link goes down with the process
+            shutdown: CancellationToken::new(),
+            rate_limiter: None,
+            memory,
+        }
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Cancel this to have the connection stop serving, as at shutdown
+    pub(crate) fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Serve the client until it hangs up, then wait for what it was sent to be written out
+    pub(crate) async fn client_loop(mut self) {
+        self.serve().await;
+        let Self {
+            id,
+            client_addr,
+            outgoing,
+            writer,
+            memory,
+            ..
+        } = self;
+        clients::unregister(id);
+        memory.untrack();
+        tracking::disable(id);
+        blocking::unblock(id);
+        pubsub::unsubscribe_all(id);
+        drop(outgoing);
+        let _ = writer.await;
+        tracing::info!("Client {client_addr} disconnected");
+    }
+
+    async fn serve(&mut self) {
+        loop {
+            // serve every command already received before flushing their replies at once, so a
+            // pipeline is answered with as few writes as possible
+            let result = match self.reader.next().now_or_never() {
+                Some(Some(result)) => result,
+                Some(None) => break,
+                None => {
+                    if self.send(Outgoing::Flush).await.is_err() {
+                        break;
+                    }
+                    // a command being served is finished and answered before shutdown is noticed
+                    tokio::select! {
+                        result = self.reader.next() => match result {
+                            Some(result) => result,
+                            None => break,
+                        },
+                        _ = self.shutdown.cancelled() => break,
+                        // the writer gave up on the client
+                        _ = self.outgoing.closed() => break,
+                    }
+                }
+            };
             match result {
-                Ok(message) => {
-                    tracing::info!("Received RESP value: {message:?}");
-                    let cmd = match RedisCommand::parse(message) {
-                        Ok(c) => c,
+                Ok(raw) => {
+                    tracing::info!("Received RESP value: {raw:?}");
+                    // parsing only borrows the frame, which is propagated as it came
+                    let (cmd, stats, flags) = match self.commands.parse(&raw) {
+                        Ok((cmd, spec)) => (cmd, stats::of(spec), spec.flags),
                         Err(e) => {
                             tracing::error!("Error while parsing command: {e:?}");
+                            let desynced = is_protocol_error(&e);
                             self.send_error(e).await;
+                            if desynced {
+                                break;
+                            }
                             continue;
                         }
                     };
 
-                    let response = match self.handle_cmd(cmd).await {
+                    if !self.authenticated && !matches!(cmd, RedisCommand::Auth { .. }) {
+                        stats.reject();
+                        self.send_error(RedisError::NoAuth.into()).await;
+                        continue;
+                    }
+
+                    // until the dataset is loaded, only the few commands flagged for it are served
+                    if self.replication.is_loading()
+                        && self.state != State::MasterLink
+                        && !flags.contains(CommandFlags::LOADING)
+                    {
+                        stats.reject();
+                        self.send_error(RedisError::Loading.into()).await;
+                        continue;
+                    }
+
+                    if let RedisCommand::Psync { replid, offset } = cmd {
+                        // this connection is now a replica, it never returns to serving commands
+                        self.state = State::ReplicaLink;
+                        let shutdown = self.shutdown.clone();
+                        tokio::select! {
+                            result = self.serve_replica(replid, offset) => {
+                                if let Err(e) = result {
+                                    tracing::error!("Replica link failed: {e:?}");
+                                }
+                            }
+                            _ = shutdown.cancelled() => {}
+                        }
+                        break;
+                    }
+
+                    if self.state == State::MasterLink {
+                        // everything from our master is part of the replication stream, so it is
+                        // applied, passed on to our own replicas and counted in our offset
+                        self.apply_from_master(cmd, raw).await;
+                        continue;
+                    }
+
+                    if let Err(e) = self.state.allows(&cmd, stats.name()) {
+                        self.send_error(e.into()).await;
+                        continue;
+                    }
+                    // a replica out of sync with its master may refuse to serve stale data
+                    if !self.config.replica_serve_stale_data
+                        && !self.replication.is_synced()
+                        && !matches!(cmd, RedisCommand::Info(_) | RedisCommand::ReplicaOf(_))
+                    {
+                        stats.reject();
+                        self.send_error(RedisError::MasterDown.into()).await;
+                        continue;
+                    }
+                    if let Some(limiter) = &self.rate_limiter {
+                        match limiter.check(&raw) {
+                            Verdict::Allow => {}
+                            Verdict::Reject => {
+                                stats.reject();
+                                self.send_error(RedisError::Throttled.into()).await;
+                                continue;
+                            }
+                            Verdict::Delay(wait) => {
+                                // hand over what was already served, then stop reading for a while
+                                if self.send(Outgoing::Flush).await.is_err() {
+                                    break;
+                                }
+                                tokio::select! {
+                                    _ = tokio::time::sleep(wait) => {}
+                                    _ = self.shutdown.cancelled() => break,
+                                }
+                            }
+                        }
+                    }
+
+                    // middleware only sees what gets past the rate limit, and may answer it itself
+                    let client = ClientContext {
+                        id: self.id,
+                        addr: self.client_addr,
+                    };
+                    let args = self.commands.intercepted().then(|| middleware::args(&raw));
+                    if let Some(args) = &args
+                        && let Some(reply) = self.commands.before(&client, args)
+                    {
+                        stats.reject();
+                        if self.reply(reply).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+
+                    // MIGRATE asks for its RESTOREs, and moves its keys whether or not they are
+                    // all still here
+                    let asking =
+                        std::mem::take(&mut self.asking) || stats.name() == "RESTORE-ASKING";
+                    let migrates = matches!(cmd, RedisCommand::Migrate { .. });
+                    // CLIENT CACHING applies to the command right after it
+                    let caching = match cmd {
+                        RedisCommand::Client(ClientCommand::Caching(_)) => None,
+                        _ => self.caching.take(),
+                    };
+                    let reads = flags.contains(CommandFlags::READONLY);
+                    if let Some(redirect) = self.redirect(&raw, asking, migrates, reads) {
+                        stats.reject();
+                        let _ = self
+                            .reply(RedisValue::SimpleError(redirect.to_string().into()))
+                            .await;
+                        continue;
+                    }
+
+                    // writes are applied and propagated under the lock so replicas see them in
+                    // the same order we applied them
+                    let is_write = flags.contains(CommandFlags::WRITE);
+                    // blocking commands take the lock for their effects alone, so the writes
+                    // they wait for can happen in the meantime
+                    let blocks = flags.contains(CommandFlags::BLOCKING);
+                    let replication = self.replication.clone();
+                    let _write_guard = match is_write && !blocks {
+                        true => Some(replication.write_lock().await),
+                        false => None,
+                    };
+
+                    // checked under the lock, as a FAILOVER may demote us while we wait for it
+                    if is_write && replication.is_replica() {
+                        stats.reject();
+                        self.send_error(RedisError::ReadOnly.into()).await;
+                        continue;
+                    }
+
+                    if flags.contains(CommandFlags::DENYOOM) && !self.make_room() {
+                        stats.reject();
+                        self.send_error(RedisError::OutOfMemory.into()).await;
+                        continue;
+                    }
+
+                    let flushes = matches!(cmd, RedisCommand::FlushAll { .. });
+
+                    // don't hold earlier replies back while WAIT or a blocking command waits
+                    if (blocks || matches!(cmd, RedisCommand::Wait { .. }))
+                        && self.send(Outgoing::Flush).await.is_err()
+                    {
+                        break;
+                    }
+
+                    let before = match is_write && !blocks {
+                        true => self.keys_before(&raw, flushes),
+                        false => Vec::new(),
+                    };
+                    let rate = self.config.hotkeys_sample_rate;
+                    if hotkeys::sampled(rate) {
+                        hotkeys::record(table::command_keys(&raw), rate);
+                    }
+                    self.effects = None;
+                    let started = Instant::now();
+                    let result = self.handle_cmd(cmd).await;
+                    // SUBSCRIBE, UNSUBSCRIBE and HELLO may have moved us in or out of subscriber mode
+                    self.state = self
+                        .state
+                        .subscribed(self.protocol, pubsub::is_subscribed(self.id));
+                    stats.record(started.elapsed(), result.is_err());
+                    let response = match result {
                         Ok(r) => r,
                         Err(e) => {
                             tracing::error!("Error handling command: {e:?}");
+                            if let Some(args) = &args {
+                                self.commands.after(&client, args, &error::reply(&e));
+                            }
                             self.send_error(e).await;
                             continue;
                         }
                     };
+                    if let Some(args) = &args {
+                        self.commands.after(&client, args, &response);
+                    }
 
-                    let _ = self.frame.send(response).await;
+                    if blocks {
+                        // already applied and propagated, as whatever it ended up doing
+                    } else if is_write {
+                        let commands = self.effects.take().unwrap_or_else(|| vec![raw]);
+                        let events = Propagator::events(&*self.db, before);
+                        let write = Write::new(commands, events).flushed(flushes);
+                        self.last_write_offset = self.propagator.propagate(write.by(Some(self.id)));
+                    } else if self.tracking {
+                        tracking::remember(self.id, table::command_keys(&raw), caching);
+                    }
+
+                    if self.reply(response).await.is_err() {
+                        break;
+                    }
                 }
                 Err(e) => {
+                    // whatever follows can't be told apart from garbage, so give up on the client
+                    // as Redis does, after telling it why unless the socket itself failed
                     tracing::error!("Received error while decoding message: {e:?}");
-                    self.send_error(e).await;
-                    continue;
+                    if is_protocol_error(&e) {
+                        self.send_error(e).await;
+                    }
+                    break;
                 }
             }
         }
     }
 
+    /// Evict keys as the policy allows until the dataset fits in `maxmemory`, propagating each
+    /// eviction as a DEL. Must be called holding the write lock.
+    fn make_room(&self) -> bool {
+        eviction::make_room(&*self.db, &self.config, |key| {
+            self.propagator.propagate(Write::single(
+                RedisValue::command([Bytes::from("DEL"), key.clone()]),
+                KeyEvent::Deleted(key),
+            ));
+        })
+    }
+
+    /// The keys a write may change, noted to tell what it did once applied. A flush may change
+    /// any key, though only watchers need to hear about each.
+    fn keys_before(&self, raw: &RedisValue, flushes: bool) -> Vec<(Bytes, bool)> {
+        match flushes {
+            true if self.propagator.watchers().watching() => {
+                self.propagator.before(&*self.db, &self.db.keys())
+            }
+            true => Vec::new(),
+            false => self.propagator.before(&*self.db, table::command_keys(raw)),
+        }
+    }
+
+    /// In cluster mode, why a command cannot be served here, based on the keys it names. Keys
+    /// missing from a slot being migrated are only looked for elsewhere unless `local` is set.
+    fn redirect(
+        &self,
+        raw: &RedisValue,
+        asking: bool,
+        local: bool,
+        reads: bool,
+    ) -> Option<Redirect> {
+        let cluster = self.cluster.as_ref()?;
+        let keys = table::command_keys(raw);
+        let missing_keys = !local && keys.iter().any(|key| !self.db.exists(key));
+        match cluster.route_keys(&keys, asking, missing_keys) {
+            // after READONLY, a replica serves reads of the slots its master owns
+            Err(Redirect::Moved { addr, .. })
+                if reads && self.readonly && self.master_addr().is_some_and(|m| m == addr) =>
+            {
+                None
+            }
+            result => result.err(),
+        }
+    }
+
+    /// `host:port` of our master, if we are a replica
+    fn master_addr(&self) -> Option<String> {
+        match self.replication.role() {
+            Role::Replica { host, port } => Some(format!("{host}:{port}")),
+            Role::Master => None,
+        }
+    }
+
+    /// Up to `count` of the keys stored in hash `slot`
+    fn keys_in_slot(&self, slot: u16, count: usize) -> Vec<Bytes> {
+        self.db
+            .keys()
+            .into_iter()
+            .filter(|key| cluster::key_slot(key) == slot)
+            .take(count)
+            .collect()
+    }
+
+    /// Apply a command received on the link from our master
+    async fn apply_from_master(&mut self, cmd: RedisCommand, raw: RedisValue) {
+        let replication = self.replication.clone();
+        let _write_guard = replication.write_lock().await;
+        replication.touch_master();
+
+        match cmd {
+            // the master asks for our offset, which must not include the GETACK itself
+            RedisCommand::ReplConf(args)
+                if args
+                    .first()
+                    .is_some_and(|a| a.eq_ignore_ascii_case(b"GETACK")) =>
+            {
+                let offset = replication.offset().to_string();
+                let ack = RedisValue::command(["REPLCONF".into(), "ACK".into(), offset]);
+                if let Err(e) = self.reply(ack).await {
+                    tracing::error!("Failed to acknowledge offset to master: {e:?}");
+                }
+                let _ = self.send(Outgoing::Flush).await;
+                replication.propagate(raw);
+            }
+            cmd => {
+                let flushes = matches!(cmd, RedisCommand::FlushAll { .. });
+                let before = self.keys_before(&raw, flushes);
+                let write = match self.handle_cmd(cmd).await {
+                    Ok(_) => Write::new(vec![raw], Propagator::events(&*self.db, before))
+                        .flushed(flushes),
+                    Err(e) => {
+                        tracing::error!("Error applying command from master: {e:?}");
+                        Write::new(vec![raw], Vec::new())
+                    }
+                };
+                // relayed as received, keeping our own replicas in step with our master
+                self.propagator.propagate(write);
+            }
+        }
+    }
+
+    /// BLPOP and BRPOP: pop from the first of `keys` holding a list, or wait for a push to one
+    /// of them. The pop is propagated as an LPOP or RPOP, as replicas must not block.
+    async fn blocking_pop(
+        &mut self,
+        keys: Vec<Bytes>,
+        timeout: Option<Duration>,
+        front: bool,
+    ) -> Result<RedisValue> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let replication = self.replication.clone();
+        loop {
+            let blocked = {
+                let _write_guard = replication.write_lock().await;
+                if replication.is_replica() {
+                    return Err(RedisError::ReadOnly.into());
+                }
+                for key in &keys {
+                    self.check_type(key, KeyType::List)?;
+                    let Some(mut popped) = self.db.pop(key, 1, front) else {
+                        continue;
+                    };
+                    let pop = if front { "LPOP" } else { "RPOP" };
+                    let events = Propagator::events(&*self.db, vec![(key.clone(), true)]);
+                    let command = RedisValue::command([Bytes::from(pop), key.clone()]);
+                    self.last_write_offset = self
+                        .propagator
+                        .propagate(Write::new(vec![command], events).by(Some(self.id)));
+                    // whatever is left is for the next client in line
+                    blocking::signal(key);
+                    return Ok(RedisValue::Array(vec![
+                        RedisValue::BulkString(key.clone()),
+                        RedisValue::BulkString(popped.remove(0)),
+                    ]));
+                }
+                // blocking under the lock means no push can slip in unnoticed
+                let timeout = deadline.map(|at| at.saturating_duration_since(Instant::now()));
+                blocking::block(self.id, &keys, timeout)
+            };
+            match blocked.wait().await {
+                Wakeup::Ready(_) => continue,
+                Wakeup::TimedOut | Wakeup::Unblocked => return Ok(RedisValue::NullArray),
+            }
+        }
+    }
+
+    /// Propagate `effect` in place of the write being served, for a write whose outcome depends
+    /// on more than its arguments, such as the time it ran at. Every effect recorded is
+    /// propagated in order, and a write that records none is propagated as it was sent.
+    fn propagate_effect(&mut self, effect: impl IntoIterator<Item = Bytes>) {
+        self.effects
+            .get_or_insert_with(Vec::new)
+            .push(RedisValue::command(effect));
+    }
+
+    /// Propagate nothing for the write being served, as it changed nothing
+    fn propagate_no_effect(&mut self) {
+        self.effects.get_or_insert_with(Vec::new);
+    }
+
+    /// Refuse a command for keys of type `expected` on a key holding another type
+    fn check_type(&self, key: &Bytes, expected: KeyType) -> Result<()> {
+        match self.db.key_type(key) {
+            Some(found) if found != expected => Err(RedisError::WrongType.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Expire `key` at `at_ms`, or delete it straight away if that time has passed, returning
+    /// whether it existed. Replicas are sent the absolute time, or the deletion, rather than
+    /// judge the time themselves.
+    fn expire_key(&mut self, key: Bytes, at_ms: u64) -> bool {
+        if at_ms > self.db.clock().now_ms() {
+            let exists = self.db.expire_at(&key, at_ms);
+            match exists {
+                true => {
+                    let at = Bytes::from(at_ms.to_string());
+                    self.propagate_effect([Bytes::from("PEXPIREAT"), key.clone(), at]);
+                    let _ = self.expiration_tx.send((Some(at_ms), key));
+                }
+                false => self.propagate_no_effect(),
+            }
+            return exists;
+        }
+        let deleted = self.db.delete(&key, self.config.lazyfree_lazy_expire);
+        match deleted {
+            true => self.propagate_effect([Bytes::from("DEL"), key]),
+            false => self.propagate_no_effect(),
+        }
+        deleted
+    }
+
+    /// Validate a FAILOVER request and start it in the background
+    fn start_failover(
+        &self,
+        target: Option<(String, u16)>,
+        force: bool,
+        abort: bool,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        if abort {
+            if self.replication.failover_state() == FailoverState::NoFailover {
+                return Err(RedisError::other("No failover in progress.").into());
+            }
+            self.replication.abort_failover();
+            return Ok(());
+        }
+        if self.replication.is_replica() {
+            return Err(
+                RedisError::other("FAILOVER is not valid when server is a replica.").into(),
+            );
+        }
+        if force && (target.is_none() || timeout.is_none()) {
+            return Err(RedisError::other(
+                "FAILOVER with force option requires both a timeout and target HOST and IP.",
+            )
+            .into());
+        }
+
+        let replicas = self.replication.replica_endpoints();
+        if replicas.is_empty() {
+            return Err(RedisError::other("FAILOVER requires connected replicas.").into());
+        }
+        let target = match target {
+            Some((host, port)) => replicas
+                .into_iter()
+                .find(|(conn, ip, listening_port)| {
+                    *listening_port == Some(port)
+                        && (*ip == host || (host == "localhost" && conn.ip().is_loopback()))
+                })
+                .map(|(conn, _, _)| FailoverTarget { conn, host, port })
+                .ok_or(RedisError::other(
+                    "FAILOVER target HOST and PORT is not a replica.",
+                ))?,
+            None => replicas
+                .into_iter()
+                .find_map(|(conn, ip, listening_port)| {
+                    Some(FailoverTarget {
+                        conn,
+                        host: ip,
+                        port: listening_port?,
+                    })
+                })
+                .ok_or(RedisError::other("FAILOVER requires connected replicas."))?,
+        };
+
+        if !self.replication.begin_failover() {
+            return Err(RedisError::other("FAILOVER already in progress.").into());
+        }
+        let failover = failover::run(
+            target,
+            timeout,
+            force,
+            self.db.clone(),
+            self.commands.clone(),
+            self.config.clone(),
+            self.replication.clone(),
+            self.expiration_tx.clone(),
+            self.propagator.clone(),
+        );
+        tasks::spawn("failover", None, failover);
+        Ok(())
+    }
+
+    /// Answer a PSYNC with a partial resync if we still hold the history the replica is missing,
+    /// or a full resync otherwise, and turn this connection into a replica link
+    async fn serve_replica(&mut self, replid: String, psync_offset: i64) -> Result<()> {
+        // replicas are held to their own output buffer limit instead, and never evicted
+        self.memory.untrack();
+        let replication = self.replication.clone();
+
+        let write_guard = replication.write_lock().await;
+        if let Some(missed) = replication.backlog_since(&replid, psync_offset) {
+            let propagated = replication.subscribe();
+            let subscribed_at = replication.offset();
+            drop(write_guard);
+
+            tracing::info!(
+                "Partial resync with replica {} from offset {psync_offset}",
+                self.client_addr
+            );
+            self.reply(RedisValue::SimpleString(
+                format!("CONTINUE {}", replication.replid()).into(),
+            ))
+            .await?;
+            self.send(Outgoing::Flush).await?;
+            return self
+                .run_replica_link(
+                    psync_offset as u64 - 1,
+                    master::Resync::Partial(missed),
+                    propagated,
+                    subscribed_at,
+                )
+                .await;
+        }
+
+        if self.config.repl_diskless_sync && self.capa_eof {
+            drop(write_guard);
+            let options = diskless::Options {
+                delay: self.config.repl_diskless_sync_delay,
+                max_replicas: self.config.repl_diskless_sync_max_replicas,
+                compress: self.config.rdbcompression,
+            };
+            let transfer = diskless::join(replication.clone(), self.db.clone(), options).await?;
+            let offset = transfer.offset;
+            self.reply(RedisValue::SimpleString(
+                format!("FULLRESYNC {} {offset}", replication.replid()).into(),
+            ))
+            .await?;
+            self.send(Outgoing::Flush).await?;
+            return self
+                .run_replica_link(
+                    offset,
+                    master::Resync::Streamed(transfer.chunks),
+                    transfer.propagated,
+                    offset,
+                )
+                .await;
+        }
+
+        // waiting out a BGSAVE for the snapshot must not hold up writes
+        drop(write_guard);
+        let (write_guard, snapshot) = master::lock_with_snapshot(&replication, &*self.db).await;
+        let offset = replication.offset();
+        let propagated = replication.subscribe();
+        drop(write_guard);
+
+        self.reply(RedisValue::SimpleString(
+            format!("FULLRESYNC {} {offset}", replication.replid()).into(),
+        ))
+        .await?;
+        self.send(Outgoing::Flush).await?;
+        let rdb = persistence::encode(snapshot, self.config.rdbcompression).await?;
+        self.run_replica_link(offset, master::Resync::Full(rdb), propagated, offset)
+            .await
+    }
+
+    /// Register this connection as a replica at `offset` and stream to it until it goes away
+    async fn run_replica_link(
+        &mut self,
+        offset: u64,
+        resync: master::Resync,
+        propagated: broadcast::Receiver<Bytes>,
+        subscribed_at: u64,
+    ) -> Result<()> {
+        let replication = self.replication.clone();
+        replication.register_replica(
+            self.client_addr,
+            self.listening_port,
+            self.announced_ip.clone(),
+            offset,
+        );
+        let result = master::serve_replica(
+            &mut self.reader,
+            &self.outgoing,
+            self.client_addr,
+            &replication,
+            resync,
+            master::Propagated {
+                commands: propagated,
+                subscribed_at,
+            },
+            self.config.client_output_buffer_limit.replica,
+        )
+        .await;
+        replication.remove_replica(&self.client_addr);
+        result
+    }
+
+    /// Queue output for the writer, failing once it has given up on the client
+    async fn send(&mut self, out: Outgoing) -> Result<()> {
+        self.outgoing
+            .send(out)
+            .await
+            .map_err(|_| anyhow::anyhow!("Connection to {} closed", self.client_addr))
+    }
+
+    /// Queue a reply, written out with the rest of the batch
+    async fn reply(&mut self, reply: RedisValue) -> Result<()> {
+        self.send(Outgoing::Value(reply)).await
+    }
+
+    /// Queue all but the last of several replies to one command, returning the last to be
+    /// answered like any other
+    async fn replies(&mut self, mut replies: Vec<RedisValue>) -> Result<RedisValue> {
+        let last = replies.pop().unwrap_or(RedisValue::Null);
+        for reply in replies {
+            self.reply(reply).await?;
+        }
+        Ok(last)
+    }
+
     async fn send_error(&mut self, e: anyhow::Error) {
-        let _ = self
-            .frame
-            .send(RedisValue::SimpleError(format!("{e:?}").into()))
-            .await;
+        if self.state.replies() == ReplyPolicy::Silent {
+            return;
+        }
+        let _ = self.reply(error::reply(&e)).await;
     }
 
     async fn handle_cmd(&mut self, cmd: RedisCommand) -> Result<RedisValue> {
         match cmd {
+            // a RESP2 subscriber's replies look like messages
+            RedisCommand::Ping if self.state == State::Subscribed => Ok(RedisValue::Array(vec![
+                RedisValue::BulkString("pong".into()),
+                RedisValue::BulkString(Bytes::new()),
+            ])),
             RedisCommand::Ping => Ok(RedisValue::SimpleString("PONG".into())),
             RedisCommand::Echo(msg) => Ok(RedisValue::BulkString(msg)),
-            RedisCommand::Get(key) => match self.db.get_key(&key) {
-                Some(v) => {
-                    tracing::info!("Returning value: {:?}", v);
-                    Ok(RedisValue::BulkString(v))
+            RedisCommand::Get(key) => {
+                self.check_type(&key, KeyType::String)?;
+                match self.db.get_key(&key) {
+                    Some(v) => {
+                        tracing::info!("Returning value: {:?}", v);
+                        Ok(RedisValue::BulkString(v))
+                    }
+                    _ => Ok(RedisValue::NullBulkString),
                 }
-                _ => Ok(RedisValue::NullBulkString),
-            },
+            }
             RedisCommand::Set {
                 key,
                 value,
                 expiration,
             } => {
-                let exp = expiration.map(|dur| Instant::now() + dur);
+                let exp = match expiration {
+                    Some(dur) => Some(
+                        self.db
+                            .clock()
+                            .expires_at(dur)
+                            .ok_or(RedisError::InvalidExpireTime("set"))?,
+                    ),
+                    None => None,
+                };
                 tracing::info!("Set {:?} -> {:?} with expiration at: {exp:?}", key, value);
 
+                // replicas are sent the absolute expiration, so they expire the key at the same
+                // moment we do no matter how late they apply the write
+                if let Some(at) = exp {
+                    self.propagate_effect([Bytes::from("SET"), key.clone(), value.clone()]);
+                    self.propagate_effect([
+                        Bytes::from("PEXPIREAT"),
+                        key.clone(),
+                        at.to_string().into(),
+                    ]);
+                }
                 let val = Value::new(value, exp);
-                self.db.set_key(&key, val);
-                // send our new expiration time to the channel if needed
-                if let Some(time) = exp {
-                    let _ = self.expiration_tx.send((time, key)).await;
-                };
+                let old = self.db.set_key(&key, val);
+                // send our new expiration time to the channel if needed, or cancel the old one
+                if exp.is_some() || old.is_some_and(|old| old.get_expiration().is_some()) {
+                    let _ = self.expiration_tx.send((exp, key));
+                }
                 Ok(RedisValue::SimpleString("OK".into()))
             }
-            RedisCommand::RPush {
+            RedisCommand::MGet(keys) => Ok(RedisValue::Array(
+                keys.iter()
+                    .map(|key| match self.db.get_key(key) {
+                        Some(v) => RedisValue::BulkString(v),
+                        None => RedisValue::NullBulkString,
+                    })
+                    .collect(),
+            )),
+            RedisCommand::MSet(pairs) => {
+                for (key, value) in pairs {
+                    let old = self.db.set_key(&key, Value::new(value, None));
+                    if old.is_some_and(|old| old.get_expiration().is_some()) {
+                        let _ = self.expiration_tx.send((None, key));
+                    }
+                }
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Push {
                 list_name,
                 elements,
+                front,
             } => {
-                tracing::info!("RPush to {list_name:?} with elements: {elements:?}");
-                let size = self.db.rpush(
+                tracing::info!("Push to {list_name:?} with elements: {elements:?}");
+                self.check_type(&list_name, KeyType::List)?;
+                let size = self.db.push(
                     &list_name,
-                    elements.iter().map(|e| Value::new(e.clone(), None)),
+                    elements
+                        .iter()
+                        .map(|e| Value::new(e.clone(), None))
+                        .collect(),
+                    front,
                 );
+                blocking::signal(&list_name);
                 Ok(RedisValue::Integer(size as i64))
             }
+            RedisCommand::Pop { key, count, front } => {
+                self.check_type(&key, KeyType::List)?;
+                let popped = self.db.pop(&key, count.unwrap_or(1), front);
+                Ok(match (popped, count) {
+                    (None, None) => RedisValue::NullBulkString,
+                    (None, Some(_)) => RedisValue::NullArray,
+                    (Some(mut popped), None) => RedisValue::BulkString(popped.remove(0)),
+                    (Some(popped), Some(_)) => {
+                        RedisValue::Array(popped.into_iter().map(RedisValue::BulkString).collect())
+                    }
+                })
+            }
+            RedisCommand::BPop {
+                keys,
+                timeout,
+                front,
+            } => self.blocking_pop(keys, timeout, front).await,
+            RedisCommand::LRange { key, start, stop } => {
+                self.check_type(&key, KeyType::List)?;
+                let range = self.db.list_range(&key, start, stop).unwrap_or_default();
+                Ok(RedisValue::Array(
+                    range.into_iter().map(RedisValue::BulkString).collect(),
+                ))
+            }
+            RedisCommand::SAdd { key, members } => {
+                self.check_type(&key, KeyType::Set)?;
+                Ok(RedisValue::Integer(self.db.set_add(&key, members) as i64))
+            }
+            RedisCommand::SRem { key, members } => {
+                self.check_type(&key, KeyType::Set)?;
+                Ok(RedisValue::Integer(self.db.set_remove(&key, members) as i64))
+            }
+            RedisCommand::SPop { key, count } => {
+                self.check_type(&key, KeyType::Set)?;
+                let popped = self
+                    .db
+                    .set_pop(&key, count.unwrap_or(1))
+                    .unwrap_or_default();
+                // replicas remove the members picked here rather than pick their own
+                match popped.is_empty() {
+                    true => self.propagate_no_effect(),
+                    false => self.propagate_effect(
+                        [Bytes::from("SREM"), key]
+                            .into_iter()
+                            .chain(popped.iter().cloned()),
+                    ),
+                }
+                let mut popped = popped.into_iter().map(RedisValue::BulkString);
+                Ok(match count {
+                    None => popped.next().unwrap_or(RedisValue::NullBulkString),
+                    Some(_) => RedisValue::Array(popped.collect()),
+                })
+            }
+            RedisCommand::SCard(key) => {
+                self.check_type(&key, KeyType::Set)?;
+                Ok(RedisValue::Integer(self.db.collection_len(&key) as i64))
+            }
+            RedisCommand::HSet { key, pairs } => {
+                self.check_type(&key, KeyType::Hash)?;
+                Ok(RedisValue::Integer(self.db.hash_set(&key, pairs) as i64))
+            }
+            RedisCommand::HGet { key, field } => {
+                self.check_type(&key, KeyType::Hash)?;
+                Ok(self
+                    .db
+                    .hash_get(&key, field)
+                    .map_or(RedisValue::NullBulkString, RedisValue::BulkString))
+            }
+            RedisCommand::ZAdd {
+                key,
+                pairs,
+                flags,
+                ch,
+            } => {
+                self.check_type(&key, KeyType::SortedSet)?;
+                let added = self
+                    .db
+                    .zadd(&key, pairs, flags)
+                    .ok_or(RedisError::other("resulting score is not a number (NaN)"))?;
+                Ok(match flags.incr {
+                    // the new score, or nil if the flags left the member alone
+                    true => added
+                        .score
+                        .map_or(RedisValue::NullBulkString, RedisValue::Double),
+                    false if ch => RedisValue::Integer((added.added + added.changed) as i64),
+                    false => RedisValue::Integer(added.added as i64),
+                })
+            }
+            RedisCommand::ZScore { key, member } => {
+                self.check_type(&key, KeyType::SortedSet)?;
+                Ok(self
+                    .db
+                    .zscore(&key, member)
+                    .map_or(RedisValue::NullBulkString, RedisValue::Double))
+            }
+            RedisCommand::ZCard(key) => {
+                self.check_type(&key, KeyType::SortedSet)?;
+                Ok(RedisValue::Integer(self.db.collection_len(&key) as i64))
+            }
+            RedisCommand::ZPopMin { key, count } => {
+                self.check_type(&key, KeyType::SortedSet)?;
+                let popped = self
+                    .db
+                    .zpop_min(&key, count.unwrap_or(1))
+                    .unwrap_or_default();
+                if popped.is_empty() {
+                    self.propagate_no_effect();
+                }
+                let pair =
+                    |(member, score)| [RedisValue::BulkString(member), RedisValue::Double(score)];
+                Ok(match (count, self.protocol) {
+                    // RESP3 asked for a count gets a pair per member, otherwise it is flattened
+                    (Some(_), Protocol::Resp3) => RedisValue::Array(
+                        popped
+                            .into_iter()
+                            .map(|popped| RedisValue::Array(pair(popped).into()))
+                            .collect(),
+                    ),
+                    _ => RedisValue::Array(popped.into_iter().flat_map(pair).collect()),
+                })
+            }
+            RedisCommand::IncrBy { key, by } => {
+                self.check_type(&key, KeyType::String)?;
+                // writes are serialized, so nothing changes the value between reading and
+                // storing it
+                let (current, exp) = match self.db.get_key(&key) {
+                    // the new value keeps the old one's expiration
+                    Some(value) => (
+                        parse_integer::<i64>(&value)?,
+                        self.db.get_key_expiration(&key),
+                    ),
+                    None => (0, None),
+                };
+                let value = current
+                    .checked_add(by)
+                    .ok_or(RedisError::other("increment or decrement would overflow"))?;
+                self.db
+                    .set_key(&key, Value::new(value.to_string().into(), exp));
+                Ok(RedisValue::Integer(value))
+            }
+            RedisCommand::Del(keys) => {
+                let lazy = self.config.lazyfree_lazy_user_del;
+                let effort: usize = keys.iter().map(|key| self.db.free_effort(key)).sum();
+                let db = self.db.clone();
+                let removed = tasks::offload_if("del", effort > OFFLOAD_EFFORT, move || {
+                    keys.iter().filter(|key| db.delete(key, lazy)).count()
+                })
+                .await?;
+                Ok(RedisValue::Integer(removed as i64))
+            }
+            RedisCommand::Keys(pattern) => {
+                let db = self.db.clone();
+                let keys = tasks::offload_if("keys", self.db.len() > OFFLOAD_EFFORT, move || {
+                    let mut keys = db.keys();
+                    keys.retain(|key| glob_match(&pattern, key));
+                    keys
+                })
+                .await?;
+                Ok(RedisValue::Array(
+                    keys.into_iter().map(RedisValue::BulkString).collect(),
+                ))
+            }
+            RedisCommand::Unlink(keys) => {
+                let removed = keys.iter().filter(|key| self.db.delete(key, true)).count();
+                Ok(RedisValue::Integer(removed as i64))
+            }
+            RedisCommand::FlushAll { lazy } => {
+                let lazy = lazy.unwrap_or(self.config.lazyfree_lazy_user_flush);
+                // a lazy flush only hands the tables over
+                let heavy = !lazy && self.db.len() > OFFLOAD_EFFORT;
+                let db = self.db.clone();
+                tasks::offload_if("flushall", heavy, move || db.flush(lazy)).await?;
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Expire { key, secs } => {
+                let at_ms = match secs {
+                    // not in the future, so deleted straight away
+                    ..=0 => self.db.clock().now_ms(),
+                    secs => self
+                        .db
+                        .clock()
+                        .expires_at(Duration::from_secs(secs as u64))
+                        .ok_or(RedisError::InvalidExpireTime("expire"))?,
+                };
+                Ok(RedisValue::Integer(self.expire_key(key, at_ms) as i64))
+            }
+            RedisCommand::PExpireAt { key, at_ms } => {
+                Ok(RedisValue::Integer(self.expire_key(key, at_ms) as i64))
+            }
+            RedisCommand::Ttl { key, ms } => {
+                if !self.db.exists(&key) {
+                    return Ok(RedisValue::Integer(-2));
+                }
+                let Some(at) = self.db.get_key_expiration(&key) else {
+                    return Ok(RedisValue::Integer(-1));
+                };
+                let left = at.saturating_sub(self.db.clock().now_ms());
+                // whole seconds are rounded to the nearest, as Redis does
+                let left = if ms {
+                    left
+                } else {
+                    left.saturating_add(500) / 1000
+                };
+                Ok(RedisValue::Integer(i64::try_from(left).unwrap_or(i64::MAX)))
+            }
+            RedisCommand::Dump(key) => Ok(match persistence::value(&*self.db, &key) {
+                Some(value) => {
+                    RedisValue::BulkString(rdb::encode_dump(&value, self.config.rdbcompression))
+                }
+                None => RedisValue::NullBulkString,
+            }),
+            RedisCommand::Restore {
+                key,
+                ttl_ms,
+                payload,
+                replace,
+                absttl,
+            } => {
+                let value = rdb::parse_dump(&payload).map_err(|e| match e {
+                    RdbError::UnsupportedVersion(_) | RdbError::ChecksumMismatch { .. } => {
+                        RedisError::other("DUMP payload version or checksum are wrong")
+                    }
+                    _ => RedisError::other("Bad data format"),
+                })?;
+                if !replace && self.db.exists(&key) {
+                    return Err(RedisError::BusyKey.into());
+                }
+                let now = self.db.clock().now_ms();
+                let at = match (ttl_ms, absttl) {
+                    (0, _) => None,
+                    (at, true) => Some(at),
+                    (ttl, false) => Some(now.saturating_add(ttl)),
+                };
+                let deleted = self.db.delete(&key, false);
+                if at.is_some_and(|at| at <= now) {
+                    // restored already expired, which only deletes what it replaces
+                    match deleted {
+                        true => self.propagate_effect([Bytes::from("DEL"), key]),
+                        false => self.propagate_no_effect(),
+                    }
+                    return Ok(RedisValue::SimpleString("OK".into()));
+                }
+                persistence::restore(&*self.db, &self.expiration_tx, key.clone(), value, at)
+                    .await?;
+                // with the expiration it ended up with, not one relative to when it's replayed
+                let at = Bytes::from(at.unwrap_or(0).to_string());
+                self.propagate_effect([
+                    Bytes::from("RESTORE"),
+                    key,
+                    at,
+                    payload,
+                    Bytes::from("REPLACE"),
+                    Bytes::from("ABSTTL"),
+                ]);
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Migrate {
+                host,
+                port,
+                keys,
+                timeout,
+                copy,
+                replace,
+            } => {
+                let now = self.db.clock().now_ms();
+                let dumped: Vec<Dumped> = keys
+                    .into_iter()
+                    .filter_map(|key| {
+                        let value = persistence::value(&*self.db, &key)?;
+                        // a key about to expire still has a moment left on the target
+                        let ttl_ms = self
+                            .db
+                            .get_key_expiration(&key)
+                            .map_or(0, |at| at.saturating_sub(now).max(1));
+                        let payload = rdb::encode_dump(&value, self.config.rdbcompression);
+                        Some(Dumped {
+                            key,
+                            ttl_ms,
+                            payload,
+                        })
+                    })
+                    .collect();
+                if dumped.is_empty() {
+                    return Ok(RedisValue::SimpleString("NOKEY".into()));
+                }
+                let replies = migrate::restore(&host, port, timeout, &dumped, replace).await?;
+                // keys are only removed once the target took every one, so a failure leaves
+                // them all here to try again
+                if let Some(RedisValue::SimpleError(e)) = replies
+                    .iter()
+                    .find(|reply| matches!(reply, RedisValue::SimpleError(_)))
+                {
+                    return Err(RedisError::other(format!(
+                        "Target instance replied with error: {}",
+                        String::from_utf8_lossy(e)
+                    ))
+                    .into());
+                }
+                match copy {
+                    true => self.propagate_no_effect(),
+                    false => {
+                        for Dumped { key, .. } in dumped {
+                            self.db.delete(&key, self.config.lazyfree_lazy_user_del);
+                            self.propagate_effect([Bytes::from("DEL"), key]);
+                        }
+                    }
+                }
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Save => {
+                let snapshot = self
+                    .db
+                    .snapshot()
+                    .ok_or(RedisError::other("Background save already in progress"))?;
+                persistence::save(snapshot, self.config.rdb_path(), self.config.rdbcompression)
+                    .await?;
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::BgSave => {
+                let snapshot = self
+                    .db
+                    .snapshot()
+                    .ok_or(RedisError::other("Background save already in progress"))?;
+                let (path, compress) = (self.config.rdb_path(), self.config.rdbcompression);
+                tasks::spawn("bgsave", None, async move {
+                    if let Err(e) = persistence::save(snapshot, path, compress).await {
+                        tracing::error!("Background save failed: {e:?}");
+                    }
+                });
+                Ok(RedisValue::SimpleString("Background saving started".into()))
+            }
+            RedisCommand::BgRewriteAof => {
+                let aof = self
+                    .propagator
+                    .aof()
+                    .ok_or(RedisError::other("The append-only file is not enabled"))?;
+                aof.rewrite(&*self.db, &self.replication).await?;
+                Ok(RedisValue::SimpleString(
+                    "Background append only file rewriting started".into(),
+                ))
+            }
+            RedisCommand::ReplConf(args) => {
+                tracing::info!("REPLCONF from {}: {args:?}", self.client_addr);
+                if let [option, port] = &args[..]
+                    && option.eq_ignore_ascii_case(b"listening-port")
+                {
+                    self.listening_port = Some(
+                        str::from_utf8(port)
+                            .ok()
+                            .and_then(|port| port.parse().ok())
+                            .ok_or(RedisError::NotInteger)?,
+                    );
+                }
+                if let [option, ip] = &args[..]
+                    && option.eq_ignore_ascii_case(b"ip-address")
+                {
+                    self.announced_ip = Some(String::from_utf8_lossy(ip).into_owned());
+                }
+                // capabilities come as `capa <name>` pairs, several in one REPLCONF
+                if args.chunks(2).any(|pair| {
+                    pair[0].eq_ignore_ascii_case(b"capa")
+                        && pair
+                            .get(1)
+                            .is_some_and(|capa| capa.eq_ignore_ascii_case(b"eof"))
+                }) {
+                    self.capa_eof = true;
+                }
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Info(sections) => Ok(RedisValue::BulkString(
+                info::render(
+                    &sections,
+                    &*self.db,
+                    &self.config,
+                    &self.replication,
+                    self.cluster.as_deref(),
+                )
+                .into(),
+            )),
+            RedisCommand::Role => Ok(self.replication.role_reply()),
+            RedisCommand::Subscribe(channels) => {
+                let replies = channels
+                    .into_iter()
+                    .map(|channel| {
+                        let count = pubsub::subscribe(self.id, channel.clone());
+                        subscription_reply("subscribe", RedisValue::BulkString(channel), count)
+                    })
+                    .collect();
+                self.replies(replies).await
+            }
+            RedisCommand::Unsubscribe(mut channels) => {
+                if channels.is_empty() {
+                    channels = pubsub::channels(self.id);
+                }
+                if channels.is_empty() {
+                    return Ok(subscription_reply("unsubscribe", RedisValue::Null, 0));
+                }
+                let replies = channels
+                    .into_iter()
+                    .map(|channel| {
+                        let count = pubsub::unsubscribe(self.id, &channel);
+                        subscription_reply("unsubscribe", RedisValue::BulkString(channel), count)
+                    })
+                    .collect();
+                self.replies(replies).await
+            }
+            RedisCommand::Publish { channel, message } => {
+                let receivers = pubsub::publish(&channel, &message);
+                // like Redis, masters pass messages on so replicas' subscribers get them too;
+                // from our own master they are passed on with the rest of its stream
+                if self.state != State::MasterLink {
+                    let _write_guard = self.replication.write_lock().await;
+                    if !self.replication.is_replica() {
+                        self.replication.propagate(RedisValue::command([
+                            Bytes::from("PUBLISH"),
+                            channel,
+                            message,
+                        ]));
+                    }
+                }
+                Ok(RedisValue::Integer(receivers as i64))
+            }
+            RedisCommand::ReplicaOf(_) if self.cluster.is_some() => {
+                Err(RedisError::other("REPLICAOF not allowed in cluster mode.").into())
+            }
+            RedisCommand::ReplicaOf(None) => {
+                self.replication.promote();
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::ReplicaOf(Some((host, port))) => {
+                self.replication.demote(host.clone(), port);
+                replica::start(
+                    host,
+                    port,
+                    self.db.clone(),
+                    self.commands.clone(),
+                    self.config.clone(),
+                    self.replication.clone(),
+                    self.expiration_tx.clone(),
+                    self.propagator.clone(),
+                );
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Failover {
+                target,
+                force,
+                abort,
+                timeout,
+            } => {
+                self.start_failover(target, force, abort, timeout)?;
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Wait { replicas, timeout } => {
+                let acked = self
+                    .replication
+                    .wait_for_acks(replicas, self.last_write_offset, timeout)
+                    .await;
+                Ok(RedisValue::Integer(acked as i64))
+            }
+            RedisCommand::Cluster(subcommand) => {
+                let cluster = self.cluster.as_ref().ok_or(RedisError::ClusterDisabled)?;
+                Ok(match subcommand {
+                    ClusterCommand::Info => RedisValue::BulkString(cluster.info().into()),
+                    ClusterCommand::MyId => RedisValue::BulkString(cluster.myid().into()),
+                    ClusterCommand::Slots => cluster.slots(),
+                    ClusterCommand::Shards => cluster.shards(),
+                    ClusterCommand::KeySlot(key) => {
+                        RedisValue::Integer(cluster::key_slot(&key) as i64)
+                    }
+                    ClusterCommand::Nodes => RedisValue::BulkString(cluster.nodes().into()),
+                    ClusterCommand::Meet { host, bus_port } => {
+                        cluster.meet(host, bus_port);
+                        RedisValue::SimpleString("OK".into())
+                    }
+                    ClusterCommand::SetSlot { slot, action } => {
+                        cluster
+                            .set_slot(slot, action, || !self.keys_in_slot(slot, 1).is_empty())?;
+                        RedisValue::SimpleString("OK".into())
+                    }
+                    ClusterCommand::CountKeysInSlot(slot) => {
+                        RedisValue::Integer(self.keys_in_slot(slot, usize::MAX).len() as i64)
+                    }
+                    ClusterCommand::GetKeysInSlot { slot, count } => RedisValue::Array(
+                        self.keys_in_slot(slot, count)
+                            .into_iter()
+                            .map(RedisValue::BulkString)
+                            .collect(),
+                    ),
+                })
+            }
+            RedisCommand::Asking => {
+                if self.cluster.is_none() {
+                    return Err(RedisError::ClusterDisabled.into());
+                }
+                self.asking = true;
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::ReadOnly | RedisCommand::ReadWrite => {
+                if self.cluster.is_none() {
+                    return Err(RedisError::ClusterDisabled.into());
+                }
+                self.readonly = matches!(cmd, RedisCommand::ReadOnly);
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Client(ClientCommand::Id) => Ok(RedisValue::Integer(self.id as i64)),
+            RedisCommand::Client(ClientCommand::Tracking(Some(options))) => {
+                tracking::enable(self.id, options, self.protocol == Protocol::Resp3)?;
+                self.tracking = true;
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Client(ClientCommand::Tracking(None)) => {
+                tracking::disable(self.id);
+                self.tracking = false;
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Client(ClientCommand::Caching(yes)) => {
+                let options = tracking::options(self.id).unwrap_or_default();
+                if yes && !options.optin {
+                    return Err(RedisError::other(
+                        "CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.",
+                    )
+                    .into());
+                }
+                if !yes && !options.optout {
+                    return Err(RedisError::other(
+                        "CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.",
+                    )
+                    .into());
+                }
+                self.caching = Some(yes);
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Client(ClientCommand::NoEvict(no_evict)) => {
+                self.memory.set_no_evict(no_evict);
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Client(ClientCommand::GetRedir) => {
+                Ok(RedisValue::Integer(match tracking::options(self.id) {
+                    None => -1,
+                    Some(options) => options.redirect.unwrap_or(0) as i64,
+                }))
+            }
+            RedisCommand::Object(ObjectCommand::Encoding(key)) => {
+                Ok(match self.db.encoding(&key) {
+                    Some(encoding) => RedisValue::BulkString(encoding.into()),
+                    None => RedisValue::NullBulkString,
+                })
+            }
+            RedisCommand::Object(ObjectCommand::Freq(key)) => {
+                if !self.config.maxmemory_policy.lfu() {
+                    return Err(RedisError::other(
+                        "An LFU maxmemory policy is not selected, access frequency not tracked.",
+                    )
+                    .into());
+                }
+                Ok(match self.db.frequency(&key) {
+                    Some(frequency) => RedisValue::Integer(frequency as i64),
+                    None => RedisValue::NullBulkString,
+                })
+            }
+            RedisCommand::Memory(MemoryCommand::Usage(key)) => {
+                Ok(match self.db.memory_usage(&key) {
+                    Some(bytes) => RedisValue::Integer(bytes as i64),
+                    None => RedisValue::NullBulkString,
+                })
+            }
+            RedisCommand::HotKeys(HotKeysCommand::Get(count)) => {
+                if self.config.hotkeys_sample_rate == 0 {
+                    return Err(RedisError::other(
+                        "hotkeys-sample-rate is 0, key accesses are not sampled.",
+                    )
+                    .into());
+                }
+                Ok(RedisValue::Map(
+                    hotkeys::top(count)
+                        .into_iter()
+                        .map(|(key, accesses)| {
+                            (
+                                RedisValue::BulkString(key),
+                                RedisValue::Integer(accesses as i64),
+                            )
+                        })
+                        .collect(),
+                ))
+            }
+            RedisCommand::HotKeys(HotKeysCommand::Reset) => {
+                hotkeys::reset();
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Auth { username, password } => {
+                let Some(requirepass) = &self.config.requirepass else {
+                    return Err(RedisError::other(
+                        "AUTH <password> called without any password configured for the default \
+                         user. Are you sure your configuration is correct?",
+                    )
+                    .into());
+                };
+                let user_ok = username.is_none_or(|username| &username[..] == b"default");
+                if !user_ok || !constant_time_eq(&password, requirepass.as_bytes()) {
+                    return Err(RedisError::WrongPass.into());
+                }
+                self.authenticated = true;
+                Ok(RedisValue::SimpleString("OK".into()))
+            }
+            RedisCommand::Hello(protover) => {
+                let protocol = match protover {
+                    None => self.protocol,
+                    Some(2) => Protocol::Resp2,
+                    Some(3) => Protocol::Resp3,
+                    Some(_) => return Err(RedisError::NoProto.into()),
+                };
+                // the reply to HELLO is already in the new protocol
+                self.protocol = protocol;
+                self.send(Outgoing::Protocol(protocol)).await?;
+                tracking::set_resp3(self.id, protocol == Protocol::Resp3);
+                let bulk = |s: &str| RedisValue::BulkString(s.to_string().into());
+                Ok(RedisValue::Map(vec![
+                    (bulk("server"), bulk("redis")),
+                    (bulk("version"), bulk(REDIS_VERSION)),
+                    (
+                        bulk("proto"),
+                        RedisValue::Integer(match protocol {
+                            Protocol::Resp2 => 2,
+                            Protocol::Resp3 => 3,
+                        }),
+                    ),
+                    (bulk("id"), RedisValue::Integer(self.id as i64)),
+                    (
+                        bulk("mode"),
+                        bulk(if self.cluster.is_some() {
+                            "cluster"
+                        } else {
+                            "standalone"
+                        }),
+                    ),
+                    (
+                        bulk("role"),
+                        bulk(if self.replication.is_replica() {
+                            "replica"
+                        } else {
+                            "master"
+                        }),
+                    ),
+                    (bulk("modules"), RedisValue::Array(Vec::new())),
+                ]))
+            }
+            RedisCommand::Config(ConfigCommand::Get(patterns)) => {
+                let mut options = Vec::new();
+                for pattern in &patterns {
+                    for (name, value) in self.config.get(&String::from_utf8_lossy(pattern)) {
+                        if !options.iter().any(|(seen, _)| *seen == name) {
+                            options.push((name, value));
+                        }
+                    }
+                }
+                Ok(RedisValue::Map(
+                    options
+                        .into_iter()
+                        .map(|(name, value)| {
+                            (
+                                RedisValue::BulkString(name.into()),
+                                RedisValue::BulkString(value.into()),
+                            )
+                        })
+                        .collect(),
+                ))
+            }
+            RedisCommand::Command(subcommand) => Ok(match subcommand {
+                CommandCommand::Count => RedisValue::Integer(self.commands.specs().count() as i64),
+                CommandCommand::Info(names) if names.is_empty() => {
+                    RedisValue::Array(self.commands.specs().map(|spec| spec.info()).collect())
+                }
+                CommandCommand::Info(names) => RedisValue::Array(
+                    names
+                        .iter()
+                        .map(|name| match self.commands.spec(name) {
+                            Some(spec) => spec.info(),
+                            None => RedisValue::NullArray,
+                        })
+                        .collect(),
+                ),
+                CommandCommand::List => RedisValue::Array(
+                    self.commands
+                        .specs()
+                        .map(|spec| RedisValue::BulkString(spec.name.to_lowercase().into()))
+                        .collect(),
+                ),
+                CommandCommand::GetKeys { args, flags } => {
+                    let spec = self
+                        .commands
+                        .spec(&args[0])
+                        .ok_or(RedisError::other("Invalid command specified"))?;
+                    let args: Vec<RedisValue> =
+                        args.into_iter().map(RedisValue::BulkString).collect();
+                    if RedisCommand::check_arity(spec, &args).is_err() {
+                        return Err(RedisError::other(
+                            "Invalid number of arguments specified for command",
+                        )
+                        .into());
+                    }
+                    let keys = spec.keys(&args);
+                    if keys.is_empty() {
+                        return Err(RedisError::other("The command has no key arguments").into());
+                    }
+                    let key_flags = || {
+                        RedisValue::Array(
+                            spec.key_flags()
+                                .iter()
+                                .map(|flag| RedisValue::SimpleString((*flag).into()))
+                                .collect(),
+                        )
+                    };
+                    RedisValue::Array(
+                        keys.into_iter()
+                            .map(|key| match flags {
+                                true => RedisValue::Array(vec![
+                                    RedisValue::BulkString(key.clone()),
+                                    key_flags(),
+                                ]),
+                                false => RedisValue::BulkString(key.clone()),
+                            })
+                            .collect(),
+                    )
+                }
+            }),
+            RedisCommand::Custom { handler, args } => handler(args).await,
+            RedisCommand::Psync { .. } => Err(RedisError::other("PSYNC not allowed here").into()),
         }
     }
 }
+
+/// One of the replies to SUBSCRIBE or UNSUBSCRIBE, `count` being the channels subscribed to after
+fn subscription_reply(kind: &str, channel: RedisValue, count: usize) -> RedisValue {
+    RedisValue::Push(vec![
+        RedisValue::BulkString(Bytes::copy_from_slice(kind.as_bytes())),
+        channel,
+        RedisValue::Integer(count as i64),
+    ])
+}
+
+/// Whether `e` means the client's stream can no longer be parsed reliably
+fn is_protocol_error(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref(), Some(RedisError::Protocol(_)))
+}
+
+/// Compare a password without the time taken telling how much of it was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
@@ -0,0 +1,103 @@
+//! The states a connection goes through. Each state decides which commands the client may run
+//! and whether they are answered, so that modes such as subscribing compose instead of piling up
+//! as flags checked all over the command loop.
+
+use crate::{command::RedisCommand, error::RedisError, resp::codec::Protocol};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    /// Serving commands, each answered
+    Normal,
+    /// A RESP2 client subscribed to channels. RESP2 can't tell pushed messages from replies, so
+    /// it may only manage its subscriptions, and its replies look like messages.
+    Subscribed,
+    /// Our link to a master: everything received is part of the replication stream, applied
+    /// and never answered
+    MasterLink,
+    /// A replica served after its PSYNC, sent the replication stream and never read commands
+    /// from again
+    ReplicaLink,
+}
+
+/// Whether the commands of a state are answered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReplyPolicy {
+    /// Every command gets its reply, errors included
+    Reply,
+    /// Nothing is answered, not even errors
+    Silent,
+}
+
+impl State {
+    /// Whether a client in this state may run `cmd`, named `name`, or the error it gets
+    pub(crate) fn allows(self, cmd: &RedisCommand, name: &str) -> Result<(), RedisError> {
+        match self {
+            Self::Normal | Self::MasterLink => Ok(()),
+            Self::Subscribed => match cmd {
+                RedisCommand::Subscribe(_) | RedisCommand::Unsubscribe(_) | RedisCommand::Ping => {
+                    Ok(())
+                }
+                _ => Err(RedisError::Other(format!(
+                    "Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / \
+                     RESET are allowed in this context",
+                    name.to_lowercase()
+                ))),
+            },
+            Self::ReplicaLink => Err(RedisError::other("Replica links don't serve commands")),
+        }
+    }
+
+    pub(crate) fn replies(self) -> ReplyPolicy {
+        match self {
+            Self::Normal | Self::Subscribed => ReplyPolicy::Reply,
+            Self::MasterLink | Self::ReplicaLink => ReplyPolicy::Silent,
+        }
+    }
+
+    /// The state of a client speaking `protocol` once it is `subscribed` to channels or not
+    pub(crate) fn subscribed(self, protocol: Protocol, subscribed: bool) -> Self {
+        match self {
+            Self::Normal | Self::Subscribed if subscribed && protocol == Protocol::Resp2 => {
+                Self::Subscribed
+            }
+            Self::Normal | Self::Subscribed => Self::Normal,
+            link => link,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribers_only_manage_subscriptions() {
+        let state = State::Normal.subscribed(Protocol::Resp2, true);
+        assert_eq!(state, State::Subscribed);
+        assert!(state.allows(&RedisCommand::Ping, "ping").is_ok());
+        assert!(state
+            .allows(&RedisCommand::Unsubscribe(vec![]), "unsubscribe")
+            .is_ok());
+        let e = state
+            .allows(&RedisCommand::Get("k".into()), "GET")
+            .unwrap_err();
+        assert!(e.to_string().starts_with("ERR Can't execute 'get'"), "{e}");
+        assert_eq!(state.replies(), ReplyPolicy::Reply);
+
+        // RESP3 tells pushes from replies, and the last unsubscribe ends the mode
+        assert_eq!(
+            State::Normal.subscribed(Protocol::Resp3, true),
+            State::Normal
+        );
+        assert_eq!(state.subscribed(Protocol::Resp2, false), State::Normal);
+        // links are not clients, whatever they subscribe to
+        assert_eq!(
+            State::MasterLink.subscribed(Protocol::Resp2, true),
+            State::MasterLink
+        );
+        assert_eq!(State::MasterLink.replies(), ReplyPolicy::Silent);
+        assert!(State::ReplicaLink
+            .allows(&RedisCommand::Ping, "ping")
+            .is_err());
+    }
+}
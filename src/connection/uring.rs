@@ -0,0 +1,126 @@
+use std::{
+    net::Shutdown,
+    pin::pin,
+    rc::Rc,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+    net::TcpStream,
+    sync::mpsc,
+};
+use tokio_uring::buf::BoundedBuf;
+
+/// Room for bytes in flight between a client socket and its connection, each way
+const BRIDGE_CAPACITY: usize = 64 * 1024;
+
+/// A client socket handed to a worker, along with its end of the connection's byte stream
+type Attach = (std::net::TcpStream, DuplexStream);
+
+/// Threads doing client socket I/O through io_uring, each running a tokio-uring runtime.
+///
+/// A connection is still served on the usual runtime over a [`DuplexStream`], like any other
+/// [`Transport`](super::Transport): its worker reads and writes the socket with the reads and
+/// writes submitted in batches to the ring, rather than one readiness-driven syscall each.
+pub(crate) struct UringWorkers {
+    workers: Vec<mpsc::UnboundedSender<Attach>>,
+    next: AtomicUsize,
+}
+
+impl UringWorkers {
+    /// Start `count` workers, each on its own thread
+    pub(crate) fn new(count: usize) -> Result<Self> {
+        let workers = (0..count.max(1))
+            .map(|i| {
+                let (tx, rx) = mpsc::unbounded_channel::<Attach>();
+                thread::Builder::new()
+                    .name(format!("io-uring-{i}"))
+                    .spawn(move || tokio_uring::start(Self::work(rx)))
+                    .context("failed to spawn io_uring worker")?;
+                Ok(tx)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            workers,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// One worker per core
+    pub(crate) fn per_core() -> Result<Self> {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(cores)
+    }
+
+    /// Move `stream` over to a worker, returning the stream its connection is served over
+    pub(crate) fn attach(&self, stream: TcpStream) -> Result<DuplexStream> {
+        let (served, bridged) = tokio::io::duplex(BRIDGE_CAPACITY);
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        self.workers[i]
+            .send((stream.into_std()?, bridged))
+            .map_err(|_| anyhow::anyhow!("io_uring worker {i} stopped"))?;
+        Ok(served)
+    }
+
+    /// Take sockets off `rx` until the server is dropped along with its senders
+    async fn work(mut rx: mpsc::UnboundedReceiver<Attach>) {
+        while let Some((socket, bridged)) = rx.recv().await {
+            let socket = tokio_uring::net::TcpStream::from_std(socket);
+            tokio_uring::spawn(async move {
+                if let Err(e) = Self::pump(socket, bridged).await {
+                    tracing::debug!("io_uring client socket failed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Copy bytes both ways between a client socket and its connection, until the connection
+    /// drops its end
+    async fn pump(socket: tokio_uring::net::TcpStream, bridged: DuplexStream) -> Result<()> {
+        let socket = Rc::new(socket);
+        let (mut from_conn, mut to_conn) = tokio::io::split(bridged);
+
+        let inbound = {
+            let socket = socket.clone();
+            async move {
+                let mut buf = vec![0; BRIDGE_CAPACITY];
+                loop {
+                    let (read, b) = socket.read(buf).await;
+                    buf = b;
+                    match read? {
+                        // the client hung up, let the connection see it
+                        0 => return to_conn.shutdown().await,
+                        n => to_conn.write_all(&buf[..n]).await?,
+                    }
+                }
+            }
+        };
+        let outbound = async move {
+            let mut buf = vec![0; BRIDGE_CAPACITY];
+            loop {
+                let n = from_conn.read(&mut buf).await?;
+                if n == 0 {
+                    return socket.shutdown(Shutdown::Write);
+                }
+                let (written, b) = socket.write_all(buf.slice(..n)).await;
+                buf = b.into_inner();
+                written?;
+            }
+        };
+
+        // a client that hung up may still be owed replies, so only the connection going away
+        // ends both directions
+        let mut outbound = pin!(outbound);
+        tokio::select! {
+            result = inbound => {
+                result?;
+                outbound.await?;
+            }
+            result = &mut outbound => result?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::VecDeque, io::IoSlice, net::SocketAddr, sync::Arc, time::Instant};
+
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncWriteExt, WriteHalf},
+    sync::mpsc,
+};
+
+use crate::{
+    connection::ClientStream,
+    resp::{
+        codec::{Protocol, ReplyBuf, ReplyEncoder},
+        RedisValue,
+    },
+    server::{clients::ClientMemory, config::OutputBufferLimit},
+};
+
+/// Output a connection may queue for its writer before having to wait for the client to read
+pub(crate) const OUTGOING_CAPACITY: usize = 1024;
+
+/// Payloads at least this big are written from where they are held rather than copied into the
+/// output buffer, as Redis' `PROTO_REPLY_CHUNK_BYTES`
+const ZERO_COPY_LEN: usize = 16 * 1024;
+
+/// Output pending past which a reply is written out as it is encoded, waiting for the client to
+/// read it, so a huge reply is never held encoded whole
+const STREAMING_LEN: usize = 64 * 1024;
+
+/// A client's pending output: replies encoded into a buffer, with large payloads chained in
+/// between as the [`Bytes`] they are already held in, to be written out with one vectored write
+#[derive(Default)]
+struct OutputBuffer {
+    /// Output before `tail`, frozen when a large payload was chained after it
+    chunks: VecDeque<Bytes>,
+    chunks_len: usize,
+    tail: BytesMut,
+}
+
+impl OutputBuffer {
+    fn len(&self) -> usize {
+        self.chunks_len + self.tail.len()
+    }
+
+    fn push_chunk(&mut self, chunk: Bytes) {
+        self.chunks_len += chunk.len();
+        self.chunks.push_back(chunk);
+    }
+}
+
+impl ReplyBuf for OutputBuffer {
+    fn reserve(&mut self, additional: usize) {
+        self.tail.reserve(additional);
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        self.tail.extend_from_slice(bytes);
+    }
+
+    fn put_payload(&mut self, payload: Bytes) {
+        if payload.len() < ZERO_COPY_LEN {
+            return self.tail.extend_from_slice(&payload);
+        }
+        if !self.tail.is_empty() {
+            let encoded = self.tail.split().freeze();
+            self.push_chunk(encoded);
+        }
+        self.push_chunk(payload);
+    }
+}
+
+impl Buf for OutputBuffer {
+    fn remaining(&self) -> usize {
+        self.len()
+    }
+
+    fn chunk(&self) -> &[u8] {
+        match self.chunks.front() {
+            Some(chunk) => chunk,
+            None => &self.tail,
+        }
+    }
+
+    fn chunks_vectored<'a>(&'a self, dst: &mut [IoSlice<'a>]) -> usize {
+        let chunks = self.chunks.iter().map(|chunk| &chunk[..]);
+        let mut n = 0;
+        for (slot, chunk) in dst
+            .iter_mut()
+            .zip(chunks.chain([&self.tail[..]]).filter(|c| !c.is_empty()))
+        {
+            *slot = IoSlice::new(chunk);
+            n += 1;
+        }
+        n
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        while let Some(chunk) = self.chunks.front_mut() {
+            if cnt < chunk.len() {
+                chunk.advance(cnt);
+                self.chunks_len -= cnt;
+                return;
+            }
+            cnt -= chunk.len();
+            self.chunks_len -= chunk.len();
+            self.chunks.pop_front();
+        }
+        self.tail.advance(cnt);
+    }
+}
+
+/// What is handed to the task writing to a client
+#[derive(Debug)]
+pub(crate) enum Outgoing {
+    /// A reply or push, encoded for the protocol in use
+    Value(RedisValue),
+    /// Bytes sent as they are, such as the replication stream. Replica links enforce their own
+    /// output buffer limit, so these don't count towards the client's.
+    Raw(Bytes),
+    /// Encode values for this protocol from now on
+    Protocol(Protocol),
+    /// Write out everything queued so far
+    Flush,
+}
+
+/// Write what the connection queues until it hangs up, the client stops reading for longer
+/// than `limit` allows, or it is evicted. Output is only written out on [`Outgoing::Flush`], so
+/// that a pipeline is answered with as few writes as possible.
+pub(crate) async fn run(
+    mut write: WriteHalf<ClientStream>,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+    limit: OutputBufferLimit,
+    client_addr: SocketAddr,
+    memory: Arc<ClientMemory>,
+) {
+    let mut out = OutputBuffer::default();
+    let mut protocol = Protocol::default();
+    let writing = async {
+        while let Some(next) = outgoing.recv().await {
+            match next {
+                Outgoing::Value(value) => {
+                    let mut encoder = ReplyEncoder::new(value, protocol);
+                    while encoder.encode_next(&mut out)? {
+                        if out.len() >= STREAMING_LEN {
+                            flush(&mut write, &mut out, limit).await?;
+                            memory.set_output(out.len());
+                        }
+                    }
+                    // until flushed, whatever is in the output buffer is the client's pending
+                    // output. Only a soft limit of zero seconds is exceeded right away, flush
+                    // times the rest.
+                    let pending = out.len() as u64;
+                    if limit.exceeded(pending, &mut None, Instant::now()) {
+                        return Err(anyhow::anyhow!(
+                            "{pending} bytes of output exceed the limit"
+                        ));
+                    }
+                }
+                Outgoing::Raw(bytes) => out.put_payload(bytes),
+                Outgoing::Protocol(next) => protocol = next,
+                Outgoing::Flush => flush(&mut write, &mut out, limit).await?,
+            }
+            memory.set_output(out.len());
+        }
+        // answer what was served before the connection went away
+        write_out(&mut write, &mut out).await
+    };
+    let result = tokio::select! {
+        result = writing => result,
+        _ = memory.evicted() => Err(anyhow::anyhow!("client memory is over maxmemory-clients")),
+    };
+    if let Err(e) = result {
+        tracing::warn!("Closing connection to {client_addr}: {e}");
+    }
+}
+
+async fn write_out(write: &mut WriteHalf<ClientStream>, out: &mut OutputBuffer) -> Result<()> {
+    write.write_all_buf(out).await?;
+    write.flush().await?;
+    Ok(())
+}
+
+/// Write out the queued output, failing if it stays over the soft limit for too long
+async fn flush(
+    write: &mut WriteHalf<ClientStream>,
+    out: &mut OutputBuffer,
+    limit: OutputBufferLimit,
+) -> Result<()> {
+    let pending = out.len() as u64;
+    if limit.soft == 0 || pending <= limit.soft {
+        return write_out(write, out).await;
+    }
+    tokio::time::timeout(limit.soft_seconds, write_out(write, out))
+        .await
+        .map_err(|_| anyhow::anyhow!("{pending} bytes of output stayed over the soft limit"))?
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::resp::codec::RespFrame;
+
+    #[test]
+    fn chains_large_payloads() {
+        let large = Bytes::from(vec![b'x'; ZERO_COPY_LEN]);
+        let reply = RedisValue::Array(vec![
+            RedisValue::BulkString("small".into()),
+            RedisValue::BulkString(large.clone()),
+            RedisValue::Integer(1),
+        ]);
+        let mut out = OutputBuffer::default();
+        RespFrame::encode_value(reply.clone(), Protocol::Resp2, &mut out).unwrap();
+
+        // the large payload is the very allocation it was held in
+        assert_eq!(out.chunks.len(), 2);
+        assert_eq!(out.chunks[1].as_ptr(), large.as_ptr());
+        let mut slices = [IoSlice::new(&[]); 4];
+        assert_eq!(out.chunks_vectored(&mut slices), 3);
+
+        let mut expected = BytesMut::new();
+        RespFrame::encode_value(reply, Protocol::Resp2, &mut expected).unwrap();
+        assert_eq!(out.len(), expected.len());
+        out.advance(10);
+        assert_eq!(out.copy_to_bytes(out.remaining()), expected[10..]);
+        assert_eq!(out.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn writes_huge_replies_as_they_are_encoded() {
+        let reply = RedisValue::Array(
+            (0..100_000)
+                .map(|i| RedisValue::BulkString(format!("element:{i}").into()))
+                .collect(),
+        );
+        let mut expected = BytesMut::new();
+        RespFrame::encode_value(reply.clone(), Protocol::Resp2, &mut expected).unwrap();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let (_, write) = tokio::io::split(Box::new(server) as ClientStream);
+        let (tx, rx) = mpsc::channel(OUTGOING_CAPACITY);
+        let addr = "127.0.0.1:6379".parse().unwrap();
+        let writer = tokio::spawn(run(
+            write,
+            rx,
+            OutputBufferLimit::default(),
+            addr,
+            ClientMemory::untracked(),
+        ));
+        tx.send(Outgoing::Value(reply)).await.unwrap();
+
+        // the client gets the start of the reply before it is flushed, while the writer waits
+        // for it to read on
+        let mut client = client;
+        let mut received = vec![0; STREAMING_LEN];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, expected[..STREAMING_LEN]);
+
+        tx.send(Outgoing::Flush).await.unwrap();
+        drop(tx);
+        client.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(received, expected);
+    }
+}
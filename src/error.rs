@@ -0,0 +1,127 @@
+use crate::resp::RedisValue;
+
+/// Errors sent back to clients, each displayed as the single line Redis replies with: an
+/// upper-case code followed by a message
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum RedisError {
+    #[error("ERR unknown command '{name}', with args beginning with: {}", quoted(.args))]
+    UnknownCommand { name: String, args: Vec<String> },
+    #[error("ERR unknown subcommand '{subcommand}'. Try {command} HELP.")]
+    UnknownSubcommand {
+        command: &'static str,
+        subcommand: String,
+    },
+    /// The client broke the protocol, so the connection can't be trusted to be in sync anymore
+    #[error("ERR Protocol error: {0}")]
+    Protocol(String),
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
+    #[error("ERR value is not a valid float")]
+    NotFloat,
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpireTime(&'static str),
+    #[error("ERR timeout is negative")]
+    NegativeTimeout,
+    #[error("ERR This instance has cluster support disabled")]
+    ClusterDisabled,
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+    /// Talking to another server failed, e.g. the target of a MIGRATE
+    #[error("IOERR error or timeout {0}")]
+    Io(&'static str),
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnly,
+    #[error("MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.")]
+    MasterDown,
+    #[error("LOADING Redis is loading the dataset in memory")]
+    Loading,
+    #[error("OOM command not allowed when used memory > 'maxmemory'.")]
+    OutOfMemory,
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+    #[error("ERR max number of clients reached")]
+    MaxClients,
+    #[error("THROTTLED rate limit exceeded, slow down")]
+    Throttled,
+    #[error("NOAUTH Authentication required.")]
+    NoAuth,
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+    #[error(
+        "DENIED Redis is running in protected mode because protected mode is enabled and no \
+         bind address or password is set. In this mode connections are only accepted from the \
+         loopback interface. If you want to connect from external computers to Redis you may \
+         adopt one of the following solutions: 1) Disable protected mode by editing the Redis \
+         configuration file, setting the protected mode option to 'no', and then restarting \
+         the server. 2) If you started the server manually just for testing, restart it with \
+         the '--protected-mode no' option. 3) Set the addresses to listen on with the bind \
+         option. NOTE: You only need to do one of the above things in order for the server to \
+         start accepting connections from the outside."
+    )]
+    ProtectedMode,
+    /// Any other error, a message without the `ERR` code
+    #[error("ERR {0}")]
+    Other(String),
+}
+
+impl RedisError {
+    pub(crate) fn other(message: impl Into<String>) -> Self {
+        Self::Other(message.into())
+    }
+}
+
+/// Arguments as quoted in an unknown command error, each followed by a space like Redis does
+fn quoted(args: &[String]) -> String {
+    args.iter().map(|arg| format!("'{arg}' ")).collect()
+}
+
+/// The reply for an error raised while serving a command. Errors not raised as a
+/// [`RedisError`] are generic `ERR`s, and line breaks are replaced so that any message is a
+/// valid simple error.
+pub(crate) fn reply(e: &anyhow::Error) -> RedisValue {
+    let message = match e.downcast_ref::<RedisError>() {
+        Some(e) => e.to_string(),
+        None => format!("ERR {e}"),
+    };
+    RedisValue::SimpleError(message.replace(['\r', '\n'], " ").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies() {
+        let reply = |e: RedisError| reply(&e.into());
+        assert_eq!(
+            reply(RedisError::WrongArity("get".into())),
+            RedisValue::SimpleError("ERR wrong number of arguments for 'get' command".into())
+        );
+        assert_eq!(
+            reply(RedisError::UnknownCommand {
+                name: "FOO".into(),
+                args: vec!["a".into(), "b\r\nc".into()],
+            }),
+            RedisValue::SimpleError(
+                "ERR unknown command 'FOO', with args beginning with: 'a' 'b  c' ".into()
+            )
+        );
+        assert_eq!(
+            reply(RedisError::UnknownSubcommand {
+                command: "CLUSTER",
+                subcommand: "nope".into(),
+            }),
+            RedisValue::SimpleError("ERR unknown subcommand 'nope'. Try CLUSTER HELP.".into())
+        );
+        assert_eq!(
+            super::reply(&anyhow::anyhow!("something broke")),
+            RedisValue::SimpleError("ERR something broke".into())
+        );
+    }
+}
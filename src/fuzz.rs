@@ -0,0 +1,50 @@
+//! Entry points for the targets in `fuzz/`, reaching the parser and command layer that the
+//! public API keeps to itself. Run one with `cargo +nightly fuzz run parse` (or `decode`,
+//! `command`) from the repository root.
+
+use bytes::BytesMut;
+
+use crate::{
+    command::{registry::CommandRegistry, table},
+    resp::{
+        codec::ProtoLimits,
+        parse::{IncrementalParser, ParseContext},
+        RedisValue,
+    },
+};
+
+/// Parse the frame at the start of `data`, handing it to the parser `chunk` bytes at a time as
+/// if it arrived in pieces. Returns how much of `data` the frame took up and its value, `None`
+/// if it is incomplete or invalid.
+pub fn parse_frame(data: &[u8], chunk: usize) -> Option<(usize, RedisValue)> {
+    let ctx = ParseContext::new(ProtoLimits::default());
+    let mut parser = IncrementalParser::default();
+    let mut input = BytesMut::new();
+    for piece in data.chunks(chunk.max(1)) {
+        input.extend_from_slice(piece);
+        match parser.parse(&input, &ctx) {
+            Ok(Some((pos, intermediate))) => {
+                assert!(
+                    pos <= input.len(),
+                    "consumed {pos} of {} bytes",
+                    input.len()
+                );
+                let frame = input.split_to(pos).freeze();
+                return Some((pos, intermediate.generate_value(&frame)));
+            }
+            Ok(None) => {}
+            Err(_) => return None,
+        }
+    }
+    None
+}
+
+/// Names of the built-in commands, for targets to pick one to build arguments for
+pub fn command_names() -> impl Iterator<Item = &'static str> {
+    table::COMMANDS.iter().map(|spec| &*spec.name)
+}
+
+/// Parse a command frame like the dispatcher does, returning whether it was accepted
+pub fn parse_command(frame: RedisValue) -> bool {
+    CommandRegistry::default().parse(&frame).is_ok()
+}
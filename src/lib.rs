@@ -1,4 +1,12 @@
+pub(crate) mod cluster;
 pub(crate) mod command;
 pub(crate) mod connection;
-pub(crate) mod resp;
+pub(crate) mod error;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+pub(crate) mod rdb;
+pub(crate) mod replication;
+/// The RESP wire protocol: values, the codec and a client
+pub mod resp;
 pub mod server;
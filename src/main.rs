@@ -1,13 +1,37 @@
-use anyhow::Result;
-use codecrafters_redis::server::Redis;
+use std::path::Path;
 
-const REDIS_PORT: u16 = 6379;
+use anyhow::Result;
+use codecrafters_redis::server::{
+    check,
+    config::{self, Config},
+    logging, Redis,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let matches = config::cli().get_matches();
+    if matches.get_flag("version") {
+        println!("Redis server v={}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    if let Some(path) = matches.get_one::<String>("check-rdb") {
+        print!("{}", check::check_rdb(Path::new(path))?);
+        return Ok(());
+    }
+    if let Some(path) = matches.get_one::<String>("check-aof") {
+        let fix = matches.get_flag("fix");
+        print!("{}", check::check_aof(Path::new(path), fix)?);
+        return Ok(());
+    }
 
-    let mut redis = Redis::new(REDIS_PORT).await?;
+    let config =
+        tracing::subscriber::with_default(logging::early(), || Config::from_matches(&matches))?;
+    // tokio-console needs its own subscriber, which logs as RUST_LOG says rather than the config
+    #[cfg(feature = "console")]
+    console_subscriber::init();
+    #[cfg(not(feature = "console"))]
+    logging::init(&config)?;
+    let redis = Redis::new(config).await?;
 
     redis.run().await?;
 
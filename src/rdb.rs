@@ -0,0 +1,74 @@
+use bytes::Bytes;
+
+mod crc64;
+mod lzf;
+mod parse;
+mod write;
+
+pub(crate) use parse::{parse, parse_dump};
+pub(crate) use write::{encode, encode_chunks, encode_dump};
+
+/// Magic string at the start of every RDB file
+pub(crate) const RDB_MAGIC: &[u8] = b"REDIS";
+
+/// Newest RDB version we know how to read (Redis 7.4)
+pub(crate) const RDB_MAX_VERSION: u32 = 12;
+
+/// Files older than this do not carry a checksum after the EOF opcode
+pub(crate) const RDB_CHECKSUM_VERSION: u32 = 5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RdbError {
+    #[error("not an RDB file (bad magic string)")]
+    BadMagic,
+    #[error("invalid RDB version field")]
+    BadVersion,
+    #[error("unsupported RDB version {0} (newest supported is {RDB_MAX_VERSION})")]
+    UnsupportedVersion(u32),
+    #[error("unexpected end of file at offset {0}")]
+    UnexpectedEof(usize),
+    #[error("unsupported RDB opcode 0x{0:02x} at offset {1}")]
+    UnsupportedOpcode(u8, usize),
+    #[error("unsupported value type {0} for key {1:?}")]
+    UnsupportedType(u8, Bytes),
+    #[error("unsupported string encoding {0} at offset {1}")]
+    UnsupportedEncoding(u8, usize),
+    #[error("corrupt {0} encoding")]
+    Corrupt(&'static str),
+    #[error("checksum mismatch: file has {expected:016x}, computed {computed:016x}")]
+    ChecksumMismatch { expected: u64, computed: u64 },
+    #[error("trailing data after EOF opcode")]
+    TrailingData,
+}
+
+/// A value type that can be loaded from (and stored to) an RDB file
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum RdbValue {
+    String(Bytes),
+    List(Vec<Bytes>),
+    Set(Vec<Bytes>),
+    /// Field and value pairs
+    Hash(Vec<(Bytes, Bytes)>),
+    /// Members and their scores
+    SortedSet(Vec<(Bytes, f64)>),
+}
+
+/// A single key read out of an RDB file
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct RdbEntry {
+    /// Logical database the key was stored in
+    pub(crate) db: u64,
+    pub(crate) key: Bytes,
+    pub(crate) value: RdbValue,
+    /// Absolute expiration in milliseconds since the UNIX epoch
+    pub(crate) expiration_ms: Option<u64>,
+}
+
+/// The decoded contents of an RDB file
+#[derive(Debug, Default)]
+pub(crate) struct Rdb {
+    pub(crate) version: u32,
+    /// Auxiliary fields (redis-ver, ctime, ...)
+    pub(crate) aux: Vec<(Bytes, Bytes)>,
+    pub(crate) entries: Vec<RdbEntry>,
+}
@@ -0,0 +1,49 @@
+/// Reflected form of the Jones polynomial (0xad93d23594c935a9) used by Redis for RDB checksums
+const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+const TABLE: [u64; 256] = build_table();
+
+const fn build_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continue a CRC64 computation over `data` starting from `crc` (0 for a fresh checksum)
+pub(crate) fn crc64(crc: u64, data: &[u8]) -> u64 {
+    data.iter().fold(crc, |crc, b| {
+        TABLE[((crc ^ *b as u64) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        // check value from the Redis source (crc64.c)
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn incremental() {
+        let whole = crc64(0, b"hello world");
+        let split = crc64(crc64(0, b"hello "), b"world");
+        assert_eq!(whole, split);
+    }
+}
@@ -0,0 +1,159 @@
+//! The LZF compression Redis applies to long strings in RDB files, see lzf_c.c and lzf_d.c
+
+/// Bits of the hash table indexing three-byte sequences seen so far
+const HASH_LOG: u32 = 14;
+
+/// Longest run of literal bytes behind one control byte
+const MAX_LITERAL: usize = 32;
+
+/// Furthest back a match may start
+const MAX_OFFSET: usize = 1 << 13;
+
+/// Longest match one back reference can copy
+const MAX_MATCH: usize = (7 + 255) + 2;
+
+/// Decompress `input` into the `len` bytes it holds, `None` if it is corrupt or does not hold
+/// exactly that many
+pub(crate) fn decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    // no bigger than the input could possibly expand to, whatever length it claims
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(MAX_MATCH)));
+    let mut ip = 0;
+    while ip < input.len() {
+        let ctrl = input[ip] as usize;
+        ip += 1;
+        if ctrl < MAX_LITERAL {
+            // a run of ctrl + 1 literal bytes
+            let literal = input.get(ip..ip + ctrl + 1)?;
+            out.extend_from_slice(literal);
+            ip += ctrl + 1;
+        } else {
+            // a back reference, its length in the top three bits or the byte after
+            let mut copy = ctrl >> 5;
+            if copy == 7 {
+                copy += *input.get(ip)? as usize;
+                ip += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *input.get(ip)? as usize + 1;
+            ip += 1;
+            let start = out.len().checked_sub(offset)?;
+            // the match may overlap what it produces, so it is copied a byte at a time
+            for i in start..start + copy + 2 {
+                out.push(out[i]);
+            }
+        }
+        if out.len() > len {
+            return None;
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+/// Compress `input`, which may come out larger if it does not repeat itself
+pub(crate) fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() + input.len() / MAX_LITERAL + 1);
+    // one past where each hashed sequence was last seen, 0 for never
+    let mut seen = vec![0usize; 1 << HASH_LOG];
+    // the control byte of the literal run being written, filled in once the run ends
+    let mut run = 0;
+    let mut literal = 0;
+    out.push(0);
+
+    let mut ip = 0;
+    while ip < input.len() {
+        if ip + 2 < input.len() {
+            let slot = hash(&input[ip..ip + 3]);
+            let candidate = seen[slot];
+            seen[slot] = ip + 1;
+            if let Some(from) = candidate.checked_sub(1)
+                && ip - from <= MAX_OFFSET
+                && input[from..from + 3] == input[ip..ip + 3]
+            {
+                let longest = (input.len() - ip).min(MAX_MATCH);
+                let mut matched = 3;
+                while matched < longest && input[from + matched] == input[ip + matched] {
+                    matched += 1;
+                }
+                match literal {
+                    0 => {
+                        out.pop();
+                    }
+                    _ => out[run] = (literal - 1) as u8,
+                }
+                let (copy, offset) = (matched - 2, ip - from - 1);
+                if copy < 7 {
+                    out.push((copy << 5 | offset >> 8) as u8);
+                } else {
+                    out.push((7 << 5 | offset >> 8) as u8);
+                    out.push((copy - 7) as u8);
+                }
+                out.push(offset as u8);
+                ip += matched;
+                run = out.len();
+                literal = 0;
+                out.push(0);
+                continue;
+            }
+        }
+        out.push(input[ip]);
+        ip += 1;
+        literal += 1;
+        if literal == MAX_LITERAL {
+            out[run] = (MAX_LITERAL - 1) as u8;
+            run = out.len();
+            literal = 0;
+            out.push(0);
+        }
+    }
+    match literal {
+        0 => {
+            out.pop();
+        }
+        _ => out[run] = (literal - 1) as u8,
+    }
+    out
+}
+
+fn hash(sequence: &[u8]) -> usize {
+    let v = (sequence[0] as u32) << 16 | (sequence[1] as u32) << 8 | sequence[2] as u32;
+    (v.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompresses_literals_and_overlapping_references() {
+        // "abc" then four bytes copied from three back
+        assert_eq!(
+            decompress(&[2, b'a', b'b', b'c', 0x40, 2], 7).as_deref(),
+            Some(&b"abcabca"[..])
+        );
+        assert_eq!(decompress(&[2, b'a', b'b', b'c', 0x40, 2], 6), None);
+        // a reference before the start, and a literal run cut short
+        assert_eq!(decompress(&[0x40, 5], 4), None);
+        assert_eq!(decompress(&[4, b'a'], 5), None);
+    }
+
+    #[test]
+    fn roundtrips() {
+        let long_run = vec![b'x'; 20_000];
+        let text = "the quick brown fox jumps over the lazy dog. ".repeat(50);
+        let noise: Vec<u8> = (0..5000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        for input in [
+            &b""[..],
+            b"ab",
+            b"abcabcabc",
+            &long_run,
+            text.as_bytes(),
+            &noise,
+        ] {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed, input.len()).as_deref(), Some(input));
+        }
+        assert!(compress(&long_run).len() < 300);
+        assert!(compress(text.as_bytes()).len() < text.len() / 10);
+    }
+}
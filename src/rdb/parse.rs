@@ -0,0 +1,733 @@
+use bytes::Bytes;
+
+use crate::rdb::{
+    crc64::crc64, lzf, Rdb, RdbEntry, RdbError, RdbValue, RDB_CHECKSUM_VERSION, RDB_MAGIC,
+    RDB_MAX_VERSION,
+};
+
+// Opcodes, see rdb.h in the Redis source
+const OPCODE_SLOT_INFO: u8 = 0xF4;
+const OPCODE_FUNCTION2: u8 = 0xF5;
+const OPCODE_IDLE: u8 = 0xF8;
+const OPCODE_FREQ: u8 = 0xF9;
+pub(super) const OPCODE_AUX: u8 = 0xFA;
+pub(super) const OPCODE_RESIZEDB: u8 = 0xFB;
+pub(super) const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+pub(super) const OPCODE_SELECTDB: u8 = 0xFE;
+pub(super) const OPCODE_EOF: u8 = 0xFF;
+
+// Value types
+pub(super) const TYPE_STRING: u8 = 0;
+pub(super) const TYPE_LIST: u8 = 1;
+pub(super) const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+pub(super) const TYPE_HASH: u8 = 4;
+pub(super) const TYPE_ZSET_2: u8 = 5;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_SET_LISTPACK: u8 = 20;
+
+// Special string encodings (length prefix with the top two bits set)
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+pub(super) const ENC_LZF: u8 = 3;
+
+// Quicklist 2 node containers
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+/// A length prefix is either a plain length or a marker for a specially encoded string
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    buf: &'a Bytes,
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a Bytes, pos: usize) -> Self {
+        Self { buf, pos }
+    }
+
+    fn take(&mut self, n: usize) -> Result<Bytes, RdbError> {
+        // a crafted length may be anything, so it is checked without overflowing
+        if n > self.buf.len() - self.pos {
+            return Err(RdbError::UnexpectedEof(self.pos));
+        }
+        let out = self.buf.slice(self.pos..self.pos + n);
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        let mut out = [0u8; N];
+        out.copy_from_slice(&self.take(N)?);
+        Ok(out)
+    }
+
+    fn u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn length_encoding(&mut self) -> Result<Length, RdbError> {
+        let first = self.u8()?;
+        match first >> 6 {
+            0b00 => Ok(Length::Len((first & 0x3f) as u64)),
+            0b01 => {
+                let second = self.u8()?;
+                Ok(Length::Len((((first & 0x3f) as u64) << 8) | second as u64))
+            }
+            0b10 => match first {
+                0x80 => Ok(Length::Len(u32::from_be_bytes(self.array()?) as u64)),
+                0x81 => Ok(Length::Len(u64::from_be_bytes(self.array()?))),
+                _ => Err(RdbError::Corrupt("length")),
+            },
+            _ => Ok(Length::Encoded(first & 0x3f)),
+        }
+    }
+
+    fn length(&mut self) -> Result<u64, RdbError> {
+        match self.length_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(RdbError::Corrupt("length")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Bytes, RdbError> {
+        let start = self.pos;
+        match self.length_encoding()? {
+            Length::Len(len) => self.take(len as usize),
+            Length::Encoded(ENC_INT8) => Ok(int_string(self.u8()? as i8 as i64)),
+            Length::Encoded(ENC_INT16) => Ok(int_string(i16::from_le_bytes(self.array()?) as i64)),
+            Length::Encoded(ENC_INT32) => Ok(int_string(i32::from_le_bytes(self.array()?) as i64)),
+            Length::Encoded(ENC_LZF) => {
+                let compressed = self.length()? as usize;
+                let len = self.length()? as usize;
+                let data = self.take(compressed)?;
+                lzf::decompress(&data, len)
+                    .map(Bytes::from)
+                    .ok_or(RdbError::Corrupt("LZF"))
+            }
+            Length::Encoded(other) => Err(RdbError::UnsupportedEncoding(other, start)),
+        }
+    }
+
+    /// `len` strings, for a length read first
+    fn strings(&mut self, len: u64) -> Result<Vec<Bytes>, RdbError> {
+        let mut strings = Vec::with_capacity(len.min(1024) as usize);
+        for _ in 0..len {
+            strings.push(self.string()?);
+        }
+        Ok(strings)
+    }
+
+    /// A sorted set score written out as a string, as RDB versions before 8 did
+    fn double_string(&mut self) -> Result<f64, RdbError> {
+        match self.u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => score(&self.take(len as usize)?),
+        }
+    }
+
+    fn value(&mut self, value_type: u8, key: &Bytes) -> Result<RdbValue, RdbError> {
+        match value_type {
+            TYPE_STRING => Ok(RdbValue::String(self.string()?)),
+            TYPE_LIST => {
+                let len = self.length()?;
+                Ok(RdbValue::List(self.strings(len)?))
+            }
+            TYPE_SET => {
+                let len = self.length()?;
+                Ok(RdbValue::Set(self.strings(len)?))
+            }
+            TYPE_HASH => {
+                let len = self.length()?;
+                Ok(RdbValue::Hash(pairs(self.strings(len.saturating_mul(2))?)?))
+            }
+            TYPE_ZSET | TYPE_ZSET_2 => {
+                let len = self.length()?;
+                let mut members = Vec::with_capacity(len.min(1024) as usize);
+                for _ in 0..len {
+                    let member = self.string()?;
+                    let score = match value_type {
+                        TYPE_ZSET => self.double_string()?,
+                        _ => f64::from_le_bytes(self.array()?),
+                    };
+                    members.push((member, score));
+                }
+                Ok(RdbValue::SortedSet(members))
+            }
+            TYPE_SET_INTSET => Ok(RdbValue::Set(intset(&self.string()?)?)),
+            TYPE_SET_LISTPACK => Ok(RdbValue::Set(listpack(&self.string()?)?)),
+            TYPE_HASH_ZIPLIST => Ok(RdbValue::Hash(pairs(ziplist(&self.string()?)?)?)),
+            TYPE_HASH_LISTPACK => Ok(RdbValue::Hash(pairs(listpack(&self.string()?)?)?)),
+            TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+                let blob = self.string()?;
+                let entries = match value_type {
+                    TYPE_ZSET_ZIPLIST => ziplist(&blob)?,
+                    _ => listpack(&blob)?,
+                };
+                let members = pairs(entries)?
+                    .into_iter()
+                    .map(|(member, s)| Ok((member, score(&s)?)))
+                    .collect::<Result<_, RdbError>>()?;
+                Ok(RdbValue::SortedSet(members))
+            }
+            TYPE_LIST_ZIPLIST => Ok(RdbValue::List(ziplist(&self.string()?)?)),
+            TYPE_LIST_QUICKLIST => {
+                let nodes = self.length()?;
+                let mut list = Vec::new();
+                for _ in 0..nodes {
+                    list.extend(ziplist(&self.string()?)?);
+                }
+                Ok(RdbValue::List(list))
+            }
+            TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.length()?;
+                let mut list = Vec::new();
+                for _ in 0..nodes {
+                    match self.length()? {
+                        QUICKLIST_NODE_PLAIN => list.push(self.string()?),
+                        QUICKLIST_NODE_PACKED => list.extend(listpack(&self.string()?)?),
+                        _ => return Err(RdbError::Corrupt("quicklist node")),
+                    }
+                }
+                Ok(RdbValue::List(list))
+            }
+            other => Err(RdbError::UnsupportedType(other, key.clone())),
+        }
+    }
+}
+
+fn int_string(i: i64) -> Bytes {
+    Bytes::from(i.to_string())
+}
+
+/// A sorted set score spelled out as a string
+fn score(s: &[u8]) -> Result<f64, RdbError> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(RdbError::Corrupt("sorted set score"))
+}
+
+/// Entries of a packed hash or sorted set, which alternate between keys and their values
+fn pairs(entries: Vec<Bytes>) -> Result<Vec<(Bytes, Bytes)>, RdbError> {
+    if !entries.len().is_multiple_of(2) {
+        return Err(RdbError::Corrupt("key and value pairs"));
+    }
+    let mut entries = entries.into_iter();
+    let mut pairs = Vec::with_capacity(entries.len() / 2);
+    while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+        pairs.push((key, value));
+    }
+    Ok(pairs)
+}
+
+/// Decode the members of an intset blob, a set of integers all stored in the same width
+fn intset(blob: &Bytes) -> Result<Vec<Bytes>, RdbError> {
+    let mut r = Reader::new(blob, 0);
+    let width = u32::from_le_bytes(r.array()?);
+    let len = u32::from_le_bytes(r.array()?);
+    let mut out = Vec::with_capacity(len.min(1024) as usize);
+    for _ in 0..len {
+        let int = match width {
+            2 => i16::from_le_bytes(r.array()?) as i64,
+            4 => i32::from_le_bytes(r.array()?) as i64,
+            8 => i64::from_le_bytes(r.array()?),
+            _ => return Err(RdbError::Corrupt("intset")),
+        };
+        out.push(int_string(int));
+    }
+    Ok(out)
+}
+
+/// Decode the entries of a ziplist blob (RDB versions before 10)
+fn ziplist(blob: &Bytes) -> Result<Vec<Bytes>, RdbError> {
+    const HEADER_LEN: usize = 10;
+    const END: u8 = 0xFF;
+
+    let mut r = Reader::new(blob, HEADER_LEN);
+    if blob.len() < HEADER_LEN {
+        return Err(RdbError::Corrupt("ziplist"));
+    }
+    let mut out = Vec::new();
+    loop {
+        // previous entry length, only needed for reverse traversal
+        match r.u8()? {
+            END => break,
+            0xFE => {
+                r.take(4)?;
+            }
+            _ => {}
+        }
+        let enc = r.u8()?;
+        let entry = match enc >> 6 {
+            0b00 => r.take((enc & 0x3f) as usize)?,
+            0b01 => {
+                let len = (((enc & 0x3f) as usize) << 8) | r.u8()? as usize;
+                r.take(len)?
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(r.array()?) as usize;
+                r.take(len)?
+            }
+            _ => match enc {
+                0xC0 => int_string(i16::from_le_bytes(r.array()?) as i64),
+                0xD0 => int_string(i32::from_le_bytes(r.array()?) as i64),
+                0xE0 => int_string(i64::from_le_bytes(r.array()?)),
+                0xF0 => {
+                    let [a, b, c] = r.array()?;
+                    int_string((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+                }
+                0xFE => int_string(r.u8()? as i8 as i64),
+                0xF1..=0xFD => int_string(((enc & 0x0f) - 1) as i64),
+                _ => return Err(RdbError::Corrupt("ziplist")),
+            },
+        };
+        out.push(entry);
+    }
+    Ok(out)
+}
+
+/// Decode the entries of a listpack blob (RDB version 10 and later)
+fn listpack(blob: &Bytes) -> Result<Vec<Bytes>, RdbError> {
+    const HEADER_LEN: usize = 6;
+    const END: u8 = 0xFF;
+
+    if blob.len() < HEADER_LEN {
+        return Err(RdbError::Corrupt("listpack"));
+    }
+    let mut r = Reader::new(blob, HEADER_LEN);
+    let mut out = Vec::new();
+    loop {
+        let start = r.pos;
+        let enc = r.u8()?;
+        let entry = if enc == END {
+            break;
+        } else if enc & 0x80 == 0 {
+            int_string((enc & 0x7f) as i64)
+        } else if enc & 0xC0 == 0x80 {
+            r.take((enc & 0x3f) as usize)?
+        } else if enc & 0xE0 == 0xC0 {
+            let raw = (((enc & 0x1f) as i64) << 8) | r.u8()? as i64;
+            int_string(if raw >= 1 << 12 { raw - (1 << 13) } else { raw })
+        } else if enc & 0xF0 == 0xE0 {
+            let len = (((enc & 0x0f) as usize) << 8) | r.u8()? as usize;
+            r.take(len)?
+        } else {
+            match enc {
+                0xF0 => {
+                    let len = u32::from_le_bytes(r.array()?) as usize;
+                    r.take(len)?
+                }
+                0xF1 => int_string(i16::from_le_bytes(r.array()?) as i64),
+                0xF2 => {
+                    let [a, b, c] = r.array()?;
+                    int_string((i32::from_le_bytes([0, a, b, c]) >> 8) as i64)
+                }
+                0xF3 => int_string(i32::from_le_bytes(r.array()?) as i64),
+                0xF4 => int_string(i64::from_le_bytes(r.array()?)),
+                _ => return Err(RdbError::Corrupt("listpack")),
+            }
+        };
+        // skip the back-length, which grows by one byte per 7 bits of entry length
+        let entry_len = r.pos - start;
+        let backlen = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        r.take(backlen)?;
+        out.push(entry);
+    }
+    Ok(out)
+}
+
+/// Parse a complete RDB file, verifying its version and checksum
+pub(crate) fn parse(data: &Bytes) -> Result<Rdb, RdbError> {
+    let mut r = Reader::new(data, 0);
+
+    if r.take(RDB_MAGIC.len()).ok().as_deref() != Some(RDB_MAGIC) {
+        return Err(RdbError::BadMagic);
+    }
+    let version = std::str::from_utf8(&r.take(4).map_err(|_| RdbError::BadVersion)?)
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or(RdbError::BadVersion)?;
+    if version == 0 || version > RDB_MAX_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+
+    let mut rdb = Rdb {
+        version,
+        ..Default::default()
+    };
+    let mut db = 0;
+    let mut expiration_ms = None;
+
+    loop {
+        let op_pos = r.pos;
+        match r.u8()? {
+            OPCODE_EOF => break,
+            OPCODE_AUX => {
+                let key = r.string()?;
+                let value = r.string()?;
+                rdb.aux.push((key, value));
+            }
+            OPCODE_RESIZEDB => {
+                r.length()?;
+                r.length()?;
+            }
+            OPCODE_SELECTDB => db = r.length()?,
+            OPCODE_EXPIRETIME_MS => expiration_ms = Some(u64::from_le_bytes(r.array()?)),
+            OPCODE_EXPIRETIME => {
+                expiration_ms = Some(u32::from_le_bytes(r.array()?) as u64 * 1000);
+            }
+            OPCODE_IDLE => {
+                r.length()?;
+            }
+            OPCODE_FREQ => {
+                r.u8()?;
+            }
+            OPCODE_SLOT_INFO => {
+                // slot id, slot size, expires slot size
+                r.length()?;
+                r.length()?;
+                r.length()?;
+            }
+            OPCODE_FUNCTION2 => {
+                // function libraries are not supported, skip the code
+                r.string()?;
+            }
+            value_type if value_type < OPCODE_SLOT_INFO => {
+                let key = r.string()?;
+                let value = r.value(value_type, &key)?;
+                rdb.entries.push(RdbEntry {
+                    db,
+                    key,
+                    value,
+                    expiration_ms: expiration_ms.take(),
+                });
+            }
+            other => return Err(RdbError::UnsupportedOpcode(other, op_pos)),
+        }
+    }
+
+    if version >= RDB_CHECKSUM_VERSION {
+        let body_len = r.pos;
+        let expected = u64::from_le_bytes(r.array()?);
+        // a checksum of 0 means the writer had checksums disabled
+        if expected != 0 {
+            let computed = crc64(0, &data[..body_len]);
+            if computed != expected {
+                return Err(RdbError::ChecksumMismatch { expected, computed });
+            }
+        }
+    }
+
+    if r.pos != data.len() {
+        return Err(RdbError::TrailingData);
+    }
+
+    Ok(rdb)
+}
+
+/// Parse a DUMP payload, verifying the RDB version and checksum that end it
+pub(crate) fn parse_dump(payload: &Bytes) -> Result<RdbValue, RdbError> {
+    const FOOTER_LEN: usize = 10;
+
+    let Some(body_len) = payload.len().checked_sub(FOOTER_LEN) else {
+        return Err(RdbError::UnexpectedEof(payload.len()));
+    };
+    let mut r = Reader::new(payload, body_len);
+    let version = u16::from_le_bytes(r.array()?) as u32;
+    if version > RDB_MAX_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+    let expected = u64::from_le_bytes(r.array()?);
+    let computed = crc64(0, &payload[..body_len + 2]);
+    if computed != expected {
+        return Err(RdbError::ChecksumMismatch { expected, computed });
+    }
+
+    let body = payload.slice(..body_len);
+    let mut r = Reader::new(&body, 0);
+    let value_type = r.u8()?;
+    let value = r.value(value_type, &Bytes::new())?;
+    if r.pos != body.len() {
+        return Err(RdbError::TrailingData);
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_checksum(mut body: Vec<u8>) -> Bytes {
+        let crc = crc64(0, &body);
+        body.extend_from_slice(&crc.to_le_bytes());
+        Bytes::from(body)
+    }
+
+    fn minimal(version: &[u8; 4]) -> Vec<u8> {
+        let mut body = b"REDIS".to_vec();
+        body.extend_from_slice(version);
+        // aux redis-ver 7.2.0
+        body.push(OPCODE_AUX);
+        body.push(9);
+        body.extend_from_slice(b"redis-ver");
+        body.push(5);
+        body.extend_from_slice(b"7.2.0");
+        body.extend_from_slice(&[OPCODE_SELECTDB, 0, OPCODE_RESIZEDB, 2, 1]);
+        // "foo" -> "bar"
+        body.extend_from_slice(&[TYPE_STRING, 3]);
+        body.extend_from_slice(b"foo");
+        body.push(3);
+        body.extend_from_slice(b"bar");
+        // "num" -> int8 encoded -5 with a millisecond expiration
+        body.push(OPCODE_EXPIRETIME_MS);
+        body.extend_from_slice(&1_700_000_000_000u64.to_le_bytes());
+        body.extend_from_slice(&[TYPE_STRING, 3]);
+        body.extend_from_slice(b"num");
+        body.extend_from_slice(&[0xC0, (-5i8) as u8]);
+        body.push(OPCODE_EOF);
+        body
+    }
+
+    #[test]
+    fn parse_strings_with_checksum() {
+        let rdb = parse(&with_checksum(minimal(b"0011"))).unwrap();
+        assert_eq!(rdb.version, 11);
+        assert_eq!(rdb.aux, vec![("redis-ver".into(), "7.2.0".into())]);
+        assert_eq!(
+            rdb.entries,
+            vec![
+                RdbEntry {
+                    db: 0,
+                    key: "foo".into(),
+                    value: RdbValue::String("bar".into()),
+                    expiration_ms: None,
+                },
+                RdbEntry {
+                    db: 0,
+                    key: "num".into(),
+                    value: RdbValue::String("-5".into()),
+                    expiration_ms: Some(1_700_000_000_000),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn huge_and_truncated_lengths() {
+        let mut huge = b"REDIS0011".to_vec();
+        huge.extend_from_slice(&[TYPE_STRING, 0x81]);
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            parse(&Bytes::from(huge)),
+            Err(RdbError::UnexpectedEof(19))
+        ));
+        let mut truncated = b"REDIS0011".to_vec();
+        truncated.extend_from_slice(&[TYPE_STRING, 3]);
+        truncated.extend_from_slice(b"fo");
+        assert!(matches!(
+            parse(&Bytes::from(truncated)),
+            Err(RdbError::UnexpectedEof(11))
+        ));
+    }
+
+    #[test]
+    fn checksum_disabled() {
+        let mut body = minimal(b"0011");
+        body.extend_from_slice(&[0; 8]);
+        assert!(parse(&Bytes::from(body)).is_ok());
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut body = minimal(b"0011");
+        body.extend_from_slice(&1u64.to_le_bytes());
+        assert!(matches!(
+            parse(&Bytes::from(body)),
+            Err(RdbError::ChecksumMismatch { expected: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn old_version_without_checksum() {
+        let rdb = parse(&Bytes::from(minimal(b"0004"))).unwrap();
+        assert_eq!(rdb.entries.len(), 2);
+    }
+
+    #[test]
+    fn version_checks() {
+        assert!(matches!(
+            parse(&with_checksum(minimal(b"0099"))),
+            Err(RdbError::UnsupportedVersion(99))
+        ));
+        assert!(matches!(
+            parse(&with_checksum(minimal(b"00x1"))),
+            Err(RdbError::BadVersion)
+        ));
+        assert!(matches!(
+            parse(&Bytes::from_static(b"RDB00011")),
+            Err(RdbError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn lzf_strings() {
+        let mut body = b"REDIS0011".to_vec();
+        // "abcabca" compressed to 6 bytes
+        body.extend_from_slice(&[TYPE_STRING, 1, b'k', 0xC3, 6, 7]);
+        body.extend_from_slice(&[2, b'a', b'b', b'c', 0x40, 2]);
+        body.push(OPCODE_EOF);
+        let rdb = parse(&with_checksum(body.clone())).unwrap();
+        assert_eq!(rdb.entries[0].value, RdbValue::String("abcabca".into()));
+
+        // claiming a length it doesn't decompress to
+        body[14] = 8;
+        assert!(matches!(
+            parse(&with_checksum(body)),
+            Err(RdbError::Corrupt("LZF"))
+        ));
+    }
+
+    #[test]
+    fn dump_payloads() {
+        // DUMP of the integer 10 by Redis 5, from the DUMP documentation
+        let payload = Bytes::from_static(b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n");
+        assert_eq!(parse_dump(&payload).unwrap(), RdbValue::String("10".into()));
+
+        let mut corrupt = payload.to_vec();
+        corrupt[2] = b'\x0b';
+        assert!(matches!(
+            parse_dump(&Bytes::from(corrupt)),
+            Err(RdbError::ChecksumMismatch { .. })
+        ));
+        assert!(parse_dump(&payload.slice(1..)).is_err());
+
+        let mut huge = vec![TYPE_STRING, 0x81];
+        huge.extend_from_slice(&u64::MAX.to_be_bytes());
+        huge.extend_from_slice(&11u16.to_le_bytes());
+        let crc = crc64(0, &huge);
+        huge.extend_from_slice(&crc.to_le_bytes());
+        assert!(matches!(
+            parse_dump(&Bytes::from(huge)),
+            Err(RdbError::UnexpectedEof(10))
+        ));
+    }
+
+    #[test]
+    fn unsupported_type() {
+        let mut body = b"REDIS0011".to_vec();
+        // a stream (type 15), whose contents are never looked at
+        body.extend_from_slice(&[15, 1, b's', 1, 1, b'm', OPCODE_EOF]);
+        assert!(matches!(
+            parse(&with_checksum(body)),
+            Err(RdbError::UnsupportedType(15, _))
+        ));
+    }
+
+    #[test]
+    fn packed_sets_and_sorted_sets() {
+        // intset of 16 bit integers holding 1 and -2
+        let mut is = 2u32.to_le_bytes().to_vec();
+        is.extend_from_slice(&2u32.to_le_bytes());
+        is.extend_from_slice(&1i16.to_le_bytes());
+        is.extend_from_slice(&(-2i16).to_le_bytes());
+        // listpack holding member "m" and its score "1.5"
+        let mut lp = vec![0, 0, 0, 0, 2, 0];
+        lp.extend_from_slice(&[0x81, b'm', 2]);
+        lp.extend_from_slice(&[0x83, b'1', b'.', b'5', 4]);
+        lp.push(0xFF);
+
+        let mut body = b"REDIS0011".to_vec();
+        body.extend_from_slice(&[TYPE_SET_INTSET, 1, b's', is.len() as u8]);
+        body.extend_from_slice(&is);
+        body.extend_from_slice(&[TYPE_ZSET_LISTPACK, 1, b'z', lp.len() as u8]);
+        body.extend_from_slice(&lp);
+        body.push(OPCODE_EOF);
+
+        let rdb = parse(&with_checksum(body)).unwrap();
+        assert_eq!(
+            rdb.entries[0].value,
+            RdbValue::Set(vec!["1".into(), "-2".into()])
+        );
+        assert_eq!(
+            rdb.entries[1].value,
+            RdbValue::SortedSet(vec![("m".into(), 1.5)])
+        );
+    }
+
+    #[test]
+    fn quicklist2_listpack() {
+        // listpack holding "a", 7 and -100
+        let mut lp = vec![0, 0, 0, 0, 3, 0];
+        lp.extend_from_slice(&[0x81, b'a', 2]);
+        lp.extend_from_slice(&[7, 1]);
+        let neg = (-100i64 + (1 << 13)) as u16;
+        lp.extend_from_slice(&[0xC0 | (neg >> 8) as u8, neg as u8, 2]);
+        lp.push(0xFF);
+        let total = lp.len() as u32;
+        lp[..4].copy_from_slice(&total.to_le_bytes());
+
+        let mut body = b"REDIS0011".to_vec();
+        body.extend_from_slice(&[
+            TYPE_LIST_QUICKLIST_2,
+            1,
+            b'l',
+            1,
+            QUICKLIST_NODE_PACKED as u8,
+        ]);
+        body.push(lp.len() as u8);
+        body.extend_from_slice(&lp);
+        body.push(OPCODE_EOF);
+
+        let rdb = parse(&with_checksum(body)).unwrap();
+        assert_eq!(
+            rdb.entries[0].value,
+            RdbValue::List(vec!["a".into(), "7".into(), "-100".into()])
+        );
+    }
+
+    #[test]
+    fn ziplist_list() {
+        // ziplist holding "hi", 5 (immediate) and 300 (int16)
+        let mut zl = vec![0; 10];
+        zl.extend_from_slice(&[0, 0x02, b'h', b'i']);
+        zl.extend_from_slice(&[4, 0xF6]);
+        zl.extend_from_slice(&[2, 0xC0]);
+        zl.extend_from_slice(&300i16.to_le_bytes());
+        zl.push(0xFF);
+
+        let mut body = b"REDIS0006".to_vec();
+        body.extend_from_slice(&[TYPE_LIST_ZIPLIST, 1, b'l']);
+        body.push(zl.len() as u8);
+        body.extend_from_slice(&zl);
+        body.push(OPCODE_EOF);
+
+        let rdb = parse(&with_checksum(body)).unwrap();
+        assert_eq!(
+            rdb.entries[0].value,
+            RdbValue::List(vec!["hi".into(), "5".into(), "300".into()])
+        );
+    }
+}
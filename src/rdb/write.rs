@@ -0,0 +1,258 @@
+use std::ops::ControlFlow;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::rdb::{crc64::crc64, lzf, RdbEntry, RdbValue, RDB_MAGIC};
+
+use super::parse::{
+    ENC_LZF, OPCODE_AUX, OPCODE_EOF, OPCODE_EXPIRETIME_MS, OPCODE_RESIZEDB, OPCODE_SELECTDB,
+    TYPE_HASH, TYPE_LIST, TYPE_SET, TYPE_STRING, TYPE_ZSET_2,
+};
+
+/// Version we write, understood by Redis 7.0 and later
+const RDB_VERSION: u16 = 11;
+
+/// Version we advertise in the redis-ver aux field
+const REDIS_VER: &str = "7.2.0";
+
+fn put_length(dst: &mut BytesMut, len: u64) {
+    if len < 1 << 6 {
+        dst.put_u8(len as u8);
+    } else if len < 1 << 14 {
+        dst.put_u16(0x4000 | len as u16);
+    } else if len <= u32::MAX as u64 {
+        dst.put_u8(0x80);
+        dst.put_u32(len as u32);
+    } else {
+        dst.put_u8(0x81);
+        dst.put_u64(len);
+    }
+}
+
+/// Strings this short are never worth compressing
+const COMPRESS_MIN_LEN: usize = 21;
+
+fn put_string(dst: &mut BytesMut, s: &[u8], compress: bool) {
+    if compress && s.len() >= COMPRESS_MIN_LEN {
+        let compressed = lzf::compress(s);
+        // as in Redis, only kept when at least four bytes shorter
+        if compressed.len() + 4 <= s.len() {
+            dst.put_u8(0xC0 | ENC_LZF);
+            put_length(dst, compressed.len() as u64);
+            put_length(dst, s.len() as u64);
+            dst.extend_from_slice(&compressed);
+            return;
+        }
+    }
+    put_length(dst, s.len() as u64);
+    dst.extend_from_slice(s);
+}
+
+/// Serialize a set of entries into a complete RDB file, checksum included, LZF compressing
+/// long strings if `compress` is set
+pub(crate) fn encode(entries: &[RdbEntry], compress: bool) -> Bytes {
+    let mut rdb = Bytes::new();
+    // a single chunk, as it never fills up before the end
+    encode_chunks(entries, compress, usize::MAX, |chunk| {
+        rdb = chunk;
+        ControlFlow::Continue(())
+    });
+    rdb
+}
+
+/// Serialize a set of entries as [`encode`] does, handing the RDB to `sink` in chunks of about
+/// `chunk_size` bytes as it is encoded. Stops early if `sink` breaks.
+pub(crate) fn encode_chunks(
+    entries: &[RdbEntry],
+    compress: bool,
+    chunk_size: usize,
+    mut sink: impl FnMut(Bytes) -> ControlFlow<()>,
+) {
+    let mut dst = BytesMut::with_capacity((64 + entries.len() * 32).min(chunk_size));
+    // the checksum of what was already handed over
+    let mut crc = 0;
+    dst.extend_from_slice(RDB_MAGIC);
+    dst.extend_from_slice(format!("{RDB_VERSION:04}").as_bytes());
+
+    let ctime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_string();
+    for (key, value) in [
+        ("redis-ver", REDIS_VER),
+        ("redis-bits", "64"),
+        ("ctime", ctime.as_str()),
+    ] {
+        dst.put_u8(OPCODE_AUX);
+        put_string(&mut dst, key.as_bytes(), compress);
+        put_string(&mut dst, value.as_bytes(), compress);
+    }
+
+    // every entry we write belongs to the single database we serve
+    dst.put_u8(OPCODE_SELECTDB);
+    put_length(&mut dst, 0);
+    dst.put_u8(OPCODE_RESIZEDB);
+    put_length(&mut dst, entries.len() as u64);
+    put_length(
+        &mut dst,
+        entries.iter().filter(|e| e.expiration_ms.is_some()).count() as u64,
+    );
+
+    for entry in entries {
+        if let Some(ms) = entry.expiration_ms {
+            dst.put_u8(OPCODE_EXPIRETIME_MS);
+            dst.put_u64_le(ms);
+        }
+        dst.put_u8(value_type(&entry.value));
+        put_string(&mut dst, &entry.key, compress);
+        put_value(&mut dst, &entry.value, compress);
+
+        if dst.len() >= chunk_size {
+            crc = crc64(crc, &dst);
+            if sink(dst.split().freeze()).is_break() {
+                return;
+            }
+        }
+    }
+
+    dst.put_u8(OPCODE_EOF);
+    let crc = crc64(crc, &dst);
+    dst.put_u64_le(crc);
+    let _ = sink(dst.freeze());
+}
+
+/// Serialize a value as DUMP does: its type and encoding as in an RDB file, followed by the
+/// RDB version and a checksum
+pub(crate) fn encode_dump(value: &RdbValue, compress: bool) -> Bytes {
+    let mut dst = BytesMut::new();
+    dst.put_u8(value_type(value));
+    put_value(&mut dst, value, compress);
+    dst.put_u16_le(RDB_VERSION);
+    let crc = crc64(0, &dst);
+    dst.put_u64_le(crc);
+    dst.freeze()
+}
+
+fn value_type(value: &RdbValue) -> u8 {
+    match value {
+        RdbValue::String(_) => TYPE_STRING,
+        RdbValue::List(_) => TYPE_LIST,
+        RdbValue::Set(_) => TYPE_SET,
+        RdbValue::Hash(_) => TYPE_HASH,
+        RdbValue::SortedSet(_) => TYPE_ZSET_2,
+    }
+}
+
+fn put_value(dst: &mut BytesMut, value: &RdbValue, compress: bool) {
+    match value {
+        RdbValue::String(value) => put_string(dst, value, compress),
+        RdbValue::List(elements) | RdbValue::Set(elements) => {
+            put_length(dst, elements.len() as u64);
+            for element in elements {
+                put_string(dst, element, compress);
+            }
+        }
+        RdbValue::Hash(pairs) => {
+            put_length(dst, pairs.len() as u64);
+            for (field, value) in pairs {
+                put_string(dst, field, compress);
+                put_string(dst, value, compress);
+            }
+        }
+        RdbValue::SortedSet(members) => {
+            put_length(dst, members.len() as u64);
+            for (member, score) in members {
+                put_string(dst, member, compress);
+                dst.put_f64_le(*score);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdb::{parse, parse_dump};
+
+    #[test]
+    fn roundtrip() {
+        let long = Bytes::from(vec![b'x'; 20_000]);
+        let entries = vec![
+            RdbEntry {
+                db: 0,
+                key: "foo".into(),
+                value: RdbValue::String("bar".into()),
+                expiration_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: "big".into(),
+                value: RdbValue::String(long),
+                expiration_ms: Some(4_000_000_000_000),
+            },
+            RdbEntry {
+                db: 0,
+                key: "list".into(),
+                value: RdbValue::List(vec!["a".into(), "b".into()]),
+                expiration_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: "set".into(),
+                value: RdbValue::Set(vec!["a".into(), "b".into()]),
+                expiration_ms: None,
+            },
+            RdbEntry {
+                db: 0,
+                key: "hash".into(),
+                value: RdbValue::Hash(vec![("f".into(), "v".into())]),
+                expiration_ms: Some(4_000_000_000_000),
+            },
+            RdbEntry {
+                db: 0,
+                key: "zset".into(),
+                value: RdbValue::SortedSet(vec![("a".into(), -1.5), ("b".into(), f64::INFINITY)]),
+                expiration_ms: None,
+            },
+        ];
+        for compress in [false, true] {
+            let rdb = parse(&encode(&entries, compress)).unwrap();
+            assert_eq!(rdb.version, 11);
+            assert_eq!(rdb.entries, entries);
+        }
+        assert!(encode(&entries, true).len() < 1000);
+
+        for entry in &entries {
+            let payload = encode_dump(&entry.value, true);
+            assert_eq!(parse_dump(&payload).unwrap(), entry.value);
+        }
+    }
+    #[test]
+    fn encodes_in_chunks() {
+        let entries: Vec<_> = (0..100)
+            .map(|i| RdbEntry {
+                db: 0,
+                key: format!("key:{i}").into(),
+                value: RdbValue::String(Bytes::from(vec![b'v'; 100])),
+                expiration_ms: None,
+            })
+            .collect();
+        let mut chunks = Vec::new();
+        encode_chunks(&entries, false, 1000, |chunk| {
+            chunks.push(chunk);
+            ControlFlow::Continue(())
+        });
+        assert!(chunks.len() > 10);
+        let rdb = parse(&chunks.concat().into()).unwrap();
+        assert_eq!(rdb.entries, entries);
+
+        // nothing more is encoded once the sink breaks
+        let mut sunk = 0;
+        encode_chunks(&entries, false, 1000, |_| {
+            sunk += 1;
+            ControlFlow::Break(())
+        });
+        assert_eq!(sunk, 1);
+    }
+}
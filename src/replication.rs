@@ -0,0 +1,678 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt::Write,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex as StdMutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use bytes::{Bytes, BytesMut};
+use dashmap::DashMap;
+use tokio::{
+    sync::{broadcast, futures::Notified, watch, Mutex, MutexGuard, Notify},
+    task::AbortHandle,
+};
+
+use crate::{
+    replication::backlog::{Backlog, BACKLOG_SIZE},
+    resp::{
+        codec::{Protocol, RespFrame},
+        RedisValue,
+    },
+};
+
+pub(crate) mod backlog;
+pub(crate) mod diskless;
+pub(crate) mod failover;
+pub(crate) mod master;
+pub(crate) mod replica;
+
+/// How many propagated commands a replica may fall behind before its link is dropped
+const PROPAGATION_CAPACITY: usize = 16 * 1024;
+
+/// Length of a replication ID in hex characters
+const REPLID_LEN: usize = 40;
+
+/// How often the master asks its replicas for their offsets
+pub(crate) const GETACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether we accept writes or follow a master
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Role {
+    Master,
+    Replica { host: String, port: u16 },
+}
+
+/// Progress of a coordinated FAILOVER, as reported by INFO
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FailoverState {
+    NoFailover,
+    WaitingForSync,
+    FailoverInProgress,
+}
+
+impl FailoverState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::NoFailover => "no-failover",
+            Self::WaitingForSync => "waiting-for-sync",
+            Self::FailoverInProgress => "failover-in-progress",
+        }
+    }
+}
+
+/// What we know about a replica connected to us
+#[derive(Debug, Clone)]
+pub(crate) struct ReplicaInfo {
+    /// Port the replica listens on, from REPLCONF listening-port
+    pub(crate) listening_port: Option<u16>,
+
+    /// Address the replica asked to be known by, from REPLCONF ip-address
+    pub(crate) announced_ip: Option<String>,
+
+    /// Last replication offset the replica acknowledged
+    pub(crate) ack_offset: u64,
+
+    /// When the replica last acknowledged an offset
+    pub(crate) last_ack: Instant,
+}
+
+impl ReplicaInfo {
+    /// Address others should reach the replica connected from `addr` at
+    pub(crate) fn ip(&self, addr: &SocketAddr) -> String {
+        self.announced_ip
+            .clone()
+            .unwrap_or_else(|| addr.ip().to_string())
+    }
+}
+
+/// Replication bookkeeping shared by every connection
+pub(crate) struct ReplicationState {
+    /// Our current role
+    role: RwLock<Role>,
+
+    /// Whether the link to our master is established and synchronized (replicas only)
+    master_link_up: AtomicBool,
+
+    /// Last time we heard from our master (replicas only)
+    master_last_io: StdMutex<Option<Instant>>,
+
+    /// When the link to our master last went down (replicas only)
+    master_link_down_since: StdMutex<Option<Instant>>,
+
+    /// Set while we are receiving a full resync from our master
+    sync_in_progress: AtomicBool,
+
+    /// Set while a dataset is being loaded, at startup or from a full resync
+    loading: watch::Sender<bool>,
+
+    /// Task running the link to our master, aborted when we stop being its replica
+    link_task: StdMutex<Option<AbortHandle>>,
+
+    /// Bumped whenever we change masters, which disconnects our own replicas
+    role_changes: watch::Sender<u64>,
+
+    /// Current FAILOVER progress
+    failover: StdMutex<FailoverState>,
+
+    /// Woken by FAILOVER ABORT
+    failover_abort: Notify,
+
+    /// Previous replication ID and the first offset it is no longer valid for
+    replid2: RwLock<(String, i64)>,
+
+    /// ID of the replication history we are part of: our own as a master, our master's as a replica
+    replid: RwLock<String>,
+
+    /// Bytes of replication stream produced (master) or processed (replica) so far. Held while
+    /// sending so offsets and stream order always agree
+    offset: StdMutex<u64>,
+
+    /// Recent replication stream for partial resyncs. Locked after `offset` when both are needed
+    backlog: StdMutex<Backlog>,
+
+    /// Serializes applying and propagating writes, so a full resync snapshot lines up exactly
+    /// with the start of the command stream the replica receives
+    write_lock: Mutex<()>,
+
+    /// Encoded replication stream, fanned out to every replica link
+    propagate_tx: broadcast::Sender<Bytes>,
+
+    /// Replicas connected to us, keyed by their connection address
+    replicas: DashMap<SocketAddr, ReplicaInfo>,
+
+    /// Woken whenever a replica acknowledges an offset
+    ack_notify: Notify,
+
+    /// Replicas waiting for a diskless sync to start
+    diskless: diskless::Pending,
+}
+
+impl ReplicationState {
+    pub(crate) fn new(role: Role) -> Self {
+        let (propagate_tx, _) = broadcast::channel(PROPAGATION_CAPACITY);
+        Self {
+            role: RwLock::new(role),
+            master_link_up: AtomicBool::new(false),
+            master_last_io: StdMutex::new(None),
+            master_link_down_since: StdMutex::new(None),
+            sync_in_progress: AtomicBool::new(false),
+            loading: watch::channel(false).0,
+            link_task: StdMutex::new(None),
+            role_changes: watch::channel(0).0,
+            failover: StdMutex::new(FailoverState::NoFailover),
+            failover_abort: Notify::new(),
+            replid: RwLock::new(generate_id()),
+            replid2: RwLock::new(("0".repeat(REPLID_LEN), -1)),
+            offset: StdMutex::new(0),
+            backlog: StdMutex::new(Backlog::new(BACKLOG_SIZE, 0)),
+            write_lock: Mutex::new(()),
+            propagate_tx,
+            replicas: DashMap::new(),
+            ack_notify: Notify::new(),
+            diskless: diskless::Pending::default(),
+        }
+    }
+
+    pub(crate) fn replid(&self) -> String {
+        self.replid.read().unwrap().clone()
+    }
+
+    pub(crate) fn offset(&self) -> u64 {
+        *self.offset.lock().unwrap()
+    }
+
+    pub(crate) fn role(&self) -> Role {
+        self.role.read().unwrap().clone()
+    }
+
+    pub(crate) fn is_replica(&self) -> bool {
+        matches!(*self.role.read().unwrap(), Role::Replica { .. })
+    }
+
+    /// Remember the task running our master link so it can be stopped on a role change
+    pub(crate) fn set_link_task(&self, task: AbortHandle) {
+        if let Some(old) = self.link_task.lock().unwrap().replace(task) {
+            old.abort();
+        }
+    }
+
+    /// Stop replicating and start accepting writes (REPLICAOF NO ONE).
+    ///
+    /// Like Redis, the old replication ID is kept as the secondary ID so our history stays
+    /// identifiable.
+    pub(crate) fn promote(&self) {
+        let mut role = self.role.write().unwrap();
+        if *role == Role::Master {
+            return;
+        }
+        *role = Role::Master;
+        if let Some(task) = self.link_task.lock().unwrap().take() {
+            task.abort();
+        }
+        self.master_link_up.store(false, Ordering::SeqCst);
+
+        let mut replid = self.replid.write().unwrap();
+        let old = std::mem::replace(&mut *replid, generate_id());
+        *self.replid2.write().unwrap() = (old, self.offset() as i64 + 1);
+        tracing::info!("Promoted to master with replication ID {replid}");
+    }
+
+    /// Become a replica of `host:port`, dropping any replicas of our own. The caller is
+    /// responsible for starting the new master link.
+    pub(crate) fn demote(&self, host: String, port: u16) {
+        tracing::info!("Becoming a replica of {host}:{port}");
+        *self.role.write().unwrap() = Role::Replica { host, port };
+        self.master_link_up.store(false, Ordering::SeqCst);
+        self.role_changes.send_modify(|generation| *generation += 1);
+    }
+
+    /// Notified when our own replicas must be disconnected
+    pub(crate) fn role_changes(&self) -> watch::Receiver<u64> {
+        self.role_changes.subscribe()
+    }
+
+    pub(crate) fn failover_state(&self) -> FailoverState {
+        *self.failover.lock().unwrap()
+    }
+
+    pub(crate) fn set_failover_state(&self, state: FailoverState) {
+        *self.failover.lock().unwrap() = state;
+    }
+
+    /// Move from no failover to waiting for sync, failing if a failover is already running
+    pub(crate) fn begin_failover(&self) -> bool {
+        let mut state = self.failover.lock().unwrap();
+        if *state != FailoverState::NoFailover {
+            return false;
+        }
+        *state = FailoverState::WaitingForSync;
+        true
+    }
+
+    /// Interrupt a FAILOVER that is waiting for its target to catch up
+    pub(crate) fn abort_failover(&self) {
+        self.failover_abort.notify_waiters();
+    }
+
+    /// Resolves on the next FAILOVER ABORT
+    pub(crate) fn failover_aborted(&self) -> Notified<'_> {
+        self.failover_abort.notified()
+    }
+
+    /// Adopt the history handed to us by our master on a full resync.
+    ///
+    /// Our own replicas hold a dataset we are about to replace, so they are disconnected to
+    /// resynchronize from us.
+    pub(crate) fn set_master(&self, replid: &str, offset: u64) {
+        *self.replid.write().unwrap() = replid.to_string();
+        let mut current = self.offset.lock().unwrap();
+        *current = offset;
+        self.backlog.lock().unwrap().reset(offset);
+        self.role_changes.send_modify(|generation| *generation += 1);
+    }
+
+    /// Continue our current history after the master accepted a partial resync, switching to
+    /// its replication ID if it has changed (e.g. it was promoted since we last spoke)
+    pub(crate) fn continue_with(&self, replid: &str) {
+        let mut current = self.replid.write().unwrap();
+        if *current != replid {
+            let old = std::mem::replace(&mut *current, replid.to_string());
+            *self.replid2.write().unwrap() = (old, self.offset() as i64 + 1);
+        }
+    }
+
+    /// The `(replid, offset)` to ask our master to continue from, if we have any history
+    pub(crate) fn resume_point(&self) -> Option<(String, u64)> {
+        match self.offset() {
+            0 => None,
+            offset => Some((self.replid(), offset)),
+        }
+    }
+
+    /// The part of the stream a replica asking for `PSYNC <replid> <psync_offset>` is missing,
+    /// or `None` if it needs a full resync.
+    ///
+    /// Like Redis, `psync_offset` is one past the last byte the replica processed.
+    pub(crate) fn backlog_since(&self, replid: &str, psync_offset: i64) -> Option<Bytes> {
+        if psync_offset < 1 {
+            return None;
+        }
+        let known = replid == *self.replid.read().unwrap() || {
+            let (replid2, second_offset) = &*self.replid2.read().unwrap();
+            replid == replid2 && psync_offset <= *second_offset
+        };
+        if !known {
+            return None;
+        }
+        let _offset = self.offset.lock().unwrap();
+        self.backlog.lock().unwrap().since(psync_offset as u64 - 1)
+    }
+
+    /// Record whether our link to the master is up
+    pub(crate) fn set_master_link_up(&self, up: bool) {
+        self.master_link_up.store(up, Ordering::SeqCst);
+        let mut down_since = self.master_link_down_since.lock().unwrap();
+        if up {
+            *down_since = None;
+            drop(down_since);
+            self.touch_master();
+        } else if down_since.is_none() {
+            *down_since = Some(Instant::now());
+        }
+    }
+
+    /// Whether we have a dataset to serve: always as a master, once linked to and synchronized
+    /// with our master as a replica
+    pub(crate) fn is_synced(&self) -> bool {
+        !self.is_replica()
+            || (self.master_link_up.load(Ordering::SeqCst)
+                && !self.sync_in_progress.load(Ordering::SeqCst))
+    }
+
+    /// Record whether we are loading a full resync from our master
+    pub(crate) fn set_sync_in_progress(&self, syncing: bool) {
+        self.sync_in_progress.store(syncing, Ordering::SeqCst);
+    }
+
+    /// Record whether a dataset is being loaded, during which only commands flagged
+    /// [`LOADING`](crate::command::table::CommandFlags::LOADING) are served
+    pub(crate) fn set_loading(&self, loading: bool) {
+        self.loading.send_replace(loading);
+    }
+
+    pub(crate) fn is_loading(&self) -> bool {
+        *self.loading.borrow()
+    }
+
+    /// Wait until no dataset is being loaded
+    pub(crate) async fn wait_loaded(&self) {
+        // the sender lives as long as we do, so waiting can't fail
+        let _ = self.loading.subscribe().wait_for(|loading| !loading).await;
+    }
+
+    /// Note that we just received data from our master
+    pub(crate) fn touch_master(&self) {
+        *self.master_last_io.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// When we last received data from our master, if ever
+    pub(crate) fn master_last_io(&self) -> Option<Instant> {
+        *self.master_last_io.lock().unwrap()
+    }
+
+    /// Hold this while applying a write command and propagating it
+    pub(crate) async fn write_lock(&self) -> MutexGuard<'_, ()> {
+        self.write_lock.lock().await
+    }
+
+    /// Start receiving the propagated command stream
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<Bytes> {
+        self.propagate_tx.subscribe()
+    }
+
+    /// Append a command to the replication stream, returning the offset just after it
+    pub(crate) fn propagate(&self, cmd: RedisValue) -> u64 {
+        let mut buf = BytesMut::new();
+        let mut offset = self.offset.lock().unwrap();
+        if let Err(e) = RespFrame::encode_value(cmd, Protocol::Resp2, &mut buf) {
+            tracing::error!("Failed to encode command for propagation: {e:?}");
+            return *offset;
+        }
+        *offset += buf.len() as u64;
+        self.backlog.lock().unwrap().push(&buf);
+        // an error only means there are no replicas listening right now
+        let _ = self.propagate_tx.send(buf.freeze());
+        *offset
+    }
+
+    /// Tell every replica we are still here, even with nothing to propagate
+    pub(crate) async fn ping_replicas(&self) {
+        let _write_guard = self.write_lock().await;
+        self.propagate(RedisValue::command(["PING"]));
+    }
+
+    /// Ask every replica to report its offset
+    pub(crate) async fn request_acks(&self) {
+        let _write_guard = self.write_lock().await;
+        self.propagate(RedisValue::command(["REPLCONF", "GETACK", "*"]));
+    }
+
+    pub(crate) fn register_replica(
+        &self,
+        addr: SocketAddr,
+        listening_port: Option<u16>,
+        announced_ip: Option<String>,
+        offset: u64,
+    ) {
+        tracing::info!(
+            "Replica {addr} (listening on {listening_port:?}) synced at offset {offset}"
+        );
+        self.replicas.insert(
+            addr,
+            ReplicaInfo {
+                listening_port,
+                announced_ip,
+                ack_offset: offset,
+                last_ack: Instant::now(),
+            },
+        );
+    }
+
+    pub(crate) fn remove_replica(&self, addr: &SocketAddr) {
+        self.replicas.remove(addr);
+        self.ack_notify.notify_waiters();
+    }
+
+    pub(crate) fn has_replicas(&self) -> bool {
+        !self.replicas.is_empty()
+    }
+
+    /// Record an offset acknowledged by a replica with REPLCONF ACK
+    pub(crate) fn acknowledge(&self, addr: &SocketAddr, offset: u64) {
+        if let Some(mut replica) = self.replicas.get_mut(addr) {
+            replica.ack_offset = replica.ack_offset.max(offset);
+            replica.last_ack = Instant::now();
+        }
+        self.ack_notify.notify_waiters();
+    }
+
+    /// Replicas as (connection address, address they are reachable at, announced listening port)
+    pub(crate) fn replica_endpoints(&self) -> Vec<(SocketAddr, String, Option<u16>)> {
+        self.replicas
+            .iter()
+            .map(|r| (*r.key(), r.ip(r.key()), r.listening_port))
+            .collect()
+    }
+
+    /// Offset last acknowledged by the replica connected from `addr`
+    pub(crate) fn replica_ack(&self, addr: &SocketAddr) -> Option<u64> {
+        self.replicas.get(addr).map(|r| r.ack_offset)
+    }
+
+    /// Resolves on the next acknowledgement from any replica
+    pub(crate) fn ack_received(&self) -> Notified<'_> {
+        self.ack_notify.notified()
+    }
+
+    /// Number of replicas that have acknowledged at least `offset`
+    pub(crate) fn acked_replicas(&self, offset: u64) -> usize {
+        self.replicas
+            .iter()
+            .filter(|r| r.ack_offset >= offset)
+            .count()
+    }
+
+    /// Wait until at least `count` replicas acknowledged `offset`, or until `timeout` passes
+    /// (`None` waits forever). Returns the number of replicas that acknowledged.
+    pub(crate) async fn wait_for_acks(
+        &self,
+        count: usize,
+        offset: u64,
+        timeout: Option<Duration>,
+    ) -> usize {
+        let acked = self.acked_replicas(offset);
+        if acked >= count {
+            return acked;
+        }
+
+        let deadline = timeout.map(|t| tokio::time::Instant::now() + t);
+        self.request_acks().await;
+        loop {
+            // register interest before checking so an ACK between the two is not missed
+            let notified = self.ack_notify.notified();
+            let acked = self.acked_replicas(offset);
+            if acked >= count {
+                return acked;
+            }
+            tokio::select! {
+                _ = notified => {}
+                _ = async {
+                    match deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline).await,
+                        None => std::future::pending::<()>().await,
+                    }
+                } => return self.acked_replicas(offset),
+            }
+        }
+    }
+}
+
+impl ReplicationState {
+    /// The `# Replication` section of INFO, with the fields replication-aware clients and
+    /// Sentinel parse. `priority` is our `replica-priority`.
+    pub(crate) fn info(&self, priority: u32) -> String {
+        let mut out = String::from("# Replication\r\n");
+        match self.role() {
+            Role::Master => out.push_str("role:master\r\n"),
+            Role::Replica { host, port } => {
+                let link_up = self.master_link_up.load(Ordering::SeqCst);
+                let last_io = self
+                    .master_last_io
+                    .lock()
+                    .unwrap()
+                    .map_or(-1, |t| t.elapsed().as_secs() as i64);
+                let offset = self.offset();
+                let _ = write!(
+                    out,
+                    "role:slave\r\n\
+                     master_host:{host}\r\n\
+                     master_port:{port}\r\n\
+                     master_link_status:{}\r\n\
+                     master_last_io_seconds_ago:{last_io}\r\n\
+                     master_sync_in_progress:{}\r\n",
+                    if link_up { "up" } else { "down" },
+                    self.sync_in_progress.load(Ordering::SeqCst) as u8,
+                );
+                if !link_up {
+                    let down_since = self
+                        .master_link_down_since
+                        .lock()
+                        .unwrap()
+                        .map_or(-1, |t| t.elapsed().as_secs() as i64);
+                    let _ = write!(out, "master_link_down_since_seconds:{down_since}\r\n");
+                }
+                let _ = write!(
+                    out,
+                    "slave_read_repl_offset:{offset}\r\n\
+                     slave_repl_offset:{offset}\r\n\
+                     slave_priority:{priority}\r\n\
+                     slave_read_only:1\r\n\
+                     replica_announced:1\r\n"
+                );
+            }
+        }
+
+        let _ = write!(out, "connected_slaves:{}\r\n", self.replicas.len());
+        for (i, replica) in self.replicas.iter().enumerate() {
+            let _ = write!(
+                out,
+                "slave{i}:ip={},port={},state=online,offset={},lag={}\r\n",
+                replica.ip(replica.key()),
+                replica.listening_port.unwrap_or(0),
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs(),
+            );
+        }
+
+        let (replid2, second_offset) = self.replid2.read().unwrap().clone();
+        let _ = write!(
+            out,
+            "master_failover_state:{}\r\n\
+             master_replid:{}\r\n\
+             master_replid2:{replid2}\r\n\
+             master_repl_offset:{}\r\n\
+             second_repl_offset:{second_offset}\r\n",
+            self.failover_state().as_str(),
+            self.replid(),
+            self.offset(),
+        );
+        let backlog = self.backlog.lock().unwrap();
+        let _ = write!(
+            out,
+            "repl_backlog_active:1\r\n\
+             repl_backlog_size:{}\r\n\
+             repl_backlog_first_byte_offset:{}\r\n\
+             repl_backlog_histlen:{}\r\n",
+            backlog.capacity(),
+            backlog.start() + 1,
+            backlog.len(),
+        );
+
+        out
+    }
+}
+
+impl ReplicationState {
+    /// The reply to ROLE: our role, offset and replicas as a master, or our master and how our
+    /// link to it is doing as a replica
+    pub(crate) fn role_reply(&self) -> RedisValue {
+        let bulk = |s: String| RedisValue::BulkString(s.into());
+        match self.role() {
+            Role::Master => {
+                let replicas = self
+                    .replicas
+                    .iter()
+                    .map(|replica| {
+                        RedisValue::Array(vec![
+                            bulk(replica.ip(replica.key())),
+                            bulk(replica.listening_port.unwrap_or(0).to_string()),
+                            bulk(replica.ack_offset.to_string()),
+                        ])
+                    })
+                    .collect();
+                RedisValue::Array(vec![
+                    bulk("master".into()),
+                    RedisValue::Integer(self.offset() as i64),
+                    RedisValue::Array(replicas),
+                ])
+            }
+            Role::Replica { host, port } => {
+                let state = if self.sync_in_progress.load(Ordering::SeqCst) {
+                    "sync"
+                } else if self.master_link_up.load(Ordering::SeqCst) {
+                    "connected"
+                } else {
+                    "connect"
+                };
+                RedisValue::Array(vec![
+                    bulk("slave".into()),
+                    bulk(host),
+                    RedisValue::Integer(port.into()),
+                    bulk(state.into()),
+                    RedisValue::Integer(self.offset() as i64),
+                ])
+            }
+        }
+    }
+}
+
+/// Periodically ask replicas for their offsets so lag stays visible
+pub(crate) async fn ack_requester(replication: std::sync::Arc<ReplicationState>) {
+    let mut interval = tokio::time::interval(GETACK_INTERVAL);
+    loop {
+        interval.tick().await;
+        // a replica's stream must stay byte-for-byte its master's, so only masters add GETACKs
+        if replication.has_replicas() && !replication.is_replica() {
+            replication.request_acks().await;
+        }
+    }
+}
+
+/// PING replicas every `period`, like Redis' `repl-ping-replica-period`, so they can tell a
+/// master with nothing to send from one that is gone
+pub(crate) async fn replica_pinger(
+    replication: std::sync::Arc<ReplicationState>,
+    period: Duration,
+) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        // the same holds as for GETACKs, a replica relays its master's PINGs instead
+        if replication.has_replicas() && !replication.is_replica() {
+            replication.ping_replicas().await;
+        }
+    }
+}
+
+/// Generate a random 40 character hex ID, used for replication IDs and cluster node IDs
+pub(crate) fn generate_id() -> String {
+    let mut id = String::with_capacity(REPLID_LEN);
+    while id.len() < REPLID_LEN {
+        // every RandomState is seeded with fresh randomness
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+        );
+        id.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    id.truncate(REPLID_LEN);
+    id
+}
@@ -0,0 +1,95 @@
+use std::collections::VecDeque;
+
+use bytes::Bytes;
+
+/// How much of the most recent replication stream we keep for partial resyncs
+pub(crate) const BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// Circular buffer holding the tail of the replication stream.
+///
+/// A replica that reconnects asking for an offset still covered here can be sent just the bytes
+/// it missed instead of a whole new RDB.
+pub(crate) struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+
+    /// Replication offset just after the last byte in the buffer
+    end: u64,
+}
+
+impl Backlog {
+    pub(crate) fn new(capacity: usize, offset: u64) -> Self {
+        Self {
+            buf: VecDeque::with_capacity(capacity),
+            capacity,
+            end: offset,
+        }
+    }
+
+    /// Append stream bytes, discarding the oldest ones once full
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        self.end += data.len() as u64;
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buf.len() + data.len()).saturating_sub(self.capacity);
+        self.buf.drain(..overflow);
+        self.buf.extend(data);
+    }
+
+    /// Forget the history, e.g. after a full resync moved us to a new offset
+    pub(crate) fn reset(&mut self, offset: u64) {
+        self.buf.clear();
+        self.end = offset;
+    }
+
+    /// Offset of the first byte still held
+    pub(crate) fn start(&self) -> u64 {
+        self.end - self.buf.len() as u64
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Everything from `offset` to the end of the stream, or `None` if it is no longer held
+    pub(crate) fn since(&self, offset: u64) -> Option<Bytes> {
+        if offset < self.start() || offset > self.end {
+            return None;
+        }
+        let skip = (offset - self.start()) as usize;
+        Some(self.buf.range(skip..).copied().collect::<Vec<u8>>().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_most_recent_bytes() {
+        let mut backlog = Backlog::new(8, 100);
+        backlog.push(b"hello");
+        assert_eq!(backlog.start(), 100);
+        assert_eq!(backlog.since(102), Some(Bytes::from_static(b"llo")));
+        assert_eq!(backlog.since(105), Some(Bytes::new()));
+        assert_eq!(backlog.since(106), None);
+
+        backlog.push(b"world");
+        assert_eq!(backlog.len(), 8);
+        assert_eq!(backlog.start(), 102);
+        assert_eq!(backlog.since(101), None);
+        assert_eq!(backlog.since(102), Some(Bytes::from_static(b"lloworld")));
+
+        // larger than the whole buffer
+        backlog.push(b"0123456789");
+        assert_eq!(backlog.start(), 112);
+        assert_eq!(backlog.since(112), Some(Bytes::from_static(b"23456789")));
+
+        backlog.reset(500);
+        assert_eq!(backlog.since(500), Some(Bytes::new()));
+        assert_eq!(backlog.since(120), None);
+    }
+}
@@ -0,0 +1,143 @@
+//! Diskless full resyncs: a snapshot is encoded straight into the links of the replicas that
+//! asked for it, with no RDB file in between. Replicas asking within `repl-diskless-sync-delay`
+//! of the first share its snapshot, encoded once and fanned out to all of them.
+
+use std::{
+    ops::ControlFlow,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use tokio::{
+    sync::{broadcast, mpsc, oneshot, Notify},
+    time::Instant,
+};
+
+use crate::{
+    rdb,
+    replication::{master, ReplicationState},
+    server::{storage::Storage, tasks},
+};
+
+/// RDB bytes encoded before they are handed to the replicas
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks queued for each replica. The encoder keeps pace with the slowest one.
+const CHUNKS_QUEUED: usize = 16;
+
+/// Replicas waiting for the next diskless sync to start
+#[derive(Default)]
+pub(crate) struct Pending {
+    /// Set while a sync is waiting for more replicas, holding how to reach those that joined it
+    waiting: StdMutex<Option<Vec<oneshot::Sender<Transfer>>>>,
+    /// Woken whenever a replica joins
+    joined: Notify,
+}
+
+/// A replica's part of a diskless sync
+pub(crate) struct Transfer {
+    /// Offset of the snapshot in the replication stream
+    pub(crate) offset: u64,
+    /// The stream from `offset` on
+    pub(crate) propagated: broadcast::Receiver<Bytes>,
+    /// The RDB as it is encoded, `None` once it is complete. Closed early if encoding failed.
+    pub(crate) chunks: mpsc::Receiver<Option<Bytes>>,
+}
+
+/// How a sync is run, from the config at the time the first replica asked for it
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Options {
+    pub(crate) delay: Duration,
+    pub(crate) max_replicas: usize,
+    pub(crate) compress: bool,
+}
+
+/// Wait for the snapshot a replica is to be sent, starting a sync if none is waiting for
+/// replicas yet
+pub(crate) async fn join(
+    replication: Arc<ReplicationState>,
+    db: Arc<dyn Storage>,
+    options: Options,
+) -> Result<Transfer> {
+    let (tx, rx) = oneshot::channel();
+    let first = {
+        let mut waiting = replication.diskless.waiting.lock().unwrap();
+        let first = waiting.is_none();
+        waiting.get_or_insert_default().push(tx);
+        first
+    };
+    replication.diskless.joined.notify_one();
+    if first {
+        tasks::spawn("diskless-sync", None, run(replication, db, options));
+    }
+    rx.await
+        .map_err(|_| anyhow!("Diskless sync ended before it started"))
+}
+
+/// Wait out the delay for more replicas, then snapshot the dataset once for all of them and
+/// stream it to each as it is encoded
+async fn run(replication: Arc<ReplicationState>, db: Arc<dyn Storage>, options: Options) {
+    let pending = &replication.diskless;
+    let deadline = Instant::now() + options.delay;
+    loop {
+        let joined = pending.waiting.lock().unwrap().as_ref().map_or(0, Vec::len);
+        if options.max_replicas > 0 && joined >= options.max_replicas {
+            break;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => break,
+            _ = pending.joined.notified() => {}
+        }
+    }
+
+    // the snapshot and the stream each replica is sent from must line up exactly
+    let (write_guard, snapshot) = master::lock_with_snapshot(&replication, &*db).await;
+    // replicas asking from now on wait for the next sync
+    let replicas = pending.waiting.lock().unwrap().take().unwrap_or_default();
+    let offset = replication.offset();
+    let mut links = Vec::with_capacity(replicas.len());
+    for replica in replicas {
+        let (tx, chunks) = mpsc::channel(CHUNKS_QUEUED);
+        let transfer = Transfer {
+            offset,
+            propagated: replication.subscribe(),
+            chunks,
+        };
+        // a replica that went away meanwhile is skipped
+        if replica.send(transfer).is_ok() {
+            links.push(tx);
+        }
+    }
+    drop(write_guard);
+    if links.is_empty() {
+        return;
+    }
+
+    tracing::info!(
+        "Starting diskless sync of {} replicas at offset {offset}",
+        links.len()
+    );
+    let encoded = tasks::offload("rdb-encode", move || {
+        let entries = snapshot.entries();
+        // stop copy-on-write tracking as soon as we have our view
+        drop(snapshot);
+        rdb::encode_chunks(&entries, options.compress, CHUNK_SIZE, |chunk| {
+            links.retain(|link| link.blocking_send(Some(chunk.clone())).is_ok());
+            // nobody is left to encode for
+            if links.is_empty() {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        for link in links {
+            let _ = link.blocking_send(None);
+        }
+    })
+    .await;
+    if let Err(e) = encoded {
+        tracing::error!("Diskless sync failed: {e}");
+    }
+}
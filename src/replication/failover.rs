@@ -0,0 +1,158 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use tokio::{net::TcpStream, sync::mpsc::UnboundedSender};
+use tokio_util::codec::Framed;
+
+use crate::{
+    command::registry::CommandRegistry,
+    replication::{replica, FailoverState, ReplicationState},
+    resp::{codec::RespFrame, RedisValue},
+    server::{config::Config, propagation::Propagator, storage::Storage, types::ExpiryEvent},
+};
+
+/// Time the target has to accept our connection, as every writer waits on the failover meanwhile
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The replica a FAILOVER hands the master role to
+pub(crate) struct FailoverTarget {
+    /// Address of the replica's replication connection to us
+    pub(crate) conn: SocketAddr,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+}
+
+/// Coordinated failover: pause writes, wait for `target` to catch up, promote it and become its
+/// replica.
+///
+/// Without `force`, a target that does not catch up within `timeout` aborts the failover and
+/// writes resume on this master.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn run(
+    target: FailoverTarget,
+    timeout: Option<Duration>,
+    force: bool,
+    db: Arc<dyn Storage>,
+    commands: Arc<CommandRegistry>,
+    config: Arc<Config>,
+    replication: Arc<ReplicationState>,
+    expiration_tx: UnboundedSender<ExpiryEvent>,
+    propagator: Arc<Propagator>,
+) {
+    // holding the write lock pauses every writer until we are done
+    let write_guard = replication.write_lock().await;
+
+    // the GETACK follows the offset we need, so the replica reports as soon as it reaches it
+    let target_offset = replication.offset();
+    replication.propagate(RedisValue::command(["REPLCONF", "GETACK", "*"]));
+
+    let deadline = timeout.map(|t| tokio::time::Instant::now() + t);
+    let caught_up = loop {
+        let acked = replication.ack_received();
+        let aborted = replication.failover_aborted();
+        match replication.replica_ack(&target.conn) {
+            Some(offset) if offset >= target_offset => break true,
+            Some(_) => {}
+            None => {
+                tracing::warn!("Failover target {} disconnected", target.conn);
+                break false;
+            }
+        }
+        tokio::select! {
+            _ = acked => {}
+            _ = aborted => {
+                tracing::info!("Failover aborted");
+                replication.set_failover_state(FailoverState::NoFailover);
+                return;
+            }
+            _ = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending::<()>().await,
+                }
+            } => break false,
+        }
+    };
+
+    if !caught_up && !force {
+        tracing::warn!("Failover target did not catch up, aborting failover");
+        replication.set_failover_state(FailoverState::NoFailover);
+        return;
+    }
+
+    replication.set_failover_state(FailoverState::FailoverInProgress);
+    if let Err(e) = promote_target(&target).await {
+        tracing::error!("Failed to promote failover target: {e:?}");
+        replication.set_failover_state(FailoverState::NoFailover);
+        return;
+    }
+
+    replication.demote(target.host.clone(), target.port);
+    // writers waiting on the lock now see that we are a replica and are rejected
+    drop(write_guard);
+    replica::start(
+        target.host,
+        target.port,
+        db,
+        commands,
+        config,
+        replication.clone(),
+        expiration_tx,
+        propagator,
+    );
+    replication.set_failover_state(FailoverState::NoFailover);
+}
+
+/// Tell the target replica to become a master
+async fn promote_target(target: &FailoverTarget) -> Result<()> {
+    let connect = TcpStream::connect((target.host.as_str(), target.port));
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!("Timed out connecting to {}:{}", target.host, target.port)
+        })??;
+    let mut frame = Framed::new(stream, RespFrame::default());
+    frame
+        .send(RedisValue::command(["REPLICAOF", "NO", "ONE"]))
+        .await?;
+    match frame.next().await {
+        Some(Ok(RedisValue::SimpleString(s))) if &s[..] == b"OK" => Ok(()),
+        other => Err(anyhow::anyhow!("Unexpected reply to REPLICAOF: {other:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpSocket;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn promoting_gives_up_on_a_target_that_never_accepts() {
+        // a listener that never accepts takes connections only until its backlog is full
+        let socket = TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let listener = socket.listen(1).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut backlog = Vec::new();
+        loop {
+            let connect = TcpStream::connect(addr);
+            match tokio::time::timeout(Duration::from_millis(100), connect).await {
+                Ok(stream) => backlog.push(stream.unwrap()),
+                Err(_) => break,
+            }
+        }
+
+        let target = FailoverTarget {
+            conn: addr,
+            host: addr.ip().to_string(),
+            port: addr.port(),
+        };
+        let promoted = tokio::time::timeout(CONNECT_TIMEOUT * 2, promote_target(&target)).await;
+        let e = promoted
+            .expect("the connection attempt should time out")
+            .unwrap_err();
+        assert!(e.to_string().starts_with("Timed out connecting"), "{e}");
+    }
+}
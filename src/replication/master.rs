@@ -0,0 +1,174 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use futures::StreamExt;
+use tokio::sync::{broadcast, mpsc, MutexGuard};
+
+use crate::{
+    connection::{writer::Outgoing, Reader},
+    replication::{generate_id, ReplicationState},
+    resp::RedisValue,
+    server::{
+        config::OutputBufferLimit,
+        storage::{Snapshot, Storage},
+    },
+};
+
+/// What a replica is sent before the live command stream
+pub(crate) enum Resync {
+    /// A whole RDB of the dataset
+    Full(Bytes),
+    /// An RDB streamed as it is encoded, see [`diskless`](super::diskless)
+    Streamed(mpsc::Receiver<Option<Bytes>>),
+    /// The part of the stream the replica missed, taken from the backlog
+    Partial(Bytes),
+}
+
+/// The replication stream a replica is sent, as subscribed to at master offset `subscribed_at`
+pub(crate) struct Propagated {
+    pub(crate) commands: broadcast::Receiver<Bytes>,
+    pub(crate) subscribed_at: u64,
+}
+
+/// Serve a connection that has been promoted to a replica link.
+///
+/// Sends the resync payload and then every propagated write command until either side hangs up,
+/// or the replica falls further behind than `limit` allows.
+pub(crate) async fn serve_replica(
+    reader: &mut Reader,
+    outgoing: &mpsc::Sender<Outgoing>,
+    replica_addr: SocketAddr,
+    replication: &ReplicationState,
+    resync: Resync,
+    propagated: Propagated,
+    limit: OutputBufferLimit,
+) -> Result<()> {
+    let Propagated {
+        commands: mut propagated,
+        subscribed_at,
+    } = propagated;
+    let mut sent = subscribed_at;
+    let mut over_soft_since = None;
+    let mut role_changes = replication.role_changes();
+
+    match resync {
+        Resync::Full(rdb) => {
+            // the RDB is sent like a bulk string, but without the trailing CRLF
+            let header = format!("${}\r\n", rdb.len());
+            outgoing.send(Outgoing::Raw(header.into())).await?;
+            let len = rdb.len();
+            outgoing.send(Outgoing::Raw(rdb)).await?;
+            tracing::info!("Sent {len} byte RDB to replica {replica_addr}");
+        }
+        Resync::Streamed(mut chunks) => {
+            // the length is not known up front, so the payload ends with a random mark instead
+            let mark = generate_id();
+            let header = format!("$EOF:{mark}\r\n");
+            outgoing.send(Outgoing::Raw(header.into())).await?;
+            let mut len = 0;
+            loop {
+                match chunks.recv().await {
+                    Some(Some(chunk)) => {
+                        len += chunk.len();
+                        outgoing.send(Outgoing::Raw(chunk)).await?;
+                    }
+                    Some(None) => break,
+                    None => {
+                        return Err(anyhow::anyhow!(
+                            "RDB encoding for replica {replica_addr} stopped"
+                        ));
+                    }
+                }
+            }
+            outgoing.send(Outgoing::Raw(mark.into())).await?;
+            tracing::info!("Streamed {len} byte RDB to replica {replica_addr}");
+        }
+        Resync::Partial(missed) => {
+            let len = missed.len();
+            outgoing.send(Outgoing::Raw(missed)).await?;
+            tracing::info!("Sent {len} backlog bytes to replica {replica_addr}");
+        }
+    }
+    outgoing.send(Outgoing::Flush).await?;
+
+    loop {
+        tokio::select! {
+            cmd = propagated.recv() => match cmd {
+                Ok(cmd) => {
+                    sent += cmd.len() as u64;
+                    outgoing.send(Outgoing::Raw(cmd)).await?;
+                    // write the commands out once caught up with the stream
+                    if propagated.is_empty() {
+                        outgoing.send(Outgoing::Flush).await?;
+                    }
+                    // what was propagated but not handed over yet is this replica's output buffer
+                    let pending = replication.offset().saturating_sub(sent);
+                    if limit.exceeded(pending, &mut over_soft_since, Instant::now()) {
+                        return Err(anyhow::anyhow!(
+                            "Replica {replica_addr} is {pending} bytes behind, over its output \
+                             buffer limit"
+                        ));
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    return Err(anyhow::anyhow!(
+                        "Replica {replica_addr} fell {missed} commands behind"
+                    ));
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+            incoming = reader.next() => match incoming {
+                Some(Ok(msg)) => match parse_ack(&msg) {
+                    Some(offset) => replication.acknowledge(&replica_addr, offset),
+                    None => tracing::debug!("Replica {replica_addr} sent {msg:?}"),
+                },
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+            _ = role_changes.changed() => {
+                tracing::info!("Disconnecting replica {replica_addr} after changing masters");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Extract the offset from a `REPLCONF ACK <offset>` message
+fn parse_ack(msg: &RedisValue) -> Option<u64> {
+    let RedisValue::Array(args) = msg else {
+        return None;
+    };
+    let [RedisValue::BulkString(cmd), RedisValue::BulkString(sub), RedisValue::BulkString(offset)] =
+        &args[..]
+    else {
+        return None;
+    };
+    if !cmd.eq_ignore_ascii_case(b"REPLCONF") || !sub.eq_ignore_ascii_case(b"ACK") {
+        return None;
+    }
+    std::str::from_utf8(offset).ok()?.parse().ok()
+}
+
+/// How long to wait between attempts when another snapshot (e.g. BGSAVE) is in progress
+pub(crate) const SNAPSHOT_RETRY: Duration = Duration::from_millis(10);
+
+/// Take the write lock along with a snapshot of `db`, so the snapshot lines up with the
+/// replication offset. While another snapshot (e.g. BGSAVE) holds the dataset the lock is let
+/// go between attempts, so writes carry on until it finishes.
+pub(crate) async fn lock_with_snapshot<'a>(
+    replication: &'a ReplicationState,
+    db: &dyn Storage,
+) -> (MutexGuard<'a, ()>, Box<dyn Snapshot>) {
+    loop {
+        let write_guard = replication.write_lock().await;
+        if let Some(snapshot) = db.snapshot() {
+            return (write_guard, snapshot);
+        }
+        drop(write_guard);
+        tokio::time::sleep(SNAPSHOT_RETRY).await;
+    }
+}
@@ -0,0 +1,348 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::{io::AsyncReadExt, net::TcpStream, sync::mpsc::UnboundedSender};
+use tokio_util::codec::Framed;
+
+use crate::{
+    command::registry::CommandRegistry,
+    connection::{ClientStream, RedisConnection},
+    replication::ReplicationState,
+    resp::{client::Client, codec::RespFrame, RedisValue},
+    server::{
+        config::Config, persistence, propagation::Propagator, storage::Storage, tasks,
+        types::ExpiryEvent,
+    },
+};
+
+/// First delay before reconnecting to a master, doubled after every failed attempt
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+
+/// Longest we wait between reconnection attempts
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Length of the mark ending a streamed RDB, generated like a replication ID
+const EOF_MARK_LEN: usize = super::REPLID_LEN;
+
+/// Longest RDB we take from a master, as all of it is buffered before it is loaded
+const MAX_RDB_LEN: usize = 1 << 36;
+
+/// Start replicating from `host:port` in the background, replacing any existing master link
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn start(
+    host: String,
+    port: u16,
+    db: Arc<dyn Storage>,
+    commands: Arc<CommandRegistry>,
+    config: Arc<Config>,
+    replication: Arc<ReplicationState>,
+    expiration_tx: UnboundedSender<ExpiryEvent>,
+    propagator: Arc<Propagator>,
+) {
+    let task = tasks::spawn(
+        "replica-link",
+        None,
+        run(
+            host,
+            port,
+            db,
+            commands,
+            config,
+            replication.clone(),
+            expiration_tx,
+            propagator,
+        ),
+    );
+    replication.set_link_task(task.abort_handle());
+}
+
+/// Keep a link to our master up: synchronize, apply its command stream, and reconnect with
+/// exponential backoff whenever the link drops. Runs until aborted by a role change.
+#[allow(clippy::too_many_arguments)]
+async fn run(
+    host: String,
+    port: u16,
+    db: Arc<dyn Storage>,
+    commands: Arc<CommandRegistry>,
+    config: Arc<Config>,
+    replication: Arc<ReplicationState>,
+    expiration_tx: UnboundedSender<ExpiryEvent>,
+    propagator: Arc<Propagator>,
+) {
+    let mut backoff = RECONNECT_BACKOFF_MIN;
+    loop {
+        let result = sync(
+            host.clone(),
+            port,
+            db.clone(),
+            commands.clone(),
+            config.clone(),
+            replication.clone(),
+            expiration_tx.clone(),
+            propagator.clone(),
+        )
+        .await;
+        replication.set_master_link_up(false);
+        replication.set_sync_in_progress(false);
+        match result {
+            Ok(()) => {
+                tracing::warn!("Lost connection to master {host}:{port}");
+                backoff = RECONNECT_BACKOFF_MIN;
+            }
+            Err(e) => tracing::error!("Replication link failed: {e:?}"),
+        }
+
+        tracing::info!("Reconnecting to master in {backoff:?}");
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+}
+
+/// Perform the handshake and then serve the master link until it closes.
+///
+/// Returns `Ok` only if the link was fully established before it closed.
+#[allow(clippy::too_many_arguments)]
+async fn sync(
+    host: String,
+    port: u16,
+    db: Arc<dyn Storage>,
+    commands: Arc<CommandRegistry>,
+    config: Arc<Config>,
+    replication: Arc<ReplicationState>,
+    expiration_tx: UnboundedSender<ExpiryEvent>,
+    propagator: Arc<Propagator>,
+) -> Result<()> {
+    // every step waits on the master for no longer than it may stay silent
+    let timeout = config.repl_timeout;
+    let stream = within(timeout, TcpStream::connect((host.as_str(), port))).await?;
+    let master_addr = stream.peer_addr()?;
+    tracing::info!("Connected to master at {master_addr}");
+    let mut master = Client::new(Box::new(stream) as ClientStream);
+
+    within(timeout, expect(&mut master, ["PING"], "PONG")).await?;
+    let listening_port = match config.replica_announce_port {
+        0 => config.port,
+        port => port,
+    };
+    let listening_port = listening_port.to_string();
+    let port_conf = ["REPLCONF", "listening-port", listening_port.as_str()];
+    within(timeout, expect(&mut master, port_conf, "OK")).await?;
+    if let Some(ip) = &config.replica_announce_ip {
+        let ip_conf = ["REPLCONF", "ip-address", ip.as_str()];
+        within(timeout, expect(&mut master, ip_conf, "OK")).await?;
+    }
+    let capa_conf = ["REPLCONF", "capa", "eof", "capa", "psync2"];
+    within(timeout, expect(&mut master, capa_conf, "OK")).await?;
+
+    // ask to continue where we left off, the master decides whether it still can
+    let reply = match replication.resume_point() {
+        Some((replid, offset)) => {
+            let offset = (offset + 1).to_string();
+            let psync = master.request(["PSYNC", replid.as_str(), offset.as_str()]);
+            within(timeout, psync).await?
+        }
+        None => within(timeout, master.request(["PSYNC", "?", "-1"])).await?,
+    };
+    let RedisValue::SimpleString(reply) = reply else {
+        return Err(anyhow::anyhow!("Unexpected PSYNC reply: {reply:?}"));
+    };
+    let reply = String::from_utf8_lossy(&reply).to_string();
+    let mut parts = reply.split_whitespace();
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("FULLRESYNC"), Some(replid), Some(offset)) => {
+            tracing::info!("Full resync with master {replid} at offset {offset}");
+            replication.set_sync_in_progress(true);
+            replication.set_master(replid, offset.parse()?);
+
+            let rdb = read_rdb(master.framed_mut(), timeout).await?;
+            tracing::info!("Received {} byte RDB from master", rdb.len());
+            replication.set_loading(true);
+            db.flush(config.replica_lazy_flush);
+            let loaded = persistence::load(&*db, &expiration_tx, &rdb).await;
+            replication.set_loading(false);
+            loaded?;
+            replication.set_sync_in_progress(false);
+            // what the append-only file logged so far is of the dataset just replaced
+            if let Some(aof) = propagator.aof() {
+                aof.rewrite(&*db, &replication).await?;
+            }
+        }
+        (Some("CONTINUE"), replid, None) => {
+            if let Some(replid) = replid {
+                replication.continue_with(replid);
+            }
+            tracing::info!(
+                "Partial resync with master from offset {}",
+                replication.offset()
+            );
+        }
+        _ => return Err(anyhow::anyhow!("Unexpected PSYNC reply: {reply}")),
+    }
+    replication.set_master_link_up(true);
+
+    let link = RedisConnection::master_link(
+        master.into_framed(),
+        master_addr,
+        db,
+        commands,
+        config,
+        replication.clone(),
+        expiration_tx,
+        propagator,
+    );
+    let hang_up = link.shutdown_token();
+    let serving = link.client_loop();
+    tokio::pin!(serving);
+    tokio::select! {
+        _ = &mut serving => Ok(()),
+        _ = silent_for(&replication, timeout) => {
+            // a master that stopped even pinging us is as good as gone
+            hang_up.cancel();
+            serving.await;
+            Err(anyhow::anyhow!("Master sent nothing for {timeout:?}, dropping the link"))
+        }
+    }
+}
+
+/// Wait until our master has sent nothing for `timeout`
+async fn silent_for(replication: &ReplicationState, timeout: Duration) {
+    loop {
+        let last = replication.master_last_io().unwrap_or_else(Instant::now);
+        if last.elapsed() >= timeout {
+            return;
+        }
+        tokio::time::sleep_until((last + timeout).into()).await;
+    }
+}
+
+/// Run a `step` of the handshake with our master, failing if the master takes over `timeout`
+async fn within<T, E>(timeout: Duration, step: impl Future<Output = Result<T, E>>) -> Result<T>
+where
+    anyhow::Error: From<E>,
+{
+    match tokio::time::timeout(timeout, step).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(anyhow::anyhow!("Master sent nothing for {timeout:?}")),
+    }
+}
+
+/// Send a handshake command and check the master answered with the expected simple string
+async fn expect<const N: usize>(
+    master: &mut Client<ClientStream>,
+    args: [&str; N],
+    expected: &str,
+) -> Result<()> {
+    match master.request(args).await? {
+        RedisValue::SimpleString(s) if s == expected.as_bytes() => Ok(()),
+        other => Err(anyhow::anyhow!(
+            "Unexpected reply to {}: {other:?}",
+            args[0]
+        )),
+    }
+}
+
+/// Read the RDB transfer of a full resync, which unlike a bulk string has no trailing CRLF
+async fn read_rdb(frame: &mut Framed<ClientStream, RespFrame>, timeout: Duration) -> Result<Bytes> {
+    let mut scanned = 0;
+    loop {
+        if let Some(rdb) = take_rdb(frame.read_buffer_mut(), &mut scanned)? {
+            return Ok(rdb);
+        }
+
+        let mut chunk = BytesMut::with_capacity(4096);
+        if within(timeout, frame.get_mut().read_buf(&mut chunk)).await? == 0 {
+            return Err(anyhow::anyhow!(
+                "Master closed the connection during RDB transfer"
+            ));
+        }
+        frame.read_buffer_mut().extend_from_slice(&chunk);
+    }
+}
+
+/// Take the RDB off the front of `buf` once all of it arrived: either `$<len>\r\n<payload>`,
+/// or `$EOF:<mark>\r\n<payload><mark>` when the master streams it without knowing its length.
+/// `scanned` keeps how much of a streamed payload was already searched for the mark.
+fn take_rdb(buf: &mut BytesMut, scanned: &mut usize) -> Result<Option<Bytes>> {
+    let Some(header_end) = memchr::memchr(b'\n', buf) else {
+        return Ok(None);
+    };
+    if buf.first() != Some(&b'$') || header_end < 2 || buf[header_end - 1] != b'\r' {
+        return Err(anyhow::anyhow!("Invalid RDB transfer header"));
+    }
+    let header = &buf[1..header_end - 1];
+    let start = header_end + 1;
+
+    if let Some(mark) = header.strip_prefix(b"EOF:") {
+        if mark.len() != EOF_MARK_LEN {
+            return Err(anyhow::anyhow!("Invalid RDB transfer end mark"));
+        }
+        let mark = mark.to_vec();
+        // the mark may have been cut in two by the last read
+        let from = start + scanned.saturating_sub(EOF_MARK_LEN);
+        let Some(found) = memchr::memmem::find(&buf[from..], &mark) else {
+            *scanned = buf.len() - start;
+            return Ok(None);
+        };
+        let len = from + found - start;
+        let mut payload = buf.split_to(start + len + EOF_MARK_LEN);
+        payload.advance(start);
+        payload.truncate(len);
+        return Ok(Some(payload.freeze()));
+    }
+
+    let len: usize = std::str::from_utf8(header)?.parse()?;
+    let end = match start.checked_add(len) {
+        Some(end) if len <= MAX_RDB_LEN => end,
+        _ => return Err(anyhow::anyhow!("RDB transfer of {len} bytes is too big")),
+    };
+    if buf.len() < end {
+        return Ok(None);
+    }
+    let mut payload = buf.split_to(end);
+    payload.advance(start);
+    Ok(Some(payload.freeze()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_either_transfer_format() {
+        let mut scanned = 0;
+        let mut buf = BytesMut::from("$5\r\nREDIS*2\r\n");
+        assert_eq!(take_rdb(&mut buf, &mut scanned).unwrap().unwrap(), "REDIS");
+        // what follows is the command stream
+        assert_eq!(&buf[..], b"*2\r\n");
+
+        let mark = "m".repeat(EOF_MARK_LEN);
+        let mut buf = BytesMut::from(format!("$EOF:{mark}\r\nRED").as_str());
+        assert_eq!(take_rdb(&mut buf, &mut scanned).unwrap(), None);
+        // the mark arrives split across reads
+        buf.extend_from_slice(format!("IS{}", &mark[..10]).as_bytes());
+        assert_eq!(take_rdb(&mut buf, &mut scanned).unwrap(), None);
+        buf.extend_from_slice(format!("{}*1\r\n", &mark[10..]).as_bytes());
+        assert_eq!(take_rdb(&mut buf, &mut scanned).unwrap().unwrap(), "REDIS");
+        assert_eq!(&buf[..], b"*1\r\n");
+
+        let mut buf = BytesMut::from("$EOF:short\r\n");
+        assert!(take_rdb(&mut buf, &mut 0).is_err());
+        let mut buf = BytesMut::from("+OK\r\n");
+        assert!(take_rdb(&mut buf, &mut 0).is_err());
+    }
+
+    #[test]
+    fn refuses_huge_lengths() {
+        let mut buf = BytesMut::from(format!("${}\r\nREDIS", usize::MAX).as_str());
+        assert!(take_rdb(&mut buf, &mut 0).is_err());
+        let mut buf = BytesMut::from(format!("${}\r\nREDIS", MAX_RDB_LEN + 1).as_str());
+        assert!(take_rdb(&mut buf, &mut 0).is_err());
+        let mut buf = BytesMut::from(format!("${MAX_RDB_LEN}\r\nREDIS").as_str());
+        assert_eq!(take_rdb(&mut buf, &mut 0).unwrap(), None);
+    }
+}
@@ -1,10 +1,14 @@
-use core::str;
-
 use bytes::Bytes;
 
+use crate::error::RedisError;
+
+pub mod cli;
+pub mod client;
 pub mod codec;
-mod parse;
+pub(crate) mod parse;
+mod shared;
 
+/// A value of either version of the protocol, whether a command, a reply or a push message
 #[derive(Debug, PartialEq, Clone)]
 pub enum RedisValue {
     SimpleString(Bytes),
@@ -14,26 +18,55 @@ pub enum RedisValue {
     BulkString(Bytes),
     NullArray,
     Array(Vec<RedisValue>),
+    /// RESP3 null, sent as a null bulk string to RESP2 clients
+    Null,
+    /// RESP3 boolean, an integer 1 or 0 in RESP2
+    Boolean(bool),
+    /// RESP3 double, a bulk string in RESP2
+    Double(f64),
+    /// RESP3 map, a flat array of keys and values in RESP2
+    Map(Vec<(RedisValue, RedisValue)>),
+    /// RESP3 set, an array in RESP2
+    Set(Vec<RedisValue>),
+    /// RESP3 out-of-band push message, e.g. a pub/sub message, an array in RESP2
+    Push(Vec<RedisValue>),
+    /// RESP3 attributes describing the value that follows, left out entirely in RESP2
+    Attribute {
+        attributes: Vec<(RedisValue, RedisValue)>,
+        value: Box<RedisValue>,
+    },
 }
 
-impl TryFrom<RedisValue> for String {
-    type Error = anyhow::Error;
+impl RedisValue {
+    /// Build a command as a client would send it: an array of bulk strings
+    pub fn command<I, T>(args: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Bytes>,
+    {
+        Self::Array(
+            args.into_iter()
+                .map(|a| Self::BulkString(a.into()))
+                .collect(),
+        )
+    }
 
-    fn try_from(value: RedisValue) -> Result<Self, Self::Error> {
-        match value {
-            RedisValue::BulkString(s) => Ok(str::from_utf8(&s[..])?.to_uppercase()),
-            _ => Err(anyhow::anyhow!("Invalid conversion to string")),
+    /// The bytes of a bulk string, exactly as sent
+    pub fn as_bulk_string(&self) -> Option<&Bytes> {
+        match self {
+            Self::BulkString(b) => Some(b),
+            _ => None,
         }
     }
 }
 
-impl TryFrom<&RedisValue> for String {
+impl TryFrom<RedisValue> for Bytes {
     type Error = anyhow::Error;
 
-    fn try_from(value: &RedisValue) -> Result<Self, Self::Error> {
+    fn try_from(value: RedisValue) -> Result<Self, Self::Error> {
         match value {
-            RedisValue::BulkString(s) => Ok(str::from_utf8(&s[..])?.to_uppercase()),
-            _ => Err(anyhow::anyhow!("Invalid conversion to string")),
+            RedisValue::BulkString(b) => Ok(b),
+            _ => Err(RedisError::Protocol("expected a bulk string".into()).into()),
         }
     }
 }
@@ -42,9 +75,9 @@ impl TryFrom<&RedisValue> for Bytes {
     type Error = anyhow::Error;
 
     fn try_from(value: &RedisValue) -> Result<Self, Self::Error> {
-        match value {
-            RedisValue::BulkString(s) => Ok(s.slice(..)),
-            _ => Err(anyhow::anyhow!("Invalid RedisType, expected BulkString")),
-        }
+        Ok(value
+            .as_bulk_string()
+            .ok_or(RedisError::Protocol("expected a bulk string".into()))?
+            .clone())
     }
 }
@@ -0,0 +1,137 @@
+//! What the bundled `redis-cli` needs from the crate: splitting a typed line into a command and
+//! showing replies, formatted for a person at a terminal or raw for a script reading them.
+
+use std::fmt::Write as _;
+
+use anyhow::Result;
+
+use crate::{resp::RedisValue, server::config::file::split_args};
+
+/// Split a line typed at the prompt into arguments, which may be "double quoted" with C-style
+/// escapes or 'single quoted'
+pub fn split_line(line: &str) -> Result<Vec<String>> {
+    split_args(line)
+}
+
+/// `value` as redis-cli shows it at a terminal: types spelled out, strings quoted and nested
+/// replies numbered and indented
+pub fn pretty(value: &RedisValue) -> String {
+    match value {
+        RedisValue::SimpleString(s) => String::from_utf8_lossy(s).into_owned(),
+        RedisValue::SimpleError(e) => format!("(error) {}", String::from_utf8_lossy(e)),
+        RedisValue::Integer(i) => format!("(integer) {i}"),
+        RedisValue::BulkString(s) => quoted(s),
+        RedisValue::NullBulkString | RedisValue::NullArray | RedisValue::Null => "(nil)".into(),
+        RedisValue::Boolean(b) => format!("({b})"),
+        RedisValue::Double(d) => format!("(double) {d}"),
+        RedisValue::Array(items) | RedisValue::Push(items) => {
+            numbered(items.iter().map(pretty), ')', "(empty array)")
+        }
+        RedisValue::Set(items) => numbered(items.iter().map(pretty), '~', "(empty set)"),
+        RedisValue::Map(pairs) => numbered(
+            pairs
+                .iter()
+                .map(|(k, v)| format!("{} => {}", pretty(k), pretty(v))),
+            '#',
+            "(empty hash)",
+        ),
+        RedisValue::Attribute { value, .. } => pretty(value),
+    }
+}
+
+/// Number `items` a line each, indenting the lines after the first of any that span several
+fn numbered(items: impl ExactSizeIterator<Item = String>, mark: char, empty: &str) -> String {
+    if items.len() == 0 {
+        return empty.to_string();
+    }
+    let width = items.len().to_string().len();
+    let mut out = String::new();
+    for (i, item) in items.enumerate() {
+        let prefix = format!("{:>width$}{mark} ", i + 1);
+        for (j, line) in item.lines().enumerate() {
+            match j {
+                0 => out.push_str(&prefix),
+                _ => out.push_str(&" ".repeat(prefix.len())),
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out.pop();
+    out
+}
+
+/// `s` double quoted, with anything unprintable escaped
+fn quoted(s: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in s {
+        match b {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(b as char);
+            }
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x20..0x7f => out.push(b as char),
+            _ => {
+                let _ = write!(out, "\\x{b:02x}");
+            }
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Append `value` to `out` as redis-cli writes it to a pipe: each scalar on a line of its own,
+/// aggregates flattened and strings as they are
+pub fn raw(value: &RedisValue, out: &mut Vec<u8>) {
+    match value {
+        RedisValue::SimpleString(s) | RedisValue::SimpleError(s) | RedisValue::BulkString(s) => {
+            out.extend_from_slice(s);
+        }
+        RedisValue::Integer(i) => out.extend_from_slice(i.to_string().as_bytes()),
+        RedisValue::NullBulkString | RedisValue::NullArray | RedisValue::Null => {}
+        RedisValue::Boolean(b) => out.extend_from_slice(if *b { b"1" } else { b"0" }),
+        RedisValue::Double(d) => out.extend_from_slice(d.to_string().as_bytes()),
+        RedisValue::Array(items) | RedisValue::Push(items) | RedisValue::Set(items) => {
+            return items.iter().for_each(|item| raw(item, out));
+        }
+        RedisValue::Map(pairs) => {
+            return pairs.iter().for_each(|(k, v)| {
+                raw(k, out);
+                raw(v, out);
+            });
+        }
+        RedisValue::Attribute { value, .. } => return raw(value, out),
+    }
+    out.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shows_replies_like_redis_cli() {
+        let bulk = |s: &'static str| RedisValue::BulkString(s.into());
+        assert_eq!(pretty(&RedisValue::Integer(3)), "(integer) 3");
+        assert_eq!(pretty(&bulk("a\"b\n\x01")), r#""a\"b\n\x01""#);
+        assert_eq!(pretty(&RedisValue::NullBulkString), "(nil)");
+        assert_eq!(pretty(&RedisValue::Array(vec![])), "(empty array)");
+        let nested = RedisValue::Array(vec![
+            bulk("a"),
+            RedisValue::Array(vec![bulk("b"), RedisValue::Integer(1)]),
+        ]);
+        assert_eq!(pretty(&nested), "1) \"a\"\n2) 1) \"b\"\n   2) (integer) 1");
+
+        let mut out = Vec::new();
+        raw(&nested, &mut out);
+        raw(&RedisValue::NullBulkString, &mut out);
+        assert_eq!(out, b"a\nb\n1\n\n");
+        assert_eq!(
+            split_line("set 'a b' \"c\\n\"").unwrap(),
+            ["set", "a b", "c\n"]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+};
+use tokio_util::codec::Framed;
+
+use crate::resp::{codec::RespFrame, RedisValue};
+
+/// A connection to a RESP server, such as this one, for writing clients and proxies:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use codecrafters_redis::resp::{client::Client, RedisValue};
+///
+/// let mut client = Client::connect("127.0.0.1:6379").await?;
+/// client.request(["SET", "greeting", "hello"]).await?;
+/// let reply = client.request(["GET", "greeting"]).await?;
+/// assert_eq!(reply, RedisValue::BulkString("hello".into()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Client<S = TcpStream> {
+    framed: Framed<S, RespFrame>,
+}
+
+impl Client {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Ok(Self::new(TcpStream::connect(addr).await?))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Client<S> {
+    /// Speak RESP over an established stream
+    pub fn new(stream: S) -> Self {
+        Self {
+            framed: Framed::new(stream, RespFrame::default()),
+        }
+    }
+
+    /// Send a command and wait for its reply. Error replies are returned as
+    /// [`RedisValue::SimpleError`], only failing to talk to the server is an error.
+    pub async fn request<I, T>(&mut self, args: I) -> Result<RedisValue>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let args = args
+            .into_iter()
+            .map(|arg| Bytes::copy_from_slice(arg.as_ref()));
+        self.send(RedisValue::command(args)).await?;
+        self.receive()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Server closed the connection"))
+    }
+
+    /// Send any value, e.g. a command to pipeline or one forwarded by a proxy
+    pub async fn send(&mut self, value: RedisValue) -> Result<()> {
+        self.framed.send(value).await
+    }
+
+    /// The next value from the server, `None` once it closed the connection
+    pub async fn receive(&mut self) -> Result<Option<RedisValue>> {
+        self.framed.next().await.transpose()
+    }
+
+    /// The underlying codec and stream, e.g. to read a payload that is not RESP
+    pub fn framed_mut(&mut self) -> &mut Framed<S, RespFrame> {
+        &mut self.framed
+    }
+
+    pub fn into_framed(self) -> Framed<S, RespFrame> {
+        self.framed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_and_replies() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut client = Client::new(client);
+        let serve = async {
+            let mut request = [0; 22];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n");
+            server.write_all(b"$5\r\nvalue\r\n").await.unwrap();
+        };
+        let (reply, ()) = tokio::join!(client.request(["GET", "key"]), serve);
+        assert_eq!(reply.unwrap(), RedisValue::BulkString("value".into()));
+        drop(server);
+        assert!(client.request(["PING"]).await.is_err());
+    }
+}
@@ -1,109 +1,456 @@
-use bytes::{BufMut, BytesMut};
+use bytes::{Bytes, BytesMut};
 use nom::AsBytes;
 use tokio_util::codec::{Decoder, Encoder};
 
-use crate::resp::{parse::parse, RedisValue};
+use crate::{
+    error::RedisError,
+    resp::{
+        parse::{IncrementalParser, ParseContext},
+        shared, RedisValue,
+    },
+};
 
-pub struct RespFrame;
+/// Version of the protocol spoken on a connection, RESP2 until HELLO negotiates otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// Largest bulk string accepted from a peer by default, Redis' 512MB `proto-max-bulk-len`
+pub const DEFAULT_MAX_BULK_LEN: u64 = 512 * 1024 * 1024;
+
+/// Most elements accepted in an aggregate by default, as Redis' `INT_MAX`
+pub const DEFAULT_MAX_MULTIBULK_LEN: u64 = i32::MAX as u64;
+
+/// Deepest nesting of aggregates accepted by default
+pub const DEFAULT_MAX_NESTING: usize = 128;
+
+/// Bounds on what a peer may send us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtoLimits {
+    pub max_bulk_len: u64,
+    pub max_multibulk_len: u64,
+    pub max_nesting: usize,
+}
+
+impl Default for ProtoLimits {
+    fn default() -> Self {
+        Self {
+            max_bulk_len: DEFAULT_MAX_BULK_LEN,
+            max_multibulk_len: DEFAULT_MAX_MULTIBULK_LEN,
+            max_nesting: DEFAULT_MAX_NESTING,
+        }
+    }
+}
+
+/// Bulk strings at least this long get their whole payload reserved as soon as the header is in,
+/// Redis' `PROTO_MBULK_BIG_ARG`
+const BIG_BULK_LEN: usize = 32 * 1024;
+
+/// Where replies are encoded to
+pub(crate) trait ReplyBuf {
+    fn reserve(&mut self, additional: usize);
+
+    fn extend_from_slice(&mut self, bytes: &[u8]);
+
+    /// Append a bulk string's payload, which a buffer may keep a reference to rather than copy
+    fn put_payload(&mut self, payload: Bytes) {
+        self.reserve(payload.len() + 2);
+        self.extend_from_slice(&payload);
+    }
+}
+
+impl ReplyBuf for BytesMut {
+    fn reserve(&mut self, additional: usize) {
+        BytesMut::reserve(self, additional);
+    }
+
+    fn extend_from_slice(&mut self, bytes: &[u8]) {
+        BytesMut::extend_from_slice(self, bytes);
+    }
+}
+
+/// Decodes values sent by a peer and encodes values for it, for use with tokio-util's
+/// `Framed`. Commands and replies are both plain values, so it serves clients and servers alike.
+#[derive(Default)]
+pub struct RespFrame {
+    protocol: Protocol,
+    ctx: ParseContext,
+
+    /// Progress on the frame at the front of the read buffer
+    parser: IncrementalParser,
+}
 
 impl Decoder for RespFrame {
     type Item = RedisValue;
     type Error = anyhow::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
+        // a bulk string's payload is still streaming in, no point parsing the frame again yet
+        if src.is_empty() || src.len() < self.ctx.needed.get() {
             return Ok(None);
         }
 
-        match parse(src, 0).map_err(|e| anyhow::anyhow!("Parsing error: {e:?}"))? {
+        self.ctx.needed.set(0);
+        match self
+            .parser
+            .parse(src, &self.ctx)
+            .map_err(|e| RedisError::Protocol(e.to_string()))?
+        {
             Some((pos, intermediate)) => {
                 let parsed = src.split_to(pos);
                 Ok(Some(intermediate.generate_value(&parsed.freeze())))
             }
-            None => Ok(None),
+            None => {
+                // grow the buffer once for a large payload instead of doubling it as it arrives
+                let missing = self.ctx.needed.get().saturating_sub(src.len());
+                if missing >= BIG_BULK_LEN {
+                    src.reserve(missing);
+                }
+                Ok(None)
+            }
         }
     }
 }
 
 impl RespFrame {
+    /// A codec refusing anything from the peer beyond `limits`
+    pub fn with_limits(limits: ProtoLimits) -> Self {
+        Self {
+            ctx: ParseContext::new(limits),
+            ..Self::default()
+        }
+    }
+
+    /// Also accept inline commands, lines of space separated arguments as typed into telnet or
+    /// sent by redis-benchmark, as Redis does from its clients
+    pub fn with_inline_commands(mut self) -> Self {
+        self.ctx.inline = true;
+        self
+    }
+
+    /// Encode replies for `protocol` from now on
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
     /// Pull out the encoder function as an associated function to be able to recursively call it
-    /// for arrays
-    fn encode_value(item: RedisValue, dst: &mut BytesMut) -> Result<(), anyhow::Error> {
+    /// for arrays. RESP3 types are downgraded to their RESP2 equivalents unless `protocol` is
+    /// RESP3, in which case every null is sent as `_`.
+    pub(crate) fn encode_value(
+        item: RedisValue,
+        protocol: Protocol,
+        dst: &mut impl ReplyBuf,
+    ) -> Result<(), anyhow::Error> {
         const NULL_ARRAY_STRING_LEN: usize = 5;
         const SIMPLE_VALUE_START_LEN: usize = 3;
         const BULK_STRING_START_LEN: usize = 5;
         const ARRAY_START_LEN: usize = 3;
         const CRLF: [u8; 2] = *b"\r\n";
+        if let Some(encoded) = shared::encoded(&item, protocol) {
+            dst.extend_from_slice(encoded);
+            return Ok(());
+        }
+        let is_push = matches!(item, RedisValue::Push(_));
 
         match item {
+            RedisValue::NullArray | RedisValue::NullBulkString | RedisValue::Null
+                if protocol == Protocol::Resp3 =>
+            {
+                dst.extend_from_slice(&b"_\r\n"[..]);
+            }
             RedisValue::NullArray => {
                 dst.reserve(NULL_ARRAY_STRING_LEN);
                 dst.extend_from_slice(&b"*-1\r\n"[..]);
             }
-            RedisValue::NullBulkString => {
+            RedisValue::NullBulkString | RedisValue::Null => {
                 dst.reserve(NULL_ARRAY_STRING_LEN);
                 dst.extend_from_slice(&b"$-1\r\n"[..]);
             }
             RedisValue::SimpleString(s) => {
                 dst.reserve(SIMPLE_VALUE_START_LEN + s.len());
-                dst.put_u8(b'+');
+                dst.extend_from_slice(b"+");
                 dst.extend_from_slice(s.as_bytes());
                 dst.extend_from_slice(&CRLF[..]);
             }
             RedisValue::SimpleError(e) => {
                 dst.reserve(SIMPLE_VALUE_START_LEN + e.len());
-                dst.put_u8(b'-');
+                dst.extend_from_slice(b"-");
                 dst.extend_from_slice(e.as_bytes());
                 dst.extend_from_slice(&CRLF[..]);
             }
             RedisValue::Integer(i) => {
                 let i_str = i.to_string();
                 dst.reserve(SIMPLE_VALUE_START_LEN + i_str.len());
-                dst.put_u8(b':');
+                dst.extend_from_slice(b":");
                 dst.extend_from_slice(i_str.as_bytes());
                 dst.extend_from_slice(&CRLF[..]);
             }
             RedisValue::BulkString(s) => {
                 let len_str = s.len().to_string();
-                dst.reserve(BULK_STRING_START_LEN + len_str.len() + s.len());
-                dst.put_u8(b'$');
+                dst.reserve(BULK_STRING_START_LEN + len_str.len());
+                dst.extend_from_slice(b"$");
                 dst.extend_from_slice(len_str.as_bytes());
                 dst.extend_from_slice(&CRLF[..]);
-                dst.extend_from_slice(s.as_bytes());
+                dst.put_payload(s);
                 dst.extend_from_slice(&CRLF[..]);
             }
             RedisValue::Array(v) => {
                 let len_str = v.len().to_string();
                 dst.reserve(ARRAY_START_LEN + len_str.len());
-                dst.put_u8(b'*');
+                dst.extend_from_slice(b"*");
                 dst.extend_from_slice(len_str.as_bytes());
                 dst.extend_from_slice(&CRLF[..]);
                 for element in v {
-                    RespFrame::encode_value(element, dst)?;
+                    RespFrame::encode_value(element, protocol, dst)?;
+                }
+            }
+            RedisValue::Boolean(b) => match protocol {
+                Protocol::Resp3 => dst.extend_from_slice(if b { b"#t\r\n" } else { b"#f\r\n" }),
+                Protocol::Resp2 => {
+                    RespFrame::encode_value(RedisValue::Integer(b as i64), protocol, dst)?
+                }
+            },
+            RedisValue::Double(d) => {
+                let repr = if d.is_infinite() {
+                    if d > 0.0 { "inf" } else { "-inf" }.to_string()
+                } else {
+                    d.to_string()
+                };
+                match protocol {
+                    Protocol::Resp3 => {
+                        dst.reserve(SIMPLE_VALUE_START_LEN + repr.len());
+                        dst.extend_from_slice(b",");
+                        dst.extend_from_slice(repr.as_bytes());
+                        dst.extend_from_slice(&CRLF[..]);
+                    }
+                    Protocol::Resp2 => {
+                        RespFrame::encode_value(RedisValue::BulkString(repr.into()), protocol, dst)?
+                    }
+                }
+            }
+            RedisValue::Map(entries) => {
+                let (prefix, len) = match protocol {
+                    Protocol::Resp3 => (b'%', entries.len()),
+                    Protocol::Resp2 => (b'*', entries.len() * 2),
+                };
+                Self::encode_aggregate_header(prefix, len, dst);
+                for (key, value) in entries {
+                    RespFrame::encode_value(key, protocol, dst)?;
+                    RespFrame::encode_value(value, protocol, dst)?;
+                }
+            }
+            RedisValue::Set(v) | RedisValue::Push(v) => {
+                let prefix = match (protocol, is_push) {
+                    (Protocol::Resp3, true) => b'>',
+                    (Protocol::Resp3, false) => b'~',
+                    (Protocol::Resp2, _) => b'*',
+                };
+                Self::encode_aggregate_header(prefix, v.len(), dst);
+                for element in v {
+                    RespFrame::encode_value(element, protocol, dst)?;
+                }
+            }
+            RedisValue::Attribute { attributes, value } => {
+                if protocol == Protocol::Resp3 {
+                    Self::encode_aggregate_header(b'|', attributes.len(), dst);
+                    for (key, value) in attributes {
+                        RespFrame::encode_value(key, protocol, dst)?;
+                        RespFrame::encode_value(value, protocol, dst)?;
+                    }
                 }
+                RespFrame::encode_value(*value, protocol, dst)?;
             }
         }
         Ok(())
     }
 }
 
+impl RespFrame {
+    fn encode_aggregate_header(prefix: u8, len: usize, dst: &mut impl ReplyBuf) {
+        let len_str = len.to_string();
+        dst.reserve(3 + len_str.len());
+        dst.extend_from_slice(&[prefix]);
+        dst.extend_from_slice(len_str.as_bytes());
+        dst.extend_from_slice(&b"\r\n"[..]);
+    }
+}
+
+/// What is left to encode of an aggregate
+enum Elements {
+    Values(std::vec::IntoIter<RedisValue>),
+    /// Keys and values, with the value of the last key taken out
+    Pairs(
+        std::vec::IntoIter<(RedisValue, RedisValue)>,
+        Option<RedisValue>,
+    ),
+}
+
+impl Elements {
+    fn next(&mut self) -> Option<RedisValue> {
+        match self {
+            Self::Values(values) => values.next(),
+            Self::Pairs(pairs, value) => value.take().or_else(|| {
+                let (key, next) = pairs.next()?;
+                *value = Some(next);
+                Some(key)
+            }),
+        }
+    }
+}
+
+/// Encodes a reply a piece at a time, an aggregate's header or one of its scalars, so that a
+/// huge reply can be written out as it is encoded rather than held encoded whole. What it
+/// encodes is exactly what [`RespFrame::encode_value`] would.
+pub(crate) struct ReplyEncoder {
+    protocol: Protocol,
+    next: Option<RedisValue>,
+    /// Aggregates being encoded, innermost last
+    open: Vec<Elements>,
+}
+
+impl ReplyEncoder {
+    pub(crate) fn new(value: RedisValue, protocol: Protocol) -> Self {
+        Self {
+            protocol,
+            next: Some(value),
+            open: Vec::new(),
+        }
+    }
+
+    /// Encode the next piece into `dst`, or return false if the whole reply is encoded
+    pub(crate) fn encode_next(&mut self, dst: &mut impl ReplyBuf) -> Result<bool, anyhow::Error> {
+        let Some(value) = self.next.take().or_else(|| self.next_element()) else {
+            return Ok(false);
+        };
+        let resp3 = self.protocol == Protocol::Resp3;
+        let (prefix, len, elements) = match value {
+            RedisValue::Array(values) => (b'*', values.len(), Elements::Values(values.into_iter())),
+            RedisValue::Set(values) => {
+                let prefix = if resp3 { b'~' } else { b'*' };
+                (prefix, values.len(), Elements::Values(values.into_iter()))
+            }
+            RedisValue::Push(values) => {
+                let prefix = if resp3 { b'>' } else { b'*' };
+                (prefix, values.len(), Elements::Values(values.into_iter()))
+            }
+            RedisValue::Map(pairs) => {
+                let (prefix, len) = if resp3 {
+                    (b'%', pairs.len())
+                } else {
+                    (b'*', pairs.len() * 2)
+                };
+                (prefix, len, Elements::Pairs(pairs.into_iter(), None))
+            }
+            RedisValue::Attribute { attributes, value } => {
+                self.open.push(Elements::Values(vec![*value].into_iter()));
+                if !resp3 {
+                    return Ok(true);
+                }
+                (
+                    b'|',
+                    attributes.len(),
+                    Elements::Pairs(attributes.into_iter(), None),
+                )
+            }
+            value => {
+                RespFrame::encode_value(value, self.protocol, dst)?;
+                return Ok(true);
+            }
+        };
+        RespFrame::encode_aggregate_header(prefix, len, dst);
+        self.open.push(elements);
+        Ok(true)
+    }
+
+    fn next_element(&mut self) -> Option<RedisValue> {
+        while let Some(elements) = self.open.last_mut() {
+            if let Some(element) = elements.next() {
+                return Some(element);
+            }
+            self.open.pop();
+        }
+        None
+    }
+}
+
 impl Encoder<RedisValue> for RespFrame {
     type Error = anyhow::Error;
 
     fn encode(&mut self, item: RedisValue, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        RespFrame::encode_value(item, dst)
+        RespFrame::encode_value(item, self.protocol, dst)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bytes::BufMut;
+
+    #[test]
+    fn decode_streams_large_bulk_strings() {
+        let mut codec = RespFrame::default();
+        let payload = vec![b'x'; 100 * 1024];
+        let mut frame = b"*2\r\n$3\r\nSET\r\n$102400\r\n".to_vec();
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(b"\r\n");
+
+        let mut src = BytesMut::new();
+        let mut chunks = frame.chunks(1000);
+        src.extend_from_slice(chunks.next().unwrap());
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        // the header is enough to know how much is coming
+        assert_eq!(codec.ctx.needed.get(), frame.len());
+        assert!(src.capacity() >= frame.len());
+
+        for chunk in chunks {
+            src.extend_from_slice(chunk);
+            if let Some(value) = codec.decode(&mut src).unwrap() {
+                assert_eq!(
+                    value,
+                    RedisValue::command([Bytes::from("SET"), Bytes::from(payload.clone())])
+                );
+                assert!(src.is_empty());
+                assert_eq!(codec.ctx.needed.get(), 0);
+                return;
+            }
+        }
+        panic!("frame never decoded");
+    }
+
+    #[test]
+    fn decode_pipelined_frames_a_byte_at_a_time() {
+        let mut codec = RespFrame::default();
+        let input = b"*2\r\n*1\r\n:1\r\n$2\r\nhi\r\n+OK\r\n%1\r\n:1\r\n#t\r\n";
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in input {
+            src.put_u8(*byte);
+            while let Some(value) = codec.decode(&mut src).unwrap() {
+                decoded.push(value);
+            }
+        }
+        assert_eq!(
+            decoded,
+            vec![
+                RedisValue::Array(vec![
+                    RedisValue::Array(vec![RedisValue::Integer(1)]),
+                    RedisValue::BulkString("hi".into()),
+                ]),
+                RedisValue::SimpleString("OK".into()),
+                RedisValue::Map(vec![(RedisValue::Integer(1), RedisValue::Boolean(true))]),
+            ]
+        );
+        assert!(src.is_empty());
+    }
 
     #[test]
     fn encode_simple_string() {
         let mut buf = BytesMut::with_capacity(1024);
         let item = RedisValue::SimpleString("OK".into());
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b"+OK\r\n"[..]);
     }
 
@@ -111,7 +458,7 @@ mod tests {
     fn encode_simple_error() {
         let mut buf = BytesMut::with_capacity(1024);
         let item = RedisValue::SimpleError("Error message".into());
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b"-Error message\r\n"[..]);
     }
 
@@ -121,19 +468,19 @@ mod tests {
 
         // encoding 0
         let item = RedisValue::Integer(0);
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b":0\r\n"[..]);
         buf.clear();
 
         // encoding 1000
         let item = RedisValue::Integer(1000);
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b":1000\r\n"[..]);
         buf.clear();
 
         // encoding negative values
         let item = RedisValue::Integer(-1);
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b":-1\r\n"[..]);
     }
 
@@ -143,19 +490,19 @@ mod tests {
 
         // null bulk string
         let item = RedisValue::NullBulkString;
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b"$-1\r\n"[..]);
         buf.clear();
 
         // empty string
         let item = RedisValue::BulkString("".into());
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b"$0\r\n\r\n"[..]);
         buf.clear();
 
         // basic bulk string
         let item = RedisValue::BulkString("hello".into());
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b"$5\r\nhello\r\n"[..]);
     }
 
@@ -165,14 +512,14 @@ mod tests {
 
         // null array
         let item = RedisValue::NullArray;
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b"*-1\r\n"[..]);
         buf.clear();
 
         // walk through official RESP spec array tests
         // empty array
         let item = RedisValue::Array(vec![]);
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b"*0\r\n"[..]);
         buf.clear();
 
@@ -181,7 +528,7 @@ mod tests {
             RedisValue::BulkString("hello".into()),
             RedisValue::BulkString("world".into()),
         ]);
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b"*2\r\n$5\r\nhello\r\n$5\r\nworld\r\n"[..]);
         buf.clear();
 
@@ -191,7 +538,7 @@ mod tests {
             RedisValue::Integer(2),
             RedisValue::Integer(3),
         ]);
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(&buf[..], &b"*3\r\n:1\r\n:2\r\n:3\r\n"[..]);
         buf.clear();
 
@@ -203,7 +550,7 @@ mod tests {
             RedisValue::Integer(4),
             RedisValue::BulkString("hello".into()),
         ]);
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(
             &buf[..],
             &b"*5\r\n:1\r\n:2\r\n:3\r\n:4\r\n$5\r\nhello\r\n"[..]
@@ -222,11 +569,95 @@ mod tests {
                 RedisValue::SimpleError("World".into()),
             ]),
         ]);
-        RespFrame::encode_value(item, &mut buf).unwrap();
+        RespFrame::encode_value(item, Protocol::Resp2, &mut buf).unwrap();
         assert_eq!(
             &buf[..],
             &b"*2\r\n*3\r\n:1\r\n:2\r\n:3\r\n*2\r\n+Hello\r\n-World\r\n"[..]
         );
         buf.clear();
     }
+
+    #[test]
+    fn encode_resp3_types() {
+        let encode = |item: RedisValue, protocol| {
+            let mut buf = BytesMut::new();
+            RespFrame::encode_value(item, protocol, &mut buf).unwrap();
+            buf
+        };
+        use Protocol::*;
+
+        assert_eq!(&encode(RedisValue::Null, Resp3)[..], b"_\r\n");
+        assert_eq!(&encode(RedisValue::NullBulkString, Resp3)[..], b"_\r\n");
+        assert_eq!(&encode(RedisValue::NullArray, Resp3)[..], b"_\r\n");
+        assert_eq!(&encode(RedisValue::Null, Resp2)[..], b"$-1\r\n");
+
+        assert_eq!(&encode(RedisValue::Boolean(true), Resp3)[..], b"#t\r\n");
+        assert_eq!(&encode(RedisValue::Boolean(false), Resp2)[..], b":0\r\n");
+
+        assert_eq!(&encode(RedisValue::Double(1.5), Resp3)[..], b",1.5\r\n");
+        assert_eq!(
+            &encode(RedisValue::Double(f64::NEG_INFINITY), Resp3)[..],
+            b",-inf\r\n"
+        );
+        assert_eq!(&encode(RedisValue::Double(2.0), Resp2)[..], b"$1\r\n2\r\n");
+
+        let map = RedisValue::Map(vec![(
+            RedisValue::BulkString("a".into()),
+            RedisValue::Integer(1),
+        )]);
+        assert_eq!(&encode(map.clone(), Resp3)[..], b"%1\r\n$1\r\na\r\n:1\r\n");
+        assert_eq!(&encode(map, Resp2)[..], b"*2\r\n$1\r\na\r\n:1\r\n");
+
+        let set = RedisValue::Set(vec![RedisValue::Integer(1)]);
+        assert_eq!(&encode(set.clone(), Resp3)[..], b"~1\r\n:1\r\n");
+        assert_eq!(&encode(set, Resp2)[..], b"*1\r\n:1\r\n");
+
+        let push = RedisValue::Push(vec![RedisValue::BulkString("message".into())]);
+        assert_eq!(&encode(push.clone(), Resp3)[..], b">1\r\n$7\r\nmessage\r\n");
+        assert_eq!(&encode(push, Resp2)[..], b"*1\r\n$7\r\nmessage\r\n");
+
+        let attributed = RedisValue::Attribute {
+            attributes: vec![(
+                RedisValue::SimpleString("ttl".into()),
+                RedisValue::Integer(3),
+            )],
+            value: Box::new(RedisValue::Integer(1)),
+        };
+        assert_eq!(
+            &encode(attributed.clone(), Resp3)[..],
+            b"|1\r\n+ttl\r\n:3\r\n:1\r\n"
+        );
+        assert_eq!(&encode(attributed, Resp2)[..], b":1\r\n");
+    }
+
+    #[test]
+    fn encodes_replies_a_piece_at_a_time() {
+        let pair = |key: &str, value| (RedisValue::BulkString(key.to_owned().into()), value);
+        let reply = RedisValue::Array(vec![
+            RedisValue::Integer(1),
+            RedisValue::Array(vec![]),
+            RedisValue::Map(vec![
+                pair("set", RedisValue::Set(vec![RedisValue::Double(1.5)])),
+                pair("null", RedisValue::Null),
+            ]),
+            RedisValue::Attribute {
+                attributes: vec![pair("ttl", RedisValue::Integer(3))],
+                value: Box::new(RedisValue::Push(vec![RedisValue::Boolean(true)])),
+            },
+            RedisValue::BulkString("last".into()),
+        ]);
+        for protocol in [Protocol::Resp2, Protocol::Resp3] {
+            let mut whole = BytesMut::new();
+            RespFrame::encode_value(reply.clone(), protocol, &mut whole).unwrap();
+
+            let mut encoder = ReplyEncoder::new(reply.clone(), protocol);
+            let mut pieces = BytesMut::new();
+            let mut count = 0;
+            while encoder.encode_next(&mut pieces).unwrap() {
+                count += 1;
+            }
+            assert_eq!(pieces, whole, "{protocol:?}");
+            assert!(count > 10);
+        }
+    }
 }
@@ -1,18 +1,31 @@
 use bytes::{Bytes, BytesMut};
 
-use crate::resp::RedisValue;
+use crate::resp::{codec::ProtoLimits, RedisValue};
 
-use std::{num::ParseIntError, str::Utf8Error};
+use std::{cell::Cell, num::ParseIntError, str::Utf8Error};
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum RespParseError {
+    #[error("I/O error: {0}")]
     IOError(std::io::Error),
+    #[error("invalid UTF-8: {0}")]
     ParseUtf8Error(Utf8Error),
+    #[error("invalid integer: {0}")]
     ParseIntegerError(ParseIntError),
+    #[error("invalid type byte")]
     InvalidFirstByte,
+    #[error("invalid bulk length")]
     InvalidBulkStringLength(i64),
-    ExceededMaxLength,
+    #[error("bulk string not followed by CRLF")]
+    MissingBulkCrlf,
+    #[error("invalid multibulk length")]
     InvalidArrayLength(i64),
+    #[error("nesting too deep")]
+    NestingTooDeep,
+    #[error("invalid double {0:?}")]
+    InvalidDouble(String),
+    #[error("too big inline request")]
+    InlineTooBig,
 }
 
 impl From<std::io::Error> for RespParseError {
@@ -45,6 +58,13 @@ pub(crate) enum RedisIntermediate {
     BulkString(BufRange),
     NullArray,
     Array(Vec<RedisIntermediate>),
+    Null,
+    Boolean(bool),
+    Double(f64),
+    Map(IntermediatePairs),
+    Set(Vec<RedisIntermediate>),
+    Push(Vec<RedisIntermediate>),
+    Attribute(IntermediatePairs, Box<RedisIntermediate>),
 }
 
 impl RedisIntermediate {
@@ -56,24 +76,78 @@ impl RedisIntermediate {
             Self::NullBulkString => RedisValue::NullBulkString,
             Self::BulkString(br) => RedisValue::BulkString(buffer.slice(br.0..br.1)),
             Self::NullArray => RedisValue::NullArray,
-            Self::Array(intermediates) => RedisValue::Array(
-                intermediates
-                    .into_iter()
-                    .map(|int| int.generate_value(buffer))
-                    .collect(),
-            ),
+            Self::Array(intermediates) => {
+                RedisValue::Array(Self::generate_all(intermediates, buffer))
+            }
+            Self::Null => RedisValue::Null,
+            Self::Boolean(b) => RedisValue::Boolean(b),
+            Self::Double(d) => RedisValue::Double(d),
+            Self::Map(pairs) => RedisValue::Map(Self::generate_pairs(pairs, buffer)),
+            Self::Set(intermediates) => RedisValue::Set(Self::generate_all(intermediates, buffer)),
+            Self::Push(intermediates) => {
+                RedisValue::Push(Self::generate_all(intermediates, buffer))
+            }
+            Self::Attribute(pairs, value) => RedisValue::Attribute {
+                attributes: Self::generate_pairs(pairs, buffer),
+                value: Box::new(value.generate_value(buffer)),
+            },
         }
     }
+
+    fn generate_all(intermediates: Vec<Self>, buffer: &Bytes) -> Vec<RedisValue> {
+        intermediates
+            .into_iter()
+            .map(|int| int.generate_value(buffer))
+            .collect()
+    }
+
+    fn generate_pairs(pairs: Vec<(Self, Self)>, buffer: &Bytes) -> Vec<(RedisValue, RedisValue)> {
+        pairs
+            .into_iter()
+            .map(|(k, v)| (k.generate_value(buffer), v.generate_value(buffer)))
+            .collect()
+    }
 }
 
+/// What the parser is allowed to accept, and what it learned about an incomplete frame
+#[derive(Default)]
+pub(crate) struct ParseContext {
+    pub(crate) limits: ProtoLimits,
+
+    /// Buffer length below which parsing is known to come up short again, set when a bulk
+    /// string's payload is still missing
+    pub(crate) needed: Cell<usize>,
+
+    /// Whether a frame may also be an inline command, see [`parse_inline`]
+    pub(crate) inline: bool,
+}
+
+impl ParseContext {
+    pub(crate) fn new(limits: ProtoLimits) -> Self {
+        Self {
+            limits,
+            needed: Cell::new(0),
+            inline: false,
+        }
+    }
+}
+
+/// Longest line accepted as an inline command, Redis' `PROTO_INLINE_MAX_SIZE`
+const INLINE_MAX_LEN: usize = 64 * 1024;
+
+/// Most elements reserved for an aggregate before they actually arrive
+const MAX_PREALLOCATED_ELEMENTS: usize = 1024;
+
 type ParseResult = Result<Option<(usize, RedisIntermediate)>, RespParseError>;
 
+type IntermediatePairs = Vec<(RedisIntermediate, RedisIntermediate)>;
+
 fn parse_word(input: &BytesMut, pos: usize) -> Option<(usize, BufRange)> {
     if input.len() <= pos {
         return None;
     }
     memchr::memchr(b'\r', &input[pos..]).and_then(|ret| {
-        if ret + 1 < input.len() && input[pos + ret + 1] == b'\n' {
+        if pos + ret + 1 < input.len() && input[pos + ret + 1] == b'\n' {
             Some((pos + ret + 2, BufRange(pos, pos + ret)))
         } else {
             None
@@ -103,16 +177,20 @@ fn parse_integer(input: &BytesMut, pos: usize) -> ParseResult {
     Ok(int(input, pos)?.map(|(p, int)| (p, RedisIntermediate::Integer(int))))
 }
 
-fn parse_bulk_string(input: &BytesMut, pos: usize) -> ParseResult {
+fn parse_bulk_string(input: &BytesMut, pos: usize, ctx: &ParseContext) -> ParseResult {
     match int(input, pos)? {
         Some((p, -1)) => Ok(Some((p, RedisIntermediate::NullBulkString))),
         Some((p, length)) if length >= 0 => {
-            if length > u32::MAX as i64 {
-                return Err(RespParseError::ExceededMaxLength);
+            if length as u64 > ctx.limits.max_bulk_len {
+                return Err(RespParseError::InvalidBulkStringLength(length));
             }
             let end = p + length as usize;
             if input.len() < end + 2 {
+                ctx.needed.set(end + 2);
                 Ok(None)
+            } else if &input[end..end + 2] != b"\r\n" {
+                // the payload is longer than its length says, nothing after it can be trusted
+                Err(RespParseError::MissingBulkCrlf)
             } else {
                 Ok(Some((
                     end + 2,
@@ -125,69 +203,305 @@ fn parse_bulk_string(input: &BytesMut, pos: usize) -> ParseResult {
     }
 }
 
-fn parse_array(input: &BytesMut, pos: usize) -> ParseResult {
-    match int(input, pos)? {
-        Some((p, -1)) => Ok(Some((p, RedisIntermediate::NullArray))),
-        Some((mut p, length)) if length >= 0 => {
-            if length > u32::MAX as i64 {
-                return Err(RespParseError::ExceededMaxLength);
+/// Kinds of aggregate, whose elements follow their header
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Aggregate {
+    Array,
+    Map,
+    Set,
+    Push,
+    Attribute,
+}
+
+impl Aggregate {
+    /// Assemble the aggregate from all of its elements
+    fn finish(self, mut elements: Vec<RedisIntermediate>) -> RedisIntermediate {
+        match self {
+            Self::Array => RedisIntermediate::Array(elements),
+            Self::Map => RedisIntermediate::Map(pairs(elements)),
+            Self::Set => RedisIntermediate::Set(elements),
+            Self::Push => RedisIntermediate::Push(elements),
+            // the attributes describe the value that follows them
+            Self::Attribute => {
+                let value = elements.pop().expect("attribute without a value");
+                RedisIntermediate::Attribute(pairs(elements), Box::new(value))
             }
-            let mut values = Vec::with_capacity(length as usize);
-            for _ in 0..length {
-                match parse(input, p)? {
-                    Some((new_p, v)) => {
-                        p = new_p;
-                        values.push(v);
-                    }
-                    None => return Ok(None),
-                }
+        }
+    }
+}
+
+/// Group alternating keys and values, as in maps and attributes
+fn pairs(elements: Vec<RedisIntermediate>) -> IntermediatePairs {
+    let mut elements = elements.into_iter();
+    let mut pairs = Vec::with_capacity(elements.len() / 2);
+    while let (Some(k), Some(v)) = (elements.next(), elements.next()) {
+        pairs.push((k, v));
+    }
+    pairs
+}
+
+/// One step of parsing: either a whole value, or the header of an aggregate followed by this
+/// many elements
+#[derive(Debug, PartialEq)]
+enum Step {
+    Value(RedisIntermediate),
+    Open(Aggregate, usize),
+}
+
+type StepResult = Result<Option<(usize, Step)>, RespParseError>;
+
+fn value(result: ParseResult) -> StepResult {
+    Ok(result?.map(|(p, v)| (p, Step::Value(v))))
+}
+
+fn parse_array(input: &BytesMut, pos: usize, ctx: &ParseContext) -> StepResult {
+    match int(input, pos)? {
+        Some((p, -1)) => Ok(Some((p, Step::Value(RedisIntermediate::NullArray)))),
+        Some((p, length)) if length >= 0 => {
+            if length as u64 > ctx.limits.max_multibulk_len {
+                return Err(RespParseError::InvalidArrayLength(length));
             }
-            Ok(Some((p, RedisIntermediate::Array(values))))
+            Ok(Some((p, Step::Open(Aggregate::Array, length as usize))))
+        }
+        Some((_p, invalid_length)) => Err(RespParseError::InvalidArrayLength(invalid_length)),
+        None => Ok(None),
+    }
+}
+
+/// The header of a RESP3 aggregate, which unlike RESP2 arrays has no null form
+fn parse_aggregate(
+    input: &BytesMut,
+    pos: usize,
+    aggregate: Aggregate,
+    ctx: &ParseContext,
+) -> StepResult {
+    match int(input, pos)? {
+        Some((p, length)) if length >= 0 && length as u64 <= ctx.limits.max_multibulk_len => {
+            let length = length as usize;
+            let elements = match aggregate {
+                Aggregate::Map => length * 2,
+                // the pairs and then the value they describe
+                Aggregate::Attribute => length * 2 + 1,
+                _ => length,
+            };
+            Ok(Some((p, Step::Open(aggregate, elements))))
         }
         Some((_p, invalid_length)) => Err(RespParseError::InvalidArrayLength(invalid_length)),
         None => Ok(None),
     }
 }
 
-pub(crate) fn parse(input: &BytesMut, pos: usize) -> ParseResult {
-    if input.is_empty() {
+fn parse_null(input: &BytesMut, pos: usize) -> ParseResult {
+    match parse_word(input, pos) {
+        Some((p, BufRange(start, end))) if start == end => Ok(Some((p, RedisIntermediate::Null))),
+        Some(_) => Err(RespParseError::InvalidFirstByte),
+        None => Ok(None),
+    }
+}
+
+fn parse_boolean(input: &BytesMut, pos: usize) -> ParseResult {
+    match parse_word(input, pos) {
+        Some((p, BufRange(start, end))) => match &input[start..end] {
+            b"t" => Ok(Some((p, RedisIntermediate::Boolean(true)))),
+            b"f" => Ok(Some((p, RedisIntermediate::Boolean(false)))),
+            _ => Err(RespParseError::InvalidFirstByte),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_double(input: &BytesMut, pos: usize) -> ParseResult {
+    match parse_word(input, pos) {
+        Some((p, BufRange(start, end))) => {
+            let s = str::from_utf8(&input[start..end])?;
+            let d = s
+                .parse::<f64>()
+                .map_err(|_| RespParseError::InvalidDouble(s.to_string()))?;
+            Ok(Some((p, RedisIntermediate::Double(d))))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Whether `byte` starts a RESP value rather than an inline command
+fn is_type_byte(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'+' | b'-' | b':' | b'$' | b'*' | b'_' | b'#' | b',' | b'%' | b'~' | b'>' | b'|'
+    )
+}
+
+/// Parse an inline command at `pos`, a line of arguments separated by spaces as typed into
+/// telnet or sent by redis-benchmark, into an array of bulk strings. Quoting is not supported.
+/// A blank line is skipped, returning no value.
+fn parse_inline(
+    input: &BytesMut,
+    pos: usize,
+) -> Result<Option<(usize, Option<RedisIntermediate>)>, RespParseError> {
+    let Some(newline) = memchr::memchr(b'\n', &input[pos..]) else {
+        if input.len() - pos > INLINE_MAX_LEN {
+            return Err(RespParseError::InlineTooBig);
+        }
         return Ok(None);
+    };
+    let end = pos + newline;
+    // the carriage return is optional
+    let line_end = match end > pos && input[end - 1] == b'\r' {
+        true => end - 1,
+        false => end,
+    };
+    let mut args = Vec::new();
+    let mut start = None;
+    for i in pos..=line_end {
+        match (i < line_end && !input[i].is_ascii_whitespace(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                args.push(RedisIntermediate::BulkString(BufRange(s, i)));
+                start = None;
+            }
+            _ => {}
+        }
     }
+    let command = (!args.is_empty()).then_some(RedisIntermediate::Array(args));
+    Ok(Some((end + 1, command)))
+}
 
+/// Parse a scalar value or an aggregate's header at `pos`
+fn parse_step(input: &BytesMut, pos: usize, ctx: &ParseContext) -> StepResult {
     if input.len() <= pos {
         return Ok(None);
     }
 
     match input[pos] {
-        b'+' => parse_simple_string(input, pos + 1),
-        b'-' => parse_simple_error(input, pos + 1),
-        b':' => parse_integer(input, pos + 1),
-        b'$' => parse_bulk_string(input, pos + 1),
-        b'*' => parse_array(input, pos + 1),
+        b'+' => value(parse_simple_string(input, pos + 1)),
+        b'-' => value(parse_simple_error(input, pos + 1)),
+        b':' => value(parse_integer(input, pos + 1)),
+        b'$' => value(parse_bulk_string(input, pos + 1, ctx)),
+        b'*' => parse_array(input, pos + 1, ctx),
+        b'_' => value(parse_null(input, pos + 1)),
+        b'#' => value(parse_boolean(input, pos + 1)),
+        b',' => value(parse_double(input, pos + 1)),
+        b'%' => parse_aggregate(input, pos + 1, Aggregate::Map, ctx),
+        b'~' => parse_aggregate(input, pos + 1, Aggregate::Set, ctx),
+        b'>' => parse_aggregate(input, pos + 1, Aggregate::Push, ctx),
+        b'|' => parse_aggregate(input, pos + 1, Aggregate::Attribute, ctx),
         _ => Err(RespParseError::InvalidFirstByte),
     }
 }
 
+/// An aggregate whose elements are still arriving
+struct Open {
+    aggregate: Aggregate,
+    remaining: usize,
+    elements: Vec<RedisIntermediate>,
+}
+
+/// Parses a frame across calls as it arrives, resuming where the last call ran out of input
+/// instead of starting over, so each element is only examined once
+#[derive(Default)]
+pub(crate) struct IncrementalParser {
+    /// Where the next element starts
+    pos: usize,
+
+    /// Aggregates entered but not yet complete, innermost last
+    open: Vec<Open>,
+}
+
+impl IncrementalParser {
+    /// Continue parsing the frame at the start of `input`, which must only have grown since the
+    /// last call. The state is reset once the frame is complete or turns out to be invalid.
+    pub(crate) fn parse(&mut self, input: &BytesMut, ctx: &ParseContext) -> ParseResult {
+        let result = self.resume(input, ctx);
+        if !matches!(result, Ok(None)) {
+            *self = Self::default();
+        }
+        result
+    }
+
+    fn resume(&mut self, input: &BytesMut, ctx: &ParseContext) -> ParseResult {
+        loop {
+            // only a whole frame can be inline, never an element of an aggregate
+            if ctx.inline
+                && self.open.is_empty()
+                && input.len() > self.pos
+                && !is_type_byte(input[self.pos])
+            {
+                match parse_inline(input, self.pos)? {
+                    Some((pos, Some(command))) => return Ok(Some((pos, command))),
+                    Some((pos, None)) => {
+                        self.pos = pos;
+                        continue;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            let Some((pos, step)) = parse_step(input, self.pos, ctx)? else {
+                return Ok(None);
+            };
+            self.pos = pos;
+            let mut value = match step {
+                Step::Value(v) => v,
+                Step::Open(aggregate, 0) => aggregate.finish(Vec::new()),
+                Step::Open(aggregate, remaining) => {
+                    // the stack is on the heap, but still the peer's to grow
+                    if self.open.len() >= ctx.limits.max_nesting {
+                        return Err(RespParseError::NestingTooDeep);
+                    }
+                    self.open.push(Open {
+                        aggregate,
+                        remaining,
+                        // the count comes from the peer, so don't let it size the allocation
+                        elements: Vec::with_capacity(remaining.min(MAX_PREALLOCATED_ELEMENTS)),
+                    });
+                    continue;
+                }
+            };
+            // hand the value to the aggregate it belongs to, closing those it completes
+            loop {
+                let Some(open) = self.open.last_mut() else {
+                    return Ok(Some((self.pos, value)));
+                };
+                open.elements.push(value);
+                open.remaining -= 1;
+                if open.remaining > 0 {
+                    break;
+                }
+                let open = self.open.pop().expect("just looked at it");
+                value = open.aggregate.finish(open.elements);
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Parse the value at `pos` in one go
+    fn parse(input: &BytesMut, pos: usize, ctx: &ParseContext) -> ParseResult {
+        IncrementalParser {
+            pos,
+            open: Vec::new(),
+        }
+        .parse(input, ctx)
+    }
+
     fn setup_parse(input: &[u8]) -> RedisValue {
         let mut buf = BytesMut::from(input);
-        let (pos, intermediate) = parse(&buf, 0).unwrap().unwrap();
+        let (pos, intermediate) = parse(&buf, 0, &ParseContext::default()).unwrap().unwrap();
         let parsed = buf.split_to(pos);
         intermediate.generate_value(&parsed.freeze())
     }
 
     fn setup_result(input: &[u8]) -> ParseResult {
         let buf = BytesMut::from(input);
-        parse(&buf, 0)
+        parse(&buf, 0, &ParseContext::default())
     }
 
     #[test]
     fn test_parse() {
         let mut buf = BytesMut::from("$5\r\nhello\r\n");
-        let (pos, v) = parse(&buf, 0).unwrap().unwrap();
+        let (pos, v) = parse(&buf, 0, &ParseContext::default()).unwrap().unwrap();
         assert_eq!(pos, 11);
         assert_eq!(v, RedisIntermediate::BulkString(BufRange(4, 9)));
         // how we would use it in the decoder is below
@@ -256,6 +570,45 @@ mod tests {
         assert!(res.is_none());
         let res = setup_result(&b"$10\r\nhello678\r\n"[..]).unwrap();
         assert!(res.is_none());
+        let res = setup_result(&b"$4\r\nPINGPONG\r\n"[..]);
+        assert!(matches!(res, Err(RespParseError::MissingBulkCrlf)));
+    }
+
+    #[test]
+    fn inline_commands() {
+        let ctx = ParseContext {
+            inline: true,
+            ..ParseContext::default()
+        };
+        let inline = |input: &[u8]| {
+            let mut buf = BytesMut::from(input);
+            parse(&buf, 0, &ctx).map(|parsed| {
+                parsed.map(|(pos, intermediate)| {
+                    let parsed = buf.split_to(pos);
+                    intermediate.generate_value(&parsed.freeze())
+                })
+            })
+        };
+        let command =
+            |args: &[&str]| RedisValue::command(args.iter().map(|a| Bytes::from(a.to_string())));
+
+        assert_eq!(inline(b"PING\r\n").unwrap(), Some(command(&["PING"])));
+        // blank lines are skipped, and the carriage return and extra spaces are optional
+        assert_eq!(
+            inline(b"\r\n\nSET  k\tv\n").unwrap(),
+            Some(command(&["SET", "k", "v"]))
+        );
+        assert_eq!(inline(b"PING").unwrap(), None);
+        assert_eq!(
+            inline(b"*1\r\n$4\r\nPING\r\n").unwrap(),
+            Some(command(&["PING"]))
+        );
+        assert!(matches!(
+            inline(&vec![b'a'; INLINE_MAX_LEN + 1]),
+            Err(RespParseError::InlineTooBig)
+        ));
+        // off unless asked for
+        assert!(setup_result(b"PING\r\n").is_err());
     }
 
     #[test]
@@ -323,18 +676,160 @@ mod tests {
     #[test]
     fn test_multiple_parse() {
         let mut input = BytesMut::from(&b"+OK\r\n:100\r\n"[..]);
-        let (pos, intermediate) = parse(&input, 0).unwrap().unwrap();
+        let (pos, intermediate) = parse(&input, 0, &ParseContext::default()).unwrap().unwrap();
         let parsed = input.split_to(pos);
         assert_eq!(
             intermediate.generate_value(&parsed.freeze()),
             RedisValue::SimpleString("OK".into())
         );
         // parse the input again from index 0
-        let (pos, intermediate) = parse(&input, 0).unwrap().unwrap();
+        let (pos, intermediate) = parse(&input, 0, &ParseContext::default()).unwrap().unwrap();
         let parsed = input.split_to(pos);
         assert_eq!(
             intermediate.generate_value(&parsed.freeze()),
             RedisValue::Integer(100)
         );
     }
+
+    #[test]
+    fn test_resp3_types() {
+        assert_eq!(setup_parse(&b"_\r\n"[..]), RedisValue::Null);
+        assert_eq!(setup_parse(&b"#t\r\n"[..]), RedisValue::Boolean(true));
+        assert_eq!(setup_parse(&b",-1.5\r\n"[..]), RedisValue::Double(-1.5));
+        assert_eq!(
+            setup_parse(&b",inf\r\n"[..]),
+            RedisValue::Double(f64::INFINITY)
+        );
+        assert_eq!(
+            setup_parse(&b"%1\r\n+a\r\n:1\r\n"[..]),
+            RedisValue::Map(vec![(
+                RedisValue::SimpleString("a".into()),
+                RedisValue::Integer(1)
+            )])
+        );
+        assert_eq!(
+            setup_parse(&b"~1\r\n:1\r\n"[..]),
+            RedisValue::Set(vec![RedisValue::Integer(1)])
+        );
+        assert_eq!(
+            setup_parse(&b">2\r\n$7\r\nmessage\r\n$2\r\nhi\r\n"[..]),
+            RedisValue::Push(vec![
+                RedisValue::BulkString("message".into()),
+                RedisValue::BulkString("hi".into())
+            ])
+        );
+        assert_eq!(
+            setup_parse(&b"|1\r\n+ttl\r\n:3\r\n$1\r\nv\r\n"[..]),
+            RedisValue::Attribute {
+                attributes: vec![(
+                    RedisValue::SimpleString("ttl".into()),
+                    RedisValue::Integer(3)
+                )],
+                value: Box::new(RedisValue::BulkString("v".into())),
+            }
+        );
+
+        // an attribute is incomplete until the value it describes has arrived
+        assert!(setup_result(&b"|1\r\n+ttl\r\n:3\r\n"[..])
+            .unwrap()
+            .is_none());
+        assert!(setup_result(&b"#x\r\n"[..]).is_err());
+        assert!(setup_result(&b",abc\r\n"[..]).is_err());
+        assert!(setup_result(&b"%-1\r\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let ctx = ParseContext::new(ProtoLimits {
+            max_bulk_len: 5,
+            max_multibulk_len: 2,
+            max_nesting: 2,
+        });
+        let result = |input: &[u8]| parse(&BytesMut::from(input), 0, &ctx);
+        assert!(result(&b"$5\r\nhello\r\n"[..]).unwrap().is_some());
+        assert!(matches!(
+            result(&b"$6\r\n"[..]),
+            Err(RespParseError::InvalidBulkStringLength(6))
+        ));
+        assert!(result(&b"*2\r\n:1\r\n:2\r\n"[..]).unwrap().is_some());
+        assert!(matches!(
+            result(&b"*3\r\n"[..]),
+            Err(RespParseError::InvalidArrayLength(3))
+        ));
+        assert!(result(&b"%3\r\n"[..]).is_err());
+        assert!(result(&b"*1\r\n*1\r\n:1\r\n"[..]).unwrap().is_some());
+        assert!(matches!(
+            result(&b"*1\r\n*1\r\n*1\r\n"[..]),
+            Err(RespParseError::NestingTooDeep)
+        ));
+        // empty aggregates never open
+        assert!(result(&b"*1\r\n*1\r\n*0\r\n"[..]).unwrap().is_some());
+        assert_eq!(
+            RespParseError::InvalidBulkStringLength(6).to_string(),
+            "invalid bulk length"
+        );
+
+        // a huge announced length doesn't allocate until the elements arrive
+        let ctx = ParseContext::default();
+        assert!(parse(&BytesMut::from(&b"*2000000000\r\n"[..]), 0, &ctx)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn incremental_parse_resumes() {
+        let ctx = ParseContext::default();
+        let frame = &b"*3\r\n$3\r\nSET\r\n*2\r\n:1\r\n|1\r\n+a\r\n:2\r\n_\r\n$1\r\nv\r\n"[..];
+        let mut parser = IncrementalParser::default();
+        let mut input = BytesMut::new();
+        for (i, byte) in frame.iter().enumerate() {
+            input.extend_from_slice(&[*byte]);
+            let result = parser.parse(&input, &ctx).unwrap();
+            if i + 1 < frame.len() {
+                assert!(result.is_none());
+                continue;
+            }
+            let (pos, intermediate) = result.unwrap();
+            assert_eq!(pos, frame.len());
+            // one shot parsing agrees
+            assert_eq!(
+                parse(&input, 0, &ctx).unwrap().unwrap(),
+                (pos, intermediate)
+            );
+        }
+        // finished frames leave nothing behind for the next one
+        assert_eq!(parser.pos, 0);
+        assert!(parser.open.is_empty());
+
+        // nor do invalid ones
+        assert!(parser
+            .parse(&BytesMut::from(&b"*2\r\n:1\r\n!"[..]), &ctx)
+            .is_err());
+        assert_eq!(parser.pos, 0);
+        assert!(parser.open.is_empty());
+    }
+
+    #[test]
+    fn deep_nesting_does_not_recurse() {
+        let depth = 1_000_000;
+        let mut input = BytesMut::new();
+        for _ in 0..depth {
+            input.extend_from_slice(b"*1\r\n");
+        }
+        input.extend_from_slice(b":1\r\n");
+        let ctx = ParseContext::new(ProtoLimits {
+            max_nesting: depth,
+            ..ProtoLimits::default()
+        });
+        let (pos, mut intermediate) = parse(&input, 0, &ctx).unwrap().unwrap();
+        assert_eq!(pos, input.len());
+        // take it apart by hand, dropping it recursively would overflow in turn
+        let mut levels = 0;
+        while let RedisIntermediate::Array(mut elements) = intermediate {
+            intermediate = elements.pop().unwrap();
+            levels += 1;
+        }
+        assert_eq!(levels, depth);
+        assert_eq!(intermediate, RedisIntermediate::Integer(1));
+    }
 }
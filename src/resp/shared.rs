@@ -0,0 +1,72 @@
+use std::sync::LazyLock;
+
+use bytes::Bytes;
+
+use crate::resp::{codec::Protocol, RedisValue};
+
+/// Integers from 0 up to this are kept encoded, as Redis' `OBJ_SHARED_INTEGERS`
+const SHARED_INTEGERS: i64 = 10_000;
+
+static OK: Bytes = Bytes::from_static(b"+OK\r\n");
+static PONG: Bytes = Bytes::from_static(b"+PONG\r\n");
+static EMPTY_ARRAY: Bytes = Bytes::from_static(b"*0\r\n");
+static NULL_BULK: Bytes = Bytes::from_static(b"$-1\r\n");
+static NULL_ARRAY: Bytes = Bytes::from_static(b"*-1\r\n");
+static NULL: Bytes = Bytes::from_static(b"_\r\n");
+
+static INTEGERS: LazyLock<Vec<Bytes>> = LazyLock::new(|| {
+    (0..SHARED_INTEGERS)
+        .map(|i| Bytes::from(format!(":{i}\r\n")))
+        .collect()
+});
+
+/// The encoding of `value` if it is a reply common enough to be kept encoded, so it can be copied
+/// straight into the output buffer
+pub(crate) fn encoded(value: &RedisValue, protocol: Protocol) -> Option<&'static Bytes> {
+    let resp3 = protocol == Protocol::Resp3;
+    Some(match value {
+        RedisValue::SimpleString(s) if s == "OK" => &OK,
+        RedisValue::SimpleString(s) if s == "PONG" => &PONG,
+        RedisValue::NullBulkString | RedisValue::NullArray | RedisValue::Null if resp3 => &NULL,
+        RedisValue::NullBulkString | RedisValue::Null => &NULL_BULK,
+        RedisValue::NullArray => &NULL_ARRAY,
+        RedisValue::Array(values) if values.is_empty() => &EMPTY_ARRAY,
+        RedisValue::Integer(i) if (0..SHARED_INTEGERS).contains(i) => &INTEGERS[*i as usize],
+        RedisValue::Boolean(b) if !resp3 => &INTEGERS[*b as usize],
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::resp::codec::RespFrame;
+
+    #[test]
+    fn encodes_common_replies() {
+        use Protocol::{Resp2, Resp3};
+        let cases: [(RedisValue, Protocol, &str); 9] = [
+            (RedisValue::SimpleString("OK".into()), Resp2, "+OK\r\n"),
+            (RedisValue::SimpleString("PONG".into()), Resp3, "+PONG\r\n"),
+            (RedisValue::NullBulkString, Resp2, "$-1\r\n"),
+            (RedisValue::NullArray, Resp2, "*-1\r\n"),
+            (RedisValue::Null, Resp3, "_\r\n"),
+            (RedisValue::Array(vec![]), Resp2, "*0\r\n"),
+            (RedisValue::Integer(0), Resp2, ":0\r\n"),
+            (RedisValue::Integer(9_999), Resp3, ":9999\r\n"),
+            (RedisValue::Boolean(true), Resp2, ":1\r\n"),
+        ];
+        for (value, protocol, expected) in cases {
+            assert_eq!(encoded(&value, protocol).unwrap(), expected);
+            let mut dst = BytesMut::new();
+            RespFrame::encode_value(value, protocol, &mut dst).unwrap();
+            assert_eq!(dst, expected);
+        }
+        assert!(encoded(&RedisValue::Integer(-1), Resp2).is_none());
+        assert!(encoded(&RedisValue::Integer(SHARED_INTEGERS), Resp2).is_none());
+        assert!(encoded(&RedisValue::Boolean(true), Resp3).is_none());
+        assert!(encoded(&RedisValue::SimpleString("QUEUED".into()), Resp2).is_none());
+    }
+}
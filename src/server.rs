@@ -1,102 +1,738 @@
-use std::{cmp::Reverse, collections::BinaryHeap, sync::Arc, time::Instant};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
 
-use anyhow::Result;
-use dashmap::DashMap;
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{
-    net::TcpListener,
-    sync::mpsc::{Receiver, Sender},
-    time::sleep_until,
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpSocket, TcpStream},
+    signal::unix::{signal, Signal, SignalKind},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::{JoinHandle, JoinSet},
 };
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
 
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::connection::uring::UringWorkers;
 use crate::{
-    connection::RedisConnection,
-    server::types::{Database, ExpiryEvent, RedisKey, Value, INITIAL_CAPACITY},
+    cluster::{self, ClusterState},
+    command::registry::CommandRegistry,
+    connection::{RedisConnection, Transport},
+    error::RedisError,
+    replication::{self, replica, ReplicationState, Role},
+    server::{
+        aof::Aof,
+        clients::ClientSlot,
+        config::{Config, ShutdownSave},
+        expire::TimerWheel,
+        propagation::{Propagator, Write},
+        storage::Storage,
+        types::{ExpiryEvent, LfuParams, ListpackLimit},
+    },
 };
 
+pub(crate) mod aof;
+pub(crate) mod blocking;
+pub mod check;
+pub(crate) mod clients;
+pub mod clock;
+pub mod config;
+pub(crate) mod defrag;
+pub mod embed;
+pub(crate) mod eviction;
+pub(crate) mod expire;
+pub(crate) mod health;
+pub(crate) mod hotkeys;
+pub(crate) mod info;
+pub mod keyspace;
+pub(crate) mod lazyfree;
+pub mod logging;
+pub(crate) mod migrate;
+pub(crate) mod persistence;
+pub(crate) mod propagation;
+pub(crate) mod pubsub;
+pub(crate) mod ratelimit;
+#[cfg(feature = "sharded-keyspace")]
+pub(crate) mod shards;
+pub(crate) mod stats;
+pub(crate) mod storage;
+#[cfg(feature = "systemd")]
+pub(crate) mod systemd;
+pub(crate) mod tasks;
+pub(crate) mod tls;
+pub(crate) mod tracking;
 pub(crate) mod types;
+pub(crate) mod watch;
+
+pub use crate::{
+    command::{
+        middleware::{ClientContext, Middleware},
+        table::CommandFlags,
+    },
+    resp::RedisValue,
+};
+pub use clock::{Clock, MockClock, SystemClock};
+pub use embed::{RedisBuilder, RunningRedis, ShutdownHandle};
+pub use keyspace::{KeyType, Keyspace};
+pub use watch::{KeyEvent, KeyEvents};
 
 pub struct Redis {
-    /// TCP Listener on given port
-    listener: TcpListener,
+    /// TCP listeners, one for each bind address
+    listeners: Vec<TcpListener>,
+
+    /// TCP listeners on the TLS port, and what does the handshake on their connections
+    tls_listeners: Vec<TcpListener>,
+    tls: Option<TlsAcceptor>,
     // Clients connected -> should be join handles or arc of the clients?
     /// The global key/value store
-    db: Arc<Database>,
+    db: Arc<dyn Storage>,
+
+    /// Built-in commands and any registered by an embedding program
+    commands: Arc<CommandRegistry>,
+
+    /// Configuration the server was started with
+    config: Arc<Config>,
+
+    /// Replication ID, offset and the stream of writes sent to replicas
+    replication: Arc<ReplicationState>,
 
     /// The channel to send expiration events on
-    expiration_tx: Sender<ExpiryEvent>,
+    expiration_tx: UnboundedSender<ExpiryEvent>,
+
+    /// Where writes go once applied: replicas, tracking clients and [watchers](Keyspace::watch_prefix)
+    propagator: Arc<Propagator>,
+
+    /// Slot ownership and cluster membership, when running in cluster mode
+    cluster: Option<Arc<ClusterState>>,
+
+    /// Loading the dataset, until it is done
+    loading: Option<JoinHandle<Result<()>>>,
+
+    /// Cancelled to ask every connection to wind down
+    shutdown: CancellationToken,
+
+    /// Cancelled by a [`ShutdownHandle`] to stop the server like SIGTERM would
+    stop: CancellationToken,
+
+    /// Whether SIGTERM and SIGINT shut the server down, left to the host when embedded
+    signals: bool,
+
+    /// Threads client sockets are handed to once accepted
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: UringWorkers,
+}
+
+/// A connection accepted, or why none could be, with the acceptor to do its TLS handshake
+/// when it came in on the TLS port
+type Accepted = (
+    std::io::Result<(TcpStream, SocketAddr)>,
+    Option<TlsAcceptor>,
+);
+
+/// Wait for the dataset to finish loading, forever once it has
+async fn loaded(loading: &mut Option<JoinHandle<Result<()>>>) -> Result<()> {
+    let result = match loading {
+        Some(task) => task.await,
+        None => std::future::pending().await,
+    };
+    *loading = None;
+    result?
+}
+
+/// Wait for `signal`, forever if it isn't handled
+async fn received(signal: &mut Option<Signal>) {
+    match signal {
+        Some(signal) => {
+            signal.recv().await;
+        }
+        None => std::future::pending().await,
+    }
 }
 
 impl Redis {
-    pub async fn new(port: u16) -> Result<Self> {
-        let db = Arc::new(Database::new());
+    pub async fn new(config: Config) -> Result<Self> {
+        let db = Self::in_memory(&config, Arc::new(SystemClock));
+        Self::from_parts(config, db, CommandRegistry::default()).await
+    }
+
+    /// The usual keyspace, held in memory, its expirations judged by `clock`
+    fn in_memory(config: &Config, clock: Arc<dyn Clock>) -> Arc<dyn Storage> {
+        let lfu = LfuParams {
+            log_factor: config.lfu_log_factor,
+            decay_time: config.lfu_decay_time,
+        };
+        let list_limit = ListpackLimit::from_config(config.list_max_listpack_size);
+        #[cfg(feature = "sharded-keyspace")]
+        return Arc::new(shards::ShardedDatabase::per_core(lfu, list_limit, clock));
+        #[cfg(not(feature = "sharded-keyspace"))]
+        Arc::new(types::Database::new(lfu, list_limit, clock))
+    }
+
+    /// A server for the keyspace held by `db`, rather than the usual in-memory
+    /// [`Database`](types::Database), serving `commands` on top of the built-in ones
+    pub(crate) async fn from_parts(
+        mut config: Config,
+        db: Arc<dyn Storage>,
+        commands: CommandRegistry,
+    ) -> Result<Self> {
+        let commands = Arc::new(commands);
+        let role = match config.replicaof.clone() {
+            Some((host, port)) => Role::Replica { host, port },
+            None => Role::Master,
+        };
+        let replication = Arc::new(ReplicationState::new(role));
+        let acks = replication.clone();
+        tasks::supervise("ack-requester", move || {
+            replication::ack_requester(acks.clone())
+        });
+        let (pinged, period) = (replication.clone(), config.repl_ping_replica_period);
+        tasks::supervise("replica-pinger", move || {
+            replication::replica_pinger(pinged.clone(), period)
+        });
+
+        let propagator = Arc::new(Propagator::new(replication.clone()));
+
+        // create task to expire keys, its events outliving it should it fail. Writers send
+        // while holding the write lock the expirer also takes, so sending must never wait.
+        let (tx, rx) = mpsc::unbounded_channel::<ExpiryEvent>();
+        let rx = Arc::new(tokio::sync::Mutex::new(rx));
+        let (expiring, replicated, propagated) =
+            (db.clone(), replication.clone(), propagator.clone());
+        tasks::supervise("expirer", move || {
+            Self::key_expirer(
+                expiring.clone(),
+                replicated.clone(),
+                propagated.clone(),
+                rx.clone(),
+            )
+        });
+        let (expiring, replicated, propagated) =
+            (db.clone(), replication.clone(), propagator.clone());
+        tasks::supervise("active-expire", move || {
+            expire::active_expire_cycle(expiring.clone(), replicated.clone(), propagated.clone())
+        });
+        if config.activedefrag {
+            let (defragged, threshold, cycle_max) = (
+                db.clone(),
+                config.active_defrag_threshold_lower,
+                config.active_defrag_cycle_max,
+            );
+            tasks::supervise("active-defrag", move || {
+                defrag::active_defrag_cycle(defragged.clone(), threshold, cycle_max)
+            });
+        }
 
-        // create task to expire keys
-        let (tx, rx) = tokio::sync::mpsc::channel::<ExpiryEvent>(INITIAL_CAPACITY);
-        tokio::spawn(Self::key_expirer(db.clone(), rx));
+        // probes can tell a server still loading its dataset from one that is down
+        replication.set_loading(true);
+        if config.health_port != 0 {
+            let listeners = Self::listen_all(config.health_port, &config)?;
+            health::serve(listeners, replication.clone(), db.clone());
+        }
+
+        let any_port = config.port == 0;
+        let listeners = Self::listen_all(config.port, &config)?;
+        // the port actually bound, when asked for any
+        config.port = listeners[0].local_addr()?.port();
+        let tls = tls::acceptor(&config)?;
+        let tls_listeners = match tls {
+            Some(_) => Self::listen_all(config.tls_port, &config)?,
+            None => Vec::new(),
+        };
+        let shutdown = CancellationToken::new();
+        let cluster = match config.cluster_enabled {
+            true => {
+                let bus_port = match config.cluster_port {
+                    0 if any_port => 0,
+                    0 => config
+                        .port
+                        .checked_add(cluster::BUS_PORT_OFFSET)
+                        .context("No cluster bus port 10000 above the port, set cluster-port")?,
+                    port => port,
+                };
+                let bus = Self::listen_all(bus_port, &config)?;
+                config.cluster_port = bus[0].local_addr()?.port();
+                let cluster = Arc::new(ClusterState::new(
+                    config.cluster_announce_ip(),
+                    config.port,
+                    config.cluster_port,
+                    config.cluster_node_timeout,
+                ));
+                let myself = cluster.myself();
+                tracing::info!(
+                    "Cluster mode enabled, node {} with bus port {}",
+                    myself.id,
+                    myself.bus_port
+                );
+                cluster::bus::start(bus, cluster.clone(), shutdown.clone());
+                Some(cluster)
+            }
+            false => None,
+        };
+        let config = Arc::new(config);
+
+        // clients are accepted while the dataset loads, and told to come back later
+        let loading = tasks::spawn(
+            "loading",
+            None,
+            Self::load(
+                config.clone(),
+                db.clone(),
+                commands.clone(),
+                replication.clone(),
+                tx.clone(),
+                propagator.clone(),
+            ),
+        );
 
         Ok(Self {
-            listener: TcpListener::bind(("127.0.0.1", port)).await?,
+            listeners,
+            tls_listeners,
+            tls,
             db,
+            commands,
+            config,
+            replication,
             expiration_tx: tx,
+            propagator,
+            cluster,
+            loading: Some(loading),
+            shutdown,
+            stop: CancellationToken::new(),
+            signals: true,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring: UringWorkers::per_core()?,
         })
     }
 
-    pub async fn run(&mut self) -> Result<()> {
+    /// Listen on `port` of every configured bind address. With port 0, the port picked for the
+    /// first address is used for the others too.
+    fn listen_all(mut port: u16, config: &Config) -> Result<Vec<TcpListener>> {
+        let (ips, required) = match config.bind.is_empty() {
+            // like Redis with no bind directive, listen everywhere, IPv6 only if available
+            true => (
+                vec![
+                    IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                ],
+                1,
+            ),
+            false => (config.bind.clone(), config.bind.len()),
+        };
+        let mut listeners = Vec::new();
+        for (i, ip) in ips.into_iter().enumerate() {
+            match Self::listen(ip, port, config) {
+                Ok(listener) => {
+                    port = listener.local_addr()?.port();
+                    listeners.push(listener);
+                }
+                Err(e) if i >= required => tracing::warn!("Not listening on {ip}: {e:#}"),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(listeners)
+    }
+
+    /// Bind a listening socket to `ip` with the configured backlog
+    fn listen(ip: IpAddr, port: u16, config: &Config) -> Result<TcpListener> {
+        let socket = match ip {
+            IpAddr::V4(_) => TcpSocket::new_v4()?,
+            IpAddr::V6(_) => {
+                let socket = TcpSocket::new_v6()?;
+                // leave IPv4 to its own listener, so `0.0.0.0` and `::` can both be bound
+                SockRef::from(&socket).set_only_v6(true)?;
+                socket
+            }
+        };
+        socket.set_reuseaddr(true)?;
+        socket
+            .bind(SocketAddr::new(ip, port))
+            .with_context(|| format!("Failed to bind {ip} port {port}"))?;
+        let listener = socket.listen(config.tcp_backlog)?;
+        tracing::info!("Listening on {ip} port {}", listener.local_addr()?.port());
+        Ok(listener)
+    }
+
+    /// Accept connections on `listener` and hand them to the server's loop, along with the
+    /// acceptor to do their TLS handshake if they speak TLS
+    async fn accept_loop(
+        listener: TcpListener,
+        tls: Option<TlsAcceptor>,
+        accepted: mpsc::Sender<Accepted>,
+    ) {
+        loop {
+            let result = listener.accept().await;
+            if accepted.send((result, tls.clone())).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Apply the TCP options to an accepted connection
+    fn tune(stream: &TcpStream, config: &Config) -> std::io::Result<()> {
+        stream.set_nodelay(config.tcp_nodelay)?;
+        if !config.tcp_keepalive.is_zero() {
+            // like Redis, probe every third of the idle time once it has elapsed
+            let keepalive = TcpKeepalive::new()
+                .with_time(config.tcp_keepalive)
+                .with_interval(config.tcp_keepalive / 3);
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+
+    /// Start building a server to embed, see [`RedisBuilder`]
+    pub fn builder() -> RedisBuilder {
+        RedisBuilder::default()
+    }
+
+    /// Address clients can connect to, the first the server listens on
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listeners[0].local_addr()?)
+    }
+
+    /// The server's data, to inspect or seed it directly
+    pub fn keyspace(&self) -> Keyspace {
+        Keyspace {
+            db: self.db.clone(),
+            config: self.config.clone(),
+            replication: self.replication.clone(),
+            expiration_tx: self.expiration_tx.clone(),
+            propagator: self.propagator.clone(),
+        }
+    }
+
+    /// A handle to shut the server down once it runs
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.stop.clone())
+    }
+
+    /// Serve clients until SIGTERM, SIGINT or a [`ShutdownHandle`] asks to stop, then shut down
+    /// gracefully
+    pub async fn run(mut self) -> Result<()> {
+        let (mut sigterm, mut sigint) = match self.signals {
+            true => (
+                Some(signal(SignalKind::terminate())?),
+                Some(signal(SignalKind::interrupt())?),
+            ),
+            false => (None, None),
+        };
+        let mut clients = JoinSet::new();
+        let mut loading = self.loading.take();
+        let mut failed = None;
+
+        let listeners = std::mem::take(&mut self.listeners)
+            .into_iter()
+            .map(|listener| (listener, None))
+            .chain(
+                std::mem::take(&mut self.tls_listeners)
+                    .into_iter()
+                    .map(|listener| (listener, self.tls.clone())),
+            )
+            .collect::<Vec<_>>();
+        let (accepted_tx, mut accepted_rx) = mpsc::channel(listeners.len());
+        let mut acceptors = JoinSet::new();
+        for (listener, tls) in listeners {
+            let accept_loop = Self::accept_loop(listener, tls, accepted_tx.clone());
+            tasks::spawn_in(&mut acceptors, "acceptor", None, accept_loop);
+        }
+        // connections through their TLS handshake, ready to be served
+        let (handshaken_tx, mut handshaken_rx) = mpsc::channel(1);
+
         tracing::info!("Serving clients");
-        while let Ok((client_stream, client_addr)) = self.listener.accept().await {
-            tracing::info!("New connection from: {client_addr}");
-
-            let mut client = RedisConnection::new(
-                client_stream,
-                client_addr,
-                self.db.clone(),
-                self.expiration_tx.clone(),
+        // readiness is the host's to report when embedded
+        #[cfg(feature = "systemd")]
+        if self.signals {
+            systemd::start(self.replication.clone());
+        }
+        let save = loop {
+            tokio::select! {
+                Some((accepted, tls)) = accepted_rx.recv() => {
+                    let (mut client_stream, client_addr) = accepted?;
+                    if !self.config.ip_filter.permits(client_addr.ip()) {
+                        // drop the connection without a word, before any work is done for it
+                        tracing::warn!("Denying {client_addr}, not allowed to connect");
+                        clients::deny();
+                        continue;
+                    }
+                    if let Err(e) = Self::tune(&client_stream, &self.config) {
+                        tracing::warn!("Failed to tune the socket of {client_addr}: {e}");
+                    }
+                    if self.config.refuses(client_addr.ip()) {
+                        tracing::warn!("Rejecting {client_addr}, protected mode is on");
+                        tasks::spawn_in(&mut clients, "rejection", None, async move {
+                            let reply = format!("-{}\r\n", RedisError::ProtectedMode);
+                            let _ = client_stream.write_all(reply.as_bytes()).await;
+                        });
+                        continue;
+                    }
+                    let Some(slot) = clients::admit(self.config.maxclients) else {
+                        tracing::warn!("Rejecting {client_addr}, max number of clients reached");
+                        tasks::spawn_in(&mut clients, "rejection", None, async move {
+                            let reply = format!("-{}\r\n", RedisError::MaxClients);
+                            let _ = client_stream.write_all(reply.as_bytes()).await;
+                        });
+                        continue;
+                    };
+                    tracing::info!("New connection from: {client_addr}");
+                    if let Some(acceptor) = tls {
+                        // apart from this loop, so a slow handshake holds up no one else
+                        let handshaken = handshaken_tx.clone();
+                        tasks::spawn_in(&mut clients, "tls-handshake", None, async move {
+                            match tls::handshake(acceptor, client_stream).await {
+                                Ok(stream) => {
+                                    let _ = handshaken.send((stream, client_addr, slot)).await;
+                                }
+                                Err(e) => tracing::warn!("Dropping {client_addr}: {e:#}"),
+                            }
+                        });
+                        continue;
+                    }
+                    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                    let client_stream = match self.uring.attach(client_stream) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            tracing::warn!("Dropping {client_addr}: {e:#}");
+                            continue;
+                        }
+                    };
+
+                    self.serve(&mut clients, client_stream, client_addr, slot);
+                }
+                Some((stream, client_addr, slot)) = handshaken_rx.recv() => {
+                    self.serve(&mut clients, stream, client_addr, slot);
+                }
+                // reap finished connections as we go
+                Some(_) = clients.join_next(), if !clients.is_empty() => {}
+                result = loaded(&mut loading) => match result {
+                    Ok(()) => tracing::info!("Done loading the dataset"),
+                    Err(e) => {
+                        tracing::error!("Failed loading the dataset: {e:#}");
+                        failed = Some(e);
+                        break ShutdownSave::NoSave;
+                    }
+                },
+                _ = received(&mut sigterm) => {
+                    tracing::warn!("Received SIGTERM, shutting down");
+                    break self.config.shutdown_on_sigterm;
+                }
+                _ = received(&mut sigint) => {
+                    tracing::warn!("Received SIGINT, shutting down");
+                    break self.config.shutdown_on_sigint;
+                }
+                _ = self.stop.cancelled() => {
+                    tracing::warn!("Shutdown requested");
+                    break self.config.shutdown_on_sigterm;
+                }
+            }
+        };
+        #[cfg(feature = "systemd")]
+        if self.signals {
+            systemd::stopping();
+        }
+        // stop accepting
+        acceptors.shutdown().await;
+        // saving a partly loaded dataset would lose the rest of it
+        let save = match loading {
+            Some(task) => {
+                task.abort();
+                ShutdownSave::NoSave
+            }
+            None => save,
+        };
+        self.shutdown(clients, save).await?;
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Serve the client connected over `stream` until it leaves, freeing its `slot` then
+    fn serve(
+        &self,
+        clients: &mut JoinSet<()>,
+        stream: impl Transport + 'static,
+        client_addr: SocketAddr,
+        slot: ClientSlot,
+    ) {
+        let client = RedisConnection::new(
+            stream,
+            client_addr,
+            self.db.clone(),
+            self.commands.clone(),
+            self.config.clone(),
+            self.replication.clone(),
+            self.expiration_tx.clone(),
+            self.propagator.clone(),
+            self.cluster.clone(),
+            self.shutdown.clone(),
+        );
+        let id = client.id();
+        tasks::spawn_in(clients, "connection", Some(id), async move {
+            client.client_loop().await;
+            drop(slot);
+        });
+    }
+
+    /// Stop accepting, let connections finish the commands they are running and answer them,
+    /// then save the dataset if asked to
+    async fn shutdown(self, mut clients: JoinSet<()>, save: ShutdownSave) -> Result<()> {
+        self.shutdown.cancel();
+
+        let drained = tokio::time::timeout(self.config.shutdown_timeout, async {
+            while clients.join_next().await.is_some() {}
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!(
+                "{} connections still busy after {:?}, closing them",
+                clients.len(),
+                self.config.shutdown_timeout
             );
+            clients.shutdown().await;
+        }
 
-            tokio::spawn(async move { client.client_loop().await });
+        if let Some(aof) = self.propagator.aof()
+            && let Err(e) = aof.flush()
+        {
+            tracing::error!("Failed writing the append-only file: {e}");
+        }
+        if save == ShutdownSave::Save {
+            tracing::info!("Saving the dataset before exiting");
+            let snapshot = loop {
+                match self.db.snapshot() {
+                    Some(snapshot) => break snapshot,
+                    // a BGSAVE or full resync is reading the dataset, wait for it to finish
+                    None => tokio::time::sleep(replication::master::SNAPSHOT_RETRY).await,
+                }
+            };
+            persistence::save(snapshot, self.config.rdb_path(), self.config.rdbcompression).await?;
         }
+        tracing::info!("Redis is now ready to exit, bye bye...");
         Ok(())
     }
 
-    async fn key_expirer(db: Arc<Database>, mut expiry_rx: Receiver<ExpiryEvent>) {
-        // binary min-heap to provide O(1) selection of next key to grab
-        let mut expiry_queue: BinaryHeap<Reverse<(Instant, RedisKey)>> =
-            BinaryHeap::with_capacity(INITIAL_CAPACITY);
+    /// Load the dataset, then start logging writes and following our master
+    async fn load(
+        config: Arc<Config>,
+        db: Arc<dyn Storage>,
+        commands: Arc<CommandRegistry>,
+        replication: Arc<ReplicationState>,
+        expiration_tx: UnboundedSender<ExpiryEvent>,
+        propagator: Arc<Propagator>,
+    ) -> Result<()> {
+        // with the append-only file on, the dataset is the one it logged
+        match config.appendonly {
+            true => aof::load(&config, db.clone(), commands.clone(), &expiration_tx).await?,
+            false => Self::load_rdb(&config, &*db, &expiration_tx).await?,
+        }
+        if config.appendonly {
+            let aof = Aof::open(&config, &*db).await?;
+            let flushed = aof.clone();
+            tasks::supervise("aof-flush", move || flushed.clone().flush_periodically());
+            propagator.set_aof(aof);
+        }
+        replication.set_loading(false);
+
+        if let Some((host, port)) = config.replicaof.clone() {
+            replica::start(
+                host,
+                port,
+                db,
+                commands,
+                config,
+                replication,
+                expiration_tx,
+                propagator,
+            );
+        }
+        Ok(())
+    }
+
+    /// Load the dataset from the configured RDB file, if one exists
+    async fn load_rdb(
+        config: &Config,
+        db: &dyn Storage,
+        expiration_tx: &UnboundedSender<ExpiryEvent>,
+    ) -> Result<()> {
+        let path = config.rdb_path();
+        let data = match tokio::fs::read(&path).await {
+            Ok(data) => Bytes::from(data),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::info!("No RDB file at {path:?}, starting with an empty dataset");
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        persistence::load(db, expiration_tx, &data)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed loading RDB file {path:?}: {e}"))
+    }
+
+    /// Remove keys as they expire, propagating each removal as a DEL.
+    ///
+    /// Replicas never expire keys themselves (reads already hide them), they wait for the DEL
+    /// from their master so both sides agree on when a key disappeared.
+    async fn key_expirer(
+        db: Arc<dyn Storage>,
+        replication: Arc<ReplicationState>,
+        propagator: Arc<Propagator>,
+        expiry_rx: Arc<tokio::sync::Mutex<UnboundedReceiver<ExpiryEvent>>>,
+    ) {
+        let mut expiry_rx = expiry_rx.lock().await;
+        // a restart picks up the expirations scheduled before, from the keys themselves
+        let mut wheel = TimerWheel::new(db.clock().now_ms());
+        for key in db.keys() {
+            if let Some(at) = db.get_key_expiration(&key) {
+                wheel.insert(at, key);
+            }
+        }
 
         // loop over events received on channel for expiration updates or the timeout
         loop {
-            let next_expiry = expiry_queue.peek().map(|Reverse((time, _))| *time);
+            let next_expiry = wheel.next_deadline();
 
             tokio::select! {
-                Some(event) = expiry_rx.recv() => {
-                    tracing::info!("Received new expiration event: {event:?}");
-                    expiry_queue.push(Reverse(event));
+                Some((at, key)) = expiry_rx.recv() => {
+                    tracing::debug!("Received new expiration event: {key:?} at {at:?}");
+                    match at {
+                        Some(at) => wheel.insert(at, key),
+                        None => wheel.cancel(&key),
+                    }
+                    tracing::trace!("{} expirations scheduled", wheel.len());
                 },
                 _ = async {
                     if let Some(time) = next_expiry {
-                        tracing::info!("Waiting until next expiration");
-                        sleep_until(tokio::time::Instant::from_std(time)).await;
+                        // a wall-clock deadline, slept until on the monotonic clock
+                        db.clock().sleep_until(db.clock().deadline(time)).await;
                     } else {
-                        tracing::info!("No keys that will expire! Waiting forever");
                         std::future::pending::<()>().await;
                     }
                 } => {
-                    let now = Instant::now();
-                    while let Some(Reverse(exp_evt)) = expiry_queue.peek() {
-                        let expire_time = exp_evt.0;
-                        if expire_time > now {
-                            // done, we've processed all events
-                            break;
-                        }
-                        // we know it is expired now, so remove the key if this event is one that
-                        // matches the true value in the db
-                        let key = expiry_queue.pop().unwrap().0.1;
+                    if replication.is_replica() {
+                        // drop what is due, our master will send DELs for these keys
+                        wheel.advance(db.clock().now_ms());
+                        continue;
+                    }
+                    // the removal and its DEL must not interleave with other writes
+                    let _write_guard = replication.write_lock().await;
+                    let now = db.clock().now_ms();
+                    for (expire_time, key) in wheel.advance(now) {
+                        // remove the key if this event is one that matches the true value in the db
                         let Some(true_exp) = db.get_key_expiration(&key) else {
                             continue;
                         };
                         if expire_time == true_exp {
                             // now we actually remove from the db, this is a real event
-                            db.remove_key(&key);
+                            db.delete_expired(&key, now);
+                            propagator.propagate(Write::single(
+                                RedisValue::command([Bytes::from("DEL"), key.clone()]),
+                                KeyEvent::Expired(key.clone()),
+                            ));
                             tracing::info!("Expired key: {key:?}");
                         } else {
                             tracing::info!("Skipping key with stale expiration");
@@ -0,0 +1,404 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write as _},
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use bytes::{Bytes, BytesMut};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_util::codec::Framed;
+
+use crate::{
+    command::{registry::CommandRegistry, table, table::CommandFlags},
+    connection::{ClientStream, RedisConnection},
+    replication::{ReplicationState, Role},
+    resp::{
+        codec::{Protocol, RespFrame},
+        RedisValue,
+    },
+    server::{
+        config::{AppendFsync, Config},
+        persistence,
+        propagation::{Propagator, Sink, Write},
+        storage::{Snapshot, Storage},
+        tasks,
+        types::ExpiryEvent,
+    },
+};
+
+pub(crate) mod manifest;
+
+use manifest::{Manifest, Part};
+
+/// How often appended writes are handed to the OS, and synced with `appendfsync everysec`
+const FLUSH_PERIOD: Duration = Duration::from_secs(1);
+
+/// An RDB base starts like any RDB file, a base of commands doesn't
+const RDB_MAGIC: &[u8] = b"REDIS";
+
+/// The append-only file, in the multi-part layout of Redis 7: a directory holding a base RDB,
+/// incremental files of the writes made since and a manifest naming them. A rewrite writes a
+/// new base while writes go to a new incremental file, then swaps the manifest over to them.
+pub(crate) struct Aof {
+    dir: PathBuf,
+    filename: String,
+    fsync: AppendFsync,
+    /// Whether bases are written with long strings LZF compressed
+    compress: bool,
+    state: Mutex<State>,
+    rewriting: AtomicBool,
+}
+
+struct State {
+    manifest: Manifest,
+    /// The incremental file writes are appended to
+    file: File,
+    /// Writes not yet handed to `file`
+    buf: BytesMut,
+}
+
+impl State {
+    /// Hand the buffered writes to the OS
+    fn write_out(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.file.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+}
+
+/// A rewrite under way: the dataset as of its start, and where its results go
+pub(crate) struct Rewrite {
+    snapshot: Box<dyn Snapshot>,
+    base: Part,
+    /// The incremental file started along with it, the first the new base is followed by
+    first_incr: u64,
+}
+
+impl Aof {
+    /// Open the append-only file configured, creating it with a base of `db` if there is none
+    pub(crate) async fn open(config: &Config, db: &dyn Storage) -> Result<Arc<Self>> {
+        let dir = config.aof_dir();
+        fs::create_dir_all(&dir)?;
+        let mut manifest =
+            Manifest::load(&manifest_path(&dir, &config.appendfilename))?.unwrap_or_default();
+        let fresh = manifest.base.is_none() && manifest.incrs.is_empty();
+        if fresh {
+            // as Redis does on starting without one, so the directory is complete from the start
+            let base = manifest.next_base(&config.appendfilename);
+            let snapshot = db
+                .snapshot()
+                .ok_or(anyhow::anyhow!("Dataset snapshot already in progress"))?;
+            write_base(&dir, &base, snapshot, config.rdbcompression).await?;
+            manifest.base = Some(base);
+        }
+        let incr = match manifest.incrs.last() {
+            Some(incr) => incr.clone(),
+            None => manifest.next_incr(&config.appendfilename).clone(),
+        };
+        let file = append_to(&dir.join(&incr.name))?;
+        manifest.save(&manifest_path(&dir, &config.appendfilename))?;
+        tracing::info!("Appending writes to {:?}", dir.join(&incr.name));
+        Ok(Arc::new(Self {
+            dir,
+            filename: config.appendfilename.clone(),
+            fsync: config.appendfsync,
+            compress: config.rdbcompression,
+            state: Mutex::new(State {
+                manifest,
+                file,
+                buf: BytesMut::new(),
+            }),
+            rewriting: AtomicBool::new(false),
+        }))
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        manifest_path(&self.dir, &self.filename)
+    }
+
+    /// Hand the writes buffered to the OS, then sync them to disk unless `appendfsync no`
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        let file = {
+            let mut state = self.state.lock().unwrap();
+            if state.buf.is_empty() {
+                return Ok(());
+            }
+            state.write_out()?;
+            // synced without the lock, so writes carry on meanwhile
+            state.file.try_clone()?
+        };
+        match self.fsync {
+            AppendFsync::No => Ok(()),
+            _ => file.sync_data(),
+        }
+    }
+
+    /// Flush the writes buffered every second, as the `aof-flush` task
+    pub(crate) async fn flush_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(FLUSH_PERIOD);
+        loop {
+            interval.tick().await;
+            let aof = self.clone();
+            match tasks::offload("aof-flush", move || aof.flush()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::error!("Failed writing the append-only file: {e}"),
+                Err(e) => tracing::error!("Append-only file flush panicked: {e}"),
+            }
+        }
+    }
+
+    /// Start a rewrite: writes go to a new incremental file from now on, and the new base will
+    /// hold the dataset as of now. Must be called holding the write lock, so no write falls
+    /// between the two.
+    pub(crate) fn start_rewrite(&self, db: &dyn Storage) -> Result<Rewrite> {
+        if self.rewriting.swap(true, Ordering::AcqRel) {
+            return Err(anyhow::anyhow!(
+                "Background append only file rewriting already in progress"
+            ));
+        }
+        let started = (|| {
+            let snapshot = db
+                .snapshot()
+                .ok_or(anyhow::anyhow!("Background save already in progress"))?;
+            let mut state = self.state.lock().unwrap();
+            state.write_out()?;
+            let base = state.manifest.next_base(&self.filename);
+            let incr = state.manifest.next_incr(&self.filename).clone();
+            state.file = append_to(&self.dir.join(&incr.name))?;
+            // the new file is replayed after the old ones should the rewrite not finish
+            state.manifest.save(&self.manifest_path())?;
+            Ok(Rewrite {
+                snapshot,
+                base,
+                first_incr: incr.seq,
+            })
+        })();
+        if started.is_err() {
+            self.rewriting.store(false, Ordering::Release);
+        }
+        started
+    }
+
+    /// Write the base of `rewrite`, then swap the manifest over to it and delete the files it
+    /// replaces
+    pub(crate) async fn finish_rewrite(&self, rewrite: Rewrite) -> Result<()> {
+        let Rewrite {
+            snapshot,
+            base,
+            first_incr,
+        } = rewrite;
+        let finished = async {
+            write_base(&self.dir, &base, snapshot, self.compress).await?;
+            let old = {
+                let mut state = self.state.lock().unwrap();
+                let old = state.manifest.rebase(base, first_incr);
+                state.manifest.save(&self.manifest_path())?;
+                old
+            };
+            for part in old {
+                if let Err(e) = fs::remove_file(self.dir.join(&part.name)) {
+                    tracing::warn!("Failed deleting old AOF file {}: {e}", part.name);
+                }
+            }
+            Ok(())
+        }
+        .await;
+        self.rewriting.store(false, Ordering::Release);
+        finished
+    }
+
+    /// Rewrite the append-only file from `db` in the background, as BGREWRITEAOF does
+    pub(crate) async fn rewrite(
+        self: &Arc<Self>,
+        db: &dyn Storage,
+        replication: &ReplicationState,
+    ) -> Result<()> {
+        let rewrite = {
+            let _write_guard = replication.write_lock().await;
+            self.start_rewrite(db)?
+        };
+        let aof = self.clone();
+        tasks::spawn("aof-rewrite", None, async move {
+            match aof.finish_rewrite(rewrite).await {
+                Ok(()) => tracing::info!("Append-only file rewrite finished"),
+                Err(e) => tracing::error!("Append-only file rewrite failed: {e:?}"),
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Whether a command changes the dataset, and so belongs in the append-only file. Anything else
+/// passed on to replicas, such as a PING from our master, doesn't.
+fn logged(command: &RedisValue) -> bool {
+    let RedisValue::Array(args) = command else {
+        return false;
+    };
+    args.first()
+        .and_then(RedisValue::as_bulk_string)
+        .and_then(|name| table::lookup(name))
+        .is_some_and(|spec| spec.flags.contains(CommandFlags::WRITE))
+}
+
+impl Sink for Aof {
+    fn accepts(&self, write: &Write) -> bool {
+        !write.commands.is_empty()
+    }
+
+    fn write(&self, write: &Write) {
+        let mut state = self.state.lock().unwrap();
+        for command in write.commands.iter().filter(|command| logged(command)) {
+            if let Err(e) =
+                RespFrame::encode_value(command.clone(), Protocol::Resp2, &mut state.buf)
+            {
+                tracing::error!("Failed to encode command for the append-only file: {e:?}");
+            }
+        }
+        if self.fsync == AppendFsync::Always && !state.buf.is_empty() {
+            // before the write is answered, as Redis does
+            if let Err(e) = state.write_out().and_then(|()| state.file.sync_data()) {
+                tracing::error!("Failed writing the append-only file: {e}");
+            }
+        }
+    }
+}
+
+fn manifest_path(dir: &Path, filename: &str) -> PathBuf {
+    dir.join(format!("{filename}.manifest"))
+}
+
+fn append_to(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Write `snapshot` as the RDB `base`, under a temporary name until complete
+async fn write_base(
+    dir: &Path,
+    base: &Part,
+    snapshot: Box<dyn Snapshot>,
+    compress: bool,
+) -> Result<()> {
+    let data = persistence::encode(snapshot, compress).await?;
+    let tmp = dir.join(format!("temp-{}", base.name));
+    tokio::fs::write(&tmp, &data).await?;
+    tokio::fs::rename(&tmp, dir.join(&base.name)).await?;
+    tracing::info!("Wrote {} byte AOF base {}", data.len(), base.name);
+    Ok(())
+}
+
+/// Load the dataset from the append-only file configured: its base, then its incremental files
+/// in order
+pub(crate) async fn load(
+    config: &Config,
+    db: Arc<dyn Storage>,
+    commands: Arc<CommandRegistry>,
+    expiration_tx: &UnboundedSender<ExpiryEvent>,
+) -> Result<()> {
+    let dir = config.aof_dir();
+    let Some(manifest) = Manifest::load(&manifest_path(&dir, &config.appendfilename))? else {
+        tracing::info!("No append-only file in {dir:?}, starting with an empty dataset");
+        return Ok(());
+    };
+    if let Some(base) = &manifest.base {
+        let path = dir.join(&base.name);
+        let data = Bytes::from(tokio::fs::read(&path).await?);
+        match data.starts_with(RDB_MAGIC) {
+            true => persistence::load(&*db, expiration_tx, &data)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed loading AOF base {path:?}: {e}"))?,
+            false => replay(&path, config, &db, &commands, expiration_tx).await?,
+        }
+    }
+    for incr in &manifest.incrs {
+        replay(&dir.join(&incr.name), config, &db, &commands, expiration_tx).await?;
+    }
+    tracing::info!("Loaded the append-only file in {dir:?}");
+    Ok(())
+}
+
+/// Apply the commands in the file at `path`, as a replica applies its master's
+async fn replay(
+    path: &Path,
+    config: &Config,
+    db: &Arc<dyn Storage>,
+    commands: &Arc<CommandRegistry>,
+    expiration_tx: &UnboundedSender<ExpiryEvent>,
+) -> Result<()> {
+    let file = match tokio::fs::File::open(path).await {
+        Ok(file) => file,
+        // created on opening, so a crash may leave it out
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    tracing::info!("Replaying {path:?}");
+    // the writes being loaded are no news to replicas, clients or watchers
+    let replication = Arc::new(ReplicationState::new(Role::Master));
+    // replies go nowhere: flushing the file itself would complete a read in flight and take
+    // the wakeup of the half waiting on it
+    let stream = tokio::io::join(file, tokio::io::sink());
+    let link = RedisConnection::master_link(
+        Framed::new(
+            Box::new(stream) as ClientStream,
+            RespFrame::with_limits(config.proto_limits()),
+        ),
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        db.clone(),
+        commands.clone(),
+        Arc::new(config.clone()),
+        replication.clone(),
+        expiration_tx.clone(),
+        Arc::new(Propagator::new(replication)),
+    );
+    link.client_loop().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::types::{Database, Value};
+
+    #[tokio::test]
+    async fn appends_writes_and_rewrites() {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}", std::process::id()));
+        let config = Config {
+            dir: dir.clone(),
+            appendfsync: AppendFsync::Always,
+            ..Config::default()
+        };
+        let db = Database::default();
+        db.set_key(&"before".into(), Value::new("x".into(), None));
+        let aof = Aof::open(&config, &db).await.unwrap();
+        let aof_dir = config.aof_dir();
+        assert!(aof_dir.join("appendonly.aof.1.base.rdb").exists());
+
+        let set = RedisValue::command(["SET", "k", "v"]);
+        let ping = RedisValue::command(["PING"]);
+        aof.write(&Write::new(vec![set, ping], Vec::new()));
+        let incr = std::fs::read(aof_dir.join("appendonly.aof.1.incr.aof")).unwrap();
+        assert_eq!(incr, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n");
+
+        let rewrite = aof.start_rewrite(&db).unwrap();
+        assert!(aof.start_rewrite(&db).is_err());
+        aof.finish_rewrite(rewrite).await.unwrap();
+        assert!(!aof.rewriting.load(Ordering::Acquire));
+        let manifest = std::fs::read_to_string(aof_dir.join("appendonly.aof.manifest")).unwrap();
+        assert_eq!(
+            manifest,
+            "file appendonly.aof.2.base.rdb seq 2 type b\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert!(!aof_dir.join("appendonly.aof.1.incr.aof").exists());
+        assert!(!aof_dir.join("appendonly.aof.1.base.rdb").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
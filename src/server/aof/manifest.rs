@@ -0,0 +1,225 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+
+use crate::server::config::file::split_args;
+
+/// What a part of the append-only file holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    /// The dataset as of a rewrite, as an RDB or as commands recreating it
+    Base,
+    /// Writes made after the base, replayed in order
+    Incr,
+    /// Left behind by a rewrite, only waiting to be deleted
+    History,
+}
+
+impl Kind {
+    fn code(self) -> &'static str {
+        match self {
+            Self::Base => "b",
+            Self::Incr => "i",
+            Self::History => "h",
+        }
+    }
+}
+
+/// A file of the append-only file, named relative to its directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Part {
+    pub(crate) name: String,
+    pub(crate) seq: u64,
+    pub(crate) kind: Kind,
+}
+
+/// The manifest of a Redis 7 append-only file, naming its parts a line each, e.g.
+/// `file appendonly.aof.1.base.rdb seq 1 type b`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub(crate) base: Option<Part>,
+    /// Incremental parts, oldest first
+    pub(crate) incrs: Vec<Part>,
+    pub(crate) history: Vec<Part>,
+    /// Highest sequence numbers handed out, so names are never reused
+    base_seq: u64,
+    incr_seq: u64,
+}
+
+impl Manifest {
+    /// The manifest at `path`, `None` if there is none
+    pub(crate) fn load(path: &Path) -> Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text)
+                .with_context(|| format!("Bad AOF manifest {}", path.display()))
+                .map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub(crate) fn parse(text: &str) -> Result<Self> {
+        let mut manifest = Self::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let args = split_args(line)?;
+            if args.len() % 2 != 0 {
+                return Err(anyhow::anyhow!("Invalid AOF manifest file format"));
+            }
+            let (mut name, mut seq, mut kind) = (None, None, None);
+            // keys may come in any order, and future ones are skipped like Redis does
+            for pair in args.chunks(2) {
+                match pair[0].as_str() {
+                    "file" => name = Some(pair[1].clone()),
+                    "seq" => seq = Some(pair[1].parse::<u64>()?),
+                    "type" => kind = Some(pair[1].clone()),
+                    _ => {}
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(anyhow::anyhow!("Invalid AOF manifest file format"));
+            };
+            match kind.as_str() {
+                "b" if manifest.base.is_some() => {
+                    return Err(anyhow::anyhow!("Found duplicate base file information"));
+                }
+                "b" => {
+                    manifest.base_seq = manifest.base_seq.max(seq);
+                    manifest.base = Some(Part {
+                        name,
+                        seq,
+                        kind: Kind::Base,
+                    });
+                }
+                "i" if seq <= manifest.incr_seq && !manifest.incrs.is_empty() => {
+                    return Err(anyhow::anyhow!("Found a non-monotonic sequence number"));
+                }
+                "i" => {
+                    manifest.incr_seq = seq;
+                    manifest.incrs.push(Part {
+                        name,
+                        seq,
+                        kind: Kind::Incr,
+                    });
+                }
+                "h" => manifest.history.push(Part {
+                    name,
+                    seq,
+                    kind: Kind::History,
+                }),
+                kind => return Err(anyhow::anyhow!("Unknown AOF file type: {kind}")),
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// The manifest as written to disk: the base, then the history, then the incremental parts
+    pub(crate) fn render(&self) -> String {
+        self.base
+            .iter()
+            .chain(&self.history)
+            .chain(&self.incrs)
+            .map(|part| {
+                // quoted as Redis would when the name would not read back as one argument
+                let name = match part.name.contains(|c: char| {
+                    c.is_whitespace() || c.is_control() || matches!(c, '"' | '\'' | '\\')
+                }) {
+                    true => format!("{:?}", part.name),
+                    false => part.name.clone(),
+                };
+                format!("file {name} seq {} type {}\n", part.seq, part.kind.code())
+            })
+            .collect()
+    }
+
+    /// Write the manifest to `path`, replacing the previous one at once so a crash leaves
+    /// either in place
+    pub(crate) fn save(&self, path: &Path) -> io::Result<()> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = path.with_file_name(format!("temp-{name}"));
+        let mut file = File::create(&tmp)?;
+        file.write_all(self.render().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    }
+
+    /// A name for the base of the next rewrite, of the append-only file named `filename`
+    pub(crate) fn next_base(&mut self, filename: &str) -> Part {
+        self.base_seq += 1;
+        Part {
+            name: format!("{filename}.{}.base.rdb", self.base_seq),
+            seq: self.base_seq,
+            kind: Kind::Base,
+        }
+    }
+
+    /// Start a new incremental part, of the append-only file named `filename`
+    pub(crate) fn next_incr(&mut self, filename: &str) -> &Part {
+        self.incr_seq += 1;
+        self.incrs.push(Part {
+            name: format!("{filename}.{}.incr.aof", self.incr_seq),
+            seq: self.incr_seq,
+            kind: Kind::Incr,
+        });
+        self.incrs.last().unwrap()
+    }
+
+    /// Make `base` the base, once written, with the incremental parts started before it became
+    /// history. Returns the parts no longer needed.
+    pub(crate) fn rebase(&mut self, base: Part, first_incr: u64) -> Vec<Part> {
+        let mut old: Vec<Part> = self.base.replace(base).into_iter().collect();
+        old.extend(self.incrs.extract_if(.., |part| part.seq < first_incr));
+        old.append(&mut self.history);
+        old
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_what_redis_writes() {
+        let manifest = Manifest::parse(
+            "file appendonly.aof.3.base.rdb seq 3 type b\n\
+             file appendonly.aof.5.incr.aof seq 5 type i\n\
+             file \"my file.6.incr.aof\" type i seq 6\n",
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.base.as_ref().unwrap().name,
+            "appendonly.aof.3.base.rdb"
+        );
+        assert_eq!(manifest.incrs.len(), 2);
+        assert_eq!(manifest.incrs[1].name, "my file.6.incr.aof");
+        assert_eq!(Manifest::parse(&manifest.render()).unwrap(), manifest);
+
+        assert!(Manifest::parse("file a seq 1").is_err());
+        assert!(Manifest::parse("file a seq 1 type b\nfile b seq 2 type b").is_err());
+        assert!(Manifest::parse("file a seq 2 type i\nfile b seq 1 type i").is_err());
+    }
+
+    #[test]
+    fn rewrites_are_manifest_swaps() {
+        let mut manifest = Manifest::default();
+        manifest.next_incr("appendonly.aof");
+        // a rewrite starts a new incremental part, writes to which are kept
+        let base = manifest.next_base("appendonly.aof");
+        let first = manifest.next_incr("appendonly.aof").seq;
+        let old = manifest.rebase(base, first);
+        assert_eq!(
+            manifest.render(),
+            "file appendonly.aof.1.base.rdb seq 1 type b\n\
+             file appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert_eq!(old.len(), 1);
+        assert_eq!(old[0].name, "appendonly.aof.1.incr.aof");
+    }
+}
@@ -0,0 +1,210 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        LazyLock, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::sync::oneshot;
+
+use crate::server::types::RedisKey;
+
+/// Why a blocked client stopped waiting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Wakeup {
+    /// This key may now have what the client was waiting for
+    Ready(RedisKey),
+    /// The timeout passed first
+    TimedOut,
+    /// The wait was called off, e.g. the client disconnected
+    Unblocked,
+}
+
+/// A client's wait on some keys
+struct Wait {
+    client: u64,
+    keys: Vec<RedisKey>,
+    wake: oneshot::Sender<Wakeup>,
+}
+
+/// Clients blocked on keys. There is only database 0, so keys alone identify what is waited on.
+#[derive(Default)]
+struct Registry {
+    /// Waits by ticket, in the order they began
+    waits: HashMap<u64, Wait>,
+    /// Tickets of the waits on each key, longest waiting first
+    queues: HashMap<RedisKey, VecDeque<u64>>,
+    next_ticket: u64,
+}
+
+impl Registry {
+    /// Take a wait out of every queue it is in
+    fn remove(&mut self, ticket: u64) -> Option<Wait> {
+        let wait = self.waits.remove(&ticket)?;
+        for key in &wait.keys {
+            if let Some(queue) = self.queues.get_mut(key) {
+                queue.retain(|t| *t != ticket);
+                if queue.is_empty() {
+                    self.queues.remove(key);
+                }
+            }
+        }
+        BLOCKED.fetch_sub(1, Ordering::Relaxed);
+        Some(wait)
+    }
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(Default::default);
+
+/// Waits in the registry, so writes can skip the lock while nobody is blocked
+static BLOCKED: AtomicUsize = AtomicUsize::new(0);
+
+/// A blocked client's place in the queues of the keys it waits on. Dropping it, as happens when
+/// a connection goes away mid-command, gives the place up.
+pub(crate) struct Blocked {
+    ticket: u64,
+    woken: oneshot::Receiver<Wakeup>,
+    timeout: Option<Duration>,
+}
+
+impl Blocked {
+    /// Wait to be woken by a write to one of the keys, or for the timeout to pass
+    pub(crate) async fn wait(mut self) -> Wakeup {
+        let woken = &mut self.woken;
+        let wakeup = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, woken).await.ok(),
+            None => Some(woken.await),
+        };
+        // the sender is only dropped along with the wait, after it was woken
+        wakeup.map_or(Wakeup::TimedOut, |wakeup| {
+            wakeup.unwrap_or(Wakeup::Unblocked)
+        })
+    }
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        REGISTRY.lock().unwrap().remove(self.ticket);
+    }
+}
+
+/// Block `client` until one of `keys` is [signalled](signal), for at most `timeout`. Clients
+/// blocked on the same key are woken in the order they blocked.
+pub(crate) fn block(client: u64, keys: &[RedisKey], timeout: Option<Duration>) -> Blocked {
+    let (wake, woken) = oneshot::channel();
+    let mut registry = REGISTRY.lock().unwrap();
+    let ticket = registry.next_ticket;
+    registry.next_ticket += 1;
+    for key in keys {
+        let queue = registry.queues.entry(key.clone()).or_default();
+        // a key named twice only needs one place in its queue
+        if queue.back() != Some(&ticket) {
+            queue.push_back(ticket);
+        }
+    }
+    registry.waits.insert(
+        ticket,
+        Wait {
+            client,
+            keys: keys.to_vec(),
+            wake,
+        },
+    );
+    BLOCKED.fetch_add(1, Ordering::Relaxed);
+    Blocked {
+        ticket,
+        woken,
+        timeout,
+    }
+}
+
+/// Wake the client that has waited longest on `key`, after a write that may have given it what
+/// it waits for. A woken client that leaves the key ready for more should signal it again, so
+/// the next in line is served.
+pub(crate) fn signal(key: &RedisKey) {
+    if BLOCKED.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let mut registry = REGISTRY.lock().unwrap();
+    let Some(&ticket) = registry.queues.get(key).and_then(VecDeque::front) else {
+        return;
+    };
+    if let Some(wait) = registry.remove(ticket) {
+        let _ = wait.wake.send(Wakeup::Ready(key.clone()));
+    }
+}
+
+/// Call off every wait of `client`, returning whether it was blocked
+pub(crate) fn unblock(client: u64) -> bool {
+    if BLOCKED.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    let mut registry = REGISTRY.lock().unwrap();
+    let tickets: Vec<u64> = registry
+        .waits
+        .iter()
+        .filter(|(_, wait)| wait.client == client)
+        .map(|(ticket, _)| *ticket)
+        .collect();
+    for &ticket in &tickets {
+        if let Some(wait) = registry.remove(ticket) {
+            let _ = wait.wake.send(Wakeup::Unblocked);
+        }
+    }
+    !tickets.is_empty()
+}
+
+/// Waits in progress, as INFO's `blocked_clients`
+pub(crate) fn blocked_clients() -> usize {
+    BLOCKED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the registry is process wide, so every test uses keys and client IDs of its own
+
+    #[tokio::test]
+    async fn wakes_waiters_in_order() {
+        let key = RedisKey::from("blocking:order");
+        let first = block(u64::MAX - 1, std::slice::from_ref(&key), None);
+        let second = block(u64::MAX - 2, &[key.clone(), "blocking:other".into()], None);
+        signal(&key);
+        assert_eq!(first.wait().await, Wakeup::Ready(key.clone()));
+        signal(&key);
+        assert_eq!(second.wait().await, Wakeup::Ready(key.clone()));
+        // nobody is left to wake
+        signal(&key);
+        assert!(!REGISTRY.lock().unwrap().queues.contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn times_out_and_cleans_up() {
+        let key = RedisKey::from("blocking:timeout");
+        let blocked = block(
+            u64::MAX - 3,
+            std::slice::from_ref(&key),
+            Some(Duration::ZERO),
+        );
+        assert_eq!(blocked.wait().await, Wakeup::TimedOut);
+        assert!(!REGISTRY.lock().unwrap().queues.contains_key(&key));
+
+        // a client that went away gives up its place to the next one
+        let gone = block(u64::MAX - 4, std::slice::from_ref(&key), None);
+        let next = block(u64::MAX - 5, std::slice::from_ref(&key), None);
+        drop(gone);
+        signal(&key);
+        assert_eq!(next.wait().await, Wakeup::Ready(key));
+    }
+
+    #[tokio::test]
+    async fn unblocks_a_client() {
+        let blocked = block(u64::MAX - 6, &["blocking:unblock".into()], None);
+        assert!(unblock(u64::MAX - 6));
+        assert!(!unblock(u64::MAX - 6));
+        assert_eq!(blocked.wait().await, Wakeup::Unblocked);
+    }
+}
@@ -0,0 +1,285 @@
+//! Offline checks of the files the server writes, as redis-check-rdb and redis-check-aof do:
+//! `--check-rdb <file>` and `--check-aof [--fix] <file>` run them instead of a server.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{self, OpenOptions},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::Decoder;
+
+use crate::{
+    rdb::{self, RDB_CHECKSUM_VERSION, RDB_MAGIC},
+    resp::{codec::RespFrame, RedisValue},
+    server::aof::manifest::Manifest,
+};
+
+/// What a check found, a line each
+#[derive(Debug, Default)]
+pub struct Report {
+    lines: Vec<String>,
+}
+
+impl Report {
+    fn note(&mut self, line: impl Into<String>) {
+        self.lines.push(line.into());
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.lines.iter().try_for_each(|line| writeln!(f, "{line}"))
+    }
+}
+
+/// Check the RDB file at `path`: its structure, every value in it and its checksum
+pub fn check_rdb(path: &Path) -> Result<Report> {
+    let data = Bytes::from(fs::read(path).with_context(|| format!("Can't read {path:?}"))?);
+    let mut report = Report::default();
+    report.note(format!("Checking RDB file {}", path.display()));
+    rdb_summary(&data, &mut report).with_context(|| format!("Bad RDB file {path:?}"))?;
+    report.note("RDB looks OK");
+    Ok(report)
+}
+
+fn rdb_summary(data: &Bytes, report: &mut Report) -> Result<()> {
+    let rdb = rdb::parse(data)?;
+    report.note(format!("RDB version {}", rdb.version));
+    for (key, value) in &rdb.aux {
+        report.note(format!(
+            "aux {} = {}",
+            String::from_utf8_lossy(key),
+            String::from_utf8_lossy(value)
+        ));
+    }
+    // keys and those with an expiration, per database
+    let mut dbs = BTreeMap::<u64, (usize, usize)>::new();
+    for entry in &rdb.entries {
+        let (keys, expires) = dbs.entry(entry.db).or_default();
+        *keys += 1;
+        *expires += entry.expiration_ms.is_some() as usize;
+    }
+    for (db, (keys, expires)) in dbs {
+        report.note(format!(
+            "db {db}: {keys} keys, {expires} with an expiration"
+        ));
+    }
+    let checksum = data.len().checked_sub(8).map(|at| &data[at..]);
+    report.note(match checksum {
+        _ if rdb.version < RDB_CHECKSUM_VERSION => "no checksum, the version predates them",
+        Some([0, 0, 0, 0, 0, 0, 0, 0]) => "checksum disabled by the writer",
+        _ => "checksum OK",
+    });
+    Ok(())
+}
+
+/// Check the append-only file at `path`, either the manifest of a multi-part one or a single
+/// file of it. With `fix`, a file cut short by a crash is truncated to its last complete
+/// command, which only the last file written to may be.
+pub fn check_aof(path: &Path, fix: bool) -> Result<Report> {
+    let mut report = Report::default();
+    let is_manifest = path.extension().is_some_and(|ext| ext == "manifest");
+    if !is_manifest {
+        check_aof_file(path, fix, &mut report)?;
+        report.note("AOF is valid");
+        return Ok(report);
+    }
+
+    let manifest = Manifest::load(path)?.with_context(|| format!("No manifest at {path:?}"))?;
+    report.note(format!("Checking AOF manifest {}", path.display()));
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let parts: Vec<_> = manifest.base.iter().chain(&manifest.incrs).collect();
+    for (i, part) in parts.iter().enumerate() {
+        let last = i + 1 == parts.len();
+        check_aof_file(&dir.join(&part.name), fix && last, &mut report)?;
+    }
+    report.note("AOF is valid");
+    Ok(report)
+}
+
+fn check_aof_file(path: &Path, fix: bool, report: &mut Report) -> Result<()> {
+    let data = fs::read(path).with_context(|| format!("Can't read {path:?}"))?;
+    if data.starts_with(RDB_MAGIC) {
+        report.note(format!("Checking RDB base {}", path.display()));
+        return rdb_summary(&Bytes::from(data), report)
+            .with_context(|| format!("Bad AOF base {path:?}"));
+    }
+    report.note(format!("Checking AOF file {}", path.display()));
+    match scan(&data) {
+        Scan::Complete { commands } => {
+            report.note(format!("{commands} commands in {} bytes", data.len()));
+            Ok(())
+        }
+        Scan::Truncated { valid, commands } if fix => {
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(valid as u64)?;
+            report.note(format!(
+                "Truncated {} from {} to {valid} bytes, keeping {commands} commands",
+                path.display(),
+                data.len()
+            ));
+            Ok(())
+        }
+        Scan::Truncated { valid, .. } => Err(anyhow::anyhow!(
+            "AOF {path:?} is truncated after offset {valid} of {} bytes, run with --fix to \
+             truncate it there (only the last file written to may be fixed)",
+            data.len()
+        )),
+        Scan::Corrupt { offset, reason } => Err(anyhow::anyhow!(
+            "AOF {path:?} is corrupt at offset {offset}: {reason}"
+        )),
+    }
+}
+
+/// How far a file of commands reads
+#[derive(Debug, PartialEq, Eq)]
+enum Scan {
+    Complete {
+        commands: usize,
+    },
+    /// Ends with a command cut short or a MULTI without its EXEC, as a crash leaves it, so the
+    /// first `valid` bytes are all that can be replayed
+    Truncated {
+        valid: usize,
+        commands: usize,
+    },
+    /// Holds something no truncation explains
+    Corrupt {
+        offset: usize,
+        reason: String,
+    },
+}
+
+fn scan(data: &[u8]) -> Scan {
+    let mut codec = RespFrame::default();
+    let mut buf = BytesMut::from(data);
+    let mut commands = 0;
+    // where the open transaction started, and how many commands preceded it
+    let mut multi = None;
+    loop {
+        let offset = data.len() - buf.len();
+        let frame = match codec.decode(&mut buf) {
+            Ok(Some(frame)) => frame,
+            Ok(None) if buf.is_empty() => break,
+            Ok(None) => {
+                let (valid, commands) = multi.unwrap_or((offset, commands));
+                return Scan::Truncated { valid, commands };
+            }
+            Err(e) => {
+                let reason = e.to_string();
+                return Scan::Corrupt { offset, reason };
+            }
+        };
+        let name = match frame {
+            RedisValue::Array(args) => match args.first() {
+                Some(RedisValue::BulkString(name)) => name.to_ascii_uppercase(),
+                _ => {
+                    let reason = "expected a command name".to_string();
+                    return Scan::Corrupt { offset, reason };
+                }
+            },
+            _ => {
+                let reason = "expected a command".to_string();
+                return Scan::Corrupt { offset, reason };
+            }
+        };
+        match name.as_slice() {
+            b"MULTI" => multi = Some((offset, commands)),
+            b"EXEC" => multi = None,
+            _ => {}
+        }
+        commands += 1;
+    }
+    match multi {
+        Some((valid, commands)) => Scan::Truncated { valid, commands },
+        None => Scan::Complete { commands },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rdb::{RdbEntry, RdbValue};
+
+    const SET: &[u8] = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+
+    #[test]
+    fn tells_truncated_files_from_corrupt_ones() {
+        assert_eq!(scan(SET), Scan::Complete { commands: 1 });
+        let cut = [SET, &SET[..10]].concat();
+        assert_eq!(
+            scan(&cut),
+            Scan::Truncated {
+                valid: SET.len(),
+                commands: 1
+            }
+        );
+        let open_multi = [SET, b"*1\r\n$5\r\nMULTI\r\n", SET].concat();
+        assert_eq!(
+            scan(&open_multi),
+            Scan::Truncated {
+                valid: SET.len(),
+                commands: 1
+            }
+        );
+        let garbage = [SET, b"+OK\r\n"].concat();
+        assert!(matches!(
+            scan(&garbage),
+            Scan::Corrupt { offset, .. } if offset == SET.len()
+        ));
+    }
+
+    #[test]
+    fn reports_corrupt_rdb_lengths() {
+        let path = std::env::temp_dir().join(format!("check-test-{}.rdb", std::process::id()));
+        // a string key claiming to be u64::MAX bytes long
+        let mut data = b"REDIS0011\x00\x81".to_vec();
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        fs::write(&path, &data).unwrap();
+        let e = check_rdb(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(
+            format!("{e:#}").contains("unexpected end of file at offset 19"),
+            "{e:#}"
+        );
+    }
+
+    #[test]
+    fn fixes_the_last_file_of_a_manifest() {
+        let dir = std::env::temp_dir().join(format!("check-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let entries = [RdbEntry {
+            db: 0,
+            key: "k".into(),
+            value: RdbValue::String("v".into()),
+            expiration_ms: Some(1),
+        }];
+        fs::write(dir.join("a.1.base.rdb"), rdb::encode(&entries, false)).unwrap();
+        fs::write(dir.join("a.1.incr.aof"), [SET, &SET[..10]].concat()).unwrap();
+        let manifest = dir.join("a.manifest");
+        fs::write(
+            &manifest,
+            "file a.1.base.rdb seq 1 type b\nfile a.1.incr.aof seq 1 type i\n",
+        )
+        .unwrap();
+
+        let e = check_aof(&manifest, false).unwrap_err();
+        assert!(e.to_string().contains("truncated after offset"), "{e}");
+        let report = check_aof(&manifest, true).unwrap().to_string();
+        assert!(
+            report.contains("db 0: 1 keys, 1 with an expiration"),
+            "{report}"
+        );
+        assert_eq!(fs::read(dir.join("a.1.incr.aof")).unwrap(), SET);
+        check_aof(&manifest, false).unwrap();
+        assert!(check_rdb(&dir.join("a.1.base.rdb")).is_ok());
+        assert!(check_rdb(&dir.join("a.1.incr.aof")).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
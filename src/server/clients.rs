@@ -0,0 +1,293 @@
+use std::{
+    cmp::Reverse,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+};
+
+use dashmap::DashMap;
+use tokio::sync::mpsc::{self, error::TrySendError, WeakSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::{connection::writer::Outgoing, resp::RedisValue, server::tasks};
+
+/// Client connections currently open
+static CONNECTED: AtomicUsize = AtomicUsize::new(0);
+
+/// Connections accepted since startup, rejected ones included
+static TOTAL_RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+/// Connections turned away because `maxclients` was reached
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Connections turned away by the IP allow and deny lists
+static DENIED: AtomicU64 = AtomicU64::new(0);
+
+/// Connections closed for holding more memory than `maxmemory-clients` allows
+static EVICTED: AtomicU64 = AtomicU64::new(0);
+
+/// Output queues of open connections by client ID, so that they can be sent messages from
+/// elsewhere
+static OUTPUTS: LazyLock<DashMap<u64, WeakSender<Outgoing>>> = LazyLock::new(DashMap::new);
+
+/// Memory held by the clients counted towards `maxmemory-clients`, by client ID
+static MEMORY: LazyLock<DashMap<u64, Arc<ClientMemory>>> = LazyLock::new(DashMap::new);
+
+/// What the clients in [`MEMORY`] hold together
+static MEMORY_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Held while clients are picked for eviction, so they are not picked twice over
+static EVICTING: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Default)]
+struct Usage {
+    query: usize,
+    output: usize,
+    /// Whether the client still counts towards [`MEMORY_USED`]
+    tracked: bool,
+}
+
+/// The memory a client holds in its query buffer and its output not yet written, counted
+/// towards `maxmemory-clients`
+#[derive(Debug, Default)]
+pub(crate) struct ClientMemory {
+    id: u64,
+    /// The `maxmemory-clients` of the server the client connected to, 0 for none
+    limit: usize,
+    usage: Mutex<Usage>,
+    /// Set by CLIENT NO-EVICT ON
+    no_evict: AtomicBool,
+    evicted: CancellationToken,
+}
+
+impl ClientMemory {
+    /// Count the memory of the client with `id` from now on, evicting clients once all of them
+    /// hold more than `limit`
+    pub(crate) fn track(id: u64, limit: usize) -> Arc<Self> {
+        let memory = Arc::new(Self {
+            id,
+            limit,
+            usage: Mutex::new(Usage {
+                tracked: true,
+                ..Usage::default()
+            }),
+            ..Self::default()
+        });
+        MEMORY.insert(id, memory.clone());
+        memory
+    }
+
+    /// Memory that is never counted, such as our master's link
+    pub(crate) fn untracked() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub(crate) fn set_query(&self, len: usize) {
+        self.set(|usage| &mut usage.query, len);
+    }
+
+    pub(crate) fn set_output(&self, len: usize) {
+        self.set(|usage| &mut usage.output, len);
+    }
+
+    fn set(&self, field: impl FnOnce(&mut Usage) -> &mut usize, len: usize) {
+        let grew = {
+            let mut usage = self.usage.lock().unwrap();
+            if !usage.tracked {
+                return;
+            }
+            let old = std::mem::replace(field(&mut usage), len);
+            MEMORY_USED.fetch_add(len, Ordering::Relaxed);
+            MEMORY_USED.fetch_sub(old, Ordering::Relaxed);
+            len > old
+        };
+        if grew && self.limit != 0 && MEMORY_USED.load(Ordering::Relaxed) > self.limit {
+            evict(self.limit);
+        }
+    }
+
+    fn used(&self) -> usize {
+        let usage = self.usage.lock().unwrap();
+        usage.query + usage.output
+    }
+
+    pub(crate) fn set_no_evict(&self, no_evict: bool) {
+        self.no_evict.store(no_evict, Ordering::Relaxed);
+    }
+
+    /// Stop counting the client, once it is gone or has become a replica
+    pub(crate) fn untrack(&self) {
+        let mut usage = self.usage.lock().unwrap();
+        if std::mem::take(&mut usage.tracked) {
+            MEMORY_USED.fetch_sub(usage.query + usage.output, Ordering::Relaxed);
+        }
+        MEMORY.remove(&self.id);
+    }
+
+    /// Wait until the client is evicted
+    pub(crate) async fn evicted(&self) {
+        self.evicted.cancelled().await
+    }
+}
+
+/// Disconnect the clients holding the most memory, sparing those that asked with CLIENT
+/// NO-EVICT, until the others fit in `limit`
+fn evict(limit: usize) {
+    let Ok(_evicting) = EVICTING.try_lock() else {
+        return;
+    };
+    let mut candidates: Vec<_> = MEMORY
+        .iter()
+        .filter(|client| !client.no_evict.load(Ordering::Relaxed))
+        .map(|client| (client.used(), client.clone()))
+        .collect();
+    candidates.sort_by_key(|(used, _)| Reverse(*used));
+    for (used, client) in candidates {
+        if MEMORY_USED.load(Ordering::Relaxed) <= limit {
+            break;
+        }
+        tracing::warn!(
+            "Evicting client {} holding {used} bytes, clients are over maxmemory-clients",
+            client.id
+        );
+        client.untrack();
+        client.evicted.cancel();
+        EVICTED.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A client's place among the `maxclients`, given back when dropped
+#[derive(Debug)]
+pub(crate) struct ClientSlot(());
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        CONNECTED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Count a newly accepted connection, returning its slot unless `maxclients` are already
+/// connected
+pub(crate) fn admit(maxclients: usize) -> Option<ClientSlot> {
+    TOTAL_RECEIVED.fetch_add(1, Ordering::Relaxed);
+    let admitted = CONNECTED
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |connected| {
+            (connected < maxclients).then_some(connected + 1)
+        })
+        .is_ok();
+    if !admitted {
+        REJECTED.fetch_add(1, Ordering::Relaxed);
+        return None;
+    }
+    Some(ClientSlot(()))
+}
+
+/// Count a newly accepted connection from a peer that may not connect
+pub(crate) fn deny() {
+    TOTAL_RECEIVED.fetch_add(1, Ordering::Relaxed);
+    DENIED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Make a connection reachable by its ID, until [`unregister`]ed
+pub(crate) fn register(id: u64, outgoing: &mpsc::Sender<Outgoing>) {
+    OUTPUTS.insert(id, outgoing.downgrade());
+}
+
+pub(crate) fn unregister(id: u64) {
+    OUTPUTS.remove(&id);
+}
+
+pub(crate) fn is_registered(id: u64) -> bool {
+    OUTPUTS.contains_key(&id)
+}
+
+/// Send a message to the client with `id` right away, rather than with its next batch of
+/// replies. Returns whether the client is still connected.
+pub(crate) fn push(id: u64, message: RedisValue) -> bool {
+    let Some(outgoing) = OUTPUTS.get(&id).and_then(|outgoing| outgoing.upgrade()) else {
+        return false;
+    };
+    match outgoing.try_reserve_many(2) {
+        Ok(mut permits) => {
+            permits.next().unwrap().send(Outgoing::Value(message));
+            permits.next().unwrap().send(Outgoing::Flush);
+            return true;
+        }
+        Err(TrySendError::Full(())) => {}
+        Err(TrySendError::Closed(())) => return false,
+    }
+    // the client is slow to read, wait for room rather than hold up the caller
+    tasks::spawn("push", Some(id), async move {
+        if let Ok(mut permits) = outgoing.reserve_many(2).await {
+            permits.next().unwrap().send(Outgoing::Value(message));
+            permits.next().unwrap().send(Outgoing::Flush);
+        }
+    });
+    true
+}
+
+pub(crate) fn connected() -> usize {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn total_received() -> u64 {
+    TOTAL_RECEIVED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn rejected() -> u64 {
+    REJECTED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn denied() -> u64 {
+    DENIED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn evicted() -> u64 {
+    EVICTED.load(Ordering::Relaxed)
+}
+
+/// Memory held by clients, as counted towards `maxmemory-clients`
+pub(crate) fn memory_used() -> usize {
+    MEMORY_USED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admits_up_to_maxclients() {
+        // the counters are process wide, so only look at how they move
+        let before = rejected();
+        let first = admit(usize::MAX).unwrap();
+        let limit = connected();
+        assert!(admit(limit).is_none());
+        assert!(rejected() > before);
+        drop(first);
+        assert!(admit(usize::MAX).is_some());
+    }
+
+    #[test]
+    fn evicts_the_biggest_clients() {
+        // sizes far beyond what other tests' clients hold, which are then never picked
+        let limit = 1 << 50;
+        let evicted = super::evicted();
+        let [small, big, spared] =
+            [u64::MAX - 3, u64::MAX - 2, u64::MAX - 1].map(|id| ClientMemory::track(id, limit));
+        spared.set_no_evict(true);
+        small.set_output(limit / 4);
+        big.set_query(limit / 2);
+        assert!(!big.evicted.is_cancelled());
+
+        spared.set_query(limit / 2);
+        assert!(big.evicted.is_cancelled());
+        assert!(!small.evicted.is_cancelled() && !spared.evicted.is_cancelled());
+        assert!(super::evicted() > evicted);
+        // once evicted, it stops being counted
+        big.set_query(limit);
+        assert_eq!(big.used(), limit / 2);
+        small.untrack();
+        spared.untrack();
+    }
+}
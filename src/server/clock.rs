@@ -0,0 +1,180 @@
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::watch;
+
+/// Longest wait for a deadline, as one far enough away would overflow an [`Instant`]
+const FAR_FUTURE: Duration = Duration::from_secs(30 * 365 * 24 * 60 * 60);
+
+/// The time key expirations are judged by. Expirations are absolute unix times in milliseconds,
+/// as PEXPIREAT and RDB files have them, checked against [`now_ms`](Clock::now_ms); only the
+/// expirer's sleeps use the monotonic [`now`](Clock::now). Tests can move time along instead of
+/// sleeping.
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time at [`now`](Clock::now)
+    fn wall(&self) -> SystemTime;
+
+    /// Wait until [`now`](Clock::now) reaches `deadline`
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+
+    /// Milliseconds since the unix epoch at [`now`](Clock::now)
+    fn now_ms(&self) -> u64 {
+        self.wall()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+
+    /// The unix time in milliseconds `ttl` from now, unless that is past what a u64 holds
+    fn expires_at(&self, ttl: Duration) -> Option<u64> {
+        let at = u128::from(self.now_ms()).checked_add(ttl.as_millis())?;
+        u64::try_from(at).ok()
+    }
+
+    /// The point on [`now`](Clock::now) when [`now_ms`](Clock::now_ms) reaches `at_ms`, to
+    /// sleep until
+    fn deadline(&self, at_ms: u64) -> Instant {
+        let wait = Duration::from_millis(at_ms.saturating_sub(self.now_ms())).min(FAR_FUTURE);
+        self.now() + wait
+    }
+}
+
+/// Real time, as tokio sees it, so tokio's paused test time applies to expirations too
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+/// A clock that only moves when told to, for tests
+#[derive(Debug)]
+pub struct MockClock {
+    /// Where the clock started, in both kinds of time
+    start: (Instant, SystemTime),
+    now: watch::Sender<Instant>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        let start = Instant::now();
+        Self {
+            start: (start, SystemTime::now()),
+            now: watch::Sender::new(start),
+        }
+    }
+}
+
+impl MockClock {
+    /// Move the clock forward, waking anything sleeping until a time it reaches
+    pub fn advance(&self, by: Duration) {
+        self.now.send_modify(|now| *now += by);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn wall(&self) -> SystemTime {
+        self.start.1 + self.now().duration_since(self.start.0)
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            // the sender lives as long as we do, so this can't fail
+            let _ = now.wait_for(|now| *now >= deadline).await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        resp::{client::Client, RedisValue},
+        server::Redis,
+    };
+
+    #[tokio::test]
+    async fn sleeps_until_advanced() {
+        let clock = Arc::new(MockClock::default());
+        let deadline = clock.now() + Duration::from_secs(10);
+        let sleeper = tokio::spawn({
+            let clock = clock.clone();
+            async move { clock.sleep_until(deadline).await }
+        });
+        clock.advance(Duration::from_secs(5));
+        tokio::task::yield_now().await;
+        assert!(!sleeper.is_finished());
+        clock.advance(Duration::from_secs(5));
+        sleeper.await.unwrap();
+    }
+
+    #[test]
+    fn deadlines_follow_the_wall_clock() {
+        let clock = MockClock::default();
+        let now_ms = clock.now_ms();
+        assert_eq!(
+            clock.deadline(now_ms + 1500),
+            clock.now() + Duration::from_millis(1500)
+        );
+        // a time already past is due straight away, one too far to tell is the far future
+        assert_eq!(clock.deadline(now_ms - 1000), clock.now());
+        assert_eq!(clock.deadline(u64::MAX), clock.now() + FAR_FUTURE);
+        assert_eq!(
+            clock.expires_at(Duration::from_millis(1500)),
+            Some(now_ms + 1500)
+        );
+        assert_eq!(clock.expires_at(Duration::from_secs(u64::MAX / 1000)), None);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now_ms(), now_ms + 2000);
+    }
+
+    #[tokio::test]
+    async fn expires_keys_by_the_clock() {
+        let clock = Arc::new(MockClock::default());
+        let redis = Redis::builder()
+            .port(0)
+            .dir(std::env::temp_dir())
+            .dbfilename("clock-test-missing.rdb")
+            .clock(clock.clone())
+            .start()
+            .await
+            .unwrap();
+        let mut client = Client::connect(redis.addr()).await.unwrap();
+        client
+            .request(["SET", "session", "x", "EX", "60"])
+            .await
+            .unwrap();
+        let keyspace = redis.keyspace();
+        assert_eq!(keyspace.ttl("session"), Some(Duration::from_secs(60)));
+
+        clock.advance(Duration::from_secs(59));
+        let reply = client.request(["GET", "session"]).await.unwrap();
+        assert_eq!(reply, RedisValue::BulkString("x".into()));
+        clock.advance(Duration::from_secs(1));
+        let reply = client.request(["GET", "session"]).await.unwrap();
+        assert_eq!(reply, RedisValue::NullBulkString);
+        redis.stop().await.unwrap();
+    }
+}
@@ -0,0 +1,1960 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use clap::{builder::ValueRange, Arg, ArgAction, ArgMatches, Command};
+use tracing::level_filters::LevelFilter;
+
+use crate::resp::codec::{
+    ProtoLimits, DEFAULT_MAX_BULK_LEN, DEFAULT_MAX_MULTIBULK_LEN, DEFAULT_MAX_NESTING,
+};
+
+pub(crate) mod file;
+
+const DEFAULT_PORT: u16 = 6379;
+const DEFAULT_DBFILENAME: &str = "dump.rdb";
+const DEFAULT_APPENDFILENAME: &str = "appendonly.aof";
+const DEFAULT_APPENDDIRNAME: &str = "appendonlydir";
+const DEFAULT_MAXMEMORY_SAMPLES: usize = 5;
+const DEFAULT_LFU_LOG_FACTOR: u32 = 10;
+const DEFAULT_LIST_MAX_LISTPACK_SIZE: i64 = -2;
+const DEFAULT_LFU_DECAY_TIME: u32 = 1;
+const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(300);
+const DEFAULT_CLUSTER_NODE_TIMEOUT: Duration = Duration::from_secs(15);
+const DEFAULT_TCP_BACKLOG: u32 = 511;
+const DEFAULT_MAXCLIENTS: usize = 10000;
+const DEFAULT_LOGFILE_KEEP: usize = 5;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_REPL_DISKLESS_SYNC_DELAY: Duration = Duration::from_secs(5);
+const DEFAULT_REPL_PING_REPLICA_PERIOD: Duration = Duration::from_secs(10);
+const DEFAULT_REPL_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_ACTIVE_DEFRAG_THRESHOLD_LOWER: u8 = 10;
+const DEFAULT_ACTIVE_DEFRAG_CYCLE_MAX: u8 = 25;
+
+/// What to do when a write would take memory use past `maxmemory`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Refuse the write with an OOM error
+    #[default]
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    /// Evict the keys closest to expiring
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    /// Whether keys are evicted by access frequency
+    pub fn lfu(&self) -> bool {
+        matches!(self, Self::AllKeysLfu | Self::VolatileLfu)
+    }
+
+    /// Whether only keys with an expiration may be evicted
+    pub fn volatile(&self) -> bool {
+        matches!(
+            self,
+            Self::VolatileLru | Self::VolatileLfu | Self::VolatileRandom | Self::VolatileTtl
+        )
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "noeviction" => Self::NoEviction,
+            "allkeys-lru" => Self::AllKeysLru,
+            "volatile-lru" => Self::VolatileLru,
+            "allkeys-lfu" => Self::AllKeysLfu,
+            "volatile-lfu" => Self::VolatileLfu,
+            "allkeys-random" => Self::AllKeysRandom,
+            "volatile-random" => Self::VolatileRandom,
+            "volatile-ttl" => Self::VolatileTtl,
+            _ => return Err(anyhow::anyhow!("Unknown maxmemory policy: {s}")),
+        })
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NoEviction => "noeviction",
+            Self::AllKeysLru => "allkeys-lru",
+            Self::VolatileLru => "volatile-lru",
+            Self::AllKeysLfu => "allkeys-lfu",
+            Self::VolatileLfu => "volatile-lfu",
+            Self::AllKeysRandom => "allkeys-random",
+            Self::VolatileRandom => "volatile-random",
+            Self::VolatileTtl => "volatile-ttl",
+        })
+    }
+}
+
+/// A `client-output-buffer-limit` for one class of clients: a client is disconnected as soon
+/// as its pending output goes over `hard` bytes, or once it has stayed over `soft` bytes for
+/// `soft_seconds`. Zero disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OutputBufferLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_seconds: Duration,
+}
+
+impl OutputBufferLimit {
+    const fn new(hard: u64, soft: u64, soft_seconds: u64) -> Self {
+        Self {
+            hard,
+            soft,
+            soft_seconds: Duration::from_secs(soft_seconds),
+        }
+    }
+
+    /// Whether a client with `pending` bytes of output must be disconnected, given when it went
+    /// over the soft limit, which is updated
+    pub fn exceeded(
+        &self,
+        pending: u64,
+        over_soft_since: &mut Option<Instant>,
+        now: Instant,
+    ) -> bool {
+        if self.hard > 0 && pending > self.hard {
+            return true;
+        }
+        if self.soft == 0 || pending <= self.soft {
+            *over_soft_since = None;
+            return false;
+        }
+        let since = *over_soft_since.get_or_insert(now);
+        now.duration_since(since) >= self.soft_seconds
+    }
+}
+
+/// Output buffer limits of each class of clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputBufferLimits {
+    pub normal: OutputBufferLimit,
+    pub replica: OutputBufferLimit,
+    /// For clients in subscribed mode, once the server has any
+    pub pubsub: OutputBufferLimit,
+}
+
+impl Default for OutputBufferLimits {
+    fn default() -> Self {
+        Self {
+            normal: OutputBufferLimit::default(),
+            replica: OutputBufferLimit::new(256 * 1024 * 1024, 64 * 1024 * 1024, 60),
+            pubsub: OutputBufferLimit::new(32 * 1024 * 1024, 8 * 1024 * 1024, 60),
+        }
+    }
+}
+
+impl OutputBufferLimits {
+    /// Set one class from a `<class> <hard> <soft> <soft seconds>` line
+    fn set(&mut self, line: &str) -> Result<()> {
+        let [class, hard, soft, soft_seconds] = line.split_whitespace().collect::<Vec<_>>()[..]
+        else {
+            return Err(anyhow::anyhow!(
+                "Expected <class> <hard limit> <soft limit> <soft seconds>, got {line:?}"
+            ));
+        };
+        let limit = OutputBufferLimit {
+            hard: parse_memory(hard)?,
+            soft: parse_memory(soft)?,
+            soft_seconds: Duration::from_secs(soft_seconds.parse()?),
+        };
+        match class.to_ascii_lowercase().as_str() {
+            "normal" => self.normal = limit,
+            "replica" | "slave" => self.replica = limit,
+            "pubsub" => self.pubsub = limit,
+            _ => return Err(anyhow::anyhow!("Unknown client class: {class}")),
+        }
+        Ok(())
+    }
+}
+
+/// Memory all clients together may hold before those holding the most are disconnected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientMemoryLimit {
+    /// Bytes, 0 for no limit
+    Bytes(u64),
+    /// A percentage of `maxmemory`
+    Percent(u64),
+}
+
+impl Default for ClientMemoryLimit {
+    fn default() -> Self {
+        Self::Bytes(0)
+    }
+}
+
+impl FromStr for ClientMemoryLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse()? {
+                0 => Err(anyhow::anyhow!(
+                    "maxmemory-clients percentage must be positive"
+                )),
+                percent => Ok(Self::Percent(percent)),
+            },
+            None => Ok(Self::Bytes(parse_memory(s)?)),
+        }
+    }
+}
+
+impl fmt::Display for ClientMemoryLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bytes(bytes) => write!(f, "{bytes}"),
+            Self::Percent(percent) => write!(f, "{percent}%"),
+        }
+    }
+}
+
+/// A block of addresses, such as `10.0.0.0/8`. A lone address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid address block: {s}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => match len.parse() {
+                Ok(len) if len <= max => len,
+                _ => return Err(anyhow::anyhow!("Invalid prefix length in {s}")),
+            },
+            None => max,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Which peers may connect, checked right after accepting
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IpFilter {
+    /// Only these peers may connect, anyone if empty
+    pub allow: Vec<Cidr>,
+    /// These peers may not connect, even if allowed
+    pub deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let listed = |blocks: &[Cidr]| blocks.iter().any(|block| block.contains(ip));
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// Per-second limits on what a client may send, enforced as token buckets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimit {
+    /// Commands per second, 0 for no limit
+    pub commands: u64,
+    /// Bytes of command arguments per second, 0 for no limit
+    pub bytes: u64,
+    /// Whether clients share a budget with the others from their IP
+    pub per_ip: bool,
+    /// Hold commands back until the budget allows them, rather than refusing them
+    pub delay: bool,
+}
+
+impl RateLimit {
+    pub fn enabled(&self) -> bool {
+        self.commands > 0 || self.bytes > 0
+    }
+}
+
+/// Whether to save an RDB file when shutting down on a signal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShutdownSave {
+    Save,
+    /// Also what `default` means, as no save points are configured
+    #[default]
+    NoSave,
+}
+
+impl FromStr for ShutdownSave {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "save" => Self::Save,
+            "nosave" | "default" => Self::NoSave,
+            _ => return Err(anyhow::anyhow!("Unknown shutdown option: {s}")),
+        })
+    }
+}
+
+/// How often the append-only file is synced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AppendFsync {
+    /// After every write, before it is answered
+    Always,
+    /// Once a second, losing at most a second of writes in a crash
+    #[default]
+    EverySec,
+    /// Whenever the OS sees fit
+    No,
+}
+
+impl FromStr for AppendFsync {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "always" => Self::Always,
+            "everysec" => Self::EverySec,
+            "no" => Self::No,
+            _ => return Err(anyhow::anyhow!("Unknown appendfsync policy: {s}")),
+        })
+    }
+}
+
+impl fmt::Display for AppendFsync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Always => "always",
+            Self::EverySec => "everysec",
+            Self::No => "no",
+        })
+    }
+}
+
+/// An option of the command line, named after the redis.conf directive it sets
+struct CliOption {
+    name: &'static str,
+    /// The option's arguments, which it may also take as one, separated by spaces
+    values: &'static [&'static str],
+    /// Whether it takes any number of arguments, named like the first
+    many: bool,
+    help: &'static str,
+}
+
+const fn opt(name: &'static str, values: &'static [&'static str], help: &'static str) -> CliOption {
+    CliOption {
+        name,
+        values,
+        many: false,
+        help,
+    }
+}
+
+const fn many(
+    name: &'static str,
+    values: &'static [&'static str],
+    help: &'static str,
+) -> CliOption {
+    CliOption {
+        name,
+        values,
+        many: true,
+        help,
+    }
+}
+
+/// Every option [`cli`] takes, under the heading its help lists it under
+const CLI_OPTIONS: &[(&str, &[CliOption])] = &[
+    (
+        "Networking",
+        &[
+            opt("port", &["port"], "TCP port to listen on [6379]"),
+            many("bind", &["addr"], "Addresses to listen on [* ::*]"),
+            opt(
+                "protected-mode",
+                &["yes|no"],
+                "Refuse remote clients when bind and requirepass are unset [yes]",
+            ),
+            opt(
+                "tcp-backlog",
+                &["n"],
+                "Queue of connections not yet accepted [511]",
+            ),
+            opt(
+                "tcp-keepalive",
+                &["seconds"],
+                "Idle time before keepalive probes, 0 for none [300]",
+            ),
+            opt(
+                "tcp-nodelay",
+                &["yes|no"],
+                "Disable Nagle's algorithm [yes]",
+            ),
+            opt(
+                "health-port",
+                &["port"],
+                "Port for HTTP probes and /metrics [0]",
+            ),
+            opt(
+                "tls-port",
+                &["port"],
+                "Port for TLS clients, 0 for none [0]",
+            ),
+            opt(
+                "tls-cert-file",
+                &["path"],
+                "PEM certificate chain served on tls-port",
+            ),
+            opt(
+                "tls-key-file",
+                &["path"],
+                "PEM private key of the certificate",
+            ),
+            many(
+                "ip-allow",
+                &["cidr"],
+                "Only accept clients from these blocks [none]",
+            ),
+            many(
+                "ip-deny",
+                &["cidr"],
+                "Refuse clients from these blocks [none]",
+            ),
+            opt(
+                "maxclients",
+                &["n"],
+                "Most clients connected at once [10000]",
+            ),
+            opt(
+                "rate-limit-commands",
+                &["n"],
+                "Commands per second a client may send, 0 for any [0]",
+            ),
+            opt(
+                "rate-limit-bytes",
+                &["bytes"],
+                "Argument bytes per second a client may send [0]",
+            ),
+            opt(
+                "rate-limit-by",
+                &["client|ip"],
+                "Limit each client or each source IP [client]",
+            ),
+            opt(
+                "rate-limit-action",
+                &["reject|delay"],
+                "Refuse commands over the limit or hold them [reject]",
+            ),
+            opt(
+                "client-output-buffer-limit",
+                &["class", "hard", "soft", "soft seconds"],
+                "Unsent output a class of clients may hold",
+            ),
+            opt(
+                "proto-max-bulk-len",
+                &["bytes"],
+                "Longest bulk string a client may send [512mb]",
+            ),
+            opt(
+                "proto-max-multibulk-len",
+                &["n"],
+                "Most elements in one command [2147483647]",
+            ),
+            opt(
+                "proto-max-nesting",
+                &["n"],
+                "Deepest nesting of aggregates [128]",
+            ),
+        ],
+    ),
+    (
+        "Security",
+        &[opt(
+            "requirepass",
+            &["password"],
+            "Password clients must AUTH with, \"\" for none [\"\"]",
+        )],
+    ),
+    (
+        "Persistence",
+        &[
+            opt("dir", &["path"], "Directory of the RDB file [.]"),
+            opt("dbfilename", &["name"], "Name of the RDB file [dump.rdb]"),
+            opt(
+                "rdbcompression",
+                &["yes|no"],
+                "Compress long strings in it with LZF [yes]",
+            ),
+            opt(
+                "appendonly",
+                &["yes|no"],
+                "Log writes to an append-only file [no]",
+            ),
+            opt(
+                "appendfilename",
+                &["name"],
+                "Name the append-only files start with [appendonly.aof]",
+            ),
+            opt(
+                "appenddirname",
+                &["name"],
+                "Its directory, inside dir [appendonlydir]",
+            ),
+            opt(
+                "appendfsync",
+                &["always|everysec|no"],
+                "How often it is synced to disk [everysec]",
+            ),
+        ],
+    ),
+    (
+        "Replication and cluster",
+        &[
+            opt("replicaof", &["host", "port"], "Replicate from this master"),
+            opt(
+                "repl-diskless-sync",
+                &["yes|no"],
+                "Stream full resyncs to replicas as encoded [no]",
+            ),
+            opt(
+                "repl-diskless-sync-delay",
+                &["seconds"],
+                "Wait for more replicas to share one [5]",
+            ),
+            opt(
+                "repl-diskless-sync-max-replicas",
+                &["n"],
+                "Start once this many wait, 0 for no limit [0]",
+            ),
+            opt(
+                "repl-ping-replica-period",
+                &["seconds"],
+                "How often a master pings its replicas [10]",
+            ),
+            opt(
+                "repl-timeout",
+                &["seconds"],
+                "Drop a master link silent for this long [60]",
+            ),
+            opt(
+                "replica-lazy-flush",
+                &["yes|no"],
+                "Free the old dataset in the background on sync [no]",
+            ),
+            opt(
+                "replica-serve-stale-data",
+                &["yes|no"],
+                "Serve reads while the master link is down [yes]",
+            ),
+            opt(
+                "replica-announce-ip",
+                &["ip"],
+                "Address our master tells others to reach us at",
+            ),
+            opt(
+                "replica-announce-port",
+                &["port"],
+                "Port our master tells others, 0 for ours [0]",
+            ),
+            opt(
+                "replica-priority",
+                &["n"],
+                "Preference for promotion by Sentinel, 0 never [100]",
+            ),
+            opt("cluster-enabled", &["yes|no"], "Run as a cluster node [no]"),
+            opt(
+                "cluster-port",
+                &["port"],
+                "Cluster bus port, 0 for port + 10000 [0]",
+            ),
+            opt(
+                "cluster-node-timeout",
+                &["ms"],
+                "Silence after which a node looks down [15000]",
+            ),
+            opt(
+                "cluster-announce-ip",
+                &["ip"],
+                "Address other nodes reach us at [bind]",
+            ),
+        ],
+    ),
+    (
+        "Memory",
+        &[
+            opt(
+                "maxmemory",
+                &["bytes"],
+                "Memory limit for the dataset, 0 for none [0]",
+            ),
+            opt(
+                "maxmemory-policy",
+                &["policy"],
+                "How to make room at the limit [noeviction]",
+            ),
+            opt(
+                "maxmemory-samples",
+                &["n"],
+                "Keys sampled for every eviction [5]",
+            ),
+            opt(
+                "maxmemory-clients",
+                &["bytes|n%"],
+                "Memory clients may hold, 0 for any [0]",
+            ),
+            opt(
+                "lfu-log-factor",
+                &["n"],
+                "How slowly LFU counters grow [10]",
+            ),
+            opt(
+                "lfu-decay-time",
+                &["minutes"],
+                "Time between LFU counter decrements [1]",
+            ),
+            opt(
+                "hotkeys-sample-rate",
+                &["n"],
+                "Count one key access in n for HOTKEYS, 0 none [0]",
+            ),
+            opt(
+                "list-max-listpack-size",
+                &["n"],
+                "Elements of a packed list, -1..-5 for 4-64 KB [-2]",
+            ),
+            opt(
+                "lazyfree-lazy-eviction",
+                &["yes|no"],
+                "Free evicted values in the background [no]",
+            ),
+            opt(
+                "lazyfree-lazy-expire",
+                &["yes|no"],
+                "Free expired values in the background [no]",
+            ),
+            opt(
+                "lazyfree-lazy-user-del",
+                &["yes|no"],
+                "Make DEL behave like UNLINK [no]",
+            ),
+            opt(
+                "lazyfree-lazy-user-flush",
+                &["yes|no"],
+                "Make flushes asynchronous by default [no]",
+            ),
+            opt(
+                "activedefrag",
+                &["yes|no"],
+                "Compact values and tables in the background [no]",
+            ),
+            opt(
+                "active-defrag-threshold-lower",
+                &["percent"],
+                "Spare room worth compacting a value for [10]",
+            ),
+            opt(
+                "active-defrag-cycle-max",
+                &["percent"],
+                "Share of each cycle compaction may take [25]",
+            ),
+        ],
+    ),
+    (
+        "Logging",
+        &[
+            opt(
+                "loglevel",
+                &["level"],
+                "debug, verbose, notice or warning [notice]",
+            ),
+            opt(
+                "logfile",
+                &["path"],
+                "File to log to, \"\" for standard output [\"\"]",
+            ),
+            opt(
+                "log-format",
+                &["text|json"],
+                "Write log lines as text or JSON objects [text]",
+            ),
+            opt(
+                "logfile-max-size",
+                &["bytes"],
+                "Rotate the log file at this size, 0 never [0]",
+            ),
+            opt("logfile-keep", &["n"], "Rotated log files kept [5]"),
+        ],
+    ),
+    (
+        "Shutdown",
+        &[
+            opt(
+                "shutdown-timeout",
+                &["seconds"],
+                "Time given to connections to finish [10]",
+            ),
+            opt(
+                "shutdown-on-sigterm",
+                &["save|nosave|default"],
+                "Save the dataset on SIGTERM [default]",
+            ),
+            opt(
+                "shutdown-on-sigint",
+                &["save|nosave|default"],
+                "Save the dataset on SIGINT [default]",
+            ),
+        ],
+    ),
+];
+
+/// The command line of redis-server: a redis.conf file, then options overriding it
+pub fn cli() -> Command {
+    let mut cli = Command::new("redis-server")
+        .override_usage(
+            "redis-server [/path/to/redis.conf] [--option value]...\n       \
+             redis-server -v | --version\n       \
+             redis-server --check-rdb <file.rdb>\n       \
+             redis-server --check-aof [--fix] <file.manifest|file.aof>",
+        )
+        .after_help(
+            "Options take the value of the redis.conf directive of the same name, either as the\n\
+             next arguments or as --option=value. They override what the config file sets.\n\
+             Environment variables named after an option, such as REDIS_PORT or REDIS_MAXMEMORY,\n\
+             override both.",
+        )
+        .disable_version_flag(true)
+        .arg(
+            Arg::new("config")
+                .value_name("/path/to/redis.conf")
+                .help("Config file the options start from"),
+        )
+        .arg(
+            Arg::new("version")
+                .short('v')
+                .long("version")
+                .action(ArgAction::SetTrue)
+                .help("Print the version"),
+        )
+        .arg(
+            Arg::new("check-rdb")
+                .long("check-rdb")
+                .value_name("file.rdb")
+                .exclusive(true)
+                .help("Check an RDB file, then exit"),
+        )
+        .arg(
+            Arg::new("check-aof")
+                .long("check-aof")
+                .value_name("file.manifest|file.aof")
+                .conflicts_with("config")
+                .help("Check an append-only file, then exit"),
+        )
+        .arg(
+            Arg::new("fix")
+                .long("fix")
+                .action(ArgAction::SetTrue)
+                .requires("check-aof")
+                .help("Truncate a damaged append-only file to what is intact"),
+        );
+    for (heading, options) in CLI_OPTIONS {
+        for option in *options {
+            let values = match option.many {
+                true => ValueRange::new(1..),
+                false => ValueRange::new(1..=option.values.len()),
+            };
+            cli = cli.arg(
+                Arg::new(option.name)
+                    .long(option.name)
+                    .value_names(option.values)
+                    .num_args(values)
+                    .allow_negative_numbers(true)
+                    .action(ArgAction::Append)
+                    .help(option.help)
+                    .help_heading(*heading),
+            );
+        }
+    }
+    cli
+}
+
+/// Server configuration gathered from the command line
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// TCP port to listen on
+    pub port: u16,
+
+    /// Addresses to listen on, every IPv4 and IPv6 address if none are configured
+    pub bind: Vec<IpAddr>,
+
+    /// Only accept clients on the loopback interface while no bind address is configured
+    pub protected_mode: bool,
+
+    /// Directory holding the RDB file
+    pub dir: PathBuf,
+
+    /// Name of the RDB file inside `dir`
+    pub dbfilename: String,
+
+    /// Compress long strings with LZF when writing RDB files
+    pub rdbcompression: bool,
+
+    /// Log writes to an append-only file, loading the dataset from it rather than the RDB
+    pub appendonly: bool,
+
+    /// Name the files of the append-only file start with, and of its manifest
+    pub appendfilename: String,
+
+    /// Directory inside `dir` holding the append-only file's parts
+    pub appenddirname: String,
+
+    /// How often the append-only file is synced to disk
+    pub appendfsync: AppendFsync,
+
+    /// Master to replicate from, if this server is a replica
+    pub replicaof: Option<(String, u16)>,
+
+    /// Run as a cluster node, serving only the hash slots assigned to us
+    pub cluster_enabled: bool,
+
+    /// Port of the cluster bus, 0 for the client port + 10000 (or any, if that is 0 too)
+    pub cluster_port: u16,
+
+    /// How long a cluster node may leave our pings unanswered before we find it down
+    pub cluster_node_timeout: Duration,
+
+    /// Address other cluster nodes and clients are told to reach us at, e.g. when behind NAT
+    pub cluster_announce_ip: Option<String>,
+
+    /// Idle time before TCP keepalive probes are sent to a client, zero to disable them
+    pub tcp_keepalive: Duration,
+
+    /// Disable Nagle's algorithm on client connections, sending small replies right away
+    pub tcp_nodelay: bool,
+
+    /// Queue length for connections not yet accepted
+    pub tcp_backlog: u32,
+
+    /// Most client connections served at once, more are turned away
+    pub maxclients: usize,
+
+    /// How much unsent output each class of clients may hold before being disconnected
+    pub client_output_buffer_limit: OutputBufferLimits,
+
+    /// Peers allowed to connect
+    pub ip_filter: IpFilter,
+
+    /// Port answering HTTP liveness and readiness probes, 0 for none
+    pub health_port: u16,
+
+    /// Port serving clients over TLS, alongside `port`, 0 for none
+    pub tls_port: u16,
+
+    /// PEM file holding the certificate chain served on `tls_port`
+    pub tls_cert_file: Option<PathBuf>,
+
+    /// PEM file holding the private key of the certificate
+    pub tls_key_file: Option<PathBuf>,
+
+    /// How fast clients may send commands
+    pub rate_limit: RateLimit,
+
+    /// Password clients must AUTH with before running commands, if any
+    pub requirepass: Option<String>,
+
+    /// Memory limit for the dataset in bytes, 0 for no limit
+    pub maxmemory: u64,
+
+    /// How to make room once `maxmemory` is reached
+    pub maxmemory_policy: EvictionPolicy,
+
+    /// Keys sampled for every eviction, trading accuracy for speed
+    pub maxmemory_samples: usize,
+
+    /// Memory clients may hold in their buffers before the biggest are disconnected
+    pub maxmemory_clients: ClientMemoryLimit,
+
+    /// How slowly the LFU counter grows, higher values needing more accesses to saturate it
+    pub lfu_log_factor: u32,
+
+    /// Minutes between decrements of an idle key's LFU counter, 0 to never decay
+    pub lfu_decay_time: u32,
+
+    /// Count one in this many key accesses towards the hot keys HOTKEYS reports, 0 for none
+    pub hotkeys_sample_rate: u32,
+
+    /// Largest list kept in the compact packed form: a count of elements, or -1 to -5 for 4 KB
+    /// to 64 KB of packed data
+    pub list_max_listpack_size: i64,
+
+    /// Free the values of evicted keys in the background
+    pub lazyfree_lazy_eviction: bool,
+
+    /// Free the values of keys deleted by an expiration in the background
+    pub lazyfree_lazy_expire: bool,
+
+    /// Make DEL behave like UNLINK
+    pub lazyfree_lazy_user_del: bool,
+
+    /// Make FLUSHALL and FLUSHDB asynchronous unless told otherwise
+    pub lazyfree_lazy_user_flush: bool,
+
+    /// Reallocate values and tables holding much more than they use in the background
+    pub activedefrag: bool,
+
+    /// Spare room, in percent of a value's size, worth reallocating it for
+    pub active_defrag_threshold_lower: u8,
+
+    /// Share of each cycle, in percent, active defragmentation may take
+    pub active_defrag_cycle_max: u8,
+
+    /// Encode full resyncs straight into the links of replicas that can take them
+    pub repl_diskless_sync: bool,
+
+    /// How long a diskless sync waits for more replicas to serve with the same snapshot
+    pub repl_diskless_sync_delay: Duration,
+
+    /// Replicas a diskless sync starts with without waiting out its delay, 0 for no limit
+    pub repl_diskless_sync_max_replicas: usize,
+
+    /// How often a master sends its replicas a PING, so they can tell a silent link from a dead one
+    pub repl_ping_replica_period: Duration,
+
+    /// How long a replica waits to hear from its master before dropping the link and reconnecting
+    pub repl_timeout: Duration,
+
+    /// Free the old dataset in the background when a replica loads its master's
+    pub replica_lazy_flush: bool,
+
+    /// Keep serving commands while out of sync with our master, or answer them with MASTERDOWN
+    pub replica_serve_stale_data: bool,
+
+    /// Address a replica announces to its master, e.g. when reached through NAT
+    pub replica_announce_ip: Option<String>,
+
+    /// Port a replica announces to its master, 0 for the one it listens on
+    pub replica_announce_port: u16,
+
+    /// How Sentinel ranks this replica for promotion, lower first and 0 never
+    pub replica_priority: u32,
+
+    /// Longest bulk string a client may send
+    pub proto_max_bulk_len: u64,
+
+    /// Most elements a client may send in one command
+    pub proto_max_multibulk_len: u64,
+
+    /// Deepest nesting of aggregates a peer may send
+    pub proto_max_nesting: usize,
+
+    /// How long a shutdown waits for connections to finish what they are doing
+    pub shutdown_timeout: Duration,
+
+    /// Whether SIGTERM saves the dataset before exiting
+    pub shutdown_on_sigterm: ShutdownSave,
+
+    /// Whether SIGINT saves the dataset before exiting
+    pub shutdown_on_sigint: ShutdownSave,
+
+    /// Least severe log messages written
+    pub loglevel: LevelFilter,
+
+    /// File to log to, standard output if `None`
+    pub logfile: Option<PathBuf>,
+
+    /// Write log lines as JSON objects rather than text
+    pub log_json: bool,
+
+    /// Size at which the log file is rotated, 0 to let it grow
+    pub logfile_max_size: u64,
+
+    /// Rotated log files kept, as `<logfile>.1` (the newest) and up
+    pub logfile_keep: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            bind: Vec::new(),
+            protected_mode: true,
+            dir: PathBuf::from("."),
+            dbfilename: DEFAULT_DBFILENAME.to_string(),
+            rdbcompression: true,
+            appendonly: false,
+            appendfilename: DEFAULT_APPENDFILENAME.to_string(),
+            appenddirname: DEFAULT_APPENDDIRNAME.to_string(),
+            appendfsync: AppendFsync::default(),
+            replicaof: None,
+            cluster_enabled: false,
+            cluster_port: 0,
+            cluster_node_timeout: DEFAULT_CLUSTER_NODE_TIMEOUT,
+            cluster_announce_ip: None,
+            tcp_keepalive: DEFAULT_TCP_KEEPALIVE,
+            tcp_nodelay: true,
+            tcp_backlog: DEFAULT_TCP_BACKLOG,
+            maxclients: DEFAULT_MAXCLIENTS,
+            client_output_buffer_limit: OutputBufferLimits::default(),
+            ip_filter: IpFilter::default(),
+            health_port: 0,
+            tls_port: 0,
+            tls_cert_file: None,
+            tls_key_file: None,
+            rate_limit: RateLimit::default(),
+            requirepass: None,
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::default(),
+            maxmemory_samples: DEFAULT_MAXMEMORY_SAMPLES,
+            maxmemory_clients: ClientMemoryLimit::default(),
+            lfu_log_factor: DEFAULT_LFU_LOG_FACTOR,
+            lfu_decay_time: DEFAULT_LFU_DECAY_TIME,
+            hotkeys_sample_rate: 0,
+            list_max_listpack_size: DEFAULT_LIST_MAX_LISTPACK_SIZE,
+            lazyfree_lazy_eviction: false,
+            lazyfree_lazy_expire: false,
+            lazyfree_lazy_user_del: false,
+            lazyfree_lazy_user_flush: false,
+            activedefrag: false,
+            active_defrag_threshold_lower: DEFAULT_ACTIVE_DEFRAG_THRESHOLD_LOWER,
+            active_defrag_cycle_max: DEFAULT_ACTIVE_DEFRAG_CYCLE_MAX,
+            repl_diskless_sync: false,
+            repl_diskless_sync_delay: DEFAULT_REPL_DISKLESS_SYNC_DELAY,
+            repl_diskless_sync_max_replicas: 0,
+            repl_ping_replica_period: DEFAULT_REPL_PING_REPLICA_PERIOD,
+            repl_timeout: DEFAULT_REPL_TIMEOUT,
+            replica_lazy_flush: false,
+            replica_serve_stale_data: true,
+            replica_announce_ip: None,
+            replica_announce_port: 0,
+            replica_priority: 100,
+            proto_max_bulk_len: DEFAULT_MAX_BULK_LEN,
+            proto_max_multibulk_len: DEFAULT_MAX_MULTIBULK_LEN,
+            proto_max_nesting: DEFAULT_MAX_NESTING,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            shutdown_on_sigterm: ShutdownSave::default(),
+            shutdown_on_sigint: ShutdownSave::default(),
+            loglevel: LevelFilter::INFO,
+            logfile: None,
+            log_json: false,
+            logfile_max_size: 0,
+            logfile_keep: DEFAULT_LOGFILE_KEEP,
+        }
+    }
+}
+
+impl Config {
+    /// Build a config from `--option value` style arguments (program name already skipped)
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let matches = parse_cli(args)?;
+        let mut config = Self::default();
+        config.apply_matches(&matches)?;
+        Ok(config)
+    }
+
+    /// Build a config from the redis.conf file named by the first argument, if there is one,
+    /// with the remaining `--option value` arguments taking precedence, then `REDIS_*`
+    /// environment variables
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        Self::from_matches(&parse_cli(args)?)
+    }
+
+    /// Build a config from a command line parsed with [`cli`], as [`load`](Self::load) does
+    pub fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let mut config = Self::default();
+        if let Some(path) = matches.get_one::<String>("config") {
+            for directive in file::parse(Path::new(path))? {
+                // a file written for Redis may configure features we don't have
+                let mut value = Some(directive.args.join(" "));
+                let mut value = || {
+                    value.take().ok_or(anyhow::anyhow!(
+                        "Wrong number of arguments for {}",
+                        directive.name
+                    ))
+                };
+                let known = config
+                    .set(&directive.name, &mut value)
+                    .with_context(|| format!("Bad directive at {}", directive.location))?;
+                if !known {
+                    tracing::warn!(
+                        "Ignoring unsupported directive {:?} at {}",
+                        directive.name,
+                        directive.location
+                    );
+                }
+            }
+        }
+        config.apply_matches(matches)?;
+        config.apply_env(std::env::vars())?;
+        Ok(config)
+    }
+
+    /// Apply `REDIS_<OPTION>` variables, `REDIS_MAXMEMORY_POLICY` setting `maxmemory-policy`.
+    /// The variables Kubernetes and Docker define for a service or container named redis are
+    /// skipped, as their `REDIS_PORT` is an address rather than a port.
+    fn apply_env(&mut self, vars: impl IntoIterator<Item = (String, String)>) -> Result<()> {
+        for (var, value) in vars {
+            let Some(name) = var.strip_prefix("REDIS_") else {
+                continue;
+            };
+            if is_service_link(name, &value) {
+                tracing::debug!("Ignoring {var}, it describes a service rather than an option");
+                continue;
+            }
+            let name = name.replace('_', "-");
+            let mut value = Some(value);
+            let mut value = || {
+                value
+                    .take()
+                    .ok_or(anyhow::anyhow!("Missing value for {var}"))
+            };
+            let known = self
+                .set(&name, &mut value)
+                .with_context(|| format!("Bad value for {var}"))?;
+            if !known {
+                // other software's variables may share the prefix, REDIS_URL say
+                tracing::warn!("Ignoring {var}, there is no {name} option");
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the option `name` as a redis.conf line would, e.g. `("maxmemory", "100mb")`
+    pub fn set_option(&mut self, name: &str, value: &str) -> Result<()> {
+        let mut value = Some(value.to_string());
+        let mut value = || {
+            value
+                .take()
+                .ok_or(anyhow::anyhow!("Missing value for {name}"))
+        };
+        match self.set(name, &mut value)? {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!("Unknown option: {name}")),
+        }
+    }
+
+    /// Apply the options given on the command line, each time one is given in turn
+    fn apply_matches(&mut self, matches: &ArgMatches) -> Result<()> {
+        for option in CLI_OPTIONS.iter().flat_map(|(_, options)| *options) {
+            let Some(occurrences) = matches.get_occurrences::<String>(option.name) else {
+                continue;
+            };
+            for values in occurrences {
+                let value = values.map(String::as_str).collect::<Vec<_>>().join(" ");
+                self.set_option(option.name, &value)
+                    .with_context(|| format!("Bad value for --{}", option.name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether protected mode refuses a client connecting from `ip`, which configuring a bind
+    /// address or a password lifts
+    pub fn refuses(&self, ip: IpAddr) -> bool {
+        self.protected_mode
+            && self.bind.is_empty()
+            && self.requirepass.is_none()
+            && !ip.is_loopback()
+    }
+
+    /// Set the option `name`, as named in redis.conf, taking its arguments from `value`.
+    /// Returns whether the option exists.
+    fn set(&mut self, name: &str, value: &mut dyn FnMut() -> Result<String>) -> Result<bool> {
+        match name.to_ascii_lowercase().as_str() {
+            "port" => self.port = value()?.parse()?,
+            "bind" => self.bind = parse_bind(&value()?)?,
+            "protected-mode" => self.protected_mode = parse_bool(&value()?)?,
+            "dir" => self.dir = PathBuf::from(value()?),
+            "dbfilename" => self.dbfilename = value()?,
+            "rdbcompression" => self.rdbcompression = parse_bool(&value()?)?,
+            "appendonly" => self.appendonly = parse_bool(&value()?)?,
+            "appendfilename" => self.appendfilename = parse_filename(value()?)?,
+            "appenddirname" => self.appenddirname = parse_filename(value()?)?,
+            "appendfsync" => self.appendfsync = value()?.parse()?,
+            "replicaof" => {
+                // accept both `--replicaof "host port"` and `--replicaof host port`
+                let master = value()?;
+                let (host, port) = match master.split_once(' ') {
+                    Some((host, port)) => (host.to_string(), port.to_string()),
+                    None => (master, value()?),
+                };
+                self.replicaof = Some((host, port.trim().parse()?));
+            }
+            "cluster-enabled" => self.cluster_enabled = parse_bool(&value()?)?,
+            "cluster-port" => self.cluster_port = value()?.parse()?,
+            "cluster-node-timeout" => {
+                self.cluster_node_timeout = Duration::from_millis(value()?.parse()?)
+            }
+            "cluster-announce-ip" => {
+                let ip = value()?;
+                self.cluster_announce_ip = (!ip.is_empty()).then_some(ip);
+            }
+            "tcp-keepalive" => self.tcp_keepalive = Duration::from_secs(value()?.parse()?),
+            "tcp-nodelay" => self.tcp_nodelay = parse_bool(&value()?)?,
+            "tcp-backlog" => self.tcp_backlog = value()?.parse()?,
+            "maxclients" => self.maxclients = value()?.parse()?,
+            "client-output-buffer-limit" => {
+                // accept the limit as one argument or as four
+                let mut line = value()?;
+                if !line.contains(' ') {
+                    for _ in 0..3 {
+                        line = format!("{line} {}", value()?);
+                    }
+                }
+                self.client_output_buffer_limit.set(&line)?
+            }
+            "health-port" => self.health_port = value()?.parse()?,
+            "tls-port" => self.tls_port = value()?.parse()?,
+            "tls-cert-file" => self.tls_cert_file = Some(PathBuf::from(value()?)),
+            "tls-key-file" => self.tls_key_file = Some(PathBuf::from(value()?)),
+            "ip-allow" => self.ip_filter.allow = parse_cidrs(&value()?)?,
+            "ip-deny" => self.ip_filter.deny = parse_cidrs(&value()?)?,
+            "rate-limit-commands" => self.rate_limit.commands = value()?.parse()?,
+            "rate-limit-bytes" => self.rate_limit.bytes = parse_memory(&value()?)?,
+            "rate-limit-by" => {
+                self.rate_limit.per_ip = match value()?.to_ascii_lowercase().as_str() {
+                    "client" => false,
+                    "ip" => true,
+                    by => return Err(anyhow::anyhow!("Unknown rate limit key: {by}")),
+                }
+            }
+            "rate-limit-action" => {
+                self.rate_limit.delay = match value()?.to_ascii_lowercase().as_str() {
+                    "reject" => false,
+                    "delay" => true,
+                    action => return Err(anyhow::anyhow!("Unknown rate limit action: {action}")),
+                }
+            }
+            "requirepass" => {
+                // as in Redis, an empty password means none is needed
+                let password = value()?;
+                self.requirepass = (!password.is_empty()).then_some(password);
+            }
+            "maxmemory" => self.maxmemory = parse_memory(&value()?)?,
+            "maxmemory-policy" => self.maxmemory_policy = value()?.parse()?,
+            "maxmemory-clients" => self.maxmemory_clients = value()?.parse()?,
+            "maxmemory-samples" => {
+                self.maxmemory_samples = match value()?.parse()? {
+                    0 => return Err(anyhow::anyhow!("maxmemory-samples must be positive")),
+                    samples => samples,
+                }
+            }
+            "lfu-log-factor" => self.lfu_log_factor = value()?.parse()?,
+            "lfu-decay-time" => self.lfu_decay_time = value()?.parse()?,
+            "hotkeys-sample-rate" => self.hotkeys_sample_rate = value()?.parse()?,
+            "list-max-listpack-size" => {
+                self.list_max_listpack_size = match value()?.parse()? {
+                    size @ (-5..=-1 | 1..) => size,
+                    _ => {
+                        return Err(anyhow::anyhow!(
+                            "list-max-listpack-size must be positive or -1 to -5"
+                        ))
+                    }
+                }
+            }
+            "lazyfree-lazy-eviction" => self.lazyfree_lazy_eviction = parse_bool(&value()?)?,
+            "lazyfree-lazy-expire" => self.lazyfree_lazy_expire = parse_bool(&value()?)?,
+            "lazyfree-lazy-user-del" => self.lazyfree_lazy_user_del = parse_bool(&value()?)?,
+            "lazyfree-lazy-user-flush" => self.lazyfree_lazy_user_flush = parse_bool(&value()?)?,
+            "activedefrag" => self.activedefrag = parse_bool(&value()?)?,
+            "active-defrag-threshold-lower" => {
+                self.active_defrag_threshold_lower = parse_percent(&value()?)?
+            }
+            "active-defrag-cycle-max" => self.active_defrag_cycle_max = parse_percent(&value()?)?,
+            "repl-diskless-sync" => self.repl_diskless_sync = parse_bool(&value()?)?,
+            "repl-diskless-sync-delay" => {
+                self.repl_diskless_sync_delay = Duration::from_secs(value()?.parse()?)
+            }
+            "repl-diskless-sync-max-replicas" => {
+                self.repl_diskless_sync_max_replicas = value()?.parse()?
+            }
+            "repl-ping-replica-period" => {
+                self.repl_ping_replica_period = Duration::from_secs(value()?.parse()?)
+            }
+            "repl-timeout" => self.repl_timeout = Duration::from_secs(value()?.parse()?),
+            "replica-lazy-flush" => self.replica_lazy_flush = parse_bool(&value()?)?,
+            "replica-serve-stale-data" => self.replica_serve_stale_data = parse_bool(&value()?)?,
+            "replica-announce-ip" => {
+                let ip = value()?;
+                self.replica_announce_ip = (!ip.is_empty()).then_some(ip);
+            }
+            "replica-announce-port" => self.replica_announce_port = value()?.parse()?,
+            "replica-priority" => self.replica_priority = value()?.parse()?,
+            "proto-max-bulk-len" => self.proto_max_bulk_len = parse_memory(&value()?)?,
+            "proto-max-multibulk-len" => self.proto_max_multibulk_len = value()?.parse()?,
+            "proto-max-nesting" => self.proto_max_nesting = value()?.parse()?,
+            "shutdown-timeout" => self.shutdown_timeout = Duration::from_secs(value()?.parse()?),
+            "shutdown-on-sigterm" => self.shutdown_on_sigterm = value()?.parse()?,
+            "shutdown-on-sigint" => self.shutdown_on_sigint = value()?.parse()?,
+            "loglevel" => self.loglevel = parse_loglevel(&value()?)?,
+            "logfile" => {
+                // as in Redis, an empty name means standard output
+                let path = value()?;
+                self.logfile = (!path.is_empty()).then(|| PathBuf::from(path));
+            }
+            "log-format" => {
+                self.log_json = match value()?.to_ascii_lowercase().as_str() {
+                    "text" => false,
+                    "json" => true,
+                    format => return Err(anyhow::anyhow!("Unknown log format: {format}")),
+                }
+            }
+            "logfile-max-size" => self.logfile_max_size = parse_memory(&value()?)?,
+            "logfile-keep" => self.logfile_keep = value()?.parse()?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Options matching the glob `pattern` along with their values, as CONFIG GET reports them.
+    /// `save` is always empty, as there are no save points.
+    pub fn get(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        let yes_no = |flag: bool| if flag { "yes" } else { "no" }.to_string();
+        let path = |path: &Option<PathBuf>| {
+            path.as_ref()
+                .map(|path| path.display().to_string())
+                .unwrap_or_default()
+        };
+        let bind = self.bind.iter().map(IpAddr::to_string).collect::<Vec<_>>();
+        let replicaof = match &self.replicaof {
+            Some((host, port)) => format!("{host} {port}"),
+            None => String::new(),
+        };
+        let options = [
+            ("port", self.port.to_string()),
+            ("bind", bind.join(" ")),
+            ("protected-mode", yes_no(self.protected_mode)),
+            ("dir", self.dir.display().to_string()),
+            ("dbfilename", self.dbfilename.clone()),
+            ("save", String::new()),
+            ("rdbcompression", yes_no(self.rdbcompression)),
+            ("appendonly", yes_no(self.appendonly)),
+            ("appendfilename", self.appendfilename.clone()),
+            ("appenddirname", self.appenddirname.clone()),
+            ("appendfsync", self.appendfsync.to_string()),
+            ("replicaof", replicaof),
+            ("repl-diskless-sync", yes_no(self.repl_diskless_sync)),
+            (
+                "repl-diskless-sync-delay",
+                self.repl_diskless_sync_delay.as_secs().to_string(),
+            ),
+            (
+                "repl-diskless-sync-max-replicas",
+                self.repl_diskless_sync_max_replicas.to_string(),
+            ),
+            (
+                "repl-ping-replica-period",
+                self.repl_ping_replica_period.as_secs().to_string(),
+            ),
+            ("repl-timeout", self.repl_timeout.as_secs().to_string()),
+            (
+                "replica-announce-ip",
+                self.replica_announce_ip.clone().unwrap_or_default(),
+            ),
+            (
+                "replica-announce-port",
+                self.replica_announce_port.to_string(),
+            ),
+            ("replica-priority", self.replica_priority.to_string()),
+            (
+                "replica-serve-stale-data",
+                yes_no(self.replica_serve_stale_data),
+            ),
+            ("cluster-enabled", yes_no(self.cluster_enabled)),
+            ("cluster-port", self.cluster_port.to_string()),
+            (
+                "cluster-node-timeout",
+                self.cluster_node_timeout.as_millis().to_string(),
+            ),
+            (
+                "cluster-announce-ip",
+                self.cluster_announce_ip.clone().unwrap_or_default(),
+            ),
+            ("tcp-keepalive", self.tcp_keepalive.as_secs().to_string()),
+            ("tls-port", self.tls_port.to_string()),
+            ("tls-cert-file", path(&self.tls_cert_file)),
+            ("tls-key-file", path(&self.tls_key_file)),
+            ("tcp-backlog", self.tcp_backlog.to_string()),
+            ("requirepass", self.requirepass.clone().unwrap_or_default()),
+            ("maxclients", self.maxclients.to_string()),
+            ("maxmemory", self.maxmemory.to_string()),
+            ("maxmemory-policy", self.maxmemory_policy.to_string()),
+            ("maxmemory-samples", self.maxmemory_samples.to_string()),
+            ("maxmemory-clients", self.maxmemory_clients.to_string()),
+            ("lfu-log-factor", self.lfu_log_factor.to_string()),
+            ("lfu-decay-time", self.lfu_decay_time.to_string()),
+            ("hotkeys-sample-rate", self.hotkeys_sample_rate.to_string()),
+            (
+                "list-max-listpack-size",
+                self.list_max_listpack_size.to_string(),
+            ),
+            (
+                "lazyfree-lazy-eviction",
+                yes_no(self.lazyfree_lazy_eviction),
+            ),
+            ("lazyfree-lazy-expire", yes_no(self.lazyfree_lazy_expire)),
+            (
+                "lazyfree-lazy-user-del",
+                yes_no(self.lazyfree_lazy_user_del),
+            ),
+            (
+                "lazyfree-lazy-user-flush",
+                yes_no(self.lazyfree_lazy_user_flush),
+            ),
+            ("activedefrag", yes_no(self.activedefrag)),
+            (
+                "active-defrag-threshold-lower",
+                self.active_defrag_threshold_lower.to_string(),
+            ),
+            (
+                "active-defrag-cycle-max",
+                self.active_defrag_cycle_max.to_string(),
+            ),
+            ("proto-max-bulk-len", self.proto_max_bulk_len.to_string()),
+            (
+                "proto-max-multibulk-len",
+                self.proto_max_multibulk_len.to_string(),
+            ),
+            (
+                "shutdown-timeout",
+                self.shutdown_timeout.as_secs().to_string(),
+            ),
+        ];
+        let pattern = pattern.to_ascii_lowercase();
+        options
+            .into_iter()
+            .filter(|(name, _)| glob_match(pattern.as_bytes(), name.as_bytes()))
+            .collect()
+    }
+
+    /// The `maxmemory-clients` limit in bytes, 0 for none
+    pub fn client_memory_limit(&self) -> u64 {
+        match self.maxmemory_clients {
+            ClientMemoryLimit::Bytes(bytes) => bytes,
+            ClientMemoryLimit::Percent(percent) => self.maxmemory.saturating_mul(percent) / 100,
+        }
+    }
+
+    /// What clients may send us
+    pub fn proto_limits(&self) -> ProtoLimits {
+        ProtoLimits {
+            max_bulk_len: self.proto_max_bulk_len,
+            max_multibulk_len: self.proto_max_multibulk_len,
+            max_nesting: self.proto_max_nesting,
+        }
+    }
+
+    /// Full path of the RDB file
+    pub fn rdb_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    /// Address this cluster node is advertised at: `cluster-announce-ip`, else the first
+    /// specific bind address, else loopback
+    pub fn cluster_announce_ip(&self) -> String {
+        if let Some(ip) = &self.cluster_announce_ip {
+            return ip.clone();
+        }
+        self.bind
+            .iter()
+            .find(|ip| !ip.is_unspecified())
+            .map_or("127.0.0.1".to_string(), IpAddr::to_string)
+    }
+
+    /// Full path of the directory holding the append-only file
+    pub fn aof_dir(&self) -> PathBuf {
+        self.dir.join(&self.appenddirname)
+    }
+}
+
+/// Whether `REDIS_<name>=<value>` is one of the variables describing a service named redis,
+/// like Kubernetes' `REDIS_PORT=tcp://10.0.0.11:6379` and `REDIS_SERVICE_HOST` or Docker's
+/// `REDIS_ENV_<VAR>` links
+fn is_service_link(name: &str, value: &str) -> bool {
+    let address = ["tcp://", "udp://", "sctp://"]
+        .iter()
+        .any(|scheme| value.starts_with(scheme));
+    let link = ["PORT_", "SERVICE_", "ENV_"]
+        .iter()
+        .any(|prefix| name.starts_with(prefix));
+    address || link || name == "NAME"
+}
+
+/// Parse `--option value` style arguments (program name already skipped) with [`cli`]
+fn parse_cli(args: impl IntoIterator<Item = String>) -> Result<ArgMatches> {
+    let args = std::iter::once("redis-server".to_string()).chain(args);
+    Ok(cli().try_get_matches_from(args)?)
+}
+
+/// Check a name meant for a file inside a directory we are given, as Redis refuses paths
+fn parse_filename(name: String) -> Result<String> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        return Err(anyhow::anyhow!("{name:?} is not a file name"));
+    }
+    Ok(name)
+}
+
+/// Parse a space separated list of addresses to listen on, where `*` and `::*` stand for every
+/// IPv4 and IPv6 address
+fn parse_bind(value: &str) -> Result<Vec<IpAddr>> {
+    let addrs = value
+        .split_whitespace()
+        .map(|addr| {
+            Ok(match addr {
+                "*" => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                "::*" => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                addr => addr
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid bind address: {addr}"))?,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if addrs.is_empty() {
+        return Err(anyhow::anyhow!("No bind address given"));
+    }
+    Ok(addrs)
+}
+
+/// Parse a space separated list of address blocks, where `none` clears the list
+fn parse_cidrs(value: &str) -> Result<Vec<Cidr>> {
+    if value.trim().eq_ignore_ascii_case("none") {
+        return Ok(Vec::new());
+    }
+    value.split_whitespace().map(str::parse).collect()
+}
+
+/// Parse a Redis log level, or the name of a `tracing` level
+fn parse_loglevel(value: &str) -> Result<LevelFilter> {
+    Ok(match value.to_ascii_lowercase().as_str() {
+        "debug" => LevelFilter::TRACE,
+        "verbose" => LevelFilter::DEBUG,
+        "notice" => LevelFilter::INFO,
+        "warning" => LevelFilter::WARN,
+        "nothing" => LevelFilter::OFF,
+        level => level
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Unknown log level: {value}"))?,
+    })
+}
+
+/// Whether `name` matches the glob `pattern`, where `*` matches any run of characters and `?`
+/// any single one. Backtracks only to the last `*`, so a client's pattern can't take long.
+pub(crate) fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // the last `*` seen, and where in `name` it has matched up to
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Parse a redis.conf style `yes`/`no` flag
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(anyhow::anyhow!("Expected yes or no, got {value:?}")),
+    }
+}
+
+fn parse_percent(value: &str) -> Result<u8> {
+    match value.parse()? {
+        percent @ 0..=100 => Ok(percent),
+        _ => Err(anyhow::anyhow!("Expected a percentage, got {value:?}")),
+    }
+}
+
+/// Parse a redis.conf style memory size such as `100mb` or `1gb` (k/m/g are powers of 1000,
+/// kb/mb/gb powers of 1024)
+fn parse_memory(value: &str) -> Result<u64> {
+    let lower = value.to_ascii_lowercase();
+    let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &lower[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        unit => return Err(anyhow::anyhow!("Unknown memory unit {unit:?} in {value:?}")),
+    };
+    Ok(digits.parse::<u64>()? * multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_sizes() {
+        assert_eq!(parse_memory("1024").unwrap(), 1024);
+        assert_eq!(parse_memory("1k").unwrap(), 1000);
+        assert_eq!(parse_memory("100MB").unwrap(), 100 * 1024 * 1024);
+        assert_eq!(parse_memory("2gb").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert!(parse_memory("12xb").is_err());
+        assert!(parse_memory("mb").is_err());
+    }
+
+    #[test]
+    fn eviction_policies() {
+        let config = Config::from_args(
+            ["--maxmemory", "1mb", "--maxmemory-policy", "ALLKEYS-LRU"].map(String::from),
+        )
+        .unwrap();
+        assert_eq!(config.maxmemory, 1024 * 1024);
+        assert_eq!(config.maxmemory_policy, EvictionPolicy::AllKeysLru);
+        assert_eq!(config.maxmemory_policy.to_string(), "allkeys-lru");
+        assert!("lru".parse::<EvictionPolicy>().is_err());
+        assert_eq!(config.maxmemory_samples, 5);
+        assert!(Config::from_args(["--maxmemory-samples", "0"].map(String::from)).is_err());
+        assert!(config.maxmemory_policy.lfu() || config.lfu_log_factor == 10);
+    }
+
+    #[test]
+    fn client_memory_limits() {
+        let mut config = Config::from_args(
+            ["--maxmemory", "1mb", "--maxmemory-clients", "10%"].map(String::from),
+        )
+        .unwrap();
+        assert_eq!(config.client_memory_limit(), 1024 * 1024 / 10);
+        assert_eq!(config.maxmemory_clients.to_string(), "10%");
+        config.maxmemory = 0;
+        assert_eq!(config.client_memory_limit(), 0);
+        assert_eq!(
+            "64mb".parse::<ClientMemoryLimit>().unwrap(),
+            ClientMemoryLimit::Bytes(64 * 1024 * 1024)
+        );
+        assert!("0%".parse::<ClientMemoryLimit>().is_err());
+        assert_eq!(Config::default().client_memory_limit(), 0);
+    }
+
+    #[test]
+    fn tcp_options() {
+        let config = Config::from_args(
+            [
+                "--tcp-keepalive",
+                "0",
+                "--tcp-nodelay",
+                "no",
+                "--tcp-backlog",
+                "1024",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        assert!(config.tcp_keepalive.is_zero());
+        assert!(!config.tcp_nodelay);
+        assert_eq!(config.tcp_backlog, 1024);
+        assert_eq!(Config::default().tcp_keepalive, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn output_buffer_limits() {
+        let config = Config::from_args(
+            [
+                "--client-output-buffer-limit",
+                "normal 1mb 512kb 10",
+                "--client-output-buffer-limit",
+                "slave",
+                "0",
+                "0",
+                "0",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        let limits = config.client_output_buffer_limit;
+        assert_eq!(
+            limits.normal,
+            OutputBufferLimit::new(1 << 20, 512 << 10, 10)
+        );
+        assert_eq!(limits.replica, OutputBufferLimit::default());
+        assert_eq!(limits.pubsub, OutputBufferLimits::default().pubsub);
+        assert!(Config::from_args(
+            ["--client-output-buffer-limit", "normal 1 2"].map(String::from)
+        )
+        .is_err());
+
+        let limit = limits.normal;
+        let now = Instant::now();
+        let mut since = None;
+        assert!(!limit.exceeded(1000, &mut since, now));
+        assert!(limit.exceeded(2 << 20, &mut since, now));
+        assert!(!limit.exceeded(600 << 10, &mut since, now));
+        assert!(!limit.exceeded(600 << 10, &mut since, now + Duration::from_secs(5)));
+        assert!(limit.exceeded(600 << 10, &mut since, now + Duration::from_secs(10)));
+        assert!(!limit.exceeded(0, &mut since, now + Duration::from_secs(10)));
+        assert_eq!(since, None);
+    }
+
+    #[test]
+    fn bind_addresses() {
+        assert_eq!(
+            parse_bind("0.0.0.0 ::1").unwrap(),
+            [
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::LOCALHOST)
+            ]
+        );
+        assert_eq!(
+            parse_bind("* ::*").unwrap(),
+            [
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            ]
+        );
+        assert!(parse_bind("localhost").is_err());
+        assert!(parse_bind(" ").is_err());
+    }
+
+    #[test]
+    fn ip_filter() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let config = Config::from_args(
+            [
+                "--ip-allow",
+                "10.0.0.0/8 ::1 fd00::/8",
+                "--ip-deny=10.1.0.0/16",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        let filter = &config.ip_filter;
+        assert!(filter.permits(ip("10.2.3.4")));
+        assert!(!filter.permits(ip("10.1.3.4")));
+        assert!(!filter.permits(ip("192.168.0.1")));
+        assert!(filter.permits(ip("::1")));
+        assert!(filter.permits(ip("fd12::1")));
+        assert!(!filter.permits(ip("fe80::1")));
+        assert!(IpFilter::default().permits(ip("192.168.0.1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("1.2.3.4")));
+        assert!(!"0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("::1")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn logging() {
+        let config = Config::from_args(
+            [
+                "--loglevel",
+                "warning",
+                "--logfile",
+                "",
+                "--log-format",
+                "json",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        assert_eq!(config.loglevel, LevelFilter::WARN);
+        assert_eq!(config.logfile, None);
+        assert!(config.log_json);
+        assert_eq!(parse_loglevel("debug").unwrap(), LevelFilter::TRACE);
+        assert_eq!(parse_loglevel("Error").unwrap(), LevelFilter::ERROR);
+        assert!(parse_loglevel("loud").is_err());
+    }
+
+    #[test]
+    fn protected_mode() {
+        let remote = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let config = Config::default();
+        assert!(config.refuses(remote));
+        assert!(!config.refuses(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(!config.refuses(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        let bound = Config::from_args(["--bind", "*"].map(String::from)).unwrap();
+        assert!(!bound.refuses(remote));
+        let disabled = Config::from_args(["--protected-mode", "no"].map(String::from)).unwrap();
+        assert!(!disabled.refuses(remote));
+        let password = Config::from_args(["--requirepass", "secret"].map(String::from)).unwrap();
+        assert!(!password.refuses(remote));
+        let empty = Config::from_args(["--requirepass", ""].map(String::from)).unwrap();
+        assert_eq!(empty.requirepass, None);
+    }
+
+    #[test]
+    fn cluster_announce_ip() {
+        assert_eq!(Config::default().cluster_announce_ip(), "127.0.0.1");
+        let bound = Config::from_args(["--bind", "* 10.0.0.5"].map(String::from)).unwrap();
+        assert_eq!(bound.cluster_announce_ip(), "10.0.0.5");
+        let announced = Config::from_args(
+            ["--bind", "10.0.0.5", "--cluster-announce-ip", "192.0.2.1"].map(String::from),
+        )
+        .unwrap();
+        assert_eq!(announced.cluster_announce_ip(), "192.0.2.1");
+    }
+
+    #[test]
+    fn command_line() {
+        cli().debug_assert();
+        let config = Config::from_args(
+            [
+                "--list-max-listpack-size",
+                "-3",
+                "--replicaof",
+                "localhost",
+                "7001",
+                "--port",
+                "7000",
+                "--port",
+                "7001",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        assert_eq!(config.list_max_listpack_size, -3);
+        assert_eq!(config.replicaof, Some(("localhost".to_string(), 7001)));
+        assert_eq!(config.port, 7001);
+        assert!(Config::from_args(["--port", "1", "a.conf", "2"].map(String::from)).is_err());
+        assert!(Config::from_args(["--no-such-option", "1"].map(String::from)).is_err());
+    }
+
+    #[test]
+    fn config_get() {
+        let config = Config::from_args(["--maxmemory", "1mb"].map(String::from)).unwrap();
+        assert_eq!(config.get("save"), [("save", String::new())]);
+        assert_eq!(config.get("APPENDONLY"), [("appendonly", "no".to_string())]);
+        assert_eq!(
+            config.get("maxmemory"),
+            [("maxmemory", "1048576".to_string())]
+        );
+        let names: Vec<_> = config
+            .get("maxmemory-*")
+            .into_iter()
+            .map(|(n, _)| n)
+            .collect();
+        assert_eq!(
+            names,
+            ["maxmemory-policy", "maxmemory-samples", "maxmemory-clients"]
+        );
+        assert_eq!(config.get("p?rt").len(), 1);
+        assert!(config.get("notify-keyspace-events").is_empty());
+        assert_eq!(config.get("*").len(), config.get("**").len());
+    }
+
+    #[test]
+    fn inline_values() {
+        let config = Config::from_args(
+            [
+                "--port=7000",
+                "--bind",
+                "::1",
+                "--client-output-buffer-limit=normal 1kb 0 0",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.bind, [IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+        assert_eq!(config.client_output_buffer_limit.normal.hard, 1024);
+        assert!(Config::from_args(["--port="].map(String::from)).is_err());
+        assert!(Config::from_args(["--appenddirname=../elsewhere"].map(String::from)).is_err());
+    }
+
+    #[test]
+    fn config_file_with_overrides() {
+        let path =
+            std::env::temp_dir().join(format!("redis-load-test-{}.conf", std::process::id()));
+        std::fs::write(
+            &path,
+            "port 7000
+maxmemory 2mb
+replicaof localhost 7001
+replica-announce-ip 10.0.0.7
+replica-priority 0
+appendonly yes
+appendfsync always
+",
+        )
+        .unwrap();
+        let args = [
+            path.display().to_string(),
+            "--port".to_string(),
+            "7002".to_string(),
+        ];
+        let config = Config::load(args).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.port, 7002);
+        assert_eq!(config.maxmemory, 2 * 1024 * 1024);
+        assert_eq!(config.replicaof, Some(("localhost".to_string(), 7001)));
+        assert_eq!(config.replica_announce_ip.as_deref(), Some("10.0.0.7"));
+        assert_eq!(config.replica_priority, 0);
+        assert!(config.appendonly);
+        assert_eq!(config.appendfsync, AppendFsync::Always);
+        assert_eq!(config.aof_dir(), PathBuf::from("./appendonlydir"));
+    }
+
+    #[test]
+    fn environment_overrides() {
+        let mut config = Config::from_args(["--port", "7000"].map(String::from)).unwrap();
+        let vars = [
+            ("REDIS_PORT", "7001"),
+            ("REDIS_MAXMEMORY_POLICY", "allkeys-lru"),
+            ("REDIS_URL", "redis://localhost"),
+            ("HOME", "/root"),
+            // what Kubernetes sets for a service named redis
+            ("REDIS_SERVICE_HOST", "10.0.0.11"),
+            ("REDIS_SERVICE_PORT", "6379"),
+            ("REDIS_PORT_6379_TCP_PORT", "6379"),
+        ];
+        config
+            .apply_env(vars.map(|(var, value)| (var.to_string(), value.to_string())))
+            .unwrap();
+        assert_eq!(config.port, 7001);
+        assert_eq!(config.maxmemory_policy, EvictionPolicy::AllKeysLru);
+        let bad = [("REDIS_PORT".to_string(), "x".to_string())];
+        assert!(config.apply_env(bad).is_err());
+        let link = [("REDIS_PORT".to_string(), "tcp://10.0.0.11:6379".to_string())];
+        config.apply_env(link).unwrap();
+        assert_eq!(config.port, 7001);
+    }
+}
@@ -0,0 +1,196 @@
+use std::{fmt, path::Path};
+
+use anyhow::{Context, Result};
+
+/// How deep `include` directives may nest, to catch files including each other
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A line of a redis.conf file
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct Directive {
+    /// Directive name, lower-cased
+    pub(super) name: String,
+    pub(super) args: Vec<String>,
+    pub(super) location: Location,
+}
+
+/// Where a directive was read from, for error messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Location {
+    pub(super) path: String,
+    pub(super) line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.path, self.line)
+    }
+}
+
+/// Read the directives of the redis.conf file at `path`, in order, with those of the files it
+/// includes in place of the `include` lines
+pub(super) fn parse(path: &Path) -> Result<Vec<Directive>> {
+    let mut directives = Vec::new();
+    read(path, 0, &mut directives)?;
+    Ok(directives)
+}
+
+fn read(path: &Path, depth: usize, directives: &mut Vec<Directive>) -> Result<()> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(anyhow::anyhow!(
+            "Too many nested includes at {}",
+            path.display()
+        ));
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    for (i, line) in content.lines().enumerate() {
+        let location = Location {
+            path: path.display().to_string(),
+            line: i + 1,
+        };
+        if line.trim_start().starts_with('#') {
+            continue;
+        }
+        let mut args = split_args(line).with_context(|| format!("Bad line at {location}"))?;
+        if args.is_empty() {
+            continue;
+        }
+        let name = args.remove(0).to_ascii_lowercase();
+        if name == "include" {
+            // like Redis, included paths are relative to the working directory
+            let [included] = &args[..] else {
+                return Err(anyhow::anyhow!("include takes one path at {location}"));
+            };
+            read(Path::new(included), depth + 1, directives)?;
+            continue;
+        }
+        directives.push(Directive {
+            name,
+            args,
+            location,
+        });
+    }
+    Ok(())
+}
+
+/// Split a line into arguments the way Redis does: separated by whitespace, either of which may
+/// be "double quoted" with C-style escapes or 'single quoted'
+pub(crate) fn split_args(line: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            return Ok(args);
+        };
+        let mut arg = String::new();
+        match first {
+            '"' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some('n') => arg.push('\n'),
+                            Some('r') => arg.push('\r'),
+                            Some('t') => arg.push('\t'),
+                            Some('b') => arg.push('\u{8}'),
+                            Some('a') => arg.push('\u{7}'),
+                            Some('x') => {
+                                let hex: String = chars.clone().take(2).collect();
+                                match u8::from_str_radix(&hex, 16) {
+                                    Ok(byte) if hex.len() == 2 => {
+                                        arg.push(char::from(byte));
+                                        chars.nth(1);
+                                    }
+                                    _ => arg.push('x'),
+                                }
+                            }
+                            Some(c) => arg.push(c),
+                            None => return Err(anyhow::anyhow!("Unbalanced quotes")),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(anyhow::anyhow!("Unbalanced quotes")),
+                    }
+                }
+                closing_quote(&mut chars)?;
+            }
+            '\'' => {
+                chars.next();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some('\\') if chars.peek() == Some(&'\'') => {
+                            arg.push('\'');
+                            chars.next();
+                        }
+                        Some(c) => arg.push(c),
+                        None => return Err(anyhow::anyhow!("Unbalanced quotes")),
+                    }
+                }
+                closing_quote(&mut chars)?;
+            }
+            _ => {
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    arg.push(c);
+                }
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// A closing quote must end the argument
+fn closing_quote(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) -> Result<()> {
+    match chars.peek() {
+        Some(c) if !c.is_whitespace() => {
+            Err(anyhow::anyhow!("Closing quote must be followed by a space"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_like_redis() {
+        assert_eq!(split_args("  port   6380 ").unwrap(), vec!["port", "6380"]);
+        assert_eq!(
+            split_args(r#"dir "/var/lib/my redis" 'it\'s' "\x41\t""#).unwrap(),
+            vec!["dir", "/var/lib/my redis", "it's", "A\t"]
+        );
+        assert_eq!(split_args(r#"save """#).unwrap(), vec!["save", ""]);
+        assert!(split_args(r#"dir "/tmp"#).is_err());
+        assert!(split_args(r#"dir "/tmp"x"#).is_err());
+    }
+
+    #[test]
+    fn follows_includes() {
+        let dir = std::env::temp_dir().join(format!("redis-conf-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let included = dir.join("included.conf");
+        std::fs::write(&included, "maxmemory 1mb\n").unwrap();
+        let main = dir.join("redis.conf");
+        std::fs::write(
+            &main,
+            format!(
+                "# comment\n\nPort 6380\ninclude {}\nbind 127.0.0.1 ::1\n",
+                included.display()
+            ),
+        )
+        .unwrap();
+
+        let directives = parse(&main).unwrap();
+        let names: Vec<_> = directives.iter().map(|d| d.name.as_str()).collect();
+        assert_eq!(names, vec!["port", "maxmemory", "bind"]);
+        assert_eq!(directives[2].args, vec!["127.0.0.1", "::1"]);
+        assert_eq!(directives[2].location.line, 5);
+
+        std::fs::write(&included, format!("include {}\n", included.display())).unwrap();
+        assert!(parse(&main).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
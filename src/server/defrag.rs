@@ -0,0 +1,163 @@
+//! Active defragmentation. Lists keep the buffers they grew to after their elements are popped,
+//! and the tables keys are held in keep their size after the keys are deleted. A background
+//! cycle samples lists, reallocates those with the most spare room to fit, and shrinks tables
+//! left mostly empty, within a share of every period.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::server::storage::Storage;
+
+/// How often the cycle runs, as the active expire cycle does
+const CYCLE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Lists sampled per round of a cycle
+const LISTS_PER_ROUND: usize = 20;
+
+/// Values reallocated
+static HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Values sampled that had too little spare room to be worth reallocating
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes given back by reallocating values and shrinking tables
+static RECLAIMED: AtomicU64 = AtomicU64::new(0);
+
+/// What a round of sampling did
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Round {
+    hits: u64,
+    misses: u64,
+    reclaimed: u64,
+}
+
+/// Compact `db` in the background, like Redis' `activeDefragCycle`: every cycle takes up to
+/// `cycle_max` percent of its period, and reallocates the values whose spare room is at least
+/// `threshold` percent of their size, the roomiest of each sample first
+pub(crate) async fn active_defrag_cycle(db: Arc<dyn Storage>, threshold: u8, cycle_max: u8) {
+    let budget = CYCLE_PERIOD * cycle_max as u32 / 100;
+    let mut interval = tokio::time::interval(CYCLE_PERIOD);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let deadline = Instant::now() + budget;
+        let mut total = Round {
+            reclaimed: db.compact_tables() as u64,
+            ..Round::default()
+        };
+        // carry on while the samples keep turning up values worth reallocating
+        loop {
+            let round = defrag_round(&*db, LISTS_PER_ROUND, threshold, deadline);
+            let again = round.hits > 0;
+            total.hits += round.hits;
+            total.misses += round.misses;
+            total.reclaimed += round.reclaimed;
+            if !again || Instant::now() >= deadline {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        HITS.fetch_add(total.hits, Ordering::Relaxed);
+        MISSES.fetch_add(total.misses, Ordering::Relaxed);
+        RECLAIMED.fetch_add(total.reclaimed, Ordering::Relaxed);
+        if total.reclaimed > 0 {
+            tracing::debug!(
+                "Active defrag reallocated {} values, reclaiming {} bytes",
+                total.hits,
+                total.reclaimed
+            );
+        }
+    }
+}
+
+/// Sample `count` lists and reallocate those with at least `threshold` percent spare room, the
+/// roomiest first, until `deadline`
+fn defrag_round(db: &dyn Storage, count: usize, threshold: u8, deadline: Instant) -> Round {
+    let mut sampled: Vec<_> = db
+        .sample_lists(count)
+        .into_iter()
+        .filter_map(|key| db.list_slack(&key).map(|(slack, size)| (key, slack, size)))
+        .collect();
+    // sampling may pick a list more than once
+    sampled.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    sampled.dedup_by(|a, b| a.0 == b.0);
+
+    let mut round = Round::default();
+    for (key, slack, size) in sampled {
+        if slack * 100 < size * threshold as usize {
+            round.misses += 1;
+            continue;
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+        round.hits += 1;
+        round.reclaimed += db.compact_list(&key) as u64;
+    }
+    round
+}
+
+/// Values reallocated so far
+pub(crate) fn hits() -> u64 {
+    HITS.load(Ordering::Relaxed)
+}
+
+/// Values sampled with too little spare room to reallocate so far
+pub(crate) fn misses() -> u64 {
+    MISSES.load(Ordering::Relaxed)
+}
+
+/// Bytes reclaimed so far
+pub(crate) fn reclaimed() -> u64 {
+    RECLAIMED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::server::types::{Database, Value};
+
+    #[test]
+    fn reallocates_lists_with_spare_room() {
+        let db = Database::default();
+        let element = || Value::new(Bytes::from("x".repeat(50)), None);
+        let (popped, kept) = (Bytes::from("popped"), Bytes::from("kept"));
+        db.push(&popped, (0..1000).map(|_| element()).collect(), false);
+        db.push(&kept, (0..1000).map(|_| element()).collect(), false);
+        db.pop(&popped, 990, true);
+
+        let used = db.used_memory();
+        let far = Instant::now() + Duration::from_secs(60);
+        let round = defrag_round(&db, 64, 10, far);
+        assert_eq!((round.hits, round.misses), (1, 1));
+        assert!(round.reclaimed > 0);
+        assert_eq!(db.used_memory(), used - round.reclaimed as usize);
+        assert_eq!(db.list_range(&popped, 0, -1).unwrap().len(), 10);
+
+        // nothing is left worth reallocating
+        let round = defrag_round(&db, 64, 10, far);
+        assert_eq!((round.hits, round.misses), (0, 2));
+    }
+
+    #[test]
+    fn shrinks_mostly_empty_tables() {
+        let db = Database::default();
+        let keys: Vec<_> = (0..1000).map(|i| Bytes::from(format!("key:{i}"))).collect();
+        for key in &keys {
+            db.set_key(key, Value::new(Bytes::from("v"), None));
+        }
+        assert_eq!(db.compact_tables(), 0);
+        for key in &keys[10..] {
+            db.delete(key, false);
+        }
+        assert!(db.compact_tables() > 0);
+        assert_eq!(db.compact_tables(), 0);
+    }
+}
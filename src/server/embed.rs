@@ -0,0 +1,373 @@
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    command::registry::CommandRegistry,
+    resp::RedisValue,
+    server::{
+        clock::{Clock, SystemClock},
+        config::Config,
+        CommandFlags, Keyspace, Middleware, Redis,
+    },
+};
+
+/// Builds a [`Redis`] to run inside another program, such as an integration test:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// let redis = codecrafters_redis::server::Redis::builder()
+///     .port(0)
+///     .dir(std::env::temp_dir())
+///     .start()
+///     .await?;
+/// let addr = redis.addr(); // connect clients here
+/// redis.stop().await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Unlike the binary, an embedded server listens on the loopback interface by default and
+/// leaves SIGTERM and SIGINT to the host program. Client counters and tracking tables are
+/// process wide, so they are shared by servers embedded in the same process.
+#[derive(Debug)]
+pub struct RedisBuilder {
+    config: Config,
+    /// Options by redis.conf name, applied on top of `config` when building
+    options: Vec<(String, String)>,
+    commands: CommandRegistry,
+    /// The first command that could not be registered, failing the build
+    invalid_command: Option<anyhow::Error>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for RedisBuilder {
+    fn default() -> Self {
+        Self {
+            config: Config {
+                bind: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+                ..Config::default()
+            },
+            options: Vec::new(),
+            commands: CommandRegistry::default(),
+            invalid_command: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl RedisBuilder {
+    /// Start from `config` rather than the defaults
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Port to listen on, 0 for any free one
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    pub fn bind(mut self, addrs: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.config.bind = addrs.into_iter().collect();
+        self
+    }
+
+    /// Directory holding the RDB file
+    pub fn dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.dir = dir.into();
+        self
+    }
+
+    pub fn dbfilename(mut self, dbfilename: impl Into<String>) -> Self {
+        self.config.dbfilename = dbfilename.into();
+        self
+    }
+
+    /// Replicate from the server at `host:port`
+    pub fn replicaof(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.config.replicaof = Some((host.into(), port));
+        self
+    }
+
+    /// Set any option by its redis.conf name, e.g. `option("maxmemory", "100mb")`. Bad options
+    /// fail [`build`](Self::build).
+    pub fn option(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.options.push((name.into(), value.into()));
+        self
+    }
+
+    /// Serve a custom command, such as one exposing the embedding program's own state.
+    /// `arity` counts the arguments including the command name, negative for at least that
+    /// many, and `handler` gets them all. Names taken by other commands fail
+    /// [`build`](Self::build).
+    pub fn command<F, Fut>(
+        mut self,
+        name: &str,
+        arity: i32,
+        flags: CommandFlags,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(Vec<Bytes>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<RedisValue>> + Send + 'static,
+    {
+        let handler = Arc::new(move |args| Box::pin(handler(args)) as _);
+        if let Err(e) = self.commands.register(name, arity, flags, handler) {
+            self.invalid_command.get_or_insert(e);
+        }
+        self
+    }
+
+    /// Run `middleware` around every command clients send, after any added before it
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.commands.add_middleware(Arc::new(middleware));
+        self
+    }
+
+    /// Judge key expirations by `clock`, e.g. a [`MockClock`](crate::server::MockClock) a test
+    /// moves along instead of sleeping
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Bind the listeners and start loading the dataset, ready to [`run`](Redis::run)
+    pub async fn build(mut self) -> Result<Redis> {
+        if let Some(e) = self.invalid_command {
+            return Err(e);
+        }
+        for (name, value) in &self.options {
+            self.config.set_option(name, value)?;
+        }
+        let db = Redis::in_memory(&self.config, self.clock);
+        let mut redis = Redis::from_parts(self.config, db, self.commands).await?;
+        redis.signals = false;
+        Ok(redis)
+    }
+
+    /// Build the server, run it in the background and wait for its dataset to load
+    pub async fn start(self) -> Result<RunningRedis> {
+        let redis = self.build().await?;
+        let addr = redis.local_addr()?;
+        let shutdown = redis.shutdown_handle();
+        let keyspace = redis.keyspace();
+        let replication = redis.replication.clone();
+        let mut task = tokio::spawn(redis.run());
+        // hand the server over with its dataset in place, or why it couldn't load it
+        tokio::select! {
+            _ = replication.wait_loaded() => {}
+            result = &mut task => {
+                result??;
+                anyhow::bail!("Stopped before loading the dataset");
+            }
+        }
+        Ok(RunningRedis {
+            addr,
+            shutdown,
+            keyspace,
+            task,
+        })
+    }
+}
+
+/// Asks a running [`Redis`] to shut down, as SIGTERM would
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(pub(super) CancellationToken);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.cancel();
+    }
+}
+
+/// A server started with [`RedisBuilder::start`]
+#[derive(Debug)]
+pub struct RunningRedis {
+    addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    keyspace: Keyspace,
+    task: JoinHandle<Result<()>>,
+}
+
+impl RunningRedis {
+    /// Address clients can connect to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// The server's data, to inspect or seed it while it runs
+    pub fn keyspace(&self) -> &Keyspace {
+        &self.keyspace
+    }
+
+    /// Shut the server down and wait for it to finish
+    pub async fn stop(self) -> Result<()> {
+        self.shutdown.shutdown();
+        self.task.await?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_in_process() {
+        let redis = Redis::builder()
+            .port(0)
+            .dir(std::env::temp_dir())
+            .dbfilename("embed-test-missing.rdb")
+            .option("maxclients", "10")
+            .command("GREET", 2, CommandFlags::FAST, |args| async move {
+                let mut greeting = Bytes::from_static(b"hello ").to_vec();
+                greeting.extend_from_slice(&args[1]);
+                Ok(RedisValue::BulkString(greeting.into()))
+            })
+            .start()
+            .await
+            .unwrap();
+        assert_ne!(redis.addr().port(), 0);
+
+        let mut client = TcpStream::connect(redis.addr()).await.unwrap();
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let mut reply = [0; 7];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+        client
+            .write_all(b"*2\r\n$5\r\ngreet\r\n$3\r\nyou\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0; 15];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"$9\r\nhello you\r\n");
+
+        redis.stop().await.unwrap();
+        assert!(Redis::builder()
+            .command("get", 2, CommandFlags::NONE, |_| async {
+                Ok(RedisValue::NullBulkString)
+            })
+            .build()
+            .await
+            .is_err());
+        assert!(Redis::builder()
+            .option("no-such-option", "1")
+            .build()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn full_resyncs_wait_out_a_bgsave_without_blocking_writes() {
+        let redis = Redis::builder()
+            .port(0)
+            .dir(std::env::temp_dir())
+            .dbfilename("embed-test-missing.rdb")
+            .build()
+            .await
+            .unwrap();
+        redis.replication.wait_loaded().await;
+        // what a BGSAVE holds while it runs, held here until the test is done with it
+        let bgsave = redis.db.snapshot().unwrap();
+        let addr = redis.local_addr().unwrap();
+        let shutdown = redis.shutdown_handle();
+        let task = tokio::spawn(redis.run());
+
+        let mut replica = TcpStream::connect(addr).await.unwrap();
+        replica.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let mut reply = [0; 7];
+        replica.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+        replica
+            .write_all(b"*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n")
+            .await
+            .unwrap();
+        // let the PSYNC start waiting for the snapshot
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"*1\r\n$6\r\nBGSAVE\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n")
+            .await
+            .unwrap();
+        let expected = b"-ERR Background save already in progress\r\n+OK\r\n";
+        let mut reply = [0; 47];
+        tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut reply))
+            .await
+            .expect("writes blocked by a full resync waiting for a BGSAVE")
+            .unwrap();
+        assert_eq!(&reply, expected);
+
+        // once the save is done, the replica gets its full resync
+        drop(bgsave);
+        let mut reply = [0; 12];
+        tokio::time::timeout(Duration::from_secs(5), replica.read_exact(&mut reply))
+            .await
+            .expect("no full resync once the BGSAVE finished")
+            .unwrap();
+        assert_eq!(&reply, b"+FULLRESYNC ");
+
+        shutdown.shutdown();
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_most_commands_while_loading() {
+        let redis = Redis::builder()
+            .port(0)
+            .dir(std::env::temp_dir())
+            .dbfilename("embed-test-missing.rdb")
+            .build()
+            .await
+            .unwrap();
+        let replication = redis.replication.clone();
+        replication.wait_loaded().await;
+        // as if a full resync were being loaded
+        replication.set_loading(true);
+        let addr = redis.local_addr().unwrap();
+        let shutdown = redis.shutdown_handle();
+        let task = tokio::spawn(redis.run());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$4\r\nPING\r\n")
+            .await
+            .unwrap();
+        let expected = b"-LOADING Redis is loading the dataset in memory\r\n+PONG\r\n";
+        let mut reply = vec![0; expected.len()];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, expected);
+
+        replication.set_loading(false);
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"$-1\r\n");
+
+        shutdown.shutdown();
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,262 @@
+use crate::server::{
+    config::{Config, EvictionPolicy},
+    storage::Storage,
+    types::{random, RedisKey},
+};
+
+/// Candidates remembered between evictions, as Redis' `EVPOOL_SIZE`
+const EVICTION_POOL_SIZE: usize = 16;
+
+/// Give up making room after this many samples in a row turn up nothing evictable
+const MAX_FRUITLESS_SAMPLES: usize = 64;
+
+/// The best eviction candidates sampled so far, kept from one eviction to the next so every
+/// choice draws on more than a single sample
+#[derive(Default)]
+pub(crate) struct EvictionPool {
+    /// `(score, key)` in ascending order, the highest score being evicted first
+    candidates: Vec<(u64, RedisKey)>,
+}
+
+impl EvictionPool {
+    /// Offer a sampled key, dropping the worst candidate if the pool is full
+    fn offer(&mut self, score: u64, key: RedisKey) {
+        if let Some(index) = self.candidates.iter().position(|(_, k)| *k == key) {
+            self.candidates.remove(index);
+        }
+        if self.candidates.len() == EVICTION_POOL_SIZE {
+            if score <= self.candidates[0].0 {
+                return;
+            }
+            self.candidates.remove(0);
+        }
+        let index = self.candidates.partition_point(|(s, _)| *s < score);
+        self.candidates.insert(index, (score, key));
+    }
+
+    /// Take the most evictable candidate
+    fn pop(&mut self) -> Option<RedisKey> {
+        self.candidates.pop().map(|(_, key)| key)
+    }
+}
+
+/// Evict keys until the dataset fits in `maxmemory` (0 meaning no limit).
+///
+/// Every evicted key is handed to `evicted`, e.g. to propagate a DEL. Returns `false` when the
+/// policy forbids evicting or nothing evictable is left, in which case the write must be refused.
+pub(crate) fn make_room(
+    db: &dyn Storage,
+    config: &Config,
+    mut evicted: impl FnMut(RedisKey),
+) -> bool {
+    let policy = config.maxmemory_policy;
+    if config.maxmemory == 0 {
+        return true;
+    }
+    let mut pool = db.eviction_pool();
+    let mut fruitless = 0;
+    while db.used_memory() as u64 > config.maxmemory {
+        if policy == EvictionPolicy::NoEviction {
+            return false;
+        }
+        match pick_victim(db, policy, config.maxmemory_samples, &mut pool) {
+            Some(key) => {
+                tracing::debug!("Evicting {key:?} under {policy}");
+                db.evict(&key, config.lazyfree_lazy_eviction);
+                evicted(key);
+                fruitless = 0;
+            }
+            None => {
+                fruitless += 1;
+                if fruitless >= MAX_FRUITLESS_SAMPLES {
+                    tracing::warn!(
+                        "Nothing left to evict under {policy} with {} keys stored",
+                        db.len()
+                    );
+                    return false;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Sample `samples` keys of each type the policy may evict, along with their expirations
+fn sample(
+    db: &dyn Storage,
+    policy: EvictionPolicy,
+    samples: usize,
+) -> Vec<(RedisKey, Option<u64>)> {
+    // lists and collections rarely carry an expiration, so volatile policies only look at
+    // strings
+    let mut keys = db.sample_strings(samples);
+    if !policy.volatile() {
+        keys.extend(db.sample_lists(samples));
+        keys.extend(db.sample_collections(samples));
+    }
+    keys.into_iter()
+        .map(|key| {
+            let expiration = db.get_key_expiration(&key);
+            (key, expiration)
+        })
+        .filter(|(_, expiration)| !policy.volatile() || expiration.is_some())
+        .collect()
+}
+
+/// Choose a key to evict, like Redis' `performEvictions`: random policies take any sampled key,
+/// the others feed the sample into the pool and evict its best candidate that is still around
+fn pick_victim(
+    db: &dyn Storage,
+    policy: EvictionPolicy,
+    samples: usize,
+    pool: &mut EvictionPool,
+) -> Option<RedisKey> {
+    let mut candidates = sample(db, policy, samples);
+    if matches!(
+        policy,
+        EvictionPolicy::AllKeysRandom | EvictionPolicy::VolatileRandom
+    ) {
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = random() as usize % candidates.len();
+        return Some(candidates.swap_remove(index).0);
+    }
+
+    let now = db.clock().now_ms();
+    for (key, expiration) in candidates {
+        let score = match (policy, expiration) {
+            // the sooner a key expires the better a candidate it is
+            (EvictionPolicy::VolatileTtl, Some(at)) => u64::MAX - at.saturating_sub(now),
+            // the least frequently used keys have the lowest counters
+            _ if policy.lfu() => match db.frequency(&key) {
+                Some(frequency) => (u8::MAX - frequency) as u64,
+                None => continue,
+            },
+            _ => match db.idle_ms(&key) {
+                Some(idle) => idle,
+                None => continue,
+            },
+        };
+        pool.offer(score, key);
+    }
+    // candidates from earlier samples may since have been deleted or lost their expiration
+    while let Some(key) = pool.pop() {
+        if db.exists(&key) && (!policy.volatile() || db.get_key_expiration(&key).is_some()) {
+            return Some(key);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::types::{Database, Value};
+
+    fn config(maxmemory: u64, policy: EvictionPolicy) -> Config {
+        Config {
+            maxmemory,
+            maxmemory_policy: policy,
+            ..Config::default()
+        }
+    }
+
+    fn fill(db: &dyn Storage, count: usize, ttl: bool) {
+        for i in 0..count {
+            let expiration = ttl.then(|| db.clock().now_ms() + (60 + i as u64) * 1000);
+            db.set_key(
+                &format!("{}{i}", if ttl { "v" } else { "p" }).into(),
+                Value::new("x".repeat(100).into(), expiration),
+            );
+        }
+    }
+
+    #[test]
+    fn noeviction_refuses() {
+        let db = Database::default();
+        fill(&db, 10, false);
+        let limit = db.used_memory() as u64 / 2;
+        assert!(!make_room(
+            &db,
+            &config(limit, EvictionPolicy::NoEviction),
+            |_| {}
+        ));
+        assert!(make_room(
+            &db,
+            &config(0, EvictionPolicy::NoEviction),
+            |_| {}
+        ));
+        assert_eq!(db.len(), 10);
+    }
+
+    #[test]
+    fn allkeys_evicts_until_under_limit() {
+        let db = Database::default();
+        fill(&db, 100, false);
+        let limit = db.used_memory() as u64 / 2;
+        let mut evicted = Vec::new();
+        assert!(make_room(
+            &db,
+            &config(limit, EvictionPolicy::AllKeysRandom),
+            |key| { evicted.push(key) }
+        ));
+        assert!(db.used_memory() as u64 <= limit);
+        assert_eq!(db.len(), 100 - evicted.len());
+    }
+
+    #[test]
+    fn volatile_only_touches_keys_with_ttl() {
+        let db = Database::default();
+        fill(&db, 10, false);
+        let persistent = db.used_memory() as u64;
+        fill(&db, 10, true);
+
+        assert!(make_room(
+            &db,
+            &config(persistent, EvictionPolicy::VolatileTtl),
+            |key| { assert!(key.starts_with(b"v")) }
+        ));
+        assert_eq!(db.len(), 10);
+        // nothing volatile is left, so going lower has to fail
+        assert!(!make_room(
+            &db,
+            &config(persistent / 2, EvictionPolicy::VolatileLru),
+            |_| {}
+        ));
+    }
+
+    #[test]
+    fn lfu_keeps_frequently_used_keys() {
+        let db = Database::default();
+        fill(&db, 20, false);
+        let hot = RedisKey::from("p7");
+        for _ in 0..1000 {
+            db.get_key(&hot);
+        }
+        let limit = db.used_memory() as u64 / 4;
+        assert!(make_room(
+            &db,
+            &config(limit, EvictionPolicy::AllKeysLfu),
+            |key| assert_ne!(key, hot)
+        ));
+        assert!(db.exists(&hot));
+    }
+
+    #[test]
+    fn pool_keeps_the_best_candidates() {
+        let mut pool = EvictionPool::default();
+        for score in 0..EVICTION_POOL_SIZE as u64 * 2 {
+            pool.offer(score, format!("k{score}").into());
+        }
+        // a worse candidate than any pooled one is turned away
+        pool.offer(0, "late".into());
+        // offering a pooled key again updates its score rather than duplicating it
+        pool.offer(100, "k20".into());
+
+        assert_eq!(pool.candidates.len(), EVICTION_POOL_SIZE);
+        assert_eq!(pool.pop(), Some("k20".into()));
+        assert_eq!(pool.pop(), Some("k31".into()));
+        assert_eq!(pool.candidates.first().unwrap().1, RedisKey::from("k16"));
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    replication::ReplicationState,
+    resp::RedisValue,
+    server::{
+        propagation::{Propagator, Write},
+        storage::Storage,
+        types::RedisKey,
+        watch::KeyEvent,
+    },
+};
+
+mod wheel;
+
+pub(crate) use wheel::TimerWheel;
+
+/// How often the active expire cycle runs, Redis' default `hz` of 10
+const CYCLE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Time one cycle may spend, a quarter of the period as in Redis
+const CYCLE_BUDGET: Duration = Duration::from_millis(25);
+
+/// Keys sampled per round of a cycle
+const KEYS_PER_ROUND: usize = 20;
+
+/// Keep sampling while more than this percentage of the volatile keys sampled had expired
+const ACCEPTABLE_STALE_PERCENT: usize = 10;
+
+/// Weight of the previous estimate in the running average of time to live, in fiftieths
+const AVG_TTL_WEIGHT: u64 = 49;
+
+/// Periodically reclaim expired keys nobody reads, like Redis' `activeExpireCycle`.
+///
+/// The expiration wheel deletes keys at their deadline, but it only knows about expirations it has
+/// been told of, e.g. not those left behind when a replica that was ignoring them is promoted.
+/// Sampling the keyspace catches those too, at a bounded cost: every cycle samples rounds of
+/// keys and carries on only while the rounds keep turning up a good share of expired ones.
+pub(crate) async fn active_expire_cycle(
+    db: Arc<dyn Storage>,
+    replication: Arc<ReplicationState>,
+    propagator: Arc<Propagator>,
+) {
+    let mut interval = tokio::time::interval(CYCLE_PERIOD);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        // replicas wait for their master's DELs
+        if replication.is_replica() {
+            continue;
+        }
+        let deadline = Instant::now() + CYCLE_BUDGET;
+        let mut total = 0;
+        loop {
+            let round = {
+                // each removal and its DEL must not interleave with other writes
+                let _write_guard = replication.write_lock().await;
+                expire_round(&*db, KEYS_PER_ROUND, |key| {
+                    propagator.propagate(Write::single(
+                        RedisValue::command([Bytes::from("DEL"), key.clone()]),
+                        KeyEvent::Expired(key),
+                    ));
+                    total += 1;
+                })
+            };
+            if let Some(ttl) = round.avg_ttl {
+                db.set_avg_ttl(running_avg_ttl(db.avg_ttl(), ttl));
+            }
+            if !round.again || Instant::now() >= deadline {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        if total > 0 {
+            tracing::debug!("Active expire cycle removed {total} keys");
+        }
+    }
+}
+
+/// What a round of the active expire cycle found
+struct Round {
+    /// Whether enough of the sampled volatile keys had expired for another round to be worthwhile
+    again: bool,
+
+    /// Average milliseconds left to live of the sampled volatile keys still alive, if any
+    avg_ttl: Option<u64>,
+}
+
+/// Sample `samples` keys and delete the expired ones, handing each to `expired`
+fn expire_round(db: &dyn Storage, samples: usize, mut expired: impl FnMut(RedisKey)) -> Round {
+    let now = db.clock().now_ms();
+    let mut volatile = 0;
+    let mut stale = 0;
+    // summed wider than a deadline, as a few far in the future would overflow it
+    let (mut alive, mut ttl) = (0, 0u128);
+    for key in db.sample_keys(samples) {
+        let Some(at) = db.get_key_expiration(&key) else {
+            continue;
+        };
+        volatile += 1;
+        if at > now {
+            alive += 1;
+            ttl += u128::from(at - now);
+        } else if db.delete_expired(&key, now) {
+            // the same key may come up twice in a sample, so it only counts once really gone
+            stale += 1;
+            expired(key);
+        }
+    }
+    Round {
+        again: volatile > 0 && stale * 100 > volatile * ACCEPTABLE_STALE_PERCENT,
+        // an average of u64 values fits in one
+        avg_ttl: (alive > 0).then(|| (ttl / alive) as u64),
+    }
+}
+
+/// Fold the average time to live of a round's sample into the running estimate, as Redis does
+fn running_avg_ttl(estimate: u64, sample: u64) -> u64 {
+    match estimate {
+        0 => sample,
+        estimate => estimate / 50 * AVG_TTL_WEIGHT + sample / 50,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::types::{Database, Value};
+
+    #[test]
+    fn rounds_remove_expired_keys() {
+        let db = Database::default();
+        let now = db.clock().now_ms();
+        let past = now - 1000;
+        for i in 0..50 {
+            db.set_key(&format!("e{i}").into(), Value::new("x".into(), Some(past)));
+            let list = format!("el{i}").into();
+            db.push(&list, vec![Value::new("x".into(), None)], false);
+            db.expire_at(&list, past);
+        }
+        for i in 0..50 {
+            let future = now + 60_000;
+            db.set_key(
+                &format!("f{i}").into(),
+                Value::new("x".into(), Some(future)),
+            );
+            db.set_key(&format!("p{i}").into(), Value::new("x".into(), None));
+        }
+
+        let mut removed = Vec::new();
+        let mut avg_ttl = None;
+        loop {
+            let round = expire_round(&db, KEYS_PER_ROUND, |key| removed.push(key));
+            avg_ttl = round.avg_ttl.or(avg_ttl);
+            if !round.again {
+                break;
+            }
+        }
+        // only the keys still to expire, a minute away, have a time to live
+        assert!(avg_ttl.is_some_and(|ttl| ttl <= 60_000 && ttl > 59_000));
+        assert!(!removed.is_empty());
+        assert!(removed.iter().all(|key| key.starts_with(b"e")));
+        assert_eq!(db.len(), 200 - removed.len());
+        assert!(removed.iter().any(|key| key.starts_with(b"el")));
+
+        // with nothing expired left to find, a round says to stop
+        let db = Database::default();
+        db.set_key(&"p".into(), Value::new("x".into(), None));
+        let round = expire_round(&db, KEYS_PER_ROUND, |_| panic!("nothing to expire"));
+        assert!(!round.again && round.avg_ttl.is_none());
+    }
+
+    #[test]
+    fn averages_far_future_deadlines() {
+        let db = Database::default();
+        for i in 0..3 {
+            db.set_key(
+                &format!("k{i}").into(),
+                Value::new("x".into(), Some(u64::MAX)),
+            );
+        }
+        // read first, as the round measures TTLs from a time no earlier
+        let now = db.clock().now_ms();
+        let round = expire_round(&db, KEYS_PER_ROUND, |_| panic!("nothing to expire"));
+        assert!(round
+            .avg_ttl
+            .is_some_and(|ttl| ttl <= u64::MAX - now && ttl > u64::MAX / 2));
+        let estimate = running_avg_ttl(round.avg_ttl.unwrap(), round.avg_ttl.unwrap());
+        assert!(estimate > u64::MAX / 2);
+    }
+
+    #[test]
+    fn averages_ttls_over_rounds() {
+        assert_eq!(running_avg_ttl(0, 1000), 1000);
+        assert_eq!(running_avg_ttl(1000, 1000), 1000);
+        assert_eq!(running_avg_ttl(50_000, 100_000), 51_000);
+    }
+}
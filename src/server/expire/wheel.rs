@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+
+use crate::server::types::RedisKey;
+
+/// Granularity of the wheel in milliseconds; keys are removed at most this long after they expire
+pub(crate) const TICK_MS: u64 = 10;
+
+/// Slots per level, as bits
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+
+/// Levels of ever coarser slots, covering 2^36 ticks or about 21 years between them
+const LEVELS: usize = 6;
+
+/// Bits of a tick the levels span. Deadlines beyond the top level's current rotation wait at its
+/// end and are placed again from there.
+const SPAN_MASK: u64 = (1 << (SLOT_BITS * LEVELS as u32)) - 1;
+
+/// Stale entries tolerated before compacting, however few keys are live
+const MIN_COMPACT: usize = 1024;
+
+/// A scheduled expiration, live only while its generation is the key's latest
+struct Entry {
+    at: u64,
+    generation: u64,
+    key: RedisKey,
+}
+
+/// A hierarchical timing wheel of key expirations.
+///
+/// Level 0 holds a slot per tick for the next 64 ticks, level 1 a slot per 64 ticks and so on.
+/// Inserting is O(1) however many keys are volatile; entries trickle down a level each time
+/// the wheel reaches the span of their slot, and fire from level 0. Deadlines are unix times in
+/// milliseconds, as expirations are kept.
+///
+/// A key has at most one live entry: scheduling it again or cancelling it leaves the old entry
+/// stale, to be dropped when reached, and once stale entries outnumber live ones they are swept
+/// out, so a hot key given a new TTL over and over can't grow the wheel.
+pub(crate) struct TimerWheel {
+    /// Tick 0
+    start: u64,
+    /// The first tick not yet processed
+    current: u64,
+    levels: Vec<Vec<Vec<Entry>>>,
+    /// The generation of every scheduled key's live entry
+    scheduled: HashMap<RedisKey, u64>,
+    next_generation: u64,
+    /// Entries still in the slots that are no longer live
+    stale: usize,
+}
+
+impl TimerWheel {
+    pub(crate) fn new(start: u64) -> Self {
+        Self {
+            start,
+            current: 0,
+            levels: (0..LEVELS)
+                .map(|_| (0..SLOTS).map(|_| Vec::new()).collect())
+                .collect(),
+            scheduled: HashMap::new(),
+            next_generation: 0,
+            stale: 0,
+        }
+    }
+
+    /// Keys waiting to fire
+    pub(crate) fn len(&self) -> usize {
+        self.scheduled.len()
+    }
+
+    /// The tick ending at or after `at`, so a key never fires early
+    fn tick_of(&self, at: u64) -> u64 {
+        at.saturating_sub(self.start).div_ceil(TICK_MS)
+    }
+
+    /// Schedule `key` to fire once the wheel is advanced past `at`, instead of when it was
+    /// scheduled to before
+    pub(crate) fn insert(&mut self, at: u64, key: RedisKey) {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        if self.scheduled.insert(key.clone(), generation).is_some() {
+            self.stale += 1;
+        }
+        self.place(Entry {
+            at,
+            generation,
+            key,
+        });
+        self.compact();
+    }
+
+    /// Stop `key` firing, now it no longer expires
+    pub(crate) fn cancel(&mut self, key: &RedisKey) {
+        if self.scheduled.remove(key).is_some() {
+            self.stale += 1;
+            self.compact();
+        }
+    }
+
+    fn is_live(&self, entry: &Entry) -> bool {
+        self.scheduled.get(&entry.key) == Some(&entry.generation)
+    }
+
+    /// Sweep out the stale entries once there are more of them than live ones
+    fn compact(&mut self) {
+        if self.stale < MIN_COMPACT.max(self.scheduled.len()) {
+            return;
+        }
+        let scheduled = &self.scheduled;
+        for slot in self.levels.iter_mut().flatten() {
+            slot.retain(|entry| scheduled.get(&entry.key) == Some(&entry.generation));
+        }
+        self.stale = 0;
+    }
+
+    /// Put an entry in the slot its deadline falls in
+    fn place(&mut self, entry: Entry) {
+        let tick = self
+            .tick_of(entry.at)
+            .clamp(self.current, self.current | SPAN_MASK);
+        // the highest bit that differs from now picks the level
+        let differing = (tick ^ self.current) | (SLOTS as u64 - 1);
+        let level = ((63 - differing.leading_zeros()) / SLOT_BITS) as usize;
+        let slot = (tick >> (SLOT_BITS * level as u32)) as usize % SLOTS;
+        self.levels[level][slot].push(entry);
+    }
+
+    /// The next tick with anything to fire or move down a level, if any
+    fn next_tick(&self) -> Option<u64> {
+        for (level, slots) in self.levels.iter().enumerate() {
+            let shift = SLOT_BITS * level as u32;
+            let position = (self.current >> shift) as usize % SLOTS;
+            // a slot is moved down as the wheel reaches its first tick, so the current one only
+            // has entries left if that tick is still to come
+            let pending = self.current.is_multiple_of(1 << shift);
+            let first = if pending { position } else { position + 1 };
+            if let Some(slot) = (first..SLOTS).find(|&slot| !slots[slot].is_empty()) {
+                let span = 1u64 << shift;
+                let rotation = self.current >> (shift + SLOT_BITS) << (shift + SLOT_BITS);
+                return Some(rotation + slot as u64 * span);
+            }
+        }
+        None
+    }
+
+    /// When [`advance`](Self::advance) next has work to do, if ever
+    pub(crate) fn next_deadline(&self) -> Option<u64> {
+        let tick = self.next_tick()?;
+        Some(self.start + TICK_MS * tick)
+    }
+
+    /// Move the wheel up to `now`, returning the keys due by then with their deadlines
+    pub(crate) fn advance(&mut self, now: u64) -> Vec<(u64, RedisKey)> {
+        let target = now.saturating_sub(self.start) / TICK_MS;
+        let mut due = Vec::new();
+        while self.current <= target {
+            // skip straight past ticks with nothing to do
+            match self.next_tick() {
+                Some(tick) if tick <= target => self.current = self.current.max(tick),
+                _ => {
+                    self.current = target + 1;
+                    break;
+                }
+            }
+            // move entries down from the higher slots starting here, widest first
+            for level in (1..LEVELS).rev() {
+                let shift = SLOT_BITS * level as u32;
+                if !self.current.is_multiple_of(1 << shift) {
+                    continue;
+                }
+                let slot = (self.current >> shift) as usize % SLOTS;
+                for entry in std::mem::take(&mut self.levels[level][slot]) {
+                    match self.is_live(&entry) {
+                        true => self.place(entry),
+                        false => self.stale -= 1,
+                    }
+                }
+            }
+            let slot = self.current as usize % SLOTS;
+            for entry in std::mem::take(&mut self.levels[0][slot]) {
+                if !self.is_live(&entry) {
+                    self.stale -= 1;
+                } else if entry.at <= now {
+                    self.scheduled.remove(&entry.key);
+                    due.push((entry.at, entry.key));
+                } else {
+                    // only deadlines clamped to the wheel's range come round early
+                    self.place(entry);
+                }
+            }
+            self.current += 1;
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Somewhere in November 2023
+    const START: u64 = 1_700_000_000_000;
+
+    fn keys(due: Vec<(u64, RedisKey)>) -> Vec<RedisKey> {
+        due.into_iter().map(|(_, key)| key).collect()
+    }
+
+    #[test]
+    fn fires_keys_when_due() {
+        let mut wheel = TimerWheel::new(START);
+        let at = |ms| START + ms;
+        wheel.insert(at(25), "soon".into());
+        wheel.insert(at(5_000), "later".into());
+        wheel.insert(at(3_600_000), "hour".into());
+        wheel.insert(at(86_400_000 * 400), "next year".into());
+        wheel.insert(at(0), "now".into());
+        assert_eq!(wheel.len(), 5);
+
+        assert_eq!(keys(wheel.advance(at(0))), ["now"]);
+        assert_eq!(wheel.next_deadline(), Some(at(30)));
+        assert!(wheel.advance(at(29)).is_empty());
+        assert_eq!(keys(wheel.advance(at(30))), ["soon"]);
+        assert!(wheel.advance(at(4_999)).is_empty());
+        assert_eq!(keys(wheel.advance(at(5_000))), ["later"]);
+        assert_eq!(keys(wheel.advance(at(3_600_000))), ["hour"]);
+        assert!(wheel.advance(at(86_400_000 * 399)).is_empty());
+        assert_eq!(keys(wheel.advance(at(86_400_000 * 401))), ["next year"]);
+        assert_eq!(wheel.len(), 0);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn fires_late_insertions_on_the_next_tick() {
+        let mut wheel = TimerWheel::new(START);
+        let now = START + 10_000;
+        wheel.advance(now);
+        wheel.insert(START + 1_000, "missed".into());
+        assert_eq!(wheel.next_deadline(), Some(now + TICK_MS));
+        assert_eq!(keys(wheel.advance(now + TICK_MS)), ["missed"]);
+    }
+
+    #[test]
+    fn fires_many_keys_in_order() {
+        let mut wheel = TimerWheel::new(START);
+        for i in (0..10_000u64).rev() {
+            let at = START + i * 37;
+            wheel.insert(at, i.to_string().into());
+        }
+        let mut fired = Vec::new();
+        while let Some(deadline) = wheel.next_deadline() {
+            let due = wheel.advance(deadline);
+            assert!(due.iter().all(|(at, _)| *at <= deadline));
+            fired.extend(due.into_iter().map(|(at, _)| at));
+        }
+        assert_eq!(fired.len(), 10_000);
+        assert!(fired.is_sorted());
+    }
+
+    /// Entries in the slots, live or not
+    fn entries(wheel: &TimerWheel) -> usize {
+        wheel.levels.iter().flatten().map(Vec::len).sum()
+    }
+
+    #[test]
+    fn only_the_latest_deadline_fires() {
+        let mut wheel = TimerWheel::new(START);
+        // a hot key given a fresh TTL on every write
+        for i in 0..100_000 {
+            wheel.insert(START + 60_000 + i, "hot".into());
+        }
+        assert_eq!(wheel.len(), 1);
+        assert!(entries(&wheel) <= MIN_COMPACT + 1);
+
+        let last = START + 60_000 + 99_999;
+        assert!(wheel.advance(last - 1).is_empty());
+        assert_eq!(wheel.advance(last + TICK_MS), [(last, "hot".into())]);
+        assert_eq!(wheel.len(), 0);
+    }
+
+    #[test]
+    fn cancelled_keys_never_fire() {
+        let mut wheel = TimerWheel::new(START);
+        wheel.insert(START + 100, "persisted".into());
+        wheel.insert(START + 100, "kept".into());
+        wheel.cancel(&"persisted".into());
+        wheel.cancel(&"unknown".into());
+        assert_eq!(wheel.len(), 1);
+        assert_eq!(keys(wheel.advance(START + 100)), ["kept"]);
+
+        // many keys cancelled at once are swept out rather than left until their deadlines
+        for i in 0..2 * MIN_COMPACT {
+            wheel.insert(START + 86_400_000, i.to_string().into());
+        }
+        for i in 0..2 * MIN_COMPACT {
+            wheel.cancel(&i.to_string().into());
+        }
+        assert_eq!(wheel.len(), 0);
+        assert!(entries(&wheel) < MIN_COMPACT);
+    }
+}
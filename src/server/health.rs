@@ -0,0 +1,174 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    replication::ReplicationState,
+    server::{
+        storage::{KeyspaceStats, Storage},
+        tasks,
+    },
+};
+
+/// Time a probe has to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether the server can serve its dataset yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Health {
+    Loading,
+    /// A replica without a complete copy of its master's dataset
+    Syncing,
+    Ready,
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Health::Loading => "loading",
+            Health::Syncing => "syncing with master",
+            Health::Ready => "ready",
+        })
+    }
+}
+
+pub(crate) fn status(replication: &ReplicationState) -> Health {
+    if replication.is_loading() {
+        Health::Loading
+    } else if !replication.is_synced() {
+        Health::Syncing
+    } else {
+        Health::Ready
+    }
+}
+
+/// Answer HTTP probes on `listeners`: `/livez` succeeds as long as the server runs, `/readyz`
+/// only once it is [`Health::Ready`]. Either reply carries the current status as its body.
+/// `/metrics` reports the keyspace statistics of `db` in the Prometheus text format.
+pub(crate) fn serve(
+    listeners: Vec<TcpListener>,
+    replication: Arc<ReplicationState>,
+    db: Arc<dyn Storage>,
+) {
+    for listener in listeners {
+        let (replication, db) = (replication.clone(), db.clone());
+        tasks::spawn("health", None, async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let probe = probe(stream, replication.clone(), db.clone());
+                        tasks::spawn("health-probe", None, probe);
+                    }
+                    Err(e) => tracing::warn!("Failed to accept a health probe: {e}"),
+                }
+            }
+        });
+    }
+}
+
+async fn probe(mut stream: TcpStream, replication: Arc<ReplicationState>, db: Arc<dyn Storage>) {
+    let mut request = [0; 1024];
+    let Ok(Ok(read)) = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut request)).await
+    else {
+        return;
+    };
+    let health = status(&replication);
+    let response = respond(&request[..read], health, db.keyspace_stats());
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// The HTTP response to `request` when the server is in `health` and its keyspace has seen
+/// `stats`
+fn respond(request: &[u8], health: Health, stats: KeyspaceStats) -> String {
+    let request_line = request.split(|&b| b == b'\r' || b == b'\n').next();
+    let path = request_line
+        .and_then(|line| line.split(|&b| b == b' ').nth(1))
+        .unwrap_or_default();
+    let (status, body) = match path {
+        b"/livez" | b"/healthz" => ("200 OK", format!("{health}\n")),
+        b"/readyz" if health == Health::Ready => ("200 OK", format!("{health}\n")),
+        b"/readyz" => ("503 Service Unavailable", format!("{health}\n")),
+        b"/metrics" => ("200 OK", metrics(stats)),
+        _ => {
+            return "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .into()
+        }
+    };
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Keyspace statistics as Prometheus counters, named as redis_exporter names them
+fn metrics(stats: KeyspaceStats) -> String {
+    [
+        (
+            "redis_keyspace_hits_total",
+            "Reads of a live key",
+            stats.hits,
+        ),
+        (
+            "redis_keyspace_misses_total",
+            "Reads of a missing key",
+            stats.misses,
+        ),
+        ("redis_expired_keys_total", "Keys expired", stats.expired),
+        (
+            "redis_evicted_keys_total",
+            "Keys evicted under maxmemory",
+            stats.evicted,
+        ),
+    ]
+    .into_iter()
+    .map(|(name, help, value)| {
+        format!("# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}\n")
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn responds_to_probes() {
+        let respond = |request: &[u8], health| respond(request, health, KeyspaceStats::default());
+        let ready = respond(b"GET /readyz HTTP/1.1\r\nHost: x\r\n\r\n", Health::Ready);
+        assert!(ready.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ready.ends_with("\r\n\r\nready\n"));
+        let syncing = respond(b"GET /readyz HTTP/1.1\r\n\r\n", Health::Syncing);
+        assert!(syncing.starts_with("HTTP/1.1 503 "));
+        assert!(syncing.ends_with("syncing with master\n"));
+        let live = respond(b"GET /livez HTTP/1.1\r\n\r\n", Health::Loading);
+        assert!(live.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(live.ends_with("loading\n"));
+        assert!(respond(b"GET / HTTP/1.1\r\n\r\n", Health::Ready).starts_with("HTTP/1.1 404"));
+        assert!(respond(b"", Health::Ready).starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn reports_metrics() {
+        let stats = KeyspaceStats {
+            hits: 3,
+            misses: 1,
+            expired: 2,
+            evicted: 0,
+        };
+        let metrics = respond(b"GET /metrics HTTP/1.1\r\n\r\n", Health::Loading, stats);
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in [
+            "# TYPE redis_keyspace_hits_total counter\n",
+            "\nredis_keyspace_hits_total 3\n",
+            "\nredis_keyspace_misses_total 1\n",
+            "\nredis_expired_keys_total 2\n",
+            "\nredis_evicted_keys_total 0\n",
+        ] {
+            assert!(metrics.contains(line), "{line:?} in {metrics}");
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Hot-key statistics for HOTKEYS: a sample of key accesses is counted in a count-min sketch,
+//! whose size does not grow with the number of keys, and the keys it estimates to be the most
+//! accessed are kept in a short top-k list
+
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::{LazyLock, Mutex},
+};
+
+use bytes::Bytes;
+
+use crate::server::types;
+
+/// Rows of the sketch, each an independent estimate of which the smallest is taken
+const DEPTH: usize = 4;
+
+/// Counters in each row of the sketch
+const WIDTH: usize = 1 << 12;
+
+/// Most keys HOTKEYS can report
+pub(crate) const TOP_K: usize = 32;
+
+/// Samples between halvings of every count, so that the statistics follow current traffic
+/// rather than all traffic since the start
+const DECAY_SAMPLES: u64 = WIDTH as u64 * 16;
+
+/// The key accesses sampled by every server in the process
+static HOT_KEYS: LazyLock<Mutex<HotKeys>> = LazyLock::new(|| Mutex::new(HotKeys::new()));
+
+struct HotKeys {
+    hasher: RandomState,
+    counts: Box<[[u32; WIDTH]; DEPTH]>,
+    /// The keys estimated to be the most accessed, with their estimates, in no order
+    top: Vec<(Bytes, u64)>,
+    /// Samples since the counts were last halved
+    samples: u64,
+}
+
+impl HotKeys {
+    fn new() -> Self {
+        Self {
+            hasher: RandomState::new(),
+            counts: Box::new([[0; WIDTH]; DEPTH]),
+            top: Vec::with_capacity(TOP_K),
+            samples: 0,
+        }
+    }
+
+    /// The counter of `key` in each row, by double hashing one hash of it
+    fn slots(&self, key: &[u8]) -> [usize; DEPTH] {
+        let hash = self.hasher.hash_one(key);
+        let (h1, h2) = (hash as u32 as usize, (hash >> 32) as usize | 1);
+        std::array::from_fn(|row| h1.wrapping_add(row.wrapping_mul(h2)) % WIDTH)
+    }
+
+    /// Count `weight` accesses to `key`
+    fn record(&mut self, key: &[u8], weight: u32) {
+        let mut estimate = u32::MAX;
+        for (row, slot) in self.slots(key).into_iter().enumerate() {
+            let count = &mut self.counts[row][slot];
+            *count = count.saturating_add(weight);
+            estimate = estimate.min(*count);
+        }
+        let estimate = estimate as u64;
+
+        if let Some(entry) = self.top.iter_mut().find(|(top, _)| top[..] == *key) {
+            entry.1 = estimate;
+        } else if self.top.len() < TOP_K {
+            self.top.push((Bytes::copy_from_slice(key), estimate));
+        } else if let Some(coldest) = self.top.iter_mut().min_by_key(|(_, count)| *count)
+            && coldest.1 < estimate
+        {
+            *coldest = (Bytes::copy_from_slice(key), estimate);
+        }
+
+        self.samples += 1;
+        if self.samples == DECAY_SAMPLES {
+            self.decay();
+        }
+    }
+
+    /// Halve every count, forgetting keys that were not accessed since
+    fn decay(&mut self) {
+        for count in self.counts.iter_mut().flatten() {
+            *count /= 2;
+        }
+        self.top.retain_mut(|(_, count)| {
+            *count /= 2;
+            *count > 0
+        });
+        self.samples = 0;
+    }
+
+    /// Up to `count` of the hottest keys, hottest first
+    fn top(&self, count: usize) -> Vec<(Bytes, u64)> {
+        let mut top = self.top.clone();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(count);
+        top
+    }
+}
+
+/// Whether to sample a command, with one in `rate` sampled and none if it is 0
+pub(crate) fn sampled(rate: u32) -> bool {
+    rate != 0 && types::random().is_multiple_of(rate as u64)
+}
+
+/// Count the keys of a sampled command, each standing for the `rate` accesses it was sampled
+/// out of
+pub(crate) fn record<'a>(keys: impl IntoIterator<Item = &'a Bytes>, rate: u32) {
+    let mut hot_keys = HOT_KEYS.lock().unwrap();
+    for key in keys {
+        hot_keys.record(key, rate);
+    }
+}
+
+/// Up to `count` of the most accessed keys, hottest first, with the accesses each is estimated
+/// to have had
+pub(crate) fn top(count: usize) -> Vec<(Bytes, u64)> {
+    HOT_KEYS.lock().unwrap().top(count)
+}
+
+/// Forget every access counted so far
+pub(crate) fn reset() {
+    *HOT_KEYS.lock().unwrap() = HotKeys::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_hottest_keys() {
+        let mut hot_keys = HotKeys::new();
+        // far more keys than fit in the top list, a few of them much hotter than the rest
+        for round in 0..20 {
+            for key in 0..2000 {
+                hot_keys.record(format!("cold:{key}").as_bytes(), 1);
+            }
+            for (key, weight) in [("hot:a", 300), ("hot:b", 200), ("hot:c", 100)] {
+                hot_keys.record(key.as_bytes(), weight);
+            }
+            assert!(hot_keys.top.len() <= TOP_K, "round {round}");
+        }
+        let top = hot_keys.top(3);
+        let names: Vec<_> = top.iter().map(|(key, _)| &key[..]).collect();
+        assert_eq!(names, [&b"hot:a"[..], b"hot:b", b"hot:c"]);
+        // estimates never undercount, and the sketch is wide enough to be close
+        assert!((6000..6200).contains(&top[0].1), "{}", top[0].1);
+        assert_eq!(hot_keys.top(1).len(), 1);
+    }
+
+    #[test]
+    fn decays() {
+        let mut hot_keys = HotKeys::new();
+        hot_keys.record(b"old", 10);
+        hot_keys.record(b"once", 1);
+        hot_keys.decay();
+        assert_eq!(hot_keys.top(TOP_K), [(Bytes::from("old"), 5)]);
+
+        // counts are halved again once enough samples were taken
+        for _ in 1..DECAY_SAMPLES {
+            hot_keys.record(b"new", 1);
+        }
+        assert_eq!(hot_keys.samples, DECAY_SAMPLES - 1);
+        hot_keys.record(b"new", 1);
+        assert_eq!(hot_keys.samples, 0);
+        assert_eq!(
+            hot_keys.top(TOP_K),
+            [
+                (Bytes::from("new"), DECAY_SAMPLES / 2),
+                (Bytes::from("old"), 2)
+            ]
+        );
+    }
+}
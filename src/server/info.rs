@@ -0,0 +1,186 @@
+use std::sync::LazyLock;
+
+use crate::{
+    cluster::ClusterState,
+    connection::REDIS_VERSION,
+    replication::{self, ReplicationState},
+    server::{blocking, clients, config::Config, defrag, lazyfree, stats, storage::Storage, tasks},
+};
+
+/// Sections reported when INFO is called without arguments (or with `default`/`all`)
+const DEFAULT_SECTIONS: &[&str] = &[
+    "SERVER",
+    "CLIENTS",
+    "MEMORY",
+    "STATS",
+    "REPLICATION",
+    "CLUSTER",
+    "KEYSPACE",
+];
+
+/// Identifies this run of the server, so Sentinel can tell when an instance restarted
+static RUN_ID: LazyLock<String> = LazyLock::new(replication::generate_id);
+
+/// Render the INFO reply for the requested (uppercased) section names
+pub(crate) fn render(
+    sections: &[String],
+    db: &dyn Storage,
+    config: &Config,
+    replication: &ReplicationState,
+    cluster: Option<&ClusterState>,
+) -> String {
+    let wanted = |name: &str| {
+        sections.is_empty()
+            || sections.iter().any(|s| {
+                s == name
+                    || (matches!(s.as_str(), "DEFAULT" | "ALL" | "EVERYTHING")
+                        && DEFAULT_SECTIONS.contains(&name))
+            })
+    };
+
+    // left out of the default sections for their length, as in Redis
+    let wanted_extra = |name: &str| {
+        sections
+            .iter()
+            .any(|s| s == name || matches!(s.as_str(), "ALL" | "EVERYTHING"))
+    };
+
+    let mut out = Vec::new();
+    if wanted("SERVER") {
+        out.push(format!(
+            "# Server\r\n\
+             redis_version:{REDIS_VERSION}\r\n\
+             redis_mode:{}\r\n\
+             process_id:{}\r\n\
+             run_id:{}\r\n\
+             tcp_port:{}\r\n",
+            if cluster.is_some() {
+                "cluster"
+            } else {
+                "standalone"
+            },
+            std::process::id(),
+            *RUN_ID,
+            config.port,
+        ));
+    }
+    if wanted("CLIENTS") {
+        out.push(format!(
+            "# Clients\r\nconnected_clients:{}\r\nmaxclients:{}\r\nblocked_clients:{}\r\n",
+            clients::connected(),
+            config.maxclients,
+            blocking::blocked_clients(),
+        ));
+    }
+    if wanted("MEMORY") {
+        let used = db.used_memory() as u64;
+        let peak = db.used_memory_peak() as u64;
+        out.push(format!(
+            "# Memory\r\n\
+             used_memory:{used}\r\n\
+             used_memory_human:{}\r\n\
+             used_memory_peak:{peak}\r\n\
+             used_memory_peak_human:{}\r\n\
+             maxmemory:{}\r\n\
+             maxmemory_human:{}\r\n\
+             maxmemory_policy:{}\r\n\
+             mem_clients_normal:{}\r\n\
+             lazyfree_pending_objects:{}\r\n",
+            human_bytes(used),
+            human_bytes(peak),
+            config.maxmemory,
+            human_bytes(config.maxmemory),
+            config.maxmemory_policy,
+            clients::memory_used(),
+            lazyfree::pending(),
+        ));
+    }
+    if wanted("STATS") {
+        let keyspace = db.keyspace_stats();
+        out.push(format!(
+            "# Stats\r\n\
+             total_connections_received:{}\r\n\
+             rejected_connections:{}\r\n\
+             denied_connections:{}\r\n\
+             expired_keys:{}\r\n\
+             evicted_keys:{}\r\n\
+             evicted_clients:{}\r\n\
+             keyspace_hits:{}\r\n\
+             keyspace_misses:{}\r\n\
+             active_defrag_hits:{}\r\n\
+             active_defrag_misses:{}\r\n\
+             active_defrag_reclaimed_bytes:{}\r\n",
+            clients::total_received(),
+            clients::rejected(),
+            clients::denied(),
+            keyspace.expired,
+            keyspace.evicted,
+            clients::evicted(),
+            keyspace.hits,
+            keyspace.misses,
+            defrag::hits(),
+            defrag::misses(),
+            defrag::reclaimed(),
+        ));
+    }
+    if wanted_extra("TASKS") {
+        out.push(tasks::info());
+    }
+    if wanted("REPLICATION") {
+        out.push(replication.info(config.replica_priority));
+    }
+    if wanted_extra("COMMANDSTATS") {
+        out.push(stats::commandstats());
+    }
+    if wanted_extra("LATENCYSTATS") {
+        out.push(stats::latencystats());
+    }
+    if wanted("CLUSTER") {
+        out.push(format!(
+            "# Cluster\r\ncluster_enabled:{}\r\n",
+            cluster.is_some() as u8
+        ));
+    }
+    if wanted("KEYSPACE") {
+        let mut section = String::from("# Keyspace\r\n");
+        // there is only database 0, listed once it holds keys as Redis does
+        let (keys, expires) = (db.len(), db.expires());
+        if keys > 0 {
+            let avg_ttl = if expires > 0 { db.avg_ttl() } else { 0 };
+            section.push_str(&format!(
+                "db0:keys={keys},expires={expires},avg_ttl={avg_ttl}\r\n"
+            ));
+        }
+        out.push(section);
+    }
+    out.join("\r\n")
+}
+
+/// Format a byte count the way Redis' `bytesToHuman` does, e.g. `1.50M`
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.2}{}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_byte_counts() {
+        assert_eq!(human_bytes(0), "0B");
+        assert_eq!(human_bytes(1023), "1023B");
+        assert_eq!(human_bytes(1536), "1.50K");
+        assert_eq!(human_bytes(100 * 1024 * 1024), "100.00M");
+        assert_eq!(human_bytes(3 << 30), "3.00G");
+    }
+}
@@ -0,0 +1,248 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::sync::{mpsc::UnboundedSender, MutexGuard};
+
+use crate::{
+    error::RedisError,
+    replication::ReplicationState,
+    resp::RedisValue,
+    server::{
+        blocking,
+        config::Config,
+        propagation::{Propagator, Write},
+        storage::Storage,
+        types::{ExpiryEvent, Value},
+        watch::{KeyEvent, KeyEvents},
+    },
+};
+
+/// The type of a key's value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyType {
+    String,
+    List,
+    Set,
+    Hash,
+    SortedSet,
+}
+
+/// A typed view of a server's keyspace, for the program embedding it to inspect or seed its
+/// data without going through RESP.
+///
+/// Writes are applied as the equivalent commands would be: refused on replicas, propagated to
+/// our own replicas and invalidating keys clients track.
+#[derive(Clone)]
+pub struct Keyspace {
+    pub(super) db: Arc<dyn Storage>,
+    pub(super) config: Arc<Config>,
+    pub(super) replication: Arc<ReplicationState>,
+    pub(super) expiration_tx: UnboundedSender<ExpiryEvent>,
+    pub(super) propagator: Arc<Propagator>,
+}
+
+impl fmt::Debug for Keyspace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyspace").finish_non_exhaustive()
+    }
+}
+
+fn key(key: impl AsRef<[u8]>) -> Bytes {
+    Bytes::copy_from_slice(key.as_ref())
+}
+
+impl Keyspace {
+    /// Every live key, as of the call
+    pub fn keys(&self) -> impl Iterator<Item = Bytes> {
+        self.db.keys().into_iter()
+    }
+
+    pub fn exists(&self, name: impl AsRef<[u8]>) -> bool {
+        self.db.exists(&key(name))
+    }
+
+    pub fn key_type(&self, name: impl AsRef<[u8]>) -> Option<KeyType> {
+        self.db.key_type(&key(name))
+    }
+
+    /// How long until a key expires, `None` if it doesn't exist or never expires
+    pub fn ttl(&self, name: impl AsRef<[u8]>) -> Option<Duration> {
+        let name = key(name);
+        if !self.db.exists(&name) {
+            return None;
+        }
+        let at = self.db.get_key_expiration(&name)?;
+        Some(Duration::from_millis(
+            at.saturating_sub(self.db.clock().now_ms()),
+        ))
+    }
+
+    /// The value of a string, `None` if the key doesn't hold one
+    pub fn get_string(&self, name: impl AsRef<[u8]>) -> Option<Bytes> {
+        let name = key(name);
+        if !self.db.holds_string(&name) {
+            return None;
+        }
+        self.db.get_key(&name)
+    }
+
+    /// Elements `start` to `stop` inclusive of a list, negative indexes counting from the end as
+    /// in LRANGE. `None` if the key doesn't hold a list.
+    pub fn list_range(&self, name: impl AsRef<[u8]>, start: i64, stop: i64) -> Option<Vec<Bytes>> {
+        self.db.list_range(&key(name), start, stop)
+    }
+
+    /// Changes to the keys starting with `prefix`, made by clients, this API, expiration or
+    /// eviction, until the stream is dropped. Events queue up until read.
+    pub fn watch_prefix(&self, prefix: impl AsRef<[u8]>) -> KeyEvents {
+        self.propagator.watchers().watch(key(prefix))
+    }
+
+    /// Store a string, expiring after `ttl` if set, as SET does
+    pub async fn set_string(
+        &self,
+        name: impl AsRef<[u8]>,
+        value: impl Into<Bytes>,
+        ttl: Option<Duration>,
+    ) -> Result<()> {
+        let (name, value) = (key(name), value.into());
+        let at = match ttl {
+            Some(ttl) => Some(
+                self.db
+                    .clock()
+                    .expires_at(ttl)
+                    .ok_or(RedisError::InvalidExpireTime("set"))?,
+            ),
+            None => None,
+        };
+        let _write_guard = self.write_lock().await?;
+        let old = self.db.set_key(&name, Value::new(value.clone(), at));
+        let mut commands = vec![RedisValue::command([
+            Bytes::from("SET"),
+            name.clone(),
+            value,
+        ])];
+        if let Some(at) = at {
+            commands.push(RedisValue::command([
+                Bytes::from("PEXPIREAT"),
+                name.clone(),
+                at.to_string().into(),
+            ]));
+        }
+        self.propagator
+            .propagate(Write::new(commands, vec![KeyEvent::Set(name.clone())]));
+        // the old value's expiration no longer applies
+        if at.is_some() || old.is_some_and(|old| old.get_expiration().is_some()) {
+            let _ = self.expiration_tx.send((at, name));
+        }
+        Ok(())
+    }
+
+    /// Append to a list, creating it if needed, as RPUSH does. Returns the list's new length.
+    pub async fn list_push<I, T>(&self, name: impl AsRef<[u8]>, values: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Bytes>,
+    {
+        let name = key(name);
+        let values: Vec<Bytes> = values.into_iter().map(Into::into).collect();
+        if values.is_empty() {
+            return Ok(self.list_range(&name, 0, -1).map_or(0, |list| list.len()));
+        }
+        let _write_guard = self.write_lock().await?;
+        if self.db.holds_string(&name) {
+            return Err(RedisError::WrongType.into());
+        }
+        let len = self.db.push(
+            &name,
+            values.iter().map(|v| Value::new(v.clone(), None)).collect(),
+            false,
+        );
+        blocking::signal(&name);
+        let command = [Bytes::from("RPUSH"), name.clone()]
+            .into_iter()
+            .chain(values);
+        self.propagator.propagate(Write::single(
+            RedisValue::command(command),
+            KeyEvent::Set(name),
+        ));
+        Ok(len)
+    }
+
+    /// Remove a key of any type, as DEL does. Returns whether it existed.
+    pub async fn delete(&self, name: impl AsRef<[u8]>) -> Result<bool> {
+        let name = key(name);
+        let _write_guard = self.write_lock().await?;
+        if !self.db.delete(&name, self.config.lazyfree_lazy_user_del) {
+            return Ok(false);
+        }
+        self.propagator.propagate(Write::single(
+            RedisValue::command([Bytes::from("DEL"), name.clone()]),
+            KeyEvent::Deleted(name),
+        ));
+        Ok(true)
+    }
+
+    /// The lock writes are applied and propagated under, refusing them on a replica
+    async fn write_lock(&self) -> Result<MutexGuard<'_, ()>> {
+        let guard = self.replication.write_lock().await;
+        if self.replication.is_replica() {
+            return Err(RedisError::ReadOnly.into());
+        }
+        Ok(guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{resp::client::Client, server::Redis};
+
+    #[tokio::test]
+    async fn seeds_and_reads_data() {
+        let redis = Redis::builder()
+            .port(0)
+            .dir(std::env::temp_dir())
+            .dbfilename("keyspace-test-missing.rdb")
+            .start()
+            .await
+            .unwrap();
+        let keyspace = redis.keyspace();
+        keyspace
+            .set_string("greeting", "hello", None)
+            .await
+            .unwrap();
+        keyspace
+            .set_string("session", "x", Some(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        assert_eq!(
+            keyspace.list_push("queue", ["a", "b", "c"]).await.unwrap(),
+            3
+        );
+        assert!(keyspace.list_push("greeting", ["a"]).await.is_err());
+
+        let mut client = Client::connect(redis.addr()).await.unwrap();
+        let reply = client.request(["GET", "greeting"]).await.unwrap();
+        assert_eq!(reply, RedisValue::BulkString("hello".into()));
+        client.request(["RPUSH", "queue", "d"]).await.unwrap();
+
+        assert_eq!(keyspace.get_string("greeting"), Some("hello".into()));
+        assert_eq!(keyspace.get_string("queue"), None);
+        assert_eq!(
+            keyspace.list_range("queue", -2, -1),
+            Some(vec!["c".into(), "d".into()])
+        );
+        assert_eq!(keyspace.key_type("queue"), Some(KeyType::List));
+        assert!(keyspace.ttl("session").unwrap() > Duration::from_secs(59));
+        assert_eq!(keyspace.ttl("greeting"), None);
+        let mut keys: Vec<_> = keyspace.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["greeting", "queue", "session"]);
+
+        assert!(keyspace.delete("greeting").await.unwrap());
+        assert!(!keyspace.exists("greeting"));
+        redis.stop().await.unwrap();
+    }
+}
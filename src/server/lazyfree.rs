@@ -0,0 +1,69 @@
+use std::{
+    any::Any,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Mutex, OnceLock,
+    },
+    thread,
+};
+
+/// Values with fewer elements than this are cheaper to drop in place than to hand over, as
+/// Redis' `LAZYFREE_THRESHOLD`
+pub(crate) const LAZYFREE_THRESHOLD: usize = 64;
+
+/// Objects handed over but not yet dropped
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// Queue to the background thread, started on first use like one of Redis' bio threads
+fn queue() -> &'static Mutex<Sender<Box<dyn Any + Send>>> {
+    static QUEUE: OnceLock<Mutex<Sender<Box<dyn Any + Send>>>> = OnceLock::new();
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Box<dyn Any + Send>>();
+        thread::Builder::new()
+            .name("lazyfree".into())
+            .spawn(move || {
+                for value in rx {
+                    drop(value);
+                    PENDING.fetch_sub(1, Ordering::Relaxed);
+                }
+            })
+            .expect("failed to spawn the lazyfree thread");
+        Mutex::new(tx)
+    })
+}
+
+/// Drop `value` on the background thread rather than on the calling task
+pub(crate) fn free<T: Send + 'static>(value: T) {
+    PENDING.fetch_add(1, Ordering::Relaxed);
+    if let Err(mpsc::SendError(value)) = queue().lock().unwrap().send(Box::new(value)) {
+        // the thread is gone, so pay for the drop here
+        drop(value);
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Objects waiting to be dropped in the background
+pub(crate) fn pending() -> usize {
+    PENDING.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn drops_in_the_background() {
+        let value = Arc::new(vec![0u8; 1024]);
+        free(value.clone());
+        for _ in 0..100 {
+            if Arc::strong_count(&value) == 1 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
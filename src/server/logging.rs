@@ -0,0 +1,228 @@
+use std::{
+    ffi::OsString,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use tracing::{field::Field, Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        writer::BoxMakeWriter,
+        FmtContext, FormatEvent, FormatFields,
+    },
+    registry::LookupSpan,
+};
+
+use crate::server::config::Config;
+
+/// A subscriber for what is logged before the config is known, such as warnings about the
+/// config itself
+pub fn early() -> impl Subscriber + Send + Sync {
+    tracing_subscriber::fmt().with_writer(io::stderr).finish()
+}
+
+/// Install the process wide subscriber the config asks for
+pub fn init(config: &Config) -> Result<()> {
+    let (writer, ansi) = match &config.logfile {
+        Some(path) => {
+            let file = RotatingFile::open(path, config.logfile_max_size, config.logfile_keep)?;
+            (BoxMakeWriter::new(Mutex::new(file)), false)
+        }
+        None => (BoxMakeWriter::new(io::stdout), !config.log_json),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(config.loglevel)
+        .with_ansi(ansi)
+        .with_writer(writer);
+    let installed = match config.log_json {
+        true => builder.event_format(JsonFormat).try_init(),
+        false => builder.try_init(),
+    };
+    installed.map_err(|e| anyhow::anyhow!("Failed to set up logging: {e}"))
+}
+
+/// Formats each event as a single line JSON object, its fields next to the message
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+        let metadata = event.metadata();
+        let mut line = format!(
+            "{{\"timestamp\":{},\"level\":\"{}\",\"target\":{}",
+            json_string(&timestamp),
+            metadata.level(),
+            json_string(metadata.target()),
+        );
+        event.record(&mut JsonFields(&mut line));
+        line.push('}');
+        writeln!(writer, "{line}")
+    }
+}
+
+/// Appends the fields of an event to a JSON object being written
+struct JsonFields<'a>(&'a mut String);
+
+impl JsonFields<'_> {
+    fn push(&mut self, field: &Field, value: &str) {
+        self.0.push(',');
+        self.0.push_str(&json_string(field.name()));
+        self.0.push(':');
+        self.0.push_str(value);
+    }
+}
+
+impl tracing::field::Visit for JsonFields<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, &value.to_string());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, &value.to_string());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, &value.to_string());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, &json_string(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, &json_string(&format!("{value:?}")));
+    }
+}
+
+/// `s` as a quoted JSON string
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A log file moved aside once it reaches `max_size`, keeping `keep` older files
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64, keep: usize) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file,
+            size,
+        })
+    }
+
+    /// Path of the `n`th newest rotated file
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = OsString::from(&self.path);
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let ignore_missing = |result: io::Result<()>| match result {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        };
+        if self.keep == 0 {
+            ignore_missing(std::fs::remove_file(&self.path))?;
+        } else {
+            for n in (1..self.keep).rev() {
+                ignore_missing(std::fs::rename(self.rotated(n), self.rotated(n + 1)))?;
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_json() {
+        assert_eq!(json_string("a \"b\"\n\\"), r#""a \"b\"\n\\""#);
+        assert_eq!(json_string("\u{1}"), r#""\u0001""#);
+    }
+
+    #[test]
+    fn rotates_log_files() {
+        let dir = std::env::temp_dir().join(format!("redis-log-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("redis.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |path: PathBuf| std::fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(file.rotated(1)), "third\n");
+        assert_eq!(read(file.rotated(2)), "second\n");
+        assert!(!file.rotated(3).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use bytes::Bytes;
+
+use crate::{error::RedisError, resp::client::Client, resp::RedisValue};
+
+/// A key to move, as DUMP serialized it, with the milliseconds it has left to live or 0
+pub(crate) struct Dumped {
+    pub(crate) key: Bytes,
+    pub(crate) ttl_ms: u64,
+    pub(crate) payload: Bytes,
+}
+
+/// Restore `keys` on the server at `host:port` with RESTORE-ASKING, so the target takes them even
+/// while it is still importing their slot. Returns its reply for each key, in order.
+pub(crate) async fn restore(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+    keys: &[Dumped],
+    replace: bool,
+) -> Result<Vec<RedisValue>, RedisError> {
+    let mut target = tokio::time::timeout(timeout, Client::connect((host, port)))
+        .await
+        .ok()
+        .and_then(Result::ok)
+        .ok_or(RedisError::Io("connecting to the client"))?;
+
+    // sent in one go, then the replies read back
+    for dumped in keys {
+        let mut args = vec![
+            Bytes::from("RESTORE-ASKING"),
+            dumped.key.clone(),
+            Bytes::from(dumped.ttl_ms.to_string()),
+            dumped.payload.clone(),
+        ];
+        if replace {
+            args.push(Bytes::from("REPLACE"));
+        }
+        tokio::time::timeout(timeout, target.send(RedisValue::command(args)))
+            .await
+            .ok()
+            .and_then(Result::ok)
+            .ok_or(RedisError::Io("writing to target instance"))?;
+    }
+    let mut replies = Vec::with_capacity(keys.len());
+    for _ in keys {
+        let reply = tokio::time::timeout(timeout, target.receive())
+            .await
+            .ok()
+            .and_then(|reply| reply.ok().flatten())
+            .ok_or(RedisError::Io("reading to target instance"))?;
+        replies.push(reply);
+    }
+    Ok(replies)
+}
@@ -0,0 +1,161 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{
+    rdb::{self, RdbValue},
+    server::{
+        keyspace::KeyType,
+        storage::{Snapshot, Storage},
+        tasks,
+        types::{ExpiryEvent, RedisKey, Value, ZAddFlags},
+    },
+};
+
+/// Serialize a snapshot into an RDB payload off the async runtime, LZF compressing long strings
+/// if `compress` is set
+pub(crate) async fn encode(snapshot: Box<dyn Snapshot>, compress: bool) -> Result<Bytes> {
+    Ok(tasks::offload("rdb-encode", move || {
+        let entries = snapshot.entries();
+        // stop copy-on-write tracking as soon as we have our view
+        drop(snapshot);
+        rdb::encode(&entries, compress)
+    })
+    .await?)
+}
+
+/// Serialize a snapshot and write it to `path`.
+///
+/// The file is written under a temporary name and renamed into place so a crash mid-save never
+/// leaves a truncated RDB behind.
+pub(crate) async fn save(snapshot: Box<dyn Snapshot>, path: PathBuf, compress: bool) -> Result<()> {
+    let data = encode(snapshot, compress).await?;
+
+    let tmp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    tokio::fs::write(&tmp, &data).await?;
+    tokio::fs::rename(&tmp, &path).await?;
+    tracing::info!("Saved {} bytes to {path:?}", data.len());
+    Ok(())
+}
+
+/// Load an RDB payload into the database, registering key expirations as we go
+pub(crate) async fn load(
+    db: &dyn Storage,
+    expiration_tx: &UnboundedSender<ExpiryEvent>,
+    data: &Bytes,
+) -> Result<()> {
+    let rdb = rdb::parse(data)?;
+    tracing::info!(
+        "Loading {} keys from RDB version {}",
+        rdb.entries.len(),
+        rdb.version
+    );
+
+    for entry in rdb.entries {
+        if entry.db != 0 {
+            tracing::warn!("Skipping key {:?} from database {}", entry.key, entry.db);
+            continue;
+        }
+        let expiration = entry.expiration_ms;
+        if expiration.is_some_and(|at| at <= db.clock().now_ms()) {
+            tracing::info!("Skipping already expired key {:?}", entry.key);
+            continue;
+        }
+        restore(db, expiration_tx, entry.key, entry.value, expiration).await?;
+    }
+    Ok(())
+}
+
+/// The value of `key` as an RDB file holds it, `None` if there is no such key
+pub(crate) fn value(db: &dyn Storage, key: &RedisKey) -> Option<RdbValue> {
+    match db.key_type(key)? {
+        KeyType::String => db.get_key(key).map(RdbValue::String),
+        KeyType::List => db.list_range(key, 0, -1).map(RdbValue::List),
+        _ => db.members(key),
+    }
+}
+
+/// Store `value` at `key`, which must not exist, registering its expiration `at` if any
+pub(crate) async fn restore(
+    db: &dyn Storage,
+    expiration_tx: &UnboundedSender<ExpiryEvent>,
+    key: RedisKey,
+    value: RdbValue,
+    at: Option<u64>,
+) -> Result<()> {
+    // strings are stored with their expiration, other types are given theirs once stored
+    let expires_after = !matches!(value, RdbValue::String(_));
+    match value {
+        RdbValue::String(value) => {
+            db.set_key(&key, Value::new(value, at));
+        }
+        RdbValue::List(elements) => {
+            db.push(
+                &key,
+                elements.into_iter().map(|e| Value::new(e, None)).collect(),
+                false,
+            );
+        }
+        RdbValue::Set(members) => {
+            db.set_add(&key, members);
+        }
+        RdbValue::Hash(pairs) => {
+            db.hash_set(&key, pairs);
+        }
+        RdbValue::SortedSet(members) => {
+            let pairs = members
+                .into_iter()
+                .filter(|(_, score)| !score.is_nan())
+                .map(|(member, score)| (score, member))
+                .collect();
+            db.zadd(&key, pairs, ZAddFlags::default());
+        }
+    }
+    if let Some(at) = at
+        && expires_after
+    {
+        db.expire_at(&key, at);
+    }
+    if let Some(at) = at {
+        expiration_tx.send((Some(at), key))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::types::Database;
+
+    #[tokio::test]
+    async fn expirations_survive_a_reload_exactly() {
+        let db = Database::default();
+        let at = db.clock().now_ms() + 60_000;
+        db.set_key(&"s".into(), Value::new("x".into(), Some(at)));
+        db.push(&"l".into(), vec![Value::new("x".into(), None)], false);
+        db.expire_at(&"l".into(), at + 1);
+        db.hash_set(&"h".into(), vec![("f".into(), "v".into())]);
+        db.expire_at(&"h".into(), at + 2);
+        db.set_key(&"gone".into(), Value::new("x".into(), Some(at - 120_000)));
+        let data = encode(db.snapshot().unwrap(), true).await.unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let reloaded = Database::default();
+        load(&reloaded, &tx, &data).await.unwrap();
+        assert_eq!(reloaded.get_key_expiration(&"s".into()), Some(at));
+        assert_eq!(reloaded.get_key_expiration(&"l".into()), Some(at + 1));
+        assert_eq!(reloaded.get_key_expiration(&"h".into()), Some(at + 2));
+        assert_eq!(reloaded.hash_get(&"h".into(), "f".into()), Some("v".into()));
+        assert!(!reloaded.exists(&"gone".into()));
+        // all are scheduled for the expirer, at the same times
+        drop(tx);
+        let mut scheduled = Vec::new();
+        while let Some((at, _)) = rx.recv().await {
+            scheduled.push(at.unwrap());
+        }
+        scheduled.sort();
+        assert_eq!(scheduled, [at, at + 1, at + 2]);
+    }
+}
@@ -0,0 +1,243 @@
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::{
+    replication::ReplicationState,
+    resp::RedisValue,
+    server::{
+        aof::Aof,
+        storage::Storage,
+        tracking,
+        types::RedisKey,
+        watch::{KeyEvent, Watchers},
+    },
+};
+
+/// A write applied to the dataset, as handed to every [`Sink`]
+pub(crate) struct Write {
+    /// Commands that redo the write, for replicas and the AOF. Empty when there is nothing to
+    /// redo.
+    pub(crate) commands: Vec<RedisValue>,
+
+    /// What the write did to each key it touched
+    pub(crate) events: Vec<KeyEvent>,
+
+    /// Set when the write removed every key, `events` only holding those anyone watches
+    pub(crate) flushed: bool,
+
+    /// Client that made the write, `None` for the server itself or our master
+    pub(crate) by: Option<u64>,
+}
+
+impl Write {
+    /// A write redone by `commands`
+    pub(crate) fn new(commands: Vec<RedisValue>, events: Vec<KeyEvent>) -> Self {
+        Self {
+            commands,
+            events,
+            flushed: false,
+            by: None,
+        }
+    }
+
+    /// A write redone by `command` that did `event` to its key
+    pub(crate) fn single(command: RedisValue, event: KeyEvent) -> Self {
+        Self::new(vec![command], vec![event])
+    }
+
+    pub(crate) fn by(mut self, client: Option<u64>) -> Self {
+        self.by = client;
+        self
+    }
+
+    pub(crate) fn flushed(mut self, flushed: bool) -> Self {
+        self.flushed = flushed;
+        self
+    }
+}
+
+/// Somewhere writes go once applied, such as replicas or an append-only file
+pub(crate) trait Sink: Send + Sync {
+    /// Whether the sink has any use for `write`, checked before it is handed over
+    fn accepts(&self, _write: &Write) -> bool {
+        true
+    }
+
+    fn write(&self, write: &Write);
+}
+
+impl Sink for ReplicationState {
+    fn accepts(&self, write: &Write) -> bool {
+        !write.commands.is_empty()
+    }
+
+    fn write(&self, write: &Write) {
+        for command in &write.commands {
+            self.propagate(command.clone());
+        }
+    }
+}
+
+impl Sink for Watchers {
+    fn accepts(&self, _write: &Write) -> bool {
+        self.watching()
+    }
+
+    fn write(&self, write: &Write) {
+        for event in &write.events {
+            self.emit(event.clone());
+        }
+    }
+}
+
+/// Clients caching the keys written, see [`tracking`]
+struct Tracking;
+
+impl Sink for Tracking {
+    fn write(&self, write: &Write) {
+        match write.flushed {
+            true => tracking::invalidate_all(write.by),
+            false => tracking::invalidate(write.events.iter().map(KeyEvent::key), write.by),
+        }
+    }
+}
+
+/// The single path writes leave the server by, handing each to the replicas, the clients
+/// tracking keys, the programs watching them and any other sink added
+pub(crate) struct Propagator {
+    replication: Arc<ReplicationState>,
+    watchers: Arc<Watchers>,
+    aof: OnceLock<Arc<Aof>>,
+    sinks: RwLock<Vec<Arc<dyn Sink>>>,
+}
+
+impl Propagator {
+    pub(crate) fn new(replication: Arc<ReplicationState>) -> Self {
+        let watchers = Arc::new(Watchers::default());
+        let sinks: Vec<Arc<dyn Sink>> =
+            vec![replication.clone(), Arc::new(Tracking), watchers.clone()];
+        Self {
+            replication,
+            watchers,
+            aof: OnceLock::new(),
+            sinks: RwLock::new(sinks),
+        }
+    }
+
+    /// Hand every write from now on to `sink` too
+    pub(crate) fn add_sink(&self, sink: Arc<dyn Sink>) {
+        self.sinks.write().unwrap().push(sink);
+    }
+
+    /// Log every write from now on to `aof`
+    pub(crate) fn set_aof(&self, aof: Arc<Aof>) {
+        if self.aof.set(aof.clone()).is_ok() {
+            self.add_sink(aof);
+        }
+    }
+
+    /// The append-only file writes are logged to, if it is on
+    pub(crate) fn aof(&self) -> Option<&Arc<Aof>> {
+        self.aof.get()
+    }
+
+    pub(crate) fn watchers(&self) -> &Watchers {
+        &self.watchers
+    }
+
+    /// Hand `write` to every sink that accepts it, returning the replication offset just after
+    /// it. Must be called holding the write lock, so every sink sees writes in the order they
+    /// were applied.
+    pub(crate) fn propagate(&self, write: Write) -> u64 {
+        for sink in self.sinks.read().unwrap().iter() {
+            if sink.accepts(&write) {
+                sink.write(&write);
+            }
+        }
+        self.replication.offset()
+    }
+
+    /// Note the `keys` a write is about to touch, along with whether they exist when anyone
+    /// watches them, to tell what it did with [`events`](Self::events) once applied
+    pub(crate) fn before<'a>(
+        &self,
+        db: &dyn Storage,
+        keys: impl IntoIterator<Item = &'a RedisKey>,
+    ) -> Vec<(RedisKey, bool)> {
+        let watching = self.watchers.watching();
+        keys.into_iter()
+            .map(|key| (key.clone(), !watching || db.exists(key)))
+            .collect()
+    }
+
+    /// What a write did to the keys noted [`before`](Self::before) it. Keys that neither
+    /// existed nor exist now were left alone.
+    pub(crate) fn events(db: &dyn Storage, before: Vec<(RedisKey, bool)>) -> Vec<KeyEvent> {
+        before
+            .into_iter()
+            .filter_map(|(key, existed)| match db.exists(&key) {
+                true => Some(KeyEvent::Set(key)),
+                false => existed.then_some(KeyEvent::Deleted(key)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use bytes::Bytes;
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::{
+        replication::Role,
+        server::types::{Database, Value},
+    };
+
+    /// Remembers the commands of every write
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<RedisValue>>);
+
+    impl Sink for Recorder {
+        fn accepts(&self, write: &Write) -> bool {
+            write.by.is_some()
+        }
+
+        fn write(&self, write: &Write) {
+            self.0
+                .lock()
+                .unwrap()
+                .extend(write.commands.iter().cloned());
+        }
+    }
+
+    #[tokio::test]
+    async fn fans_writes_out_to_every_sink() {
+        let replication = Arc::new(ReplicationState::new(Role::Master));
+        let propagator = Propagator::new(replication.clone());
+        let recorder = Arc::new(Recorder::default());
+        propagator.add_sink(recorder.clone());
+        let mut events = propagator.watchers().watch("user:".into());
+        let db = Database::default();
+        let (user, missing) = (Bytes::from("user:1"), Bytes::from("user:missing"));
+
+        let before = propagator.before(&db, [&user, &missing]);
+        db.set_key(&user, Value::new("x".into(), None));
+        let set = RedisValue::command(["SET", "user:1", "x"]);
+        let write = Write::new(vec![set.clone()], Propagator::events(&db, before));
+        let offset = propagator.propagate(write.by(Some(7)));
+        assert_eq!(offset, replication.offset());
+        assert!(offset > 0);
+
+        // only the recorder filters out the server's own writes
+        let del = RedisValue::command(["DEL", "user:1"]);
+        db.delete(&user, false);
+        propagator.propagate(Write::single(del, KeyEvent::Expired(user.clone())));
+
+        assert_eq!(*recorder.0.lock().unwrap(), [set]);
+        assert_eq!(events.next().await, Some(KeyEvent::Set(user.clone())));
+        assert_eq!(events.next().await, Some(KeyEvent::Expired(user)));
+        assert!(replication.offset() > offset);
+    }
+}
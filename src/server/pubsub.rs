@@ -0,0 +1,105 @@
+use std::{collections::HashSet, sync::LazyLock};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use crate::{resp::RedisValue, server::clients};
+
+/// Subscribed client IDs by channel
+static CHANNELS: LazyLock<DashMap<Bytes, HashSet<u64>>> = LazyLock::new(DashMap::new);
+
+/// Channels by subscribed client ID
+static SUBSCRIPTIONS: LazyLock<DashMap<u64, HashSet<Bytes>>> = LazyLock::new(DashMap::new);
+
+/// Subscribe client `id` to `channel`, returning how many channels it is now subscribed to
+pub(crate) fn subscribe(id: u64, channel: Bytes) -> usize {
+    CHANNELS.entry(channel.clone()).or_default().insert(id);
+    let mut channels = SUBSCRIPTIONS.entry(id).or_default();
+    channels.insert(channel);
+    channels.len()
+}
+
+/// Unsubscribe client `id` from `channel`, returning how many channels it is still subscribed to
+pub(crate) fn unsubscribe(id: u64, channel: &Bytes) -> usize {
+    CHANNELS.remove_if_mut(channel, |_, ids| {
+        ids.remove(&id);
+        ids.is_empty()
+    });
+    let Some(mut channels) = SUBSCRIPTIONS.get_mut(&id) else {
+        return 0;
+    };
+    channels.remove(channel);
+    let left = channels.len();
+    drop(channels);
+    if left == 0 {
+        SUBSCRIPTIONS.remove_if(&id, |_, channels| channels.is_empty());
+    }
+    left
+}
+
+/// The channels client `id` is subscribed to
+pub(crate) fn channels(id: u64) -> Vec<Bytes> {
+    SUBSCRIPTIONS
+        .get(&id)
+        .map(|channels| channels.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Whether client `id` is subscribed to any channel, and so in subscribed mode
+pub(crate) fn is_subscribed(id: u64) -> bool {
+    SUBSCRIPTIONS.contains_key(&id)
+}
+
+/// Drop every subscription of client `id`, once it disconnects
+pub(crate) fn unsubscribe_all(id: u64) {
+    for channel in channels(id) {
+        unsubscribe(id, &channel);
+    }
+}
+
+/// Send `message` to the subscribers of `channel`, returning how many there were
+pub(crate) fn publish(channel: &Bytes, message: &Bytes) -> usize {
+    let ids: Vec<u64> = match CHANNELS.get(channel) {
+        Some(ids) => ids.iter().copied().collect(),
+        None => return 0,
+    };
+    ids.into_iter()
+        .filter(|&id| {
+            clients::push(
+                id,
+                RedisValue::Push(vec![
+                    RedisValue::BulkString("message".into()),
+                    RedisValue::BulkString(channel.clone()),
+                    RedisValue::BulkString(message.clone()),
+                ]),
+            )
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_subscriptions_both_ways() {
+        // the registry is process wide, so use IDs and channels of our own
+        let (id, news, weather) = (
+            u64::MAX - 100,
+            Bytes::from("pubsub:news"),
+            Bytes::from("pubsub:weather"),
+        );
+        assert_eq!(subscribe(id, news.clone()), 1);
+        assert_eq!(subscribe(id, weather.clone()), 2);
+        assert_eq!(subscribe(id, news.clone()), 2);
+        assert!(is_subscribed(id));
+
+        assert_eq!(unsubscribe(id, &news), 1);
+        assert!(!CHANNELS.contains_key(&news));
+        unsubscribe_all(id);
+        assert!(!is_subscribed(id));
+        assert!(!CHANNELS.contains_key(&weather));
+        // nobody connected with this ID to hear it
+        assert_eq!(publish(&weather, &Bytes::from("sunny")), 0);
+    }
+}
@@ -0,0 +1,228 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+use crate::{resp::RedisValue, server::config::RateLimit};
+
+/// Budgets shared by the connections from each IP, while any of them is open
+static BY_IP: LazyLock<DashMap<IpAddr, Arc<Mutex<Budget>>>> = LazyLock::new(DashMap::new);
+
+/// What to do with a command
+#[derive(Debug, PartialEq)]
+pub(crate) enum Verdict {
+    Allow,
+    /// Refuse the command, the client is over its limit
+    Reject,
+    /// Serve the command once this long has passed
+    Delay(Duration),
+}
+
+/// A budget refilled at `rate` per second, holding at most a second's worth
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.updated = now;
+    }
+
+    /// Tokens needed for `cost`, as a request above the capacity could never be granted
+    fn needed(&self, cost: u64) -> f64 {
+        (cost as f64).min(self.rate)
+    }
+
+    /// How long until the bucket is no longer in debt
+    fn debt(&self) -> Duration {
+        Duration::from_secs_f64((-self.tokens).max(0.0) / self.rate)
+    }
+}
+
+/// The buckets of a client, or of an IP
+#[derive(Debug)]
+struct Budget {
+    commands: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl Budget {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        let bucket = |rate| (rate > 0).then(|| TokenBucket::new(rate, now));
+        Self {
+            commands: bucket(limit.commands),
+            bytes: bucket(limit.bytes),
+        }
+    }
+
+    /// Charge a command of `bytes` to the budget. When delaying, the budget goes into debt and
+    /// the command waits for it to be paid off, so queued commands are spread out.
+    fn charge(&mut self, bytes: u64, delay: bool, now: Instant) -> Verdict {
+        let mut buckets: Vec<(&mut TokenBucket, u64)> = Vec::with_capacity(2);
+        if let Some(commands) = &mut self.commands {
+            buckets.push((commands, 1));
+        }
+        if let Some(bytes_bucket) = &mut self.bytes {
+            buckets.push((bytes_bucket, bytes));
+        }
+        for (bucket, _) in buckets.iter_mut() {
+            bucket.refill(now);
+        }
+        if !delay
+            && buckets
+                .iter()
+                .any(|(bucket, cost)| bucket.tokens < bucket.needed(*cost))
+        {
+            return Verdict::Reject;
+        }
+        let mut wait = Duration::ZERO;
+        for (bucket, cost) in buckets {
+            bucket.tokens -= bucket.needed(cost);
+            wait = wait.max(bucket.debt());
+        }
+        match wait.is_zero() {
+            true => Verdict::Allow,
+            false => Verdict::Delay(wait),
+        }
+    }
+}
+
+/// Enforces the configured [`RateLimit`] on one connection
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    budget: Arc<Mutex<Budget>>,
+    delay: bool,
+    /// Set when the budget is shared with the other connections from this IP
+    ip: Option<IpAddr>,
+}
+
+impl RateLimiter {
+    /// A limiter for a client connecting from `ip`, `None` if commands aren't limited
+    pub(crate) fn new(limit: RateLimit, ip: IpAddr) -> Option<Self> {
+        if !limit.enabled() {
+            return None;
+        }
+        let now = Instant::now();
+        let (budget, ip) = match limit.per_ip {
+            true => {
+                let budget = BY_IP
+                    .entry(ip)
+                    .or_insert_with(|| Arc::new(Mutex::new(Budget::new(limit, now))))
+                    .clone();
+                (budget, Some(ip))
+            }
+            false => (Arc::new(Mutex::new(Budget::new(limit, now))), None),
+        };
+        Some(Self {
+            budget,
+            delay: limit.delay,
+            ip,
+        })
+    }
+
+    /// Charge `command` to the client's budget
+    pub(crate) fn check(&self, command: &RedisValue) -> Verdict {
+        self.budget
+            .lock()
+            .unwrap()
+            .charge(argument_bytes(command), self.delay, Instant::now())
+    }
+}
+
+impl Drop for RateLimiter {
+    fn drop(&mut self) {
+        // forget the IP with its last connection, the map holding the only other reference
+        if let Some(ip) = self.ip {
+            BY_IP.remove_if(&ip, |_, budget| Arc::strong_count(budget) == 2);
+        }
+    }
+}
+
+/// Size of a command's arguments, what the bytes limit counts
+fn argument_bytes(command: &RedisValue) -> u64 {
+    match command {
+        RedisValue::Array(args) => args.iter().map(argument_bytes).sum(),
+        RedisValue::BulkString(arg) | RedisValue::SimpleString(arg) => arg.len() as u64,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_over_the_limit() {
+        let limit = RateLimit {
+            commands: 2,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let mut budget = Budget::new(limit, start);
+        assert_eq!(budget.charge(0, false, start), Verdict::Allow);
+        assert_eq!(budget.charge(0, false, start), Verdict::Allow);
+        assert_eq!(budget.charge(0, false, start), Verdict::Reject);
+        // half a second refills one command
+        let later = start + Duration::from_millis(500);
+        assert_eq!(budget.charge(0, false, later), Verdict::Allow);
+        assert_eq!(budget.charge(0, false, later), Verdict::Reject);
+    }
+
+    #[test]
+    fn delays_over_the_limit() {
+        let limit = RateLimit {
+            bytes: 100,
+            delay: true,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut budget = Budget::new(limit, now);
+        assert_eq!(budget.charge(100, true, now), Verdict::Allow);
+        assert_eq!(
+            budget.charge(50, true, now),
+            Verdict::Delay(Duration::from_millis(500))
+        );
+        // commands queued behind it wait their turn
+        assert_eq!(
+            budget.charge(50, true, now),
+            Verdict::Delay(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn shares_budgets_by_ip() {
+        let limit = RateLimit {
+            commands: 1,
+            per_ip: true,
+            ..Default::default()
+        };
+        let ip: IpAddr = "192.0.2.33".parse().unwrap();
+        let (first, second) = (
+            RateLimiter::new(limit, ip).unwrap(),
+            RateLimiter::new(limit, ip).unwrap(),
+        );
+        let ping = RedisValue::Array(vec![RedisValue::BulkString("PING".into())]);
+        assert_eq!(first.check(&ping), Verdict::Allow);
+        assert_eq!(second.check(&ping), Verdict::Reject);
+        drop(first);
+        assert!(BY_IP.contains_key(&ip));
+        drop(second);
+        assert!(!BY_IP.contains_key(&ip));
+    }
+}
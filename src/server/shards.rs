@@ -0,0 +1,451 @@
+use std::{
+    any::Any,
+    hash::{BuildHasher, RandomState},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex, MutexGuard,
+    },
+    thread,
+};
+
+use bytes::Bytes;
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    sync::oneshot,
+    task,
+};
+
+use crate::{
+    rdb::{RdbEntry, RdbValue},
+    server::{
+        clock::Clock,
+        eviction::EvictionPool,
+        keyspace::KeyType,
+        storage::{KeyspaceStats, Snapshot, Storage},
+        types::{Database, LfuParams, ListpackLimit, RedisKey, Value, ZAddFlags, ZAdded},
+    },
+};
+
+/// Work for a shard, run on its thread against the keys it owns
+type Job = Box<dyn FnOnce(&Database) + Send>;
+
+/// What a job returned, or what it panicked with
+type Outcome<R> = Result<R, Box<dyn Any + Send>>;
+
+/// A keyspace split into shards by key hash, each owned by a dedicated thread that applies the
+/// operations sent to it one at a time, so no two cores ever contend for the same keys.
+///
+/// Operations on one key are atomic; anything spanning keys (a snapshot, FLUSHALL, sampling) is
+/// made of one operation per shard and not atomic across them.
+///
+/// `cargo bench --bench keyspace`, with and without the `sharded-keyspace` feature, compares
+/// it with the shared maps.
+pub(crate) struct ShardedDatabase {
+    shards: Vec<mpsc::Sender<Job>>,
+    hasher: RandomState,
+    clock: Arc<dyn Clock>,
+    eviction_pool: Mutex<EvictionPool>,
+    /// See [`Storage::avg_ttl`], estimated over every shard
+    avg_ttl: AtomicU64,
+}
+
+impl ShardedDatabase {
+    /// Start `count` shards, each on its own thread
+    pub(crate) fn new(
+        count: usize,
+        lfu: LfuParams,
+        list_limit: ListpackLimit,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let shards = (0..count.max(1))
+            .map(|i| {
+                let (tx, rx) = mpsc::channel::<Job>();
+                let db = Database::new(lfu, list_limit, clock.clone());
+                thread::Builder::new()
+                    .name(format!("keyspace-shard-{i}"))
+                    .spawn(move || {
+                        // ends once the keyspace is dropped along with its senders
+                        for job in rx {
+                            job(&db);
+                        }
+                    })
+                    .expect("failed to spawn keyspace shard");
+                tx
+            })
+            .collect();
+        Self {
+            shards,
+            hasher: RandomState::new(),
+            clock,
+            eviction_pool: Mutex::new(EvictionPool::default()),
+            avg_ttl: AtomicU64::new(0),
+        }
+    }
+
+    /// One shard per core
+    pub(crate) fn per_core(
+        lfu: LfuParams,
+        list_limit: ListpackLimit,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let cores = thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(cores, lfu, list_limit, clock)
+    }
+
+    /// Run `f` on shard `shard` and wait for its result. If `f` panics, the panic is caught on
+    /// the shard, which carries on serving, and raised again here as if `f` had run here.
+    fn call<R, F>(&self, shard: usize, f: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&Database) -> R + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move |db| {
+            let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(|| f(db))));
+        });
+        self.shards[shard]
+            .send(job)
+            .expect("keyspace shard stopped");
+        match wait(rx).expect("keyspace shard stopped") {
+            Ok(result) => result,
+            Err(panic) => panic::resume_unwind(panic),
+        }
+    }
+
+    /// Run `f` on the shard owning `key`
+    fn with_key<R, F>(&self, key: &RedisKey, f: F) -> R
+    where
+        R: Send + 'static,
+        F: FnOnce(&Database, &RedisKey) -> R + Send + 'static,
+    {
+        let shard = self.hasher.hash_one(key) as usize % self.shards.len();
+        let key = key.clone();
+        self.call(shard, move |db| f(db, &key))
+    }
+
+    /// Run `f` on every shard, collecting the results in shard order
+    fn each<R, F>(&self, f: F) -> Vec<R>
+    where
+        R: Send + 'static,
+        F: Fn(&Database) -> R + Send + Sync + 'static,
+    {
+        let f = Arc::new(f);
+        // send every job before waiting, so the shards work in parallel
+        let replies: Vec<_> = (0..self.shards.len())
+            .map(|shard| {
+                let (tx, rx) = mpsc::sync_channel(1);
+                let f = f.clone();
+                let job: Job = Box::new(move |db| {
+                    let _ = tx.send(f(db));
+                });
+                self.shards[shard]
+                    .send(job)
+                    .expect("keyspace shard stopped");
+                rx
+            })
+            .collect();
+        replies
+            .into_iter()
+            .map(|rx| rx.recv().expect("keyspace shard stopped"))
+            .collect()
+    }
+
+    /// Up to `count` keys drawn by `sample` spread over the shards
+    fn sample(&self, count: usize, sample: fn(&Database, usize) -> Vec<RedisKey>) -> Vec<RedisKey> {
+        let per_shard = count.div_ceil(self.shards.len());
+        let mut keys: Vec<RedisKey> = self
+            .each(move |db| sample(db, per_shard))
+            .into_iter()
+            .flatten()
+            .collect();
+        keys.truncate(count);
+        keys
+    }
+}
+
+impl Storage for ShardedDatabase {
+    fn get_key(&self, key: &RedisKey) -> Option<Bytes> {
+        self.with_key(key, |db, key| db.get_key(key))
+    }
+
+    fn set_key(&self, key: &RedisKey, value: Value) -> Option<Value> {
+        self.with_key(key, move |db, key| db.set_key(key, value))
+    }
+
+    fn push(&self, key: &RedisKey, values: Vec<Value>, front: bool) -> usize {
+        self.with_key(key, move |db, key| db.push(key, values, front))
+    }
+
+    fn pop(&self, key: &RedisKey, count: usize, front: bool) -> Option<Vec<Bytes>> {
+        self.with_key(key, move |db, key| db.pop(key, count, front))
+    }
+
+    fn list_range(&self, key: &RedisKey, start: i64, stop: i64) -> Option<Vec<Bytes>> {
+        self.with_key(key, move |db, key| db.list_range(key, start, stop))
+    }
+
+    fn set_add(&self, key: &RedisKey, members: Vec<Bytes>) -> usize {
+        self.with_key(key, move |db, key| db.set_add(key, members))
+    }
+
+    fn set_remove(&self, key: &RedisKey, members: Vec<Bytes>) -> usize {
+        self.with_key(key, move |db, key| db.set_remove(key, members))
+    }
+
+    fn set_pop(&self, key: &RedisKey, count: usize) -> Option<Vec<Bytes>> {
+        self.with_key(key, move |db, key| db.set_pop(key, count))
+    }
+
+    fn hash_set(&self, key: &RedisKey, pairs: Vec<(Bytes, Bytes)>) -> usize {
+        self.with_key(key, move |db, key| db.hash_set(key, pairs))
+    }
+
+    fn hash_get(&self, key: &RedisKey, field: Bytes) -> Option<Bytes> {
+        self.with_key(key, move |db, key| db.hash_get(key, field))
+    }
+
+    fn zadd(&self, key: &RedisKey, pairs: Vec<(f64, Bytes)>, flags: ZAddFlags) -> Option<ZAdded> {
+        self.with_key(key, move |db, key| db.zadd(key, pairs, flags))
+    }
+
+    fn zscore(&self, key: &RedisKey, member: Bytes) -> Option<f64> {
+        self.with_key(key, move |db, key| db.zscore(key, member))
+    }
+
+    fn zpop_min(&self, key: &RedisKey, count: usize) -> Option<Vec<(Bytes, f64)>> {
+        self.with_key(key, move |db, key| db.zpop_min(key, count))
+    }
+
+    fn collection_len(&self, key: &RedisKey) -> usize {
+        self.with_key(key, |db, key| db.collection_len(key))
+    }
+
+    fn members(&self, key: &RedisKey) -> Option<RdbValue> {
+        self.with_key(key, |db, key| db.members(key))
+    }
+
+    fn key_type(&self, key: &RedisKey) -> Option<KeyType> {
+        self.with_key(key, |db, key| db.key_type(key))
+    }
+
+    fn delete(&self, key: &RedisKey, lazy: bool) -> bool {
+        self.with_key(key, move |db, key| db.delete(key, lazy))
+    }
+
+    fn evict(&self, key: &RedisKey, lazy: bool) -> bool {
+        self.with_key(key, move |db, key| db.evict(key, lazy))
+    }
+
+    fn delete_expired(&self, key: &RedisKey, now: u64) -> bool {
+        self.with_key(key, move |db, key| db.delete_expired(key, now))
+    }
+
+    fn expire_at(&self, key: &RedisKey, at: u64) -> bool {
+        self.with_key(key, move |db, key| db.expire_at(key, at))
+    }
+
+    fn get_key_expiration(&self, key: &RedisKey) -> Option<u64> {
+        self.with_key(key, |db, key| db.get_key_expiration(key))
+    }
+
+    fn flush(&self, lazy: bool) {
+        self.each(move |db| db.flush(lazy));
+    }
+
+    fn len(&self) -> usize {
+        self.each(|db| db.len()).into_iter().sum()
+    }
+
+    fn expires(&self) -> usize {
+        self.each(|db| db.expires()).into_iter().sum()
+    }
+
+    fn keyspace_stats(&self) -> KeyspaceStats {
+        self.each(|db| db.keyspace_stats())
+            .into_iter()
+            .fold(KeyspaceStats::default(), |total, shard| total + shard)
+    }
+
+    fn avg_ttl(&self) -> u64 {
+        self.avg_ttl.load(Ordering::Relaxed)
+    }
+
+    fn set_avg_ttl(&self, ms: u64) {
+        self.avg_ttl.store(ms, Ordering::Relaxed);
+    }
+
+    fn keys(&self) -> Vec<RedisKey> {
+        self.each(|db| db.keys()).into_iter().flatten().collect()
+    }
+
+    fn sample_strings(&self, count: usize) -> Vec<RedisKey> {
+        self.sample(count, |db, count| db.sample_strings(count))
+    }
+
+    fn sample_lists(&self, count: usize) -> Vec<RedisKey> {
+        self.sample(count, |db, count| db.sample_lists(count))
+    }
+
+    fn sample_collections(&self, count: usize) -> Vec<RedisKey> {
+        self.sample(count, |db, count| db.sample_collections(count))
+    }
+
+    fn sample_keys(&self, count: usize) -> Vec<RedisKey> {
+        self.sample(count, |db, count| db.sample_keys(count))
+    }
+
+    fn snapshot(&self) -> Option<Box<dyn Snapshot>> {
+        // any shard already snapshotting means one is in progress; dropping the rest ends them
+        let shards: Option<Vec<_>> = self.each(|db| db.snapshot()).into_iter().collect();
+        shards.map(|shards| Box::new(ShardedSnapshot(shards)) as Box<dyn Snapshot>)
+    }
+
+    fn used_memory(&self) -> usize {
+        self.each(|db| db.used_memory()).into_iter().sum()
+    }
+
+    fn used_memory_peak(&self) -> usize {
+        // the shards peak at different times, so this overstates the true peak
+        self.each(|db| db.used_memory_peak()).into_iter().sum()
+    }
+
+    fn memory_usage(&self, key: &RedisKey) -> Option<usize> {
+        self.with_key(key, |db, key| db.memory_usage(key))
+    }
+
+    fn free_effort(&self, key: &RedisKey) -> usize {
+        self.with_key(key, |db, key| db.free_effort(key))
+    }
+
+    fn encoding(&self, key: &RedisKey) -> Option<&'static str> {
+        self.with_key(key, |db, key| db.encoding(key))
+    }
+
+    fn list_slack(&self, key: &RedisKey) -> Option<(usize, usize)> {
+        self.with_key(key, |db, key| db.list_slack(key))
+    }
+
+    fn compact_list(&self, key: &RedisKey) -> usize {
+        self.with_key(key, |db, key| db.compact_list(key))
+    }
+
+    fn compact_tables(&self) -> usize {
+        self.each(|db| db.compact_tables()).into_iter().sum()
+    }
+
+    fn idle_ms(&self, key: &RedisKey) -> Option<u64> {
+        self.with_key(key, |db, key| db.idle_ms(key))
+    }
+
+    fn frequency(&self, key: &RedisKey) -> Option<u8> {
+        self.with_key(key, |db, key| db.frequency(key))
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    fn eviction_pool(&self) -> MutexGuard<'_, EvictionPool> {
+        self.eviction_pool.lock().unwrap()
+    }
+}
+
+/// Await a shard's reply. [`Storage`] calls are synchronous, so a worker of a multi-threaded
+/// runtime first hands its other tasks to the rest of the pool rather than stall them.
+fn wait<R>(reply: oneshot::Receiver<Outcome<R>>) -> Result<Outcome<R>, oneshot::error::RecvError> {
+    // the task calling us may have used up its coop budget, which would leave the reply
+    // pending however often it is polled here
+    let wait = || futures::executor::block_on(task::unconstrained(reply));
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            task::block_in_place(wait)
+        }
+        _ => wait(),
+    }
+}
+
+/// One snapshot per shard, each consistent within its shard
+struct ShardedSnapshot(Vec<Box<dyn Snapshot>>);
+
+impl Snapshot for ShardedSnapshot {
+    fn entries(&self) -> Vec<RdbEntry> {
+        self.0.iter().flat_map(|shard| shard.entries()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::clock::SystemClock;
+
+    fn sharded(count: usize) -> ShardedDatabase {
+        ShardedDatabase::new(
+            count,
+            LfuParams::default(),
+            ListpackLimit::default(),
+            Arc::new(SystemClock),
+        )
+    }
+
+    #[test]
+    fn routes_keys_to_shards() {
+        let db = sharded(4);
+        for i in 0..100 {
+            db.set_key(&format!("k{i}").into(), Value::new("v".into(), None));
+        }
+        db.push(&"list".into(), vec![Value::new("a".into(), None)], false);
+        db.zadd(
+            &"zset".into(),
+            vec![(1.0, "m".into())],
+            ZAddFlags::default(),
+        );
+        assert_eq!(db.len(), 102);
+        assert_eq!(db.keys().len(), 102);
+        assert_eq!(db.get_key(&"k42".into()), Some("v".into()));
+        assert_eq!(db.key_type(&"list".into()), Some(KeyType::List));
+        assert_eq!(db.zscore(&"zset".into(), "m".into()), Some(1.0));
+        assert_eq!(db.sample_keys(10).len(), 10);
+
+        let snapshot = db.snapshot().unwrap();
+        assert!(db.snapshot().is_none());
+        assert_eq!(snapshot.entries().len(), 102);
+        drop(snapshot);
+        assert!(db.snapshot().is_some());
+
+        db.flush(false);
+        assert_eq!(db.len(), 0);
+    }
+
+    #[test]
+    fn shards_outlive_panicking_jobs() {
+        let db = sharded(1);
+        db.set_key(&"k".into(), Value::new("v".into(), None));
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            db.call::<(), _>(0, |_| panic!("boom"));
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(db.get_key(&"k".into()), Some("v".into()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn waits_without_stalling_the_runtime() {
+        let db = Arc::new(sharded(2));
+        let tasks: Vec<_> = (0..8)
+            .map(|t| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    for i in 0..100 {
+                        let key: RedisKey = format!("key:{t}:{i}").into();
+                        db.set_key(&key, Value::new("v".into(), None));
+                        assert_eq!(db.get_key(&key), Some("v".into()));
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(db.len(), 800);
+    }
+}
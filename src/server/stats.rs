@@ -0,0 +1,289 @@
+use std::{
+    borrow::Cow,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, OnceLock,
+    },
+    time::Duration,
+};
+
+use dashmap::DashMap;
+
+use crate::command::table::{CommandSpec, COMMANDS};
+
+/// Percentiles reported by `INFO latencystats`, Redis' default ones
+const LATENCY_PERCENTILES: &[f64] = &[50.0, 99.0, 99.9];
+
+/// Sub-buckets per power of two in a [`Histogram`], bounding its error to 1/16th
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Statistics of each built-in command, in [`COMMANDS`] order, made on its first call
+static BUILTIN: LazyLock<Box<[OnceLock<CommandStats>]>> =
+    LazyLock::new(|| COMMANDS.iter().map(|_| OnceLock::new()).collect());
+
+/// Statistics of each custom command, by name. Those are registered once at startup, so the
+/// few made here live as long as the process.
+static CUSTOM: LazyLock<DashMap<String, &'static CommandStats>> = LazyLock::new(DashMap::new);
+
+/// Statistics of a command, counted without taking a lock
+#[derive(Debug)]
+pub(crate) struct CommandStats {
+    /// Name in the command table
+    name: Cow<'static, str>,
+    calls: AtomicU64,
+    usec: AtomicU64,
+    /// Refused before being executed, e.g. by an OOM or READONLY error
+    rejected_calls: AtomicU64,
+    /// Executed, but replied with an error
+    failed_calls: AtomicU64,
+    latency: Histogram,
+}
+
+impl CommandStats {
+    fn new(name: Cow<'static, str>) -> Self {
+        Self {
+            name,
+            calls: AtomicU64::new(0),
+            usec: AtomicU64::new(0),
+            rejected_calls: AtomicU64::new(0),
+            failed_calls: AtomicU64::new(0),
+            latency: Histogram::default(),
+        }
+    }
+
+    /// The command's name as the table has it, upper-cased
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Count a call that took `elapsed`
+    pub(crate) fn record(&self, elapsed: Duration, failed: bool) {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.usec
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.failed_calls
+            .fetch_add(u64::from(failed), Ordering::Relaxed);
+        self.latency.record(elapsed.as_nanos() as u64);
+    }
+
+    /// Count a call refused before it was executed
+    pub(crate) fn reject(&self) {
+        self.rejected_calls.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The statistics of the command `spec` describes
+pub(crate) fn of(spec: &CommandSpec) -> &'static CommandStats {
+    if COMMANDS.as_ptr_range().contains(&(spec as *const _)) {
+        let i = (spec as *const CommandSpec as usize - COMMANDS.as_ptr() as usize)
+            / size_of::<CommandSpec>();
+        return BUILTIN[i].get_or_init(|| CommandStats::new(spec.name.clone()));
+    }
+    if let Some(stats) = CUSTOM.get(&*spec.name) {
+        return *stats;
+    }
+    *CUSTOM
+        .entry(spec.name.to_string())
+        .or_insert_with(|| Box::leak(Box::new(CommandStats::new(spec.name.clone()))))
+}
+
+/// Log-linear histogram of durations in nanoseconds, a much simplified HdrHistogram
+#[derive(Debug)]
+struct Histogram {
+    counts: Box<[AtomicU64]>,
+    total: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            total: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    fn bucket(value: u64) -> usize {
+        if value < SUB_BUCKETS as u64 {
+            return value as usize;
+        }
+        let exp = 63 - value.leading_zeros();
+        let shift = exp - SUB_BUCKET_BITS;
+        let sub_bucket = (value >> shift) as usize - SUB_BUCKETS;
+        (shift as usize + 1) * SUB_BUCKETS + sub_bucket
+    }
+
+    /// Highest value counted in `bucket`
+    fn highest(bucket: usize) -> u64 {
+        if bucket < SUB_BUCKETS {
+            return bucket as u64;
+        }
+        let shift = (bucket / SUB_BUCKETS - 1) as u32;
+        let low = ((bucket % SUB_BUCKETS + SUB_BUCKETS) as u64) << shift;
+        low + ((1u64 << shift) - 1)
+    }
+
+    fn record(&self, value: u64) {
+        self.counts[Self::bucket(value)].fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The value `percentile`% of the recorded values are at most, as near as calls counted
+    /// while it looks can make it
+    fn percentile(&self, percentile: f64) -> u64 {
+        let total = self.total.load(Ordering::Relaxed);
+        let rank = ((percentile / 100.0 * total as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Self::highest(bucket);
+            }
+        }
+        0
+    }
+}
+
+/// Commands by lower-cased name, so that INFO lists them in a stable order
+fn sorted() -> Vec<(String, CommandStatsSnapshot)> {
+    let builtin = BUILTIN.iter().filter_map(OnceLock::get);
+    let custom: Vec<_> = CUSTOM.iter().map(|entry| *entry.value()).collect();
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut commands: Vec<_> = builtin
+        .chain(custom)
+        .map(|stats| {
+            let snapshot = CommandStatsSnapshot {
+                calls: load(&stats.calls),
+                usec: load(&stats.usec),
+                rejected_calls: load(&stats.rejected_calls),
+                failed_calls: load(&stats.failed_calls),
+                percentiles: LATENCY_PERCENTILES
+                    .iter()
+                    .map(|&p| stats.latency.percentile(p))
+                    .collect(),
+            };
+            (stats.name.to_lowercase(), snapshot)
+        })
+        .collect();
+    commands.sort_by(|a, b| a.0.cmp(&b.0));
+    commands
+}
+
+/// What INFO shows of a command's statistics, copied out before rendering
+struct CommandStatsSnapshot {
+    calls: u64,
+    usec: u64,
+    rejected_calls: u64,
+    failed_calls: u64,
+    /// Nanoseconds at each of [`LATENCY_PERCENTILES`]
+    percentiles: Vec<u64>,
+}
+
+/// The `INFO commandstats` section
+pub(crate) fn commandstats() -> String {
+    let mut out = String::from("# Commandstats\r\n");
+    for (name, stats) in sorted() {
+        let per_call = match stats.calls {
+            0 => 0.0,
+            calls => stats.usec as f64 / calls as f64,
+        };
+        let _ = write!(
+            out,
+            "cmdstat_{name}:calls={},usec={},usec_per_call={per_call:.2},rejected_calls={},\
+             failed_calls={}\r\n",
+            stats.calls, stats.usec, stats.rejected_calls, stats.failed_calls,
+        );
+    }
+    out
+}
+
+/// The `INFO latencystats` section
+pub(crate) fn latencystats() -> String {
+    let mut out = String::from("# Latencystats\r\n");
+    for (name, stats) in sorted() {
+        // rejected calls were never timed
+        if stats.calls == 0 {
+            continue;
+        }
+        let percentiles: Vec<String> = LATENCY_PERCENTILES
+            .iter()
+            .zip(&stats.percentiles)
+            .map(|(p, &nanos)| format!("p{p}={:.3}", nanos as f64 / 1000.0))
+            .collect();
+        let _ = write!(
+            out,
+            "latency_percentiles_usec_{name}:{}\r\n",
+            percentiles.join(",")
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::table::{lookup, CommandFlags};
+
+    #[test]
+    fn histogram_buckets() {
+        for value in [0, 1, 15, 16, 17, 31, 32, 1000, 123_456_789, u64::MAX] {
+            let bucket = Histogram::bucket(value);
+            assert!(bucket < BUCKETS);
+            let highest = Histogram::highest(bucket);
+            assert!(highest >= value, "{value} counted up to {highest}");
+            assert!(
+                highest - value <= value / 16,
+                "{value} counted up to {highest}"
+            );
+        }
+    }
+
+    #[test]
+    fn percentiles() {
+        let histogram = Histogram::default();
+        for value in 1..=1000 {
+            histogram.record(value * 1000);
+        }
+        let within = |value: u64, expected: u64| value.abs_diff(expected) <= expected / 16;
+        assert!(within(histogram.percentile(50.0), 500_000));
+        assert!(within(histogram.percentile(99.0), 990_000));
+        assert!(within(histogram.percentile(100.0), 1_000_000));
+        assert_eq!(Histogram::default().percentile(50.0), 0);
+    }
+
+    #[test]
+    fn renders_like_redis() {
+        let spec = CommandSpec {
+            name: Cow::Borrowed("STATS-TEST-COMMAND"),
+            arity: -1,
+            flags: CommandFlags::NONE,
+            first_key: 0,
+            last_key: 0,
+            step: 0,
+            options: &[],
+            subcommands: &[],
+        };
+        let stats = of(&spec);
+        assert!(std::ptr::eq(stats, of(&spec)));
+        stats.record(Duration::from_micros(10), false);
+        stats.record(Duration::from_micros(20), true);
+        stats.reject();
+        assert!(commandstats().contains(
+            "cmdstat_stats-test-command:calls=2,usec=30,usec_per_call=15.00,rejected_calls=1,\
+             failed_calls=1\r\n"
+        ));
+        assert!(latencystats().contains("latency_percentiles_usec_stats-test-command:p50="));
+    }
+
+    #[test]
+    fn keeps_built_in_commands_apart() {
+        let (get, set) = (lookup(b"get").unwrap(), lookup(b"SET").unwrap());
+        assert!(std::ptr::eq(of(get), of(lookup(b"GET").unwrap())));
+        assert!(!std::ptr::eq(of(get), of(set)));
+        assert_eq!(of(set).name(), "SET");
+    }
+}
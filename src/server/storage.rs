@@ -0,0 +1,259 @@
+use std::sync::MutexGuard;
+
+use bytes::Bytes;
+
+use crate::{
+    rdb::{RdbEntry, RdbValue},
+    server::{
+        clock::Clock,
+        eviction::EvictionPool,
+        keyspace::KeyType,
+        types::{RedisKey, Value, ZAddFlags, ZAdded},
+    },
+};
+
+/// How reads found keys and how keys went away, as INFO stats reports them
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyspaceStats {
+    /// Reads of a live key
+    pub(crate) hits: u64,
+    /// Reads of a missing or expired key
+    pub(crate) misses: u64,
+    /// Keys removed for having expired
+    pub(crate) expired: u64,
+    /// Keys removed to make room under `maxmemory`
+    pub(crate) evicted: u64,
+}
+
+impl std::ops::Add for KeyspaceStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+            expired: self.expired + other.expired,
+            evicted: self.evicted + other.evicted,
+        }
+    }
+}
+
+/// A keyspace the command layer reads and writes through, so the server can be built on a
+/// backend other than the in-memory [`Database`](crate::server::types::Database).
+///
+/// Expired keys must look absent to every read, even before they are removed. Expirations are
+/// unix times in milliseconds, judged by the [`Clock`]'s [`now_ms`](Clock::now_ms).
+pub(crate) trait Storage: Send + Sync {
+    /// Value of a live string, counting as an access to it
+    fn get_key(&self, key: &RedisKey) -> Option<Bytes>;
+
+    /// Store a string, replacing a key of any type, returning the string it replaced
+    fn set_key(&self, key: &RedisKey, value: Value) -> Option<Value>;
+
+    /// Append `values` to a list, or push them onto its front one at a time as LPUSH does,
+    /// creating it if needed. Returns its new length.
+    fn push(&self, key: &RedisKey, values: Vec<Value>, front: bool) -> usize;
+
+    /// Pop up to `count` elements from the front (or back) of a list, deleting the key once the
+    /// list is drained as collections never exist empty. `None` if there is no such list.
+    fn pop(&self, key: &RedisKey, count: usize, front: bool) -> Option<Vec<Bytes>>;
+
+    /// Elements `start` to `stop` inclusive of a list, negative indexes counting from the end as
+    /// in LRANGE, counting as an access to it. `None` if there is no such list.
+    fn list_range(&self, key: &RedisKey, start: i64, stop: i64) -> Option<Vec<Bytes>>;
+
+    /// Add `members` to a set, creating it if needed. Returns how many were not already there.
+    fn set_add(&self, key: &RedisKey, members: Vec<Bytes>) -> usize;
+
+    /// Remove `members` from a set, deleting the key once the set is empty. Returns how many
+    /// were there.
+    fn set_remove(&self, key: &RedisKey, members: Vec<Bytes>) -> usize;
+
+    /// Remove up to `count` random members from a set, deleting the key once the set is empty.
+    /// `None` if there is no such set.
+    fn set_pop(&self, key: &RedisKey, count: usize) -> Option<Vec<Bytes>>;
+
+    /// Set fields of a hash, creating it if needed. Returns how many fields are new.
+    fn hash_set(&self, key: &RedisKey, pairs: Vec<(Bytes, Bytes)>) -> usize;
+
+    /// Value of a hash field, counting as an access to the hash
+    fn hash_get(&self, key: &RedisKey, field: Bytes) -> Option<Bytes>;
+
+    /// Add members to a sorted set or update their scores as ZADD does with `flags`, creating it
+    /// if needed. `None` if an increment left a score NaN, in which case nothing changed.
+    fn zadd(&self, key: &RedisKey, pairs: Vec<(f64, Bytes)>, flags: ZAddFlags) -> Option<ZAdded>;
+
+    /// Score of a sorted set member, counting as an access to the sorted set
+    fn zscore(&self, key: &RedisKey, member: Bytes) -> Option<f64>;
+
+    /// Remove up to `count` of the lowest scoring members of a sorted set, lowest first,
+    /// deleting the key once it is empty. `None` if there is no such sorted set.
+    fn zpop_min(&self, key: &RedisKey, count: usize) -> Option<Vec<(Bytes, f64)>>;
+
+    /// Number of members of a set, hash or sorted set, 0 if there is no such key
+    fn collection_len(&self, key: &RedisKey) -> usize;
+
+    /// Every member of a set, hash or sorted set, in RDB form
+    fn members(&self, key: &RedisKey) -> Option<RdbValue>;
+
+    /// The type of a live key
+    fn key_type(&self, key: &RedisKey) -> Option<KeyType>;
+
+    /// Whether `key` holds a live string
+    fn holds_string(&self, key: &RedisKey) -> bool {
+        self.key_type(key) == Some(KeyType::String)
+    }
+
+    /// Whether a live key of any type exists
+    fn exists(&self, key: &RedisKey) -> bool {
+        self.key_type(key).is_some()
+    }
+
+    /// Remove a key of any type, returning whether a live key was removed. With `lazy`, a large
+    /// value is dropped in the background.
+    fn delete(&self, key: &RedisKey, lazy: bool) -> bool;
+
+    /// Remove a key of any type if it has expired by `now`, returning whether it was removed
+    fn delete_expired(&self, key: &RedisKey, now: u64) -> bool;
+
+    /// Remove a key to make room, as [`delete`](Self::delete) does, counting it as evicted
+    fn evict(&self, key: &RedisKey, lazy: bool) -> bool;
+
+    /// Set the expiration of an existing key of any type, returning whether the key exists
+    fn expire_at(&self, key: &RedisKey, at: u64) -> bool;
+
+    /// When a key of any type expires
+    fn get_key_expiration(&self, key: &RedisKey) -> Option<u64>;
+
+    /// Remove every key, e.g. before loading a dataset sent by a master. With `lazy`, the old
+    /// contents are dropped in the background.
+    fn flush(&self, lazy: bool);
+
+    /// Number of keys of every type, including expired keys not yet removed
+    fn len(&self) -> usize;
+
+    /// Number of keys with an expiration, including expired keys not yet removed
+    fn expires(&self) -> usize;
+
+    /// Estimated average milliseconds left to live of keys with an expiration, as the active
+    /// expire cycle last left it
+    fn avg_ttl(&self) -> u64;
+
+    fn set_avg_ttl(&self, ms: u64);
+
+    /// Every live key of any type, in no particular order
+    fn keys(&self) -> Vec<RedisKey>;
+
+    /// Up to `count` random string keys, possibly with repeats
+    fn sample_strings(&self, count: usize) -> Vec<RedisKey>;
+
+    /// Up to `count` random list keys, possibly with repeats
+    fn sample_lists(&self, count: usize) -> Vec<RedisKey>;
+
+    /// Up to `count` random set, hash and sorted set keys, possibly with repeats
+    fn sample_collections(&self, count: usize) -> Vec<RedisKey>;
+
+    /// Up to `count` random keys of any type, possibly with repeats, each type drawn in
+    /// proportion to how many keys it has
+    fn sample_keys(&self, count: usize) -> Vec<RedisKey>;
+
+    /// Begin a snapshot, or `None` if one is already in progress
+    fn snapshot(&self) -> Option<Box<dyn Snapshot>>;
+
+    /// How reads found keys and how keys went away since startup
+    fn keyspace_stats(&self) -> KeyspaceStats;
+
+    /// Approximate bytes used by the dataset
+    fn used_memory(&self) -> usize;
+
+    /// Highest approximate bytes used by the dataset since startup
+    fn used_memory_peak(&self) -> usize;
+
+    /// Approximate bytes used by a key of any type and its value, as MEMORY USAGE reports
+    fn memory_usage(&self, key: &RedisKey) -> Option<usize>;
+
+    /// Elements a key's value holds, 1 for a string and 0 for a missing key, as a measure of the
+    /// work dropping it takes
+    fn free_effort(&self, key: &RedisKey) -> usize;
+
+    /// Bytes a list holds allocated for its elements but unused, and its size
+    fn list_slack(&self, key: &RedisKey) -> Option<(usize, usize)>;
+
+    /// Reallocate a list's elements to fit, returning the bytes reclaimed
+    fn compact_list(&self, key: &RedisKey) -> usize;
+
+    /// Shrink the tables keys are held in that are mostly empty, returning the bytes reclaimed
+    fn compact_tables(&self) -> usize;
+
+    /// How a live key's value is stored, as OBJECT ENCODING names it
+    fn encoding(&self, key: &RedisKey) -> Option<&'static str>;
+
+    /// Approximate milliseconds since a key of any type was last accessed, without touching it
+    fn idle_ms(&self, key: &RedisKey) -> Option<u64>;
+
+    /// Logarithmic access frequency counter of a key of any type, without touching it
+    fn frequency(&self, key: &RedisKey) -> Option<u8>;
+
+    /// The time expirations are judged by
+    fn clock(&self) -> &dyn Clock;
+
+    /// Best eviction candidates seen while sampling for earlier evictions
+    fn eviction_pool(&self) -> MutexGuard<'_, EvictionPool>;
+}
+
+/// A consistent point-in-time view of a [`Storage`], for saving or a full resync.
+///
+/// Only one snapshot can exist at a time; dropping it lets the next one begin.
+pub(crate) trait Snapshot: Send {
+    /// Collect every live key as of the snapshot, in RDB form
+    fn entries(&self) -> Vec<RdbEntry>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::server::{config::Config, types::Database, Redis};
+
+    #[tokio::test]
+    async fn serves_the_storage_it_is_given() {
+        let db = Arc::new(Database::default());
+        db.set_key(&"preloaded".into(), Value::new("yes".into(), None));
+        let config = Config {
+            port: 0,
+            bind: vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+            dir: std::env::temp_dir(),
+            dbfilename: "storage-test-missing.rdb".into(),
+            ..Config::default()
+        };
+        let mut redis = Redis::from_parts(config, db.clone(), Default::default())
+            .await
+            .unwrap();
+        redis.signals = false;
+        redis.replication.wait_loaded().await;
+        let addr = redis.local_addr().unwrap();
+        let shutdown = redis.shutdown_handle();
+        let server = tokio::spawn(redis.run());
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"*2\r\n$3\r\nGET\r\n$9\r\npreloaded\r\n")
+            .await
+            .unwrap();
+        let mut reply = [0; 9];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"$3\r\nyes\r\n");
+
+        shutdown.shutdown();
+        server.await.unwrap().unwrap();
+    }
+}
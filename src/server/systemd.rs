@@ -0,0 +1,156 @@
+//! Notifications to systemd for `Type=notify` services, as Redis sends when `supervised
+//! systemd`: READY=1 once the dataset is loaded and, on a replica, synchronized with the master,
+//! STOPPING=1 when shutting down, and WATCHDOG=1 pings if the unit sets `WatchdogSec`. Nothing
+//! is sent unless systemd passed a `NOTIFY_SOCKET`.
+
+use std::{
+    io,
+    os::unix::net::{SocketAddr, UnixDatagram},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    replication::ReplicationState,
+    server::{
+        health::{self, Health},
+        tasks,
+    },
+};
+
+/// How often readiness is checked until it is reached
+const READY_POLL: Duration = Duration::from_millis(100);
+
+/// Where notifications are sent
+pub(crate) struct Notifier {
+    addr: SocketAddr,
+}
+
+impl Notifier {
+    /// The notifier for the socket systemd passed in `NOTIFY_SOCKET`, if any
+    pub(crate) fn from_env() -> Option<Self> {
+        let socket = std::env::var("NOTIFY_SOCKET").ok()?;
+        match Self::new(&socket) {
+            Ok(notifier) => Some(notifier),
+            Err(e) => {
+                tracing::warn!("Not notifying systemd at {socket}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Notify the socket at `path`, in the abstract namespace if it starts with `@`
+    fn new(path: &str) -> io::Result<Self> {
+        let addr = match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "abstract sockets are Linux only",
+                ));
+            }
+            None => SocketAddr::from_pathname(path)?,
+        };
+        Ok(Self { addr })
+    }
+
+    /// Send `state`, newline separated assignments such as `READY=1`
+    pub(crate) fn notify(&self, state: &str) {
+        let sent = UnixDatagram::unbound()
+            .and_then(|socket| socket.send_to_addr(state.as_bytes(), &self.addr));
+        if let Err(e) = sent {
+            tracing::warn!("Failed to notify systemd: {e}");
+        }
+    }
+}
+
+/// How often to ping the watchdog for the `WATCHDOG_USEC` and `WATCHDOG_PID` systemd set: twice
+/// per timeout, as sd_watchdog_enabled advises, unless the watchdog is meant for another process
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>) -> Option<Duration> {
+    if let Some(pid) = pid
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok().filter(|&usec| usec > 0)?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// Tell systemd the server is ready once its dataset is, and keep its watchdog fed
+pub(crate) fn start(replication: Arc<ReplicationState>) {
+    let Some(notifier) = Notifier::from_env().map(Arc::new) else {
+        return;
+    };
+    let watchdog = watchdog_interval(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+    );
+    if let Some(interval) = watchdog {
+        let notifier = notifier.clone();
+        tasks::spawn("systemd-watchdog", None, async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                notifier.notify("WATCHDOG=1");
+            }
+        });
+    }
+    tasks::spawn("systemd-ready", None, async move {
+        // a replica is ready once synchronized, which may take a while
+        while health::status(&replication) != Health::Ready {
+            tokio::time::sleep(READY_POLL).await;
+        }
+        notifier.notify("READY=1\nSTATUS=Ready to accept connections");
+        tracing::info!("Notified systemd of readiness");
+    });
+}
+
+/// Tell systemd the server is shutting down
+pub(crate) fn stopping() {
+    if let Some(notifier) = Notifier::from_env() {
+        notifier.notify("STOPPING=1");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_to_the_notify_socket() {
+        let dir = std::env::temp_dir().join(format!("systemd-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify");
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        Notifier::new(path.to_str().unwrap())
+            .unwrap()
+            .notify("READY=1");
+        let mut buf = [0; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pings_the_watchdog_twice_per_timeout() {
+        let pid = std::process::id().to_string();
+        assert_eq!(
+            watchdog_interval(Some("10000000"), Some(&pid)),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            watchdog_interval(Some("10000000"), None),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(watchdog_interval(Some("10000000"), Some("1")), None);
+        assert_eq!(watchdog_interval(Some("0"), None), None);
+        assert_eq!(watchdog_interval(None, None), None);
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use tokio::task::{JoinError, JoinHandle, JoinSet};
+
+/// Polls taking longer than this hold up every other task on their worker thread
+const SLOW_POLL: Duration = Duration::from_millis(10);
+
+/// Wait before restarting a supervised task that failed, so one failing over and over doesn't
+/// spin
+const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// Statistics of the tasks of each kind, e.g. every `connection`
+static KINDS: LazyLock<DashMap<&'static str, Arc<TaskStats>>> = LazyLock::new(DashMap::new);
+
+#[derive(Debug, Default)]
+struct TaskStats {
+    spawned: AtomicU64,
+    finished: AtomicU64,
+    polls: AtomicU64,
+    poll_usec: AtomicU64,
+    slow_polls: AtomicU64,
+    max_poll_usec: AtomicU64,
+    restarts: AtomicU64,
+}
+
+/// A task's future, timing each of its polls
+struct Instrumented<F> {
+    future: Pin<Box<F>>,
+    stats: Arc<TaskStats>,
+}
+
+impl<F: Future> Future for Instrumented<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let started = Instant::now();
+        let poll = self.future.as_mut().poll(cx);
+        let elapsed = started.elapsed();
+        let usec = elapsed.as_micros() as u64;
+        let stats = &self.stats;
+        stats.polls.fetch_add(1, Ordering::Relaxed);
+        stats.poll_usec.fetch_add(usec, Ordering::Relaxed);
+        stats.max_poll_usec.fetch_max(usec, Ordering::Relaxed);
+        if elapsed > SLOW_POLL {
+            stats.slow_polls.fetch_add(1, Ordering::Relaxed);
+        }
+        poll
+    }
+}
+
+impl<F> Drop for Instrumented<F> {
+    fn drop(&mut self) {
+        // also counts tasks aborted before completing
+        self.stats.finished.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn instrument<F: Future>(kind: &'static str, future: F) -> Instrumented<F> {
+    let stats = KINDS.entry(kind).or_default().clone();
+    stats.spawned.fetch_add(1, Ordering::Relaxed);
+    Instrumented {
+        future: Box::pin(future),
+        stats,
+    }
+}
+
+/// Name of a task as shown by tokio-console, `kind-id` for one of many
+#[cfg(any(all(tokio_unstable, feature = "console"), test))]
+fn name(kind: &'static str, id: Option<u64>) -> String {
+    match id {
+        Some(id) => format!("{kind}-{id}"),
+        None => kind.to_string(),
+    }
+}
+
+/// Spawn `future` as a task of `kind`, counted in `INFO tasks`. Builds with the `console`
+/// feature and `--cfg tokio_unstable` also name it, for tokio-console.
+pub(crate) fn spawn<F>(kind: &'static str, id: Option<u64>, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future = instrument(kind, future);
+    #[cfg(all(tokio_unstable, feature = "console"))]
+    return tokio::task::Builder::new()
+        .name(&name(kind, id))
+        .spawn(future)
+        .expect("failed to spawn a task");
+    #[cfg(not(all(tokio_unstable, feature = "console")))]
+    {
+        // only named for tokio-console
+        let _ = id;
+        tokio::spawn(future)
+    }
+}
+
+/// Spawn the task of `kind` that `start` returns, and start it again whenever it panics, for
+/// background work the server can't do without. `start` rebuilds whatever state the task needs
+/// from what outlives it, as the failed task's own is lost. Restarts are counted in `INFO tasks`.
+pub(crate) fn supervise<F, Fut>(kind: &'static str, mut start: F) -> JoinHandle<()>
+where
+    F: FnMut() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            match spawn(kind, None, start()).await {
+                Ok(()) => return,
+                Err(e) if e.is_cancelled() => return,
+                Err(e) => {
+                    tracing::error!("Background task {kind} failed, restarting it: {e}");
+                    let stats = KINDS.entry(kind).or_default().clone();
+                    stats.restarts.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(RESTART_DELAY).await;
+                }
+            }
+        }
+    })
+}
+
+/// [`spawn`], onto `set`
+pub(crate) fn spawn_in<F>(
+    set: &mut JoinSet<F::Output>,
+    kind: &'static str,
+    id: Option<u64>,
+    future: F,
+) where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future = instrument(kind, future);
+    #[cfg(all(tokio_unstable, feature = "console"))]
+    set.build_task()
+        .name(&name(kind, id))
+        .spawn(future)
+        .expect("failed to spawn a task");
+    #[cfg(not(all(tokio_unstable, feature = "console")))]
+    {
+        let _ = id;
+        set.spawn(future);
+    }
+}
+
+/// Blocking work of some kind running, counted as a task polled once for as long as it runs
+struct Running {
+    stats: Arc<TaskStats>,
+    started: Instant,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let usec = self.started.elapsed().as_micros() as u64;
+        let stats = &self.stats;
+        stats.polls.fetch_add(1, Ordering::Relaxed);
+        stats.poll_usec.fetch_add(usec, Ordering::Relaxed);
+        stats.max_poll_usec.fetch_max(usec, Ordering::Relaxed);
+        // long runs are the point here, they hold up no other task
+        stats.finished.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Run CPU-heavy `work` on the blocking pool rather than on the worker thread it was asked for
+/// on, so the connections sharing that thread keep being served. Counted in `INFO tasks` as a
+/// task of `kind`. `work` should own what it reads, e.g. a [`Snapshot`](super::storage::Snapshot),
+/// as writes carry on while it runs.
+pub(crate) async fn offload<F, T>(kind: &'static str, work: F) -> Result<T, JoinError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let stats = KINDS.entry(kind).or_default().clone();
+    stats.spawned.fetch_add(1, Ordering::Relaxed);
+    tokio::task::spawn_blocking(move || {
+        let _running = Running {
+            stats,
+            started: Instant::now(),
+        };
+        work()
+    })
+    .await
+}
+
+/// [`offload`] `work` if it is `heavy`, otherwise run it here, sparing light work the handover
+pub(crate) async fn offload_if<F, T>(
+    kind: &'static str,
+    heavy: bool,
+    work: F,
+) -> Result<T, JoinError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match heavy {
+        true => offload(kind, work).await,
+        false => Ok(work()),
+    }
+}
+
+/// The `INFO tasks` section
+pub(crate) fn info() -> String {
+    let mut kinds: Vec<_> = KINDS
+        .iter()
+        .map(|entry| (*entry.key(), entry.value().clone()))
+        .collect();
+    kinds.sort_by_key(|(kind, _)| *kind);
+    let mut out = String::from("# Tasks\r\n");
+    for (kind, stats) in kinds {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let spawned = load(&stats.spawned);
+        let _ = write!(
+            out,
+            "task_{kind}:spawned={spawned},alive={},polls={},poll_usec={},slow_polls={},\
+             max_poll_usec={},restarts={}\r\n",
+            spawned.saturating_sub(load(&stats.finished)),
+            load(&stats.polls),
+            load(&stats.poll_usec),
+            load(&stats.slow_polls),
+            load(&stats.max_poll_usec),
+            load(&stats.restarts),
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn counts_tasks_and_polls() {
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = spawn("tasks-test", Some(1), async move {
+            let _ = rx.await;
+        });
+        tokio::task::yield_now().await;
+        assert!(info().contains("task_tasks-test:spawned=1,alive=1,"));
+        tx.send(()).unwrap();
+        task.await.unwrap();
+        let info = info();
+        assert!(info.contains("task_tasks-test:spawned=1,alive=0,"));
+        assert!(!info.contains("task_tasks-test:spawned=1,alive=0,polls=0,"));
+        assert_eq!(name("connection", Some(7)), "connection-7");
+    }
+
+    #[tokio::test]
+    async fn restarts_failed_tasks() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut starts = 0;
+        supervise("supervised-test", move || {
+            starts += 1;
+            let (tx, starts) = (tx.clone(), starts);
+            async move {
+                tx.send(starts).unwrap();
+                if starts < 3 {
+                    panic!("boom");
+                }
+            }
+        });
+        for expected in 1..=3 {
+            assert_eq!(rx.recv().await, Some(expected));
+        }
+        // done once it finishes without failing
+        assert_eq!(rx.recv().await, None);
+        let info = info();
+        let line = info
+            .lines()
+            .find(|line| line.starts_with("task_supervised-test:"))
+            .unwrap();
+        assert!(line.starts_with("task_supervised-test:spawned=3,alive=0,"));
+        assert!(line.ends_with(",restarts=2"));
+    }
+
+    #[tokio::test]
+    async fn counts_offloaded_work() {
+        let sum = offload("offload-test", || (1..=100u64).sum::<u64>()).await;
+        assert_eq!(sum.unwrap(), 5050);
+        assert!(info().contains("task_offload-test:spawned=1,alive=0,polls=1,"));
+        assert!(offload("offload-test", || panic!("boom")).await.is_err());
+        assert!(info().contains("task_offload-test:spawned=2,alive=0,polls=2,"));
+        // light work stays where it is
+        assert_eq!(offload_if("offload-test", false, || 7).await.unwrap(), 7);
+        assert!(info().contains("task_offload-test:spawned=2,"));
+    }
+}
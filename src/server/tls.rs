@@ -0,0 +1,61 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use tokio::net::TcpStream;
+use tokio_rustls::{
+    rustls::{
+        self,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+
+use crate::server::config::Config;
+
+/// Time a client has to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An acceptor serving the certificate chain and key the config names, if it enables TLS
+pub(crate) fn acceptor(config: &Config) -> Result<Option<TlsAcceptor>> {
+    if config.tls_port == 0 {
+        return Ok(None);
+    }
+    let (Some(cert_file), Some(key_file)) = (&config.tls_cert_file, &config.tls_key_file) else {
+        return Err(anyhow::anyhow!(
+            "tls-port needs tls-cert-file and tls-key-file"
+        ));
+    };
+    let certs = load_certs(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .with_context(|| format!("No private key in {key_file:?}"))?;
+    // rustls is built with ring alone, and no process-wide default to pick it for us
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let server = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("The TLS certificate doesn't match its key")?;
+    Ok(Some(TlsAcceptor::from(Arc::new(server))))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .with_context(|| format!("Can't read the certificates in {path:?}"))?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No certificate in {path:?}"));
+    }
+    Ok(certs)
+}
+
+/// Run the server side of the handshake on an accepted connection
+pub(crate) async fn handshake(
+    acceptor: TlsAcceptor,
+    stream: TcpStream,
+) -> Result<TlsStream<TcpStream>> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("Timed out")?
+        .context("Handshake failed")
+}
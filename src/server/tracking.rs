@@ -0,0 +1,238 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::LazyLock,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use dashmap::DashMap;
+
+use crate::{error::RedisError, resp::RedisValue, server::clients};
+
+/// Channel redirected invalidations are sent on, as Redis does for RESP2 clients
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// Options of `CLIENT TRACKING ON`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct TrackingOptions {
+    /// Client to send invalidations to instead of the tracking client itself
+    pub(crate) redirect: Option<u64>,
+    /// Broadcast mode: invalidate every key under `prefixes` rather than only keys read
+    pub(crate) bcast: bool,
+    pub(crate) prefixes: Vec<Bytes>,
+    /// Only track keys read right after `CLIENT CACHING yes`
+    pub(crate) optin: bool,
+    /// Track keys unless read right after `CLIENT CACHING no`
+    pub(crate) optout: bool,
+    /// Don't invalidate keys for the client that modified them
+    pub(crate) noloop: bool,
+}
+
+/// A client with tracking turned on
+struct Tracker {
+    options: TrackingOptions,
+    /// Invalidations can only be pushed to RESP3 clients, RESP2 ones need a redirect
+    resp3: bool,
+}
+
+/// Clients with tracking turned on, by ID
+static TRACKERS: LazyLock<DashMap<u64, Tracker>> = LazyLock::new(DashMap::new);
+
+/// Clients that read each key, for those tracking in the default mode. Entries are dropped once
+/// invalidated, like Redis' tracking table.
+static KEYS: LazyLock<DashMap<Bytes, HashSet<u64>>> = LazyLock::new(DashMap::new);
+
+/// Turn tracking on for client `id`, or change its options
+pub(crate) fn enable(id: u64, options: TrackingOptions, resp3: bool) -> Result<()> {
+    if !options.bcast && !options.prefixes.is_empty() {
+        return Err(RedisError::other("PREFIX option requires BCAST mode to be enabled").into());
+    }
+    if options.optin && options.optout {
+        return Err(RedisError::other(
+            "You can't use both OPTIN and OPTOUT in the same CLIENT TRACKING command",
+        )
+        .into());
+    }
+    if options.bcast && (options.optin || options.optout) {
+        return Err(RedisError::other("OPTIN and OPTOUT are not compatible with BCAST").into());
+    }
+    if let Some(redirect) = options.redirect
+        && !clients::is_registered(redirect)
+    {
+        return Err(RedisError::other("The client ID you want redirect to does not exist").into());
+    }
+    TRACKERS.insert(id, Tracker { options, resp3 });
+    Ok(())
+}
+
+/// Turn tracking off for client `id`, also when it disconnects. Keys it read are forgotten
+/// lazily, as they are invalidated.
+pub(crate) fn disable(id: u64) {
+    TRACKERS.remove(&id);
+}
+
+/// Keep track of the protocol client `id` speaks, after a HELLO
+pub(crate) fn set_resp3(id: u64, resp3: bool) {
+    if let Some(mut tracker) = TRACKERS.get_mut(&id) {
+        tracker.resp3 = resp3;
+    }
+}
+
+/// Tracking options of client `id`, `None` if it isn't tracking
+pub(crate) fn options(id: u64) -> Option<TrackingOptions> {
+    TRACKERS.get(&id).map(|tracker| tracker.options.clone())
+}
+
+/// Remember that client `id` read `keys`, if it tracks them. `caching` is what the client asked
+/// for with `CLIENT CACHING` just before, if anything.
+pub(crate) fn remember<'a>(
+    id: u64,
+    keys: impl IntoIterator<Item = &'a Bytes>,
+    caching: Option<bool>,
+) {
+    let Some(tracker) = TRACKERS.get(&id) else {
+        return;
+    };
+    let options = &tracker.options;
+    let tracked = !options.bcast
+        && match (options.optin, options.optout) {
+            (true, _) => caching == Some(true),
+            (_, true) => caching != Some(false),
+            _ => true,
+        };
+    if !tracked {
+        return;
+    }
+    for key in keys {
+        KEYS.entry(key.clone()).or_default().insert(id);
+    }
+}
+
+/// Tell clients that cached any of `keys` they changed, `by` the client that changed them if
+/// any
+pub(crate) fn invalidate<'a>(keys: impl IntoIterator<Item = &'a Bytes>, by: Option<u64>) {
+    if TRACKERS.is_empty() {
+        return;
+    }
+    let mut invalidated: HashMap<u64, Vec<Bytes>> = HashMap::new();
+    for key in keys {
+        if let Some((_, readers)) = KEYS.remove(key) {
+            for id in readers {
+                invalidated.entry(id).or_default().push(key.clone());
+            }
+        }
+        for tracker in TRACKERS.iter() {
+            let options = &tracker.options;
+            if options.bcast
+                && (options.prefixes.is_empty()
+                    || options
+                        .prefixes
+                        .iter()
+                        .any(|prefix| key.starts_with(prefix)))
+            {
+                invalidated
+                    .entry(*tracker.key())
+                    .or_default()
+                    .push(key.clone());
+            }
+        }
+    }
+    for (id, keys) in invalidated {
+        let keys = keys.into_iter().map(RedisValue::BulkString).collect();
+        notify(id, RedisValue::Array(keys), by);
+    }
+}
+
+/// Tell every tracking client to drop its whole cache, after a flush
+pub(crate) fn invalidate_all(by: Option<u64>) {
+    KEYS.clear();
+    let ids: Vec<u64> = TRACKERS.iter().map(|tracker| *tracker.key()).collect();
+    for id in ids {
+        notify(id, RedisValue::Null, by);
+    }
+}
+
+/// Send an invalidation to client `id`, or where it redirects them
+fn notify(id: u64, keys: RedisValue, by: Option<u64>) {
+    let Some(tracker) = TRACKERS.get(&id) else {
+        return;
+    };
+    if tracker.options.noloop && by == Some(id) {
+        return;
+    }
+    let bulk = |s: &str| RedisValue::BulkString(Bytes::copy_from_slice(s.as_bytes()));
+    match tracker.options.redirect {
+        Some(redirect) => {
+            let message = RedisValue::Push(vec![bulk("message"), bulk(INVALIDATE_CHANNEL), keys]);
+            if !clients::push(redirect, message) && tracker.resp3 {
+                clients::push(
+                    id,
+                    RedisValue::Push(vec![
+                        bulk("tracking-redir-broken"),
+                        RedisValue::Integer(redirect as i64),
+                    ]),
+                );
+            }
+        }
+        None if tracker.resp3 => {
+            clients::push(id, RedisValue::Push(vec![bulk("invalidate"), keys]));
+        }
+        // a RESP2 client can't be sent invalidations on its own connection
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_reads_until_invalidated() {
+        // the tables are process wide, so use IDs no connection has
+        let (optin, optout) = (u64::MAX - 1, u64::MAX - 2);
+        let mode = |optin, optout| TrackingOptions {
+            optin,
+            optout,
+            ..Default::default()
+        };
+        enable(optin, mode(true, false), true).unwrap();
+        enable(optout, mode(false, true), true).unwrap();
+        let key = Bytes::from("tracking-test-key");
+        let readers = || KEYS.get(&key).map(|ids| ids.clone()).unwrap_or_default();
+
+        remember(optin, [&key], None);
+        remember(optout, [&key], Some(false));
+        assert!(readers().is_empty());
+        remember(optin, [&key], Some(true));
+        remember(optout, [&key], None);
+        assert_eq!(readers(), HashSet::from([optin, optout]));
+
+        invalidate([&key], None);
+        assert!(readers().is_empty());
+        disable(optin);
+        disable(optout);
+        assert_eq!(options(optin), None);
+    }
+
+    #[test]
+    fn rejects_conflicting_options() {
+        let id = u64::MAX - 3;
+        let prefix_only = TrackingOptions {
+            prefixes: vec![Bytes::from("a")],
+            ..Default::default()
+        };
+        assert!(enable(id, prefix_only, true).is_err());
+        let both = TrackingOptions {
+            optin: true,
+            optout: true,
+            ..Default::default()
+        };
+        assert!(enable(id, both, true).is_err());
+        let gone = TrackingOptions {
+            redirect: Some(u64::MAX),
+            ..Default::default()
+        };
+        assert!(enable(id, gone, true).is_err());
+        assert_eq!(options(id), None);
+    }
+}
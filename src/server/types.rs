@@ -1,24 +1,71 @@
-use std::{sync::Arc, time::Instant};
+use std::{
+    collections::VecDeque,
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
+    },
+};
 
 use bytes::Bytes;
-use dashmap::DashMap;
+use dashmap::{
+    mapref::{entry::Entry, one::Ref},
+    DashMap,
+};
+
+use crate::{
+    rdb::RdbValue,
+    server::{
+        clock::{Clock, SystemClock},
+        eviction::EvictionPool,
+        keyspace::KeyType,
+        lazyfree,
+        storage::{KeyspaceStats, Snapshot, Storage},
+    },
+};
+
+mod access;
+mod collection;
+mod encoding;
+mod listpack;
+mod sample;
+mod snapshot;
+
+use access::Access;
+pub(crate) use access::LfuParams;
+use collection::{Collection, Members};
+pub(crate) use collection::{ZAddFlags, ZAdded};
+use encoding::Encoded;
+use listpack::Listpack;
+pub(crate) use listpack::ListpackLimit;
+pub(crate) use sample::random;
+use snapshot::{Shadow, ShadowSnapshot};
 
 pub(crate) type RedisKey = Bytes;
 
+#[derive(Clone)]
 pub(crate) struct Value {
     /// The actual value
-    value: Bytes,
+    value: Encoded,
 
-    /// Last set time (if key was set with expirations)
-    expiration: Option<Instant>,
+    /// When the key expires, in milliseconds since the unix epoch
+    expiration: Option<u64>,
+
+    /// Last access, for LRU eviction
+    access: Access,
 }
 
 impl Value {
-    pub(crate) fn new(value: Bytes, expiration: Option<Instant>) -> Self {
-        Self { value, expiration }
+    pub(crate) fn new(value: Bytes, expiration: Option<u64>) -> Self {
+        Self {
+            value: Encoded::new(value),
+            expiration,
+            access: Access::new(),
+        }
     }
 
-    pub(crate) fn expired(&self, current: Instant) -> bool {
+    /// Whether the key has expired at `current`, a unix time in milliseconds
+    pub(crate) fn expired(&self, current: u64) -> bool {
         if let Some(expiration) = self.expiration {
             if current >= expiration {
                 // key is now expired
@@ -34,73 +81,1123 @@ impl Value {
     }
 
     pub(crate) fn get_value(&self) -> Bytes {
-        self.value.slice(..)
+        self.value.to_bytes()
+    }
+
+    /// How the value is stored, as OBJECT ENCODING names it
+    pub(crate) fn encoding(&self) -> &'static str {
+        self.value.name()
     }
 
-    pub(crate) fn get_expiration(&self) -> Option<&Instant> {
-        self.expiration.as_ref()
+    pub(crate) fn get_expiration(&self) -> Option<u64> {
+        self.expiration
     }
 }
 
-pub(crate) type ExpiryEvent = (Instant, RedisKey);
+/// A list's elements, packed into one buffer while the list is small
+#[derive(Clone)]
+enum Items {
+    Packed(Listpack),
+    /// Past the listpack limit, and never packed again
+    Full(VecDeque<Encoded>),
+}
+
+/// A list of values, when it expires and when it was last accessed
+#[derive(Clone)]
+pub(crate) struct List {
+    items: Items,
+    /// When the list expires, in milliseconds since the unix epoch
+    expiration: Option<u64>,
+    access: Access,
+
+    /// Heap bytes held by the elements of a full list, kept so its size is known without a scan
+    payload: usize,
+}
+
+impl List {
+    fn new() -> Self {
+        Self {
+            items: Items::Packed(Listpack::default()),
+            expiration: None,
+            access: Access::new(),
+            payload: 0,
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match &self.items {
+            Items::Packed(listpack) => listpack.len(),
+            Items::Full(items) => items.len(),
+        }
+    }
+
+    /// Append `values`, or push each onto the front, unpacking the list once it outgrows `limit`
+    fn extend(&mut self, values: impl Iterator<Item = Value>, front: bool, limit: ListpackLimit) {
+        for value in values {
+            match &mut self.items {
+                Items::Packed(listpack) if front => listpack.push_front(&value.get_value()),
+                Items::Packed(listpack) => listpack.push_back(&value.get_value()),
+                Items::Full(items) => {
+                    self.payload += value.value.heap_size();
+                    match front {
+                        true => items.push_front(value.value),
+                        false => items.push_back(value.value),
+                    }
+                }
+            }
+        }
+        if let Items::Packed(listpack) = &self.items
+            && !limit.fits(listpack)
+        {
+            let items: VecDeque<Encoded> = listpack
+                .iter()
+                .map(|element| Encoded::new(Bytes::copy_from_slice(element)))
+                .collect();
+            self.payload = items.iter().map(Encoded::heap_size).sum();
+            self.items = Items::Full(items);
+        }
+    }
+
+    /// Elements `start` to `stop` inclusive, negative indexes counting from the end
+    fn range(&self, start: i64, stop: i64) -> Vec<Bytes> {
+        let len = self.len() as i64;
+        let index = |i: i64| if i < 0 { (len + i).max(0) } else { i };
+        let (start, stop) = (index(start), index(stop).min(len - 1));
+        if start > stop {
+            return Vec::new();
+        }
+        let (start, count) = (start as usize, (stop - start + 1) as usize);
+        match &self.items {
+            Items::Packed(listpack) => listpack
+                .iter()
+                .skip(start)
+                .take(count)
+                .map(Bytes::copy_from_slice)
+                .collect(),
+            Items::Full(items) => items
+                .range(start..start + count)
+                .map(Encoded::to_bytes)
+                .collect(),
+        }
+    }
+
+    pub(crate) fn expired(&self, current: u64) -> bool {
+        self.expiration
+            .is_some_and(|expiration| current >= expiration)
+    }
+
+    /// Take up to `count` elements off the front, or off the back in popping order
+    fn pop(&mut self, count: usize, front: bool) -> Vec<Bytes> {
+        let count = count.min(self.len());
+        match &mut self.items {
+            Items::Packed(listpack) => (0..count)
+                .filter_map(|_| match front {
+                    true => listpack.pop_front(),
+                    false => listpack.pop_back(),
+                })
+                .collect(),
+            Items::Full(items) => {
+                let popped: Vec<Encoded> = (0..count)
+                    .filter_map(|_| match front {
+                        true => items.pop_front(),
+                        false => items.pop_back(),
+                    })
+                    .collect();
+                self.payload -= popped.iter().map(Encoded::heap_size).sum::<usize>();
+                popped.into_iter().map(Encoded::into_bytes).collect()
+            }
+        }
+    }
+
+    /// How the elements are stored, as OBJECT ENCODING names it
+    fn encoding(&self) -> &'static str {
+        match self.items {
+            Items::Packed(_) => "listpack",
+            Items::Full(_) => "quicklist",
+        }
+    }
+
+    /// Bytes allocated for the elements but unused
+    fn slack(&self) -> usize {
+        match &self.items {
+            Items::Packed(listpack) => listpack.slack(),
+            Items::Full(items) => (items.capacity() - items.len()) * size_of::<Encoded>(),
+        }
+    }
+
+    /// Reallocate the elements to fit
+    fn compact(&mut self) {
+        match &mut self.items {
+            Items::Packed(listpack) => listpack.shrink_to_fit(),
+            Items::Full(items) => items.shrink_to_fit(),
+        }
+    }
+}
+
+/// Approximate footprint of a string key and its value, including the map entry
+fn string_size(key: &RedisKey, value: &Value) -> usize {
+    size_of::<(RedisKey, Value)>() + key.len() + value.value.heap_size()
+}
+
+/// Approximate footprint of a list key and its elements, including the map entry and the slack
+/// of the element buffer
+fn list_size(key: &RedisKey, list: &List) -> usize {
+    size_of::<(RedisKey, List)>()
+        + key.len()
+        + match &list.items {
+            Items::Packed(listpack) => listpack.capacity(),
+            Items::Full(items) => items.capacity() * size_of::<Encoded>(),
+        }
+        + list.payload
+}
+
+/// Spare slots a table must have before shrinking it is worth locking each of its shards
+const TABLE_SLACK_MIN: usize = 1024;
+
+/// Shrink a table holding keys if it is less than a quarter full, returning the bytes reclaimed
+fn compact_table<V>(table: &DashMap<RedisKey, V>) -> usize {
+    let (capacity, len) = (table.capacity(), table.len());
+    if capacity <= len * 4 || capacity - len < TABLE_SLACK_MIN {
+        return 0;
+    }
+    table.shrink_to_fit();
+    capacity.saturating_sub(table.capacity()) * size_of::<(RedisKey, V)>()
+}
+
+/// A key given an expiration, at a unix time in milliseconds, or `None` once it no longer has one
+pub(crate) type ExpiryEvent = (Option<u64>, RedisKey);
 
 pub(crate) const INITIAL_CAPACITY: usize = 16;
 
+/// The in-memory [`Storage`], every key held in concurrent hash maps
 pub(crate) struct Database {
     /// Basic Key/Value store
     kv: Arc<DashMap<RedisKey, Value>>,
 
     /// List support
-    lists: Arc<DashMap<RedisKey, Vec<Value>>>,
+    lists: Arc<DashMap<RedisKey, List>>,
+
+    /// Sets, hashes and sorted sets
+    collections: Arc<DashMap<RedisKey, Collection>>,
+
+    /// Pre-write copies of keys modified while a snapshot is in progress
+    shadow: Arc<RwLock<Option<Arc<Shadow>>>>,
+
+    /// Approximate bytes used by the dataset, kept up to date on every mutation
+    used_memory: AtomicUsize,
+
+    /// Highest `used_memory` seen
+    used_memory_peak: AtomicUsize,
+
+    /// Keys with an expiration, kept up to date on every mutation
+    expires: AtomicUsize,
+
+    /// See [`Storage::avg_ttl`]
+    avg_ttl: AtomicU64,
+
+    /// See [`Storage::keyspace_stats`]
+    hits: AtomicU64,
+    misses: AtomicU64,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+
+    /// Best eviction candidates seen while sampling for earlier evictions
+    eviction_pool: Mutex<EvictionPool>,
+
+    /// How key access frequencies are counted
+    lfu: LfuParams,
+
+    /// When lists outgrow their packed form
+    list_limit: ListpackLimit,
+
+    /// What expirations are judged by
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new(
+            LfuParams::default(),
+            ListpackLimit::default(),
+            Arc::new(SystemClock),
+        )
+    }
 }
 
 impl Database {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(lfu: LfuParams, list_limit: ListpackLimit, clock: Arc<dyn Clock>) -> Self {
         Self {
             kv: Arc::new(DashMap::with_capacity(INITIAL_CAPACITY)),
             lists: Arc::new(DashMap::with_capacity(INITIAL_CAPACITY)),
+            collections: Arc::new(DashMap::with_capacity(INITIAL_CAPACITY)),
+            shadow: Arc::new(RwLock::new(None)),
+            used_memory: AtomicUsize::new(0),
+            used_memory_peak: AtomicUsize::new(0),
+            expires: AtomicUsize::new(0),
+            avg_ttl: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            evicted: AtomicU64::new(0),
+            eviction_pool: Mutex::new(EvictionPool::default()),
+            lfu,
+            list_limit,
+            clock,
+        }
+    }
+
+    /// Count a read that found a live key or not
+    fn count_read<T>(&self, found: Option<T>) -> Option<T> {
+        let counter = match found {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    fn grow(&self, bytes: usize) {
+        let used = self.used_memory.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.used_memory_peak.fetch_max(used, Ordering::Relaxed);
+    }
+
+    fn shrink(&self, bytes: usize) {
+        self.used_memory.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Account for a key's expiration changing from `before` to `after`, `None` for none
+    fn reexpire(&self, before: Option<u64>, after: Option<u64>) {
+        match (before, after) {
+            (None, Some(_)) => {
+                self.expires.fetch_add(1, Ordering::Relaxed);
+            }
+            (Some(_), None) => {
+                self.expires.fetch_sub(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    /// Account for a value changing from `before` to `after` bytes
+    fn resize(&self, before: usize, after: usize) {
+        if after >= before {
+            self.grow(after - before);
+        } else {
+            self.shrink(before - after);
+        }
+    }
+
+    /// The collection at `key`, unless it has expired
+    fn live_collection(&self, key: &RedisKey) -> Option<Ref<'_, RedisKey, Collection>> {
+        self.collections
+            .get(key)
+            .filter(|collection| !collection.expired(self.clock.now_ms()))
+    }
+
+    /// Drop the list at `key` if any, the shadow having already preserved it
+    fn remove_list(&self, key: &RedisKey) {
+        if let Some((key, old)) = self.lists.remove(key) {
+            self.shrink(list_size(&key, &old));
+            self.reexpire(old.expiration, None);
+        }
+    }
+
+    /// Drop the collection at `key` if any, the shadow having already preserved it
+    fn remove_collection(&self, key: &RedisKey) {
+        if let Some((key, old)) = self.collections.remove(key) {
+            self.shrink(old.size(&key));
+            self.reexpire(old.expiration, None);
+        }
+    }
+
+    /// Apply `f` to the members of the live collection at `key`, first creating an empty one of
+    /// type `create` if there is none and it is set. The key is deleted if `f` leaves it empty,
+    /// as collections never exist empty. `None` if there was no collection to apply `f` to.
+    fn update_collection<R>(
+        &self,
+        key: &RedisKey,
+        create: Option<KeyType>,
+        f: impl FnOnce(&mut Members) -> R,
+    ) -> Option<R> {
+        let shadow = self.shadow.read().unwrap();
+        if let Some(shadow) = shadow.as_ref() {
+            shadow.preserve_collection(&self.collections, key);
+        }
+        let mut entry = match self.collections.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                // an expired collection is already gone as far as clients can tell
+                if entry.get().expired(self.clock.now_ms()) {
+                    let kind = create?;
+                    let collection = Collection::new(kind);
+                    self.resize(entry.get().size(key), collection.size(key));
+                    self.reexpire(entry.insert(collection).expiration, None);
+                }
+                entry
+            }
+            Entry::Vacant(entry) => {
+                let collection = Collection::new(create?);
+                self.grow(collection.size(key));
+                entry.insert_entry(collection)
+            }
+        };
+        let collection = entry.get_mut();
+        collection.access.touch(self.lfu);
+        let before = collection.size(key);
+        let result = f(&mut collection.members);
+        if collection.members.len() == 0 {
+            self.shrink(before);
+            self.reexpire(entry.remove().expiration, None);
+        } else {
+            self.resize(before, collection.size(key));
+        }
+        Some(result)
+    }
+
+    /// Apply `f` to the members of the live collection at `key`, counting as an access to it
+    fn read_collection<R>(
+        &self,
+        key: &RedisKey,
+        f: impl FnOnce(&Members) -> Option<R>,
+    ) -> Option<R> {
+        let found = self.live_collection(key).and_then(|collection| {
+            collection.access.touch(self.lfu);
+            f(&collection.members)
+        });
+        self.count_read(found)
+    }
+}
+
+impl Storage for Database {
+    fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
+    fn used_memory_peak(&self) -> usize {
+        self.used_memory_peak.load(Ordering::Relaxed)
+    }
+
+    fn memory_usage(&self, key: &RedisKey) -> Option<usize> {
+        let now = self.clock.now_ms();
+        match self.kv.get(key) {
+            Some(v) if v.expired(now) => None,
+            Some(v) => Some(string_size(key, &v)),
+            None => match self.lists.get(key) {
+                Some(list) if list.expired(now) => None,
+                Some(list) => Some(list_size(key, &list)),
+                None => self
+                    .live_collection(key)
+                    .map(|collection| collection.size(key)),
+            },
         }
     }
 
-    pub(crate) fn get_key(&self, key: &RedisKey) -> Option<Bytes> {
-        self.kv.get(key).and_then(|v| {
-            if !v.expired(Instant::now()) {
+    fn free_effort(&self, key: &RedisKey) -> usize {
+        if self.kv.contains_key(key) {
+            return 1;
+        }
+        match self.lists.get(key) {
+            Some(list) => list.len(),
+            None => self
+                .collections
+                .get(key)
+                .map_or(0, |collection| collection.members.len()),
+        }
+    }
+
+    fn encoding(&self, key: &RedisKey) -> Option<&'static str> {
+        let now = self.clock.now_ms();
+        match self.kv.get(key) {
+            Some(v) if v.expired(now) => None,
+            Some(v) => Some(v.encoding()),
+            None => match self.lists.get(key) {
+                Some(list) if list.expired(now) => None,
+                Some(list) => Some(list.encoding()),
+                None => self
+                    .live_collection(key)
+                    .map(|collection| collection.encoding()),
+            },
+        }
+    }
+
+    fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    fn eviction_pool(&self) -> MutexGuard<'_, EvictionPool> {
+        self.eviction_pool.lock().unwrap()
+    }
+
+    fn len(&self) -> usize {
+        self.kv.len() + self.lists.len() + self.collections.len()
+    }
+
+    fn expires(&self) -> usize {
+        self.expires.load(Ordering::Relaxed)
+    }
+
+    fn keyspace_stats(&self) -> KeyspaceStats {
+        KeyspaceStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+        }
+    }
+
+    fn avg_ttl(&self) -> u64 {
+        self.avg_ttl.load(Ordering::Relaxed)
+    }
+
+    fn set_avg_ttl(&self, ms: u64) {
+        self.avg_ttl.store(ms, Ordering::Relaxed);
+    }
+
+    fn keys(&self) -> Vec<RedisKey> {
+        let now = self.clock.now_ms();
+        let strings = self.kv.iter().filter(|e| !e.expired(now));
+        let lists = self.lists.iter().filter(|e| !e.expired(now));
+        let collections = self.collections.iter().filter(|e| !e.expired(now));
+        strings
+            .map(|e| e.key().clone())
+            .chain(lists.map(|e| e.key().clone()))
+            .chain(collections.map(|e| e.key().clone()))
+            .collect()
+    }
+
+    fn get_key(&self, key: &RedisKey) -> Option<Bytes> {
+        self.count_read(self.kv.get(key).and_then(|v| {
+            if !v.expired(self.clock.now_ms()) {
+                v.access.touch(self.lfu);
                 Some(v.get_value())
             } else {
                 None
             }
-        })
+        }))
+    }
+
+    fn list_range(&self, key: &RedisKey, start: i64, stop: i64) -> Option<Vec<Bytes>> {
+        let list = self
+            .lists
+            .get(key)
+            .filter(|list| !list.expired(self.clock.now_ms()));
+        let list = self.count_read(list)?;
+        list.access.touch(self.lfu);
+        Some(list.range(start, stop))
+    }
+
+    fn key_type(&self, key: &RedisKey) -> Option<KeyType> {
+        let now = self.clock.now_ms();
+        if self.kv.get(key).is_some_and(|v| !v.expired(now)) {
+            Some(KeyType::String)
+        } else if self.lists.get(key).is_some_and(|list| !list.expired(now)) {
+            Some(KeyType::List)
+        } else {
+            self.live_collection(key)
+                .map(|collection| collection.members.kind())
+        }
+    }
+
+    fn idle_ms(&self, key: &RedisKey) -> Option<u64> {
+        match self.kv.get(key) {
+            Some(v) => Some(v.access.idle_ms()),
+            None => match self.lists.get(key) {
+                Some(list) => Some(list.access.idle_ms()),
+                None => self
+                    .collections
+                    .get(key)
+                    .map(|collection| collection.access.idle_ms()),
+            },
+        }
+    }
+
+    fn frequency(&self, key: &RedisKey) -> Option<u8> {
+        let now = self.clock.now_ms();
+        match self.kv.get(key) {
+            Some(v) if v.expired(now) => None,
+            Some(v) => Some(v.access.frequency(self.lfu)),
+            None => match self.lists.get(key) {
+                Some(list) if list.expired(now) => None,
+                Some(list) => Some(list.access.frequency(self.lfu)),
+                None => self
+                    .live_collection(key)
+                    .map(|collection| collection.access.frequency(self.lfu)),
+            },
+        }
+    }
+
+    fn get_key_expiration(&self, key: &RedisKey) -> Option<u64> {
+        match self.kv.get(key) {
+            Some(v) => v.get_expiration(),
+            None => match self.lists.get(key) {
+                Some(list) => list.expiration,
+                None => self
+                    .collections
+                    .get(key)
+                    .and_then(|collection| collection.expiration),
+            },
+        }
+    }
+
+    fn set_key(&self, key: &RedisKey, mut value: Value) -> Option<Value> {
+        let shadow = self.shadow.read().unwrap();
+        if let Some(shadow) = shadow.as_ref() {
+            shadow.preserve_kv(&self.kv, key);
+            shadow.preserve_list(&self.lists, key);
+            shadow.preserve_collection(&self.collections, key);
+        }
+        // a string replaces a key of any other type
+        self.remove_list(key);
+        self.remove_collection(key);
+        let size = string_size(key, &value);
+        let expiration = value.expiration;
+        let old = match self.kv.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                // an overwrite is an access to the key, so its frequency carries over
+                value.access = entry.get().access.clone();
+                value.access.touch(self.lfu);
+                Some(entry.insert(value))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+                None
+            }
+        };
+        match &old {
+            Some(old) => self.resize(string_size(key, old), size),
+            None => self.grow(size),
+        }
+        self.reexpire(old.as_ref().and_then(|old| old.expiration), expiration);
+        old
     }
 
-    pub(crate) fn get_key_expiration(&self, key: &RedisKey) -> Option<Instant> {
-        self.kv.get(key).and_then(|v| {
-            let exp = v.get_expiration()?;
-            Some(exp.clone())
-        })
+    fn delete(&self, key: &RedisKey, lazy: bool) -> bool {
+        let shadow = self.shadow.read().unwrap();
+        if let Some(shadow) = shadow.as_ref() {
+            shadow.preserve_kv(&self.kv, key);
+            shadow.preserve_list(&self.lists, key);
+            shadow.preserve_collection(&self.collections, key);
+        }
+        let string = self.kv.remove(key).is_some_and(|(key, old)| {
+            self.shrink(string_size(&key, &old));
+            self.reexpire(old.expiration, None);
+            !old.expired(self.clock.now_ms())
+        });
+        let list = self.lists.remove(key).is_some_and(|(key, old)| {
+            self.shrink(list_size(&key, &old));
+            self.reexpire(old.expiration, None);
+            let live = !old.expired(self.clock.now_ms());
+            if lazy && old.len() > lazyfree::LAZYFREE_THRESHOLD {
+                lazyfree::free(old);
+            }
+            live
+        });
+        let collection = self.collections.remove(key).is_some_and(|(key, old)| {
+            self.shrink(old.size(&key));
+            self.reexpire(old.expiration, None);
+            let live = !old.expired(self.clock.now_ms());
+            if lazy && old.members.len() > lazyfree::LAZYFREE_THRESHOLD {
+                lazyfree::free(old);
+            }
+            live
+        });
+        string || list || collection
     }
 
-    pub(crate) fn set_key(&self, key: &RedisKey, value: Value) -> Option<Value> {
-        self.kv.insert(key.clone(), value)
+    fn delete_expired(&self, key: &RedisKey, now: u64) -> bool {
+        let shadow = self.shadow.read().unwrap();
+        if let Some(shadow) = shadow.as_ref() {
+            shadow.preserve_kv(&self.kv, key);
+            shadow.preserve_list(&self.lists, key);
+            shadow.preserve_collection(&self.collections, key);
+        }
+        let removed = if let Some((key, old)) = self.kv.remove_if(key, |_, v| v.expired(now)) {
+            self.shrink(string_size(&key, &old));
+            self.reexpire(old.expiration, None);
+            true
+        } else if let Some((key, old)) = self.lists.remove_if(key, |_, list| list.expired(now)) {
+            self.shrink(list_size(&key, &old));
+            self.reexpire(old.expiration, None);
+            true
+        } else if let Some((key, old)) = self
+            .collections
+            .remove_if(key, |_, collection| collection.expired(now))
+        {
+            self.shrink(old.size(&key));
+            self.reexpire(old.expiration, None);
+            true
+        } else {
+            false
+        };
+        if removed {
+            self.expired.fetch_add(1, Ordering::Relaxed);
+        }
+        removed
     }
 
-    pub(crate) fn remove_key(&self, key: &RedisKey) {
-        self.kv.remove(key);
+    fn evict(&self, key: &RedisKey, lazy: bool) -> bool {
+        let evicted = self.delete(key, lazy);
+        if evicted {
+            self.evicted.fetch_add(1, Ordering::Relaxed);
+        }
+        evicted
     }
 
-    pub(crate) fn rpush(&self, key: &RedisKey, value: impl Iterator<Item = Value>) -> usize {
-        let mut list = self
-            .lists
-            .entry(key.clone())
-            .or_insert(Vec::with_capacity(INITIAL_CAPACITY));
-        list.extend(value);
+    fn expire_at(&self, key: &RedisKey, at: u64) -> bool {
+        let shadow = self.shadow.read().unwrap();
+        if let Some(shadow) = shadow.as_ref() {
+            shadow.preserve_kv(&self.kv, key);
+            shadow.preserve_list(&self.lists, key);
+            shadow.preserve_collection(&self.collections, key);
+        }
+        let now = self.clock.now_ms();
+        if let Some(mut v) = self.kv.get_mut(key) {
+            if v.expired(now) {
+                return false;
+            }
+            self.reexpire(v.expiration.replace(at), Some(at));
+            v.access.touch(self.lfu);
+            return true;
+        }
+        if let Some(mut list) = self.lists.get_mut(key) {
+            if list.expired(now) {
+                return false;
+            }
+            self.reexpire(list.expiration.replace(at), Some(at));
+            list.access.touch(self.lfu);
+            return true;
+        }
+        match self.collections.get_mut(key) {
+            Some(mut collection) if !collection.expired(now) => {
+                self.reexpire(collection.expiration.replace(at), Some(at));
+                collection.access.touch(self.lfu);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn flush(&self, lazy: bool) {
+        let shadow = self.shadow.read().unwrap();
+        if let Some(shadow) = shadow.as_ref() {
+            let keys: Vec<RedisKey> = self.kv.iter().map(|e| e.key().clone()).collect();
+            for key in keys {
+                shadow.preserve_kv(&self.kv, &key);
+            }
+            let keys: Vec<RedisKey> = self.lists.iter().map(|e| e.key().clone()).collect();
+            for key in keys {
+                shadow.preserve_list(&self.lists, &key);
+            }
+            let keys: Vec<RedisKey> = self.collections.iter().map(|e| e.key().clone()).collect();
+            for key in keys {
+                shadow.preserve_collection(&self.collections, &key);
+            }
+        }
+        if lazy {
+            // swap every shard's table for an empty one, so only the handover happens here
+            for shard in self.kv.shards() {
+                lazyfree::free(std::mem::take(&mut *shard.write()));
+            }
+            for shard in self.lists.shards() {
+                lazyfree::free(std::mem::take(&mut *shard.write()));
+            }
+            for shard in self.collections.shards() {
+                lazyfree::free(std::mem::take(&mut *shard.write()));
+            }
+        } else {
+            self.kv.clear();
+            self.lists.clear();
+            self.collections.clear();
+        }
+        self.used_memory.store(0, Ordering::Relaxed);
+        self.expires.store(0, Ordering::Relaxed);
+    }
+
+    fn pop(&self, key: &RedisKey, count: usize, front: bool) -> Option<Vec<Bytes>> {
+        let shadow = self.shadow.read().unwrap();
+        if let Some(shadow) = shadow.as_ref() {
+            shadow.preserve_list(&self.lists, key);
+        }
+        let Entry::Occupied(mut entry) = self.lists.entry(key.clone()) else {
+            return None;
+        };
+        if entry.get().expired(self.clock.now_ms()) {
+            return None;
+        }
+        let list = entry.get_mut();
+        list.access.touch(self.lfu);
+        let before = list_size(key, list);
+        let popped = list.pop(count, front);
+        if list.len() == 0 {
+            self.shrink(before);
+            self.reexpire(entry.remove().expiration, None);
+        } else {
+            self.resize(before, list_size(key, list));
+        }
+        Some(popped)
+    }
+
+    fn push(&self, key: &RedisKey, values: Vec<Value>, front: bool) -> usize {
+        let shadow = self.shadow.read().unwrap();
+        if let Some(shadow) = shadow.as_ref() {
+            shadow.preserve_list(&self.lists, key);
+        }
+        let mut list = match self.lists.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                // an expired list is already gone as far as clients can tell, so start afresh
+                if entry.get().expired(self.clock.now_ms()) {
+                    let list = List::new();
+                    self.resize(list_size(key, entry.get()), list_size(key, &list));
+                    self.reexpire(entry.insert(list).expiration, None);
+                }
+                entry.into_ref()
+            }
+            Entry::Vacant(entry) => {
+                let list = List::new();
+                self.grow(list_size(key, &list));
+                entry.insert(list)
+            }
+        };
+        list.access.touch(self.lfu);
+        let before = list_size(key, &list);
+        list.extend(values.into_iter(), front, self.list_limit);
+        self.resize(before, list_size(key, &list));
         list.len()
     }
 
-    pub(crate) fn kv(&self) -> Arc<DashMap<RedisKey, Value>> {
-        self.kv.clone()
+    fn set_add(&self, key: &RedisKey, members: Vec<Bytes>) -> usize {
+        self.update_collection(key, Some(KeyType::Set), |set| set.set_add(members))
+            .unwrap_or(0)
+    }
+
+    fn set_remove(&self, key: &RedisKey, members: Vec<Bytes>) -> usize {
+        self.update_collection(key, None, |set| set.set_remove(&members))
+            .unwrap_or(0)
+    }
+
+    fn set_pop(&self, key: &RedisKey, count: usize) -> Option<Vec<Bytes>> {
+        self.update_collection(key, None, |set| set.set_pop(count))
+    }
+
+    fn hash_set(&self, key: &RedisKey, pairs: Vec<(Bytes, Bytes)>) -> usize {
+        self.update_collection(key, Some(KeyType::Hash), |hash| hash.hash_set(pairs))
+            .unwrap_or(0)
     }
 
-    pub(crate) fn lists(&self) -> Arc<DashMap<RedisKey, Vec<Value>>> {
-        self.lists.clone()
+    fn hash_get(&self, key: &RedisKey, field: Bytes) -> Option<Bytes> {
+        self.read_collection(key, |hash| hash.hash_get(&field))
+    }
+
+    fn zadd(&self, key: &RedisKey, pairs: Vec<(f64, Bytes)>, flags: ZAddFlags) -> Option<ZAdded> {
+        // XX only updates, so never creates the key
+        let create = (!flags.xx).then_some(KeyType::SortedSet);
+        self.update_collection(key, create, |zset| zset.zadd(pairs, flags))
+            .unwrap_or(Some(ZAdded::default()))
+    }
+
+    fn zscore(&self, key: &RedisKey, member: Bytes) -> Option<f64> {
+        self.read_collection(key, |zset| zset.zscore(&member))
+    }
+
+    fn zpop_min(&self, key: &RedisKey, count: usize) -> Option<Vec<(Bytes, f64)>> {
+        self.update_collection(key, None, |zset| zset.zpop_min(count))
+    }
+
+    fn collection_len(&self, key: &RedisKey) -> usize {
+        self.live_collection(key)
+            .map_or(0, |collection| collection.members.len())
+    }
+
+    fn members(&self, key: &RedisKey) -> Option<RdbValue> {
+        self.live_collection(key)
+            .map(|collection| collection.members.to_rdb())
+    }
+
+    fn sample_strings(&self, count: usize) -> Vec<RedisKey> {
+        sample::sample(&self.kv, count)
+    }
+
+    fn sample_lists(&self, count: usize) -> Vec<RedisKey> {
+        sample::sample(&self.lists, count)
+    }
+
+    fn sample_collections(&self, count: usize) -> Vec<RedisKey> {
+        sample::sample(&self.collections, count)
+    }
+
+    fn list_slack(&self, key: &RedisKey) -> Option<(usize, usize)> {
+        let list = self.lists.get(key)?;
+        Some((list.slack(), list_size(key, &list)))
+    }
+
+    fn compact_list(&self, key: &RedisKey) -> usize {
+        let Some(mut list) = self.lists.get_mut(key) else {
+            return 0;
+        };
+        let before = list_size(key, &list);
+        list.compact();
+        let reclaimed = before - list_size(key, &list);
+        self.shrink(reclaimed);
+        reclaimed
+    }
+
+    fn compact_tables(&self) -> usize {
+        compact_table(&self.kv) + compact_table(&self.lists) + compact_table(&self.collections)
+    }
+
+    fn sample_keys(&self, count: usize) -> Vec<RedisKey> {
+        sample::sample_any(&self.kv, &self.lists, &self.collections, count)
+    }
+
+    fn snapshot(&self) -> Option<Box<dyn Snapshot>> {
+        ShadowSnapshot::begin(self).map(|snapshot| Box::new(snapshot) as Box<dyn Snapshot>)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_and_expire_at() {
+        let db = Database::default();
+        db.set_key(&"a".into(), Value::new("1".into(), None));
+        db.push(&"l".into(), vec![Value::new("x".into(), None)], false);
+
+        let at = db.clock().now_ms() + 60_000;
+        assert!(db.expire_at(&"a".into(), at));
+        assert!(!db.expire_at(&"missing".into(), at));
+        assert_eq!(db.get_key_expiration(&"a".into()), Some(at));
+
+        assert!(db.delete(&"a".into(), false));
+        assert!(db.delete(&"l".into(), true));
+        assert!(!db.delete(&"a".into(), false));
+        assert_eq!(db.get_key(&"a".into()), None);
+    }
+
+    #[test]
+    fn counts_keys_with_an_expiration() {
+        let db = Database::default();
+        let now = db.clock().now_ms();
+        let (later, past) = (now + 60_000, now - 1);
+        db.set_key(&"a".into(), Value::new("1".into(), Some(later)));
+        db.set_key(&"b".into(), Value::new("1".into(), None));
+        db.push(&"l".into(), vec![Value::new("x".into(), None)], false);
+        assert_eq!(db.expires(), 1);
+
+        // setting one again, or another expiration, does not count it twice
+        assert!(db.expire_at(&"a".into(), later + 1));
+        assert!(db.expire_at(&"l".into(), later));
+        assert!(db.expire_at(&"b".into(), past));
+        assert_eq!(db.expires(), 3);
+        db.set_key(&"a".into(), Value::new("2".into(), None));
+        assert_eq!(db.expires(), 2);
+
+        assert!(db.delete_expired(&"b".into(), now));
+        db.pop(&"l".into(), 1, true);
+        assert_eq!((db.len(), db.expires()), (1, 0));
+
+        db.set_key(&"c".into(), Value::new("1".into(), Some(later)));
+        db.flush(false);
+        assert_eq!(db.expires(), 0);
+    }
+
+    #[test]
+    fn counts_hits_misses_expirations_and_evictions() {
+        let db = Database::default();
+        let now = db.clock().now_ms();
+        db.set_key(&"a".into(), Value::new("1".into(), None));
+        db.set_key(&"stale".into(), Value::new("1".into(), Some(now - 1)));
+        db.push(&"l".into(), vec![Value::new("x".into(), None)], false);
+
+        db.get_key(&"a".into());
+        db.list_range(&"l".into(), 0, -1);
+        // an expired key is a miss, even before it is removed
+        db.get_key(&"stale".into());
+        db.get_key(&"missing".into());
+        db.list_range(&"missing".into(), 0, -1);
+
+        assert!(db.delete_expired(&"stale".into(), now));
+        assert!(!db.delete_expired(&"a".into(), now));
+        assert!(db.evict(&"a".into(), false));
+        assert!(!db.evict(&"a".into(), false));
+        // a plain delete is neither
+        db.delete(&"l".into(), false);
+
+        assert_eq!(
+            db.keyspace_stats(),
+            KeyspaceStats {
+                hits: 2,
+                misses: 3,
+                expired: 1,
+                evicted: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn tracks_used_memory() {
+        let db = Database::default();
+        let raw = |len| Value::new("x".repeat(len).into(), None);
+        db.set_key(&"a".into(), raw(40));
+        let one = db.used_memory();
+        assert!(one > 0);
+
+        // overwriting swaps the old value's footprint for the new one
+        db.set_key(&"a".into(), raw(44));
+        assert_eq!(db.used_memory(), one + 4);
+        // short strings and integers live inside the value
+        db.set_key(&"a".into(), Value::new("12345".into(), None));
+        assert_eq!(db.used_memory(), one - 40);
+        db.set_key(&"a".into(), raw(44));
+
+        db.push(&"l".into(), vec![Value::new("x".into(), None)], false);
+        db.push(&"l".into(), vec![Value::new("y".into(), None)], false);
+        assert!(db.used_memory() > one);
+
+        let usage = db.memory_usage(&"l".into()).unwrap();
+        assert_eq!(
+            db.used_memory(),
+            usage + db.memory_usage(&"a".into()).unwrap()
+        );
+        assert_eq!(db.memory_usage(&"missing".into()), None);
+
+        db.delete(&"l".into(), false);
+        db.delete(&"a".into(), false);
+        assert_eq!(db.used_memory(), 0);
+        assert_eq!(db.used_memory_peak(), one + 4 + usage);
+
+        db.set_key(&"a".into(), Value::new("1".into(), None));
+        db.flush(false);
+        assert_eq!(db.used_memory(), 0);
+
+        db.set_key(&"a".into(), Value::new("1".into(), None));
+        db.push(
+            &"l".into(),
+            (0..1000)
+                .map(|i| Value::new(i.to_string().into(), None))
+                .collect(),
+            false,
+        );
+        db.flush(true);
+        assert_eq!(db.used_memory(), 0);
+        assert_eq!(db.len(), 0);
+        assert!(!db.exists(&"a".into()));
+        db.set_key(&"a".into(), Value::new("1".into(), None));
+        assert!(db.exists(&"a".into()));
+    }
+
+    #[test]
+    fn lists_expire() {
+        let db = Database::default();
+        let list = RedisKey::from("l");
+        db.push(&list, vec![Value::new("x".into(), None)], false);
+
+        let now = db.clock().now_ms();
+        let at = now + 60_000;
+        assert!(db.expire_at(&list, at));
+        assert_eq!(db.get_key_expiration(&list), Some(at));
+        assert!(!db.delete_expired(&list, now));
+        assert_eq!(db.key_type(&list), Some(KeyType::List));
+
+        let past = now - 1;
+        assert!(db.expire_at(&list, past));
+        assert!(!db.exists(&list));
+        assert!(!db.expire_at(&list, at));
+        assert_eq!(db.memory_usage(&list), None);
+
+        // pushing onto an expired list starts a new one without the old TTL
+        assert_eq!(db.push(&list, vec![Value::new("y".into(), None)], false), 1);
+        assert_eq!(db.get_key_expiration(&list), None);
+        assert_eq!(db.used_memory(), db.memory_usage(&list).unwrap());
+
+        db.expire_at(&list, past);
+        assert!(db.delete_expired(&list, now));
+        assert_eq!(db.len(), 0);
+        assert_eq!(db.used_memory(), 0);
+    }
+
+    #[test]
+    fn small_lists_are_packed() {
+        let db = Database::new(
+            LfuParams::default(),
+            ListpackLimit::Entries(4),
+            Arc::new(SystemClock),
+        );
+        let list = "l".into();
+        let push = |values: &[&'static str]| {
+            db.push(
+                &list,
+                values
+                    .iter()
+                    .map(|v| Value::new((*v).into(), None))
+                    .collect(),
+                false,
+            )
+        };
+        push(&["a", "b", "1"]);
+        assert_eq!(db.encoding(&list), Some("listpack"));
+        assert_eq!(push(&["c", "d"]), 5);
+        assert_eq!(db.encoding(&list), Some("quicklist"));
+        assert_eq!(
+            db.list_range(&list, 0, -1).unwrap(),
+            ["a", "b", "1", "c", "d"]
+        );
+        assert_eq!(db.pop(&list, 2, false).unwrap(), ["d", "c"]);
+        // lists don't go back to being packed
+        assert_eq!(db.encoding(&list), Some("quicklist"));
+    }
+
+    #[test]
+    fn drained_lists_are_deleted() {
+        let db = Database::default();
+        let list = RedisKey::from("l");
+        db.push(
+            &list,
+            ["a", "b", "c"].map(|v| Value::new(v.into(), None)).into(),
+            false,
+        );
+
+        assert_eq!(db.pop(&list, 1, true), Some(vec!["a".into()]));
+        assert_eq!(db.used_memory(), db.memory_usage(&list).unwrap());
+        assert_eq!(db.pop(&list, 5, false), Some(vec!["c".into(), "b".into()]));
+        assert!(!db.exists(&list));
+        assert_eq!(db.len(), 0);
+        assert_eq!(db.used_memory(), 0);
+        assert_eq!(db.pop(&list, 1, true), None);
+    }
+
+    #[test]
+    fn collections_are_typed_and_accounted() {
+        let db = Database::default();
+        let (set, hash) = (RedisKey::from("s"), RedisKey::from("h"));
+        assert_eq!(
+            db.set_add(&set, vec!["a".into(), "b".into(), "a".into()]),
+            2
+        );
+        assert_eq!(db.hash_set(&hash, vec![("f".into(), "v".into())]), 1);
+        assert_eq!(db.key_type(&set), Some(KeyType::Set));
+        assert_eq!(db.key_type(&hash), Some(KeyType::Hash));
+        assert_eq!(db.encoding(&set), Some("hashtable"));
+        assert_eq!(
+            db.used_memory(),
+            db.memory_usage(&set).unwrap() + db.memory_usage(&hash).unwrap()
+        );
+
+        // XX never creates a sorted set
+        let xx = ZAddFlags {
+            xx: true,
+            ..Default::default()
+        };
+        db.zadd(&"z".into(), vec![(1.0, "m".into())], xx);
+        assert!(!db.exists(&"z".into()));
+
+        // a drained set is gone, and a string replaces a key of any type
+        assert_eq!(db.set_pop(&set, 5).unwrap().len(), 2);
+        assert!(!db.exists(&set));
+        assert_eq!(db.set_pop(&set, 1), None);
+        db.set_key(&hash, Value::new("x".into(), None));
+        assert_eq!(db.key_type(&hash), Some(KeyType::String));
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.used_memory(), db.memory_usage(&hash).unwrap());
     }
 }
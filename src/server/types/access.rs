@@ -0,0 +1,185 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        OnceLock,
+    },
+    time::Instant,
+};
+
+use super::random;
+
+/// Milliseconds per tick of the LRU clock, as in Redis
+const LRU_CLOCK_RESOLUTION_MS: u64 = 1000;
+
+/// The LRU clock is kept in 24 bits like Redis' `robj.lru`, wrapping every ~194 days
+const LRU_CLOCK_MAX: u32 = (1 << 24) - 1;
+
+/// Access count given to new keys so they are not evicted before they get a chance to be used
+const LFU_INIT_VAL: u8 = 5;
+
+/// Milliseconds since the clocks started
+fn elapsed_ms() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+/// Current value of the LRU clock
+fn lru_clock() -> u32 {
+    (elapsed_ms() / LRU_CLOCK_RESOLUTION_MS) as u32 & LRU_CLOCK_MAX
+}
+
+/// Minutes on a 16 bit clock, the decay time unit of the LFU counter
+fn lfu_minutes() -> u16 {
+    (elapsed_ms() / 60_000) as u16
+}
+
+/// Tuning of the LFU counter, see `lfu-log-factor` and `lfu-decay-time` in redis.conf
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LfuParams {
+    /// Higher values take more accesses to saturate the counter
+    pub(crate) log_factor: u32,
+
+    /// Minutes between decrements of an idle key's counter, 0 to never decay
+    pub(crate) decay_time: u32,
+}
+
+impl Default for LfuParams {
+    fn default() -> Self {
+        Self {
+            log_factor: 10,
+            decay_time: 1,
+        }
+    }
+}
+
+/// `counter` after the decay owed for the minutes since `last_decrement`
+fn lfu_decayed(counter: u8, last_decrement: u16, now: u16, decay_time: u32) -> u8 {
+    if decay_time == 0 {
+        return counter;
+    }
+    let periods = now.wrapping_sub(last_decrement) as u32 / decay_time;
+    counter.saturating_sub(periods.min(u8::MAX as u32) as u8)
+}
+
+/// Logarithmically increment `counter`: the higher it is, the less likely `chance` (uniform in
+/// 0..1) lets it grow, so 8 bits can tell a few accesses from millions
+fn lfu_incremented(counter: u8, log_factor: u32, chance: f64) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let p = 1.0 / (base * log_factor as f64 + 1.0);
+    if chance < p {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+/// Milliseconds between `clock` and `now`, allowing for the clock having wrapped once
+fn idle_between(clock: u32, now: u32) -> u64 {
+    let ticks = if now >= clock {
+        now - clock
+    } else {
+        LRU_CLOCK_MAX - clock + now
+    };
+    ticks as u64 * LRU_CLOCK_RESOLUTION_MS
+}
+
+/// When and how often a key is accessed, updated through shared references so reads can record
+/// it
+pub(crate) struct Access {
+    clock: AtomicU32,
+
+    /// Minutes of the last decrement in the high 16 bits, the LFU counter in the low 8
+    lfu: AtomicU32,
+}
+
+impl Access {
+    /// Accessed just now
+    pub(crate) fn new() -> Self {
+        Self {
+            clock: AtomicU32::new(lru_clock()),
+            lfu: AtomicU32::new(Self::pack_lfu(lfu_minutes(), LFU_INIT_VAL)),
+        }
+    }
+
+    fn pack_lfu(minutes: u16, counter: u8) -> u32 {
+        (minutes as u32) << 8 | counter as u32
+    }
+
+    /// Record an access
+    pub(crate) fn touch(&self, params: LfuParams) {
+        self.clock.store(lru_clock(), Ordering::Relaxed);
+        let chance = random() as f64 / u64::MAX as f64;
+        let counter = lfu_incremented(self.frequency(params), params.log_factor, chance);
+        self.lfu
+            .store(Self::pack_lfu(lfu_minutes(), counter), Ordering::Relaxed);
+    }
+
+    /// The LFU counter with any decay owed applied, without counting as an access
+    pub(crate) fn frequency(&self, params: LfuParams) -> u8 {
+        let lfu = self.lfu.load(Ordering::Relaxed);
+        lfu_decayed(
+            lfu as u8,
+            (lfu >> 8) as u16,
+            lfu_minutes(),
+            params.decay_time,
+        )
+    }
+
+    /// Approximate milliseconds since the last access
+    pub(crate) fn idle_ms(&self) -> u64 {
+        idle_between(self.clock.load(Ordering::Relaxed), lru_clock())
+    }
+}
+
+impl Clone for Access {
+    fn clone(&self) -> Self {
+        Self {
+            clock: AtomicU32::new(self.clock.load(Ordering::Relaxed)),
+            lfu: AtomicU32::new(self.lfu.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_time_survives_wrapping() {
+        assert_eq!(idle_between(10, 10), 0);
+        assert_eq!(idle_between(10, 15), 5 * LRU_CLOCK_RESOLUTION_MS);
+        assert_eq!(
+            idle_between(LRU_CLOCK_MAX - 2, 3),
+            5 * LRU_CLOCK_RESOLUTION_MS
+        );
+        assert!(Access::new().idle_ms() < LRU_CLOCK_RESOLUTION_MS);
+    }
+
+    #[test]
+    fn lfu_counter() {
+        // new keys always count, busy ones only with decreasing probability
+        assert_eq!(lfu_incremented(LFU_INIT_VAL, 10, 0.99), LFU_INIT_VAL + 1);
+        assert_eq!(
+            lfu_incremented(LFU_INIT_VAL + 1, 10, 0.05),
+            LFU_INIT_VAL + 2
+        );
+        assert_eq!(lfu_incremented(LFU_INIT_VAL + 1, 10, 0.5), LFU_INIT_VAL + 1);
+        assert_eq!(lfu_incremented(u8::MAX, 0, 0.0), u8::MAX);
+
+        assert_eq!(lfu_decayed(10, 100, 103, 1), 7);
+        assert_eq!(lfu_decayed(10, 100, 103, 2), 9);
+        assert_eq!(lfu_decayed(10, 100, 103, 0), 10);
+        assert_eq!(lfu_decayed(2, u16::MAX, 5, 1), 0);
+
+        let access = Access::new();
+        let params = LfuParams::default();
+        for _ in 0..100 {
+            access.touch(params);
+        }
+        let frequency = access.frequency(params);
+        assert!(frequency > LFU_INIT_VAL && frequency < 50);
+    }
+}
@@ -0,0 +1,473 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+    mem::size_of,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    rdb::RdbValue,
+    server::{keyspace::KeyType, types::random},
+};
+
+use super::access::Access;
+
+/// A set's members, kept in a vector so SPOP can pick one at random in constant time, and
+/// indexed by value
+#[derive(Clone, Default)]
+pub(crate) struct Set {
+    members: Vec<Bytes>,
+    index: HashMap<Bytes, usize>,
+    /// Bytes held by the members, kept so its size is known without a scan
+    payload: usize,
+}
+
+impl Set {
+    /// Add `member`, returning whether it is new
+    fn insert(&mut self, member: Bytes) -> bool {
+        if self.index.contains_key(&member) {
+            return false;
+        }
+        self.payload += member.len();
+        self.index.insert(member.clone(), self.members.len());
+        self.members.push(member);
+        true
+    }
+
+    /// Remove `member`, returning whether it was there
+    fn remove(&mut self, member: &[u8]) -> bool {
+        let Some(i) = self.index.remove(member) else {
+            return false;
+        };
+        let removed = self.members.swap_remove(i);
+        self.payload -= removed.len();
+        // the last member took the removed one's place
+        if let Some(moved) = self.members.get(i) {
+            self.index.insert(moved.clone(), i);
+        }
+        true
+    }
+
+    /// Remove and return a random member
+    fn pop(&mut self) -> Option<Bytes> {
+        if self.members.is_empty() {
+            return None;
+        }
+        let member = self.members[random() as usize % self.members.len()].clone();
+        self.remove(&member);
+        Some(member)
+    }
+}
+
+/// A score ordered by [`f64::total_cmp`], so sorted sets can keep members in a [`BTreeSet`]
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+/// A sorted set's members, by score and in score order
+#[derive(Clone, Default)]
+pub(crate) struct SortedSet {
+    scores: HashMap<Bytes, f64>,
+    order: BTreeSet<(Score, Bytes)>,
+    /// Bytes held by the members
+    payload: usize,
+}
+
+/// How ZADD treats members already in a sorted set, and those not yet in it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ZAddFlags {
+    /// Only add new members
+    pub(crate) nx: bool,
+    /// Only update members already there
+    pub(crate) xx: bool,
+    /// Only update a score to a greater one
+    pub(crate) gt: bool,
+    /// Only update a score to a lesser one
+    pub(crate) lt: bool,
+    /// Add to the score rather than replace it
+    pub(crate) incr: bool,
+}
+
+/// What a ZADD did
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct ZAdded {
+    pub(crate) added: usize,
+    /// Members already there whose score changed
+    pub(crate) changed: usize,
+    /// Score of the last member given, `None` if the flags left it alone
+    pub(crate) score: Option<f64>,
+}
+
+impl SortedSet {
+    /// Give `member` `score`, which must not be NaN
+    fn insert(&mut self, member: Bytes, score: f64) {
+        // -0 and 0 are the same score
+        let score = score + 0.0;
+        match self.scores.insert(member.clone(), score) {
+            Some(old) => {
+                self.order.remove(&(Score(old), member.clone()));
+            }
+            None => self.payload += member.len(),
+        }
+        self.order.insert((Score(score), member));
+    }
+
+    /// Add or update members as ZADD does with `flags`, `None` if an increment left a score NaN
+    fn add(&mut self, pairs: Vec<(f64, Bytes)>, flags: ZAddFlags) -> Option<ZAdded> {
+        let mut added = ZAdded::default();
+        for (score, member) in pairs {
+            let old = self.scores.get(&member).copied();
+            let new = match (old, flags.incr) {
+                (Some(old), true) => old + score,
+                _ => score,
+            };
+            if new.is_nan() {
+                return None;
+            }
+            let applies = match old {
+                None => !flags.xx,
+                Some(old) => !flags.nx && (!flags.gt || new > old) && (!flags.lt || new < old),
+            };
+            if !applies {
+                added.score = None;
+                continue;
+            }
+            match old {
+                None => added.added += 1,
+                Some(old) if old != new => added.changed += 1,
+                Some(_) => {}
+            }
+            self.insert(member, new);
+            added.score = Some(new);
+        }
+        Some(added)
+    }
+
+    /// Remove and return the member with the lowest score
+    fn pop_min(&mut self) -> Option<(Bytes, f64)> {
+        let (score, member) = self.order.pop_first()?;
+        self.scores.remove(&member);
+        self.payload -= member.len();
+        Some((member, score.0))
+    }
+}
+
+/// The members of a set, hash or sorted set
+#[derive(Clone)]
+pub(crate) enum Members {
+    Set(Set),
+    Hash {
+        fields: HashMap<Bytes, Bytes>,
+        /// Bytes held by the fields and values
+        payload: usize,
+    },
+    SortedSet(SortedSet),
+}
+
+impl Members {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Self::Set(set) => set.members.len(),
+            Self::Hash { fields, .. } => fields.len(),
+            Self::SortedSet(zset) => zset.scores.len(),
+        }
+    }
+
+    pub(crate) fn kind(&self) -> KeyType {
+        match self {
+            Self::Set(_) => KeyType::Set,
+            Self::Hash { .. } => KeyType::Hash,
+            Self::SortedSet(_) => KeyType::SortedSet,
+        }
+    }
+
+    /// Bytes held outside the collection itself
+    fn heap_size(&self) -> usize {
+        match self {
+            Self::Set(set) => {
+                set.members.capacity() * size_of::<Bytes>()
+                    + set.index.capacity() * size_of::<(Bytes, usize)>()
+                    + set.payload
+            }
+            Self::Hash { fields, payload } => {
+                fields.capacity() * size_of::<(Bytes, Bytes)>() + payload
+            }
+            Self::SortedSet(zset) => {
+                zset.scores.capacity() * size_of::<(Bytes, f64)>()
+                    + zset.order.len() * size_of::<(Score, Bytes)>()
+                    + zset.payload
+            }
+        }
+    }
+
+    /// Add `members` to a set, returning how many are new
+    pub(crate) fn set_add(&mut self, members: Vec<Bytes>) -> usize {
+        match self {
+            Self::Set(set) => members
+                .into_iter()
+                .filter(|member| set.insert(member.clone()))
+                .count(),
+            _ => 0,
+        }
+    }
+
+    /// Remove `members` from a set, returning how many were there
+    pub(crate) fn set_remove(&mut self, members: &[Bytes]) -> usize {
+        match self {
+            Self::Set(set) => members.iter().filter(|member| set.remove(member)).count(),
+            _ => 0,
+        }
+    }
+
+    /// Remove up to `count` random members of a set
+    pub(crate) fn set_pop(&mut self, count: usize) -> Vec<Bytes> {
+        match self {
+            Self::Set(set) => (0..count).map_while(|_| set.pop()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Set the fields of a hash, returning how many are new
+    pub(crate) fn hash_set(&mut self, pairs: Vec<(Bytes, Bytes)>) -> usize {
+        let Self::Hash { fields, payload } = self else {
+            return 0;
+        };
+        let mut added = 0;
+        for (field, value) in pairs {
+            *payload += value.len();
+            match fields.insert(field.clone(), value) {
+                Some(old) => *payload -= old.len(),
+                None => {
+                    *payload += field.len();
+                    added += 1;
+                }
+            }
+        }
+        added
+    }
+
+    pub(crate) fn hash_get(&self, field: &[u8]) -> Option<Bytes> {
+        match self {
+            Self::Hash { fields, .. } => fields.get(field).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Add or update the members of a sorted set, see [`ZAddFlags`]. `None` if an increment
+    /// left a score NaN, which changes nothing.
+    pub(crate) fn zadd(&mut self, pairs: Vec<(f64, Bytes)>, flags: ZAddFlags) -> Option<ZAdded> {
+        match self {
+            Self::SortedSet(zset) => zset.add(pairs, flags),
+            _ => Some(ZAdded::default()),
+        }
+    }
+
+    pub(crate) fn zscore(&self, member: &[u8]) -> Option<f64> {
+        match self {
+            Self::SortedSet(zset) => zset.scores.get(member).copied(),
+            _ => None,
+        }
+    }
+
+    /// Remove up to `count` of the lowest scoring members of a sorted set, lowest first
+    pub(crate) fn zpop_min(&mut self, count: usize) -> Vec<(Bytes, f64)> {
+        match self {
+            Self::SortedSet(zset) => (0..count).map_while(|_| zset.pop_min()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The members as an RDB file holds them, sorted sets in score order
+    pub(crate) fn to_rdb(&self) -> RdbValue {
+        match self {
+            Self::Set(set) => RdbValue::Set(set.members.clone()),
+            Self::Hash { fields, .. } => RdbValue::Hash(
+                fields
+                    .iter()
+                    .map(|(field, value)| (field.clone(), value.clone()))
+                    .collect(),
+            ),
+            Self::SortedSet(zset) => RdbValue::SortedSet(
+                zset.order
+                    .iter()
+                    .map(|(score, member)| (member.clone(), score.0))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// A set, hash or sorted set, when it expires and when it was last accessed
+#[derive(Clone)]
+pub(crate) struct Collection {
+    pub(super) members: Members,
+    /// When the collection expires, in milliseconds since the unix epoch
+    pub(super) expiration: Option<u64>,
+    pub(super) access: Access,
+}
+
+impl Collection {
+    /// An empty collection of type `kind`, which must be a set, hash or sorted set
+    pub(super) fn new(kind: KeyType) -> Self {
+        let members = match kind {
+            KeyType::Hash => Members::Hash {
+                fields: HashMap::new(),
+                payload: 0,
+            },
+            KeyType::SortedSet => Members::SortedSet(SortedSet::default()),
+            _ => Members::Set(Set::default()),
+        };
+        Self {
+            members,
+            expiration: None,
+            access: Access::new(),
+        }
+    }
+
+    pub(crate) fn expired(&self, current: u64) -> bool {
+        self.expiration
+            .is_some_and(|expiration| current >= expiration)
+    }
+
+    /// How the members are stored, as OBJECT ENCODING names it
+    pub(super) fn encoding(&self) -> &'static str {
+        match self.members {
+            Members::SortedSet(_) => "skiplist",
+            _ => "hashtable",
+        }
+    }
+
+    /// Approximate footprint of the collection at `key`, including the map entry
+    pub(super) fn size(&self, key: &[u8]) -> usize {
+        size_of::<(Bytes, Collection)>() + key.len() + self.members.heap_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(members: &[&'static str]) -> Members {
+        let mut set = Collection::new(KeyType::Set).members;
+        set.set_add(members.iter().map(|m| Bytes::from(*m)).collect());
+        set
+    }
+
+    #[test]
+    fn sets_pop_every_member_once() {
+        let mut members = set(&["a", "b", "c", "d"]);
+        assert_eq!(members.set_add(vec!["a".into(), "e".into()]), 1);
+        assert_eq!(members.set_remove(&["b".into(), "x".into()]), 1);
+        let mut popped = members.set_pop(10);
+        popped.sort();
+        assert_eq!(popped, ["a", "c", "d", "e"]);
+        assert_eq!(members.len(), 0);
+        let Members::Set(set) = members else {
+            unreachable!()
+        };
+        assert_eq!(set.payload, 0);
+    }
+
+    #[test]
+    fn hashes_count_new_fields() {
+        let mut hash = Collection::new(KeyType::Hash).members;
+        let pair = |f: &'static str, v: &'static str| (Bytes::from(f), Bytes::from(v));
+        assert_eq!(hash.hash_set(vec![pair("f", "1"), pair("g", "2")]), 2);
+        assert_eq!(hash.hash_set(vec![pair("f", "10"), pair("h", "3")]), 1);
+        assert_eq!(hash.hash_get(b"f"), Some("10".into()));
+        assert_eq!(hash.hash_get(b"missing"), None);
+        let Members::Hash { payload, .. } = hash else {
+            unreachable!()
+        };
+        assert_eq!(payload, 7);
+    }
+
+    #[test]
+    fn zadd_follows_its_flags() {
+        let mut zset = Collection::new(KeyType::SortedSet).members;
+        let pairs = |pairs: &[(f64, &'static str)]| {
+            pairs.iter().map(|(s, m)| (*s, Bytes::from(*m))).collect()
+        };
+        let added = zset.zadd(pairs(&[(1.0, "a"), (2.0, "b")]), ZAddFlags::default());
+        assert_eq!(added.unwrap().added, 2);
+
+        let gt = ZAddFlags {
+            gt: true,
+            ..Default::default()
+        };
+        let added = zset.zadd(pairs(&[(0.5, "a"), (3.0, "b"), (-1.0, "c")]), gt);
+        assert_eq!(
+            added,
+            Some(ZAdded {
+                added: 1,
+                changed: 1,
+                score: Some(-1.0),
+            })
+        );
+        assert_eq!(zset.zscore(b"a"), Some(1.0));
+
+        let incr = ZAddFlags {
+            incr: true,
+            xx: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            zset.zadd(pairs(&[(2.5, "a")]), incr).unwrap().score,
+            Some(3.5)
+        );
+        assert_eq!(zset.zadd(pairs(&[(1.0, "new")]), incr).unwrap().score, None);
+        zset.zadd(pairs(&[(f64::INFINITY, "a")]), ZAddFlags::default());
+        let nan = ZAddFlags {
+            incr: true,
+            ..Default::default()
+        };
+        assert_eq!(zset.zadd(pairs(&[(f64::NEG_INFINITY, "a")]), nan), None);
+
+        assert_eq!(
+            zset.to_rdb(),
+            RdbValue::SortedSet(vec![
+                ("c".into(), -1.0),
+                ("b".into(), 3.0),
+                ("a".into(), f64::INFINITY),
+            ])
+        );
+    }
+
+    #[test]
+    fn zpop_min_takes_the_lowest_scores() {
+        let mut zset = Collection::new(KeyType::SortedSet).members;
+        let pairs = vec![(2.0, "b".into()), (-1.0, "c".into()), (1.0, "a".into())];
+        zset.zadd(pairs, ZAddFlags::default());
+        assert_eq!(
+            zset.zpop_min(2),
+            vec![("c".into(), -1.0), ("a".into(), 1.0)]
+        );
+        assert_eq!(zset.zscore(b"c"), None);
+        assert_eq!(zset.zpop_min(5), vec![("b".into(), 2.0)]);
+        assert_eq!(zset.len(), 0);
+        let Members::SortedSet(zset) = zset else {
+            unreachable!()
+        };
+        assert_eq!(zset.payload, 0);
+    }
+}
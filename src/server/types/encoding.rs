@@ -0,0 +1,99 @@
+use bytes::Bytes;
+
+/// Strings up to this long are stored inline, the most that fits in the space a tagged [`Bytes`]
+/// takes anyway
+pub(crate) const EMBSTR_MAX: usize = 38;
+
+/// The contents of a string, in the most compact form that holds them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Encoded {
+    /// An integer in its canonical decimal form
+    Int(i64),
+    /// A short string, inline
+    Embstr { len: u8, bytes: [u8; EMBSTR_MAX] },
+    /// Anything else, on the heap
+    Raw(Bytes),
+}
+
+/// The integer `bytes` spell, if they are exactly how it is written out, so the string
+/// round-trips: no sign on positives, no leading zeros, no "-0"
+fn canonical_int(bytes: &[u8]) -> Option<i64> {
+    if bytes.is_empty() || bytes.len() > 20 {
+        return None;
+    }
+    let int: i64 = std::str::from_utf8(bytes).ok()?.parse().ok()?;
+    (int.to_string().as_bytes() == bytes).then_some(int)
+}
+
+impl Encoded {
+    pub(crate) fn new(value: Bytes) -> Self {
+        if let Some(int) = canonical_int(&value) {
+            return Self::Int(int);
+        }
+        if value.len() <= EMBSTR_MAX {
+            let mut bytes = [0; EMBSTR_MAX];
+            bytes[..value.len()].copy_from_slice(&value);
+            return Self::Embstr {
+                len: value.len() as u8,
+                bytes,
+            };
+        }
+        Self::Raw(value)
+    }
+
+    pub(crate) fn to_bytes(&self) -> Bytes {
+        match self {
+            Self::Int(int) => Bytes::from(int.to_string()),
+            Self::Embstr { len, bytes } => Bytes::copy_from_slice(&bytes[..*len as usize]),
+            Self::Raw(bytes) => bytes.clone(),
+        }
+    }
+
+    pub(crate) fn into_bytes(self) -> Bytes {
+        match self {
+            Self::Raw(bytes) => bytes,
+            encoded => encoded.to_bytes(),
+        }
+    }
+
+    /// Bytes held outside the value itself
+    pub(crate) fn heap_size(&self) -> usize {
+        match self {
+            Self::Raw(bytes) => bytes.len(),
+            _ => 0,
+        }
+    }
+
+    /// As OBJECT ENCODING names it
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Self::Int(_) => "int",
+            Self::Embstr { .. } => "embstr",
+            Self::Raw(_) => "raw",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_the_smallest_encoding() {
+        let encode = |s: &'static str| Encoded::new(Bytes::from(s));
+        assert_eq!(encode("12345"), Encoded::Int(12345));
+        assert_eq!(encode("-9223372036854775808"), Encoded::Int(i64::MIN));
+        for not_int in ["007", "+1", "-0", " 1", "1.5", "9223372036854775808", ""] {
+            assert_eq!(encode(not_int).name(), "embstr", "{not_int}");
+        }
+        assert_eq!(encode("x".repeat(EMBSTR_MAX).leak()).name(), "embstr");
+        let long: &'static str = "x".repeat(EMBSTR_MAX + 1).leak();
+        assert_eq!(encode(long), Encoded::Raw(Bytes::from(long)));
+
+        for s in ["-42", "007", "hello", long] {
+            assert_eq!(encode(s).to_bytes(), s);
+            assert_eq!(encode(s).into_bytes(), s);
+        }
+        assert!(size_of::<Encoded>() <= size_of::<Bytes>() + size_of::<usize>());
+    }
+}
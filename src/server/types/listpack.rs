@@ -0,0 +1,179 @@
+use bytes::Bytes;
+
+/// When a packed list is converted to the full structure, from `list-max-listpack-size`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ListpackLimit {
+    /// At most this many elements
+    Entries(usize),
+    /// At most this many bytes of packed data
+    Bytes(usize),
+}
+
+impl ListpackLimit {
+    /// Read `list-max-listpack-size`: a positive count of elements, or -1 to -5 for 4 KB to 64 KB
+    pub(crate) fn from_config(size: i64) -> Self {
+        match size {
+            1.. => Self::Entries(size as usize),
+            _ => Self::Bytes(4096 << (size.unsigned_abs().clamp(1, 5) - 1)),
+        }
+    }
+
+    pub(crate) fn fits(&self, listpack: &Listpack) -> bool {
+        match *self {
+            Self::Entries(entries) => listpack.len <= entries,
+            Self::Bytes(bytes) => listpack.buf.len() <= bytes,
+        }
+    }
+}
+
+impl Default for ListpackLimit {
+    fn default() -> Self {
+        Self::from_config(-2)
+    }
+}
+
+/// Elements packed back to back in one buffer, each framed by its length before and after in
+/// LEB128 so the buffer can be walked from either end: a couple of bytes of overhead per element
+/// rather than a separate allocation.
+#[derive(Clone, Default)]
+pub(crate) struct Listpack {
+    buf: Vec<u8>,
+    len: usize,
+}
+
+/// Append `n` as LEB128, or its bytes in reverse to read backwards from the end
+fn write_len(buf: &mut Vec<u8>, mut n: usize, reversed: bool) {
+    let start = buf.len();
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            buf.push(byte);
+            break;
+        }
+        buf.push(byte | 0x80);
+    }
+    if reversed {
+        buf[start..].reverse();
+    }
+}
+
+/// Read a LEB128 length from `bytes` in order, returning it and the bytes it took
+fn read_len(bytes: impl Iterator<Item = u8>) -> (usize, usize) {
+    let mut n = 0;
+    for (i, byte) in bytes.enumerate() {
+        n |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return (n, i + 1);
+        }
+    }
+    unreachable!("listpack entry lengths are always terminated")
+}
+
+impl Listpack {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Bytes allocated for the buffer
+    pub(crate) fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Bytes allocated for the buffer but unused
+    pub(crate) fn slack(&self) -> usize {
+        self.buf.capacity() - self.buf.len()
+    }
+
+    /// Reallocate the buffer to fit the elements
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.buf.shrink_to_fit();
+    }
+
+    pub(crate) fn push_back(&mut self, element: &[u8]) {
+        write_len(&mut self.buf, element.len(), false);
+        self.buf.extend_from_slice(element);
+        write_len(&mut self.buf, element.len(), true);
+        self.len += 1;
+    }
+
+    /// Prepend `element`, moving every other element along
+    pub(crate) fn push_front(&mut self, element: &[u8]) {
+        let mut entry = Vec::with_capacity(element.len() + 4);
+        write_len(&mut entry, element.len(), false);
+        entry.extend_from_slice(element);
+        write_len(&mut entry, element.len(), true);
+        self.buf.splice(..0, entry);
+        self.len += 1;
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<Bytes> {
+        if self.len == 0 {
+            return None;
+        }
+        let (len, header) = read_len(self.buf.iter().copied());
+        let element = Bytes::copy_from_slice(&self.buf[header..header + len]);
+        self.buf.drain(..2 * header + len);
+        self.len -= 1;
+        Some(element)
+    }
+
+    pub(crate) fn pop_back(&mut self) -> Option<Bytes> {
+        if self.len == 0 {
+            return None;
+        }
+        let (len, trailer) = read_len(self.buf.iter().rev().copied());
+        let end = self.buf.len() - trailer;
+        let element = Bytes::copy_from_slice(&self.buf[end - len..end]);
+        self.buf.truncate(end - len - trailer);
+        self.len -= 1;
+        Some(element)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        let mut rest = &self.buf[..];
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let (len, header) = read_len(rest.iter().copied());
+            let element = &rest[header..header + len];
+            rest = &rest[2 * header + len..];
+            Some(element)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packs_from_both_ends() {
+        let mut listpack = Listpack::default();
+        let long = "x".repeat(300);
+        for element in ["", &long, "bc"] {
+            listpack.push_back(element.as_bytes());
+        }
+        listpack.push_front(b"a");
+        assert_eq!(listpack.len(), 4);
+        let elements: Vec<_> = listpack.iter().collect();
+        assert_eq!(elements, [&b"a"[..], b"", long.as_bytes(), b"bc"]);
+        // one length byte either side, two for the long element
+        assert_eq!(listpack.buf.len(), 3 + 2 + 304 + 4);
+
+        assert_eq!(listpack.pop_back().unwrap(), "bc");
+        assert_eq!(listpack.pop_front().unwrap(), "a");
+        assert_eq!(listpack.pop_back().unwrap(), long);
+        assert_eq!(listpack.pop_front().unwrap(), "");
+        assert_eq!(listpack.pop_front(), None);
+        assert!(listpack.buf.is_empty());
+    }
+
+    #[test]
+    fn limits_from_config() {
+        assert_eq!(ListpackLimit::from_config(-2), ListpackLimit::Bytes(8192));
+        assert_eq!(ListpackLimit::from_config(-5), ListpackLimit::Bytes(65536));
+        assert_eq!(ListpackLimit::from_config(128), ListpackLimit::Entries(128));
+    }
+}
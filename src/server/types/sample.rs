@@ -0,0 +1,127 @@
+use std::{
+    cell::Cell,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use dashmap::DashMap;
+
+use crate::server::types::RedisKey;
+
+thread_local! {
+    static RNG: Cell<u64> = Cell::new({
+        // every RandomState is seeded with fresh randomness
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u8(0);
+        hasher.finish() | 1
+    });
+}
+
+/// A fast non-cryptographic random number (xorshift64*), good enough for sampling keys
+pub(crate) fn random() -> u64 {
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        rng.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+/// Pick a random key from `map`, like Redis' `dictGetRandomKey`.
+///
+/// Chooses a random shard and then probes random buckets of its table until one is occupied,
+/// so the cost does not depend on how many keys there are.
+fn random_key<V>(map: &DashMap<RedisKey, V>) -> Option<RedisKey> {
+    if map.is_empty() {
+        return None;
+    }
+    let shards = map.shards();
+    // a few shards may be empty even when the map is not, so give up after enough misses
+    for _ in 0..shards.len() * 4 {
+        let shard = shards[random() as usize % shards.len()].read();
+        if shard.is_empty() {
+            continue;
+        }
+        let buckets = shard.buckets();
+        loop {
+            let index = random() as usize % buckets;
+            // SAFETY: `index` is within the table and the shard's read lock is held, so an
+            // occupied bucket stays valid while we clone its key
+            unsafe {
+                if shard.is_bucket_full(index) {
+                    return Some(shard.bucket(index).as_ref().0.clone());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Up to `count` random keys of `map`, possibly with repeats
+pub(super) fn sample<V>(map: &DashMap<RedisKey, V>, count: usize) -> Vec<RedisKey> {
+    (0..count).filter_map(|_| random_key(map)).collect()
+}
+
+/// Up to `count` random keys of any of the maps, each drawn in proportion to how many keys it
+/// has
+pub(super) fn sample_any<A, B, C>(
+    a: &DashMap<RedisKey, A>,
+    b: &DashMap<RedisKey, B>,
+    c: &DashMap<RedisKey, C>,
+    count: usize,
+) -> Vec<RedisKey> {
+    (0..count)
+        .filter_map(|_| {
+            let total = a.len() + b.len() + c.len();
+            if total == 0 {
+                return None;
+            }
+            match random() as usize % total {
+                i if i < a.len() => random_key(a),
+                i if i < a.len() + b.len() => random_key(b),
+                _ => random_key(c),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::{
+        storage::Storage,
+        types::{Database, Value},
+    };
+
+    #[test]
+    fn samples_existing_keys() {
+        let db = Database::default();
+        assert!(db.sample_strings(5).is_empty());
+
+        for i in 0..100 {
+            db.set_key(&format!("k{i}").into(), Value::new("v".into(), None));
+        }
+        let sample = db.sample_strings(50);
+        assert_eq!(sample.len(), 50);
+        assert!(sample.iter().all(|key| db.exists(key)));
+        // with 100 keys, 50 draws are all but certain to hit more than one
+        assert!(sample.iter().any(|key| *key != sample[0]));
+
+        for i in 0..100 {
+            db.push(
+                &format!("l{i}").into(),
+                vec![Value::new("v".into(), None)],
+                false,
+            );
+        }
+        for i in 0..100 {
+            db.set_add(&format!("s{i}").into(), vec!["v".into()]);
+        }
+        let sample = db.sample_keys(150);
+        assert_eq!(sample.len(), 150);
+        assert!(sample.iter().any(|key| key.starts_with(b"k")));
+        assert!(sample.iter().any(|key| key.starts_with(b"l")));
+        assert!(sample.iter().any(|key| key.starts_with(b"s")));
+    }
+}
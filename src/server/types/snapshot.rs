@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use dashmap::DashMap;
+
+use crate::{
+    rdb::{RdbEntry, RdbValue},
+    server::{
+        storage::Snapshot,
+        types::{Collection, Database, List, RedisKey, Value},
+    },
+};
+
+/// Copy-on-write record of the keyspace as it was when a snapshot began.
+///
+/// The first write to a key after the snapshot starts stashes the old value (or its absence)
+/// here, so the snapshot can be read out at leisure while writes continue on the live maps.
+#[derive(Default)]
+pub(crate) struct Shadow {
+    kv: DashMap<RedisKey, Option<Value>>,
+    lists: DashMap<RedisKey, Option<List>>,
+    collections: DashMap<RedisKey, Option<Collection>>,
+}
+
+impl Shadow {
+    pub(super) fn preserve_kv(&self, live: &DashMap<RedisKey, Value>, key: &RedisKey) {
+        self.kv
+            .entry(key.clone())
+            .or_insert_with(|| live.get(key).map(|v| v.clone()));
+    }
+
+    pub(super) fn preserve_list(&self, live: &DashMap<RedisKey, List>, key: &RedisKey) {
+        self.lists
+            .entry(key.clone())
+            .or_insert_with(|| live.get(key).map(|v| v.clone()));
+    }
+
+    pub(super) fn preserve_collection(&self, live: &DashMap<RedisKey, Collection>, key: &RedisKey) {
+        self.collections
+            .entry(key.clone())
+            .or_insert_with(|| live.get(key).map(|v| v.clone()));
+    }
+}
+
+/// A consistent point-in-time view of a [`Database`].
+///
+/// Only one snapshot can exist at a time; dropping it stops the copy-on-write tracking.
+pub(super) struct ShadowSnapshot {
+    kv: Arc<DashMap<RedisKey, Value>>,
+    lists: Arc<DashMap<RedisKey, List>>,
+    collections: Arc<DashMap<RedisKey, Collection>>,
+    /// The database's slot for the shadow, cleared when done
+    slot: Arc<RwLock<Option<Arc<Shadow>>>>,
+    shadow: Arc<Shadow>,
+    /// When the snapshot began, in milliseconds since the unix epoch
+    taken_at: u64,
+}
+
+impl ShadowSnapshot {
+    /// Begin a snapshot of `db`, or `None` if one is already in progress
+    pub(super) fn begin(db: &Database) -> Option<Self> {
+        // taking the write lock waits for in-flight writes, giving us a clean starting point
+        let mut slot = db.shadow.write().unwrap();
+        if slot.is_some() {
+            return None;
+        }
+        let shadow = Arc::new(Shadow::default());
+        *slot = Some(shadow.clone());
+        Some(Self {
+            kv: db.kv.clone(),
+            lists: db.lists.clone(),
+            collections: db.collections.clone(),
+            slot: db.shadow.clone(),
+            shadow,
+            taken_at: db.clock.now_ms(),
+        })
+    }
+}
+
+impl Snapshot for ShadowSnapshot {
+    fn entries(&self) -> Vec<RdbEntry> {
+        // read the live maps first, then let the shadow override anything touched meanwhile
+        let mut kv: HashMap<RedisKey, Value> = self
+            .kv
+            .iter()
+            .map(|e| (e.key().clone(), e.value().clone()))
+            .collect();
+        for e in self.shadow.kv.iter() {
+            match e.value() {
+                Some(v) => kv.insert(e.key().clone(), v.clone()),
+                None => kv.remove(e.key()),
+            };
+        }
+
+        let mut lists: HashMap<RedisKey, List> = self
+            .lists
+            .iter()
+            .map(|e| (e.key().clone(), e.value().clone()))
+            .collect();
+        for e in self.shadow.lists.iter() {
+            match e.value() {
+                Some(v) => lists.insert(e.key().clone(), v.clone()),
+                None => lists.remove(e.key()),
+            };
+        }
+
+        let mut collections: HashMap<RedisKey, Collection> = self
+            .collections
+            .iter()
+            .map(|e| (e.key().clone(), e.value().clone()))
+            .collect();
+        for e in self.shadow.collections.iter() {
+            match e.value() {
+                Some(v) => collections.insert(e.key().clone(), v.clone()),
+                None => collections.remove(e.key()),
+            };
+        }
+
+        let taken_at = self.taken_at;
+        let mut entries = Vec::with_capacity(kv.len() + lists.len() + collections.len());
+        for (key, value) in kv {
+            if value.expired(taken_at) {
+                continue;
+            }
+            entries.push(RdbEntry {
+                db: 0,
+                key,
+                value: RdbValue::String(value.get_value()),
+                expiration_ms: value.get_expiration(),
+            });
+        }
+        for (key, list) in lists {
+            if list.expired(taken_at) {
+                continue;
+            }
+            entries.push(RdbEntry {
+                db: 0,
+                key,
+                value: RdbValue::List(list.range(0, -1)),
+                expiration_ms: list.expiration,
+            });
+        }
+        for (key, collection) in collections {
+            if collection.expired(taken_at) {
+                continue;
+            }
+            entries.push(RdbEntry {
+                db: 0,
+                key,
+                value: collection.members.to_rdb(),
+                expiration_ms: collection.expiration,
+            });
+        }
+        entries
+    }
+}
+
+impl Drop for ShadowSnapshot {
+    fn drop(&mut self) {
+        let mut slot = self.slot.write().unwrap();
+        if slot.as_ref().is_some_and(|s| Arc::ptr_eq(s, &self.shadow)) {
+            *slot = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::Storage;
+
+    fn values(snapshot: &dyn Snapshot) -> HashMap<RedisKey, RdbValue> {
+        snapshot
+            .entries()
+            .into_iter()
+            .map(|e| (e.key, e.value))
+            .collect()
+    }
+
+    #[test]
+    fn writes_after_snapshot_are_invisible() {
+        let db = Database::default();
+        db.set_key(&"a".into(), Value::new("1".into(), None));
+        db.set_key(&"b".into(), Value::new("2".into(), None));
+        db.push(&"l".into(), vec![Value::new("x".into(), None)], false);
+        db.set_add(&"s".into(), vec!["x".into()]);
+
+        let snapshot = db.snapshot().unwrap();
+        assert!(db.snapshot().is_none());
+
+        db.set_key(&"a".into(), Value::new("changed".into(), None));
+        db.delete(&"b".into(), false);
+        db.set_key(&"c".into(), Value::new("new".into(), None));
+        db.push(&"l".into(), vec![Value::new("y".into(), None)], false);
+        db.set_add(&"s".into(), vec!["y".into()]);
+
+        let entries = values(&*snapshot);
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[&RedisKey::from("a")], RdbValue::String("1".into()));
+        assert_eq!(entries[&RedisKey::from("b")], RdbValue::String("2".into()));
+        assert_eq!(
+            entries[&RedisKey::from("l")],
+            RdbValue::List(vec!["x".into()])
+        );
+        assert_eq!(
+            entries[&RedisKey::from("s")],
+            RdbValue::Set(vec!["x".into()])
+        );
+
+        // the live view sees every write
+        assert_eq!(db.get_key(&"a".into()), Some("changed".into()));
+        assert_eq!(db.get_key(&"b".into()), None);
+
+        // dropping the snapshot allows a new one to begin
+        drop(snapshot);
+        let entries = values(&*db.snapshot().unwrap());
+        assert_eq!(entries.len(), 4);
+        assert_eq!(
+            entries[&RedisKey::from("c")],
+            RdbValue::String("new".into())
+        );
+    }
+
+    #[test]
+    fn lists_keep_their_expiration() {
+        let db = Database::default();
+        db.push(&"l".into(), vec![Value::new("x".into(), None)], false);
+        db.push(&"gone".into(), vec![Value::new("x".into(), None)], false);
+        let now = db.clock().now_ms();
+        db.expire_at(&"l".into(), now + 60_000);
+        db.expire_at(&"gone".into(), now);
+
+        let entries = db.snapshot().unwrap().entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].expiration_ms, Some(now + 60_000));
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::sync::mpsc;
+
+/// A change to a watched key, see [`Keyspace::watch_prefix`](super::Keyspace::watch_prefix)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyEvent {
+    /// Written, e.g. by SET or a push, and still holding a value
+    Set(Bytes),
+    /// Removed, e.g. by DEL, FLUSHALL, eviction or popping its last element
+    Deleted(Bytes),
+    /// Removed once its expiration passed
+    Expired(Bytes),
+}
+
+impl KeyEvent {
+    pub fn key(&self) -> &Bytes {
+        match self {
+            Self::Set(key) | Self::Deleted(key) | Self::Expired(key) => key,
+        }
+    }
+}
+
+/// The changes to the keys under a prefix, as a [`Stream`]. Dropping it stops the watch.
+#[derive(Debug)]
+pub struct KeyEvents(mpsc::UnboundedReceiver<KeyEvent>);
+
+impl Stream for KeyEvents {
+    type Item = KeyEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KeyEvent>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// Someone watching the keys starting with `prefix`
+struct Watcher {
+    prefix: Bytes,
+    events: mpsc::UnboundedSender<KeyEvent>,
+}
+
+/// The programs watching a server's keys, told what each write did through the
+/// [`Propagator`](super::propagation::Propagator)
+#[derive(Default)]
+pub(crate) struct Watchers {
+    watchers: Mutex<Vec<Watcher>>,
+
+    /// Watchers registered, so writes can skip the lock while nobody watches
+    count: AtomicUsize,
+}
+
+impl Watchers {
+    /// Events for the keys starting with `prefix`, until the stream is dropped. Events queue up
+    /// until read, so a stream should be read promptly.
+    pub(crate) fn watch(&self, prefix: Bytes) -> KeyEvents {
+        let (events, rx) = mpsc::unbounded_channel();
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.push(Watcher { prefix, events });
+        self.count.store(watchers.len(), Ordering::Relaxed);
+        KeyEvents(rx)
+    }
+
+    /// Whether anyone watches any key
+    pub(crate) fn watching(&self) -> bool {
+        self.count.load(Ordering::Relaxed) > 0
+    }
+
+    /// Hand `event` to everyone watching its key, forgetting those who stopped watching
+    pub(crate) fn emit(&self, event: KeyEvent) {
+        if !self.watching() {
+            return;
+        }
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|watcher| {
+            !event.key().starts_with(&watcher.prefix) || watcher.events.send(event.clone()).is_ok()
+        });
+        self.count.store(watchers.len(), Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_changes_under_the_prefix() {
+        let watchers = Watchers::default();
+        let mut events = watchers.watch("user:".into());
+        let user = Bytes::from("user:1");
+
+        watchers.emit(KeyEvent::Set(user.clone()));
+        watchers.emit(KeyEvent::Set("session:1".into()));
+        watchers.emit(KeyEvent::Deleted(user.clone()));
+        watchers.emit(KeyEvent::Expired("user:2".into()));
+
+        assert_eq!(events.next().await, Some(KeyEvent::Set(user.clone())));
+        assert_eq!(events.next().await, Some(KeyEvent::Deleted(user)));
+        assert_eq!(
+            events.next().await,
+            Some(KeyEvent::Expired("user:2".into()))
+        );
+
+        drop(events);
+        watchers.emit(KeyEvent::Expired("user:3".into()));
+        assert_eq!(watchers.count.load(Ordering::Relaxed), 0);
+    }
+}
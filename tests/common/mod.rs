@@ -0,0 +1,106 @@
+//! Boots servers inside the test process and talks to them over real sockets
+
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use codecrafters_redis::{
+    resp::{client::Client, RedisValue},
+    server::{Clock, RedisBuilder, RunningRedis},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Longest a test waits on the server before failing rather than hanging
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server on an ephemeral loopback port, with no dataset to load
+pub fn builder() -> RedisBuilder {
+    codecrafters_redis::server::Redis::builder()
+        .port(0)
+        .dir(std::env::temp_dir())
+        .dbfilename(format!("e2e-missing-{}.rdb", std::process::id()))
+}
+
+pub async fn start() -> RunningRedis {
+    builder().start().await.unwrap()
+}
+
+/// A server judging expirations by `clock`
+pub async fn start_with_clock(clock: Arc<dyn Clock>) -> RunningRedis {
+    builder().clock(clock).start().await.unwrap()
+}
+
+pub async fn client(addr: SocketAddr) -> Client {
+    Client::connect(addr).await.unwrap()
+}
+
+/// Send a command and wait for its reply
+pub async fn request<S, const N: usize>(client: &mut Client<S>, args: [&str; N]) -> RedisValue
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(TIMEOUT, client.request(args))
+        .await
+        .expect("no reply in time")
+        .unwrap()
+}
+
+pub fn bulk(s: &str) -> RedisValue {
+    RedisValue::BulkString(s.to_string().into())
+}
+
+pub fn ok() -> RedisValue {
+    RedisValue::SimpleString("OK".into())
+}
+
+/// A plain socket, for sending what a well-behaved client wouldn't
+pub async fn raw(addr: SocketAddr) -> TcpStream {
+    TcpStream::connect(addr).await.unwrap()
+}
+
+/// Write `request` and read exactly `reply.len()` bytes back, expecting them to be `reply`
+pub async fn exchange(stream: &mut TcpStream, request: &[u8], reply: &[u8]) {
+    stream.write_all(request).await.unwrap();
+    let mut buf = vec![0; reply.len()];
+    tokio::time::timeout(TIMEOUT, stream.read_exact(&mut buf))
+        .await
+        .expect("no reply in time")
+        .unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&buf),
+        String::from_utf8_lossy(reply)
+    );
+}
+
+/// Read until the server closes the connection, returning everything it sent first
+pub async fn read_to_close(stream: &mut TcpStream) -> Vec<u8> {
+    let mut buf = Vec::new();
+    tokio::time::timeout(TIMEOUT, stream.read_to_end(&mut buf))
+        .await
+        .expect("connection not closed in time")
+        .unwrap();
+    buf
+}
+
+/// A payload RESTORE takes for `body`, a value as RDB files encode it: followed by the RDB
+/// version and the CRC-64 Redis checksums with, so that only what `body` says is judged
+pub fn dump_payload(body: &[u8]) -> Vec<u8> {
+    let mut payload = body.to_vec();
+    payload.extend_from_slice(&11u16.to_le_bytes());
+    // CRC-64/Jones, bit by bit
+    let mut crc = 0u64;
+    for byte in &payload {
+        crc ^= u64::from(*byte);
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0x95ac_9329_ac4b_c9b5,
+                _ => crc >> 1,
+            };
+        }
+    }
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
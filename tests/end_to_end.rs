@@ -0,0 +1,1653 @@
+//! The server as clients see it: booted in-process on an ephemeral port and driven over TCP
+
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use codecrafters_redis::{
+    resp::{client::Client, codec::RespFrame, RedisValue},
+    server::{KeyEvent, MockClock},
+};
+use common::{bulk, ok, request};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_rustls::{rustls, TlsConnector};
+use tokio_util::codec::Framed;
+
+#[tokio::test]
+async fn concurrent_clients_see_each_others_writes() {
+    let redis = common::start().await;
+    let addr = redis.addr();
+
+    let writers = (0..8).map(|c| {
+        tokio::spawn(async move {
+            let mut client = common::client(addr).await;
+            for i in 0..100 {
+                let (key, value) = (format!("key:{c}:{i}"), format!("value:{c}:{i}"));
+                assert_eq!(request(&mut client, ["SET", &key, &value]).await, ok());
+                assert_eq!(request(&mut client, ["GET", &key]).await, bulk(&value));
+            }
+            // a list every client appends to
+            for i in 0..100 {
+                request(&mut client, ["RPUSH", "shared", &i.to_string()]).await;
+            }
+        })
+    });
+    for writer in writers {
+        writer.await.unwrap();
+    }
+
+    let mut reader = common::client(addr).await;
+    assert_eq!(
+        request(&mut reader, ["GET", "key:7:99"]).await,
+        bulk("value:7:99")
+    );
+    assert_eq!(
+        request(&mut reader, ["RPUSH", "shared", "last"]).await,
+        RedisValue::Integer(801)
+    );
+    assert_eq!(redis.keyspace().keys().count(), 801);
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_writes_with_expirations_keep_flowing() {
+    let redis = common::start().await;
+    let addr = redis.addr();
+
+    // keys expiring while others are written keep the expirer contending for the write lock
+    let writers = (0..64).map(|c| {
+        tokio::spawn(async move {
+            let mut client = common::client(addr).await;
+            for i in 0..200 {
+                let key = format!("key:{c}:{i}");
+                let ttl = (i % 5 + 1).to_string();
+                assert_eq!(
+                    request(&mut client, ["SET", &key, "v", "PX", &ttl]).await,
+                    ok()
+                );
+            }
+        })
+    });
+    for writer in writers {
+        tokio::time::timeout(common::TIMEOUT, writer)
+            .await
+            .expect("writes stalled")
+            .unwrap();
+    }
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn keys_expire_on_time() {
+    let clock = Arc::new(MockClock::default());
+    let redis = common::start_with_clock(clock.clone()).await;
+    let mut client = common::client(redis.addr()).await;
+
+    request(&mut client, ["SET", "short", "x", "PX", "1500"]).await;
+    request(&mut client, ["SET", "long", "y", "EX", "10"]).await;
+    request(&mut client, ["SET", "forever", "z"]).await;
+    let keyspace = redis.keyspace();
+    assert_eq!(keyspace.ttl("short"), Some(Duration::from_millis(1500)));
+    assert_eq!(keyspace.ttl("forever"), None);
+
+    clock.advance(Duration::from_millis(1499));
+    assert_eq!(request(&mut client, ["GET", "short"]).await, bulk("x"));
+    clock.advance(Duration::from_millis(1));
+    assert_eq!(
+        request(&mut client, ["GET", "short"]).await,
+        RedisValue::NullBulkString
+    );
+    assert_eq!(keyspace.ttl("long"), Some(Duration::from_millis(8500)));
+
+    // another client sees the same expirations
+    let mut other = common::client(redis.addr()).await;
+    clock.advance(Duration::from_millis(8500));
+    assert_eq!(
+        request(&mut other, ["MGET", "short", "long", "forever"]).await,
+        RedisValue::Array(vec![
+            RedisValue::NullBulkString,
+            RedisValue::NullBulkString,
+            bulk("z"),
+        ])
+    );
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn pipelined_commands_are_answered_in_order() {
+    let redis = common::start().await;
+    let mut stream = common::raw(redis.addr()).await;
+
+    let mut request = Vec::new();
+    let mut reply = Vec::new();
+    for i in 0..1000 {
+        let value = i.to_string();
+        request.extend_from_slice(
+            format!(
+                "*3\r\n$3\r\nSET\r\n$1\r\nk\r\n${}\r\n{value}\r\n",
+                value.len()
+            )
+            .as_bytes(),
+        );
+        request.extend_from_slice(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n");
+        reply.extend_from_slice(format!("+OK\r\n${}\r\n{value}\r\n", value.len()).as_bytes());
+    }
+    common::exchange(&mut stream, &request, &reply).await;
+
+    // the same pipeline arriving a few bytes at a time, frames split anywhere
+    let mut stream = common::raw(redis.addr()).await;
+    let (head, tail) = request.split_at(request.len() - 7);
+    for piece in head.chunks(7) {
+        stream.write_all(piece).await.unwrap();
+        tokio::task::yield_now().await;
+    }
+    common::exchange(&mut stream, tail, &reply).await;
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn malformed_input_only_affects_its_client() {
+    let redis = common::start().await;
+    let mut bystander = common::client(redis.addr()).await;
+    request(&mut bystander, ["SET", "k", "v"]).await;
+
+    // mistakes within the protocol are answered and the connection carries on
+    let mut stream = common::raw(redis.addr()).await;
+    common::exchange(
+        &mut stream,
+        b"*1\r\n$7\r\nNOTACMD\r\n",
+        b"-ERR unknown command 'NOTACMD', with args beginning with: \r\n",
+    )
+    .await;
+    common::exchange(
+        &mut stream,
+        b"*1\r\n$3\r\nGET\r\n",
+        b"-ERR wrong number of arguments for 'get' command\r\n",
+    )
+    .await;
+    common::exchange(&mut stream, b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n").await;
+
+    // broken framing can't be recovered from, so the client is told why and dropped
+    for garbage in [
+        &b"*abc\r\n"[..],
+        b"$-5\r\n",
+        b"*1\r\n$4\r\nPINGPONG\r\n",
+        b"*2\r\n$3\r\nGET\r\n:1\r\n%\r\n",
+    ] {
+        let mut stream = common::raw(redis.addr()).await;
+        stream.write_all(garbage).await.unwrap();
+        let reply = common::read_to_close(&mut stream).await;
+        // anything well formed before the garbage is still answered
+        assert!(
+            reply.ends_with(b"\r\n") && reply.windows(19).any(|w| w == b"-ERR Protocol error"),
+            "{:?} answered with {:?}",
+            String::from_utf8_lossy(garbage),
+            String::from_utf8_lossy(&reply)
+        );
+    }
+
+    // a client hanging up mid-frame is just gone
+    let mut stream = common::raw(redis.addr()).await;
+    stream.write_all(b"*2\r\n$3\r\nSET\r\n$1").await.unwrap();
+    drop(stream);
+
+    assert_eq!(request(&mut bystander, ["GET", "k"]).await, bulk("v"));
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn answers_what_redis_benchmark_sends() {
+    let redis = common::start().await;
+    let mut client = common::client(redis.addr()).await;
+
+    // asked once at startup
+    assert_eq!(
+        request(&mut client, ["CONFIG", "GET", "save"]).await,
+        RedisValue::Array(vec![bulk("save"), bulk("")])
+    );
+    assert_eq!(
+        request(&mut client, ["CONFIG", "GET", "appendonly"]).await,
+        RedisValue::Array(vec![bulk("appendonly"), bulk("no")])
+    );
+
+    // the PING_INLINE test, and a pipeline of it
+    let mut stream = common::raw(redis.addr()).await;
+    common::exchange(&mut stream, b"PING\r\n", b"+PONG\r\n").await;
+    common::exchange(&mut stream, b"PING\r\nPING\r\n", b"+PONG\r\n+PONG\r\n").await;
+
+    assert_eq!(
+        request(&mut client, ["INCR", "counter:__rand_int__"]).await,
+        RedisValue::Integer(1)
+    );
+    assert_eq!(
+        request(&mut client, ["DECRBY", "counter:__rand_int__", "3"]).await,
+        RedisValue::Integer(-2)
+    );
+    for i in 0..3 {
+        request(&mut client, ["LPUSH", "mylist", &i.to_string()]).await;
+    }
+    request(&mut client, ["RPUSH", "mylist", "last"]).await;
+    assert_eq!(
+        request(&mut client, ["LRANGE", "mylist", "0", "99"]).await,
+        RedisValue::Array(vec![bulk("2"), bulk("1"), bulk("0"), bulk("last")])
+    );
+    assert_eq!(
+        request(&mut client, ["LRANGE", "mylist", "-2", "-1"]).await,
+        RedisValue::Array(vec![bulk("0"), bulk("last")])
+    );
+
+    // mistakes are answered like Redis does
+    request(&mut client, ["SET", "word", "abc"]).await;
+    assert_eq!(
+        request(&mut client, ["INCR", "word"]).await,
+        RedisValue::SimpleError("ERR value is not an integer or out of range".into())
+    );
+    assert!(matches!(
+        request(&mut client, ["INCR", "mylist"]).await,
+        RedisValue::SimpleError(e) if e.starts_with(b"WRONGTYPE")
+    ));
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn answers_redis_benchmarks_set_hash_and_sorted_set_tests() {
+    let redis = common::start().await;
+    let mut client = common::client(redis.addr()).await;
+
+    // each default test but XADD, as redis-benchmark sends it; the PING_INLINE and LRANGE tests
+    // are in answers_what_redis_benchmark_sends
+    assert_eq!(
+        request(&mut client, ["PING"]).await,
+        RedisValue::SimpleString("PONG".into())
+    );
+    assert_eq!(
+        request(&mut client, ["SET", "key:__rand_int__", "xxx"]).await,
+        ok()
+    );
+    assert_eq!(
+        request(&mut client, ["GET", "key:__rand_int__"]).await,
+        bulk("xxx")
+    );
+    assert_eq!(
+        request(&mut client, ["INCR", "counter:__rand_int__"]).await,
+        RedisValue::Integer(1)
+    );
+    assert_eq!(
+        request(&mut client, ["LPUSH", "mylist", "xxx"]).await,
+        RedisValue::Integer(1)
+    );
+    assert_eq!(
+        request(&mut client, ["RPUSH", "mylist", "yyy"]).await,
+        RedisValue::Integer(2)
+    );
+    assert_eq!(request(&mut client, ["LPOP", "mylist"]).await, bulk("xxx"));
+    assert_eq!(request(&mut client, ["RPOP", "mylist"]).await, bulk("yyy"));
+    assert_eq!(
+        request(&mut client, ["SADD", "myset", "element:__rand_int__"]).await,
+        RedisValue::Integer(1)
+    );
+    assert_eq!(
+        request(
+            &mut client,
+            ["HSET", "myhash", "element:__rand_int__", "xxx"]
+        )
+        .await,
+        RedisValue::Integer(1)
+    );
+    assert_eq!(
+        request(&mut client, ["SPOP", "myset"]).await,
+        bulk("element:__rand_int__")
+    );
+    assert_eq!(
+        request(&mut client, ["ZADD", "myzset", "0", "element:__rand_int__"]).await,
+        RedisValue::Integer(1)
+    );
+    assert_eq!(
+        request(&mut client, ["ZPOPMIN", "myzset"]).await,
+        RedisValue::Array(vec![bulk("element:__rand_int__"), bulk("0")])
+    );
+    let mset: [&str; 21] = std::array::from_fn(|i| match i {
+        0 => "MSET",
+        _ if i % 2 == 1 => "key:__rand_int__",
+        _ => "xxx",
+    });
+    assert_eq!(request(&mut client, mset).await, ok());
+    request(
+        &mut client,
+        ["DEL", "key:__rand_int__", "counter:__rand_int__", "myhash"],
+    )
+    .await;
+
+    assert_eq!(
+        request(&mut client, ["SADD", "myset", "a", "b", "a"]).await,
+        RedisValue::Integer(2)
+    );
+    assert_eq!(
+        request(&mut client, ["SCARD", "myset"]).await,
+        RedisValue::Integer(2)
+    );
+    let RedisValue::BulkString(popped) = request(&mut client, ["SPOP", "myset"]).await else {
+        panic!("SPOP should reply with a member");
+    };
+    assert!(popped == "a" || popped == "b");
+    request(&mut client, ["SPOP", "myset"]).await;
+    assert_eq!(
+        request(&mut client, ["SPOP", "myset"]).await,
+        RedisValue::NullBulkString
+    );
+
+    assert_eq!(
+        request(&mut client, ["HSET", "myhash", "f", "1", "g", "2"]).await,
+        RedisValue::Integer(2)
+    );
+    assert_eq!(
+        request(&mut client, ["HSET", "myhash", "f", "3"]).await,
+        RedisValue::Integer(0)
+    );
+    assert_eq!(
+        request(&mut client, ["HGET", "myhash", "f"]).await,
+        bulk("3")
+    );
+
+    assert_eq!(
+        request(&mut client, ["ZADD", "myzset", "1", "a", "2", "b"]).await,
+        RedisValue::Integer(2)
+    );
+    assert_eq!(
+        request(&mut client, ["ZADD", "myzset", "NX", "CH", "5", "a"]).await,
+        RedisValue::Integer(0)
+    );
+    assert_eq!(
+        request(&mut client, ["ZADD", "myzset", "XX", "CH", "5", "a"]).await,
+        RedisValue::Integer(1)
+    );
+    assert_eq!(
+        request(&mut client, ["ZADD", "myzset", "NX", "XX", "1", "a"]).await,
+        RedisValue::SimpleError("ERR XX and NX options at the same time are not compatible".into())
+    );
+    assert_eq!(
+        request(&mut client, ["ZSCORE", "myzset", "a"]).await,
+        bulk("5")
+    );
+    assert_eq!(
+        request(&mut client, ["ZCARD", "myzset"]).await,
+        RedisValue::Integer(2)
+    );
+    assert_eq!(
+        request(&mut client, ["ZPOPMIN", "myzset"]).await,
+        RedisValue::Array(vec![bulk("b"), bulk("2")])
+    );
+    assert_eq!(
+        request(&mut client, ["ZPOPMIN", "myzset", "5"]).await,
+        RedisValue::Array(vec![bulk("a"), bulk("5")])
+    );
+    assert_eq!(
+        request(&mut client, ["ZPOPMIN", "myzset"]).await,
+        RedisValue::Array(vec![])
+    );
+    assert_eq!(
+        request(&mut client, ["ZPOPMIN", "myzset", "-1"]).await,
+        RedisValue::SimpleError("ERR value is out of range, must be positive".into())
+    );
+    request(&mut client, ["ZADD", "myzset", "1", "a"]).await;
+
+    // types are kept apart
+    assert!(matches!(
+        request(&mut client, ["SADD", "myhash", "x"]).await,
+        RedisValue::SimpleError(e) if e.starts_with(b"WRONGTYPE")
+    ));
+    assert!(matches!(
+        request(&mut client, ["GET", "myzset"]).await,
+        RedisValue::SimpleError(e) if e.starts_with(b"WRONGTYPE")
+    ));
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn expire_and_ttl_follow_the_clock() {
+    let clock = Arc::new(MockClock::default());
+    let redis = common::start_with_clock(clock.clone()).await;
+    let mut client = common::client(redis.addr()).await;
+
+    assert_eq!(
+        request(&mut client, ["SET", "k", "v", "EX", "10", "PX", "100"]).await,
+        RedisValue::SimpleError("ERR syntax error".into())
+    );
+    request(&mut client, ["SET", "k", "v"]).await;
+    assert_eq!(
+        request(&mut client, ["TTL", "k"]).await,
+        RedisValue::Integer(-1)
+    );
+    assert_eq!(
+        request(&mut client, ["EXPIRE", "k", "10"]).await,
+        RedisValue::Integer(1)
+    );
+    assert_eq!(
+        request(&mut client, ["TTL", "k"]).await,
+        RedisValue::Integer(10)
+    );
+    clock.advance(Duration::from_millis(2500));
+    assert_eq!(
+        request(&mut client, ["PTTL", "k"]).await,
+        RedisValue::Integer(7500)
+    );
+    clock.advance(Duration::from_millis(7500));
+    assert_eq!(
+        request(&mut client, ["TTL", "k"]).await,
+        RedisValue::Integer(-2)
+    );
+    assert_eq!(
+        request(&mut client, ["EXPIRE", "k", "10"]).await,
+        RedisValue::Integer(0)
+    );
+
+    // a set expires like any other key, and at once when told to
+    request(&mut client, ["SADD", "s", "a"]).await;
+    assert_eq!(
+        request(&mut client, ["EXPIRE", "s", "0"]).await,
+        RedisValue::Integer(1)
+    );
+    assert_eq!(
+        request(&mut client, ["SCARD", "s"]).await,
+        RedisValue::Integer(0)
+    );
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn command_getkeys_reads_the_key_positions() {
+    let redis = common::start().await;
+    let mut client = common::client(redis.addr()).await;
+
+    assert_eq!(
+        request(
+            &mut client,
+            ["COMMAND", "GETKEYS", "MSET", "a", "1", "b", "2"]
+        )
+        .await,
+        RedisValue::Array(vec![bulk("a"), bulk("b")])
+    );
+    let flagged = |key: &str, flags: &[&str]| {
+        RedisValue::Array(vec![
+            bulk(key),
+            RedisValue::Array(
+                flags
+                    .iter()
+                    .map(|flag| RedisValue::SimpleString(flag.to_string().into()))
+                    .collect(),
+            ),
+        ])
+    };
+    assert_eq!(
+        request(&mut client, ["command", "getkeysandflags", "get", "k"]).await,
+        RedisValue::Array(vec![flagged("k", &["RO", "access"])])
+    );
+
+    let error = |message: &str| RedisValue::SimpleError(format!("ERR {message}").into());
+    assert_eq!(
+        request(&mut client, ["COMMAND", "GETKEYS", "NOPE", "k"]).await,
+        error("Invalid command specified")
+    );
+    assert_eq!(
+        request(&mut client, ["COMMAND", "GETKEYS", "GET", "a", "b"]).await,
+        error("Invalid number of arguments specified for command")
+    );
+    assert_eq!(
+        request(&mut client, ["COMMAND", "GETKEYS", "PING"]).await,
+        error("The command has no key arguments")
+    );
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn far_future_expirations_keep_the_expirers_running() {
+    let redis = common::start().await;
+    let mut client = common::client(redis.addr()).await;
+    for key in ["a", "b", "c"] {
+        request(&mut client, ["SET", key, "1"]).await;
+        let at = u64::MAX.to_string();
+        assert_eq!(
+            request(&mut client, ["PEXPIREAT", key, &at]).await,
+            RedisValue::Integer(1)
+        );
+    }
+    // a few active expire cycles, each sampling the keys
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    let RedisValue::BulkString(info) = request(&mut client, ["INFO", "tasks"]).await else {
+        panic!("INFO is not a bulk string");
+    };
+    let info = String::from_utf8_lossy(&info);
+    for task in ["task_active-expire:", "task_expirer:"] {
+        let line = info.lines().find(|line| line.starts_with(task)).unwrap();
+        assert!(line.ends_with(",restarts=0"), "{line}");
+    }
+    assert_eq!(request(&mut client, ["GET", "a"]).await, bulk("1"));
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn serves_clients_over_tls() {
+    let dir = std::env::temp_dir().join(format!("e2e-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let certified = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+    let (cert_file, key_file) = (dir.join("redis.crt"), dir.join("redis.key"));
+    std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.signing_key.serialize_pem()).unwrap();
+    // 0 turns TLS off, so borrow a port that was free a moment ago
+    let tls_port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let redis = common::builder()
+        .option("tls-port", tls_port.to_string())
+        .option("tls-cert-file", cert_file.to_str().unwrap())
+        .option("tls-key-file", key_file.to_str().unwrap())
+        .start()
+        .await
+        .unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let stream = tokio::net::TcpStream::connect(("127.0.0.1", tls_port))
+        .await
+        .unwrap();
+    let stream = TlsConnector::from(Arc::new(tls))
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    let mut secure = Client::new(stream);
+    assert_eq!(
+        request(&mut secure, ["PING"]).await,
+        RedisValue::SimpleString("PONG".into())
+    );
+    assert_eq!(request(&mut secure, ["SET", "k", "v"]).await, ok());
+
+    // the plaintext port serves the same data alongside
+    let mut plain = common::client(redis.addr()).await;
+    assert_eq!(request(&mut plain, ["GET", "k"]).await, bulk("v"));
+    // and plaintext on the TLS port fails the handshake
+    let mut raw = common::raw(([127, 0, 0, 1], tls_port).into()).await;
+    raw.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+    assert!(!common::read_to_close(&mut raw).await.starts_with(b"+PONG"));
+
+    redis.stop().await.unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn heavy_commands_leave_other_clients_served() {
+    let redis = common::start().await;
+    let addr = redis.addr();
+    let mut client = common::client(addr).await;
+    for batch in 0..40 {
+        let mut mset = vec!["MSET".to_string()];
+        for i in 0..5000 {
+            mset.extend([format!("key:{batch}:{i}"), "v".to_string()]);
+        }
+        assert_eq!(client.request(&mset).await.unwrap(), ok());
+    }
+    for i in 0..5000 {
+        request(&mut client, ["RPUSH", "biglist", &i.to_string()]).await;
+    }
+
+    // one worker thread serves every client here, so they only get answers while KEYS runs if
+    // it runs elsewhere
+    let scan = tokio::spawn(async move {
+        let mut scanner = common::client(addr).await;
+        request(&mut scanner, ["KEYS", "key:1?:*"]).await
+    });
+    let running = loop {
+        let RedisValue::BulkString(info) = request(&mut client, ["INFO", "tasks"]).await else {
+            panic!("INFO should reply with a bulk string");
+        };
+        if String::from_utf8_lossy(&info).contains("task_keys:spawned=1,alive=1,") {
+            break true;
+        }
+        if scan.is_finished() {
+            break false;
+        }
+    };
+    assert!(running, "KEYS ran without leaving other clients served");
+    assert_eq!(
+        request(&mut client, ["PING"]).await,
+        RedisValue::SimpleString("PONG".into())
+    );
+    let RedisValue::Array(keys) = scan.await.unwrap() else {
+        panic!("KEYS should reply with an array");
+    };
+    assert_eq!(keys.len(), 50_000);
+
+    assert_eq!(
+        request(&mut client, ["DEL", "biglist", "key:0:0"]).await,
+        RedisValue::Integer(2)
+    );
+    assert_eq!(request(&mut client, ["FLUSHALL", "SYNC"]).await, ok());
+    assert_eq!(
+        request(&mut client, ["KEYS", "*"]).await,
+        RedisValue::Array(vec![])
+    );
+    let RedisValue::BulkString(info) = request(&mut client, ["INFO", "tasks"]).await else {
+        panic!("INFO should reply with a bulk string");
+    };
+    let info = String::from_utf8_lossy(&info);
+    assert!(info.contains("task_del:spawned=1,alive=0,"));
+    assert!(info.contains("task_flushall:spawned=1,alive=0,"));
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn set_rejects_expirations_past_the_end_of_time() {
+    let redis = common::start().await;
+    let mut client = common::client(redis.addr()).await;
+    assert_eq!(
+        request(&mut client, ["SET", "k", "v", "EX", "18446744073709551"]).await,
+        RedisValue::SimpleError("ERR invalid expire time in 'set' command".into())
+    );
+    assert_eq!(
+        request(&mut client, ["GET", "k"]).await,
+        RedisValue::NullBulkString
+    );
+    request(&mut client, ["SET", "k", "v", "PX", "100000"]).await;
+    assert_eq!(request(&mut client, ["GET", "k"]).await, bulk("v"));
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn info_reports_the_keyspace() {
+    let redis = common::start().await;
+    let mut client = common::client(redis.addr()).await;
+    assert_eq!(
+        request(&mut client, ["INFO", "keyspace"]).await,
+        bulk("# Keyspace\r\n")
+    );
+    request(&mut client, ["SET", "a", "1"]).await;
+    request(&mut client, ["SET", "b", "1", "EX", "100"]).await;
+    request(&mut client, ["RPUSH", "l", "x"]).await;
+    request(&mut client, ["PEXPIREAT", "l", "99999999999999"]).await;
+    let RedisValue::BulkString(info) = request(&mut client, ["INFO"]).await else {
+        panic!("INFO is not a bulk string");
+    };
+    let info = String::from_utf8_lossy(&info);
+    assert!(
+        info.contains("\r\n# Keyspace\r\ndb0:keys=3,expires=2,avg_ttl="),
+        "{info}"
+    );
+
+    request(&mut client, ["GET", "a"]).await;
+    request(&mut client, ["LRANGE", "l", "0", "-1"]).await;
+    request(&mut client, ["GET", "missing"]).await;
+    let RedisValue::BulkString(info) = request(&mut client, ["INFO", "stats"]).await else {
+        panic!("INFO is not a bulk string");
+    };
+    let info = String::from_utf8_lossy(&info);
+    assert!(
+        info.contains("\r\nkeyspace_hits:2\r\nkeyspace_misses:1\r\n"),
+        "{info}"
+    );
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn hot_keys_are_sampled() {
+    let redis = common::builder()
+        .option("hotkeys-sample-rate", "1")
+        .start()
+        .await
+        .unwrap();
+    let mut client = common::client(redis.addr()).await;
+    request(&mut client, ["SET", "hot", "1"]).await;
+    for _ in 0..50 {
+        request(&mut client, ["GET", "hot"]).await;
+    }
+    request(&mut client, ["MGET", "hot", "cold"]).await;
+    assert_eq!(
+        request(&mut client, ["HOTKEYS", "GET", "COUNT", "1"]).await,
+        RedisValue::Array(vec![bulk("hot"), RedisValue::Integer(52)])
+    );
+    assert_eq!(request(&mut client, ["HOTKEYS", "RESET"]).await, ok());
+    assert_eq!(
+        request(&mut client, ["HOTKEYS", "GET"]).await,
+        RedisValue::Array(vec![])
+    );
+    redis.stop().await.unwrap();
+
+    // off unless asked for
+    let redis = common::start().await;
+    let mut client = common::client(redis.addr()).await;
+    assert!(matches!(
+        request(&mut client, ["HOTKEYS", "GET"]).await,
+        RedisValue::SimpleError(_)
+    ));
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn requirepass_needs_auth() {
+    let redis = common::builder()
+        .option("requirepass", "secret")
+        .start()
+        .await
+        .unwrap();
+    let mut client = common::client(redis.addr()).await;
+    let error = |e: &str| RedisValue::SimpleError(e.to_string().into());
+    assert_eq!(
+        request(&mut client, ["GET", "k"]).await,
+        error("NOAUTH Authentication required.")
+    );
+    assert_eq!(
+        request(&mut client, ["AUTH", "wrong"]).await,
+        error("WRONGPASS invalid username-password pair or user is disabled.")
+    );
+    assert_eq!(
+        request(&mut client, ["AUTH", "someone", "secret"]).await,
+        error("WRONGPASS invalid username-password pair or user is disabled.")
+    );
+    assert_eq!(
+        request(&mut client, ["AUTH", "default", "secret"]).await,
+        ok()
+    );
+    assert_eq!(request(&mut client, ["SET", "k", "v"]).await, ok());
+
+    let mut other = common::client(redis.addr()).await;
+    assert_eq!(request(&mut other, ["AUTH", "secret"]).await, ok());
+    assert_eq!(request(&mut other, ["GET", "k"]).await, bulk("v"));
+    redis.stop().await.unwrap();
+
+    let redis = common::start().await;
+    let mut client = common::client(redis.addr()).await;
+    assert!(matches!(
+        request(&mut client, ["AUTH", "secret"]).await,
+        RedisValue::SimpleError(e) if e.starts_with(b"ERR AUTH <password> called without")
+    ));
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn blocked_pops_are_served_in_order() {
+    let redis = common::start().await;
+    let addr = redis.addr();
+    let mut pusher = common::client(addr).await;
+
+    let mut waiters = Vec::new();
+    for blocked in 1..=2 {
+        waiters.push(tokio::spawn(async move {
+            let mut client = common::client(addr).await;
+            request(&mut client, ["BLPOP", "empty", "queue", "0"]).await
+        }));
+        // wait for each to block, so they are queued in a known order
+        loop {
+            let RedisValue::BulkString(info) = request(&mut pusher, ["INFO", "clients"]).await
+            else {
+                panic!("INFO is not a bulk string");
+            };
+            if String::from_utf8_lossy(&info).contains(&format!("blocked_clients:{blocked}")) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    assert_eq!(
+        request(&mut pusher, ["RPUSH", "queue", "a", "b", "c"]).await,
+        RedisValue::Integer(3)
+    );
+    for (waiter, element) in waiters.into_iter().zip(["a", "b"]) {
+        assert_eq!(
+            waiter.await.unwrap(),
+            RedisValue::Array(vec![bulk("queue"), bulk(element)])
+        );
+    }
+    assert_eq!(
+        request(&mut pusher, ["LRANGE", "queue", "0", "-1"]).await,
+        RedisValue::Array(vec![bulk("c")])
+    );
+    assert_eq!(
+        request(&mut pusher, ["BRPOP", "empty", "0.01"]).await,
+        RedisValue::NullArray
+    );
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn embedders_watch_key_changes() {
+    let clock = Arc::new(MockClock::default());
+    let redis = common::start_with_clock(clock.clone()).await;
+    let mut client = common::client(redis.addr()).await;
+    let mut events = redis.keyspace().watch_prefix("user:");
+
+    request(&mut client, ["SET", "user:1", "ada"]).await;
+    request(&mut client, ["SET", "session:1", "x"]).await;
+    request(&mut client, ["RPUSH", "user:list", "a"]).await;
+    request(&mut client, ["LPOP", "user:list"]).await;
+    request(&mut client, ["DEL", "user:1", "user:missing"]).await;
+    request(&mut client, ["SET", "user:2", "bob", "PX", "100"]).await;
+    clock.advance(Duration::from_millis(100));
+
+    let expected = [
+        KeyEvent::Set("user:1".into()),
+        KeyEvent::Set("user:list".into()),
+        KeyEvent::Deleted("user:list".into()),
+        KeyEvent::Deleted("user:1".into()),
+        KeyEvent::Set("user:2".into()),
+        KeyEvent::Expired("user:2".into()),
+    ];
+    for event in expected {
+        let next = tokio::time::timeout(Duration::from_secs(5), events.next()).await;
+        assert_eq!(next.unwrap(), Some(event));
+    }
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn sentinel_can_monitor_and_say_hello() {
+    let master = common::start().await;
+    let mut client = common::client(master.addr()).await;
+
+    // sentinels find each other through the hello channel
+    let mut sentinel = common::client(master.addr()).await;
+    assert_eq!(
+        request(&mut sentinel, ["SUBSCRIBE", "__sentinel__:hello"]).await,
+        RedisValue::Array(vec![
+            bulk("subscribe"),
+            bulk("__sentinel__:hello"),
+            RedisValue::Integer(1)
+        ])
+    );
+    assert_eq!(
+        request(
+            &mut client,
+            ["PUBLISH", "__sentinel__:hello", "127.0.0.1,26379"]
+        )
+        .await,
+        RedisValue::Integer(1)
+    );
+    let message = tokio::time::timeout(common::TIMEOUT, sentinel.receive()).await;
+    assert_eq!(
+        message.unwrap().unwrap(),
+        Some(RedisValue::Array(vec![
+            bulk("message"),
+            bulk("__sentinel__:hello"),
+            bulk("127.0.0.1,26379")
+        ]))
+    );
+    // a RESP2 subscriber can't run other commands
+    assert!(matches!(
+        request(&mut sentinel, ["GET", "k"]).await,
+        RedisValue::SimpleError(_)
+    ));
+    assert_eq!(
+        request(&mut sentinel, ["UNSUBSCRIBE"]).await,
+        RedisValue::Array(vec![
+            bulk("unsubscribe"),
+            bulk("__sentinel__:hello"),
+            RedisValue::Integer(0)
+        ])
+    );
+    assert_eq!(
+        request(&mut sentinel, ["GET", "k"]).await,
+        RedisValue::NullBulkString
+    );
+
+    let replica = common::builder()
+        .replicaof("127.0.0.1", master.addr().port())
+        .option("replica-announce-ip", "10.1.2.3")
+        .option("replica-priority", "7")
+        .start()
+        .await
+        .unwrap();
+
+    // the replica is listed at the address it announced
+    let info = loop {
+        let RedisValue::BulkString(info) = request(&mut client, ["INFO", "replication"]).await
+        else {
+            panic!("INFO is not a bulk string");
+        };
+        let info = String::from_utf8_lossy(&info).into_owned();
+        if info.contains("connected_slaves:1") {
+            break info;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert!(info.contains("slave0:ip=10.1.2.3,"), "{info}");
+    let RedisValue::Array(role) = request(&mut client, ["ROLE"]).await else {
+        panic!("ROLE is not an array");
+    };
+    assert_eq!(role[0], bulk("master"));
+    let RedisValue::Array(replicas) = &role[2] else {
+        panic!("no replica list");
+    };
+    assert!(matches!(&replicas[0], RedisValue::Array(r) if r[0] == bulk("10.1.2.3")));
+
+    let mut monitored = common::client(replica.addr()).await;
+    let RedisValue::BulkString(info) = request(&mut monitored, ["INFO"]).await else {
+        panic!("INFO is not a bulk string");
+    };
+    let info = String::from_utf8_lossy(&info);
+    assert!(info.contains("role:slave") && info.contains("slave_priority:7"));
+    assert!(info.contains("run_id:"));
+    replica.stop().await.unwrap();
+    master.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn replicas_apply_the_effects_of_time_dependent_writes() {
+    let clock = Arc::new(MockClock::default());
+    let master = common::builder()
+        .clock(clock.clone())
+        .start()
+        .await
+        .unwrap();
+    let replica = common::builder()
+        .clock(clock.clone())
+        .replicaof("127.0.0.1", master.addr().port())
+        .start()
+        .await
+        .unwrap();
+    let mut client = common::client(master.addr()).await;
+    let replicated = |key: &'static str, present: bool| {
+        let keyspace = replica.keyspace().clone();
+        async move {
+            tokio::time::timeout(common::TIMEOUT, async {
+                while keyspace.exists(key) != present {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .expect("replica never caught up");
+        }
+    };
+
+    // the deadline is the master's, however late the replica applies it
+    request(&mut client, ["SET", "session", "x", "PX", "5000"]).await;
+    replicated("session", true).await;
+    assert_eq!(
+        replica.keyspace().ttl("session"),
+        Some(Duration::from_millis(5000))
+    );
+
+    // a deadline already passed removes the key everywhere
+    request(&mut client, ["SET", "stale", "x"]).await;
+    replicated("stale", true).await;
+    assert_eq!(
+        request(&mut client, ["PEXPIREAT", "stale", "1"]).await,
+        RedisValue::Integer(1)
+    );
+    replicated("stale", false).await;
+
+    replica.stop().await.unwrap();
+    master.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn replicas_without_a_master_refuse_stale_reads() {
+    // nothing listens where the master should be
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let replica = common::builder()
+        .replicaof("127.0.0.1", port)
+        .option("replica-serve-stale-data", "no")
+        .start()
+        .await
+        .unwrap();
+    let mut client = common::client(replica.addr()).await;
+
+    let master_down = RedisValue::SimpleError(
+        "MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.".into(),
+    );
+    assert_eq!(request(&mut client, ["GET", "k"]).await, master_down);
+    assert_eq!(request(&mut client, ["PING"]).await, master_down);
+    let RedisValue::BulkString(info) = request(&mut client, ["INFO", "replication"]).await else {
+        panic!("INFO is not a bulk string");
+    };
+    assert!(String::from_utf8_lossy(&info).contains("master_link_status:down"));
+
+    // once promoted, there is no master to be out of sync with
+    assert_eq!(request(&mut client, ["REPLICAOF", "NO", "ONE"]).await, ok());
+    assert_eq!(
+        request(&mut client, ["GET", "k"]).await,
+        RedisValue::NullBulkString
+    );
+    replica.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn masters_ping_idle_replicas() {
+    let master = common::builder()
+        .option("repl-ping-replica-period", "1")
+        .start()
+        .await
+        .unwrap();
+    let mut replica = common::raw(master.addr()).await;
+    replica
+        .write_all(
+            b"*1\r\n$4\r\nPING\r\n\
+              *3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n6380\r\n\
+              *3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n",
+        )
+        .await
+        .unwrap();
+
+    // with nothing written, the stream still carries a PING now and then
+    let ping = b"*1\r\n$4\r\nPING\r\n";
+    let mut received = Vec::new();
+    tokio::time::timeout(common::TIMEOUT, async {
+        while !received.windows(ping.len()).any(|w| w == ping) {
+            let mut chunk = [0; 1024];
+            let n = replica.read(&mut chunk).await.unwrap();
+            assert!(n > 0, "master hung up");
+            received.extend_from_slice(&chunk[..n]);
+        }
+    })
+    .await
+    .expect("no PING in time");
+}
+
+#[tokio::test]
+async fn replicas_drop_a_silent_master() {
+    let fake = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let replica = common::builder()
+        .replicaof("127.0.0.1", fake.local_addr().unwrap().port())
+        .option("repl-timeout", "1")
+        .start()
+        .await
+        .unwrap();
+
+    // answer the handshake, then say nothing more
+    let (stream, _) = fake.accept().await.unwrap();
+    let mut link = Framed::new(stream, RespFrame::default());
+    for reply in ["PONG", "OK", "OK", "CONTINUE"] {
+        link.next().await.unwrap().unwrap();
+        link.send(RedisValue::SimpleString(reply.into()))
+            .await
+            .unwrap();
+    }
+    let mut client = common::client(replica.addr()).await;
+    let link_status = |info: RedisValue| match info {
+        RedisValue::BulkString(info) => String::from_utf8_lossy(&info)
+            .lines()
+            .find_map(|line| line.strip_prefix("master_link_status:").map(str::to_owned)),
+        other => panic!("INFO is not a bulk string: {other:?}"),
+    };
+    assert_eq!(
+        link_status(request(&mut client, ["INFO", "replication"]).await).as_deref(),
+        Some("up")
+    );
+
+    // the replica hangs up on us and comes back for another try
+    let closed = tokio::time::timeout(common::TIMEOUT, link.next()).await;
+    assert!(matches!(closed, Ok(None)), "link still open");
+    while link_status(request(&mut client, ["INFO", "replication"]).await).as_deref()
+        != Some("down")
+    {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    tokio::time::timeout(common::TIMEOUT, fake.accept())
+        .await
+        .expect("no reconnection in time")
+        .unwrap();
+    replica.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn replicas_share_a_diskless_sync() {
+    // the delay is long enough that the test only passes if both replicas share one sync
+    let master = common::builder()
+        .option("repl-diskless-sync", "yes")
+        .option("repl-diskless-sync-delay", "60")
+        .option("repl-diskless-sync-max-replicas", "2")
+        .start()
+        .await
+        .unwrap();
+    let mut client = common::client(master.addr()).await;
+    for i in 0..1000 {
+        let key = format!("key:{i}");
+        request(&mut client, ["SET", key.as_str(), "value"]).await;
+    }
+    let start_replica = || {
+        common::builder()
+            .replicaof("127.0.0.1", master.addr().port())
+            .start()
+    };
+    let (first, second) = tokio::join!(start_replica(), start_replica());
+    let replicas = [first.unwrap(), second.unwrap()];
+
+    request(&mut client, ["SET", "after", "sync"]).await;
+    for replica in &replicas {
+        let keyspace = replica.keyspace();
+        tokio::time::timeout(common::TIMEOUT, async {
+            while !keyspace.exists("after") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("replica never synced");
+        assert!(keyspace.exists("key:0") && keyspace.exists("key:999"));
+    }
+
+    for replica in replicas {
+        replica.stop().await.unwrap();
+    }
+    master.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn the_append_only_file_survives_restarts_and_rewrites() {
+    let dir = std::env::temp_dir().join(format!("e2e-aof-{}", std::process::id()));
+    let start = |dir: std::path::PathBuf| async move {
+        common::builder()
+            .dir(dir)
+            .option("appendonly", "yes")
+            .start()
+            .await
+            .unwrap()
+    };
+
+    let redis = start(dir.clone()).await;
+    let mut client = common::client(redis.addr()).await;
+    request(&mut client, ["SET", "a", "1"]).await;
+    request(&mut client, ["RPUSH", "list", "x", "y"]).await;
+    request(&mut client, ["SET", "gone", "x"]).await;
+    request(&mut client, ["DEL", "gone"]).await;
+    assert_eq!(
+        request(&mut client, ["BGREWRITEAOF"]).await,
+        RedisValue::SimpleString("Background append only file rewriting started".into())
+    );
+    // written while the rewrite runs, so kept in the incremental file it started
+    request(&mut client, ["SET", "b", "2"]).await;
+    let manifest = dir.join("appendonlydir/appendonly.aof.manifest");
+    tokio::time::timeout(common::TIMEOUT, async {
+        while !std::fs::read_to_string(&manifest)
+            .unwrap()
+            .starts_with("file appendonly.aof.2.base.rdb")
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("rewrite never finished");
+    redis.stop().await.unwrap();
+
+    let redis = start(dir.clone()).await;
+    let mut client = common::client(redis.addr()).await;
+    assert_eq!(request(&mut client, ["GET", "a"]).await, bulk("1"));
+    assert_eq!(request(&mut client, ["GET", "b"]).await, bulk("2"));
+    assert_eq!(
+        request(&mut client, ["LRANGE", "list", "0", "-1"]).await,
+        RedisValue::Array(vec![bulk("x"), bulk("y")])
+    );
+    assert!(!redis.keyspace().exists("gone"));
+    redis.stop().await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn loads_an_append_only_file_written_by_redis() {
+    let dir = std::env::temp_dir().join(format!("e2e-redis-aof-{}", std::process::id()));
+    let aof_dir = dir.join("appendonlydir");
+    std::fs::create_dir_all(&aof_dir).unwrap();
+    // a base of commands, as written with aof-use-rdb-preamble off, and an incremental file
+    // starting with the SELECT Redis always writes first
+    std::fs::write(
+        aof_dir.join("appendonly.aof.manifest"),
+        "file appendonly.aof.1.base.aof seq 1 type b\n\
+         file appendonly.aof.1.incr.aof seq 1 type i\n",
+    )
+    .unwrap();
+    std::fs::write(
+        aof_dir.join("appendonly.aof.1.base.aof"),
+        "*3\r\n$3\r\nSET\r\n$4\r\nbase\r\n$1\r\nx\r\n",
+    )
+    .unwrap();
+    std::fs::write(
+        aof_dir.join("appendonly.aof.1.incr.aof"),
+        "*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*3\r\n$3\r\nSET\r\n$4\r\nincr\r\n$1\r\ny\r\n",
+    )
+    .unwrap();
+
+    let redis = common::builder()
+        .dir(&dir)
+        .option("appendonly", "yes")
+        .start()
+        .await
+        .unwrap();
+    let mut client = common::client(redis.addr()).await;
+    assert_eq!(request(&mut client, ["GET", "base"]).await, bulk("x"));
+    assert_eq!(request(&mut client, ["GET", "incr"]).await, bulk("y"));
+    redis.stop().await.unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn fails_to_start_on_a_corrupt_dataset() {
+    let dir = std::env::temp_dir().join(format!("e2e-corrupt-rdb-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("dump.rdb"), "not an rdb file").unwrap();
+
+    let started = common::builder()
+        .dir(&dir)
+        .dbfilename("dump.rdb")
+        .start()
+        .await;
+    let e = started.expect_err("started on a corrupt dataset");
+    assert!(e.to_string().contains("Failed loading RDB file"), "{e}");
+    // nothing was saved over it
+    assert_eq!(
+        std::fs::read(dir.join("dump.rdb")).unwrap(),
+        b"not an rdb file"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn slots_move_between_running_nodes() {
+    let start = || common::builder().option("cluster-enabled", "yes").start();
+    let nodes = [start().await.unwrap(), start().await.unwrap()];
+    let mut clients = [
+        common::client(nodes[0].addr()).await,
+        common::client(nodes[1].addr()).await,
+    ];
+    let bus_port = cluster_port(&mut clients[1]).await;
+    let port = nodes[1].addr().port().to_string();
+    assert_eq!(
+        request(
+            &mut clients[0],
+            ["CLUSTER", "MEET", "127.0.0.1", &port, &bus_port]
+        )
+        .await,
+        ok()
+    );
+
+    // both started out owning every slot, and settle on one of them
+    let owner = loop {
+        let slots = [
+            request(&mut clients[0], ["CLUSTER", "SLOTS"]).await,
+            request(&mut clients[1], ["CLUSTER", "SLOTS"]).await,
+        ];
+        if let RedisValue::Array(ranges) = &slots[0]
+            && slots[0] == slots[1]
+            && cluster_nodes(&mut clients[0]).await.lines().count() == 2
+            && let [RedisValue::Array(range)] = &ranges[..]
+            && let RedisValue::Array(node) = &range[2]
+        {
+            break node[1].clone();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let ([mut source, mut target], [mut a, mut b]) = (nodes, clients);
+    if RedisValue::Integer(target.addr().port() as i64) == owner {
+        std::mem::swap(&mut source, &mut target);
+        std::mem::swap(&mut a, &mut b);
+    }
+    let (a_port, b_port) = (
+        source.addr().port().to_string(),
+        target.addr().port().to_string(),
+    );
+    let id = |reply: RedisValue| match reply {
+        RedisValue::BulkString(id) => String::from_utf8(id.to_vec()).unwrap(),
+        other => panic!("expected an ID, got {other:?}"),
+    };
+    let a_id = id(request(&mut a, ["CLUSTER", "MYID"]).await);
+    let b_id = id(request(&mut b, ["CLUSTER", "MYID"]).await);
+    let RedisValue::Integer(slot) = request(&mut a, ["CLUSTER", "KEYSLOT", "{x}"]).await else {
+        panic!("expected a slot");
+    };
+    let slot = slot.to_string();
+    let error = |e: String| RedisValue::SimpleError(e.into());
+
+    request(&mut a, ["SET", "{x}a", "1"]).await;
+    request(&mut a, ["SET", "{x}b", "2", "EX", "100"]).await;
+    request(&mut a, ["RPUSH", "{x}list", "e"]).await;
+
+    assert_eq!(
+        request(&mut b, ["CLUSTER", "SETSLOT", &slot, "IMPORTING", &a_id]).await,
+        ok()
+    );
+    assert_eq!(
+        request(&mut a, ["CLUSTER", "SETSLOT", &slot, "MIGRATING", &b_id]).await,
+        ok()
+    );
+    let b_addr = format!("127.0.0.1:{b_port}");
+    assert_eq!(
+        request(
+            &mut a,
+            ["MIGRATE", "127.0.0.1", &b_port, "{x}a", "0", "1000"]
+        )
+        .await,
+        ok()
+    );
+
+    // moved keys are asked for on the target, the others are still served here
+    assert_eq!(
+        request(&mut a, ["GET", "{x}a"]).await,
+        error(format!("ASK {slot} {b_addr}"))
+    );
+    assert_eq!(request(&mut a, ["GET", "{x}b"]).await, bulk("2"));
+    assert_eq!(
+        request(&mut b, ["GET", "{x}a"]).await,
+        error(format!("MOVED {slot} 127.0.0.1:{a_port}"))
+    );
+    assert_eq!(request(&mut b, ["ASKING"]).await, ok());
+    assert_eq!(request(&mut b, ["GET", "{x}a"]).await, bulk("1"));
+
+    assert_eq!(
+        request(&mut a, ["CLUSTER", "COUNTKEYSINSLOT", &slot]).await,
+        RedisValue::Integer(2)
+    );
+    assert_eq!(
+        request(&mut a, ["CLUSTER", "SETSLOT", &slot, "NODE", &b_id]).await,
+        error(format!(
+            "ERR Can't assign hashslot {slot} to a different node while I still hold keys for \
+             this hash slot."
+        ))
+    );
+    let migrate = [
+        "MIGRATE",
+        "127.0.0.1",
+        &b_port,
+        "",
+        "0",
+        "1000",
+        "KEYS",
+        "{x}b",
+        "{x}list",
+    ];
+    assert_eq!(request(&mut a, migrate).await, ok());
+    assert_eq!(
+        request(&mut a, migrate).await,
+        RedisValue::SimpleString("NOKEY".into())
+    );
+    assert_eq!(
+        request(&mut a, ["CLUSTER", "GETKEYSINSLOT", &slot, "10"]).await,
+        RedisValue::Array(vec![])
+    );
+
+    // the slot is the target's once both say so
+    for client in [&mut a, &mut b] {
+        assert_eq!(
+            request(client, ["CLUSTER", "SETSLOT", &slot, "NODE", &b_id]).await,
+            ok()
+        );
+    }
+    assert_eq!(
+        request(&mut a, ["GET", "{x}b"]).await,
+        error(format!("MOVED {slot} {b_addr}"))
+    );
+    assert_eq!(request(&mut b, ["GET", "{x}b"]).await, bulk("2"));
+    assert!(target.keyspace().ttl("{x}b").unwrap() > Duration::from_secs(90));
+    assert_eq!(
+        request(&mut b, ["LRANGE", "{x}list", "0", "-1"]).await,
+        RedisValue::Array(vec![bulk("e")])
+    );
+    assert!(!source.keyspace().exists("{x}b"));
+
+    // what DUMP gives RESTORE takes, once told it may replace the key
+    let RedisValue::BulkString(payload) = request(&mut b, ["DUMP", "{x}a"]).await else {
+        panic!("expected a payload");
+    };
+    let restore = |replace: &'static [u8]| {
+        let args: [&[u8]; 5] = [b"RESTORE", b"{x}a", b"0", &payload, replace];
+        args.into_iter()
+            .filter(|arg| !arg.is_empty())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        b.request(restore(b"")).await.unwrap(),
+        error("BUSYKEY Target key name already exists.".into())
+    );
+    assert_eq!(b.request(restore(b"REPLACE")).await.unwrap(), ok());
+    assert_eq!(request(&mut b, ["GET", "{x}a"]).await, bulk("1"));
+
+    source.stop().await.unwrap();
+    target.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn restore_refuses_corrupt_payloads() {
+    let redis = common::start().await;
+    let mut client = common::client(redis.addr()).await;
+    let error = |e: &str| RedisValue::SimpleError(e.to_string().into());
+    // a well-formed string
+    let payload = common::dump_payload(b"\x00\x03abc");
+    let args: [&[u8]; 4] = [b"RESTORE", b"k", b"0", &payload];
+    assert_eq!(client.request(args).await.unwrap(), ok());
+    assert_eq!(request(&mut client, ["GET", "k"]).await, bulk("abc"));
+
+    // a string as long as a 64 bit length goes, with a checksum that holds
+    let mut body = vec![0x00, 0x81];
+    body.extend_from_slice(&u64::MAX.to_be_bytes());
+    let payload = common::dump_payload(&body);
+    let args: [&[u8]; 4] = [b"RESTORE", b"huge", b"0", &payload];
+    assert_eq!(
+        client.request(args).await.unwrap(),
+        error("ERR Bad data format")
+    );
+    let mut payload = common::dump_payload(b"\x00\x03abc");
+    payload[2] = b'x';
+    let args: [&[u8]; 4] = [b"RESTORE", b"bad", b"0", &payload];
+    assert_eq!(
+        client.request(args).await.unwrap(),
+        error("ERR DUMP payload version or checksum are wrong")
+    );
+    // the connection survives both
+    assert_eq!(
+        request(&mut client, ["PING"]).await,
+        RedisValue::SimpleString("PONG".into())
+    );
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn readonly_replicas_serve_their_masters_slots() {
+    let master = common::builder()
+        .option("cluster-enabled", "yes")
+        .start()
+        .await
+        .unwrap();
+    let replica = common::builder()
+        .option("cluster-enabled", "yes")
+        .replicaof("127.0.0.1", master.addr().port())
+        .start()
+        .await
+        .unwrap();
+    let mut m = common::client(master.addr()).await;
+    let mut r = common::client(replica.addr()).await;
+    let port = master.addr().port().to_string();
+    let bus_port = cluster_port(&mut m).await;
+    assert_eq!(
+        request(&mut r, ["CLUSTER", "MEET", "127.0.0.1", &port, &bus_port]).await,
+        ok()
+    );
+    while cluster_nodes(&mut r).await.lines().count() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let RedisValue::BulkString(master_id) = request(&mut m, ["CLUSTER", "MYID"]).await else {
+        panic!("expected an ID");
+    };
+    let master_id = String::from_utf8(master_id.to_vec()).unwrap();
+    let RedisValue::Integer(slot) = request(&mut r, ["CLUSTER", "KEYSLOT", "k"]).await else {
+        panic!("expected a slot");
+    };
+    let slot = slot.to_string();
+    assert_eq!(
+        request(&mut r, ["CLUSTER", "SETSLOT", &slot, "NODE", &master_id]).await,
+        ok()
+    );
+    assert_eq!(request(&mut m, ["SET", "k", "v"]).await, ok());
+
+    let moved = RedisValue::SimpleError(format!("MOVED {slot} 127.0.0.1:{port}").into());
+    assert_eq!(request(&mut r, ["GET", "k"]).await, moved);
+    assert_eq!(request(&mut r, ["READONLY"]).await, ok());
+    while request(&mut r, ["GET", "k"]).await != bulk("v") {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // writes still go to the master
+    assert_eq!(request(&mut r, ["SET", "k", "w"]).await, moved);
+    assert_eq!(request(&mut r, ["READWRITE"]).await, ok());
+    assert_eq!(request(&mut r, ["GET", "k"]).await, moved);
+
+    replica.stop().await.unwrap();
+    master.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn nodes_gossip_and_detect_failures() {
+    let start = || {
+        common::builder()
+            .option("cluster-enabled", "yes")
+            .option("cluster-node-timeout", "300")
+            .start()
+    };
+    let nodes = [
+        start().await.unwrap(),
+        start().await.unwrap(),
+        start().await.unwrap(),
+    ];
+    let mut clients = Vec::new();
+    for node in &nodes {
+        clients.push(common::client(node.addr()).await);
+    }
+    // the first node is only met by the second, and the third hears of it from gossip
+    for (from, to) in [(0, 1), (1, 2)] {
+        let bus_port = cluster_port(&mut clients[to]).await;
+        let port = nodes[to].addr().port().to_string();
+        assert_eq!(
+            request(
+                &mut clients[from],
+                ["CLUSTER", "MEET", "127.0.0.1", &port, &bus_port]
+            )
+            .await,
+            ok()
+        );
+    }
+    for client in &mut clients {
+        while cluster_nodes(client).await.lines().count() < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    let [first, second, third] = nodes;
+    let RedisValue::BulkString(third_id) = request(&mut clients[2], ["CLUSTER", "MYID"]).await
+    else {
+        panic!("expected an ID");
+    };
+    let third_id = String::from_utf8(third_id.to_vec()).unwrap();
+    third.stop().await.unwrap();
+    for client in &mut clients[..2] {
+        loop {
+            let nodes = cluster_nodes(client).await;
+            let line = nodes.lines().find(|line| line.starts_with(&third_id));
+            if line.unwrap().contains(" master,fail ") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    first.stop().await.unwrap();
+    second.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn cli_bulk_loads_and_runs_commands() {
+    let redis = common::start().await;
+    let port = redis.addr().port().to_string();
+    let cli = |args: &[&str]| {
+        let mut command = tokio::process::Command::new(env!("CARGO_BIN_EXE_redis-cli"));
+        command
+            .args(["-p", &port])
+            .args(args)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null());
+        command.spawn().unwrap()
+    };
+    async fn output(mut child: tokio::process::Child, input: &[u8]) -> String {
+        let mut stdin = child.stdin.take().unwrap();
+        stdin.write_all(input).await.unwrap();
+        drop(stdin);
+        let output = tokio::time::timeout(common::TIMEOUT, child.wait_with_output())
+            .await
+            .expect("cli still running")
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    let load = [
+        &b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n"[..],
+        b"*2\r\n$4\r\nINCR\r\n$1\r\na\r\n",
+        b"*1\r\n$3\r\nNOP\r\n",
+    ]
+    .concat();
+    let summary = output(cli(&["--pipe"]), &load).await;
+    assert!(summary.ends_with("errors: 1, replies: 3\n"), "{summary}");
+    // not at a terminal, replies come raw
+    assert_eq!(output(cli(&["GET", "a"]), b"").await, "2\n");
+    let script = b"set b 'x y'\nget b\nlpush l p q\nlrange l 0 -1\n";
+    assert_eq!(output(cli(&[]), script).await, "OK\nx y\n2\nq\np\n");
+    redis.stop().await.unwrap();
+}
+
+#[tokio::test]
+async fn bench_reports_throughput_and_latency() {
+    let redis = common::start().await;
+    let port = redis.addr().port().to_string();
+    let args = ["-p", &port, "-c", "3", "-n", "100", "-P", "4", "-r", "10"];
+    let run = |tests: &'static [&'static str]| {
+        tokio::process::Command::new(env!("CARGO_BIN_EXE_redis-bench"))
+            .args(args)
+            .args(tests)
+            .output()
+    };
+
+    let output = tokio::time::timeout(common::TIMEOUT, run(&["-t", "set,incr"]))
+        .await
+        .expect("bench still running")
+        .unwrap();
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains("====== SET ======"), "{report}");
+    assert!(report.contains("====== INCR ======"), "{report}");
+    assert!(report.contains("100 requests completed"), "{report}");
+    assert!(report.contains("requests per second"), "{report}");
+    assert!(!report.contains("error replies"), "{report}");
+    // ten names at most, for strings and counters
+    let keys = redis.keyspace().keys().count();
+    assert!(keys > 0 && keys <= 20, "{keys} keys");
+
+    let output = tokio::time::timeout(common::TIMEOUT, run(&["-t", "get,lpush", "--mix"]))
+        .await
+        .expect("bench still running")
+        .unwrap();
+    let report = String::from_utf8(output.stdout).unwrap();
+    assert!(report.contains("====== GET,LPUSH ======"), "{report}");
+    redis.stop().await.unwrap();
+}
+
+/// The cluster bus port of the node `client` is connected to
+async fn cluster_port(client: &mut Client) -> String {
+    match request(client, ["CONFIG", "GET", "cluster-port"]).await {
+        RedisValue::Array(pair) => match &pair[1] {
+            RedisValue::BulkString(port) => String::from_utf8(port.to_vec()).unwrap(),
+            other => panic!("expected a port, got {other:?}"),
+        },
+        other => panic!("expected a pair, got {other:?}"),
+    }
+}
+
+async fn cluster_nodes(client: &mut Client) -> String {
+    match request(client, ["CLUSTER", "NODES"]).await {
+        RedisValue::BulkString(nodes) => String::from_utf8(nodes.to_vec()).unwrap(),
+        other => panic!("expected the nodes, got {other:?}"),
+    }
+}